@@ -0,0 +1,175 @@
+// Runtime introspection and controls for operators: running jobs, what each connected client
+// is doing, the queue and the compile cache, plus cancelling jobs and pausing intake. Served
+// by the `/admin/*` HTTP routes and the `/admin` Socket.IO namespace, which pushes a `status`
+// snapshot every few seconds. Both require CLOUD_COMPILER_ADMIN_TOKEN.
+use std::collections::HashMap;
+use std::sync::{ LazyLock, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use serde::Serialize;
+use serde_json::Value;
+use utoipa::ToSchema;
+use socketioxide::adapter::Adapter;
+use socketioxide::extract::{ AckSender, Data, SocketRef };
+use tracing::info;
+use crate::cache::{ self, CacheStats };
+use crate::queue::{ self, QueueStats };
+use crate::resources::{ self, ResourceInfo, ResourceKind };
+use crate::shutdown;
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+static INTAKE_PAUSED: AtomicBool = AtomicBool::new(false);
+
+struct Client {
+    subject: String,
+    namespace: String,
+    connected_at: u64,
+    events: u64,
+    last_event: Option<&'static str>,
+    last_event_at: Option<u64>,
+}
+
+// Connected sockets by id
+static CLIENTS: LazyLock<Mutex<HashMap<String, Client>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, ToSchema)]
+pub struct ClientActivity {
+    pub socket_id: String,
+    pub subject: String,
+    pub namespace: String,
+    pub connected_at: u64,
+    pub events: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_event: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_event_at: Option<u64>,
+    // Jobs the client has running
+    pub jobs: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Status {
+    pub intake_paused: bool,
+    pub draining: bool,
+    pub queue: QueueStats,
+    pub cache: CacheStats,
+    pub jobs: Vec<ResourceInfo>,
+    pub clients: Vec<ClientActivity>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// True while new events are refused by operator request
+pub fn intake_paused() -> bool {
+    INTAKE_PAUSED.load(Ordering::Relaxed)
+}
+
+pub fn set_intake_paused(paused: bool) {
+    INTAKE_PAUSED.store(paused, Ordering::Relaxed);
+    info!("Intake {}", if paused { "paused" } else { "resumed" });
+}
+
+// Whether `token` is the configured admin token, always false without one
+pub fn token_valid(token: &str) -> bool {
+    std::env::var("CLOUD_COMPILER_ADMIN_TOKEN").is_ok_and(|admin| !admin.is_empty() && token == admin)
+}
+
+pub fn client_connected(socket_id: &str, subject: &str, namespace: &str) {
+    let client = Client {
+        subject: subject.to_string(),
+        namespace: namespace.to_string(),
+        connected_at: now(),
+        events: 0,
+        last_event: None,
+        last_event_at: None,
+    };
+    CLIENTS.lock().unwrap().insert(socket_id.to_string(), client);
+}
+
+pub fn client_event(socket_id: &str, event: &'static str) {
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(socket_id) {
+        client.events += 1;
+        client.last_event = Some(event);
+        client.last_event_at = Some(now());
+    }
+}
+
+pub fn client_disconnected(socket_id: &str) {
+    CLIENTS.lock().unwrap().remove(socket_id);
+}
+
+pub fn status() -> Status {
+    let jobs: Vec<ResourceInfo> = resources
+        ::list()
+        .into_iter()
+        .filter(|resource| resource.kind == ResourceKind::Job)
+        .collect();
+    let mut clients: Vec<ClientActivity> = CLIENTS.lock()
+        .unwrap()
+        .iter()
+        .map(|(socket_id, client)| ClientActivity {
+            socket_id: socket_id.clone(),
+            subject: client.subject.clone(),
+            namespace: client.namespace.clone(),
+            connected_at: client.connected_at,
+            events: client.events,
+            last_event: client.last_event,
+            last_event_at: client.last_event_at,
+            jobs: jobs.iter().filter(|job| job.owner.as_deref() == Some(socket_id)).count(),
+        })
+        .collect();
+    clients.sort_by_key(|client| client.connected_at);
+    Status {
+        intake_paused: intake_paused(),
+        draining: shutdown::draining(),
+        queue: queue::stats(),
+        cache: cache::stats(),
+        jobs,
+        clients,
+    }
+}
+
+// Handshake of the `/admin` namespace: the admin token as `token` in the auth payload or as a
+// bearer Authorization header
+pub fn on_connect<A: Adapter>(socket: SocketRef<A>, Data(data): Data<Value>) {
+    let bearer = socket
+        .req_parts()
+        .headers.get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(String::from);
+    let token = data.get("token").and_then(|v| v.as_str()).map(String::from).or(bearer);
+    if !token.is_some_and(|token| token_valid(&token)) {
+        info!(?socket.id, "Rejected admin connection");
+        socket.emit("unauthorized", &serde_json::json!({ "error": "Admin token required" })).ok();
+        socket.disconnect().ok();
+        return;
+    }
+    info!(?socket.id, "Admin connected");
+
+    socket.on("status", |ack: AckSender<A>| {
+        ack.send(&status()).ok();
+    });
+    socket.on("cancel-job", |Data::<Value>(data), ack: AckSender<A>| {
+        let released = data.get("id").and_then(|v| v.as_u64()).is_some_and(resources::release);
+        ack.send(&serde_json::json!({ "released": released })).ok();
+    });
+    socket.on("pause-intake", |ack: AckSender<A>| {
+        set_intake_paused(true);
+        ack.send(&status()).ok();
+    });
+    socket.on("resume-intake", |ack: AckSender<A>| {
+        set_intake_paused(false);
+        ack.send(&status()).ok();
+    });
+
+    tokio::spawn(async move {
+        while socket.connected() {
+            socket.emit("status", &status()).ok();
+            tokio::time::sleep(STATUS_INTERVAL).await;
+        }
+    });
+}
@@ -0,0 +1,118 @@
+// Static analysis with cppcheck. arduino-cli writes the compilation database of a sketch for a
+// board without compiling it, which gives cppcheck the include paths and defines of the core
+// and libraries the sketch builds with. Only the sketch is analyzed, and findings point into its
+// own files.
+use std::path::{ Path, PathBuf };
+use serde::Serialize;
+use crate::compiler::{ compilation_database, run_program, sketch_dir, COMPILATION_DATABASE };
+
+pub const CHECKS: &[&str] = &["warning", "style", "performance", "portability", "information"];
+const DEFAULT_CHECKS: &[&str] = &["warning", "style", "performance", "portability"];
+// Fields of the output template, tab separated
+const TEMPLATE: &str = "{file}\\t{line}\\t{column}\\t{severity}\\t{id}\\t{cwe}\\t{message}";
+const RESULTS_FILE: &str = "cppcheck.txt";
+
+#[derive(Serialize)]
+pub struct Finding {
+    // cppcheck's severity: error, warning, style, performance, portability or information
+    pub severity: String,
+    // Check id, like `nullPointer` or `arrayIndexOutOfBounds`
+    pub id: String,
+    pub message: String,
+    // Relative to the sketch folder
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwe: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct AnalysisReport {
+    pub findings: Vec<Finding>,
+}
+
+fn cppcheck_binary() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_CPPCHECK")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("cppcheck"))
+}
+
+// Check groups to enable, the defaults without `checks`
+pub fn checks(requested: Option<&[String]>) -> Result<Vec<String>, String> {
+    let Some(requested) = requested else {
+        return Ok(DEFAULT_CHECKS.iter().map(|check| check.to_string()).collect());
+    };
+    match requested.iter().find(|check| !CHECKS.contains(&check.as_str())) {
+        Some(check) => Err(format!("Unknown check group {}, use {}", check, CHECKS.join(", "))),
+        None => Ok(requested.to_vec()),
+    }
+}
+
+// A finding in the sketch, from a line of the template output. The sketch is preprocessed into
+// `<build>/sketch`, files there are mapped back to the sketch folder; core and library headers
+// are left out.
+fn parse_finding(line: &str, sketch_dir: &Path, build_sketch: &Path) -> Option<Finding> {
+    let fields: Vec<&str> = line.splitn(7, '\t').collect();
+    let [file, line, column, severity, id, cwe, message] = fields.as_slice() else {
+        return None;
+    };
+    let path = Path::new(file);
+    let relative = path.strip_prefix(build_sketch).or_else(|_| path.strip_prefix(sketch_dir)).ok()?;
+    // The sketch's .ino files are concatenated into `<name>.ino.cpp`, `#line` maps most of it
+    // back, what remains is generated code
+    if relative.to_string_lossy().ends_with(".ino.cpp") {
+        return None;
+    }
+    Some(Finding {
+        severity: severity.to_string(),
+        id: id.to_string(),
+        message: message.trim().to_string(),
+        file: relative.to_string_lossy().to_string(),
+        line: line.parse().unwrap_or_default(),
+        column: column.parse().unwrap_or_default(),
+        cwe: cwe.parse().ok().filter(|cwe| *cwe != 0),
+    })
+}
+
+// Analyze `sketch` as built for `fqbn`, using `build_dir` for the compilation database
+pub async fn analyze(fqbn: &str, sketch: &str, build_dir: &Path, checks: &[String]) -> Result<AnalysisReport, String> {
+    let response = compilation_database(fqbn, sketch, build_dir).await;
+    if !response.success {
+        return Err(response.error.unwrap_or_default().trim().to_string());
+    }
+    let database = build_dir.join(COMPILATION_DATABASE);
+    if !database.is_file() {
+        return Err("arduino-cli wrote no compilation database".to_string());
+    }
+
+    let build_sketch = build_dir.join("sketch");
+    let results = build_dir.join(RESULTS_FILE);
+    let args = vec![
+        format!("--project={}", database.display()),
+        format!("--file-filter={}*", build_sketch.join("").display()),
+        format!("--enable={}", checks.join(",")),
+        // The ESP32 cores are 32 bit
+        "--platform=unix32".to_string(),
+        "--inline-suppr".to_string(),
+        "--suppress=missingIncludeSystem".to_string(),
+        "--quiet".to_string(),
+        format!("--template={}", TEMPLATE),
+        format!("--output-file={}", results.display()),
+    ];
+    let response = run_program(&cppcheck_binary(), "cppcheck", &args).await;
+    if !response.success {
+        return Err(format!("cppcheck failed: {}", response.error.unwrap_or_default().trim()));
+    }
+
+    let output = std::fs::read_to_string(&results).unwrap_or_default();
+    let sketch_dir = sketch_dir(Path::new(sketch));
+    let mut findings: Vec<Finding> = output
+        .lines()
+        .filter_map(|line| parse_finding(line, sketch_dir, &build_sketch))
+        .collect();
+    findings.sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+    findings.dedup_by(|a, b| a.file == b.file && a.line == b.line && a.id == b.id);
+    Ok(AnalysisReport { findings })
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use utoipa::ToSchema;
+use tracing::info;
+use crate::models::*;
+use crate::compiler::{ compiler_diagnostics, server_data_dir };
+use crate::teaching::explain;
+
+// Opt-in usage analytics for the operator, enabled with `CLOUD_COMPILER_ANALYTICS=1`.
+// Only anonymous counts are kept, nothing identifying a client or a sketch: compiles per
+// board, libraries used and categories of compile errors. Counts are rolled into a report
+// file every period and reset.
+
+const DEFAULT_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+// Reports returned by the admin route
+const RECENT_REPORTS: usize = 30;
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct Report {
+    pub period_start: u64,
+    pub period_end: Option<u64>,
+    pub compiles: u64,
+    pub failures: u64,
+    // Keyed by FQBN without board options
+    pub boards: BTreeMap<String, u64>,
+    pub libraries: BTreeMap<String, u64>,
+    // Teaching-mode error codes, `OTHER` for errors no rule recognizes
+    pub errors: BTreeMap<String, u64>,
+}
+
+static CURRENT: LazyLock<Mutex<Report>> = LazyLock::new(|| Mutex::new(Report { period_start: now(), ..Default::default() }));
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+pub fn enabled() -> bool {
+    std::env::var("CLOUD_COMPILER_ANALYTICS").is_ok_and(|v| v == "1" || v == "true")
+}
+
+fn reports_dir() -> PathBuf {
+    server_data_dir().join("analytics")
+}
+
+// Libraries from the "Used library" table arduino-cli prints after a compile
+fn used_libraries(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip_while(|line| !line.starts_with("Used library"))
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty() && !line.starts_with("Used platform"))
+        .filter_map(|line| line.split_whitespace().next().map(String::from))
+        .collect()
+}
+
+// Count a finished compile
+pub fn record_compile(fqbn: Option<&str>, response: &CommandResponse) {
+    if !enabled() {
+        return;
+    }
+    let board = fqbn
+        .map(|fqbn| fqbn.splitn(4, ':').take(3).collect::<Vec<_>>().join(":"))
+        .unwrap_or_else(|| "unknown".to_string());
+    let errors: Vec<String> = match response.success {
+        true => Vec::new(),
+        false => {
+            let diagnostics = compiler_diagnostics(response.error.as_deref().unwrap_or_default());
+            let mut codes: Vec<String> = explain(&diagnostics, response.error.as_deref())
+                .into_iter()
+                .map(|e| e.code)
+                .collect();
+            codes.sort();
+            codes.dedup();
+            if codes.is_empty() {
+                codes.push("OTHER".to_string());
+            }
+            codes
+        }
+    };
+
+    let mut report = CURRENT.lock().unwrap();
+    report.compiles += 1;
+    if !response.success {
+        report.failures += 1;
+    }
+    *report.boards.entry(board).or_default() += 1;
+    for library in used_libraries(&response.output) {
+        *report.libraries.entry(library).or_default() += 1;
+    }
+    for code in errors {
+        *report.errors.entry(code).or_default() += 1;
+    }
+}
+
+// Counts of the period in progress
+pub fn current_report() -> Report {
+    CURRENT.lock().unwrap().clone()
+}
+
+// Finished reports, newest first
+pub fn recent_reports() -> Vec<Report> {
+    let mut reports: Vec<Report> = std::fs
+        ::read_dir(reports_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| std::fs::read(e.path()).ok())
+                .filter_map(|json| serde_json::from_slice(&json).ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    reports.sort_by_key(|r: &Report| std::cmp::Reverse(r.period_start));
+    reports.truncate(RECENT_REPORTS);
+    reports
+}
+
+// Close the current period into a report file and start a new one
+fn roll_over() {
+    let report = {
+        let mut current = CURRENT.lock().unwrap();
+        let mut report = std::mem::replace(&mut *current, Report { period_start: now(), ..Default::default() });
+        report.period_end = Some(now());
+        report
+    };
+    let path = reports_dir().join(format!("{}.json", report.period_start));
+    let written = std::fs
+        ::create_dir_all(reports_dir())
+        .and_then(|_| std::fs::write(&path, serde_json::to_vec_pretty(&report).unwrap_or_default()));
+    match written {
+        Ok(()) => info!("Wrote analytics report {}", path.display()),
+        Err(e) => info!("Failed to write analytics report: {}", e),
+    }
+}
+
+// Roll reports over every `CLOUD_COMPILER_ANALYTICS_PERIOD_SECS` (default one day)
+pub fn spawn_reporter() {
+    if !enabled() {
+        return;
+    }
+    let period = std::env
+        ::var("CLOUD_COMPILER_ANALYTICS_PERIOD_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .filter(|period| !period.is_zero())
+        .unwrap_or(DEFAULT_PERIOD);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // The first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            tokio::task::spawn_blocking(roll_over).await.ok();
+        }
+    });
+}
@@ -0,0 +1,165 @@
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use crate::models::*;
+use crate::artifactstore;
+use crate::compiler::{ is_safe_name, server_data_dir };
+use crate::sessions::is_guest_build;
+use crate::zipstream::ZipStream;
+
+// Root directory holding one subdirectory per build
+pub fn builds_root() -> PathBuf {
+    server_data_dir().join("builds")
+}
+
+// Allocate a fresh build directory, returns its id and path
+pub fn new_build_dir() -> std::io::Result<(String, PathBuf)> {
+    let build_id = uuid::Uuid::new_v4().to_string();
+    let dir = builds_root().join(&build_id);
+    std::fs::create_dir_all(&dir)?;
+    Ok((build_id, dir))
+}
+
+// Resolve a client-supplied build id to its directory
+pub fn build_dir(build_id: &str) -> Option<PathBuf> {
+    if !is_safe_name(build_id) {
+        return None;
+    }
+    let dir = builds_root().join(build_id);
+    dir.is_dir().then_some(dir)
+}
+
+// Resolve an artifact of a build, the name must be a plain file name
+pub fn artifact_path(build_id: &str, name: &str) -> Option<PathBuf> {
+    if !is_safe_name(name) {
+        return None;
+    }
+    let path = build_dir(build_id)?.join(name);
+    path.is_file().then_some(path)
+}
+
+// List the files in a build directory
+pub fn list_artifacts(dir: &Path) -> Vec<Artifact> {
+    let mut artifacts: Vec<Artifact> = std::fs
+        ::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let metadata = e.metadata().ok()?;
+                    let name = e.file_name().to_string_lossy().to_string();
+                    // Dotfiles are server bookkeeping, not build outputs
+                    (metadata.is_file() && !name.starts_with('.')).then_some(Artifact {
+                        name,
+                        size: metadata.len(),
+                        sha256: None,
+                        url: None,
+                        expires_at: None,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+    artifacts
+}
+
+// Find the application image (`<sketch>.ino.bin`), the bootloader/partitions/merged
+// images next to it have their own suffixes
+pub fn find_app_binary(dir: &Path) -> Option<PathBuf> {
+    list_artifacts(dir)
+        .into_iter()
+        .find(|a| a.name.ends_with(".ino.bin"))
+        .map(|a| dir.join(a.name))
+}
+
+// Attach a build directory and its artifacts to a response, saved to the artifact store with
+// their download URLs unless a guest made them
+pub async fn attach_artifacts(response: &mut CommandResponse, build_id: &str, dir: &Path) {
+    response.build_id = Some(build_id.to_string());
+    response.artifacts = list_artifacts(dir);
+    if !is_guest_build(dir) {
+        artifactstore::publish(&mut response.artifacts, dir).await;
+    }
+}
+
+// Subdirectory where builds that keep their intermediate files put arduino-cli's build path
+pub const BUILD_PATH_DIR: &str = "build";
+
+// Stream a whole build directory (artifacts, objects, map, intermediate JSON) as a ZIP into `out`
+pub fn stream_build_dir(dir: &Path, out: impl Write) -> Result<(), String> {
+    let mut zip = ZipStream::new(out);
+    walk_zip_entries(dir, "", |name, path, is_dir| {
+        if is_dir {
+            return zip.add_directory(&name).map_err(|e| e.to_string());
+        }
+        let mut source = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let size = source.metadata().map_err(|e| e.to_string())?.len();
+        zip.add_file(&name, &mut source, size).map_err(|e| e.to_string())
+    })?;
+    zip.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Zip the files below `dir` into `output`, inside folder `root` of the archive when not empty.
+// Dotfiles are left out.
+pub fn zip_dir(dir: &Path, root: &str, output: &Path) -> Result<(), String> {
+    let file = std::fs::File::create(output).map_err(|e| e.to_string())?;
+    let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+    let options = zip::write::SimpleFileOptions
+        ::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .large_file(true);
+
+    walk_zip_entries(dir, root, |name, path, is_dir| {
+        if is_dir {
+            return zip.add_directory(name, options).map_err(|e| e.to_string());
+        }
+        zip.start_file(name, options).map_err(|e| e.to_string())?;
+        let mut source = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        std::io::copy(&mut source, &mut zip).map_err(|e| e.to_string())?;
+        Ok(())
+    })?;
+
+    zip.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Visit the directories and files below `dir` in name order, with their archive name inside
+// folder `root` when not empty. Dotfiles are left out.
+fn walk_zip_entries(
+    dir: &Path,
+    root: &str,
+    mut visit: impl FnMut(String, &Path, bool) -> Result<(), String>
+) -> Result<(), String> {
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let mut entries: Vec<_> = std::fs
+            ::read_dir(&current)
+            .map_err(|e| e.to_string())?
+            .filter_map(|e| e.ok())
+            .collect();
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let Ok(relative) = path.strip_prefix(dir) else {
+                continue;
+            };
+            let mut name = relative.to_string_lossy().replace('\\', "/");
+            if !root.is_empty() {
+                name = format!("{}/{}", root, name);
+            }
+            let file_type = entry.file_type().map_err(|e| e.to_string())?;
+            if file_type.is_dir() {
+                visit(name, &path, true)?;
+                pending.push(path);
+            } else if file_type.is_file() {
+                visit(name, &path, false)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,190 @@
+// Artifact store. Every compile output is saved under a content-addressed key,
+// `artifacts/<sha256>/<name>`, and responses carry a temporary signed download URL for it, so
+// big merged images never travel through the socket and any instance can hand out what
+// another one built.
+//
+// CLOUD_COMPILER_ARTIFACT_BACKEND=local|s3|gcs picks where they live:
+//   local: `<data dir>/artifacts/`, downloaded from `GET /artifacts/<sha256>/<name>` with an
+//          HMAC signature keyed by CLOUD_COMPILER_ARTIFACT_URL_SECRET (default a key generated
+//          in the data directory, share it between instances behind one URL)
+//   s3:    CLOUD_COMPILER_ARTIFACT_S3_BUCKET, CLOUD_COMPILER_ARTIFACT_S3_REGION (default
+//          us-east-1), CLOUD_COMPILER_ARTIFACT_S3_ENDPOINT, CLOUD_COMPILER_ARTIFACT_S3_PREFIX,
+//          AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY; URLs are presigned GETs
+//   gcs:   the same settings against Cloud Storage's S3-compatible API, the credentials being
+//          an HMAC key of a service account
+// URLs stay valid for CLOUD_COMPILER_ARTIFACT_URL_TTL_SECS (default 3600).
+use std::path::{ Path, PathBuf };
+use std::sync::LazyLock;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use hmac::{ Hmac, Mac };
+use sha2::{ Digest, Sha256 };
+use tracing::{ info, warn };
+use crate::auth::constant_time_eq;
+use crate::blobstore::S3Store;
+use crate::compiler::{ is_safe_name, server_data_dir };
+use crate::models::Artifact;
+use crate::notifications::public_url;
+
+const DEFAULT_URL_TTL: Duration = Duration::from_secs(3600);
+const SECRET_FILE: &str = "artifact-url.key";
+
+pub enum ArtifactStore {
+    Local,
+    S3(S3Store),
+}
+
+static STORE: LazyLock<ArtifactStore> = LazyLock::new(|| {
+    let backend = std::env::var("CLOUD_COMPILER_ARTIFACT_BACKEND").unwrap_or_default().trim().to_lowercase();
+    let remote = match backend.as_str() {
+        "" | "local" => None,
+        "s3" => Some(S3Store::from_settings("CLOUD_COMPILER_ARTIFACT_S3", "us-east-1", None)),
+        "gcs" => Some(S3Store::from_settings("CLOUD_COMPILER_ARTIFACT_S3", "auto", Some("https://storage.googleapis.com"))),
+        other => {
+            warn!("Unknown artifact backend {}, storing artifacts locally", other);
+            None
+        }
+    };
+    match remote {
+        Some(Some(store)) => {
+            info!("Artifact store: {}", backend);
+            ArtifactStore::S3(store)
+        }
+        Some(None) => {
+            warn!("CLOUD_COMPILER_ARTIFACT_S3_BUCKET is not set, storing artifacts locally");
+            ArtifactStore::Local
+        }
+        None => ArtifactStore::Local,
+    }
+});
+
+// Key signing local download URLs
+static SECRET: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    if let Some(secret) = std::env::var("CLOUD_COMPILER_ARTIFACT_URL_SECRET").ok().filter(|s| !s.is_empty()) {
+        return secret.into_bytes();
+    }
+    let path = server_data_dir().join(SECRET_FILE);
+    if let Ok(secret) = std::fs::read(&path) && !secret.is_empty() {
+        return secret;
+    }
+    let secret = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple()).into_bytes();
+    if let Err(e) = std::fs::create_dir_all(server_data_dir()).and_then(|_| std::fs::write(&path, &secret)) {
+        warn!("Failed to save {}, download URLs won't survive a restart: {}", path.display(), e);
+    }
+    secret
+});
+
+fn url_ttl() -> Duration {
+    std::env
+        ::var("CLOUD_COMPILER_ARTIFACT_URL_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_URL_TTL)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn object_key(sha256: &str, name: &str) -> String {
+    format!("artifacts/{}/{}", sha256, name)
+}
+
+// Directory of the local backend
+pub fn store_root() -> PathBuf {
+    server_data_dir().join("artifacts")
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+// Stored file of a locally kept artifact
+pub fn local_path(sha256: &str, name: &str) -> Option<PathBuf> {
+    if !is_sha256(sha256) || !is_safe_name(name) {
+        return None;
+    }
+    let path = store_root().join(sha256).join(name);
+    path.is_file().then_some(path)
+}
+
+fn signature(sha256: &str, name: &str, expires: u64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&SECRET).expect("HMAC takes keys of any size");
+    mac.update(format!("{}/{}/{}", sha256, name, expires).as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Whether a local download URL is genuine and not expired yet
+pub fn verify(sha256: &str, name: &str, expires: u64, given: &str) -> bool {
+    expires >= now() && constant_time_eq(&signature(sha256, name, expires), given)
+}
+
+// Local copy under its content key. Not a hard link: tools rewriting a build's files in
+// place would change the stored content behind its hash.
+fn store_local(source: &Path, sha256: &str, name: &str) -> Result<(), String> {
+    let dir = store_root().join(sha256);
+    let path = dir.join(name);
+    // Already stored: refreshed so retention counts its age from this build
+    if path.is_file() {
+        let file = std::fs::File::options().append(true).open(&path).map_err(|e| e.to_string())?;
+        return file.set_modified(SystemTime::now()).map_err(|e| e.to_string());
+    }
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    // Written aside and renamed so a download never sees half a file
+    let partial = dir.join(format!(".{}.{}", name, uuid::Uuid::new_v4().simple()));
+    std::fs::copy(source, &partial).map_err(|e| e.to_string())?;
+    std::fs::rename(&partial, &path).map_err(|e| {
+        let _ = std::fs::remove_file(&partial);
+        e.to_string()
+    })
+}
+
+async fn store(artifact: &mut Artifact, dir: &Path) -> Result<(), String> {
+    let source = dir.join(&artifact.name);
+    let bytes = tokio::fs::read(&source).await.map_err(|e| e.to_string())?;
+    let sha256 = format!("{:x}", Sha256::digest(&bytes));
+    let ttl = url_ttl();
+    let expires_at = now() + ttl.as_secs();
+    let url = match &*STORE {
+        ArtifactStore::Local => {
+            let (hash, name) = (sha256.clone(), artifact.name.clone());
+            tokio::task
+                ::spawn_blocking(move || store_local(&source, &hash, &name)).await
+                .map_err(|e| e.to_string())??;
+            format!(
+                "{}/artifacts/{}/{}?expires={}&signature={}",
+                public_url(),
+                sha256,
+                artifact.name,
+                expires_at,
+                signature(&sha256, &artifact.name, expires_at)
+            )
+        }
+        ArtifactStore::S3(s3) => {
+            let key = object_key(&sha256, &artifact.name);
+            // Same content, same key: outputs already uploaded by any instance are reused
+            if !s3.head(&key).await? {
+                s3.put(&key, bytes).await?;
+            }
+            s3.presign_get(&key, ttl)
+        }
+    };
+    artifact.sha256 = Some(sha256);
+    artifact.url = Some(url);
+    artifact.expires_at = Some(expires_at);
+    Ok(())
+}
+
+// Save the artifacts of build directory `dir` and give each a download URL. Failures are
+// logged and leave the artifact without one, it is still served from its build.
+pub async fn publish(artifacts: &mut [Artifact], dir: &Path) {
+    for artifact in artifacts.iter_mut() {
+        if let Err(e) = store(artifact, dir).await {
+            warn!("Failed to store artifact {}: {}", artifact.name, e);
+        }
+    }
+}
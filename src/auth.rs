@@ -0,0 +1,230 @@
+use std::sync::{ LazyLock, OnceLock, RwLock };
+use std::time::Duration;
+use axum::http::{ header, HeaderMap };
+use jsonwebtoken::{ decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation };
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use tracing::{ info, warn };
+use crate::sessions::{ guest_expiry, guest_session_id };
+
+// Authentication of Socket.IO handshakes. Clients present an API key or a JWT in the
+// handshake auth (`api_key` / `token`) or as `Authorization: Bearer`. Credentials are
+// configured with:
+//   CLOUD_COMPILER_API_KEYS     `name=key` pairs, comma separated
+//   CLOUD_COMPILER_JWT_SECRET   HS256 shared secret
+//   CLOUD_COMPILER_JWKS         JWKS URL or file for RS256/ES256 tokens
+//   CLOUD_COMPILER_JWT_ISSUER / CLOUD_COMPILER_JWT_AUDIENCE   optional claim checks
+// With none of them set the server stays open and every socket is anonymous.
+
+const JWKS_REFRESH: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+    Guest,
+    Anonymous,
+}
+
+// Who a socket acts for, stored in its extensions
+#[derive(Serialize, Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub method: AuthMethod,
+}
+
+pub enum AuthError {
+    Unauthorized(String),
+    GuestExpired,
+}
+
+#[derive(Default)]
+struct AuthConfig {
+    api_keys: Vec<(String, String)>,
+    jwt_secret: Option<String>,
+    jwks_source: Option<String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl AuthConfig {
+    fn required(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt_secret.is_some() || self.jwks_source.is_some()
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+static CONFIG: OnceLock<AuthConfig> = OnceLock::new();
+static JWKS: LazyLock<RwLock<JwkSet>> = LazyLock::new(|| RwLock::new(JwkSet { keys: Vec::new() }));
+
+fn config() -> &'static AuthConfig {
+    CONFIG.get_or_init(load_config)
+}
+
+fn load_config() -> AuthConfig {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let api_keys = var("CLOUD_COMPILER_API_KEYS")
+        .map(|keys| {
+            keys.split(',')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .filter(|(name, key)| !name.is_empty() && !key.is_empty())
+                .map(|(name, key)| (name.to_string(), key.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    AuthConfig {
+        api_keys,
+        jwt_secret: var("CLOUD_COMPILER_JWT_SECRET"),
+        jwks_source: var("CLOUD_COMPILER_JWKS"),
+        issuer: var("CLOUD_COMPILER_JWT_ISSUER"),
+        audience: var("CLOUD_COMPILER_JWT_AUDIENCE"),
+    }
+}
+
+pub fn auth_required() -> bool {
+    config().required()
+}
+
+async fn fetch_jwks(source: &str) -> Result<JwkSet, String> {
+    let json = match source.starts_with("http://") || source.starts_with("https://") {
+        true => {
+            reqwest::get(source).await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?
+                .bytes().await
+                .map_err(|e| e.to_string())?
+                .to_vec()
+        }
+        false => tokio::fs::read(source).await.map_err(|e| e.to_string())?,
+    };
+    serde_json::from_slice(&json).map_err(|e| e.to_string())
+}
+
+// Load the configuration and keep the JWKS fresh
+pub fn init() {
+    match auth_required() {
+        true => info!("Socket.IO authentication enabled"),
+        false => warn!("No API keys or JWT configured, the server accepts unauthenticated clients"),
+    }
+    let Some(source) = config().jwks_source.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(JWKS_REFRESH);
+        loop {
+            interval.tick().await;
+            match fetch_jwks(&source).await {
+                Ok(jwks) => {
+                    *JWKS.write().unwrap() = jwks;
+                }
+                Err(e) => warn!("Failed to load JWKS from {}: {}", source, e),
+            }
+        }
+    });
+}
+
+// Compare secrets without leaking where they differ
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn validation(algorithm: Algorithm, config: &AuthConfig) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    match &config.audience {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => {
+            validation.validate_aud = false;
+        }
+    }
+    validation
+}
+
+fn verify_jwt(token: &str, config: &AuthConfig) -> Result<String, String> {
+    let header = decode_header(token).map_err(|e| format!("Invalid token: {}", e))?;
+    let claims = match header.alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = config.jwt_secret.as_ref().ok_or("Invalid token: unexpected algorithm")?;
+            let key = DecodingKey::from_secret(secret.as_bytes());
+            decode::<Claims>(token, &key, &validation(header.alg, config))
+        }
+        _ => {
+            let jwks = JWKS.read().unwrap();
+            let jwk = match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }.ok_or("Invalid token: unknown signing key")?;
+            let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("Invalid token: {}", e))?;
+            decode::<Claims>(token, &key, &validation(header.alg, config))
+        }
+    };
+    claims.map(|data| data.claims.sub).map_err(|e| format!("Invalid token: {}", e))
+}
+
+// Credential presented by a client: handshake auth first, then the Authorization header
+fn presented<'a>(auth: &'a Value, headers: &'a HeaderMap, field: &str) -> Option<&'a str> {
+    auth.get(field)
+        .and_then(|v| v.as_str())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        })
+}
+
+// Authenticate a handshake. Guests holding a live `guest_token` are let in as guests.
+pub fn authenticate(auth: &Value, headers: &HeaderMap) -> Result<Identity, AuthError> {
+    let config = config();
+
+    if let Some(key) = presented(auth, headers, "api_key") && !config.api_keys.is_empty() {
+        let found = config.api_keys.iter().find(|(_, configured)| constant_time_eq(configured, key));
+        if let Some((name, _)) = found {
+            return Ok(Identity { subject: name.clone(), method: AuthMethod::ApiKey });
+        }
+    }
+    if let Some(token) = presented(auth, headers, "token") && token.matches('.').count() == 2 {
+        if config.jwt_secret.is_none() && config.jwks_source.is_none() {
+            return Err(AuthError::Unauthorized("Token authentication is not configured".to_string()));
+        }
+        return verify_jwt(token, config)
+            .map(|subject| Identity { subject, method: AuthMethod::Jwt })
+            .map_err(AuthError::Unauthorized);
+    }
+    if let Some(token) = auth.get("guest_token").and_then(|v| v.as_str()) {
+        return match guest_expiry(token) {
+            Some(_) => Ok(Identity { subject: format!("guest:{}", guest_session_id(token)), method: AuthMethod::Guest }),
+            None => Err(AuthError::GuestExpired),
+        };
+    }
+
+    match config.required() {
+        true => Err(AuthError::Unauthorized("Authentication required".to_string())),
+        false => Ok(Identity { subject: "anonymous".to_string(), method: AuthMethod::Anonymous }),
+    }
+}
+
+// Authenticated identity of an HTTP request, from its `Authorization: Bearer` header
+pub fn authenticate_request(headers: &HeaderMap) -> Option<Identity> {
+    authenticate(&Value::Null, headers)
+        .ok()
+        .filter(|identity| matches!(identity.method, AuthMethod::ApiKey | AuthMethod::Jwt))
+}
+
+// Handshake auth as echoed back to the client, without its credentials
+pub fn redact_credentials(auth: &Value) -> Value {
+    let mut echoed = auth.clone();
+    if let Some(fields) = echoed.as_object_mut() {
+        fields.remove("api_key");
+        fields.remove("token");
+    }
+    echoed
+}
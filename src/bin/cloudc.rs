@@ -0,0 +1,187 @@
+// Command line client of the compile server, built with the `client` feature. It uploads a local
+// sketch folder, compiles it while the compiler's output streams to the terminal, and optionally
+// downloads the artifacts or flashes the merged image to a board on a local serial port:
+//
+//     cloudc ./Blink --fqbn esp32:esp32:esp32 --output-dir build --flash /dev/ttyUSB0
+use std::path::{ Path, PathBuf };
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use clap::Parser;
+use serde_json::json;
+use arduino_esp32_cloud_compiler::client::{ Client, ClientError, ClientOptions };
+use arduino_esp32_cloud_compiler::models::*;
+
+#[derive(Parser)]
+#[command(version, about = "Compile a local sketch on an arduino-esp32-cloud-compiler server")]
+struct Flags {
+    #[arg(value_name = "DIR", default_value = ".", help = "Sketch folder")]
+    sketch: PathBuf,
+    #[arg(long, short = 's', env = "CLOUD_COMPILER_URL", default_value = "http://localhost:3000", help = "Server URL")]
+    server: String,
+    #[arg(long, env = "CLOUD_COMPILER_API_KEY", hide_env_values = true, help = "API key to authenticate with")]
+    api_key: Option<String>,
+    #[arg(long, short = 'b', help = "Board to compile for (esp32:esp32:esp32)")]
+    fqbn: Option<String>,
+    #[arg(long, help = "Build profile of the sketch's sketch.yaml")]
+    profile: Option<String>,
+    #[arg(long, help = "Toolchain to compile with")]
+    toolchain: Option<String>,
+    #[arg(long, short = 'o', value_name = "DIR", help = "Download the artifacts into this folder")]
+    output_dir: Option<PathBuf>,
+    #[arg(long, value_name = "PORT", help = "Flash the merged image to the board on this serial port")]
+    flash: Option<String>,
+    #[arg(long, default_value = "esptool", value_name = "PATH", help = "esptool used for --flash")]
+    esptool: String,
+    #[arg(long, help = "Flash baud rate [default: esptool's]")]
+    baud: Option<u32>,
+    #[arg(long, short = 'q', help = "Don't print the compiler output")]
+    quiet: bool,
+}
+
+fn print_diagnostics(response: &CommandResponse) {
+    for diagnostic in &response.diagnostics {
+        let severity = match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Advisory => "note",
+        };
+        match (&diagnostic.file, diagnostic.line) {
+            (Some(file), Some(line)) => eprintln!("{}:{}: {}: {}", file, line, severity, diagnostic.message),
+            (Some(file), None) => eprintln!("{}: {}: {}", file, severity, diagnostic.message),
+            _ => eprintln!("{}: {}", severity, diagnostic.message),
+        }
+    }
+}
+
+// Download the artifacts into `dir`, the path of each one written
+async fn download(client: &Client, response: &CommandResponse, dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let build_id = response.build_id.as_deref().ok_or("The response has no build id")?;
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let mut written = Vec::new();
+    for artifact in &response.artifacts {
+        let bytes = client.fetch_artifact(build_id, artifact).await.map_err(|e| format!("{}: {}", artifact.name, e))?;
+        // Only the file name, an artifact can't write outside `dir`
+        let name = Path::new(&artifact.name).file_name().ok_or_else(|| format!("Invalid artifact name {}", artifact.name))?;
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+// Write the merged image at offset 0 with the local esptool
+fn flash(flags: &Flags, port: &str, image: &Path) -> Result<(), String> {
+    let mut esptool = std::process::Command::new(&flags.esptool);
+    esptool.arg("--port").arg(port);
+    if let Some(baud) = flags.baud {
+        esptool.arg("--baud").arg(baud.to_string());
+    }
+    esptool.arg("write_flash").arg("0x0").arg(image);
+    let status = esptool.status().map_err(|e| format!("Failed to run {}: {}", flags.esptool, e))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("{} exited with {}", flags.esptool, status)),
+    }
+}
+
+async fn run(flags: Flags) -> Result<(), String> {
+    let mut options = ClientOptions::new(&flags.server);
+    options.auth = flags.api_key.as_ref().map(|key| json!({ "api_key": key }));
+    let client = Client::connect(options).await.map_err(|e| e.to_string())?;
+
+    let sketch_path = client.upload_sketch(&flags.sketch).await.map_err(|e| format!("Upload failed: {}", e))?;
+    let mut events = client.events();
+    let quiet = flags.quiet;
+    let streamed = Arc::new(AtomicBool::new(false));
+    let printed = streamed.clone();
+    let printer = tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            match event.event.as_str() {
+                "compile-output" if !quiet => {
+                    printed.store(true, Ordering::Relaxed);
+                    let text = event.data["text"].as_str().unwrap_or_default();
+                    match event.data["stream"].as_str() {
+                        Some("stderr") => eprint!("{}", text),
+                        _ => print!("{}", text),
+                    }
+                }
+                "queue-update" => {
+                    if let Some(position) = event.data["position"].as_u64().filter(|position| *position > 0) {
+                        eprintln!("Queued, position {}", position);
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let request = CompileRequest {
+        sketch_path: Some(sketch_path),
+        fqbn: flags.fqbn.clone(),
+        profile: flags.profile.clone(),
+        toolchain: flags.toolchain.clone(),
+        // Flashing writes the single image at offset 0
+        merge: flags.flash.is_some().then_some(true),
+        stream_output: Some(true),
+        ..Default::default()
+    };
+    let compiled = client.compile(&request).await;
+    printer.abort();
+    let response = match compiled {
+        Ok(response) => response,
+        Err(ClientError::Failed(response)) => {
+            print_diagnostics(&response);
+            return Err(response.error.unwrap_or_else(|| "Compile failed".to_string()));
+        }
+        Err(e) => {
+            return Err(e.to_string());
+        }
+    };
+    // A build reused from the cache has nothing to stream
+    if !quiet && !streamed.load(Ordering::Relaxed) {
+        print!("{}", response.output);
+    }
+    print_diagnostics(&response);
+    eprintln!("Compiled, build {}", response.build_id.as_deref().unwrap_or_default());
+
+    // Without an output folder, the image to flash is only kept until it is written
+    let temporary = (flags.output_dir.is_none() && flags.flash.is_some())
+        .then(|| std::env::temp_dir().join(format!("cloudc-{}", response.build_id.as_deref().unwrap_or("build"))));
+    let result = async {
+        let Some(dir) = flags.output_dir.as_ref().or(temporary.as_ref()) else {
+            return Ok(());
+        };
+        let written = download(&client, &response, dir).await?;
+        if flags.output_dir.is_some() {
+            for path in &written {
+                eprintln!("Wrote {}", path.display());
+            }
+        }
+        if let Some(port) = &flags.flash {
+            let image = written
+                .iter()
+                .find(|path| path.to_string_lossy().ends_with(".merged.bin"))
+                .ok_or("The build has no merged image to flash")?;
+            flash(&flags, port, image)?;
+            eprintln!("Flashed {}", port);
+        }
+        Ok(())
+    }.await;
+    if let Some(dir) = &temporary {
+        std::fs::remove_dir_all(dir).ok();
+    }
+    client.disconnect().await.ok();
+    result
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Flags::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("cloudc: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Writes the TypeScript types of the event contract, the request payloads and responses of
+// `models.rs`, as the `types/` package frontends depend on instead of hand-written interfaces.
+// Run `cargo run --bin export-types [dir]` after changing a model; `dir` defaults to `types`.
+use std::path::{ Path, PathBuf };
+use ts_rs::TS;
+use arduino_esp32_cloud_compiler::models::*;
+
+// Client events and the request each one takes, answered with a `CommandResponse`
+// (`CommandResponseV2` under protocol v2)
+const EVENTS: &[(&str, &str)] = &[
+    ("install-core", "InstallCoreRequest"),
+    ("compile-sketch", "CompileRequest"),
+    ("upload-sketch", "UploadRequest"),
+    ("chip-info", "EsptoolRequest"),
+    ("read-mac", "EsptoolRequest"),
+    ("erase-flash", "EsptoolRequest"),
+    ("reset-board", "ResetRequest"),
+    ("build-filesystem", "FilesystemRequest"),
+    ("generate-nvs", "NvsRequest"),
+    ("format-sketch", "FormatRequest"),
+    ("lint-project", "LintRequest"),
+    ("project-save", "ProjectRequest"),
+    ("project-search", "ProjectQuery"),
+    ("monitor-start", "MonitorRequest"),
+    ("cancel-job", "JobRequest"),
+    ("archive-begin", "TransferBeginRequest"),
+    ("archive-chunk", "TransferChunkRequest"),
+    ("archive-end", "ArchiveEndRequest"),
+    ("download-begin", "DownloadRequest"),
+    ("download-chunk", "DownloadChunkRequest"),
+    ("download-end", "TransferRequest"),
+    ("set-locale", "LocaleRequest"),
+    ("lsp-start", "IdeRequest"),
+    ("lsp-read-file", "LspFileRequest"),
+    ("list-examples", "ExamplesQuery"),
+    ("compile-example", "CompileExampleRequest"),
+    ("replay-build", "ReplayRequest"),
+    ("sketch-new", "SketchNewRequest"),
+    ("sketch-archive", "SketchArchiveRequest"),
+    ("profile-save", "ProfileRequest"),
+    ("generate-lockfile", "SketchRequest"),
+    ("preprocess-sketch", "SketchRequest"),
+    ("check-sketch", "SketchRequest"),
+    ("compilation-database", "IdeRequest"),
+    ("analyze-sketch", "AnalyzeRequest"),
+    ("power-cycle", "PowerCycleRequest"),
+    ("encryption-key-create", "EncryptionKeyRequest"),
+    ("encryption-key-import", "EncryptionKeyImportRequest"),
+    ("encryption-key-get", "EncryptionKeyRequest"),
+    ("encryption-key-delete", "EncryptionKeyRequest"),
+    ("signing-key-generate", "SigningKeyRequest"),
+    ("signing-key-import", "SigningKeyImportRequest"),
+    ("signing-key-delete", "SigningKeyRequest"),
+    ("project-get", "ProjectNameRequest"),
+    ("project-delete", "ProjectNameRequest"),
+    ("monitor-stop", "MonitorStopRequest"),
+    ("list-recordings", "RecordingsQuery"),
+    ("firmware-publish", "FirmwarePublishRequest"),
+    ("firmware-list", "ReleasesQuery"),
+    ("firmware-assign", "FirmwareAssignRequest"),
+    ("device-register", "DeviceRequest"),
+    ("device-list", "DeviceQuery"),
+    ("device-delete", "DeviceDeleteRequest"),
+    ("rollout-set", "RolloutRequest"),
+    ("rollout-status", "RolloutGroupRequest"),
+    ("rollout-delete", "RolloutGroupRequest"),
+];
+
+const HEADER: &str = "// Generated by `cargo run --bin export-types`, do not edit.\n";
+
+fn export(dir: &Path) -> Result<(), ts_rs::ExportError> {
+    // Everything they refer to (artifacts, diagnostics, files, ...) is written along with them
+    CommandResponse::export_all_to(dir)?;
+    CommandResponseV2::export_all_to(dir)?;
+    InstallCoreRequest::export_all_to(dir)?;
+    CompileRequest::export_all_to(dir)?;
+    UploadRequest::export_all_to(dir)?;
+    EsptoolRequest::export_all_to(dir)?;
+    ResetRequest::export_all_to(dir)?;
+    FilesystemRequest::export_all_to(dir)?;
+    NvsRequest::export_all_to(dir)?;
+    FormatRequest::export_all_to(dir)?;
+    LintRequest::export_all_to(dir)?;
+    ProjectRequest::export_all_to(dir)?;
+    ProjectQuery::export_all_to(dir)?;
+    MonitorRequest::export_all_to(dir)?;
+    JobRequest::export_all_to(dir)?;
+    TransferBeginRequest::export_all_to(dir)?;
+    TransferChunkRequest::export_all_to(dir)?;
+    ArchiveEndRequest::export_all_to(dir)?;
+    DownloadRequest::export_all_to(dir)?;
+    DownloadChunkRequest::export_all_to(dir)?;
+    TransferRequest::export_all_to(dir)?;
+    LocaleRequest::export_all_to(dir)?;
+    IdeRequest::export_all_to(dir)?;
+    LspFileRequest::export_all_to(dir)?;
+    ExamplesQuery::export_all_to(dir)?;
+    CompileExampleRequest::export_all_to(dir)?;
+    ReplayRequest::export_all_to(dir)?;
+    SketchNewRequest::export_all_to(dir)?;
+    SketchArchiveRequest::export_all_to(dir)?;
+    ProfileRequest::export_all_to(dir)?;
+    SketchRequest::export_all_to(dir)?;
+    AnalyzeRequest::export_all_to(dir)?;
+    PowerCycleRequest::export_all_to(dir)?;
+    EncryptionKeyRequest::export_all_to(dir)?;
+    EncryptionKeyImportRequest::export_all_to(dir)?;
+    SigningKeyRequest::export_all_to(dir)?;
+    SigningKeyImportRequest::export_all_to(dir)?;
+    ProjectNameRequest::export_all_to(dir)?;
+    MonitorStopRequest::export_all_to(dir)?;
+    RecordingsQuery::export_all_to(dir)?;
+    FirmwarePublishRequest::export_all_to(dir)?;
+    ReleasesQuery::export_all_to(dir)?;
+    FirmwareAssignRequest::export_all_to(dir)?;
+    DeviceRequest::export_all_to(dir)?;
+    DeviceQuery::export_all_to(dir)?;
+    DeviceDeleteRequest::export_all_to(dir)?;
+    RolloutRequest::export_all_to(dir)?;
+    RolloutGroupRequest::export_all_to(dir)?;
+    Ok(())
+}
+
+fn events() -> String {
+    let mut requests: Vec<&str> = EVENTS.iter().map(|(_, request)| *request).collect();
+    requests.sort();
+    requests.dedup();
+    let imports: String = requests
+        .iter()
+        .map(|request| format!("import type {{ {} }} from \"./{}\";\n", request, request))
+        .collect();
+    let entries: String = EVENTS.iter().map(|(event, request)| format!("  \"{}\": {};\n", event, request)).collect();
+    format!(
+        "{}{}import type {{ CommandResponse }} from \"./CommandResponse\";\n\n\
+         // Payload of each client event\n\
+         export interface ClientEvents {{\n{}}}\n\n\
+         export type ClientEvent = keyof ClientEvents;\n\n\
+         // Every client event is acknowledged with a response of this shape\n\
+         export type ClientEventResponse = CommandResponse;\n",
+        HEADER,
+        imports,
+        entries
+    )
+}
+
+// Modules under `dir`, as import paths relative to it
+fn modules(dir: &Path, prefix: &str) -> std::io::Result<Vec<String>> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_dir() {
+            found.extend(modules(&entry.path(), &format!("{}{}/", prefix, name))?);
+        } else if let Some(module) = name.strip_suffix(".ts") {
+            found.push(format!("{}{}", prefix, module));
+        }
+    }
+    Ok(found)
+}
+
+fn index(dir: &Path) -> std::io::Result<String> {
+    let mut modules = modules(dir, "")?;
+    modules.retain(|module| module != "index");
+    modules.sort();
+    let exports: String = modules.iter().map(|module| format!("export * from \"./{}\";\n", module)).collect();
+    Ok(format!("{}{}", HEADER, exports))
+}
+
+fn package() -> String {
+    format!(
+        r#"{{
+  "name": "arduino-esp32-cloud-compiler-types",
+  "version": "{}",
+  "description": "TypeScript types of the arduino-esp32-cloud-compiler Socket.IO events",
+  "license": "GPL-3.0",
+  "types": "index.ts",
+  "files": ["*.ts", "serde_json/*.ts"]
+}}
+"#,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let out = std::env::args_os().nth(1).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("types"));
+    // Types a model no longer has must not linger
+    if out.is_dir() {
+        for module in modules(&out, "")? {
+            std::fs::remove_file(out.join(format!("{}.ts", module)))?;
+        }
+    }
+    std::fs::create_dir_all(&out)?;
+    export(&out)?;
+    std::fs::write(out.join("events.ts"), events())?;
+    std::fs::write(out.join("index.ts"), index(&out)?)?;
+    std::fs::write(out.join("package.json"), package())?;
+    println!("Wrote the TypeScript types to {}", out.display());
+    Ok(())
+}
@@ -0,0 +1,298 @@
+// Shared blob store for caches, so several server instances reuse each other's compile results
+// and downloaded core archives. Blobs are content addressed (the key is a hash of what they were
+// built from, or the archive's own name), and both backends make a write visible only once it
+// is complete.
+//
+// CLOUD_COMPILER_CACHE_BACKEND=local|redis|s3 picks the backend, `local` keeps everything in the
+// data directory of this instance.
+//   redis: CLOUD_COMPILER_CACHE_REDIS_URL (`redis://host:6379/0`)
+//   s3:    CLOUD_COMPILER_CACHE_S3_BUCKET, CLOUD_COMPILER_CACHE_S3_REGION (default us-east-1),
+//          CLOUD_COMPILER_CACHE_S3_ENDPOINT (default AWS, set for MinIO and friends),
+//          CLOUD_COMPILER_CACHE_S3_PREFIX, AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+use std::sync::LazyLock;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use hmac::{ Hmac, Mac };
+use sha2::{ Digest, Sha256 };
+use tracing::{ info, warn };
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct S3Store {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    http: reqwest::Client,
+}
+
+pub enum BlobStore {
+    Redis(redis::Client),
+    S3(S3Store),
+}
+
+static STORE: LazyLock<Option<BlobStore>> = LazyLock::new(|| {
+    let backend = std::env::var("CLOUD_COMPILER_CACHE_BACKEND").unwrap_or_default().trim().to_lowercase();
+    let setting = |name: &str| std::env::var(name).ok().filter(|value| !value.trim().is_empty());
+    let store = match backend.as_str() {
+        "" | "local" => None,
+        "redis" => {
+            let url = setting("CLOUD_COMPILER_CACHE_REDIS_URL").unwrap_or_else(|| "redis://127.0.0.1/".to_string());
+            match redis::Client::open(url) {
+                Ok(client) => Some(BlobStore::Redis(client)),
+                Err(e) => {
+                    warn!("Invalid Redis URL, using the local cache: {}", e);
+                    None
+                }
+            }
+        }
+        "s3" => {
+            let store = S3Store::from_settings("CLOUD_COMPILER_CACHE_S3", "us-east-1", None);
+            if store.is_none() {
+                warn!("CLOUD_COMPILER_CACHE_S3_BUCKET is not set, using the local cache");
+            }
+            store.map(BlobStore::S3)
+        }
+        other => {
+            warn!("Unknown cache backend {}, using the local cache", other);
+            None
+        }
+    };
+    if let Some(store) = &store {
+        info!("Shared cache: {}", store.name());
+    }
+    store
+});
+
+// The shared store, None when caches stay local
+pub fn shared_store() -> Option<&'static BlobStore> {
+    STORE.as_ref()
+}
+
+impl BlobStore {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlobStore::Redis(_) => "redis",
+            BlobStore::S3(_) => "s3",
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            BlobStore::Redis(client) => {
+                let mut connection = client.get_multiplexed_async_connection().await.map_err(|e| e.to_string())?;
+                redis::cmd("GET").arg(key).query_async(&mut connection).await.map_err(|e| e.to_string())
+            }
+            BlobStore::S3(s3) => s3.get(key).await,
+        }
+    }
+
+    // Store `bytes` under `key`, expiring after `ttl` where the backend supports it (S3 buckets
+    // expire objects through lifecycle rules instead)
+    pub async fn put(&self, key: &str, bytes: Vec<u8>, ttl: Option<Duration>) -> Result<(), String> {
+        match self {
+            BlobStore::Redis(client) => {
+                let mut connection = client.get_multiplexed_async_connection().await.map_err(|e| e.to_string())?;
+                let mut command = redis::cmd("SET");
+                command.arg(key).arg(bytes);
+                if let Some(ttl) = ttl {
+                    command.arg("EX").arg(ttl.as_secs().max(1));
+                }
+                command.query_async::<()>(&mut connection).await.map_err(|e| e.to_string())
+            }
+            BlobStore::S3(s3) => s3.put(key, bytes).await,
+        }
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool, String> {
+        match self {
+            BlobStore::Redis(client) => {
+                let mut connection = client.get_multiplexed_async_connection().await.map_err(|e| e.to_string())?;
+                redis::cmd("EXISTS").arg(key).query_async(&mut connection).await.map_err(|e| e.to_string())
+            }
+            BlobStore::S3(s3) => s3.head(key).await,
+        }
+    }
+}
+
+fn hex_sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// (`20240131T120000Z`, `20240131`) for a unix time, in UTC
+fn amz_dates(secs: u64) -> (String, String) {
+    let days = (secs / 86400) as i64;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    (format!("{}T{:02}{:02}{:02}Z", date, hour, minute, second), date)
+}
+
+impl S3Store {
+    // Store configured by `<settings>_BUCKET`, `_REGION`, `_ENDPOINT` and `_PREFIX` plus the AWS
+    // credentials, None without a bucket. Without an endpoint it talks to AWS in the region.
+    pub fn from_settings(settings: &str, default_region: &str, default_endpoint: Option<&str>) -> Option<S3Store> {
+        let setting = |name: &str| std::env::var(name).ok().filter(|value| !value.trim().is_empty());
+        let bucket = setting(&format!("{}_BUCKET", settings))?;
+        let region = setting(&format!("{}_REGION", settings)).unwrap_or_else(|| default_region.to_string());
+        let endpoint = setting(&format!("{}_ENDPOINT", settings))
+            .or_else(|| default_endpoint.map(String::from))
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        Some(S3Store {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket,
+            region,
+            prefix: setting(&format!("{}_PREFIX", settings)).unwrap_or_default(),
+            access_key: setting("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+            secret_key: setting("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+            http: reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap_or_default(),
+        })
+    }
+
+    fn host(&self) -> String {
+        self.endpoint.split("://").nth(1).unwrap_or(&self.endpoint).to_string()
+    }
+
+    // Key signing requests of `date` (`20240131`)
+    fn signing_key(&self, date: &str) -> Vec<u8> {
+        let mut key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date);
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part);
+        }
+        key
+    }
+
+    // Path-style URL and canonical path of an object
+    fn object(&self, key: &str) -> (String, String) {
+        let encoded: String = format!("{}{}", self.prefix, key)
+            .split('/')
+            .map(percent_encode)
+            .collect::<Vec<_>>()
+            .join("/");
+        let path = format!("/{}/{}", self.bucket, encoded);
+        (format!("{}{}", self.endpoint, path), path)
+    }
+
+    // AWS Signature Version 4 headers for a request without query parameters
+    fn sign(&self, method: &str, path: &str, payload: &[u8]) -> Vec<(String, String)> {
+        let host = self.host();
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let (timestamp, date) = amz_dates(secs);
+        let payload_hash = hex_sha256(payload);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            host,
+            payload_hash,
+            timestamp,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, hex_sha256(canonical.as_bytes()));
+        let signature = hmac_sha256(&self.signing_key(&date), &string_to_sign)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        vec![
+            ("x-amz-date".to_string(), timestamp),
+            ("x-amz-content-sha256".to_string(), payload_hash),
+            (
+                "authorization".to_string(),
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key,
+                    scope,
+                    signed_headers,
+                    signature
+                ),
+            )
+        ]
+    }
+
+    // Presigned GET URL of an object (SigV4 query signing), valid for `expires_in`
+    pub fn presign_get(&self, key: &str, expires_in: Duration) -> String {
+        let (url, path) = self.object(key);
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let (timestamp, date) = amz_dates(secs);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        // Already in canonical (sorted) order
+        let query = format!(
+            "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            percent_encode(&format!("{}/{}", self.access_key, scope)),
+            timestamp,
+            expires_in.as_secs().clamp(1, 604800)
+        );
+        let canonical = format!("GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", path, query, self.host());
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, hex_sha256(canonical.as_bytes()));
+        let signature = hmac_sha256(&self.signing_key(&date), &string_to_sign)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("{}?{}&X-Amz-Signature={}", url, query, signature)
+    }
+
+    async fn request(&self, method: reqwest::Method, key: &str, body: Vec<u8>) -> Result<reqwest::Response, String> {
+        let (url, path) = self.object(key);
+        let mut request = self.http.request(method.clone(), url);
+        for (name, value) in self.sign(method.as_str(), &path, &body) {
+            request = request.header(name, value);
+        }
+        request.body(body).send().await.map_err(|e| e.to_string())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let response = self.request(reqwest::Method::GET, key, Vec::new()).await?;
+        match response.status() {
+            status if status.is_success() => Ok(Some(response.bytes().await.map_err(|e| e.to_string())?.to_vec())),
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            status => Err(format!("S3 GET {}: {}", key, status)),
+        }
+    }
+
+    pub async fn head(&self, key: &str) -> Result<bool, String> {
+        let response = self.request(reqwest::Method::HEAD, key, Vec::new()).await?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            status => Err(format!("S3 HEAD {}: {}", key, status)),
+        }
+    }
+
+    pub async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), String> {
+        let response = self.request(reqwest::Method::PUT, key, bytes).await?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("S3 PUT {}: {}", key, response.status())),
+        }
+    }
+}
+
+// URI-encode a path segment the way SigV4 expects (unreserved characters stay)
+fn percent_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,133 @@
+// arduino-cli bootstrap. Builds without the embedded binary (`--no-default-features`) and no
+// CLOUD_COMPILER_ARDUINO_CLI look for a previous install in `<data dir>/bin`; if there is none,
+// the pinned arduino-cli release for this OS and architecture is downloaded there, its checksum
+// verified and the package index fetched, so the server deploys from a bare binary.
+//
+// The archive is checked against CLOUD_COMPILER_ARDUINO_CLI_SHA256 when set, otherwise against
+// the checksums published with the release.
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use sha2::{ Digest, Sha256 };
+use tracing::info;
+use crate::compiler::{ get_arduino_cli_path, server_data_dir };
+
+pub const ARDUINO_CLI_VERSION: &str = "1.2.2";
+const DOWNLOAD_URL: &str = "https://downloads.arduino.cc/arduino-cli";
+
+#[cfg(windows)]
+const BINARY_NAME: &str = "arduino-cli.exe";
+#[cfg(not(windows))]
+const BINARY_NAME: &str = "arduino-cli";
+
+// Where a bootstrapped arduino-cli lives
+pub fn installed_path() -> PathBuf {
+    server_data_dir().join("bin").join(BINARY_NAME)
+}
+
+// Release archive for this platform, None where arduino-cli publishes no build
+fn release_asset() -> Option<String> {
+    let platform = match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => "Linux_64bit.tar.gz",
+        ("linux", "x86") => "Linux_32bit.tar.gz",
+        ("linux", "aarch64") => "Linux_ARM64.tar.gz",
+        ("linux", "arm") => "Linux_ARMv7.tar.gz",
+        ("macos", "x86_64") => "macOS_64bit.tar.gz",
+        ("macos", "aarch64") => "macOS_ARM64.tar.gz",
+        ("windows", "x86_64") => "Windows_64bit.zip",
+        ("windows", "x86") => "Windows_32bit.zip",
+        _ => {
+            return None;
+        }
+    };
+    Some(format!("arduino-cli_{}_{}", ARDUINO_CLI_VERSION, platform))
+}
+
+async fn download(http: &reqwest::Client, url: &str) -> Result<Vec<u8>, String> {
+    let response = http.get(url).send().await.map_err(|e| format!("Failed to download {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to download {}: {}", url, response.status()));
+    }
+    response.bytes().await.map(|bytes| bytes.to_vec()).map_err(|e| format!("Failed to download {}: {}", url, e))
+}
+
+// Expected SHA-256 of `asset`, pinned or from the release's checksum list
+async fn expected_checksum(http: &reqwest::Client, asset: &str) -> Result<String, String> {
+    if let Ok(pinned) = std::env::var("CLOUD_COMPILER_ARDUINO_CLI_SHA256") && !pinned.trim().is_empty() {
+        return Ok(pinned.trim().to_lowercase());
+    }
+    let url = format!("{}/arduino-cli_{}_checksums.txt", DOWNLOAD_URL, ARDUINO_CLI_VERSION);
+    let list = String::from_utf8_lossy(&download(http, &url).await?).to_string();
+    list.lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, name)| name.trim() == asset)
+        .map(|(sum, _)| sum.to_lowercase())
+        .ok_or_else(|| format!("No checksum published for {}", asset))
+}
+
+// The arduino-cli binary inside a release archive
+fn extract_binary(asset: &str, archive: &[u8]) -> Result<Vec<u8>, String> {
+    let mut binary = Vec::new();
+    if asset.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive)).map_err(|e| e.to_string())?;
+        zip.by_name(BINARY_NAME)
+            .map_err(|e| e.to_string())?
+            .read_to_end(&mut binary)
+            .map_err(|e| e.to_string())?;
+        return Ok(binary);
+    }
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    for entry in tar.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        if entry.path().map_err(|e| e.to_string())?.file_name() == Some(BINARY_NAME.as_ref()) {
+            entry.read_to_end(&mut binary).map_err(|e| e.to_string())?;
+            return Ok(binary);
+        }
+    }
+    Err(format!("{} not found in {}", BINARY_NAME, asset))
+}
+
+fn install(path: &Path, binary: &[u8]) -> Result<(), String> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    // Written aside and renamed, so a half-written binary is never picked up
+    let partial = dir.join(format!(".{}.partial", BINARY_NAME));
+    std::fs::write(&partial, binary).map_err(|e| e.to_string())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&partial, std::fs::Permissions::from_mode(0o755)).map_err(|e| e.to_string())?;
+    }
+    std::fs::rename(&partial, path).map_err(|e| e.to_string())
+}
+
+// Make sure an arduino-cli is available, installing the pinned release if none is
+pub async fn ensure_arduino_cli() -> Result<(), String> {
+    let path = get_arduino_cli_path();
+    if path.is_file() {
+        return Ok(());
+    }
+    let asset = release_asset().ok_or_else(|| {
+        format!("No arduino-cli release for {} {}", std::env::consts::OS, std::env::consts::ARCH)
+    })?;
+    info!("arduino-cli not found, installing {} to {}", asset, path.display());
+
+    let http = reqwest::Client::new();
+    let expected = expected_checksum(&http, &asset).await?;
+    let archive = download(&http, &format!("{}/{}", DOWNLOAD_URL, asset)).await?;
+    let actual = format!("{:x}", Sha256::digest(&archive));
+    if actual != expected {
+        return Err(format!("Checksum mismatch for {}: expected {}, got {}", asset, expected, actual));
+    }
+    install(path, &extract_binary(&asset, &archive)?)?;
+
+    let status = tokio::process::Command
+        ::new(path)
+        .args(["core", "update-index"])
+        .status().await
+        .map_err(|e| format!("Failed to run arduino-cli: {}", e))?;
+    if !status.success() {
+        return Err(format!("arduino-cli core update-index failed ({})", status));
+    }
+    info!("Installed arduino-cli {}", ARDUINO_CLI_VERSION);
+    Ok(())
+}
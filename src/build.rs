@@ -0,0 +1,271 @@
+use std::path::{ Path, PathBuf };
+use serde_json::Value;
+use crate::models::*;
+use crate::artifacts::find_app_binary;
+use crate::cache;
+use crate::encryption;
+use crate::esptool::merge_binaries;
+use crate::files::{ copy_dir, TempTree };
+use crate::patches::{ self, Patch };
+use crate::psram;
+use crate::secrets::{ self, Secrets };
+use crate::templates::{ self, Variables };
+use crate::signing::{ sign_app_binary, SigningKey };
+use crate::compiler::{ build_properties, compiler_diagnostics, memory_usage, server_data_dir, sketch_dir };
+use crate::teaching::explain;
+use crate::partitions::{ parse_number, parse_partition_csv };
+
+// Optional steps run after a compile
+#[derive(Default)]
+pub struct BuildOptions {
+    // Project whose key encrypts the application image
+    pub encrypt: Option<String>,
+    // Produce a single image to flash at offset 0x0
+    pub merge: bool,
+    // Target board and sketch, used by the advisory checks
+    pub fqbn: Option<String>,
+    pub sketch_path: String,
+    // Custom partition table linked into this build
+    pub partitions_csv: Option<String>,
+    // Secure Boot V2 signing key
+    pub sign: Option<SigningKey>,
+    // Keep objects and intermediate files in the build directory for download
+    pub keep_build_dir: bool,
+    // Add beginner-friendly explanations of compile errors
+    pub teaching: bool,
+    // Answer with the time spent in each phase of the compile
+    pub timings: bool,
+    // Profile of the sketch's `sketch.yaml` to build with
+    pub profile: Option<String>,
+    // Library folders compiled with `--library` ahead of the installed ones, set by the server
+    // for pinned versions, never from the request
+    pub libraries: Vec<String>,
+    // Written into `secrets.h` for the build
+    pub secrets: Secrets,
+    // Filled into the `{{name}}` placeholders of the sources
+    pub variables: Variables,
+    // Written into the placeholder blocks of the app image after the compile
+    pub patches: Vec<Patch>,
+}
+
+impl BuildOptions {
+    pub fn from_request(data: &Value) -> Self {
+        BuildOptions {
+            encrypt: data.get("encrypt").and_then(|v| v.as_str()).map(String::from),
+            merge: data.get("merge").and_then(|v| v.as_bool()).unwrap_or(false),
+            fqbn: data.get("fqbn").and_then(|v| v.as_str()).map(String::from),
+            sketch_path: data.get("sketch_path").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+            partitions_csv: data.get("partitions_csv").and_then(|v| v.as_str()).map(String::from),
+            sign: data.get("sign").and_then(|v| serde_json::from_value(v.clone()).ok()),
+            keep_build_dir: data.get("keep_build_dir").and_then(|v| v.as_bool()).unwrap_or(false),
+            teaching: data.get("teaching").and_then(|v| v.as_bool()).unwrap_or(false),
+            timings: data.get("timings").and_then(|v| v.as_bool()).unwrap_or(false),
+            profile: data.get("profile").and_then(|v| v.as_str()).filter(|p| !p.is_empty()).map(String::from),
+            libraries: Vec::new(),
+            secrets: Secrets::new(),
+            variables: Variables::new(),
+            patches: Vec::new(),
+        }
+    }
+
+    // `from_request` with the inputs that can be invalid: secrets, template variables and patches
+    pub fn validated(data: &Value) -> Result<Self, String> {
+        let mut options = BuildOptions::from_request(data);
+        options.secrets = secrets::from_request(data)?;
+        options.variables = templates::from_request(data)?;
+        options.patches = patches::from_request(data)?;
+        // A signature or encryption covers the image as compiled
+        if !options.patches.is_empty() && (options.sign.is_some() || options.encrypt.is_some()) {
+            return Err("patches can't be combined with sign or encrypt".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// Where builds that change the sketch's files compile from, one private copy per build
+fn copies_root() -> PathBuf {
+    server_data_dir().join("sketches")
+}
+
+// Copies left behind by a crash or a hard shutdown
+pub fn remove_stale_copies() {
+    let _ = std::fs::remove_dir_all(copies_root());
+}
+
+// The sketch a build compiles, a private copy removed on drop once the request changes any of
+// its files, the client's folder is never written
+pub struct PreparedSketch {
+    copy: Option<TempTree>,
+    sketch_path: PathBuf,
+}
+
+impl PreparedSketch {
+    pub fn sketch_path(&self) -> &Path {
+        &self.sketch_path
+    }
+
+    // `command` with its sketch operand pointed at the copy
+    pub fn command(&self, command: &ArduinoCommand, options: &BuildOptions) -> ArduinoCommand {
+        let mut args = command.args.clone();
+        if self.copy.is_some() && let Some(operand) = args.last_mut() && *operand == options.sketch_path {
+            *operand = self.sketch_path.to_string_lossy().to_string();
+        }
+        ArduinoCommand { command: command.command.clone(), args }
+    }
+
+    // Paths into the copy in the response, moved back to the client's sketch folder
+    pub fn relocate(&self, response: &mut CommandResponse, options: &BuildOptions) {
+        let Some(copy) = &self.copy else {
+            return;
+        };
+        let original = sketch_dir(Path::new(&options.sketch_path)).to_string_lossy().to_string();
+        cache::relocate(response, &[(&copy.tree.to_string_lossy(), &original)]);
+    }
+}
+
+// Copy the sketch folder for a build that changes its files
+fn copy_sketch(sketch_path: &Path) -> Result<(TempTree, PathBuf), String> {
+    let folder = sketch_dir(sketch_path);
+    let name = folder.file_name().ok_or_else(|| format!("Invalid sketch path {}", sketch_path.display()))?;
+    let dir = copies_root().join(uuid::Uuid::new_v4().to_string());
+    let copy = TempTree { tree: dir.join(name), dir };
+    copy_dir(folder, &copy.tree).map_err(|e| format!("Failed to copy the sketch: {}", e))?;
+    let path = match sketch_path.is_dir() {
+        true => copy.tree.clone(),
+        false => copy.tree.join(sketch_path.file_name().unwrap_or(name)),
+    };
+    Ok((copy, path))
+}
+
+// "4MB" -> 4194304
+fn parse_flash_size(value: &str) -> Option<u32> {
+    parse_number(value.trim_end_matches(['B', 'b']))
+}
+
+// Validate the custom partition table against the board's flash size
+async fn validate_partitions(csv: &str, options: &BuildOptions) -> Result<(), String> {
+    let flash_size = match &options.fqbn {
+        Some(fqbn) =>
+            build_properties(fqbn, &options.sketch_path).await
+                .ok()
+                .and_then(|properties| properties.get("build.flash_size").and_then(|v| parse_flash_size(v))),
+        None => None,
+    };
+    parse_partition_csv(csv, flash_size).map(|_| ())
+}
+
+// The header goes only into the build's own copy, readable by the server user alone
+fn write_secrets(copy: &TempTree, values: &Secrets) -> std::io::Result<()> {
+    let path = copy.tree.join(secrets::HEADER_FILE);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&copy.dir, std::fs::Permissions::from_mode(0o700))?;
+        std::fs::write(&path, "")?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::fs::write(&path, secrets::header(values))
+}
+
+// Put request-supplied files in place before compiling. The esp32 core links against a
+// `partitions.csv` found in the sketch folder instead of the board's partition scheme, template
+// variables are filled into the sources and secrets go into a `secrets.h` next to the sketch.
+// All of them are written into a private copy of the sketch, concurrent builds of the same
+// folder and the cache key of its tree never see them.
+pub async fn prepare(options: &BuildOptions) -> Result<PreparedSketch, String> {
+    let sketch_path = PathBuf::from(&options.sketch_path);
+    // A kept build directory would hand out the secrets with the intermediate files
+    if !options.secrets.is_empty() && options.keep_build_dir {
+        return Err("secrets can't be combined with keep_build_dir".to_string());
+    }
+    if options.partitions_csv.is_none() && options.variables.is_empty() && options.secrets.is_empty() {
+        return Ok(PreparedSketch { copy: None, sketch_path });
+    }
+
+    if let Some(csv) = &options.partitions_csv {
+        validate_partitions(csv, options).await.map_err(|e| format!("Invalid partition table: {}", e))?;
+    }
+    let (copy, copied_path) = copy_sketch(&sketch_path)?;
+    let dir = copy.tree.as_path();
+
+    if let Some(csv) = &options.partitions_csv {
+        std::fs::write(dir.join("partitions.csv"), csv).map_err(|e| format!("Failed to write partitions.csv: {}", e))?;
+    }
+
+    if !options.variables.is_empty() {
+        let mut unresolved = Vec::new();
+        for path in templates::sources(dir) {
+            let Ok(text) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let (filled, missing) = templates::substitute(&text, &options.variables);
+            let file = path.strip_prefix(dir).unwrap_or(&path).display().to_string();
+            unresolved.extend(missing.into_iter().map(|(name, line)| format!("{} ({}:{})", name, file, line)));
+            if filled != text {
+                std::fs::write(&path, filled).map_err(|e| format!("Failed to write {}: {}", file, e))?;
+            }
+        }
+        if !unresolved.is_empty() {
+            return Err(format!("Unresolved placeholders: {}", unresolved.join(", ")));
+        }
+    }
+
+    if !options.secrets.is_empty() {
+        write_secrets(&copy, &options.secrets).map_err(|e| format!("Failed to write {}: {}", secrets::HEADER_FILE, e))?;
+    }
+
+    Ok(PreparedSketch { copy: Some(copy), sketch_path: copied_path })
+}
+
+// Encrypt the app image of a finished build into `<name>.enc.bin`, the signed image
+// when there is one so the device can verify it after decrypting
+fn encrypt_app_binary(project: &str, owner: Option<&str>, build_dir: &Path) -> Result<(), String> {
+    let app = find_app_binary(build_dir).ok_or("No application binary produced")?;
+    let signed = app.with_extension("signed.bin");
+    let input = if signed.is_file() { signed } else { app.clone() };
+    encryption::encrypt_image(owner, project, &input, &app.with_extension("enc.bin"))
+}
+
+// Run the requested post-compile steps, a failing step fails the whole response. Stored keys are
+// looked up among `owner`'s.
+pub async fn post_process(response: &mut CommandResponse, build_dir: &Path, options: &BuildOptions, owner: Option<&str>) {
+    let compiler = compiler_diagnostics(response.error.as_deref().unwrap_or_default());
+    if options.teaching && !response.success {
+        response.explanations = explain(&compiler, response.error.as_deref());
+    }
+    response.diagnostics.extend(compiler);
+
+    if !response.success {
+        return;
+    }
+    response.size = memory_usage(&response.output);
+
+    if let Some(fqbn) = options.fqbn.as_deref().filter(|fqbn| fqbn.starts_with("esp32:")) {
+        response.diagnostics.extend(psram::check(build_dir, &options.sketch_path, fqbn).await);
+    }
+
+    if let Some(key) = &options.sign && let Err(e) = sign_app_binary(key, owner, build_dir).await {
+        response.success = false;
+        response.error = Some(format!("Signing failed: {}", e));
+        return;
+    }
+
+    if options.merge {
+        let merged = merge_binaries(build_dir).await;
+        if !merged.success {
+            response.success = false;
+            response.error = Some(
+                format!("Merging binaries failed: {}", merged.error.unwrap_or_default())
+            );
+            return;
+        }
+    }
+
+    if
+        let Some(project) = &options.encrypt &&
+        let Err(e) = encrypt_app_binary(project, owner, build_dir)
+    {
+        response.success = false;
+        response.error = Some(format!("Encryption failed: {}", e));
+    }
+}
@@ -0,0 +1,646 @@
+// Compile result cache. Identical requests (same sketch sources, board, options and installed
+// cores and libraries) are answered with a copy of an earlier successful build instead of being
+// compiled again.
+//
+// Entries live in `<data dir>/cache/<key>/` (the artifacts plus `entry.json`) and expire after
+// CLOUD_COMPILER_CACHE_TTL_SECS (default a day). CLOUD_COMPILER_CACHE_MAX_MB bounds the whole
+// store (default 1024), least recently used entries are evicted first; 0 disables the cache.
+//
+// With a shared blob store configured, entries are also published there as ZIPs and fetched on
+// a local miss, and core archives arduino-cli downloads are shared between instances.
+//
+// Identical requests arriving while the first is still compiling wait for it and get a copy of
+// its result, failed or not, instead of compiling the same thing again.
+use std::collections::{ BTreeSet, HashMap, HashSet };
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, LazyLock, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use tokio::sync::oneshot;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use utoipa::ToSchema;
+use sha2::{ Digest, Sha256 };
+use tracing::warn;
+use crate::models::CommandResponse;
+use crate::blobstore::{ shared_store, BlobStore };
+use crate::build::BuildOptions;
+use crate::compiler::{ arduino_data_dir, arduino_user_dir, is_safe_name, server_data_dir, sketch_dir };
+use crate::files::{ extract_zip, MAX_FILES, MAX_TOTAL_BYTES };
+use crate::sessions::is_guest_build;
+
+const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_MB: u64 = 1024;
+const ENTRY_FILE: &str = "entry.json";
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    created_at: u64,
+    used_at: u64,
+    size: u64,
+    // Directories the response was produced for, replaced with the new ones on a hit
+    build_dir: String,
+    sketch_dir: String,
+    response: CommandResponse,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn read_setting(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|value| value.trim().parse().ok()).unwrap_or(default)
+}
+
+fn max_bytes() -> u64 {
+    read_setting("CLOUD_COMPILER_CACHE_MAX_MB", DEFAULT_MAX_MB) * 1024 * 1024
+}
+
+fn ttl() -> u64 {
+    read_setting("CLOUD_COMPILER_CACHE_TTL_SECS", DEFAULT_TTL_SECS)
+}
+
+// Local directory of cached builds and shared core archives
+pub fn cache_root() -> PathBuf {
+    server_data_dir().join("cache")
+}
+
+// Feed every file below `dir` into the hash in a stable order, skipping dotfiles. Fails past
+// the upload limits so huge trees are compiled instead of hashed.
+fn hash_tree(hasher: &mut Sha256, dir: &Path, budget: &mut (usize, u64)) -> Option<()> {
+    let mut entries: Vec<PathBuf> = std::fs
+        ::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|path| !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            hasher.update(b"dir\0");
+            hasher.update(path.file_name()?.as_encoded_bytes());
+            hash_tree(hasher, &path, budget)?;
+            continue;
+        }
+        let bytes = std::fs::read(&path).ok()?;
+        budget.0 += 1;
+        budget.1 += bytes.len() as u64;
+        if budget.0 > MAX_FILES || budget.1 > MAX_TOTAL_BYTES {
+            return None;
+        }
+        hasher.update(b"file\0");
+        hasher.update(path.file_name()?.as_encoded_bytes());
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(&bytes);
+    }
+    Some(())
+}
+
+// Installed core and tool versions (`packages/<vendor>/<hardware|tools>/<name>/<version>`) and
+// library versions, any change invalidates earlier results
+fn toolchain_fingerprint(hasher: &mut Sha256) {
+    let sorted = |dir: &Path| -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = std::fs
+            ::read_dir(dir)
+            .map(|entries| entries.filter_map(|e| e.ok().map(|e| e.path())).collect())
+            .unwrap_or_default();
+        paths.sort();
+        paths
+    };
+    for vendor in sorted(&arduino_data_dir().join("packages")) {
+        for kind in ["hardware", "tools"] {
+            for name in sorted(&vendor.join(kind)) {
+                for version in sorted(&name) {
+                    hasher.update(version.to_string_lossy().as_bytes());
+                    hasher.update(b"\0");
+                }
+            }
+        }
+    }
+    for library in sorted(&arduino_user_dir().join("libraries")) {
+        hasher.update(library.to_string_lossy().as_bytes());
+        if let Ok(properties) = std::fs::read_to_string(library.join("library.properties")) {
+            hasher.update(properties.as_bytes());
+        }
+    }
+}
+
+// Key of a compile, None when it must not be cached: keys for signing or encryption are not
+// part of the key, kept build directories are too large to duplicate, and binaries built with
+// secrets must not be shared
+pub fn cache_key(options: &BuildOptions) -> Option<String> {
+    if max_bytes() == 0 || options.sign.is_some() || options.encrypt.is_some() || options.keep_build_dir || !options.secrets.is_empty() {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    let settings = serde_json::json!({
+        "fqbn": options.fqbn,
+        "merge": options.merge,
+        "partitions_csv": options.partitions_csv,
+        "teaching": options.teaching,
+        "profile": options.profile,
+        "libraries": options.libraries,
+        "variables": options.variables,
+        "sketch": Path::new(&options.sketch_path).file_name().map(|name| name.to_string_lossy()),
+    });
+    hasher.update(settings.to_string().as_bytes());
+    hash_tree(&mut hasher, sketch_dir(Path::new(&options.sketch_path)), &mut (0, 0))?;
+    toolchain_fingerprint(&mut hasher);
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+fn read_entry(dir: &Path) -> Option<Entry> {
+    serde_json::from_slice(&std::fs::read(dir.join(ENTRY_FILE)).ok()?).ok()
+}
+
+// Copy the build outputs (plain files, no server bookkeeping) from `from` to `to`
+fn copy_outputs(from: &Path, to: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(from)?.filter_map(|e| e.ok()) {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') || name == ENTRY_FILE || !entry.file_type()?.is_file() {
+            continue;
+        }
+        size += std::fs::copy(entry.path(), to.join(&name))?;
+    }
+    Ok(size)
+}
+
+// Paths in the output, stderr, arguments and diagnostics of a response, moved to other directories
+pub fn relocate(response: &mut CommandResponse, moves: &[(&str, &str)]) {
+    let apply = |text: &mut String| {
+        for (from, to) in moves {
+            if !from.is_empty() {
+                *text = text.replace(from, to);
+            }
+        }
+    };
+    apply(&mut response.output);
+    response.error.iter_mut().for_each(apply);
+    response.stderr.iter_mut().for_each(apply);
+    response.args.iter_mut().for_each(apply);
+    response.diagnostics.iter_mut().filter_map(|d| d.file.as_mut()).for_each(apply);
+    response.explanations.iter_mut().filter_map(|e| e.file.as_mut()).for_each(apply);
+}
+
+fn sketch_dir_of(options: &BuildOptions) -> String {
+    sketch_dir(Path::new(&options.sketch_path)).to_string_lossy().to_string()
+}
+
+// A finished compile, handed to the identical requests that waited for it
+struct Landed {
+    response: CommandResponse,
+    build_dir: PathBuf,
+    sketch_dir: String,
+}
+
+// Followers waiting on each running compile, by cache key
+type Followers = Vec<oneshot::Sender<Arc<Landed>>>;
+
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, Followers>>> = LazyLock::new(||
+    Mutex::new(HashMap::new())
+);
+
+pub enum Flight {
+    // Nobody compiles this yet: compile, then `land` the result
+    Leader(Leader),
+    // Another request compiles the same thing, wait for its result
+    Follower(Follower),
+}
+
+pub struct Leader {
+    key: String,
+}
+
+pub struct Follower {
+    landed: oneshot::Receiver<Arc<Landed>>,
+}
+
+// Join the compile of `key` already running, or become the one running it
+pub fn coalesce(key: &str) -> Flight {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    match in_flight.get_mut(key) {
+        Some(followers) => {
+            let (sender, landed) = oneshot::channel();
+            followers.push(sender);
+            Flight::Follower(Follower { landed })
+        }
+        None => {
+            in_flight.insert(key.to_string(), Vec::new());
+            Flight::Leader(Leader { key: key.to_string() })
+        }
+    }
+}
+
+impl Leader {
+    // Fan the result out to every request that waited for it
+    pub fn land(self, response: &CommandResponse, build_dir: &Path, options: &BuildOptions) {
+        let followers = IN_FLIGHT.lock().unwrap().remove(&self.key).unwrap_or_default();
+        let landed = Arc::new(Landed {
+            response: response.clone(),
+            build_dir: build_dir.to_path_buf(),
+            sketch_dir: sketch_dir_of(options),
+        });
+        for follower in followers {
+            follower.send(landed.clone()).ok();
+        }
+    }
+}
+
+// A leader that never lands (cancelled) releases its followers, they compile themselves
+impl Drop for Leader {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Follower {
+    // The leader's result copied into `build_dir`, None when the leader gave up
+    pub async fn result(self, build_dir: &Path, options: &BuildOptions) -> Option<CommandResponse> {
+        let landed = self.landed.await.ok()?;
+        copy_outputs(&landed.build_dir, build_dir).ok()?;
+        let mut response = landed.response.clone();
+        let build_dir = build_dir.to_string_lossy();
+        let leader_dir = landed.build_dir.to_string_lossy();
+        let sketch_dir = sketch_dir_of(options);
+        relocate(&mut response, &[(&leader_dir, &build_dir), (&landed.sketch_dir, &sketch_dir)]);
+        Some(response)
+    }
+}
+
+// Zip of an entry directory, the form entries take in the shared store
+fn pack(dir: &Path) -> Result<Vec<u8>, String> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for entry in std::fs::read_dir(dir).map_err(|e| e.to_string())?.filter_map(|e| e.ok()) {
+        if !entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        zip.start_file(entry.file_name().to_string_lossy(), options).map_err(|e| e.to_string())?;
+        zip.write_all(&std::fs::read(entry.path()).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    }
+    Ok(zip.finish().map_err(|e| e.to_string())?.into_inner())
+}
+
+// Pull an entry another instance stored into the local cache
+async fn fetch_shared(store: &BlobStore, key: &str) {
+    let bytes = match store.get(&format!("compile/{}", key)).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => {
+            return;
+        }
+        Err(e) => {
+            warn!("Shared cache unavailable: {}", e);
+            return;
+        }
+    };
+    let root = cache_root();
+    let staging = root.join(format!(".{}-{}", key, uuid::Uuid::new_v4()));
+    let unpacked = std::fs
+        ::create_dir_all(&staging)
+        .map_err(|e| e.to_string())
+        .and_then(|_| extract_zip(&staging, &bytes))
+        .and_then(|_| std::fs::rename(&staging, root.join(key)).map_err(|e| e.to_string()));
+    if unpacked.is_err() {
+        let _ = std::fs::remove_dir_all(&staging);
+    }
+}
+
+// Fill `build_dir` from the cache, returns the cached response rewritten for this request
+pub async fn lookup(key: &str, build_dir: &Path, options: &BuildOptions) -> Option<CommandResponse> {
+    let response = find(key, build_dir, options).await;
+    match response {
+        Some(_) => HITS.fetch_add(1, Ordering::Relaxed),
+        None => MISSES.fetch_add(1, Ordering::Relaxed),
+    };
+    response
+}
+
+async fn find(key: &str, build_dir: &Path, options: &BuildOptions) -> Option<CommandResponse> {
+    let dir = cache_root().join(key);
+    if !dir.is_dir() && let Some(store) = shared_store() {
+        fetch_shared(store, key).await;
+    }
+    let mut entry = read_entry(&dir)?;
+    if now().saturating_sub(entry.created_at) > ttl() {
+        let _ = std::fs::remove_dir_all(&dir);
+        return None;
+    }
+    copy_outputs(&dir, build_dir).ok()?;
+
+    entry.used_at = now();
+    if let Ok(json) = serde_json::to_vec(&entry) {
+        std::fs::write(dir.join(ENTRY_FILE), json).ok();
+    }
+    let mut response = entry.response;
+    let build_dir = build_dir.to_string_lossy();
+    let sketch_dir = sketch_dir_of(options);
+    relocate(&mut response, &[(&entry.build_dir, &build_dir), (&entry.sketch_dir, &sketch_dir)]);
+    response.cached = true;
+    Some(response)
+}
+
+// Keep a successful build for later identical requests, guest builds are not kept past their
+// session
+pub async fn store(key: &str, build_dir: &Path, options: &BuildOptions, response: &CommandResponse) {
+    if !response.success || is_guest_build(build_dir) {
+        return;
+    }
+    store_local(key, build_dir, options, response);
+    if let Some(store) = shared_store() {
+        let published = match pack(&cache_root().join(key)) {
+            Ok(bytes) => store.put(&format!("compile/{}", key), bytes, Some(Duration::from_secs(ttl()))).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = published {
+            warn!("Failed to share cache entry: {}", e);
+        }
+    }
+    evict();
+}
+
+fn store_local(key: &str, build_dir: &Path, options: &BuildOptions, response: &CommandResponse) {
+    let root = cache_root();
+    let staging = root.join(format!(".{}-{}", key, uuid::Uuid::new_v4()));
+    let stored = (|| -> std::io::Result<()> {
+        std::fs::create_dir_all(&staging)?;
+        let size = copy_outputs(build_dir, &staging)?;
+        let mut response = response.clone();
+        response.build_id = None;
+        response.artifacts.clear();
+        let entry = Entry {
+            created_at: now(),
+            used_at: now(),
+            size,
+            build_dir: build_dir.to_string_lossy().to_string(),
+            sketch_dir: sketch_dir_of(options),
+            response,
+        };
+        std::fs::write(staging.join(ENTRY_FILE), serde_json::to_vec(&entry)?)?;
+        // A concurrent identical build may have stored the key first, either copy is fine
+        std::fs::rename(&staging, root.join(key))
+    })();
+    if stored.is_err() {
+        let _ = std::fs::remove_dir_all(&staging);
+    }
+}
+
+// Lookups since startup and what the local store holds
+#[derive(Serialize, Clone, Copy, ToSchema)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub size_bytes: u64,
+    pub max_bytes: u64,
+    // Compiles other identical requests are waiting on
+    pub in_flight: usize,
+}
+
+pub fn stats() -> CacheStats {
+    let entries: Vec<Entry> = std::fs
+        ::read_dir(cache_root())
+        .map(|dirs| {
+            dirs.filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|dir| !dir.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+                .filter_map(|dir| read_entry(&dir))
+                .collect()
+        })
+        .unwrap_or_default();
+    CacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        entries: entries.len(),
+        size_bytes: entries.iter().map(|entry| entry.size).sum(),
+        max_bytes: max_bytes(),
+        in_flight: IN_FLIGHT.lock().unwrap().len(),
+    }
+}
+
+// Drop expired entries, then the least recently used ones until the store fits its bound
+fn evict() {
+    let Ok(entries) = std::fs::read_dir(cache_root()) else {
+        return;
+    };
+    let ttl = ttl();
+    let mut live: Vec<(u64, u64, PathBuf)> = Vec::new();
+    for dir in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+        if dir.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')) {
+            continue;
+        }
+        match read_entry(&dir) {
+            Some(entry) if now().saturating_sub(entry.created_at) <= ttl => live.push((entry.used_at, entry.size, dir)),
+            _ => {
+                let _ = std::fs::remove_dir_all(&dir);
+            }
+        }
+    }
+    live.sort_by_key(|(used_at, _, _)| *used_at);
+    let mut total: u64 = live.iter().map(|(_, size, _)| size).sum();
+    let max = max_bytes();
+    for (_, size, dir) in live {
+        if total <= max {
+            break;
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+        total -= size;
+    }
+}
+
+// Where arduino-cli keeps downloaded core and tool archives
+fn archives_dir() -> PathBuf {
+    arduino_data_dir().join("staging").join("packages")
+}
+
+fn staged_archives() -> HashSet<String> {
+    std::fs
+        ::read_dir(archives_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Before `core install`: download the archives other instances fetched for `core`. arduino-cli
+// checks every archive against the package index, a bad one is simply downloaded again.
+// Returns the archives staged at that point.
+pub async fn restore_archives(core: &str) -> HashSet<String> {
+    let mut staged = staged_archives();
+    let Some(store) = shared_store() else {
+        return staged;
+    };
+    let names: BTreeSet<String> = match store.get(&format!("archives/cores/{}", core)).await {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        Ok(None) => BTreeSet::new(),
+        Err(e) => {
+            warn!("Shared cache unavailable: {}", e);
+            return staged;
+        }
+    };
+    let dir = archives_dir();
+    let missing: Vec<String> = names
+        .into_iter()
+        .filter(|name| !staged.contains(name) && is_safe_name(name))
+        .collect();
+    for name in missing {
+        if let Ok(Some(bytes)) = store.get(&format!("archives/files/{}", name)).await {
+            // Written aside first so arduino-cli never sees a partial archive
+            let partial = dir.join(format!(".{}.partial", name));
+            let written = std::fs
+                ::create_dir_all(&dir)
+                .and_then(|_| std::fs::write(&partial, &bytes))
+                .and_then(|_| std::fs::rename(&partial, dir.join(&name)));
+            match written {
+                Ok(()) => {
+                    staged.insert(name);
+                }
+                Err(_) => {
+                    let _ = std::fs::remove_file(&partial);
+                }
+            }
+        }
+    }
+    staged
+}
+
+// After a successful `core install`: publish the archives it downloaded, and record them for
+// `core` so other instances can restore them
+pub async fn share_archives(core: &str, before: &HashSet<String>) {
+    let Some(store) = shared_store() else {
+        return;
+    };
+    let downloaded: BTreeSet<String> = staged_archives().difference(before).cloned().collect();
+    if downloaded.is_empty() {
+        return;
+    }
+    for name in &downloaded {
+        let key = format!("archives/files/{}", name);
+        // Archive names carry their version, an existing blob is the same archive
+        if store.exists(&key).await.unwrap_or(false) {
+            continue;
+        }
+        let Ok(bytes) = std::fs::read(archives_dir().join(name)) else {
+            continue;
+        };
+        if let Err(e) = store.put(&key, bytes, None).await {
+            warn!("Failed to share {}: {}", name, e);
+        }
+    }
+
+    // Concurrent installs of the same core may race here, the loser's extra archives are
+    // downloaded from upstream again next time
+    let index_key = format!("archives/cores/{}", core);
+    let mut names: BTreeSet<String> = match store.get(&index_key).await {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => BTreeSet::new(),
+    };
+    names.extend(downloaded);
+    if let Ok(json) = serde_json::to_vec(&names) && let Err(e) = store.put(&index_key, json, None).await {
+        warn!("Failed to share the archives of {}: {}", core, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ Diagnostic, Explanation, Severity };
+
+    // A sketch at `Blink/Blink.ino` in a fresh directory, and the options compiling it
+    fn sketch() -> (PathBuf, BuildOptions) {
+        let dir = std::env::temp_dir().join(format!("cloud-compiler-test-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(dir.join("Blink")).unwrap();
+        std::fs::write(dir.join("Blink").join("Blink.ino"), "void setup() {}\nvoid loop() {}\n").unwrap();
+        let options = BuildOptions::from_request(&serde_json::json!({
+            "sketch_path": dir.join("Blink").to_string_lossy(),
+            "fqbn": "esp32:esp32:esp32",
+        }));
+        (dir, options)
+    }
+
+    #[test]
+    fn same_inputs_give_the_same_key() {
+        let (dir, options) = sketch();
+        let key = cache_key(&options).unwrap();
+        assert_eq!(cache_key(&options), Some(key.clone()));
+        // Dotfiles are editor and server bookkeeping, not sources
+        std::fs::write(dir.join("Blink").join(".swp"), "x").unwrap();
+        assert_eq!(cache_key(&options), Some(key));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sources_and_settings_change_the_key() {
+        let (dir, mut options) = sketch();
+        let key = cache_key(&options).unwrap();
+
+        std::fs::write(dir.join("Blink").join("Blink.ino"), "void setup() {}\nvoid loop() { delay(1); }\n").unwrap();
+        let edited = cache_key(&options).unwrap();
+        assert_ne!(edited, key);
+        std::fs::create_dir_all(dir.join("Blink").join("src")).unwrap();
+        std::fs::write(dir.join("Blink").join("src").join("util.h"), "#pragma once\n").unwrap();
+        let added = cache_key(&options).unwrap();
+        assert_ne!(added, edited);
+
+        options.fqbn = Some("esp32:esp32:esp32s3".to_string());
+        assert_ne!(cache_key(&options).unwrap(), added);
+        options.merge = true;
+        let merged = cache_key(&options).unwrap();
+        options.variables.insert("SSID".to_string(), "home".to_string());
+        assert_ne!(cache_key(&options).unwrap(), merged);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn private_builds_are_not_cached() {
+        let (dir, options) = sketch();
+        let encrypted = BuildOptions { encrypt: Some("fleet".to_string()), ..BuildOptions::from_request(&serde_json::json!({ "sketch_path": options.sketch_path })) };
+        assert!(cache_key(&encrypted).is_none());
+        let kept = BuildOptions { keep_build_dir: true, ..BuildOptions::from_request(&serde_json::json!({ "sketch_path": options.sketch_path })) };
+        assert!(cache_key(&kept).is_none());
+        let mut secret = BuildOptions::from_request(&serde_json::json!({ "sketch_path": options.sketch_path }));
+        secret.secrets.insert("WIFI_PASSWORD".to_string(), "hunter2".to_string());
+        assert!(cache_key(&secret).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relocation_covers_every_path_field() {
+        let leader = "/data/builds/leader";
+        let mut response = CommandResponse {
+            output: format!("Sketch uses 1024 bytes\n{}/Blink.ino.bin", leader),
+            error: Some(format!("{}/sketch/Blink.ino.cpp:3: warning", leader)),
+            stderr: Some(format!("In file included from {}/sketch/Blink.ino.cpp:1:", leader)),
+            args: vec![format!("--build-path={}", leader)],
+            diagnostics: vec![Diagnostic {
+                severity: Severity::Warning,
+                code: String::new(),
+                message: "unused variable".to_string(),
+                file: Some(format!("{}/sketch/Blink.ino.cpp", leader)),
+                line: Some(3),
+            }],
+            explanations: vec![Explanation {
+                code: String::new(),
+                title: String::new(),
+                explanation: String::new(),
+                link: String::new(),
+                file: Some(format!("{}/sketch/Blink.ino.cpp", leader)),
+                line: Some(3),
+            }],
+            ..CommandResponse::default()
+        };
+        relocate(&mut response, &[(leader, "/data/builds/follower"), ("", "/ignored")]);
+
+        let text = serde_json::to_string(&response).unwrap();
+        assert!(!text.contains(leader));
+        assert!(!text.contains("/ignored"));
+        assert_eq!(response.stderr.unwrap(), "In file included from /data/builds/follower/sketch/Blink.ino.cpp:1:");
+        assert_eq!(response.args, ["--build-path=/data/builds/follower"]);
+    }
+}
@@ -0,0 +1,166 @@
+// Fast syntax checks. `check-sketch` answers with the diagnostics of a sketch for a board in a
+// fraction of a compile: arduino-cli only resolves the libraries and writes the compilation
+// database, then gcc runs with `-fsyntax-only` over the sketch's own files. Nothing is compiled,
+// archived or linked, and the core isn't built at all. The build path of a sketch and board is
+// kept for the next check, which reuses its cached library discovery.
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, LazyLock, Mutex };
+use std::time::{ Duration, SystemTime };
+use md5::{ Digest, Md5 };
+use serde::Deserialize;
+use crate::models::*;
+use crate::compiler::{
+    compilation_database,
+    compiler_diagnostics,
+    error_response,
+    run_toolchain_program,
+    server_data_dir,
+    sketch_dir,
+    COMPILATION_DATABASE,
+};
+use crate::toolchain;
+
+const COMMAND: &str = "check-sketch";
+// Build paths kept for later checks, the least recently used go first
+const MAX_CHECK_DIRS: usize = 64;
+// Build paths checked this recently may be in use and are kept
+const PROTECTED_AGE: Duration = Duration::from_secs(600);
+const LAST_CHECK_FILE: &str = ".last-check";
+
+// Checks of the same sketch and board share a build path, they run one after the other
+static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> = LazyLock::new(Default::default);
+
+#[derive(Deserialize)]
+struct CompileCommand {
+    #[serde(default)]
+    arguments: Vec<String>,
+    file: String,
+}
+
+fn checks_root() -> PathBuf {
+    server_data_dir().join("checks")
+}
+
+// Build path of a sketch on a board with the selected toolchain
+fn check_dir(sketch: &str, fqbn: &str) -> PathBuf {
+    let toolchain = toolchain::selected().unwrap_or_default();
+    let key = format!("{}\n{}\n{}", sketch_dir(Path::new(sketch)).display(), fqbn, toolchain.display());
+    checks_root().join(format!("{:x}", Md5::digest(key.as_bytes())))
+}
+
+fn lock_for(dir: &Path) -> Arc<tokio::sync::Mutex<()>> {
+    let mut locks = LOCKS.lock().unwrap();
+    locks.entry(dir.to_path_buf()).or_default().clone()
+}
+
+// Remove the least recently checked build paths beyond the limit
+fn prune(keep: &Path) {
+    let Ok(entries) = std::fs::read_dir(checks_root()) else {
+        return;
+    };
+    let mut dirs: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|dir| dir != keep)
+        .map(|dir| {
+            let checked = std::fs
+                ::metadata(dir.join(LAST_CHECK_FILE))
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (checked, dir)
+        })
+        .collect();
+    if dirs.len() < MAX_CHECK_DIRS {
+        return;
+    }
+    dirs.sort();
+    let excess = dirs.len() + 1 - MAX_CHECK_DIRS;
+    for (checked, dir) in dirs.into_iter().take(excess) {
+        if checked.elapsed().is_ok_and(|age| age > PROTECTED_AGE) {
+            let _ = std::fs::remove_dir_all(&dir);
+            LOCKS.lock().unwrap().remove(&dir);
+        }
+    }
+}
+
+// The gcc invocation of a database entry as a syntax check: no object or dependency files
+fn syntax_only(arguments: Vec<String>) -> Option<(PathBuf, Vec<String>)> {
+    let mut arguments = arguments.into_iter();
+    let program = PathBuf::from(arguments.next()?);
+    let mut args = vec!["-fsyntax-only".to_string()];
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "-o" => {
+                arguments.next();
+            }
+            "-c" | "-MMD" | "-MD" => {}
+            _ => args.push(arg),
+        }
+    }
+    Some((program, args))
+}
+
+// Check `sketch` for `fqbn`, answered with its diagnostics
+pub async fn check_sketch(fqbn: &str, sketch: &str) -> CommandResponse {
+    let args = vec![fqbn.to_string(), sketch.to_string()];
+    let dir = check_dir(sketch, fqbn);
+    let lock = lock_for(&dir);
+    let _guard = lock.lock().await;
+    prune(&dir);
+    if let Err(e) = std::fs::create_dir_all(&dir).and_then(|_| std::fs::write(dir.join(LAST_CHECK_FILE), b"")) {
+        return error_response(COMMAND, args, &e.to_string());
+    }
+
+    let mut response = compilation_database(fqbn, sketch, &dir).await;
+    if !response.success {
+        response.command = COMMAND.to_string();
+        response.diagnostics = compiler_diagnostics(response.error.as_deref().unwrap_or_default());
+        return response;
+    }
+    let database: Option<Vec<CompileCommand>> = std::fs
+        ::read(dir.join(COMPILATION_DATABASE))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+    let Some(database) = database else {
+        return error_response(COMMAND, args, "arduino-cli wrote no compilation database");
+    };
+
+    // The sketch is copied into `<build>/sketch`, the .ino files are mapped back by `#line`
+    let build_sketch = dir.join("sketch");
+    let checks = database
+        .into_iter()
+        .filter(|entry| Path::new(&entry.file).starts_with(&build_sketch))
+        .filter_map(|entry| syntax_only(entry.arguments))
+        .map(|(program, args)| {
+            let dirs = [dir.clone(), sketch_dir(Path::new(sketch)).to_path_buf()];
+            async move { run_toolchain_program(&program, "check", &args, &dirs).await }
+        });
+    let results = futures::future::join_all(checks).await;
+
+    let copied = format!("{}{}", build_sketch.display(), std::path::MAIN_SEPARATOR);
+    let original = format!("{}{}", sketch_dir(Path::new(sketch)).display(), std::path::MAIN_SEPARATOR);
+    let errors: Vec<String> = results
+        .iter()
+        .filter_map(|result| result.error.as_deref())
+        .filter(|error| !error.trim().is_empty())
+        .map(|error| error.replace(&copied, &original))
+        .collect();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for diagnostic in errors.iter().flat_map(|error| compiler_diagnostics(error)) {
+        let duplicate = diagnostics
+            .iter()
+            .any(|d| d.message == diagnostic.message && d.file == diagnostic.file && d.line == diagnostic.line);
+        if !duplicate {
+            diagnostics.push(diagnostic);
+        }
+    }
+    let success = results.iter().all(|result| result.success);
+    CommandResponse {
+        success,
+        command: COMMAND.to_string(),
+        args,
+        error: (!success).then(|| errors.join("")),
+        diagnostics,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,257 @@
+// Async client for the server's Socket.IO events, built with the `client` feature, for services
+// and tests that would otherwise re-implement the event protocol. Requests are typed with the
+// models the server itself parses and answered with the acknowledged `CommandResponse`; events the
+// server sends on its own (queue updates, progress, monitor data) reach every `events()` receiver.
+// Sketch folders are uploaded and artifacts downloaded over the server's HTTP routes.
+use std::path::Path;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use futures::FutureExt;
+use rust_socketio::asynchronous::{ Client as Socket, ClientBuilder };
+use rust_socketio::{ Event, Payload };
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::{ broadcast, oneshot };
+use crate::artifacts::zip_dir;
+use crate::models::*;
+
+// Compiles of large sketches on a busy server take minutes
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+// Server events kept for receivers that fall behind
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug)]
+pub enum ClientError {
+    // The socket could not connect, or an event or download could not be sent
+    Connection(String),
+    // No response within the timeout
+    Timeout,
+    // The server's answer was not a response
+    Protocol(String),
+    // The server answered with `success: false`
+    Failed(Box<CommandResponse>),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Connection(e) => write!(f, "Connection failed: {}", e),
+            ClientError::Timeout => write!(f, "No response from the server"),
+            ClientError::Protocol(e) => write!(f, "Unexpected answer: {}", e),
+            ClientError::Failed(response) => write!(f, "{}", response.error.as_deref().unwrap_or("Command failed")),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+// An event the server sent on its own
+#[derive(Clone, Debug)]
+pub struct ServerEvent {
+    pub event: String,
+    pub data: Value,
+}
+
+pub struct ClientOptions {
+    // Base URL of the server, `http://host:3000`
+    pub url: String,
+    pub namespace: String,
+    // Handshake auth, `{"api_key": "..."}` or `{"token": "..."}`
+    pub auth: Option<Value>,
+    // How long a request may wait for its response
+    pub timeout: Duration,
+}
+
+impl ClientOptions {
+    pub fn new(url: impl Into<String>) -> Self {
+        ClientOptions { url: url.into(), namespace: "/".to_string(), auth: None, timeout: DEFAULT_TIMEOUT }
+    }
+}
+
+pub struct Client {
+    socket: Socket,
+    url: String,
+    timeout: Duration,
+    events: broadcast::Sender<ServerEvent>,
+    http: reqwest::Client,
+    // The handshake's credential, sent as `Authorization: Bearer` over HTTP
+    credential: Option<String>,
+}
+
+// A request as event data. Unset fields are left out rather than sent as null.
+fn payload<T: Serialize>(request: &T) -> Result<Value, ClientError> {
+    let mut value = serde_json::to_value(request).map_err(|e| ClientError::Protocol(e.to_string()))?;
+    if let Value::Object(fields) = &mut value {
+        fields.retain(|_, field| !field.is_null());
+    }
+    Ok(value)
+}
+
+fn first_value(payload: Payload) -> Value {
+    match payload {
+        Payload::Text(mut values) if !values.is_empty() => values.swap_remove(0),
+        _ => Value::Null,
+    }
+}
+
+impl Client {
+    pub async fn connect(options: ClientOptions) -> Result<Client, ClientError> {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let sender = events.clone();
+        let (connected, joined) = oneshot::channel();
+        let connected = Arc::new(Mutex::new(Some(connected)));
+        let credential = options.auth
+            .as_ref()
+            .and_then(|auth| auth.get("api_key").or_else(|| auth.get("token")))
+            .and_then(|credential| credential.as_str())
+            .map(String::from);
+        let mut builder = ClientBuilder::new(options.url.clone())
+            .namespace(options.namespace)
+            .reconnect(false)
+            .on(Event::Connect, move |_, _| {
+                if let Some(connected) = connected.lock().unwrap().take() {
+                    connected.send(()).ok();
+                }
+                async {}.boxed()
+            })
+            .on_any(move |event, payload, _| {
+                let event = ServerEvent { event: String::from(event), data: first_value(payload) };
+                // Nobody listening is fine
+                sender.send(event).ok();
+                async {}.boxed()
+            });
+        if let Some(auth) = options.auth {
+            builder = builder.auth(auth);
+        }
+        let socket = builder.connect().await.map_err(|e| ClientError::Connection(e.to_string()))?;
+        // Events sent before the server accepted the namespace would be dropped
+        if !matches!(tokio::time::timeout(CONNECT_TIMEOUT, joined).await, Ok(Ok(()))) {
+            socket.disconnect().await.ok();
+            return Err(ClientError::Connection("The server did not accept the connection".to_string()));
+        }
+        Ok(Client {
+            socket,
+            url: options.url.trim_end_matches('/').to_string(),
+            timeout: options.timeout,
+            events,
+            http: reqwest::Client::new(),
+            credential,
+        })
+    }
+
+    // Events the server sends from now on: `queue-update`, `git-progress`, `monitor-data`, ...
+    pub fn events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    // Send `event` with `request` and wait for its response, successful or not
+    pub async fn request<T: Serialize>(&self, event: &str, request: &T) -> Result<CommandResponse, ClientError> {
+        let data = payload(request)?;
+        let (sender, receiver) = oneshot::channel();
+        let sender = Arc::new(Mutex::new(Some(sender)));
+        self.socket
+            .emit_with_ack(event, data, self.timeout, move |payload, _| {
+                if let Some(sender) = sender.lock().unwrap().take() {
+                    // The acknowledgement's arguments arrive as one array
+                    let value = match first_value(payload) {
+                        Value::Array(mut arguments) if !arguments.is_empty() => arguments.swap_remove(0),
+                        value => value,
+                    };
+                    sender.send(value).ok();
+                }
+                async {}.boxed()
+            }).await
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+        // The socket drops the callback, and with it the sender, once the timeout passes
+        let value = match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(value)) => value,
+            _ => {
+                return Err(ClientError::Timeout);
+            }
+        };
+        serde_json::from_value(value).map_err(|e| ClientError::Protocol(e.to_string()))
+    }
+
+    // Send `event` with `request`, its unsuccessful responses as errors
+    async fn command<T: Serialize>(&self, event: &str, request: &T) -> Result<CommandResponse, ClientError> {
+        let response = self.request(event, request).await?;
+        match response.success {
+            true => Ok(response),
+            false => Err(ClientError::Failed(Box::new(response))),
+        }
+    }
+
+    // Upload the sketch folder `dir` with everything in it but dotfiles, answered with the path
+    // `compile` takes as its `sketch_path`
+    pub async fn upload_sketch(&self, dir: &Path) -> Result<String, ClientError> {
+        let dir = dir.to_path_buf();
+        let archive = tokio::task
+            ::spawn_blocking(move || {
+                // The archive's folder carries the sketch's name, which has to match its .ino
+                let root = dir
+                    .canonicalize()
+                    .map_err(|e| e.to_string())?
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .ok_or_else(|| "Not a sketch folder".to_string())?;
+                let path = std::env::temp_dir().join(format!("cloudc-{}.zip", uuid::Uuid::new_v4()));
+                let archive = zip_dir(&dir, &root, &path).and_then(|_| std::fs::read(&path).map_err(|e| e.to_string()));
+                std::fs::remove_file(&path).ok();
+                archive
+            }).await
+            .map_err(|e| ClientError::Protocol(e.to_string()))?
+            .map_err(ClientError::Connection)?;
+        let part = reqwest::multipart::Part::bytes(archive).file_name("sketch.zip");
+        let mut request = self.http
+            .post(format!("{}/sketches", self.url))
+            .multipart(reqwest::multipart::Form::new().part("archive", part));
+        if let Some(credential) = &self.credential {
+            request = request.bearer_auth(credential);
+        }
+        let response = request.send().await.map_err(|e| ClientError::Connection(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(ClientError::Protocol(format!("{} {}", status, message)));
+        }
+        let uploaded: Value = response.json().await.map_err(|e| ClientError::Protocol(e.to_string()))?;
+        uploaded["sketch_path"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| ClientError::Protocol("No sketch_path in the upload response".to_string()))
+    }
+
+    pub async fn install_core(&self, request: &InstallCoreRequest) -> Result<CommandResponse, ClientError> {
+        self.command("install-core", request).await
+    }
+
+    // Compile a sketch, the response carries its `build_id`, `artifacts` and `diagnostics`
+    pub async fn compile(&self, request: &CompileRequest) -> Result<CommandResponse, ClientError> {
+        self.command("compile-sketch", request).await
+    }
+
+    pub async fn upload(&self, request: &UploadRequest) -> Result<CommandResponse, ClientError> {
+        self.command("upload-sketch", request).await
+    }
+
+    // Download an artifact of a compile, from its signed URL if it was stored
+    pub async fn fetch_artifact(&self, build_id: &str, artifact: &Artifact) -> Result<Vec<u8>, ClientError> {
+        let url = match &artifact.url {
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => url.clone(),
+            Some(url) => format!("{}{}", self.url, url),
+            None => format!("{}/builds/{}/artifacts/{}", self.url, build_id, artifact.name),
+        };
+        let response = self.http
+            .get(&url)
+            .send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+        let bytes = response.bytes().await.map_err(|e| ClientError::Connection(e.to_string()))?;
+        Ok(bytes.to_vec())
+    }
+
+    pub async fn disconnect(self) -> Result<(), ClientError> {
+        self.socket.disconnect().await.map_err(|e| ClientError::Connection(e.to_string()))
+    }
+}
@@ -0,0 +1,73 @@
+// Several instances behind one load balancer. With CLOUD_COMPILER_CLUSTER_REDIS_URL the Socket.IO
+// servers use socketioxide's Redis adapter, so rooms and broadcasts span every instance sharing
+// that Redis. A client names its session with `session` in the handshake auth and its sockets
+// join the session's room. Events and answers of a job that outlive the socket which started it
+// go to that room, so they reach the client once it reconnected, on whichever instance.
+use futures::future::BoxFuture;
+use socketioxide::adapter::{ Adapter, Emitter };
+use socketioxide::extract::SocketRef;
+use socketioxide_redis::drivers::redis::{ redis_client, RedisDriver };
+use socketioxide_redis::{ RedisAdapter, RedisAdapterCtr };
+use tokio::sync::mpsc;
+use tracing::{ info, warn };
+
+pub type ClusterAdapter = RedisAdapter<Emitter>;
+
+// Stable name of a client across its reconnects, in the socket's extensions
+#[derive(Clone)]
+pub struct Session(pub String);
+
+// Delivers forwarded events of a socket in the order they were sent, in its extensions
+#[derive(Clone)]
+struct Forwarder(mpsc::UnboundedSender<BoxFuture<'static, ()>>);
+
+pub fn redis_url() -> Option<String> {
+    std::env::var("CLOUD_COMPILER_CLUSTER_REDIS_URL").ok().filter(|url| !url.trim().is_empty())
+}
+
+// A constructor of the Redis adapter, one per Socket.IO server. The adapter needs RESP3 push
+// messages, so the protocol is switched to it whatever the URL says.
+pub async fn adapter(url: &str) -> Result<RedisAdapterCtr<RedisDriver>, String> {
+    let mut info = redis_client::IntoConnectionInfo::into_connection_info(url).map_err(|e| e.to_string())?;
+    info.redis.protocol = redis_client::ProtocolVersion::RESP3;
+    let client = redis_client::Client::open(info).map_err(|e| e.to_string())?;
+    let adapter = RedisAdapterCtr::new_with_redis(&client).await.map_err(|e| format!("Failed to connect the Socket.IO adapter to Redis: {}", e))?;
+    info!("Socket.IO rooms and broadcasts shared through Redis");
+    Ok(adapter)
+}
+
+fn room(session: &str) -> String {
+    format!("session:{}", session)
+}
+
+// Put the socket in the room of its session
+pub fn join<A: Adapter>(socket: &SocketRef<A>, session: &str) {
+    socket.extensions.insert(Session(session.to_string()));
+    socket.join(room(session));
+}
+
+// Send `event` to the session of a socket that went away
+pub fn forward<A: Adapter, T: ?Sized + serde::Serialize>(socket: &SocketRef<A>, event: &str, data: &T) -> Result<(), String> {
+    let Some(Session(session)) = socket.extensions.get::<Session>() else {
+        return Err("The socket has no session".to_string());
+    };
+    let forwarder = socket.extensions.get::<Forwarder>().unwrap_or_else(|| {
+        let (sender, mut forwarded) = mpsc::unbounded_channel::<BoxFuture<'static, ()>>();
+        tokio::spawn(async move {
+            while let Some(delivery) = forwarded.recv().await {
+                delivery.await;
+            }
+        });
+        let forwarder = Forwarder(sender);
+        socket.extensions.insert(forwarder.clone());
+        forwarder
+    });
+    // Serialized now, delivered after the earlier ones
+    let data = serde_json::to_value(data).map_err(|e| e.to_string())?;
+    let (socket, event) = (socket.clone(), event.to_string());
+    forwarder.0.send(Box::pin(async move {
+        if let Err(e) = socket.within(room(&session)).emit(&event, &data).await {
+            warn!("Failed to forward {} to its session: {}", event, e);
+        }
+    })).map_err(|_| "The forwarder stopped".to_string())
+}
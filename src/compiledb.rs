@@ -0,0 +1,79 @@
+// Compilation databases for IDE tooling. `compilation-database` has arduino-cli write the
+// `compile_commands.json` of a sketch for a board and answers with it, its paths rewritten from
+// the server's folders to the client's view: the workspace becomes `workspace_root`, the build
+// folder `<workspace_root>/.build/<sketch>`, and the Arduino data and user folders the client's
+// own when it names them. clangd and IntelliSense engines on the client can then resolve the
+// entries against their copy of the sources.
+use std::path::{ Path, PathBuf };
+use serde_json::Value;
+use crate::compiler::{ arduino_data_dir, arduino_user_dir, compilation_database, sketch_dir, COMPILATION_DATABASE };
+use crate::files;
+
+const DEFAULT_WORKSPACE_ROOT: &str = "/workspace";
+
+// Client folders to rewrite server paths to
+pub struct ClientPaths {
+    pub workspace_root: Option<String>,
+    pub arduino_data_dir: Option<String>,
+    pub arduino_user_dir: Option<String>,
+}
+
+// Replace the folder `from` with `to` wherever it begins a path in `text`
+fn rewrite(text: &str, from: &Path, to: &str) -> String {
+    let from = from.to_string_lossy();
+    if text == from {
+        return to.to_string();
+    }
+    let to = format!("{}/", to.trim_end_matches('/'));
+    ["/", "\\"].iter().fold(text.to_string(), |text, separator| text.replace(&format!("{}{}", from, separator), &to))
+}
+
+fn rewrite_all(value: &mut Value, mappings: &[(PathBuf, String)]) {
+    match value {
+        Value::String(text) => {
+            for (from, to) in mappings {
+                *text = rewrite(text, from, to);
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| rewrite_all(item, mappings)),
+        Value::Object(fields) => fields.values_mut().for_each(|field| rewrite_all(field, mappings)),
+        _ => {}
+    }
+}
+
+// Server folders and what they become for the client, the most specific first: the build folder
+// and workspace may lie inside the data folder
+pub fn client_mappings(sketch: &str, workspace: &Path, build_dir: &Path, client: &ClientPaths) -> Result<Vec<(PathBuf, String)>, String> {
+    let root = client.workspace_root.as_deref().unwrap_or(DEFAULT_WORKSPACE_ROOT).trim_end_matches('/').to_string();
+    // POSIX or Windows, the client's system may not be the server's
+    if !root.starts_with('/') && root.get(1..2) != Some(":") {
+        return Err("workspace_root must be an absolute path".to_string());
+    }
+    let name = sketch_dir(Path::new(sketch)).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let workspace = files::canonical(workspace).unwrap_or_else(|_| workspace.to_path_buf());
+    let mut mappings = vec![(build_dir.to_path_buf(), format!("{}/.build/{}", root, name)), (workspace, root)];
+    if let Some(dir) = &client.arduino_data_dir {
+        mappings.push((arduino_data_dir(), dir.clone()));
+    }
+    if let Some(dir) = &client.arduino_user_dir {
+        mappings.push((arduino_user_dir(), dir.clone()));
+    }
+    Ok(mappings)
+}
+
+// The compilation database of `sketch` on `fqbn`, written into `build_dir`, with the folders of
+// `workspace` and the server rewritten for the client
+pub async fn generate(fqbn: &str, sketch: &str, workspace: &Path, build_dir: &Path, client: &ClientPaths) -> Result<Value, String> {
+    let mappings = client_mappings(sketch, workspace, build_dir, client)?;
+    let response = compilation_database(fqbn, sketch, build_dir).await;
+    if !response.success {
+        return Err(response.error.unwrap_or_default().trim().to_string());
+    }
+    let mut database: Value = std::fs
+        ::read(build_dir.join(COMPILATION_DATABASE))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or("arduino-cli wrote no compilation database")?;
+    rewrite_all(&mut database, &mappings);
+    Ok(database)
+}
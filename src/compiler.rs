@@ -1,22 +1,39 @@
+use std::collections::HashMap;
 use std::path::{ Path, PathBuf };
-use tracing::info;
+use std::process::Stdio;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, SystemTime };
+use tracing::{ info, instrument };
+use tokio::io::{ AsyncBufReadExt, AsyncRead, BufReader };
 use tokio::process::Command as TokioCommand;
-use axum::{ extract::Json, routing::{ get, post }, http::StatusCode, Router };
+use crate::daemon;
+use crate::errors::{ classify, CompilerError };
 use crate::models::*;
+use crate::output;
+use crate::resources::{ acquire, ResourceKind };
+use crate::sandbox::{ sandbox, Mounts, Sandbox };
+use crate::status;
+use crate::toolchain;
 // Path to the arduino-cli binary
-#[cfg(target_os = "linux")]
+#[cfg(all(feature = "embedded-cli", target_os = "linux"))]
 static ARDUINO_CLI_BINARY: &[u8] = include_bytes!("../resource/linux/arduino-cli"); // Change this if needed
-#[cfg(target_os = "windows")]
+#[cfg(all(feature = "embedded-cli", target_os = "windows"))]
 static ARDUINO_CLI_BINARY: &[u8] = include_bytes!("../resource/windows/arduino-cli.exe"); // Change this if needed
-#[cfg(target_os = "macos")]
-static ARDUINO_CLI_BINARY: &[u8] = include_bytes!("../resource/macos/arduino-cli"); // Change this if needed
+#[cfg(all(feature = "embedded-cli", target_os = "macos"))]
+static ARDUINO_CLI_BINARY: &[u8] = include_bytes!("../resource/macOS/arduino-cli"); // Change this if needed
 static ARDUINO_CLI_PATH: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
 
-// Function to initialize the arduino-cli binary
-fn initialize_arduino_cli() -> PathBuf {
+// Write the embedded arduino-cli binary to disk
+#[cfg(feature = "embedded-cli")]
+fn write_embedded_cli() -> PathBuf {
     let temp_dir = std::env::temp_dir();
-    let arduino_cli_path = temp_dir.join("arduino-cli-embedded");
+    // Windows only runs it with its .exe extension
+    let arduino_cli_path = temp_dir.join(format!("arduino-cli-embedded{}", std::env::consts::EXE_SUFFIX));
 
+    // Another instance may be running it, which Windows doesn't let us overwrite
+    if std::fs::read(&arduino_cli_path).is_ok_and(|written| written == ARDUINO_CLI_BINARY) {
+        return arduino_cli_path;
+    }
     // Write the binary to a temporary location
     std::fs
         ::write(&arduino_cli_path, ARDUINO_CLI_BINARY)
@@ -36,6 +53,18 @@ fn initialize_arduino_cli() -> PathBuf {
 
     arduino_cli_path
 }
+
+// Function to initialize the arduino-cli binary: an explicit CLOUD_COMPILER_ARDUINO_CLI, the
+// embedded one, or where the bootstrap installs it
+fn initialize_arduino_cli() -> PathBuf {
+    if let Some(path) = std::env::var_os("CLOUD_COMPILER_ARDUINO_CLI").filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+    #[cfg(feature = "embedded-cli")]
+    return write_embedded_cli();
+    #[cfg(not(feature = "embedded-cli"))]
+    return crate::bootstrap::installed_path();
+}
 // Get the path to the arduino-cli binary
 pub fn get_arduino_cli_path() -> &'static PathBuf {
     ARDUINO_CLI_PATH.get_or_init(initialize_arduino_cli)
@@ -59,41 +88,835 @@ pub fn health_check() -> bool {
         }
     }
 }
+// Versions by arduino-cli binary and its modification time, so a replaced binary is asked again
+type Binary = (PathBuf, Option<SystemTime>);
+static VERSIONS: LazyLock<Mutex<HashMap<Binary, String>>> = LazyLock::new(Default::default);
+
+// Version of the arduino-cli binary at `arduino_cli`, as `arduino-cli version` reports it
+pub async fn query_arduino_cli_version(arduino_cli: &Path) -> Result<String, String> {
+    let args = ["version", "--format", "json"].map(String::from);
+    let response = run_program(arduino_cli, "version", &args).await;
+    if !response.success {
+        return Err(response.error.unwrap_or_default().trim().to_string());
+    }
+    let version: serde_json::Value = serde_json::from_str(&response.output).map_err(|e| e.to_string())?;
+    version["VersionString"].as_str().map(String::from).ok_or_else(|| "No version in arduino-cli output".to_string())
+}
+
+// The version reported once per binary, for stamping responses
+async fn resolved_arduino_cli_version(arduino_cli: &Path) -> Option<String> {
+    let modified = std::fs::metadata(arduino_cli).and_then(|metadata| metadata.modified()).ok();
+    let key = (arduino_cli.to_path_buf(), modified);
+    if let Some(version) = VERSIONS.lock().unwrap().get(&key) {
+        return Some(version.clone());
+    }
+    let version = output::quiet(query_arduino_cli_version(arduino_cli)).await.ok()?;
+    VERSIONS.lock().unwrap().insert(key, version.clone());
+    Some(version)
+}
+
+// Kind of value a flag or operand takes, checked before the command runs
+#[derive(Clone, Copy)]
+enum ArgKind {
+    Fqbn,
+    Core,
+    // A library name, `name@version` or search terms
+    Library,
+    Format,
+    Protocol,
+    UploadField,
+    Port,
+    Path,
+    Profile,
+}
+
+// Subcommand clients may reach through arduino-cli, with the actions, flags and operand it accepts
+struct CommandPolicy {
+    command: &'static str,
+    // Actions that must follow the command (`board list`), with the operand each takes
+    actions: &'static [(&'static str, Option<ArgKind>)],
+    // Operand of commands without actions (the sketch of `compile`)
+    operand: Option<ArgKind>,
+    flags: &'static [(&'static str, Option<ArgKind>)],
+}
+
+const POLICIES: &[CommandPolicy] = &[
+    CommandPolicy {
+        command: "board",
+        actions: &[("list", None), ("listall", None)],
+        operand: None,
+        flags: &[("--format", Some(ArgKind::Format))],
+    },
+    CommandPolicy {
+        command: "core",
+        actions: &[("list", None), ("install", Some(ArgKind::Core))],
+        operand: None,
+        flags: &[("--format", Some(ArgKind::Format))],
+    },
+    CommandPolicy {
+        command: "lib",
+        actions: &[("list", None), ("search", Some(ArgKind::Library)), ("install", Some(ArgKind::Library))],
+        operand: None,
+        flags: &[("--format", Some(ArgKind::Format))],
+    },
+    CommandPolicy {
+        command: "compile",
+        actions: &[],
+        operand: Some(ArgKind::Path),
+        flags: &[
+            ("--fqbn", Some(ArgKind::Fqbn)),
+            ("--output-dir", Some(ArgKind::Path)),
+            ("--build-path", Some(ArgKind::Path)),
+            ("--show-properties", None),
+            ("--profile", Some(ArgKind::Profile)),
+            ("--dump-profile", None),
+            ("--library", Some(ArgKind::Path)),
+            ("--only-compilation-database", None),
+            ("--preprocess", None),
+        ],
+    },
+    CommandPolicy {
+        command: "upload",
+        actions: &[],
+        operand: Some(ArgKind::Path),
+        flags: &[
+            ("--port", Some(ArgKind::Port)),
+            ("--fqbn", Some(ArgKind::Fqbn)),
+            ("--input-dir", Some(ArgKind::Path)),
+            ("--protocol", Some(ArgKind::Protocol)),
+            ("--upload-field", Some(ArgKind::UploadField)),
+        ],
+    },
+];
+
+// Why a command was refused before reaching arduino-cli
+#[derive(Debug)]
+pub enum PolicyError {
+    Command(String),
+    Action(String),
+    Flag(String),
+    Value(String, String),
+    Operand(String),
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyError::Command(command) => write!(f, "Command not allowed: {}", command),
+            PolicyError::Action(action) => write!(f, "Action not allowed: {}", action),
+            PolicyError::Flag(flag) => write!(f, "Flag not allowed: {}", flag),
+            PolicyError::Value(flag, value) => write!(f, "Invalid value for {}: {}", flag, value),
+            PolicyError::Operand(operand) => write!(f, "Unexpected argument: {}", operand),
+        }
+    }
+}
+
+// Identifier segments of FQBNs and core ids (`esp32`, `esp32s3`, `arduino-esp32`)
+fn is_identifier(segment: &str) -> bool {
+    !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn valid_value(kind: ArgKind, value: &str) -> bool {
+    // Nothing may smuggle in a flag or break out of the argument
+    if value.is_empty() || value.starts_with('-') || value.chars().any(|c| c.is_control()) {
+        return false;
+    }
+    match kind {
+        // vendor:arch:board[:option=value,...]
+        ArgKind::Fqbn => {
+            let parts: Vec<&str> = value.splitn(4, ':').collect();
+            parts.len() >= 3 &&
+                parts[..3].iter().all(|part| is_identifier(part)) &&
+                parts.get(3).is_none_or(|options| {
+                    options.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '=' | ','))
+                })
+        }
+        // vendor:arch[@version]
+        ArgKind::Core => {
+            let (id, version) = value.split_once('@').unwrap_or((value, "x"));
+            matches!(id.split_once(':'), Some((vendor, arch)) if is_identifier(vendor) && is_identifier(arch)) &&
+                is_identifier(version)
+        }
+        ArgKind::Library => value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.' | '@' | '+')),
+        ArgKind::Format => matches!(value, "json" | "text"),
+        ArgKind::Protocol => matches!(value, "serial" | "network"),
+        ArgKind::UploadField => value.starts_with("password="),
+        ArgKind::Profile => crate::profiles::valid_name(value),
+        ArgKind::Port | ArgKind::Path => true,
+    }
+}
+
+// Check a command against the allowlist: a vetted subcommand and action, known flags with
+// well-formed values, and at most one operand
+pub fn check_policy(command: &ArduinoCommand) -> Result<(), PolicyError> {
+    let policy = POLICIES.iter()
+        .find(|p| p.command == command.command)
+        .ok_or_else(|| PolicyError::Command(command.command.clone()))?;
+
+    let mut args = command.args.iter();
+    let mut operand = policy.operand;
+    if !policy.actions.is_empty() {
+        let action = args.next().map(String::as_str).unwrap_or_default();
+        operand = policy.actions
+            .iter()
+            .find(|(name, _)| *name == action)
+            .ok_or_else(|| PolicyError::Action(action.to_string()))?.1;
+    }
+
+    let mut operand_seen = false;
+    while let Some(arg) = args.next() {
+        if arg.starts_with('-') {
+            let kind = policy.flags
+                .iter()
+                .find(|(flag, _)| flag == arg)
+                .ok_or_else(|| PolicyError::Flag(arg.clone()))?.1;
+            if let Some(kind) = kind {
+                let value = args.next().map(String::as_str).unwrap_or_default();
+                if !valid_value(kind, value) {
+                    return Err(PolicyError::Value(arg.clone(), value.to_string()));
+                }
+            }
+            continue;
+        }
+        match operand {
+            Some(kind) if !operand_seen && valid_value(kind, arg) => {
+                operand_seen = true;
+            }
+            _ => {
+                return Err(PolicyError::Operand(arg.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Memory limit for a single process, in bytes
+pub fn memory_limit() -> Option<u64> {
+    PROCESS_LIMITS.memory_bytes
+}
+
+// Per-process limits for arduino-cli and the toolchain it starts. Unset limits stay unlimited.
+#[derive(Clone, Copy, Default)]
+struct ProcessLimits {
+    cpu_secs: Option<u64>,
+    memory_bytes: Option<u64>,
+    open_files: Option<u64>,
+}
+
+static PROCESS_LIMITS: LazyLock<ProcessLimits> = LazyLock::new(|| {
+    let read = |name: &str| std::env::var(name).ok().and_then(|value| value.trim().parse::<u64>().ok());
+    ProcessLimits {
+        cpu_secs: read("CLOUD_COMPILER_LIMIT_CPU_SECS"),
+        memory_bytes: read("CLOUD_COMPILER_LIMIT_MEMORY_MB").map(|mb| mb * 1024 * 1024),
+        open_files: read("CLOUD_COMPILER_LIMIT_OPEN_FILES"),
+    }
+});
+
+// Whether the operator set any of the process limits
+pub fn limits_configured() -> bool {
+    let limits = *PROCESS_LIMITS;
+    limits.cpu_secs.is_some() || limits.memory_bytes.is_some() || limits.open_files.is_some()
+}
+
+// Apply the limits in the child before it execs, children inherit them
+pub fn apply_limits(process: &mut TokioCommand) {
+    #[cfg(unix)]
+    {
+        let limits = *PROCESS_LIMITS;
+        if !limits_configured() {
+            return;
+        }
+        // Only async-signal-safe calls between fork and exec
+        unsafe {
+            process.pre_exec(move || {
+                for (resource, value) in [
+                    (libc::RLIMIT_CPU, limits.cpu_secs),
+                    (libc::RLIMIT_AS, limits.memory_bytes),
+                    (libc::RLIMIT_NOFILE, limits.open_files),
+                ] {
+                    if let Some(value) = value {
+                        let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t };
+                        if libc::setrlimit(resource, &limit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = process;
+}
+
+// `args` as they may be logged, traced, listed as a resource or sent back: OTA passwords masked.
+// arduino-cli takes upload fields only on its command line (or typed at a terminal), so this is
+// all that keeps them out of the server's records.
+pub fn redact_args(args: &[String]) -> Vec<String> {
+    args.iter()
+        .map(|arg| match arg.starts_with("password=") {
+            true => "password=***".to_string(),
+            false => arg.clone(),
+        })
+        .collect()
+}
+
 // Helper function to run Arduino CLI commands
+#[instrument(name = "arduino_cli", skip_all, fields(command = %command.command, args = ?redact_args(&command.args)))]
 pub async fn run_arduino_command(command: &ArduinoCommand) -> CommandResponse {
-    let arduino_cli_path = get_arduino_cli_path();
+    if let Err(e) = check_policy(command) {
+        return CompilerError::PolicyViolation(e.to_string()).response(&command.command, redact_args(&command.args));
+    }
+    let arduino_cli_path = toolchain::arduino_cli().unwrap_or_else(|| get_arduino_cli_path().clone());
+    let mut response = run_arduino_cli(command, &arduino_cli_path).await;
+    response.arduino_cli_version = resolved_arduino_cli_version(&arduino_cli_path).await;
+    response
+}
+
+async fn run_arduino_cli(command: &ArduinoCommand, arduino_cli_path: &Path) -> CommandResponse {
+    let selected = toolchain::selected();
+
+    info!("Running Arduino CLI command: {} {:?}", command.command, redact_args(&command.args));
+
+    // Compiles run sketch code through the toolchain, so they go through the sandbox backend
+    let sandbox = sandbox();
+    if
+        command.command == "compile" &&
+        *sandbox == Sandbox::Direct &&
+        selected.is_none() &&
+        let Some(response) = daemon::compile(&command.args).await
+    {
+        return classified(response);
+    }
+    let process = if command.command == "compile" && *sandbox != Sandbox::Direct {
+        let mut args = vec![command.command.clone()];
+        args.extend(command.args.iter().cloned());
+        sandbox.command(arduino_cli_path, &args, &Mounts::compile(&command.args), command_timeout(&command.command))
+    } else {
+        let mut process = TokioCommand::new(arduino_cli_path);
+        process.arg(&command.command).args(&command.args);
+        if let Some(dir) = &selected {
+            process.env("ARDUINO_DIRECTORIES_DATA", dir);
+        }
+        apply_limits(&mut process);
+        process
+    };
+
+    let response = execute(process, &command.command, &command.args).await;
+    if matches!(command.command.as_str(), "core" | "lib") {
+        daemon::reload().await;
+    }
+    response
+}
+
+// Directory of a sketch given either the folder or its main .ino
+pub fn sketch_dir(sketch_path: &Path) -> &Path {
+    if sketch_path.is_dir() { sketch_path } else { sketch_path.parent().unwrap_or(sketch_path) }
+}
+
+// Resolve the build properties arduino-cli would use for `sketch_path` on `fqbn`
+pub async fn build_properties(fqbn: &str, sketch_path: &str) -> Result<HashMap<String, String>, String> {
+    let command = ArduinoCommand {
+        command: "compile".to_string(),
+        args: vec![
+            "--fqbn".to_string(),
+            fqbn.to_string(),
+            "--show-properties".to_string(),
+            sketch_path.to_string()
+        ],
+    };
+    let response = run_arduino_command(&command).await;
+    if !response.success {
+        return Err(response.error.unwrap_or_default());
+    }
+
+    Ok(
+        response.output
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect()
+    )
+}
 
-    let cmd_name = &command.command;
-    let args = &command.args;
+// Have arduino-cli write the compilation database of `sketch_path` on `fqbn` into `build_path`:
+// libraries are resolved and the sketch preprocessed, nothing is compiled
+pub async fn compilation_database(fqbn: &str, sketch_path: &str, build_path: &Path) -> CommandResponse {
+    let command = ArduinoCommand {
+        command: "compile".to_string(),
+        args: vec![
+            "--fqbn".to_string(),
+            fqbn.to_string(),
+            "--only-compilation-database".to_string(),
+            "--build-path".to_string(),
+            build_path.to_string_lossy().to_string(),
+            sketch_path.to_string(),
+        ],
+    };
+    run_arduino_command(&command).await
+}
+
+// Run an external tool (esptool, ...), reporting it under `cmd_name` in the response
+#[instrument(name = "program", skip_all, fields(program = %program.display(), command = cmd_name, args = ?args))]
+pub async fn run_program(program: &Path, cmd_name: &str, args: &[String]) -> CommandResponse {
+    info!("Running {}: {:?}", cmd_name, args);
+
+    let mut process = TokioCommand::new(program);
+    process.args(args);
+
+    execute(process, cmd_name, args).await
+}
+
+// `run_program` with extra environment variables for the tool
+#[instrument(name = "program", skip_all, fields(program = %program.display(), command = cmd_name, args = ?args))]
+pub async fn run_program_with_env(program: &Path, cmd_name: &str, args: &[String], env: &[(&str, &str)]) -> CommandResponse {
+    info!("Running {}: {:?}", cmd_name, args);
 
-    info!("Running Arduino CLI command: {} {:?}", cmd_name, args);
+    let mut process = TokioCommand::new(program);
+    process.args(args).envs(env.iter().copied());
 
-    let output = TokioCommand::new(arduino_cli_path).arg(cmd_name).args(args).output().await;
+    execute(process, cmd_name, args).await
+}
+
+// `run_program` for toolchain programs reading sketch code (gcc outside of arduino-cli), through
+// the sandbox backend and under the process limits like compiles. `dirs` are the job's
+// directories the sandbox lets it at.
+#[instrument(name = "program", skip_all, fields(program = %program.display(), command = cmd_name, args = ?args))]
+pub async fn run_toolchain_program(program: &Path, cmd_name: &str, args: &[String], dirs: &[PathBuf]) -> CommandResponse {
+    info!("Running {}: {:?}", cmd_name, args);
+
+    let sandbox = sandbox();
+    let process = if *sandbox != Sandbox::Direct {
+        sandbox.command(program, args, &Mounts::writable(dirs.to_vec()), command_timeout(cmd_name))
+    } else {
+        let mut process = TokioCommand::new(program);
+        process.args(args);
+        apply_limits(&mut process);
+        process
+    };
+
+    execute(process, cmd_name, args).await
+}
+
+// Written into the build path by `compilation_database`
+pub const COMPILATION_DATABASE: &str = "compile_commands.json";
+
+// Time limits per command, overridable with `CLOUD_COMPILER_TIMEOUT_<COMMAND>` in seconds
+const DEFAULT_TIMEOUTS: &[(&str, u64)] = &[
+    ("compile", 600),
+    ("upload", 300),
+    ("core", 1800),
+    ("esptool", 300),
+    ("git", 300),
+    ("clang-format", 60),
+    ("check", 60),
+];
+const DEFAULT_TIMEOUT: u64 = 600;
+
+// How long `cmd_name` may run before it is killed
+pub fn command_timeout(cmd_name: &str) -> Duration {
+    let variable = format!("CLOUD_COMPILER_TIMEOUT_{}", cmd_name.to_uppercase().replace('-', "_"));
+    let seconds = std::env
+        ::var(variable)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .or_else(|| DEFAULT_TIMEOUTS.iter().find(|(name, _)| *name == cmd_name).map(|(_, secs)| *secs))
+        .unwrap_or(DEFAULT_TIMEOUT);
+    Duration::from_secs(seconds)
+}
+
+// Kill everything the process started (gcc, esptool, ...), not just the process itself
+pub fn kill_process_group(pid: Option<u32>) {
+    #[cfg(unix)]
+    if let Some(pid) = pid {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    // Windows has no process groups to signal, taskkill walks the tree by parent instead
+    #[cfg(windows)]
+    if let Some(pid) = pid {
+        let killed = std::process::Command
+            ::new("taskkill")
+            .args(["/PID", &pid.to_string(), "/T", "/F"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if let Err(e) = killed {
+            tracing::warn!("Failed to run taskkill for {}: {}", pid, e);
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    let _ = pid;
+}
+
+// Run a child without a console window of its own, which Windows otherwise opens for every
+// console program a service starts
+pub fn hide_console(process: &mut TokioCommand) {
+    #[cfg(windows)]
+    {
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        process.creation_flags(CREATE_NO_WINDOW);
+    }
+    #[cfg(not(windows))]
+    let _ = process;
+}
+
+// A serial port as arduino-cli and esptool name it. Windows ports are case-insensitive and may
+// come in the device namespace form (`\\.\COM10`), both named `COM10` so one device is one
+// port resource; other systems' device paths are kept as given.
+pub fn port_name(port: &str) -> String {
+    let port = port.trim();
+    let name = port.strip_prefix(r"\\.\").unwrap_or(port);
+    match name.get(..3).is_some_and(|prefix| prefix.eq_ignore_ascii_case("com")) && name[3..].parse::<u32>().is_ok() {
+        true => name.to_uppercase(),
+        false => port.to_string(),
+    }
+}
+
+// Everything printed on a pipe, each line passed to the job's output sink as it arrives
+async fn read_pipe(pipe: Option<impl AsyncRead + Unpin>, stream: LogStream) -> std::io::Result<Vec<u8>> {
+    let mut collected = Vec::new();
+    let Some(pipe) = pipe else {
+        return Ok(collected);
+    };
+    let mut reader = BufReader::new(pipe);
+    loop {
+        let start = collected.len();
+        if reader.read_until(b'\n', &mut collected).await? == 0 {
+            return Ok(collected);
+        }
+        output::forward(stream, &String::from_utf8_lossy(&collected[start..]));
+    }
+}
+
+// Wait for a prepared process and collect its output into a response. The process is
+// registered as a resource and killed if it is released or runs past its timeout. `args` are
+// only reported, redacted.
+#[instrument(name = "process", skip_all, fields(command = cmd_name, exit_code))]
+async fn execute(process: TokioCommand, cmd_name: &str, args: &[String]) -> CommandResponse {
+    let args = &redact_args(args);
+    // Reported to the `status` room while it runs
+    let _index_update = args.iter().any(|arg| arg == "update-index").then(status::index_update);
+    let timing = Timing::start();
+    let mut response = wait_for(process, cmd_name, args).await;
+    timing.stamp(&mut response);
+    response
+}
+
+async fn wait_for(mut process: TokioCommand, cmd_name: &str, args: &[String]) -> CommandResponse {
+    let guard = acquire(ResourceKind::Process, format!("{} {}", cmd_name, args.join(" ")));
+    // Cancelled before it got to run, in the queue say
+    if guard.token().is_cancelled() {
+        return CompilerError::Cancelled.response(cmd_name, args.to_vec());
+    }
+    process.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+    // Its own process group, so the whole tree can be killed at once
+    #[cfg(unix)]
+    process.process_group(0);
+    hide_console(&mut process);
+    let timeout = command_timeout(cmd_name);
+
+    let output = match process.spawn() {
+        Ok(mut child) => {
+            let pid = child.id();
+            let stdout = child.stdout.take();
+            let stderr = child.stderr.take();
+            // Read as it is printed, so a job with an output sink can follow along
+            let wait = async {
+                let (stdout, stderr, status) = tokio::join!(
+                    read_pipe(stdout, LogStream::Stdout),
+                    read_pipe(stderr, LogStream::Stderr),
+                    child.wait()
+                );
+                Ok::<_, std::io::Error>(std::process::Output { status: status?, stdout: stdout?, stderr: stderr? })
+            };
+            tokio::select! {
+                output = wait => output,
+                // Dropping the wait future drops the child, which kills it
+                _ = guard.cancelled() => {
+                    kill_process_group(pid);
+                    return CompilerError::Cancelled.response(cmd_name, args.to_vec());
+                }
+                _ = tokio::time::sleep(timeout) => {
+                    kill_process_group(pid);
+                    return CompilerError::Timeout(timeout.as_secs()).response(cmd_name, args.to_vec());
+                }
+            }
+        }
+        Err(e) => Err(e),
+    };
 
     match output {
         Ok(output) => {
+            if let Some(code) = output.status.code() {
+                tracing::Span::current().record("exit_code", code);
+            }
             let stdout = String::from_utf8_lossy(&output.stdout).to_string();
             let stderr = String::from_utf8_lossy(&output.stderr).to_string();
 
-            CommandResponse {
+            let stderr = Some(stderr).filter(|stderr| !stderr.is_empty());
+            classified(CommandResponse {
                 success: output.status.success(),
                 output: stdout,
-                error: if stderr.is_empty() {
-                    None
-                } else {
-                    Some(stderr)
-                },
-                command: cmd_name.clone(),
-                args: args.clone(),
-            }
+                error: stderr.clone(),
+                command: cmd_name.to_string(),
+                args: args.to_vec(),
+                stderr,
+                exit_code: output.status.code(),
+                ..Default::default()
+            })
+        }
+        Err(e) => error_response(cmd_name, args.to_vec(), &format!("Failed to execute command: {}", e)),
+    }
+}
+
+// When a run started, for the timestamps and duration of its response
+pub struct Timing {
+    started_at: u64,
+    started: std::time::Instant,
+}
+
+impl Timing {
+    pub fn start() -> Self {
+        Timing { started_at: unix_millis(), started: std::time::Instant::now() }
+    }
+
+    pub fn stamp(&self, response: &mut CommandResponse) {
+        let duration = self.started.elapsed().as_millis() as u64;
+        response.started_at = Some(self.started_at);
+        response.finished_at = Some(self.started_at + duration);
+        response.duration_ms = Some(duration);
+    }
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime
+        ::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// A failed run with the kind of failure its output tells, see `errors::classify`
+fn classified(mut response: CommandResponse) -> CommandResponse {
+    if !response.success && response.error_code.is_none() {
+        response.error_code = classify(&response.command, &response.output, response.error.as_deref().unwrap_or_default());
+    }
+    response
+}
+
+// Build a failed response without running anything
+pub fn error_response(command: &str, args: Vec<String>, message: &str) -> CommandResponse {
+    CommandResponse {
+        success: false,
+        output: String::new(),
+        error: Some(message.to_string()),
+        command: command.to_string(),
+        args,
+        ..Default::default()
+    }
+}
+
+// Directory for state owned by this server (build outputs, keys, ...)
+pub fn server_data_dir() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("arduino-cloud-compiler"))
+}
+
+// Names used as directory components must not be able to escape their parent
+pub fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() &&
+        !name.starts_with('.') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn home_dir() -> PathBuf {
+    std::env
+        ::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+// Arduino user directory (sketchbook) where libraries are installed
+pub fn arduino_user_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("ARDUINO_DIRECTORIES_USER") {
+        return PathBuf::from(dir);
+    }
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    return home_dir().join("Documents").join("Arduino");
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    return home_dir().join("Arduino");
+}
+
+// Arduino data directory where cores and their tools are installed, the selected toolchain's
+// inside a toolchain scope
+pub fn arduino_data_dir() -> PathBuf {
+    if let Some(dir) = toolchain::selected() {
+        return dir;
+    }
+    if let Some(dir) = std::env::var_os("ARDUINO_DIRECTORIES_DATA") {
+        return PathBuf::from(dir);
+    }
+    let home = home_dir();
+
+    #[cfg(target_os = "macos")]
+    return home.join("Library").join("Arduino15");
+    #[cfg(target_os = "windows")]
+    return std::env
+        ::var_os("LOCALAPPDATA")
+        .map(PathBuf::from)
+        .unwrap_or(home)
+        .join("Arduino15");
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    return home.join(".arduino15");
+}
+
+// Error and warning lines of gcc output (`file:line:col: error: message`) as diagnostics
+pub fn compiler_diagnostics(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for line in output.lines() {
+        let Some((location, severity, message)) = [
+            (": fatal error: ", Severity::Error),
+            (": error: ", Severity::Error),
+            (": warning: ", Severity::Warning),
+        ]
+            .into_iter()
+            .find_map(|(marker, severity)| {
+                line.split_once(marker).map(|(location, message)| (location, severity, message))
+            }) else {
+            continue;
+        };
+
+        // Drive letters put a colon in Windows paths, so split from the right
+        let mut parts = location.rsplitn(3, ':');
+        let (file, line_number) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(_column), Some(line), Some(file)) if line.parse::<u32>().is_ok() => (file, line.parse().ok()),
+            (Some(line), Some(file), None) if line.parse::<u32>().is_ok() => (file, line.parse().ok()),
+            _ => (location, None),
+        };
+
+        let diagnostic = Diagnostic {
+            severity,
+            code: "COMPILER".to_string(),
+            message: message.trim().to_string(),
+            file: Some(file.trim().to_string()),
+            line: line_number,
+        };
+        let duplicate = diagnostics
+            .iter()
+            .any(|d| d.message == diagnostic.message && d.file == diagnostic.file && d.line == diagnostic.line);
+        if !duplicate {
+            diagnostics.push(diagnostic);
+        }
+    }
+    diagnostics
+}
+
+// First number after `marker` in `line`
+fn number_after(line: &str, marker: &str) -> Option<u64> {
+    let rest = &line[line.find(marker)? + marker.len()..];
+    rest.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+}
+
+// Sizes of arduino-cli's report ("Sketch uses N bytes (P%) of program storage space. Maximum is
+// M bytes." and "Global variables use N bytes ...")
+pub fn memory_usage(output: &str) -> Option<MemoryUsage> {
+    let flash = output.lines().find(|line| line.starts_with("Sketch uses "))?;
+    let ram = output.lines().find(|line| line.starts_with("Global variables use "));
+    Some(MemoryUsage {
+        flash_bytes: number_after(flash, "Sketch uses ")?,
+        flash_max: number_after(flash, "Maximum is "),
+        ram_bytes: ram.and_then(|line| number_after(line, "Global variables use ")),
+        ram_max: ram.and_then(|line| number_after(line, "Maximum is ")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(command: &str, args: &[&str]) -> ArduinoCommand {
+        ArduinoCommand { command: command.to_string(), args: args.iter().map(|arg| arg.to_string()).collect() }
+    }
+
+    #[test]
+    fn allows_vetted_commands() {
+        let allowed = [
+            command("compile", &["--fqbn", "esp32:esp32:esp32", "--output-dir", "/tmp/build", "/workspace/Blink"]),
+            command("compile", &["--fqbn", "esp32:esp32:esp32s3:PSRAM=opi,FlashSize=16M", "--preprocess", "/workspace/Blink"]),
+            command("core", &["install", "esp32:esp32@3.0.7"]),
+            command("lib", &["install", "Adafruit NeoPixel@1.12.0"]),
+            command("board", &["list", "--format", "json"]),
+            command("upload", &["--port", "/dev/ttyUSB0", "--fqbn", "esp32:esp32:esp32", "--upload-field", "password=secret", "/workspace/Blink"]),
+        ];
+        for command in &allowed {
+            assert!(check_policy(command).is_ok(), "{} {:?} should be allowed", command.command, command.args);
         }
-        Err(e) =>
-            CommandResponse {
-                success: false,
-                output: String::new(),
-                error: Some(format!("Failed to execute command: {}", e)),
-                command: cmd_name.clone(),
-                args: args.clone(),
-            },
+    }
+
+    #[test]
+    fn rejects_unknown_commands_actions_and_flags() {
+        assert!(matches!(check_policy(&command("config", &["dump"])), Err(PolicyError::Command(_))));
+        assert!(matches!(check_policy(&command("core", &["uninstall", "esp32:esp32"])), Err(PolicyError::Action(_))));
+        assert!(matches!(check_policy(&command("core", &[])), Err(PolicyError::Action(_))));
+        assert!(matches!(check_policy(&command("compile", &["--config-file", "/etc/x", "/workspace/Blink"])), Err(PolicyError::Flag(_))));
+        assert!(matches!(check_policy(&command("compile", &["-v", "/workspace/Blink"])), Err(PolicyError::Flag(_))));
+    }
+
+    #[test]
+    fn rejects_flags_with_inline_values() {
+        // Only the separate form is checked, `--flag=value` would carry an unchecked value
+        let inline = command("compile", &["--fqbn=esp32:esp32:esp32", "/workspace/Blink"]);
+        assert!(matches!(check_policy(&inline), Err(PolicyError::Flag(flag)) if flag == "--fqbn=esp32:esp32:esp32"));
+        let inline = command("board", &["list", "--format=json"]);
+        assert!(matches!(check_policy(&inline), Err(PolicyError::Flag(_))));
+    }
+
+    #[test]
+    fn rejects_injected_values() {
+        // A flag in place of a value
+        let smuggled = command("compile", &["--fqbn", "--build-property", "/workspace/Blink"]);
+        assert!(matches!(check_policy(&smuggled), Err(PolicyError::Value(..))));
+        let missing = command("compile", &["/workspace/Blink", "--fqbn"]);
+        assert!(matches!(check_policy(&missing), Err(PolicyError::Value(..))));
+        let control = command("compile", &["--fqbn", "esp32:esp32:esp32\n--verbose", "/workspace/Blink"]);
+        assert!(matches!(check_policy(&control), Err(PolicyError::Value(..))));
+        let options = command("compile", &["--fqbn", "esp32:esp32:esp32:PSRAM=$(reboot)", "/workspace/Blink"]);
+        assert!(matches!(check_policy(&options), Err(PolicyError::Value(..))));
+        let format = command("board", &["list", "--format", "yaml"]);
+        assert!(matches!(check_policy(&format), Err(PolicyError::Value(..))));
+    }
+
+    #[test]
+    fn rejects_extra_operands() {
+        let second = command("compile", &["--fqbn", "esp32:esp32:esp32", "/workspace/Blink", "/workspace/Other"]);
+        assert!(matches!(check_policy(&second), Err(PolicyError::Operand(_))));
+        let none_taken = command("board", &["list", "extra"]);
+        assert!(matches!(check_policy(&none_taken), Err(PolicyError::Operand(_))));
+        let bad_core = command("core", &["install", "esp32:esp32@3.0;reboot"]);
+        assert!(matches!(check_policy(&bad_core), Err(PolicyError::Operand(_))));
+        let bad_library = command("lib", &["install", "Servo;reboot"]);
+        assert!(matches!(check_policy(&bad_library), Err(PolicyError::Operand(_))));
+    }
+
+    #[test]
+    fn checks_values_by_kind() {
+        assert!(valid_value(ArgKind::Fqbn, "esp32:esp32:esp32"));
+        assert!(!valid_value(ArgKind::Fqbn, "esp32:esp32"));
+        assert!(!valid_value(ArgKind::Fqbn, "esp32:esp32:../../x"));
+        assert!(valid_value(ArgKind::Core, "esp32:esp32"));
+        assert!(valid_value(ArgKind::Core, "esp32:esp32@3.0.7"));
+        assert!(!valid_value(ArgKind::Core, "esp32:esp32@../3.0.7"));
+        assert!(!valid_value(ArgKind::Core, "esp32"));
+        assert!(valid_value(ArgKind::UploadField, "password=secret"));
+        assert!(!valid_value(ArgKind::UploadField, "username=admin"));
+        assert!(valid_value(ArgKind::Protocol, "network"));
+        assert!(!valid_value(ArgKind::Protocol, "ssh"));
+        assert!(!valid_value(ArgKind::Path, ""));
+        assert!(!valid_value(ArgKind::Path, "-/workspace"));
     }
 }
@@ -0,0 +1,271 @@
+// Server configuration from a file, the environment and command line flags. Every setting is
+// the environment variable the module using it reads (CLOUD_COMPILER_WORKERS, ...); the file and
+// the flags fill in the same variables before anything starts, so a flag beats the environment,
+// which beats the file, which beats the built-in default.
+//
+// The file is TOML, `--config <file>`, CLOUD_COMPILER_CONFIG or `cloud-compiler.toml` in the
+// working directory. Its keys are the variable names without the CLOUD_COMPILER_ prefix, in
+// lower case, optionally grouped in tables joined with `_`:
+//
+//   port = 8080
+//   workers = 4
+//   warm_fqbns = ["esp32:esp32:esp32", "esp32:esp32:esp32s3"]
+//   [timeout]
+//   compile = 900            # CLOUD_COMPILER_TIMEOUT_COMPILE
+//   [cache]
+//   max_mb = 512             # CLOUD_COMPILER_CACHE_MAX_MB
+//
+// `arduino_directories_data`, `arduino_directories_user` and the `aws_*` keys set the variables
+// of the same name, without prefix.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use clap::Parser;
+use tracing::{ info, warn };
+
+const PREFIX: &str = "CLOUD_COMPILER_";
+const DEFAULT_FILE: &str = "cloud-compiler.toml";
+const DEFAULT_BIND: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 3000;
+
+// Settings read by the modules, without prefix, for catching typos in the file
+const SETTINGS: &[&str] = &[
+    "BIND",
+    "PORT",
+    "TLS_CERT",
+    "TLS_KEY",
+    "TLS_RELOAD_SECS",
+    "ACME_DOMAINS",
+    "ACME_EMAIL",
+    "ACME_CACHE_DIR",
+    "ACME_PRODUCTION",
+    "CORS_ORIGINS",
+    "CORS_HEADERS",
+    "CORS_CREDENTIALS",
+    "DATA_DIR",
+    "WORKSPACE_ROOT",
+    "ARDUINO_CLI",
+    "ARDUINO_CLI_SHA256",
+    "DAEMON",
+    "TOOLCHAINS_DIR",
+    "PROVISION_MANIFEST",
+    "WARM_FQBNS",
+    "PUBLIC_URL",
+    "LOCALES_DIR",
+    "POWER_CYCLE_COMMAND",
+    "WORKERS",
+    "QUEUE_LIMIT",
+    "PRIORITY_AGING_SECS",
+    "IDENTITY_PRIORITIES",
+    "SANDBOX",
+    "SANDBOX_PROGRAM",
+    "SANDBOX_IMAGE",
+    "LIMIT_CPU_SECS",
+    "LIMIT_MEMORY_MB",
+    "LIMIT_OPEN_FILES",
+    "RATE_LIMIT_EVENTS",
+    "RATE_LIMIT_COMPILES",
+    "RATE_LIMIT_HTTP",
+    "TRUSTED_PROXIES",
+    "CACHE_BACKEND",
+    "CACHE_TTL_SECS",
+    "CACHE_MAX_MB",
+    "CACHE_REDIS_URL",
+    "CACHE_S3_BUCKET",
+    "CACHE_S3_REGION",
+    "CACHE_S3_ENDPOINT",
+    "CACHE_S3_PREFIX",
+    "ADMIN_TOKEN",
+    "API_KEYS",
+    "JWT_SECRET",
+    "JWT_ISSUER",
+    "JWT_AUDIENCE",
+    "JWKS",
+    "QUOTA_COMPILE_MINUTES",
+    "QUOTA_UPLOADS",
+    "QUOTA_STORAGE_MB",
+    "QUOTA_PERIOD_SECS",
+    "MQTT_URL",
+    "MQTT_PREFIX",
+    "ANALYTICS",
+    "ANALYTICS_PERIOD_SECS",
+    "SHUTDOWN_GRACE_SECS",
+    "KEEPALIVE_SECS",
+    "LOG_FORMAT",
+    "OTLP_ENDPOINT",
+    "OTLP_SERVICE_NAME",
+    "READY_CORES",
+    "READY_MIN_FREE_MB",
+    "HISTORY_DB",
+    "WEBHOOK_URLS",
+    "WEBHOOK_SECRET",
+    "ARTIFACT_BACKEND",
+    "ARTIFACT_S3_BUCKET",
+    "ARTIFACT_S3_REGION",
+    "ARTIFACT_S3_ENDPOINT",
+    "ARTIFACT_S3_PREFIX",
+    "ARTIFACT_URL_SECRET",
+    "ARTIFACT_URL_TTL_SECS",
+    "RETENTION_MAX_AGE_HOURS",
+    "RETENTION_MAX_TOTAL_MB",
+    "RETENTION_MAX_USER_MB",
+    "RETENTION_WORKSPACE_MAX_AGE_HOURS",
+    "RETENTION_INTERVAL_SECS",
+    "GIT",
+    "GIT_HOSTS",
+    "GIT_MAX_MB",
+    "SIZE_REGRESSION_PERCENT",
+    "CLANG_FORMAT",
+    "ARDUINO_LINT",
+    "CPPCHECK",
+    "LSP",
+    "CLANGD",
+    "LSP_MAX_SESSIONS",
+    "MSGPACK",
+    "GRPC_PORT",
+    "CLUSTER_REDIS_URL",
+    "ROLE",
+    "WORKER_TOKEN",
+    "DISPATCHER_URL",
+    "WORKER_NAME",
+    "WORKER_SLOTS",
+    "NATS_URL",
+    "NATS_SUBJECT",
+    "GITHUB_WEBHOOK_SECRET",
+    "GITHUB_BUILDS",
+    "GITHUB_TOKEN",
+    "GITHUB_API_URL",
+];
+
+// Variables set by unprefixed keys
+const UNPREFIXED: &[&str] = &[
+    "ARDUINO_DIRECTORIES_DATA",
+    "ARDUINO_DIRECTORIES_USER",
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+];
+
+#[derive(Parser)]
+#[command(version, about = "Cloud compiler for Arduino ESP32 sketches")]
+struct Flags {
+    #[arg(long, short = 'c', value_name = "FILE", help = "Configuration file (TOML)")]
+    config: Option<PathBuf>,
+    #[arg(long, value_name = "ADDRESS", help = "Address to listen on [default: 0.0.0.0]")]
+    bind: Option<String>,
+    #[arg(long, short = 'p', help = "Port to listen on [default: 3000]")]
+    port: Option<u16>,
+    #[arg(long, value_name = "FILE", requires = "tls_key", help = "TLS certificate chain (PEM), serves HTTPS")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", requires = "tls_cert", help = "TLS private key (PEM)")]
+    tls_key: Option<PathBuf>,
+    #[arg(long, value_name = "DIR", help = "Directory for builds, keys and other server state")]
+    data_dir: Option<PathBuf>,
+    #[arg(long, value_name = "DIR", help = "Root of the per-client workspaces")]
+    workspace_root: Option<PathBuf>,
+    #[arg(long, value_name = "PATH", help = "arduino-cli binary to use instead of the embedded one")]
+    arduino_cli: Option<PathBuf>,
+    #[arg(long, help = "Number of arduino-cli jobs running at once")]
+    workers: Option<usize>,
+    #[arg(long = "set", value_name = "KEY=VALUE", help = "Any other setting, by its file key (`cache.max_mb=512`)")]
+    set: Vec<String>,
+}
+
+// Variable a file key sets, None for keys nothing reads
+fn variable(key: &str) -> Option<String> {
+    let name = key.replace(['.', '-'], "_").to_uppercase();
+    if UNPREFIXED.contains(&name.as_str()) {
+        return Some(name);
+    }
+    let known = SETTINGS.contains(&name.as_str()) || name.starts_with("TIMEOUT_");
+    known.then(|| format!("{}{}", PREFIX, name))
+}
+
+// Flatten a TOML table into (key, value) pairs, nested tables joined with `_`
+fn flatten(prefix: &str, table: &toml::Table, settings: &mut BTreeMap<String, String>) {
+    for (key, value) in table {
+        let key = match prefix {
+            "" => key.clone(),
+            prefix => format!("{}_{}", prefix, key),
+        };
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten(&key, table, settings);
+                continue;
+            }
+            toml::Value::String(value) => value.clone(),
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::String(item) => item.clone(),
+                    item => item.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            value => value.to_string(),
+        };
+        settings.insert(key, value);
+    }
+}
+
+fn read_file(path: &PathBuf) -> Result<BTreeMap<String, String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let table: toml::Table = toml::from_str(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    let mut settings = BTreeMap::new();
+    flatten("", &table, &mut settings);
+    Ok(settings)
+}
+
+fn set(name: &str, value: &str) {
+    // Only called from `load`, before the runtime or any other thread exists
+    unsafe {
+        std::env::set_var(name, value);
+    }
+}
+
+// Apply the file and the flags to the environment and return the address to listen on. Must run
+// before any thread is started.
+pub fn load() -> Result<(String, u16), String> {
+    let flags = Flags::parse();
+
+    let file = flags.config
+        .clone()
+        .or_else(|| std::env::var_os("CLOUD_COMPILER_CONFIG").map(PathBuf::from))
+        .or_else(|| Some(PathBuf::from(DEFAULT_FILE)).filter(|path| path.is_file()));
+    if let Some(path) = &file {
+        info!("Configuration file: {}", path.display());
+        for (key, value) in read_file(path)? {
+            match variable(&key) {
+                // The environment wins over the file
+                Some(name) if std::env::var_os(&name).is_none() => set(&name, &value),
+                Some(_) => {}
+                None => warn!("Unknown setting {} in {}", key, path.display()),
+            }
+        }
+    }
+
+    let path = |path: &Option<PathBuf>| path.as_ref().map(|path| path.to_string_lossy().to_string());
+    let named = [
+        ("bind", flags.bind.clone()),
+        ("port", flags.port.map(|port| port.to_string())),
+        ("tls_cert", path(&flags.tls_cert)),
+        ("tls_key", path(&flags.tls_key)),
+        ("data_dir", path(&flags.data_dir)),
+        ("workspace_root", path(&flags.workspace_root)),
+        ("arduino_cli", path(&flags.arduino_cli)),
+        ("workers", flags.workers.map(|workers| workers.to_string())),
+    ];
+    for (key, value) in named.into_iter().filter_map(|(key, value)| Some((key, value?))) {
+        set(&variable(key).expect("flags name known settings"), &value);
+    }
+    for setting in &flags.set {
+        let (key, value) = setting.split_once('=').ok_or_else(|| format!("--set {}: expected KEY=VALUE", setting))?;
+        let name = variable(key.trim()).ok_or_else(|| format!("--set {}: unknown setting", key))?;
+        set(&name, value.trim());
+    }
+
+    let bind = std::env::var("CLOUD_COMPILER_BIND").unwrap_or_else(|_| DEFAULT_BIND.to_string());
+    let port = match std::env::var("CLOUD_COMPILER_PORT") {
+        Ok(port) => port.trim().parse().map_err(|_| format!("Invalid port {}", port))?,
+        Err(_) => DEFAULT_PORT,
+    };
+    Ok((bind, port))
+}
@@ -0,0 +1,279 @@
+// A client connection as event handlers see it, over Socket.IO or the plain WebSocket protocol
+// of `ws`. Handlers register on it, emit events to it and answer through its `Ack`, so every
+// operation is served the same way on both transports.
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use axum::http::request::Parts;
+use serde::Serialize;
+use serde_json::Value;
+use socketioxide::adapter::Adapter;
+use socketioxide::extensions::Extensions;
+use socketioxide::extract::{ AckSender, SocketRef, TryData };
+use socketioxide::handler::MessageHandler;
+use socketioxide::socket::{ Sid, Socket };
+use crate::cluster::{ self, ClusterAdapter, Session };
+use crate::msgpack;
+use crate::resources::{ with_request, Request };
+use crate::ws::WsClient;
+
+pub type Handler = Arc<dyn Fn(Connection, Value, Ack) + Send + Sync>;
+
+#[derive(Clone)]
+pub enum Connection {
+    SocketIo(SocketRef),
+    // Socket.IO with rooms shared between instances
+    Cluster(SocketRef<ClusterAdapter>),
+    WebSocket(Arc<WsClient>),
+}
+
+// Longest `request_id` echoed, longer ones are ignored
+const MAX_REQUEST_ID: usize = 128;
+
+// Answers one event, if the client asked for an answer
+pub struct Ack {
+    answer: Answer,
+    event: &'static str,
+    socket: Connection,
+    request_id: Option<Value>,
+}
+
+// The `request_id` a client put in an event's payload, a string or a number, which its answer,
+// progress events and output lines carry too so several operations on one socket can be told apart
+pub fn request_id(data: &Value) -> Option<Value> {
+    match &data["request_id"] {
+        Value::String(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID => Some(data["request_id"].clone()),
+        Value::Number(_) => Some(data["request_id"].clone()),
+        _ => None,
+    }
+}
+
+enum Answer {
+    SocketIo(AckSender),
+    Cluster(AckSender<ClusterAdapter>),
+    WebSocket(Option<u64>),
+}
+
+// The answer to an event whose socket went away first, sent to its session instead
+#[derive(Serialize)]
+struct JobResult<'a, T: ?Sized> {
+    event: &'a str,
+    response: &'a T,
+}
+
+impl From<SocketRef> for Connection {
+    fn from(socket: SocketRef) -> Self {
+        Connection::SocketIo(socket)
+    }
+}
+
+impl From<SocketRef<ClusterAdapter>> for Connection {
+    fn from(socket: SocketRef<ClusterAdapter>) -> Self {
+        Connection::Cluster(socket)
+    }
+}
+
+impl From<AckSender> for Answer {
+    fn from(ack: AckSender) -> Self {
+        Answer::SocketIo(ack)
+    }
+}
+
+impl From<AckSender<ClusterAdapter>> for Answer {
+    fn from(ack: AckSender<ClusterAdapter>) -> Self {
+        Answer::Cluster(ack)
+    }
+}
+
+// Runs a handler with the event's binaries inlined, for MessagePack framed sockets
+struct Inline<H>(H);
+
+impl<A: Adapter, H, T> MessageHandler<A, T> for Inline<H> where H: MessageHandler<A, T>, T: Send + Sync + 'static {
+    fn call(&self, socket: Arc<Socket<A>>, data: socketioxide::handler::Value, ack_id: Option<i64>) {
+        self.0.call(socket, msgpack::inline_binaries(data), ack_id)
+    }
+}
+
+fn on_socket<A: Adapter>(socket: &SocketRef<A>, event: &'static str, handler: Handler)
+    where Connection: From<SocketRef<A>>, Answer: From<AckSender<A>>
+{
+    socket.on(
+        event,
+        Inline(move |socket: SocketRef<A>, TryData::<Value>(data), ack: AckSender<A>| {
+            let socket = Connection::from(socket);
+            let ack = Ack { answer: Answer::from(ack), event, socket: socket.clone(), request_id: None };
+            handler(socket, data.unwrap_or_default(), ack);
+        })
+    );
+}
+
+fn on_socket_disconnect<A: Adapter>(socket: &SocketRef<A>, callback: impl Fn(Connection) + Send + Sync + 'static)
+    where Connection: From<SocketRef<A>>
+{
+    // Socket.IO clones its callbacks
+    let callback = Arc::new(callback);
+    socket.on_disconnect(move |socket: SocketRef<A>| callback(Connection::from(socket)));
+}
+
+impl Connection {
+    pub fn id(&self) -> Sid {
+        match self {
+            Connection::SocketIo(socket) => socket.id,
+            Connection::Cluster(socket) => socket.id,
+            Connection::WebSocket(client) => client.id,
+        }
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        match self {
+            Connection::SocketIo(socket) => &socket.extensions,
+            Connection::Cluster(socket) => &socket.extensions,
+            Connection::WebSocket(client) => &client.extensions,
+        }
+    }
+
+    // The request the connection was opened with
+    pub fn req_parts(&self) -> &Parts {
+        match self {
+            Connection::SocketIo(socket) => socket.req_parts(),
+            Connection::Cluster(socket) => socket.req_parts(),
+            Connection::WebSocket(client) => &client.parts,
+        }
+    }
+
+    // Socket.IO namespace, or the path of the WebSocket endpoint
+    pub fn ns(&self) -> &str {
+        match self {
+            Connection::SocketIo(socket) => socket.ns(),
+            Connection::Cluster(socket) => socket.ns(),
+            Connection::WebSocket(_) => crate::ws::PATH,
+        }
+    }
+
+    // Put a Socket.IO connection in the room of its session. The WebSocket protocol has no rooms.
+    pub fn join_session(&self, session: &str) {
+        match self {
+            Connection::SocketIo(socket) => cluster::join(socket, session),
+            Connection::Cluster(socket) => cluster::join(socket, session),
+            Connection::WebSocket(_) => {}
+        }
+    }
+
+    pub fn join(&self, room: &str) {
+        match self {
+            Connection::SocketIo(socket) => socket.join(room.to_string()),
+            Connection::Cluster(socket) => socket.join(room.to_string()),
+            Connection::WebSocket(client) => {
+                client.rooms.lock().unwrap().insert(room.to_string());
+            }
+        }
+    }
+
+    pub fn leave(&self, room: &str) {
+        match self {
+            Connection::SocketIo(socket) => socket.leave(room.to_string()),
+            Connection::Cluster(socket) => socket.leave(room.to_string()),
+            Connection::WebSocket(client) => {
+                client.rooms.lock().unwrap().remove(room);
+            }
+        }
+    }
+
+    // A Socket.IO connection that went away but has a session to report to
+    fn detached(&self) -> bool {
+        let connected = match self {
+            Connection::SocketIo(socket) => socket.connected(),
+            Connection::Cluster(socket) => socket.connected(),
+            Connection::WebSocket(_) => true,
+        };
+        !connected && self.extensions().get::<Session>().is_some()
+    }
+
+    // Emit `event`, to the connection's session once the socket went away
+    pub fn emit<T: ?Sized + Serialize>(&self, event: &str, data: &T) -> Result<(), String> {
+        match self {
+            Connection::SocketIo(socket) if self.detached() => cluster::forward(socket, event, data),
+            Connection::Cluster(socket) if self.detached() => cluster::forward(socket, event, data),
+            Connection::SocketIo(socket) => socket.emit(event, data).map_err(|e| e.to_string()),
+            Connection::Cluster(socket) => socket.emit(event, data).map_err(|e| e.to_string()),
+            Connection::WebSocket(client) => client.send(Some(event), None, data),
+        }
+    }
+
+    // Handle `event`, whatever data it comes with
+    pub fn on(&self, event: &'static str, handler: impl Fn(Connection, Value, Ack) + Send + Sync + 'static) {
+        let handler: Handler = Arc::new(move |socket: Connection, data: Value, mut ack| {
+            let id = request_id(&data);
+            ack.request_id = id.clone();
+            let session = socket.extensions().get::<Session>().map(|session| session.0);
+            // Jobs of a socket with a session report to its next socket, unless told otherwise
+            let detach = data.get("detach").and_then(|v| v.as_bool()).unwrap_or(session.is_some());
+            with_request(Request { id, detach, session }, || handler(socket, data, ack));
+        });
+        match self {
+            Connection::SocketIo(socket) => on_socket(socket, event, handler),
+            Connection::Cluster(socket) => on_socket(socket, event, handler),
+            Connection::WebSocket(client) => {
+                client.handlers.lock().unwrap().insert(event, handler);
+            }
+        }
+    }
+
+    pub fn on_disconnect(&self, callback: impl Fn(Connection) + Send + Sync + 'static) {
+        match self {
+            Connection::SocketIo(socket) => on_socket_disconnect(socket, callback),
+            Connection::Cluster(socket) => on_socket_disconnect(socket, callback),
+            Connection::WebSocket(client) => client.disconnected.lock().unwrap().push(Box::new(callback)),
+        }
+    }
+
+    pub fn disconnect(self) -> Result<(), String> {
+        match self {
+            Connection::SocketIo(socket) => socket.disconnect().map_err(|e| e.to_string()),
+            Connection::Cluster(socket) => socket.disconnect().map_err(|e| e.to_string()),
+            Connection::WebSocket(client) => {
+                client.close();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Ack {
+    // The answer to event `id` of a WebSocket client, if it gave one, carrying its `request_id`.
+    // There are no sessions to forward it to, so the event's name isn't needed.
+    pub fn websocket(client: &Arc<WsClient>, id: Option<u64>, data: &Value) -> Self {
+        let socket = Connection::WebSocket(client.clone());
+        Ack { answer: Answer::WebSocket(id), event: "", socket, request_id: request_id(data) }
+    }
+
+    pub fn send<T: ?Sized + Serialize>(self, data: &T) -> Result<(), String> {
+        let Some(request_id) = &self.request_id else {
+            return self.answer(data);
+        };
+        let mut tagged = serde_json::to_value(data).map_err(|e| e.to_string())?;
+        if let Some(fields) = tagged.as_object_mut() {
+            fields.insert("request_id".to_string(), request_id.clone());
+        }
+        self.answer(&tagged)
+    }
+
+    fn answer<T: ?Sized + Serialize>(self, data: &T) -> Result<(), String> {
+        // The socket is gone, its session may still be listening
+        if self.socket.detached() {
+            return self.socket.emit("job-result", &JobResult { event: self.event, response: data });
+        }
+        match (self.answer, &self.socket) {
+            (Answer::SocketIo(ack), _) => ack.send(data).map_err(|e| e.to_string()),
+            (Answer::Cluster(ack), _) => ack.send(data).map_err(|e| e.to_string()),
+            (Answer::WebSocket(Some(id)), Connection::WebSocket(client)) => client.send(None, Some(id), data),
+            // Nothing to answer
+            (Answer::WebSocket(_), _) => Ok(()),
+        }
+    }
+}
+
+// Handlers a WebSocket client registered, by event
+pub type Handlers = Mutex<HashMap<&'static str, Handler>>;
+
+// Callbacks a WebSocket client runs when it goes away
+pub type Disconnected = Mutex<Vec<Box<dyn Fn(Connection) + Send + Sync>>>;
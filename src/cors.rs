@@ -0,0 +1,73 @@
+// Cross-origin access for browser clients hosted elsewhere. CLOUD_COMPILER_CORS_ORIGINS lists the
+// allowed origins (comma separated, `*` for any); without it no CORS headers are sent and only
+// same-origin pages can use the HTTP routes and Socket.IO polling.
+//
+// CLOUD_COMPILER_CORS_HEADERS lists the request headers allowed (default: whatever the preflight
+// asks for) and CLOUD_COMPILER_CORS_CREDENTIALS=1 allows cookies and Authorization headers.
+// WebSocket upgrades aren't covered by CORS, so Socket.IO handshakes from an origin that isn't
+// listed are refused explicitly.
+use std::sync::LazyLock;
+use std::time::Duration;
+use axum::{ extract::Request, http::{ header, HeaderValue, Method, StatusCode }, middleware::Next, response::{ IntoResponse, Response } };
+use tower_http::cors::{ AllowHeaders, AllowOrigin, CorsLayer };
+use tracing::info;
+use crate::ws;
+
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(600);
+
+// Allowed origins, None for same-origin only and an empty list for any
+static ORIGINS: LazyLock<Option<Vec<String>>> = LazyLock::new(|| {
+    let origins = std::env::var("CLOUD_COMPILER_CORS_ORIGINS").ok().filter(|value| !value.trim().is_empty())?;
+    let origins: Vec<String> = origins
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    info!("Cross-origin clients allowed from {}", origins.join(", "));
+    Some(origins.into_iter().filter(|origin| origin != "*").collect())
+});
+
+fn setting(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+// CORS layer for all routes, None when cross-origin access isn't configured
+pub fn layer() -> Option<CorsLayer> {
+    let origins = ORIGINS.as_ref()?;
+    let credentials = matches!(setting("CLOUD_COMPILER_CORS_CREDENTIALS").as_deref(), Some("1" | "true" | "yes"));
+    let allow_origin = match origins.is_empty() {
+        // `*` can't be combined with credentials, echoing the origin back can
+        true if credentials => AllowOrigin::mirror_request(),
+        true => AllowOrigin::any(),
+        false => AllowOrigin::list(origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok())),
+    };
+    let allow_headers = match setting("CLOUD_COMPILER_CORS_HEADERS") {
+        Some(headers) => AllowHeaders::list(
+            headers.split(',').filter_map(|name| name.trim().parse::<header::HeaderName>().ok())
+        ),
+        None => AllowHeaders::mirror_request(),
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+            .allow_headers(allow_headers)
+            .allow_credentials(credentials)
+            .max_age(PREFLIGHT_MAX_AGE)
+    )
+}
+
+// Refuse Socket.IO and WebSocket handshakes from origins that aren't allowed. Requests without an Origin
+// header don't come from a browser page and pass.
+pub async fn check_socket_origin(request: Request, next: Next) -> Response {
+    if
+        let Some(origins) = ORIGINS.as_ref() &&
+        !origins.is_empty() &&
+        (request.uri().path().starts_with("/socket.io") || request.uri().path() == ws::PATH) &&
+        let Some(origin) = request.headers().get(header::ORIGIN).and_then(|origin| origin.to_str().ok()) &&
+        !origins.iter().any(|allowed| allowed == origin)
+    {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,369 @@
+// Persistent arduino-cli daemon. A fresh arduino-cli process loads the package and library
+// indexes and every installed platform before it does anything, on each compile; with
+// CLOUD_COMPILER_DAEMON=1 compiles go to one long-running `arduino-cli daemon` over its gRPC API
+// instead, which keeps all of that loaded between requests and reports compile progress.
+//
+// Other commands still spawn arduino-cli, and so do sandboxed compiles, which can't share a
+// daemon. The process limits would add up over the daemon's whole lifetime, so the daemon is
+// not used when any is configured. After a `core` or `lib` command the daemon reloads its instance before the next
+// compile. If the daemon can't be started or dies, compiles fall back to spawning processes.
+//
+// Only the messages and fields used here are declared, decoding skips the rest.
+use std::process::Stdio;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::process::{ Child, Command as TokioCommand };
+use tokio::sync::Mutex;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{ Channel, Endpoint };
+use tracing::{ debug, info, instrument, warn };
+use crate::compiler::{ command_timeout, get_arduino_cli_path, hide_console, kill_process_group, limits_configured, Timing };
+use crate::errors::CompilerError;
+use crate::models::{ CommandResponse, LogStream };
+use crate::output;
+use crate::keepalive;
+use crate::resources::{ self, acquire, ResourceKind };
+
+const SERVICE: &str = "/cc.arduino.cli.commands.v1.ArduinoCoreService";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Instance {
+    #[prost(int32, tag = "1")]
+    id: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CreateRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CreateResponse {
+    #[prost(message, optional, tag = "1")]
+    instance: Option<Instance>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct DestroyRequest {
+    #[prost(message, optional, tag = "1")]
+    instance: Option<Instance>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct DestroyResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct InitRequest {
+    #[prost(message, optional, tag = "1")]
+    instance: Option<Instance>,
+}
+
+// google.rpc.Status
+#[derive(Clone, PartialEq, prost::Message)]
+struct Status {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct InitResponse {
+    // Problems loading single platforms or indexes, the instance is still usable
+    #[prost(message, optional, tag = "2")]
+    error: Option<Status>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CompileRequest {
+    #[prost(message, optional, tag = "1")]
+    instance: Option<Instance>,
+    #[prost(string, tag = "2")]
+    fqbn: String,
+    #[prost(string, tag = "3")]
+    sketch_path: String,
+    #[prost(string, tag = "7")]
+    build_path: String,
+    #[prost(string, tag = "18")]
+    export_dir: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct TaskProgress {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(bool, tag = "3")]
+    completed: bool,
+    #[prost(float, tag = "4")]
+    percent: f32,
+}
+
+// One of the fields is set per message
+#[derive(Clone, PartialEq, prost::Message)]
+struct CompileResponse {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    out_stream: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    err_stream: Option<Vec<u8>>,
+    #[prost(message, optional, tag = "3")]
+    progress: Option<TaskProgress>,
+}
+
+struct Daemon {
+    // Killed when the daemon is dropped, the compilers it started with `kill`
+    process: Child,
+    channel: Channel,
+    // None until initialized, and again once installed platforms or libraries changed
+    instance: Option<Instance>,
+}
+
+static ENABLED: LazyLock<bool> = LazyLock::new(|| {
+    let enabled = std::env
+        ::var("CLOUD_COMPILER_DAEMON")
+        .map(|value| matches!(value.trim(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    if enabled && limits_configured() {
+        warn!("CLOUD_COMPILER_DAEMON is ignored, the process limits can't apply to a shared daemon");
+        return false;
+    }
+    if enabled {
+        info!("Compiles run through the arduino-cli daemon");
+    }
+    enabled
+});
+
+static DAEMON: LazyLock<Mutex<Option<Daemon>>> = LazyLock::new(|| Mutex::new(None));
+
+fn rpc(method: &str) -> PathAndQuery {
+    PathAndQuery::try_from(format!("{}/{}", SERVICE, method)).expect("valid gRPC path")
+}
+
+async fn grpc(channel: &Channel) -> Result<tonic::client::Grpc<Channel>, String> {
+    let mut grpc = tonic::client::Grpc::new(channel.clone());
+    grpc.ready().await.map_err(|e| e.to_string())?;
+    Ok(grpc)
+}
+
+async fn start() -> Result<Daemon, String> {
+    // A port nobody uses right now
+    let port = std::net::TcpListener
+        ::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map_err(|e| e.to_string())?
+        .port();
+    let mut command = TokioCommand::new(get_arduino_cli_path());
+    command
+        .args(["daemon", "--port", &port.to_string()])
+        // The daemon exits once its stdin closes, so it goes away with the server
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+    // Its own process group, so the compilers it starts can be killed with it
+    #[cfg(unix)]
+    command.process_group(0);
+    hide_console(&mut command);
+    let process = command
+        .spawn()
+        .map_err(|e| format!("Failed to start arduino-cli daemon: {}", e))?;
+
+    let endpoint = Endpoint::from_shared(format!("http://127.0.0.1:{}", port)).map_err(|e| e.to_string())?;
+    let started = std::time::Instant::now();
+    let channel = loop {
+        match endpoint.connect().await {
+            Ok(channel) => break channel,
+            Err(e) if started.elapsed() > STARTUP_TIMEOUT => {
+                return Err(format!("arduino-cli daemon did not come up: {}", e));
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    };
+    info!("arduino-cli daemon listening on port {}", port);
+    Ok(Daemon { process, channel, instance: None })
+}
+
+async fn initialize(channel: &Channel) -> Result<Instance, String> {
+    let response = grpc(channel).await?
+        .unary(tonic::Request::new(CreateRequest {}), rpc("Create"), ProstCodec::<CreateRequest, CreateResponse>::default()).await
+        .map_err(|e| e.message().to_string())?;
+    let instance = response.into_inner().instance.ok_or("arduino-cli daemon returned no instance")?;
+
+    let request = InitRequest { instance: Some(instance.clone()) };
+    let mut stream = grpc(channel).await?
+        .server_streaming(tonic::Request::new(request), rpc("Init"), ProstCodec::<InitRequest, InitResponse>::default()).await
+        .map_err(|e| e.message().to_string())?
+        .into_inner();
+    while let Some(message) = stream.message().await.map_err(|e| e.message().to_string())? {
+        if let Some(error) = message.error {
+            warn!("arduino-cli daemon: {}", error.message);
+        }
+    }
+    Ok(instance)
+}
+
+// Channel and initialized instance of the daemon, starting it first if needed
+async fn connection() -> Result<(Channel, Instance), String> {
+    let mut daemon = DAEMON.lock().await;
+    if daemon.is_none() {
+        *daemon = Some(start().await?);
+    }
+    let daemon = daemon.as_mut().expect("daemon just started");
+    if daemon.instance.is_none() {
+        daemon.instance = Some(initialize(&daemon.channel).await?);
+    }
+    Ok((daemon.channel.clone(), daemon.instance.clone().expect("instance just initialized")))
+}
+
+// Stop the daemon along with every gcc it is running. Dropping a compile's stream doesn't stop
+// the compile, other compiles in flight fall back to spawning processes.
+async fn kill() {
+    if let Some(daemon) = DAEMON.lock().await.take() {
+        kill_process_group(daemon.process.id());
+    }
+}
+
+// Installed platforms or libraries changed, reload them before the next compile
+pub async fn reload() {
+    let mut daemon = DAEMON.lock().await;
+    let Some(daemon) = daemon.as_mut() else {
+        return;
+    };
+    if let Some(instance) = daemon.instance.take() && let Ok(mut grpc) = grpc(&daemon.channel).await {
+        let request = DestroyRequest { instance: Some(instance) };
+        grpc.unary(tonic::Request::new(request), rpc("Destroy"), ProstCodec::<DestroyRequest, DestroyResponse>::default()).await.ok();
+    }
+}
+
+// Compile request for `arduino-cli compile` arguments, None for flags the daemon path doesn't map
+fn compile_request(args: &[String]) -> Option<CompileRequest> {
+    let mut request = CompileRequest::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let field = match arg.as_str() {
+            "--fqbn" => &mut request.fqbn,
+            "--output-dir" => &mut request.export_dir,
+            "--build-path" => &mut request.build_path,
+            flag if flag.starts_with('-') => {
+                return None;
+            }
+            _ => {
+                request.sketch_path = arg.clone();
+                continue;
+            }
+        };
+        *field = args.next()?.clone();
+    }
+    Some(request)
+}
+
+// Pass the complete lines of `collected` after `sent` to the job's log. The daemon streams
+// arbitrary chunks, and a line split across two would escape the masking of build secrets.
+fn forward_lines(stream: LogStream, collected: &[u8], sent: &mut usize) {
+    if let Some(end) = collected[*sent..].iter().rposition(|byte| *byte == b'\n') {
+        let end = *sent + end + 1;
+        output::forward(stream, &String::from_utf8_lossy(&collected[*sent..end]));
+        *sent = end;
+    }
+}
+
+// Run a compile through the daemon, None when it isn't enabled or usable so the caller spawns
+// arduino-cli instead
+#[instrument(name = "daemon_compile", skip_all)]
+pub async fn compile(args: &[String]) -> Option<CommandResponse> {
+    if !*ENABLED {
+        return None;
+    }
+    let mut request = compile_request(args)?;
+    let (channel, instance) = match connection().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("{}, spawning arduino-cli instead", e);
+            DAEMON.lock().await.take();
+            return None;
+        }
+    };
+    request.instance = Some(instance);
+
+    let guard = acquire(ResourceKind::Process, format!("compile {} (daemon)", args.join(" ")));
+    if guard.token().is_cancelled() {
+        return Some(CompilerError::Cancelled.response("compile", args.to_vec()));
+    }
+    let timing = Timing::start();
+    let timeout = command_timeout("compile");
+    let mut output = Vec::new();
+    let mut errors = Vec::new();
+    let (mut output_sent, mut errors_sent) = (0, 0);
+    let result = tokio::select! {
+        result = async {
+            let mut stream = grpc(&channel).await
+                .map_err(tonic::Status::unavailable)?
+                .server_streaming(tonic::Request::new(request), rpc("Compile"), ProstCodec::<CompileRequest, CompileResponse>::default()).await?
+                .into_inner();
+            while let Some(message) = stream.message().await? {
+                if let Some(bytes) = message.out_stream {
+                    output.extend(bytes);
+                    forward_lines(LogStream::Stdout, &output, &mut output_sent);
+                }
+                if let Some(bytes) = message.err_stream {
+                    errors.extend(bytes);
+                    forward_lines(LogStream::Stderr, &errors, &mut errors_sent);
+                }
+                if let Some(progress) = message.progress {
+                    debug!("Compile progress: {} {} {:.0}%", progress.name, progress.message, progress.percent);
+                    resources::set_job_progress(progress.percent);
+                    if !progress.name.is_empty() {
+                        keepalive::phase(&progress.name);
+                    }
+                }
+            }
+            Ok::<(), tonic::Status>(())
+        } => result,
+        _ = guard.cancelled() => {
+            kill().await;
+            return Some(CompilerError::Cancelled.response("compile", args.to_vec()));
+        }
+        _ = tokio::time::sleep(timeout) => {
+            kill().await;
+            return Some(CompilerError::Timeout(timeout.as_secs()).response("compile", args.to_vec()));
+        }
+    };
+
+    for (stream, collected, sent) in [(LogStream::Stdout, &output, output_sent), (LogStream::Stderr, &errors, errors_sent)] {
+        if sent < collected.len() {
+            output::forward(stream, &String::from_utf8_lossy(&collected[sent..]));
+        }
+    }
+    let stderr = String::from_utf8_lossy(&errors).to_string();
+    let mut error = stderr.clone();
+    let success = result.is_ok();
+    match result {
+        Ok(()) => {}
+        // The daemon went away, this compile is retried with a process
+        Err(status) if status.code() == tonic::Code::Unavailable => {
+            warn!("arduino-cli daemon unavailable ({}), spawning arduino-cli instead", status.message());
+            DAEMON.lock().await.take();
+            return None;
+        }
+        // Another compile killed the daemon, retried the same way
+        Err(_) if DAEMON.lock().await.is_none() => {
+            return None;
+        }
+        Err(status) => {
+            error.push_str(status.message());
+            error.push('\n');
+        }
+    }
+    let mut response = CommandResponse {
+        success,
+        output: String::from_utf8_lossy(&output).to_string(),
+        error: Some(error).filter(|error| !error.is_empty()),
+        command: "compile".to_string(),
+        args: args.to_vec(),
+        stderr: Some(stderr).filter(|stderr| !stderr.is_empty()),
+        ..Default::default()
+    };
+    timing.stamp(&mut response);
+    Some(response)
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use md5::{ Digest, Md5 };
+use serde::{ Deserialize, Serialize };
+use tracing::warn;
+use crate::compiler::{ is_safe_name, server_data_dir };
+use crate::firmware::{ find_release, normalize_mac, Release };
+
+// Device registry and staged fleet rollouts. Devices are registered by MAC and grouped; a
+// rollout assigns a published release to a group and a percentage of it. Whether a device
+// is in the rollout is decided by a stable hash of its MAC, so raising the percentage only
+// ever adds devices. The registry is kept in memory, devices poll too often to read it from
+// disk each time; changes are written through to the files.
+
+const DEVICES_FILE: &str = "devices.json";
+const ROLLOUTS_FILE: &str = "rollouts.json";
+
+#[derive(Default)]
+struct Registry {
+    devices: BTreeMap<String, Device>,
+    rollouts: BTreeMap<String, Rollout>,
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(||
+    Mutex::new(Registry { devices: read(DEVICES_FILE), rollouts: read(ROLLOUTS_FILE) })
+);
+// Held while a file is written, so a write never replaces a newer state with an older one
+static WRITES: Mutex<()> = Mutex::new(());
+// Polls recorded in memory and not written yet
+static POLLS_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Device {
+    pub mac: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    // Version reported by the device on its last firmware poll
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    pub registered_at: u64,
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rollout {
+    pub group: String,
+    pub channel: String,
+    pub version: String,
+    pub percentage: u8,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Serialize)]
+pub struct RolloutStatus {
+    pub rollout: Rollout,
+    pub devices: usize,
+    // Devices the current percentage selects
+    pub targeted: usize,
+    // Devices reporting the rollout version
+    pub updated: usize,
+}
+
+// What a polling device should get
+pub enum Decision {
+    // The device is in a rollout that selected it
+    Update(Release),
+    // The device is in a rollout that has not reached it yet
+    Hold,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn registry_path(file: &str) -> PathBuf {
+    server_data_dir().join("devices").join(file)
+}
+
+fn read<T: for<'de> Deserialize<'de> + Default>(file: &str) -> T {
+    std::fs::read(registry_path(file))
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default()
+}
+
+fn write<T: Serialize>(file: &str, value: &T) -> Result<(), String> {
+    let path = registry_path(file);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_vec_pretty(value).map_err(|e| e.to_string())?;
+    std::fs::write(path, json).map_err(|e| e.to_string())
+}
+
+// Write the devices as they are now, pending polls included
+fn save_devices() -> Result<(), String> {
+    let _writes = WRITES.lock().unwrap();
+    POLLS_PENDING.store(false, Ordering::SeqCst);
+    let devices = REGISTRY.lock().unwrap().devices.clone();
+    write(DEVICES_FILE, &devices)
+}
+
+fn save_rollouts() -> Result<(), String> {
+    let _writes = WRITES.lock().unwrap();
+    let rollouts = REGISTRY.lock().unwrap().rollouts.clone();
+    write(ROLLOUTS_FILE, &rollouts)
+}
+
+fn valid_mac(mac: &str) -> bool {
+    let parts: Vec<&str> = mac.split(':').collect();
+    parts.len() == 6 && parts.iter().all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// Register a device or update its name and group
+pub fn register_device(mac: &str, name: Option<String>, group: Option<String>) -> Result<Device, String> {
+    let mac = normalize_mac(mac);
+    if !valid_mac(&mac) {
+        return Err(format!("Invalid MAC address: {}", mac));
+    }
+    if let Some(group) = &group && !is_safe_name(group) {
+        return Err(format!("Invalid group: {}", group));
+    }
+
+    let device = REGISTRY.lock()
+        .unwrap()
+        .devices.entry(mac.clone())
+        .and_modify(|device| {
+            device.name = name.clone().or(device.name.take());
+            device.group = group.clone().or(device.group.take());
+        })
+        .or_insert_with(|| Device {
+            mac,
+            name,
+            group,
+            firmware_version: None,
+            registered_at: now(),
+            last_seen: None,
+        })
+        .clone();
+    save_devices()?;
+    Ok(device)
+}
+
+pub fn delete_device(mac: &str) -> Result<(), String> {
+    REGISTRY.lock()
+        .unwrap()
+        .devices.remove(&normalize_mac(mac))
+        .ok_or_else(|| format!("No device {}", mac))?;
+    save_devices()
+}
+
+pub fn list_devices(group: Option<&str>) -> Vec<Device> {
+    REGISTRY.lock()
+        .unwrap()
+        .devices.values()
+        .filter(|d| group.is_none() || d.group.as_deref() == group)
+        .cloned()
+        .collect()
+}
+
+// Track a firmware poll of a registered device. It is written in the background, and polls
+// arriving before that write starts share it.
+pub fn record_poll(mac: &str, version: Option<&str>) {
+    {
+        let mut registry = REGISTRY.lock().unwrap();
+        let Some(device) = registry.devices.get_mut(&normalize_mac(mac)) else {
+            return;
+        };
+        device.last_seen = Some(now());
+        if let Some(version) = version {
+            device.firmware_version = Some(version.to_string());
+        }
+    }
+    if !POLLS_PENDING.swap(true, Ordering::SeqCst) {
+        tokio::task::spawn_blocking(|| {
+            if let Err(e) = save_devices() {
+                warn!("Failed to save the device registry: {}", e);
+            }
+        });
+    }
+}
+
+// Bucket 0..100 of a device for a rollout
+fn bucket(rollout: &Rollout, mac: &str) -> u8 {
+    let digest = Md5::digest(format!("{}/{}", rollout.group, mac));
+    (u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 100) as u8
+}
+
+fn targets(rollout: &Rollout, mac: &str) -> bool {
+    bucket(rollout, mac) < rollout.percentage
+}
+
+// Start or update the rollout of a published release to a group
+pub fn set_rollout(group: &str, channel: &str, version: &str, percentage: u8) -> Result<Rollout, String> {
+    if !is_safe_name(group) {
+        return Err(format!("Invalid group: {}", group));
+    }
+    if percentage > 100 {
+        return Err("Percentage must be between 0 and 100".to_string());
+    }
+    find_release(channel, version).ok_or_else(|| format!("Version {} is not published on {}", version, channel))?;
+
+    let mut registry = REGISTRY.lock().unwrap();
+    let now = now();
+    let started_at = registry.rollouts
+        .get(group)
+        .filter(|r| r.channel == channel && r.version == version)
+        .map(|r| r.started_at)
+        .unwrap_or(now);
+    let rollout = Rollout {
+        group: group.to_string(),
+        channel: channel.to_string(),
+        version: version.to_string(),
+        percentage,
+        started_at,
+        updated_at: now,
+    };
+    registry.rollouts.insert(group.to_string(), rollout.clone());
+    drop(registry);
+    save_rollouts()?;
+    Ok(rollout)
+}
+
+pub fn delete_rollout(group: &str) -> Result<(), String> {
+    REGISTRY.lock()
+        .unwrap()
+        .rollouts.remove(group)
+        .ok_or_else(|| format!("No rollout for group {}", group))?;
+    save_rollouts()
+}
+
+pub fn rollout_status(group: &str) -> Result<RolloutStatus, String> {
+    let rollout = REGISTRY.lock().unwrap().rollouts.get(group).cloned().ok_or_else(|| format!("No rollout for group {}", group))?;
+    let devices = list_devices(Some(group));
+    Ok(RolloutStatus {
+        devices: devices.len(),
+        targeted: devices
+            .iter()
+            .filter(|d| targets(&rollout, &d.mac))
+            .count(),
+        updated: devices
+            .iter()
+            .filter(|d| d.firmware_version.as_deref() == Some(rollout.version.as_str()))
+            .count(),
+        rollout,
+    })
+}
+
+// Devices of a group the rollout currently selects
+pub fn targeted_devices(rollout: &Rollout) -> Vec<String> {
+    list_devices(Some(&rollout.group))
+        .into_iter()
+        .map(|d| d.mac)
+        .filter(|mac| targets(rollout, mac))
+        .collect()
+}
+
+// Rollout decision for a polling device, None if it is not part of a rollout
+pub fn rollout_decision(mac: &str) -> Option<Decision> {
+    let mac = normalize_mac(mac);
+    let rollout = {
+        let registry = REGISTRY.lock().unwrap();
+        let group = registry.devices.get(&mac)?.group.as_ref()?;
+        registry.rollouts.get(group)?.clone()
+    };
+    if !targets(&rollout, &mac) {
+        return Some(Decision::Hold);
+    }
+    find_release(&rollout.channel, &rollout.version).map(Decision::Update)
+}
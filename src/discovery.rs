@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use mdns_sd::{ ServiceDaemon, ServiceEvent };
+use serde::Serialize;
+
+// ArduinoOTA devices announce themselves as `_arduino._tcp` with board and auth TXT records
+const ARDUINO_OTA_SERVICE: &str = "_arduino._tcp.local.";
+pub const DEFAULT_BROWSE_TIME: Duration = Duration::from_secs(3);
+pub const MAX_BROWSE_TIME: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+pub struct OtaDevice {
+    pub name: String,
+    pub hostname: String,
+    // Address to pass to `upload-sketch`
+    pub address: String,
+    pub port: u16,
+    // Board variant announced by the device (`esp32`, `esp32s3`, ...)
+    pub board: Option<String>,
+    // Whether the device requires an OTA password
+    pub auth: bool,
+}
+
+// Browse the server's network for OTA-capable devices during `browse_time`
+pub async fn discover_ota_devices(browse_time: Duration) -> Result<Vec<OtaDevice>, String> {
+    let daemon = ServiceDaemon::new().map_err(|e| format!("mDNS unavailable: {}", e))?;
+    let events = daemon.browse(ARDUINO_OTA_SERVICE).map_err(|e| e.to_string())?;
+
+    // Devices answer repeatedly, keep the latest resolution per instance
+    let mut devices = BTreeMap::new();
+    let deadline = tokio::time::sleep(browse_time);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            event = events.recv_async() => {
+                let Ok(event) = event else {
+                    break;
+                };
+                if let ServiceEvent::ServiceResolved(service) = event {
+                    let Some(address) = service.get_addresses_v4().into_iter().min() else {
+                        continue;
+                    };
+                    let name = service
+                        .get_fullname()
+                        .strip_suffix(ARDUINO_OTA_SERVICE)
+                        .unwrap_or(service.get_fullname())
+                        .trim_end_matches('.')
+                        .to_string();
+                    let device = OtaDevice {
+                        name: name.clone(),
+                        hostname: service.get_hostname().trim_end_matches('.').to_string(),
+                        address: address.to_string(),
+                        port: service.get_port(),
+                        board: service.get_property_val_str("board").map(String::from),
+                        auth: service.get_property_val_str("auth_upload") == Some("yes"),
+                    };
+                    devices.insert(name, device);
+                }
+            }
+            _ = &mut deadline => {
+                break;
+            }
+        }
+    }
+
+    daemon.shutdown().ok();
+    Ok(devices.into_values().collect())
+}
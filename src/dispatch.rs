@@ -0,0 +1,553 @@
+// Dispatcher and worker nodes. A server compiles what it queues unless CLOUD_COMPILER_ROLE says
+// otherwise: a `dispatcher` keeps the sockets, auth and queue, and hands the arduino-cli run of
+// every compile to `worker` nodes (see `worker`) that register with it, pull compiles over HTTP
+// and push their output and build directory back. The queue runs as many compiles at once as the
+// registered workers have slots, a compile goes to a worker that has its board's platform
+// installed. Preparing the sketch and post-processing the build (merging, signing, caching)
+// stay on the dispatcher, as do the other operations.
+//
+// CLOUD_COMPILER_WORKER_TOKEN is the secret workers authenticate with, the worker routes answer
+// 401 without it. A worker not heard from for a minute is dropped and its compiles fail.
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, Instant };
+use axum::{
+    extract::{ DefaultBodyLimit, Multipart, Path as RoutePath },
+    http::{ header, HeaderMap, StatusCode },
+    response::{ IntoResponse, Response },
+    routing::{ delete, post },
+    Json,
+    Router,
+};
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::{ mpsc, oneshot, Notify };
+use tracing::{ info, warn };
+use utoipa::ToSchema;
+use crate::artifacts::zip_dir;
+use crate::compiler::{ command_timeout, run_arduino_command, sketch_dir };
+use crate::errors::CompilerError;
+use crate::files::extract_zip_within;
+use crate::models::{ ArduinoCommand, CommandResponse, LogLine };
+use crate::output;
+use crate::queue;
+use crate::resources::{ acquire, ResourceKind };
+
+// Stand-ins for the sketch folder and build directory in the paths of an assignment, and in the
+// output coming back
+pub const SKETCH: &str = "{sketch}";
+pub const BUILD: &str = "{build}";
+
+// How long a poll waits for a compile before answering 204
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+const WORKER_EXPIRY: Duration = Duration::from_secs(60);
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+// Allowed on top of the compile timeout for the transfers
+const TRANSFER_MARGIN: Duration = Duration::from_secs(120);
+// Build directories workers send back
+const BUILD_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const BUILD_MAX_FILES: usize = 100_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Standalone,
+    Dispatcher,
+    Worker,
+}
+
+static ROLE: LazyLock<Role> = LazyLock::new(|| {
+    match std::env::var("CLOUD_COMPILER_ROLE").unwrap_or_default().trim().to_lowercase().as_str() {
+        "dispatcher" => Role::Dispatcher,
+        "worker" => Role::Worker,
+        _ => Role::Standalone,
+    }
+});
+
+pub fn role() -> Role {
+    *ROLE
+}
+
+// Secret shared by the dispatcher and its workers
+pub fn token() -> Option<String> {
+    std::env::var("CLOUD_COMPILER_WORKER_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+// What a worker tells about itself when it registers
+#[derive(Serialize, Deserialize)]
+pub struct Registration {
+    pub name: String,
+    // Compiles it runs at once
+    pub slots: usize,
+    // Installed platforms, `vendor:arch`
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    #[serde(default)]
+    pub arduino_cli: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Registered {
+    pub id: String,
+}
+
+// Health a worker reports with every poll
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct WorkerHealth {
+    // Compiles running
+    pub running: usize,
+    // Least free space of its data directories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_disk_mb: Option<u64>,
+}
+
+// A compile handed to a worker. Paths in `args` start with `SKETCH` for the sketch folder, named
+// `sketch`, and with `BUILD` for the build directory.
+#[derive(Serialize, Deserialize)]
+pub struct Assignment {
+    pub job_id: String,
+    pub command: String,
+    pub args: Vec<String>,
+    pub sketch: String,
+    // The sketch folder as a base64 ZIP archive
+    pub archive: String,
+}
+
+// A registered worker as operators see it
+#[derive(Serialize, Clone, ToSchema)]
+pub struct WorkerInfo {
+    pub id: String,
+    pub name: String,
+    pub slots: usize,
+    pub platforms: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arduino_cli: Option<String>,
+    // Compiles handed to it and not answered yet
+    pub assigned: usize,
+    pub health: WorkerHealth,
+    pub registered_at: u64,
+    pub last_seen_secs: u64,
+}
+
+struct Worker {
+    info: WorkerInfo,
+    last_seen: Instant,
+}
+
+// A compile waiting for a worker, or running on one
+struct Remote {
+    id: String,
+    // Taken by the worker that picks it up
+    assignment: Option<Assignment>,
+    platform: Option<String>,
+    worker: Option<String>,
+    build_dir: PathBuf,
+    output: mpsc::UnboundedSender<LogLine>,
+    result: Option<oneshot::Sender<Result<CommandResponse, String>>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    workers: HashMap<String, Worker>,
+    // Oldest first
+    jobs: Vec<Remote>,
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+// Wakes the polling workers when a compile is queued
+static QUEUED: Notify = Notify::const_new();
+
+// Removes a compile from the registry once its caller stops waiting
+struct Pending(String);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().jobs.retain(|job| job.id != self.0);
+    }
+}
+
+// The queue runs as many compiles as the workers have slots
+fn resize(registry: &Registry) {
+    queue::set_workers(registry.workers.values().map(|worker| worker.info.slots).sum());
+}
+
+// `vendor:arch` of the board a compile is for, unless a profile picks it
+fn platform(args: &[String]) -> Option<String> {
+    let fqbn = args.windows(2).find(|pair| pair[0] == "--fqbn")?[1].as_str();
+    let mut parts = fqbn.split(':');
+    Some(format!("{}:{}", parts.next()?, parts.next()?))
+}
+
+// Path `arg` with the directory of one of `dirs` replaced by its stand-in
+fn relocate(arg: &str, dirs: &[(&Path, &str)]) -> String {
+    for (dir, stand_in) in dirs {
+        if let Ok(rest) = Path::new(arg).strip_prefix(dir) {
+            return match rest.as_os_str().is_empty() {
+                true => stand_in.to_string(),
+                false => format!("{}/{}", stand_in, rest.to_string_lossy().replace('\\', "/")),
+            };
+        }
+    }
+    arg.to_string()
+}
+
+fn restore(text: &str, sketch: &Path, build_dir: &Path) -> String {
+    text.replace(SKETCH, &sketch.to_string_lossy()).replace(BUILD, &build_dir.to_string_lossy())
+}
+
+// The sketch folder as a base64 ZIP archive, staged in the build directory
+async fn pack(sketch: &Path, build_dir: &Path) -> Result<String, String> {
+    let sketch = sketch.to_path_buf();
+    let staged = build_dir.join(".sketch.zip");
+    tokio::task
+        ::spawn_blocking(move || {
+            let packed = zip_dir(&sketch, "", &staged).and_then(|_| std::fs::read(&staged).map_err(|e| e.to_string()));
+            std::fs::remove_file(&staged).ok();
+            packed.map(|bytes| BASE64.encode(bytes))
+        }).await
+        .map_err(|e| e.to_string())?
+}
+
+// Run the compile `command` of the sketch at `sketch_path` into `build_dir`: on a worker for a
+// dispatcher, here otherwise
+pub async fn compile(command: &ArduinoCommand, sketch_path: &Path, build_dir: &Path) -> CommandResponse {
+    if role() != Role::Dispatcher {
+        return run_arduino_command(command).await;
+    }
+    match remote(command, sketch_path, build_dir).await {
+        Ok(response) => response,
+        Err(e) => CompilerError::WorkerFailed(e).response(&command.command, command.args.clone()),
+    }
+}
+
+async fn remote(command: &ArduinoCommand, sketch_path: &Path, build_dir: &Path) -> Result<CommandResponse, String> {
+    let sketch = sketch_dir(sketch_path).to_path_buf();
+    let name = sketch
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| format!("Invalid sketch path {}", sketch_path.display()))?;
+    let archive = pack(&sketch, build_dir).await.map_err(|e| format!("Failed to pack the sketch: {}", e))?;
+    let dirs = [(sketch.as_path(), SKETCH), (build_dir, BUILD)];
+    let args = command.args
+        .iter()
+        .map(|arg| relocate(arg, &dirs))
+        .collect();
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let (output, mut lines) = mpsc::unbounded_channel();
+    let (result, mut answered) = oneshot::channel();
+    REGISTRY.lock().unwrap().jobs.push(Remote {
+        id: id.clone(),
+        assignment: Some(Assignment { job_id: id.clone(), command: command.command.clone(), args, sketch: name, archive }),
+        platform: platform(&command.args),
+        worker: None,
+        build_dir: build_dir.to_path_buf(),
+        output,
+        result: Some(result),
+    });
+    let _pending = Pending(id);
+    QUEUED.notify_waiters();
+
+    let guard = acquire(ResourceKind::Process, format!("{} {} on a worker", command.command, command.args.join(" ")));
+    let timeout = command_timeout(&command.command) + TRANSFER_MARGIN;
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let answer = loop {
+        tokio::select! {
+            // Output sent before the result goes out first
+            biased;
+            Some(line) = lines.recv() => {
+                output::forward(line.stream, &restore(&line.text, &sketch, build_dir));
+            }
+            answer = &mut answered => {
+                break answer.map_err(|_| "The worker went away".to_string());
+            }
+            _ = guard.cancelled() => {
+                return Ok(CompilerError::Cancelled.response(&command.command, command.args.clone()));
+            }
+            _ = &mut deadline => {
+                return Ok(CompilerError::Timeout(timeout.as_secs()).response(&command.command, command.args.clone()));
+            }
+        }
+    };
+    while let Ok(line) = lines.try_recv() {
+        output::forward(line.stream, &restore(&line.text, &sketch, build_dir));
+    }
+
+    let mut response = answer??;
+    response.args = command.args.clone();
+    response.output = restore(&response.output, &sketch, build_dir);
+    response.error = response.error.map(|error| restore(&error, &sketch, build_dir));
+    Ok(response)
+}
+
+// Whether worker `id` has the platform of a compile, any does for platforms none of them has
+// since the compile can only fail with arduino-cli's own error
+fn suits(registry: &Registry, id: &str, platform: Option<&str>) -> bool {
+    let Some(platform) = platform else {
+        return true;
+    };
+    let has = |worker: &Worker| worker.info.platforms.iter().any(|installed| installed == platform);
+    registry.workers.get(id).is_some_and(has) || !registry.workers.values().any(has)
+}
+
+// Hand the oldest waiting compile worker `id` suits to it
+fn take(registry: &mut Registry, id: &str) -> Option<Assignment> {
+    let index = registry.jobs
+        .iter()
+        .position(|job| job.assignment.is_some() && suits(registry, id, job.platform.as_deref()))?;
+    let job = &mut registry.jobs[index];
+    job.worker = Some(id.to_string());
+    job.assignment.take()
+}
+
+// Drop worker `id`, failing the compiles it runs
+fn remove(registry: &mut Registry, id: &str, reason: &str) -> bool {
+    let Some(worker) = registry.workers.remove(id) else {
+        return false;
+    };
+    info!(worker = %worker.info.name, "Worker {}", reason);
+    for job in registry.jobs.iter_mut().filter(|job| job.worker.as_deref() == Some(id)) {
+        if let Some(result) = job.result.take() {
+            result.send(Err(format!("Worker {} {}", worker.info.name, reason))).ok();
+        }
+    }
+    resize(registry);
+    true
+}
+
+// Drop workers that stopped polling
+pub fn spawn_reaper() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut registry = REGISTRY.lock().unwrap();
+            let expired: Vec<String> = registry.workers
+                .iter()
+                .filter(|(_, worker)| worker.last_seen.elapsed() > WORKER_EXPIRY)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in expired {
+                warn!("Worker {} stopped responding", id);
+                remove(&mut registry, &id, "stopped responding");
+            }
+        }
+    });
+}
+
+// Registered workers, by name
+pub fn workers() -> Vec<WorkerInfo> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut workers: Vec<WorkerInfo> = registry.workers
+        .values()
+        .map(|worker| {
+            let mut info = worker.info.clone();
+            info.assigned = registry.jobs
+                .iter()
+                .filter(|job| job.worker.as_deref() == Some(&info.id))
+                .count();
+            info.last_seen_secs = worker.last_seen.elapsed().as_secs();
+            info
+        })
+        .collect();
+    workers.sort_by(|a, b| a.name.cmp(&b.name));
+    workers
+}
+
+// Whether a dispatcher has a worker to compile on, for its readiness
+pub fn available() -> Result<(), String> {
+    match REGISTRY.lock().unwrap().workers.is_empty() {
+        true => Err("No worker registered".to_string()),
+        false => Ok(()),
+    }
+}
+
+// Routes workers talk to the dispatcher on
+pub fn routes() -> Router {
+    Router::new()
+        .route("/workers", post(register))
+        .route("/workers/{id}", delete(deregister))
+        .route("/workers/{id}/poll", post(poll))
+        .route("/workers/{id}/jobs/{job_id}/output", post(push_output))
+        .route(
+            "/workers/{id}/jobs/{job_id}/result",
+            post(push_result).layer(DefaultBodyLimit::max(BUILD_MAX_BYTES as usize))
+        )
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    matches!((presented, token()), (Some(presented), Some(token)) if presented == token)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime
+        ::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+async fn register(headers: HeaderMap, Json(registration): Json<Registration>) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    info!(
+        worker = %registration.name,
+        slots = registration.slots,
+        platforms = ?registration.platforms,
+        "Worker registered"
+    );
+    let info = WorkerInfo {
+        id: id.clone(),
+        name: registration.name,
+        slots: registration.slots,
+        platforms: registration.platforms,
+        arduino_cli: registration.arduino_cli,
+        assigned: 0,
+        health: WorkerHealth::default(),
+        registered_at: now(),
+        last_seen_secs: 0,
+    };
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.workers.insert(id.clone(), Worker { info, last_seen: Instant::now() });
+    resize(&registry);
+    Json(Registered { id }).into_response()
+}
+
+async fn deregister(headers: HeaderMap, RoutePath(id): RoutePath<String>) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match remove(&mut REGISTRY.lock().unwrap(), &id, "deregistered") {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// A compile for the worker, or 204 once none came for a while. 404 tells a worker the
+// dispatcher doesn't know it (anymore) and it should register again.
+async fn poll(headers: HeaderMap, RoutePath(id): RoutePath<String>, Json(health): Json<WorkerHealth>) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let deadline = tokio::time::Instant::now() + POLL_TIMEOUT;
+    loop {
+        let queued = QUEUED.notified();
+        tokio::pin!(queued);
+        // Compiles queued from here on wake this poll
+        queued.as_mut().enable();
+        {
+            let mut registry = REGISTRY.lock().unwrap();
+            let Some(worker) = registry.workers.get_mut(&id) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            worker.last_seen = Instant::now();
+            worker.info.health = health.clone();
+            if let Some(assignment) = take(&mut registry, &id) {
+                return Json(assignment).into_response();
+            }
+        }
+        if tokio::time::timeout_at(deadline, queued).await.is_err() {
+            return StatusCode::NO_CONTENT.into_response();
+        }
+    }
+}
+
+// The compile `job_id` worker `id` runs, 410 once nobody waits for it (cancelled, timed out)
+fn running<'a>(registry: &'a mut Registry, id: &str, job_id: &str) -> Option<&'a mut Remote> {
+    if let Some(worker) = registry.workers.get_mut(id) {
+        worker.last_seen = Instant::now();
+    }
+    registry.jobs
+        .iter_mut()
+        .find(|job| job.id == job_id && job.worker.as_deref() == Some(id) && job.result.is_some())
+}
+
+async fn push_output(
+    headers: HeaderMap,
+    RoutePath((id, job_id)): RoutePath<(String, String)>,
+    Json(lines): Json<Vec<LogLine>>
+) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    let Some(job) = running(&mut registry, &id, &job_id) else {
+        return StatusCode::GONE.into_response();
+    };
+    for line in lines {
+        job.output.send(line).ok();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// The response of the compile as JSON in the `response` part, its build directory as a ZIP
+// archive in `build`
+async fn push_result(
+    headers: HeaderMap,
+    RoutePath((id, job_id)): RoutePath<(String, String)>,
+    mut multipart: Multipart
+) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let (mut response, mut build) = (None, None);
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+            }
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+            }
+        };
+        match name.as_str() {
+            "response" => {
+                response = Some(bytes);
+            }
+            "build" => {
+                build = Some(bytes);
+            }
+            _ => {}
+        }
+    }
+    let response: CommandResponse = match response.map(|bytes| serde_json::from_slice(&bytes)) {
+        Some(Ok(response)) => response,
+        Some(Err(e)) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid response: {}", e)).into_response();
+        }
+        None => {
+            return (StatusCode::BAD_REQUEST, "Missing response").into_response();
+        }
+    };
+
+    let claimed = {
+        let mut registry = REGISTRY.lock().unwrap();
+        running(&mut registry, &id, &job_id).and_then(|job| Some((job.build_dir.clone(), job.result.take()?)))
+    };
+    let Some((build_dir, result)) = claimed else {
+        return StatusCode::GONE.into_response();
+    };
+    let unpacked = match build {
+        Some(build) =>
+            tokio::task
+                ::spawn_blocking(move || extract_zip_within(&build_dir, &build, BUILD_MAX_FILES, BUILD_MAX_BYTES)).await
+                .map_err(|e| e.to_string())
+                .and_then(|unpacked| unpacked),
+        None => Ok(()),
+    };
+    result.send(unpacked.map(|_| response).map_err(|e| format!("Failed to unpack the build of the worker: {}", e))).ok();
+    StatusCode::NO_CONTENT.into_response()
+}
@@ -0,0 +1,109 @@
+use std::path::{ Path, PathBuf };
+use aes_gcm::{ aead::{ consts::U16, generic_array::GenericArray, AeadInPlace, KeyInit }, aes::Aes256, AesGcm };
+use rand::RngCore;
+use rsa::{
+    pkcs8::{ DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding },
+    sha2::Sha256,
+    traits::PublicKeyParts,
+    Oaep,
+    RsaPrivateKey,
+    RsaPublicKey,
+};
+use crate::compiler::{ is_safe_name, server_data_dir };
+use crate::files::subject_dir;
+
+// Header layout of ESP-IDF pre-encrypted images (esp_encrypted_img)
+const MAGIC: u32 = 0x0788b6cf;
+const RSA_BITS: usize = 3072;
+const HEADER_RESERVED: usize = 88;
+
+// ESP-IDF uses a 16 byte IV with AES-256-GCM
+type Aes256Gcm16 = AesGcm<Aes256, U16>;
+
+// Keys of an identity's projects, the shared root for anonymous clients
+fn keys_dir(owner: Option<&str>) -> PathBuf {
+    subject_dir(server_data_dir().join("keys"), owner)
+}
+
+fn public_key_path(owner: Option<&str>, project: &str) -> Result<PathBuf, String> {
+    if !is_safe_name(project) {
+        return Err(format!("Invalid project name: {}", project));
+    }
+    Ok(keys_dir(owner).join(format!("{}.pub.pem", project)))
+}
+
+fn store_public_key(owner: Option<&str>, project: &str, key: &RsaPublicKey) -> Result<(), String> {
+    let path = public_key_path(owner, project)?;
+    let pem = key.to_public_key_pem(LineEnding::LF).map_err(|e| e.to_string())?;
+    std::fs::create_dir_all(keys_dir(owner)).map_err(|e| e.to_string())?;
+    std::fs::write(path, pem).map_err(|e| e.to_string())
+}
+
+// Generate a project key pair. Only the public half is kept on the server, the
+// private key is returned once so it can be embedded in the device firmware.
+pub fn create_key(owner: Option<&str>, project: &str) -> Result<String, String> {
+    let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), RSA_BITS).map_err(|e|
+        e.to_string()
+    )?;
+    store_public_key(owner, project, &RsaPublicKey::from(&private_key))?;
+
+    private_key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map(|pem| pem.to_string())
+        .map_err(|e| e.to_string())
+}
+
+// Register an existing public key, e.g. generated with the ESP-IDF tooling
+pub fn import_key(owner: Option<&str>, project: &str, public_key_pem: &str) -> Result<(), String> {
+    let key = RsaPublicKey::from_public_key_pem(public_key_pem).map_err(|e|
+        format!("Invalid public key: {}", e)
+    )?;
+    if key.size() * 8 != RSA_BITS {
+        return Err(format!("Public key must be RSA-{}", RSA_BITS));
+    }
+    store_public_key(owner, project, &key)
+}
+
+pub fn get_key(owner: Option<&str>, project: &str) -> Result<String, String> {
+    let path = public_key_path(owner, project)?;
+    std::fs::read_to_string(path).map_err(|_| format!("No encryption key for project {}", project))
+}
+
+pub fn delete_key(owner: Option<&str>, project: &str) -> Result<(), String> {
+    let path = public_key_path(owner, project)?;
+    std::fs::remove_file(path).map_err(|_| format!("No encryption key for project {}", project))
+}
+
+// Encrypt `input` with the key of `owner`'s project into the esp_encrypted_img format
+pub fn encrypt_image(owner: Option<&str>, project: &str, input: &Path, output: &Path) -> Result<(), String> {
+    let public_key = RsaPublicKey::from_public_key_pem(&get_key(owner, project)?).map_err(|e|
+        e.to_string()
+    )?;
+    let mut image = std::fs::read(input).map_err(|e| e.to_string())?;
+    let image_len = u32::try_from(image.len()).map_err(|_| "Image too large".to_string())?;
+
+    let mut rng = rand::thread_rng();
+    let mut gcm_key = [0u8; 32];
+    let mut iv = [0u8; 16];
+    rng.fill_bytes(&mut gcm_key);
+    rng.fill_bytes(&mut iv);
+
+    let cipher = Aes256Gcm16::new(GenericArray::from_slice(&gcm_key));
+    let tag = cipher
+        .encrypt_in_place_detached(GenericArray::from_slice(&iv), b"", &mut image)
+        .map_err(|e| e.to_string())?;
+    let wrapped_key = public_key
+        .encrypt(&mut rng, Oaep::new::<Sha256>(), &gcm_key)
+        .map_err(|e| e.to_string())?;
+
+    let mut out = Vec::with_capacity(512 + image.len());
+    out.extend_from_slice(&MAGIC.to_le_bytes());
+    out.extend_from_slice(&wrapped_key);
+    out.extend_from_slice(&iv);
+    out.extend_from_slice(&image_len.to_le_bytes());
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(&[0u8; HEADER_RESERVED]);
+    out.extend_from_slice(&image);
+
+    std::fs::write(output, out).map_err(|e| e.to_string())
+}
@@ -0,0 +1,90 @@
+// Failures clients can act on. A failed response carries the message in `error` and, when it is
+// one of these, the stable code of its kind in `error_code`, so frontends branch on the code
+// instead of matching messages. Rejections by the server are raised as a `CompilerError`; the
+// failures of arduino-cli and esptool are classified from what the tool printed.
+use thiserror::Error;
+use crate::models::{ CommandResponse, ErrorCode };
+
+#[derive(Error, Debug, Clone)]
+pub enum CompilerError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("Rate limited, retry in {0} s")]
+    RateLimited(u64),
+    #[error("{0}")]
+    QueueFull(String),
+    #[error("Server shutting down")]
+    ShuttingDown,
+    #[error("Server is not accepting new jobs")]
+    IntakePaused,
+    #[error("Unknown event: {0}")]
+    UnknownEvent(String),
+    #[error("{0}")]
+    PolicyViolation(String),
+    #[error("Command timed out after {0} s")]
+    Timeout(u64),
+    #[error("Command cancelled")]
+    Cancelled,
+    #[error("{0}")]
+    WorkerFailed(String),
+}
+
+impl CompilerError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CompilerError::InvalidRequest(_) => ErrorCode::InvalidRequest,
+            CompilerError::Unauthorized(_) => ErrorCode::Unauthorized,
+            CompilerError::RateLimited(_) => ErrorCode::RateLimited,
+            CompilerError::QueueFull(_) => ErrorCode::QueueFull,
+            CompilerError::ShuttingDown => ErrorCode::ShuttingDown,
+            CompilerError::IntakePaused => ErrorCode::IntakePaused,
+            CompilerError::UnknownEvent(_) => ErrorCode::UnknownEvent,
+            CompilerError::PolicyViolation(_) => ErrorCode::PolicyViolation,
+            CompilerError::Timeout(_) => ErrorCode::Timeout,
+            CompilerError::Cancelled => ErrorCode::Cancelled,
+            CompilerError::WorkerFailed(_) => ErrorCode::WorkerFailed,
+        }
+    }
+
+    // The failed response of `command` for this error
+    pub fn response(&self, command: &str, args: Vec<String>) -> CommandResponse {
+        CommandResponse {
+            success: false,
+            error: Some(self.to_string()),
+            error_code: Some(self.code()),
+            retry_after: match self {
+                CompilerError::RateLimited(secs) => Some(*secs),
+                _ => None,
+            },
+            command: command.to_string(),
+            args,
+            ..Default::default()
+        }
+    }
+}
+
+// What printed by a failed `command` tells which kind of failure it was, if any
+pub fn classify(command: &str, output: &str, error: &str) -> Option<ErrorCode> {
+    let text = format!("{}\n{}", output, error).to_lowercase();
+    let any = |patterns: &[&str]| patterns.iter().any(|pattern| text.contains(pattern));
+    if any(&["platform not installed", "unknown fqbn", "platform not found"]) {
+        return Some(ErrorCode::CoreNotInstalled);
+    }
+    if any(&["sketch too big", "exceeds available space", "will not fit in region", "overflowed by"]) {
+        return Some(ErrorCode::SketchTooLarge);
+    }
+    // Compiles print missing headers as "No such file", which says nothing about ports
+    if command != "compile" {
+        // esptool's "the port is busy or doesn't exist" is usually the former
+        if any(&["port is busy", "resource busy", "access is denied", "could not exclusively lock port"]) {
+            return Some(ErrorCode::PortBusy);
+        }
+        if any(&["no such file or directory", "cannot find the file specified", "port not found", "doesn't exist"]) {
+            return Some(ErrorCode::PortNotFound);
+        }
+        return None;
+    }
+    Some(ErrorCode::CompileFailed)
+}
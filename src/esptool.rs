@@ -0,0 +1,284 @@
+use std::path::{ Path, PathBuf };
+use crate::models::*;
+use crate::compiler::{ arduino_data_dir, error_response, port_name, run_program };
+use crate::artifacts::find_app_binary;
+use crate::partitions::*;
+use crate::resources::{ acquire, ResourceKind };
+
+#[cfg(target_os = "windows")]
+const ESPTOOL_NAMES: &[&str] = &["esptool.exe"];
+#[cfg(not(target_os = "windows"))]
+const ESPTOOL_NAMES: &[&str] = &["esptool", "esptool.py"];
+
+// Locate esptool: the copy shipped with the esp32 core, then anything on PATH
+pub fn find_esptool() -> Option<PathBuf> {
+    find_core_tool("esptool_py", ESPTOOL_NAMES)
+}
+
+// Locate a tool installed by the esp32 core under `packages/esp32/tools/<tool>/<version>`,
+// falling back to PATH
+pub fn find_core_tool(tool: &str, names: &[&str]) -> Option<PathBuf> {
+    let tools_dir = arduino_data_dir().join("packages").join("esp32").join("tools").join(tool);
+
+    for dir in version_dirs(&tools_dir) {
+        for name in names {
+            let candidate = dir.join(name);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+        }
+    }
+
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).find_map(|dir| {
+        names.iter()
+            .map(|name| dir.join(name))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+// Installed version directories under `dir`, newest first by semver precedence
+fn version_dirs(dir: &Path) -> Vec<PathBuf> {
+    let mut versions: Vec<PathBuf> = std::fs
+        ::read_dir(dir)
+        .map(|entries| entries.filter_map(|e| e.ok().map(|e| e.path())).collect())
+        .unwrap_or_default();
+    versions.sort_by_cached_key(|path| {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        (version_key(&name), name)
+    });
+    versions.reverse();
+    versions
+}
+
+// Pre-release identifier, numeric ones rank below alphanumeric ones
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    Numeric(u64),
+    Text(String),
+}
+
+// Precedence of a version: its numeric core, a release above its pre-releases, then the
+// pre-release identifiers. Build metadata is ignored. Loose versions like `4.9.dev3` take the
+// leading numbers as the core and the rest as pre-release; names without any sort below versions.
+fn version_key(name: &str) -> (Vec<u64>, bool, Vec<Identifier>) {
+    let version = name.split_once('+').map_or(name, |(version, _)| version);
+    let (main, pre) = match version.split_once('-') {
+        Some((main, pre)) => (main, Some(pre)),
+        None => (version, None),
+    };
+
+    let mut parts = main.split('.').peekable();
+    let mut core = Vec::new();
+    while let Some(number) = parts.next_if(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit())) {
+        core.push(number.parse().unwrap_or(u64::MAX));
+    }
+    let identifiers: Vec<Identifier> = parts
+        .chain(pre.into_iter().flat_map(|pre| pre.split('.')))
+        .map(|part| match part.parse() {
+            Ok(number) if part.bytes().all(|b| b.is_ascii_digit()) => Identifier::Numeric(number),
+            _ => Identifier::Text(part.to_string()),
+        })
+        .collect();
+    (core, identifiers.is_empty(), identifiers)
+}
+
+// Chip name and bootloader flash offset from the chip id in the image extended header
+pub fn chip_from_image(image: &[u8]) -> Option<(&'static str, u32)> {
+    if image.len() < 14 || image[0] != 0xe9 {
+        return None;
+    }
+    match u16::from_le_bytes([image[12], image[13]]) {
+        0 => Some(("esp32", 0x1000)),
+        2 => Some(("esp32s2", 0x1000)),
+        5 => Some(("esp32c3", 0x0)),
+        9 => Some(("esp32s3", 0x0)),
+        12 => Some(("esp32c2", 0x0)),
+        13 => Some(("esp32c6", 0x0)),
+        16 => Some(("esp32h2", 0x0)),
+        18 => Some(("esp32p4", 0x2000)),
+        20 => Some(("esp32c61", 0x0)),
+        23 => Some(("esp32c5", 0x2000)),
+        _ => None,
+    }
+}
+
+// boot_app0.bin from the installed esp32 core, selects the first OTA slot
+fn find_boot_app0() -> Option<PathBuf> {
+    let hardware_dir = arduino_data_dir().join("packages").join("esp32").join("hardware").join("esp32");
+    version_dirs(&hardware_dir)
+        .into_iter()
+        .map(|dir| dir.join("tools").join("partitions").join("boot_app0.bin"))
+        .find(|path| path.is_file())
+}
+
+// A binary of the build laid out at its flash offset
+pub struct FlashPart {
+    pub offset: u32,
+    pub path: PathBuf,
+}
+
+// Chip and flash layout of a finished build, derived from its bootloader and partition table
+pub fn flash_layout(build_dir: &Path) -> Result<(&'static str, Vec<FlashPart>), String> {
+    let app = find_app_binary(build_dir).ok_or("No application binary produced")?;
+    let base = app.to_string_lossy().trim_end_matches(".bin").to_string();
+    let bootloader = PathBuf::from(format!("{}.bootloader.bin", base));
+    let partitions = PathBuf::from(format!("{}.partitions.bin", base));
+
+    let bootloader_image = std::fs::read(&bootloader).map_err(|_| "No bootloader binary produced")?;
+    let table = std::fs::read(&partitions).map_err(|_| "No partition table produced")?;
+    let (chip, bootloader_offset) = chip_from_image(&bootloader_image).ok_or(
+        "Unrecognized bootloader image"
+    )?;
+    let partitions_parsed = parse_partition_table(&table);
+    let app_partition = boot_app_partition(&partitions_parsed).ok_or(
+        "Partition table has no app partition"
+    )?;
+
+    let mut parts = vec![
+        FlashPart { offset: bootloader_offset, path: bootloader },
+        FlashPart { offset: PARTITION_TABLE_OFFSET, path: partitions }
+    ];
+    if
+        let Some(otadata) = otadata_partition(&partitions_parsed) &&
+        let Some(boot_app0) = find_boot_app0()
+    {
+        parts.push(FlashPart { offset: otadata.offset, path: boot_app0 });
+    }
+    parts.push(FlashPart { offset: app_partition.offset, path: app });
+
+    Ok((chip, parts))
+}
+
+// Merge bootloader, partition table, boot_app0 and app into `<sketch>.ino.merged.bin`, the
+// signed app when the build was signed
+pub async fn merge_binaries(build_dir: &Path) -> CommandResponse {
+    let (chip, mut parts) = match flash_layout(build_dir) {
+        Ok(layout) => layout,
+        Err(e) => {
+            return error_response("esptool", vec!["merge_bin".to_string()], &e);
+        }
+    };
+    let app = parts[parts.len() - 1].path.clone();
+    let output = app.with_extension("merged.bin");
+    // A Secure Boot device only boots the signed app
+    let signed = app.with_extension("signed.bin");
+    if signed.is_file() && let Some(part) = parts.last_mut() {
+        part.path = signed;
+    }
+
+    let mut args = vec![
+        "--chip".to_string(),
+        chip.to_string(),
+        "merge_bin".to_string(),
+        "-o".to_string(),
+        output.to_string_lossy().to_string()
+    ];
+    // Keep the flash settings the bootloader was built with
+    for flag in ["--flash_mode", "--flash_freq", "--flash_size"] {
+        args.push(flag.to_string());
+        args.push("keep".to_string());
+    }
+    for part in &parts {
+        args.push(format!("0x{:x}", part.offset));
+        args.push(part.path.to_string_lossy().to_string());
+    }
+
+    match find_esptool() {
+        Some(esptool) => run_program(&esptool, "esptool", &args).await,
+        None => error_response("esptool", args, "esptool not found, install the esp32 core first"),
+    }
+}
+
+// Write a single image at `offset` on the device on `port`
+pub async fn write_flash(port: &str, chip: &str, offset: u32, image: &Path) -> CommandResponse {
+    let port = port_name(port);
+    let args = vec![
+        "--chip".to_string(),
+        chip.to_string(),
+        "--port".to_string(),
+        port.to_string(),
+        "write_flash".to_string(),
+        format!("0x{:x}", offset),
+        image.to_string_lossy().to_string()
+    ];
+
+    match find_esptool() {
+        Some(esptool) => {
+            let serial_port = acquire(ResourceKind::SerialPort, &port);
+            serial_port.scope(run_program(&esptool, "esptool", &args)).await
+        }
+        None => error_response("esptool", args, "esptool not found, install the esp32 core first"),
+    }
+}
+
+// Run an esptool operation against the device on `port`
+pub async fn run_esptool(request: &EsptoolRequest, operation: &str) -> CommandResponse {
+    run_esptool_with(request, &[], operation).await
+}
+
+async fn run_esptool_with(request: &EsptoolRequest, options: &[&str], operation: &str) -> CommandResponse {
+    let port = port_name(&request.port);
+    let mut args = vec!["--port".to_string(), port.clone()];
+    args.extend(options.iter().map(|o| o.to_string()));
+    if let Some(baud) = request.baud {
+        args.push("--baud".to_string());
+        args.push(baud.to_string());
+    }
+    if let Some(chip) = &request.chip {
+        args.push("--chip".to_string());
+        args.push(chip.clone());
+    }
+    args.push(operation.to_string());
+
+    match find_esptool() {
+        Some(esptool) => {
+            let serial_port = acquire(ResourceKind::SerialPort, &port);
+            serial_port.scope(run_program(&esptool, "esptool", &args)).await
+        }
+        None => error_response("esptool", args, "esptool not found, install the esp32 core first"),
+    }
+}
+
+// Reset the device on `port`: restart the application, or stay in the ROM download mode
+pub async fn reset_board(request: &ResetRequest) -> CommandResponse {
+    // esptool resets around any operation, reading the MAC is the cheapest one
+    let after = match request.mode {
+        ResetMode::Run => "hard_reset",
+        ResetMode::Bootloader => "no_reset",
+    };
+    let esptool_request = EsptoolRequest { port: request.port.clone(), baud: None, chip: request.chip.clone() };
+    let mut response = run_esptool_with(&esptool_request, &["--after", after], "read_mac").await;
+    response.command = "reset-board".to_string();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_dirs_are_ordered_by_semver() {
+        let dir = std::env::temp_dir().join(format!("cloud-compiler-test-{}", uuid::Uuid::new_v4().simple()));
+        let names = ["3.3.0-alpha1", "3.9.1", "5.0.0", "3.10.0", "4.9.dev3", "3.3.0", "3.3.0-rc.2", "3.3.0-rc.10"];
+        for name in names {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        let ordered: Vec<String> = version_dirs(&dir)
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(ordered, [
+            "5.0.0",
+            "4.9.dev3",
+            "3.10.0",
+            "3.9.1",
+            "3.3.0",
+            "3.3.0-rc.10",
+            "3.3.0-rc.2",
+            "3.3.0-alpha1",
+        ]);
+    }
+}
@@ -0,0 +1,184 @@
+// Example sketches of the installed platforms and libraries. An example is named
+// `<library> / <path>`, the path being the example's directory inside the library's
+// `examples/` (`ESP32 BLE Arduino / BLE_scan`, `WiFi / WiFiScan`). `list-examples` lists them
+// for examples browsers, `compile-example` copies one into the client's workspace and compiles
+// it like `compile-sketch`.
+use std::path::{ Path, PathBuf };
+use serde::Serialize;
+use crate::compiler::{ arduino_data_dir, arduino_user_dir };
+use crate::files::{ copy_dir, TempTree };
+
+// Example directories nest in categories (`01.Basics/Blink`), up to this deep
+const EXAMPLE_DEPTH: usize = 3;
+
+#[derive(Serialize, Clone)]
+pub struct Example {
+    pub id: String,
+    pub library: String,
+    pub name: String,
+    // Directory inside the library's `examples/`, `/` separated
+    pub path: String,
+    // First paragraph of the sketch's header comment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip)]
+    pub dir: PathBuf,
+    // `core` for libraries bundled with a platform, `library` for installed ones
+    pub source: &'static str,
+    // `packager:architecture` of the bundling platform
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+}
+
+fn subdirectories(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = std::fs
+        ::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                .map(|e| e.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
+}
+
+// `name=` of library.properties, the directory name when missing
+fn library_name(dir: &Path) -> String {
+    std::fs
+        ::read_to_string(dir.join("library.properties"))
+        .ok()
+        .and_then(|properties| {
+            properties
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("name="))
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+        })
+        .unwrap_or_else(|| dir.file_name().unwrap_or_default().to_string_lossy().to_string())
+}
+
+// The first paragraph of a sketch's opening comment (`/* ... */` or `//` lines), past a title
+// repeating the example's name
+fn description(sketch: &Path, name: &str) -> Option<String> {
+    let source = std::fs::read_to_string(sketch).ok()?;
+    let source = source.trim_start();
+    let comment: Vec<&str> = match source.strip_prefix("/*") {
+        Some(block) => block
+            .split("*/")
+            .next()
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.trim().trim_start_matches('*').trim())
+            .collect(),
+        None => source
+            .lines()
+            .map_while(|line| line.trim().strip_prefix("//"))
+            .map(|line| line.trim())
+            .collect(),
+    };
+    let mut paragraphs = comment
+        .split(|line| line.is_empty())
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| paragraph.join(" "));
+    let first = paragraphs.next()?;
+    match first.eq_ignore_ascii_case(name) {
+        true => paragraphs.next(),
+        false => Some(first),
+    }
+}
+
+// Directories below `dir` holding the `.ino` of their own name
+fn find_examples(dir: &Path, depth: usize, found: &mut Vec<PathBuf>) {
+    for child in subdirectories(dir) {
+        let name = child.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if child.join(format!("{}.ino", name)).is_file() {
+            found.push(child);
+        } else if depth > 1 {
+            find_examples(&child, depth - 1, found);
+        }
+    }
+}
+
+fn library_examples(library: &Path, source: &'static str, platform: Option<&str>, examples: &mut Vec<Example>) {
+    let root = library.join("examples");
+    let name = library_name(library);
+    let mut found = Vec::new();
+    find_examples(&root, EXAMPLE_DEPTH, &mut found);
+    for dir in found {
+        let path = dir.strip_prefix(&root).unwrap_or(&dir).to_string_lossy().replace('\\', "/");
+        let example = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+        examples.push(Example {
+            id: format!("{} / {}", name, path),
+            library: name.clone(),
+            description: description(&dir.join(format!("{}.ino", example)), &example),
+            name: example,
+            path,
+            dir,
+            source,
+            platform: platform.map(String::from),
+        });
+    }
+}
+
+// Every installed example. Platform libraries come first, an example of the same name in a
+// user library or an older platform version is left out.
+pub fn list() -> Vec<Example> {
+    let mut examples = Vec::new();
+    for packager in subdirectories(&arduino_data_dir().join("packages")) {
+        for architecture in subdirectories(&packager.join("hardware")) {
+            let platform = format!(
+                "{}:{}",
+                packager.file_name().unwrap_or_default().to_string_lossy(),
+                architecture.file_name().unwrap_or_default().to_string_lossy()
+            );
+            // Newest version first
+            for version in subdirectories(&architecture).into_iter().rev() {
+                for library in subdirectories(&version.join("libraries")) {
+                    library_examples(&library, "core", Some(&platform), &mut examples);
+                }
+            }
+        }
+    }
+    for library in subdirectories(&arduino_user_dir().join("libraries")) {
+        library_examples(&library, "library", None, &mut examples);
+    }
+    let mut seen = std::collections::HashSet::new();
+    examples.retain(|example| seen.insert(example.id.to_lowercase()));
+    examples
+}
+
+// The example `id` names: `<library> / <path>`, case-insensitive, the path may be reduced to
+// the example's own name when that is unique in the library
+pub fn find(id: &str) -> Result<Example, String> {
+    let (library, path) = id.split_once('/').ok_or_else(|| format!("Invalid example: {}, expected <library> / <example>", id))?;
+    let (library, path) = (library.trim().to_lowercase(), path.trim().to_lowercase());
+    let candidates: Vec<Example> = list().into_iter().filter(|example| example.library.to_lowercase() == library).collect();
+    if candidates.is_empty() {
+        return Err(format!("No library {} with examples installed", library));
+    }
+    if let Some(example) = candidates.iter().find(|example| example.path.to_lowercase() == path) {
+        return Ok(example.clone());
+    }
+    let mut named: Vec<Example> = candidates.into_iter().filter(|example| example.name.to_lowercase() == path).collect();
+    match named.len() {
+        0 => Err(format!("No example {}", id)),
+        1 => Ok(named.remove(0)),
+        _ => Err(format!(
+            "Several examples named {}: {}",
+            path,
+            named.iter().map(|example| example.id.as_str()).collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+// Copy `example` into a fresh directory below `parent`, to be compiled from there
+pub fn copy(example: &Example, parent: &Path) -> Result<TempTree, String> {
+    let dir = parent.join(uuid::Uuid::new_v4().to_string());
+    let copied = TempTree { tree: dir.join(&example.name), dir };
+    copy_dir(&example.dir, &copied.tree).map_err(|e| format!("Failed to copy example {}: {}", example.id, e))?;
+    Ok(copied)
+}
@@ -0,0 +1,363 @@
+use std::io::Read;
+use std::path::{ Component, Path, PathBuf };
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use md5::{ Digest, Md5 };
+use tracing::warn;
+use crate::models::FilePayload;
+use crate::compiler::{ is_safe_name, server_data_dir };
+
+// Upper bounds for client-supplied file trees
+pub const MAX_TOTAL_BYTES: u64 = 64 * 1024 * 1024;
+pub const MAX_FILES: usize = 2048;
+
+impl FilePayload {
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        match self.encoding.as_deref() {
+            Some("utf8") => Ok(self.content.as_bytes().to_vec()),
+            _ => BASE64.decode(&self.content).map_err(|e| format!("{}: invalid base64: {}", self.path, e)),
+        }
+    }
+}
+
+// Only plain relative paths are accepted, nothing that climbs out of the target directory
+pub fn safe_relative_path(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::CurDir => {}
+            _ => {
+                return None;
+            }
+        }
+    }
+    (!clean.as_os_str().is_empty()).then_some(clean)
+}
+
+// Root every client-supplied sketch path must resolve inside
+pub fn workspace_root() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_WORKSPACE_ROOT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| server_data_dir().join("workspaces"))
+}
+
+// Directory of an authenticated identity below `root`, the root itself otherwise
+pub fn subject_dir(root: PathBuf, subject: Option<&str>) -> PathBuf {
+    match subject {
+        Some(subject) if is_safe_name(subject) => root.join(subject),
+        Some(subject) => root.join(format!("{:x}", Md5::digest(subject.as_bytes()))),
+        None => root,
+    }
+}
+
+// Workspace of a client: a directory per authenticated identity, the root itself otherwise
+pub fn client_workspace(subject: Option<&str>) -> PathBuf {
+    subject_dir(workspace_root(), subject)
+}
+
+// `path` with symlinks and `.` resolved. On Windows without the `\\?\` prefix `canonicalize`
+// adds, which arduino-cli and gcc don't take, unless the path needs it (longer than MAX_PATH).
+pub fn canonical(path: &Path) -> std::io::Result<PathBuf> {
+    let path = path.canonicalize()?;
+    #[cfg(windows)]
+    {
+        let text = path.to_string_lossy();
+        if let Some(plain) = text.strip_prefix(r"\\?\") {
+            let drive = plain.as_bytes().get(1) == Some(&b':');
+            if drive && plain.len() < 260 {
+                return Ok(PathBuf::from(plain));
+            }
+        }
+    }
+    Ok(path)
+}
+
+// Resolve a client-supplied path inside `workspace`. Relative paths are taken from the workspace,
+// absolute ones must point into it, `..` is refused outright and the canonical target (symlinks
+// followed) may not leave it.
+pub fn resolve_client_path(workspace: &Path, path: &str) -> Result<PathBuf, String> {
+    let requested = Path::new(path);
+    if path.is_empty() || requested.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("Invalid path: {}", path));
+    }
+    std::fs::create_dir_all(workspace).map_err(|e| e.to_string())?;
+    let root = canonical(workspace).map_err(|e| e.to_string())?;
+    let target = canonical(&workspace.join(requested)).map_err(|_| format!("No such file or directory: {}", path))?;
+    if !target.starts_with(&root) {
+        return Err(format!("Path is outside the workspace: {}", path));
+    }
+
+    // The compiler reads the whole sketch folder, links in it may not escape either
+    let folder = if target.is_dir() { target.clone() } else { target.parent().unwrap_or(&root).to_path_buf() };
+    if escaping_link(&folder, &root, MAX_FILES).is_some() {
+        return Err(format!("Sketch links outside the workspace: {}", path));
+    }
+    Ok(target)
+}
+
+// First symlink below `dir` resolving outside `root`, visiting at most `budget` entries
+fn escaping_link(dir: &Path, root: &Path, mut budget: usize) -> Option<PathBuf> {
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            if budget == 0 {
+                return None;
+            }
+            budget -= 1;
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_symlink() {
+                if !canonical(&path).is_ok_and(|target| target.starts_with(root)) {
+                    return Some(path);
+                }
+            } else if file_type.is_dir() {
+                pending.push(path);
+            }
+        }
+    }
+    None
+}
+
+fn write_file(dir: &Path, relative: &Path, bytes: &[u8]) -> Result<(), String> {
+    let target = dir.join(relative);
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(&target, bytes).map_err(|e| format!("{}: {}", relative.display(), e))
+}
+
+// Write client files below `dir`
+pub fn write_files(dir: &Path, files: &[FilePayload]) -> Result<(), String> {
+    if files.len() > MAX_FILES {
+        return Err(format!("Too many files (max {})", MAX_FILES));
+    }
+    let mut total = 0u64;
+    for file in files {
+        let relative = safe_relative_path(&file.path).ok_or_else(||
+            format!("Invalid file path: {}", file.path)
+        )?;
+        let bytes = file.bytes()?;
+        total += bytes.len() as u64;
+        if total > MAX_TOTAL_BYTES {
+            return Err(format!("Files exceed {} MiB", MAX_TOTAL_BYTES / 1024 / 1024));
+        }
+        write_file(dir, &relative, &bytes)?;
+    }
+    Ok(())
+}
+
+// Extract a ZIP archive below `dir`, rejecting entries that escape it (zip-slip)
+pub fn extract_zip(dir: &Path, archive: &[u8]) -> Result<(), String> {
+    extract_zip_within(dir, archive, MAX_FILES, MAX_TOTAL_BYTES)
+}
+
+// `extract_zip` with limits of its own, for archives larger than sketches (worker builds)
+pub fn extract_zip_within(dir: &Path, archive: &[u8], max_files: usize, max_bytes: u64) -> Result<(), String> {
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive)).map_err(|e|
+        format!("Invalid ZIP archive: {}", e)
+    )?;
+    if zip.len() > max_files {
+        return Err(format!("Too many files (max {})", max_files));
+    }
+
+    let mut total = 0u64;
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index).map_err(|e| e.to_string())?;
+        if entry.is_dir() {
+            continue;
+        }
+        let relative = entry
+            .enclosed_name()
+            .and_then(|name| safe_relative_path(&name.to_string_lossy()))
+            .ok_or_else(|| format!("Invalid file path in archive: {}", entry.name()))?;
+
+        // Declared sizes can lie, so bound what is actually read
+        let remaining = max_bytes - total;
+        let mut bytes = Vec::new();
+        (&mut entry)
+            .take(remaining + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        total += bytes.len() as u64;
+        if total > max_bytes {
+            return Err(format!("Archive exceeds {} MiB", max_bytes / 1024 / 1024));
+        }
+        write_file(dir, &relative, &bytes)?;
+    }
+    Ok(())
+}
+
+// A temporary source tree (git checkout, copied example), deleted when dropped. The tree is a
+// subdirectory of `dir` so it can be renamed after the sketch it holds.
+pub struct TempTree {
+    pub dir: PathBuf,
+    pub tree: PathBuf,
+}
+
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) && e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+// Copy the directory `from` to `to`, symlinks are skipped
+pub fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if file_type.is_file() {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+// How deep below a source tree (or its `subdir`) sketches are looked for
+const SKETCH_SEARCH_DEPTH: usize = 3;
+
+// Directories below `dir` holding a sketch: an `.ino` named after the directory
+fn find_sketches(dir: &Path, depth: usize, found: &mut Vec<PathBuf>) {
+    let is_sketch = dir
+        .file_name()
+        .is_some_and(|name| dir.join(format!("{}.ino", name.to_string_lossy())).is_file());
+    if is_sketch {
+        found.push(dir.to_path_buf());
+        return;
+    }
+    if depth == 0 {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut subdirs: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|e| e.path())
+        .collect();
+    subdirs.sort();
+    for subdir in subdirs {
+        find_sketches(&subdir, depth - 1, found);
+    }
+}
+
+// The sketch in a source tree (a git checkout, an extracted archive): `subdir` or the tree
+// itself when it is one, otherwise the only sketch below it. `tree` lives in `container` and
+// is renamed there when a single .ino at its root names the sketch, as arduino-cli requires.
+pub fn locate_sketch(container: &Path, tree: &mut PathBuf, subdir: Option<&Path>) -> Result<PathBuf, String> {
+    let root = match subdir {
+        Some(subdir) => tree.join(subdir),
+        None => tree.clone(),
+    };
+    if !root.is_dir() {
+        return Err(format!("No such directory: {}", subdir.unwrap_or(Path::new("")).display()));
+    }
+    if subdir.is_none() {
+        let inos: Vec<PathBuf> = std::fs
+            ::read_dir(&root)
+            .map_err(|e| e.to_string())?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "ino") && path.is_file())
+            .collect();
+        if let [ino] = inos.as_slice() {
+            let stem = ino.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let renamed = container.join(&stem);
+            std::fs::rename(&*tree, &renamed).map_err(|e| e.to_string())?;
+            *tree = renamed.clone();
+            return Ok(renamed);
+        }
+    }
+
+    let mut found = Vec::new();
+    find_sketches(&root, SKETCH_SEARCH_DEPTH, &mut found);
+    match found.as_slice() {
+        [sketch] => Ok(sketch.clone()),
+        [] => Err("No sketch found".to_string()),
+        sketches => {
+            let names: Vec<String> = sketches
+                .iter()
+                .filter_map(|sketch| sketch.strip_prefix(&*tree).ok())
+                .map(|sketch| sketch.to_string_lossy().to_string())
+                .collect();
+            Err(format!("Several sketches found, pick one with subdir: {}", names.join(", ")))
+        }
+    }
+}
+
+// Decode a base64 ZIP payload and extract it below `dir`
+pub fn extract_zip_base64(dir: &Path, archive: &str) -> Result<(), String> {
+    let bytes = BASE64.decode(archive).map_err(|e| format!("Invalid base64 archive: {}", e))?;
+    extract_zip(dir, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory below the system temp dir, with a sketch at `Blink/Blink.ino`
+    fn workspace() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cloud-compiler-test-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(dir.join("Blink")).unwrap();
+        std::fs::write(dir.join("Blink").join("Blink.ino"), "void setup() {}\nvoid loop() {}\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_workspace() {
+        let workspace = workspace();
+        let root = canonical(&workspace).unwrap();
+        assert_eq!(resolve_client_path(&workspace, "Blink").unwrap(), root.join("Blink"));
+        assert_eq!(resolve_client_path(&workspace, "Blink/Blink.ino").unwrap(), root.join("Blink").join("Blink.ino"));
+        let absolute = root.join("Blink").to_string_lossy().to_string();
+        assert_eq!(resolve_client_path(&workspace, &absolute).unwrap(), root.join("Blink"));
+        std::fs::remove_dir_all(&workspace).unwrap();
+    }
+
+    #[test]
+    fn refuses_paths_leaving_the_workspace() {
+        let workspace = workspace();
+        let outside = self::workspace();
+        assert!(resolve_client_path(&workspace, "").is_err());
+        assert!(resolve_client_path(&workspace, "../Blink").unwrap_err().starts_with("Invalid path"));
+        assert!(resolve_client_path(&workspace, "Blink/../../Blink").unwrap_err().starts_with("Invalid path"));
+        let absolute = outside.join("Blink").to_string_lossy().to_string();
+        assert!(resolve_client_path(&workspace, &absolute).unwrap_err().starts_with("Path is outside the workspace"));
+        assert!(resolve_client_path(&workspace, "Missing").unwrap_err().starts_with("No such file or directory"));
+        std::fs::remove_dir_all(&workspace).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_links_out_of_the_workspace() {
+        let workspace = workspace();
+        let outside = self::workspace();
+        // A link that is the requested path
+        std::os::unix::fs::symlink(outside.join("Blink"), workspace.join("Linked")).unwrap();
+        assert!(resolve_client_path(&workspace, "Linked").unwrap_err().starts_with("Path is outside the workspace"));
+        // A link inside the sketch folder the compiler would read through
+        std::os::unix::fs::symlink(outside.join("Blink").join("Blink.ino"), workspace.join("Blink").join("extra.h")).unwrap();
+        assert!(resolve_client_path(&workspace, "Blink").unwrap_err().starts_with("Sketch links outside the workspace"));
+        // Links staying inside are fine
+        std::fs::remove_file(workspace.join("Blink").join("extra.h")).unwrap();
+        std::os::unix::fs::symlink(workspace.join("Blink").join("Blink.ino"), workspace.join("Blink").join("extra.h")).unwrap();
+        assert!(resolve_client_path(&workspace, "Blink").is_ok());
+        std::fs::remove_dir_all(&workspace).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
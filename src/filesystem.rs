@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+use crate::models::*;
+use crate::artifacts::{ attach_artifacts, build_dir };
+use crate::compiler::{ error_response, run_program };
+use crate::esptool::{ find_core_tool, flash_layout, write_flash };
+use crate::files::{ extract_zip_base64, write_files };
+use crate::partitions::{ filesystem_partition, read_build_partitions };
+
+// Page and block sizes used by the arduino-esp32 LittleFS/SPIFFS drivers
+const PAGE_SIZE: u32 = 256;
+const BLOCK_SIZE: u32 = 4096;
+
+impl FilesystemKind {
+    fn name(self) -> &'static str {
+        match self {
+            FilesystemKind::Littlefs => "littlefs",
+            FilesystemKind::Spiffs => "spiffs",
+        }
+    }
+
+    fn find_tool(self) -> Option<PathBuf> {
+        match self {
+            #[cfg(target_os = "windows")]
+            FilesystemKind::Littlefs => find_core_tool("mklittlefs", &["mklittlefs.exe"]),
+            #[cfg(not(target_os = "windows"))]
+            FilesystemKind::Littlefs => find_core_tool("mklittlefs", &["mklittlefs"]),
+            #[cfg(target_os = "windows")]
+            FilesystemKind::Spiffs => find_core_tool("mkspiffs", &["mkspiffs.exe"]),
+            #[cfg(not(target_os = "windows"))]
+            FilesystemKind::Spiffs => find_core_tool("mkspiffs", &["mkspiffs"]),
+        }
+    }
+}
+
+// Build a filesystem image for the data partition of a finished build, optionally flashing it
+pub async fn build_filesystem(request: &FilesystemRequest) -> CommandResponse {
+    let command = format!("build-{}", request.filesystem.name());
+    let fail = |message: &str| error_response(&command, vec![request.build_id.clone()], message);
+
+    let Some(dir) = build_dir(&request.build_id) else {
+        return fail("Unknown build");
+    };
+    let partitions = match read_build_partitions(&dir) {
+        Ok(partitions) => partitions,
+        Err(e) => {
+            return fail(&e);
+        }
+    };
+    let Some(partition) = filesystem_partition(&partitions) else {
+        return fail("The selected partition scheme has no filesystem partition");
+    };
+
+    // Stage the data/ folder from scratch so earlier images don't leak into this one
+    let data_dir = dir.join("data");
+    let _ = std::fs::remove_dir_all(&data_dir);
+    if let Err(e) = std::fs::create_dir_all(&data_dir) {
+        return fail(&e.to_string());
+    }
+    let staged = match &request.zip {
+        Some(archive) => extract_zip_base64(&data_dir, archive),
+        None => write_files(&data_dir, &request.files),
+    };
+    if let Err(e) = staged {
+        return fail(&e);
+    }
+
+    let Some(tool) = request.filesystem.find_tool() else {
+        return fail("Filesystem image tool not found, install the esp32 core first");
+    };
+    let image = dir.join(format!("{}.bin", request.filesystem.name()));
+    let args = vec![
+        "-c".to_string(),
+        data_dir.to_string_lossy().to_string(),
+        "-p".to_string(),
+        PAGE_SIZE.to_string(),
+        "-b".to_string(),
+        BLOCK_SIZE.to_string(),
+        "-s".to_string(),
+        partition.size.to_string(),
+        image.to_string_lossy().to_string()
+    ];
+    let mut response = run_program(&tool, &command, &args).await;
+
+    if response.success && let Some(port) = &request.port {
+        let chip = match flash_layout(&dir) {
+            Ok((chip, _)) => chip,
+            Err(e) => {
+                return fail(&e);
+            }
+        };
+        let flashed = write_flash(port, chip, partition.offset, &image).await;
+        response.success = flashed.success;
+        response.output.push_str(&flashed.output);
+        response.error = flashed.error.or(response.error);
+    }
+
+    attach_artifacts(&mut response, &request.build_id, &dir).await;
+    response
+}
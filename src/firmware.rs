@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use md5::{ Digest, Md5 };
+use serde::{ Deserialize, Serialize };
+use crate::artifacts::{ build_dir, find_app_binary };
+use crate::compiler::{ is_safe_name, server_data_dir };
+
+// Firmware hosting for devices running HTTPUpdate. Builds are published to a channel under
+// a version; devices poll their channel and get the newest image or a 304. Published
+// images are copied out of the build so they outlive build cleanup.
+
+pub const DEFAULT_CHANNEL: &str = "stable";
+const RELEASES_FILE: &str = "releases.json";
+const ASSIGNMENTS_FILE: &str = "assignments.json";
+
+// Serializes read-modify-write of the release and assignment files
+static STORE: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Release {
+    pub channel: String,
+    pub version: String,
+    pub build_id: String,
+    pub md5: String,
+    pub size: u64,
+    pub published_at: u64,
+}
+
+fn firmware_root() -> PathBuf {
+    server_data_dir().join("firmware")
+}
+
+fn channel_dir(channel: &str) -> Result<PathBuf, String> {
+    if !is_safe_name(channel) {
+        return Err(format!("Invalid channel: {}", channel));
+    }
+    Ok(firmware_root().join(channel))
+}
+
+fn read_json<T: for<'de> Deserialize<'de> + Default>(path: &std::path::Path) -> T {
+    std::fs::read(path)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default()
+}
+
+fn write_json<T: Serialize>(path: &std::path::Path, value: &T) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_vec_pretty(value).map_err(|e| e.to_string())?;
+    std::fs::write(path, json).map_err(|e| e.to_string())
+}
+
+// Releases of a channel, oldest first
+pub fn list_releases(channel: &str) -> Result<Vec<Release>, String> {
+    Ok(read_json(&channel_dir(channel)?.join(RELEASES_FILE)))
+}
+
+pub fn latest_release(channel: &str) -> Option<Release> {
+    list_releases(channel).ok()?.pop()
+}
+
+pub fn find_release(channel: &str, version: &str) -> Option<Release> {
+    list_releases(channel).ok()?.into_iter().find(|r| r.version == version)
+}
+
+// Image of a release on disk
+pub fn release_image(release: &Release) -> Option<PathBuf> {
+    let path = channel_dir(&release.channel).ok()?.join(format!("{}.bin", release.version));
+    path.is_file().then_some(path)
+}
+
+// Publish the app image of a build (the signed one if present) as `version` on `channel`
+pub fn publish(channel: &str, version: &str, build_id: &str) -> Result<Release, String> {
+    let dir = channel_dir(channel)?;
+    if !is_safe_name(version) {
+        return Err(format!("Invalid version: {}", version));
+    }
+    let build = build_dir(build_id).ok_or("Unknown build")?;
+    let app = find_app_binary(&build).ok_or("No application binary produced")?;
+    let signed = app.with_extension("signed.bin");
+    let image = std::fs::read(if signed.is_file() { signed } else { app }).map_err(|e| e.to_string())?;
+
+    let _store = STORE.lock().unwrap();
+    let mut releases = list_releases(channel)?;
+    if releases.iter().any(|r| r.version == version) {
+        return Err(format!("Version {} is already published on {}", version, channel));
+    }
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    std::fs::write(dir.join(format!("{}.bin", version)), &image).map_err(|e| e.to_string())?;
+
+    let release = Release {
+        channel: channel.to_string(),
+        version: version.to_string(),
+        build_id: build_id.to_string(),
+        md5: format!("{:x}", Md5::digest(&image)),
+        size: image.len() as u64,
+        published_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
+    releases.push(release.clone());
+    write_json(&dir.join(RELEASES_FILE), &releases)?;
+    Ok(release)
+}
+
+// MAC addresses are compared as lowercase hex pairs separated by colons
+pub fn normalize_mac(mac: &str) -> String {
+    mac.trim().to_lowercase().replace('-', ":")
+}
+
+// Channel a device is assigned to, by MAC
+pub fn assigned_channel(mac: &str) -> Option<String> {
+    let assignments: BTreeMap<String, String> = read_json(&firmware_root().join(ASSIGNMENTS_FILE));
+    assignments.get(&normalize_mac(mac)).cloned()
+}
+
+pub fn assign_channel(mac: &str, channel: &str) -> Result<(), String> {
+    channel_dir(channel)?;
+    let path = firmware_root().join(ASSIGNMENTS_FILE);
+    let _store = STORE.lock().unwrap();
+    let mut assignments: BTreeMap<String, String> = read_json(&path);
+    assignments.insert(normalize_mac(mac), channel.to_string());
+    write_json(&path, &assignments)
+}
+
+// Whether a device reporting `version`/`md5` already runs `release`
+pub fn is_current(release: &Release, version: Option<&str>, md5: Option<&str>) -> bool {
+    version == Some(release.version.as_str()) || md5.is_some_and(|md5| md5.eq_ignore_ascii_case(&release.md5))
+}
@@ -0,0 +1,245 @@
+// Source formatting. `format-sketch` runs clang-format over the files a client sends, with the
+// style the Arduino IDE formats sketches in, and answers with the formatted text or a unified
+// diff against what was sent. A `.clang-format` among the files replaces the Arduino style for
+// the files below it, as in the IDE.
+use std::path::{ Path, PathBuf };
+use serde::Serialize;
+use crate::models::FormatRequest;
+use crate::compiler::{ run_program, server_data_dir };
+use crate::files::{ safe_relative_path, write_files, TempTree };
+
+const MAX_FILES: usize = 256;
+const SOURCE_EXTENSIONS: &[&str] = &["ino", "pde", "h", "hh", "hpp", "c", "cc", "cpp", "cxx"];
+const STYLE_FILE: &str = ".clang-format";
+// Lines around each change in a diff
+const DIFF_CONTEXT: usize = 3;
+// Changed regions larger than this (old lines times new lines) are diffed as one replacement
+const MAX_DIFF_CELLS: usize = 4 * 1024 * 1024;
+
+// The Arduino IDE 2 formatter configuration, needs clang-format 14 or newer
+const ARDUINO_STYLE: &str = "\
+Language: Cpp
+AccessModifierOffset: -2
+AlignAfterOpenBracket: Align
+AlignConsecutiveAssignments: None
+AlignConsecutiveBitFields: None
+AlignConsecutiveDeclarations: None
+AlignConsecutiveMacros: None
+AlignEscapedNewlines: DontAlign
+AlignOperands: Align
+AlignTrailingComments: true
+AllowAllArgumentsOnNextLine: true
+AllowAllParametersOfDeclarationOnNextLine: true
+AllowShortBlocksOnASingleLine: Always
+AllowShortCaseLabelsOnASingleLine: true
+AllowShortEnumsOnASingleLine: true
+AllowShortFunctionsOnASingleLine: Empty
+AllowShortIfStatementsOnASingleLine: AllIfsAndElse
+AllowShortLambdasOnASingleLine: Empty
+AllowShortLoopsOnASingleLine: true
+AlwaysBreakAfterReturnType: None
+AlwaysBreakBeforeMultilineStrings: false
+AlwaysBreakTemplateDeclarations: No
+BinPackArguments: true
+BinPackParameters: true
+BreakBeforeBinaryOperators: NonAssignment
+BreakBeforeBraces: Attach
+BreakBeforeTernaryOperators: true
+BreakConstructorInitializers: BeforeColon
+BreakStringLiterals: false
+ColumnLimit: 0
+CompactNamespaces: false
+ContinuationIndentWidth: 2
+Cpp11BracedListStyle: false
+DerivePointerAlignment: true
+FixNamespaceComments: false
+IncludeBlocks: Preserve
+IndentCaseLabels: true
+IndentPPDirectives: None
+IndentWidth: 2
+KeepEmptyLinesAtTheStartOfBlocks: true
+MaxEmptyLinesToKeep: 100000
+NamespaceIndentation: None
+PointerAlignment: Right
+ReflowComments: false
+SortIncludes: Never
+SpaceAfterCStyleCast: false
+SpaceBeforeAssignmentOperators: true
+SpaceBeforeParens: ControlStatementsExceptControlMacros
+SpaceInEmptyParentheses: false
+SpacesInParentheses: false
+SpacesInSquareBrackets: false
+TabWidth: 2
+UseTab: Never
+";
+
+#[derive(Serialize)]
+pub struct FormattedFile {
+    pub path: String,
+    pub changed: bool,
+    // The formatted text, or with `diff` the changes to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+fn clang_format_binary() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_CLANG_FORMAT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("clang-format"))
+}
+
+fn is_style_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == STYLE_FILE)
+}
+
+fn is_source(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| SOURCE_EXTENSIONS.contains(&extension.to_string_lossy().as_ref()))
+}
+
+// Format the sources of a request, style files only configure the others
+pub async fn format_files(request: &FormatRequest) -> Result<Vec<FormattedFile>, String> {
+    if request.files.is_empty() || request.files.len() > MAX_FILES {
+        return Err(format!("Send 1 to {} files to format", MAX_FILES));
+    }
+    let mut sources = Vec::new();
+    for file in &request.files {
+        let relative = safe_relative_path(&file.path).ok_or_else(|| format!("Invalid file path: {}", file.path))?;
+        if is_style_file(&relative) {
+            continue;
+        }
+        if !is_source(&relative) {
+            return Err(format!("Not a C/C++ source: {}", file.path));
+        }
+        let text = String::from_utf8(file.bytes()?).map_err(|_| format!("{}: not UTF-8 text", file.path))?;
+        sources.push((file.path.clone(), relative, text));
+    }
+
+    let dir = server_data_dir().join("format").join(uuid::Uuid::new_v4().to_string());
+    let tree = TempTree { tree: dir.join("files"), dir };
+    write_files(&tree.tree, &request.files)?;
+    if !tree.tree.join(STYLE_FILE).is_file() {
+        std::fs::write(tree.tree.join(STYLE_FILE), ARDUINO_STYLE).map_err(|e| e.to_string())?;
+    }
+
+    let mut args = vec!["-i".to_string(), "--style=file".to_string()];
+    args.extend(sources.iter().map(|(_, relative, _)| tree.tree.join(relative).to_string_lossy().to_string()));
+    let response = run_program(&clang_format_binary(), "clang-format", &args).await;
+    if !response.success {
+        // Temporary paths mean nothing to the client
+        let prefix = format!("{}{}", tree.tree.display(), std::path::MAIN_SEPARATOR);
+        let error = response.error.unwrap_or_default().replace(&prefix, "");
+        return Err(format!("clang-format failed: {}", error.trim()));
+    }
+
+    sources
+        .into_iter()
+        .map(|(path, relative, original)| {
+            let formatted = std::fs::read_to_string(tree.tree.join(&relative)).map_err(|e| format!("{}: {}", path, e))?;
+            let changed = formatted != original;
+            let (content, diff) = match request.diff {
+                true => (None, Some(unified_diff(&path, &original, &formatted))),
+                false => (Some(formatted), None),
+            };
+            Ok(FormattedFile { path, changed, content, diff })
+        })
+        .collect()
+}
+
+// Line edits turning `old` into `new`: ' ' kept, '-' removed, '+' added. Common lines at both
+// ends are taken as they are, the rest is a longest common subsequence.
+fn line_edits<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut edits: Vec<(char, &str)> = old[..prefix].iter().map(|line| (' ', *line)).collect();
+    let (mut i, mut j) = (0, 0);
+    if a.len() * b.len() <= MAX_DIFF_CELLS {
+        // lengths[i * width + j]: length of the common subsequence of a[i..] and b[j..]
+        let width = b.len() + 1;
+        let mut lengths = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lengths[i * width + j] = match a[i] == b[j] {
+                    true => lengths[(i + 1) * width + j + 1] + 1,
+                    false => lengths[(i + 1) * width + j].max(lengths[i * width + j + 1]),
+                };
+            }
+        }
+        while i < a.len() && j < b.len() {
+            if a[i] == b[j] {
+                edits.push((' ', a[i]));
+                (i, j) = (i + 1, j + 1);
+            } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+                edits.push(('-', a[i]));
+                i += 1;
+            } else {
+                edits.push(('+', b[j]));
+                j += 1;
+            }
+        }
+    }
+    edits.extend(a[i..].iter().map(|line| ('-', *line)));
+    edits.extend(b[j..].iter().map(|line| ('+', *line)));
+    edits.extend(old[old.len() - suffix..].iter().map(|line| (' ', *line)));
+    edits
+}
+
+// Hunk range: the first line and the line count, the line before for an empty range
+fn hunk_range(first: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", first - 1),
+        1 => first.to_string(),
+        _ => format!("{},{}", first, count),
+    }
+}
+
+// `old` to `new` as a unified diff of `path`, empty when they are the same
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let edits = line_edits(&old_lines, &new_lines);
+    let changes: Vec<usize> = (0..edits.len()).filter(|index| edits[*index].0 != ' ').collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", path, path);
+    let mut next = 0;
+    while next < changes.len() {
+        // Changes closer than twice the context share a hunk
+        let start = changes[next].saturating_sub(DIFF_CONTEXT);
+        let mut last = changes[next];
+        while next < changes.len() && changes[next] <= last + 2 * DIFF_CONTEXT + 1 {
+            last = changes[next];
+            next += 1;
+        }
+        let end = (last + DIFF_CONTEXT + 1).min(edits.len());
+
+        let count = |range: &[(char, &str)], skip: char| range.iter().filter(|(kind, _)| *kind != skip).count();
+        let (before, hunk) = (&edits[..start], &edits[start..end]);
+        diff.push_str(
+            &format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(count(before, '+') + 1, count(hunk, '+')),
+                hunk_range(count(before, '-') + 1, count(hunk, '-'))
+            )
+        );
+        for (kind, line) in hunk {
+            diff.push(*kind);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    diff
+}
@@ -0,0 +1,147 @@
+// Sources from git. `compile-from-git` fetches one revision of a repository into a temporary
+// checkout in the client's workspace, finds the sketch in it and compiles it like
+// `compile-sketch`; the checkout is removed when the compile is done.
+//
+// Only http(s) remotes on the hosts in CLOUD_COMPILER_GIT_HOSTS are fetched (comma separated,
+// default github.com, gitlab.com, bitbucket.org and codeberg.org, `*` for any), without
+// submodules, LFS or credentials. Checkouts larger than CLOUD_COMPILER_GIT_MAX_MB (default 100)
+// are refused. CLOUD_COMPILER_GIT sets the git binary (default `git` from the PATH).
+use std::path::{ Path, PathBuf };
+use serde_json::Value;
+use crate::compiler::run_program_with_env;
+use crate::files::{ self, safe_relative_path, TempTree };
+use crate::usage::dir_size;
+
+const DEFAULT_HOSTS: &[&str] = &["github.com", "gitlab.com", "bitbucket.org", "codeberg.org"];
+const DEFAULT_MAX_MB: u64 = 100;
+
+// What to fetch, from a `compile-from-git` request
+pub struct GitSource {
+    pub url: String,
+    // Branch, tag or full commit hash, the remote's HEAD when missing
+    pub reference: Option<String>,
+    // Directory of the sketch inside the repository
+    pub subdir: Option<PathBuf>,
+    // HTTP Authorization header for the fetch, only set by the server for its own repositories
+    pub authorization: Option<String>,
+}
+
+fn allowed_hosts() -> Vec<String> {
+    match std::env::var("CLOUD_COMPILER_GIT_HOSTS").ok().filter(|hosts| !hosts.trim().is_empty()) {
+        Some(hosts) => hosts.split(',').map(|host| host.trim().to_lowercase()).filter(|host| !host.is_empty()).collect(),
+        None => DEFAULT_HOSTS.iter().map(|host| host.to_string()).collect(),
+    }
+}
+
+fn max_bytes() -> u64 {
+    std::env
+        ::var("CLOUD_COMPILER_GIT_MAX_MB")
+        .ok()
+        .and_then(|mb| mb.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_MB) * 1024 * 1024
+}
+
+fn git_binary() -> PathBuf {
+    std::env::var_os("CLOUD_COMPILER_GIT").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("git"))
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|_| format!("Invalid repository URL: {}", url))?;
+    if !matches!(parsed.scheme(), "https" | "http") {
+        return Err("Only http(s) repository URLs are supported".to_string());
+    }
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        return Err("Repository URLs may not carry credentials".to_string());
+    }
+    let host = parsed.host_str().unwrap_or_default().to_lowercase();
+    let hosts = allowed_hosts();
+    if !hosts.iter().any(|allowed| allowed == "*" || *allowed == host) {
+        return Err(format!("Repository host {} is not allowed", host));
+    }
+    Ok(())
+}
+
+// Refs are passed to git as arguments, nothing that could read as an option or a range
+fn valid_reference(reference: &str) -> bool {
+    !reference.is_empty() &&
+        !reference.starts_with('-') &&
+        !reference.contains("..") &&
+        reference.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+impl GitSource {
+    pub fn from_request(data: &Value) -> Result<GitSource, String> {
+        let url = data.get("url").and_then(|v| v.as_str()).ok_or("Missing repository URL")?.trim().to_string();
+        check_url(&url)?;
+        let reference = data.get("ref").and_then(|v| v.as_str()).filter(|r| !r.is_empty()).map(String::from);
+        if let Some(reference) = &reference && !valid_reference(reference) {
+            return Err(format!("Invalid ref: {}", reference));
+        }
+        let subdir = match data.get("subdir").and_then(|v| v.as_str()).filter(|s| !s.is_empty()) {
+            Some(subdir) => Some(safe_relative_path(subdir).ok_or_else(|| format!("Invalid subdirectory: {}", subdir))?),
+            None => None,
+        };
+        Ok(GitSource { url, reference, subdir, authorization: None })
+    }
+}
+
+async fn git(args: &[&str], authorization: Option<&str>) -> Result<(), String> {
+    // No prompts, no user or system configuration (credential helpers, LFS filters, hooks)
+    let mut env = vec![
+        ("GIT_TERMINAL_PROMPT", "0"),
+        ("GIT_CONFIG_NOSYSTEM", "1"),
+        ("GIT_CONFIG_GLOBAL", "/dev/null"),
+    ];
+    // In the environment rather than the arguments, which any process can list
+    let header = authorization.map(|authorization| format!("Authorization: {}", authorization));
+    if let Some(header) = &header {
+        env.extend([("GIT_CONFIG_COUNT", "1"), ("GIT_CONFIG_KEY_0", "http.extraHeader"), ("GIT_CONFIG_VALUE_0", header.as_str())]);
+    }
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let response = run_program_with_env(&git_binary(), "git", &args, &env).await;
+    match response.success {
+        true => Ok(()),
+        false => Err(response.error.unwrap_or_default().trim().to_string()),
+    }
+}
+
+// Fetch `source` into a fresh directory below `parent`, `progress` is told each step
+pub async fn fetch(source: &GitSource, parent: &Path, progress: impl Fn(&str)) -> Result<TempTree, String> {
+    std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    let dir = parent.join(uuid::Uuid::new_v4().to_string());
+    let checkout = TempTree { tree: dir.join("repository"), dir };
+    let dir = checkout.tree.to_string_lossy().to_string();
+    let reference = source.reference.as_deref().unwrap_or("HEAD");
+    let safe = [
+        "-c", "protocol.allow=never",
+        "-c", "protocol.https.allow=always",
+        "-c", "protocol.http.allow=always",
+        "-c", "core.symlinks=false",
+        "-c", "core.hooksPath=/dev/null",
+        "-c", "advice.detachedHead=false",
+    ];
+
+    progress("fetching");
+    git(&["init", "--quiet", &dir], None).await?;
+    let mut fetch = vec!["-C", &dir];
+    fetch.extend(safe);
+    fetch.extend(["fetch", "--quiet", "--depth", "1", "--no-tags", "--", &source.url, reference]);
+    git(&fetch, source.authorization.as_deref()).await.map_err(|e| format!("Failed to fetch {} {}: {}", source.url, reference, e))?;
+
+    progress("checking-out");
+    let mut checkout_args = vec!["-C", &dir];
+    checkout_args.extend(safe);
+    checkout_args.extend(["checkout", "--quiet", "--detach", "FETCH_HEAD"]);
+    git(&checkout_args, None).await.map_err(|e| format!("Failed to check out {}: {}", reference, e))?;
+
+    let size = dir_size(&checkout.dir);
+    if size > max_bytes() {
+        return Err(format!("Repository is larger than {} MB", max_bytes() / 1024 / 1024));
+    }
+    Ok(checkout)
+}
+
+// The sketch of a checkout, see `files::locate_sketch`
+pub fn locate_sketch(checkout: &mut TempTree, source: &GitSource) -> Result<PathBuf, String> {
+    files::locate_sketch(&checkout.dir, &mut checkout.tree, source.subdir.as_deref())
+}
@@ -0,0 +1,372 @@
+// Builds triggered by GitHub. A repository webhook sending JSON, signed with
+// CLOUD_COMPILER_GITHUB_WEBHOOK_SECRET, is pointed at `POST /github/webhook`. Every push and pull
+// request compiles the builds configured for its repository at the pushed commit and reports each
+// as a commit status with the sketch size and diagnostics. The builds are listed in a TOML file,
+// CLOUD_COMPILER_GITHUB_BUILDS (default `<data dir>/github.toml` when it exists):
+//
+//   [[build]]
+//   repository = "octo-org/firmware"
+//   sketch = "firmware"           # directory of the sketch, found like compile-from-git when missing
+//   fqbn = "esp32:esp32:esp32"
+//   branches = ["main"]           # pushes to and pull requests into these, any when missing
+//   name = "firmware (ESP32)"     # status context, `cloud-compiler/<sketch> (<fqbn>)` when missing
+//
+// The file is read for every delivery. Statuses are posted with CLOUD_COMPILER_GITHUB_TOKEN, which
+// also fetches private repositories, to CLOUD_COMPILER_GITHUB_API_URL (default
+// https://api.github.com). Repositories are fetched like `compile-from-git`, from the hosts in
+// CLOUD_COMPILER_GIT_HOSTS.
+use std::path::PathBuf;
+use std::sync::{ Arc, LazyLock };
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use hmac::{ Hmac, Mac };
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use sha2::Sha256;
+use tracing::{ info, warn };
+use utoipa::ToSchema;
+use crate::analytics;
+use crate::build::BuildOptions;
+use crate::compiler::{ error_response, server_data_dir };
+use crate::errors::CompilerError;
+use crate::files::{ self, client_workspace, safe_relative_path, TempTree };
+use crate::git::{ self, GitSource };
+use crate::history;
+use crate::models::{ CommandResponse, MemoryUsage, Severity };
+use crate::notifications::public_url;
+use crate::queue::{ self, Priority };
+use crate::resources::{ current_job, job };
+use crate::socketio::{ compile_args, compile_target, Caller };
+use crate::webhooks;
+
+const DEFAULT_API_URL: &str = "https://api.github.com";
+// GitHub cuts status descriptions longer than this
+const MAX_DESCRIPTION: usize = 140;
+
+static HTTP: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    build: Vec<Build>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Build {
+    repository: String,
+    #[serde(default)]
+    sketch: Option<String>,
+    fqbn: String,
+    #[serde(default)]
+    branches: Vec<String>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+impl Build {
+    fn context(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("cloud-compiler/{} ({})", self.sketch.as_deref().unwrap_or("sketch"), self.fqbn),
+        }
+    }
+}
+
+// The commit a delivery asks to build
+struct Commit {
+    // `owner/name` of the repository the statuses go to
+    repository: String,
+    // Where the commit is fetched from, a fork for pull requests from one
+    clone_url: String,
+    sha: String,
+    // Branch pushed to, or the pull request's base
+    branch: String,
+    private: bool,
+}
+
+// Answer to a delivery
+#[derive(Serialize, ToSchema)]
+pub struct Delivery {
+    // Builds started for the delivery, 0 for events and repositories without any
+    pub builds: usize,
+}
+
+pub fn secret() -> Option<String> {
+    std::env::var("CLOUD_COMPILER_GITHUB_WEBHOOK_SECRET").ok().filter(|secret| !secret.is_empty())
+}
+
+fn token() -> Option<String> {
+    std::env::var("CLOUD_COMPILER_GITHUB_TOKEN").ok().filter(|token| !token.trim().is_empty())
+}
+
+fn api_url() -> String {
+    std::env
+        ::var("CLOUD_COMPILER_GITHUB_API_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| DEFAULT_API_URL.to_string())
+}
+
+fn config_path() -> Option<PathBuf> {
+    match std::env::var_os("CLOUD_COMPILER_GITHUB_BUILDS").filter(|path| !path.is_empty()) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(server_data_dir().join("github.toml")).filter(|path| path.is_file()),
+    }
+}
+
+fn builds() -> Result<Vec<Build>, String> {
+    let Some(path) = config_path() else {
+        return Ok(Vec::new());
+    };
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let config: Config = toml::from_str(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    Ok(config.build)
+}
+
+// Whether `signature`, the X-Hub-Signature-256 header, signs `body` with `secret`
+pub fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(hex) = signature.strip_prefix("sha256=") else {
+        return false;
+    };
+    let Some(bytes) = decode_hex(hex) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.verify_slice(&bytes).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn valid_repository(name: &str) -> bool {
+    name.split('/').count() == 2 &&
+        name.split('/').all(|part| !part.is_empty() && part != "." && part != "..") &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+// The commit of a `push` or `pull_request` event, none for other events and for pushes that
+// build nothing (deleted branches, tags)
+fn commit(event: &str, payload: &Value) -> Result<Option<Commit>, String> {
+    let (clone_url, sha, branch) = match event {
+        "push" => {
+            if payload["deleted"].as_bool().unwrap_or(false) {
+                return Ok(None);
+            }
+            let Some(branch) = payload["ref"].as_str().and_then(|r| r.strip_prefix("refs/heads/")) else {
+                return Ok(None);
+            };
+            (&payload["repository"]["clone_url"], &payload["after"], branch)
+        }
+        "pull_request" => {
+            if !matches!(payload["action"].as_str(), Some("opened" | "synchronize" | "reopened")) {
+                return Ok(None);
+            }
+            let pull = &payload["pull_request"];
+            (&pull["head"]["repo"]["clone_url"], &pull["head"]["sha"], pull["base"]["ref"].as_str().unwrap_or_default())
+        }
+        _ => {
+            return Ok(None);
+        }
+    };
+    let repository = payload["repository"]["full_name"].as_str().unwrap_or_default();
+    if !valid_repository(repository) {
+        return Err(format!("Invalid repository name: {}", repository));
+    }
+    let sha = sha.as_str().filter(|sha| sha.len() == 40 && sha.chars().all(|c| c.is_ascii_hexdigit()));
+    let (Some(sha), Some(clone_url)) = (sha, clone_url.as_str()) else {
+        return Err("Missing commit or clone URL".to_string());
+    };
+    Ok(
+        Some(Commit {
+            repository: repository.to_string(),
+            clone_url: clone_url.to_string(),
+            sha: sha.to_string(),
+            branch: branch.to_string(),
+            private: payload["repository"]["private"].as_bool().unwrap_or(false),
+        })
+    )
+}
+
+// Start the builds a delivery of `event` asks for
+pub fn receive(event: &str, delivery: &str, body: &[u8]) -> Result<Delivery, String> {
+    let payload: Value = serde_json::from_slice(body).map_err(|e| format!("Invalid payload: {}", e))?;
+    let Some(commit) = commit(event, &payload)? else {
+        return Ok(Delivery { builds: 0 });
+    };
+    let builds: Vec<Build> = builds()?
+        .into_iter()
+        .filter(|build| build.repository.eq_ignore_ascii_case(&commit.repository))
+        .filter(|build| build.branches.is_empty() || build.branches.contains(&commit.branch))
+        .collect();
+    if builds.is_empty() {
+        return Ok(Delivery { builds: 0 });
+    }
+    let mut source = GitSource::from_request(&serde_json::json!({ "url": commit.clone_url, "ref": commit.sha }))?;
+    if commit.private && let Some(token) = token() {
+        source.authorization = Some(format!("Basic {}", BASE64.encode(format!("x-access-token:{}", token))));
+    }
+    info!("GitHub delivery {}: {} build(s) of {} at {}", delivery, builds.len(), commit.repository, commit.sha);
+    let count = builds.len();
+    tokio::spawn(run(Arc::new(commit), source, builds, format!("github:{}", delivery)));
+    Ok(Delivery { builds: count })
+}
+
+async fn run(commit: Arc<Commit>, source: GitSource, builds: Vec<Build>, owner: String) {
+    for build in &builds {
+        post_status(&commit, build, "pending", "Compiling", None).await;
+    }
+    // Checkouts live in the server's workspace, where compile paths have to resolve
+    let parent = client_workspace(None).join("git");
+    let mut checkout = match git::fetch(&source, &parent, |_| {}).await {
+        Ok(checkout) => checkout,
+        Err(e) => {
+            warn!("Failed to fetch {} at {}: {}", commit.repository, commit.sha, e);
+            for build in &builds {
+                post_status(&commit, build, "error", &e, None).await;
+            }
+            return;
+        }
+    };
+    // Locating may move the checkout, so every sketch is found before any compile starts
+    let sketches: Vec<Result<PathBuf, String>> = builds
+        .iter()
+        .map(|build| {
+            let subdir = match &build.sketch {
+                Some(sketch) => Some(safe_relative_path(sketch).ok_or_else(|| format!("Invalid sketch directory: {}", sketch))?),
+                None => None,
+            };
+            files::locate_sketch(&checkout.dir, &mut checkout.tree, subdir.as_deref())
+        })
+        .collect();
+    let checkout = Arc::new(checkout);
+    let compiles = builds.into_iter().zip(sketches).map(|(build, sketch)| {
+        tokio::spawn(job(owner.clone(), "github-build", compile(commit.clone(), build, sketch, owner.clone(), checkout.clone())))
+    });
+    futures::future::join_all(compiles).await;
+}
+
+// Compile one build and report it, `_checkout` kept until the compile is done
+async fn compile(commit: Arc<Commit>, build: Build, sketch: Result<PathBuf, String>, owner: String, _checkout: Arc<TempTree>) {
+    let mut response = match sketch {
+        Ok(sketch) => compile_sketch(&build, &sketch, &owner).await,
+        Err(e) => error_response("git", vec![], &e),
+    };
+    if let Some(job) = current_job() {
+        history::record(&job, None, &owner, &response);
+        webhooks::job_finished(&job, None, &response);
+        response.job_id.get_or_insert(job.id);
+    }
+    let state = if response.success { "success" } else { "failure" };
+    let target_url = response.build_id
+        .as_ref()
+        .filter(|_| response.success && !public_url().is_empty())
+        .map(|build_id| format!("{}/builds/{}/manifest.json", public_url(), build_id));
+    info!(job_id = ?response.job_id, "{} of {} at {}: {}", build.context(), commit.repository, commit.sha, state);
+    post_status(&commit, &build, state, &description(&response), target_url).await;
+}
+
+async fn compile_sketch(build: &Build, sketch: &std::path::Path, owner: &str) -> CommandResponse {
+    let data = serde_json::json!({ "sketch_path": sketch.to_string_lossy(), "fqbn": build.fqbn });
+    let mut options = match BuildOptions::validated(&data) {
+        Ok(options) => options,
+        Err(e) => {
+            return error_response("compile", vec![], &e);
+        }
+    };
+    let mut args = compile_args(&mut options);
+    let ticket = match queue::join(Priority::Bulk) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            return CompilerError::QueueFull(e).response("compile", vec![]);
+        }
+    };
+    let caller = Caller { owner: owner.to_string(), queued: Box::new(|_| {}) };
+    let built = compile_target(&caller, ticket, &data, &mut args, &options, None, None).await;
+    let response = built.unwrap_or_else(|response| response);
+    analytics::record_compile(options.fqbn.as_deref(), &response);
+    response
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+}
+
+fn usage(bytes: u64, max: Option<u64>) -> String {
+    match max.filter(|max| *max > 0) {
+        Some(max) => format!("{} B ({}%)", bytes, (bytes * 100) / max),
+        None => format!("{} B", bytes),
+    }
+}
+
+// Status description: the size and warnings of a build, or its first error
+fn description(response: &CommandResponse) -> String {
+    let count = |severity: Severity| response.diagnostics.iter().filter(|diagnostic| diagnostic.severity == severity).count();
+    let text = match response.success {
+        true => {
+            let mut parts = Vec::new();
+            if let Some(MemoryUsage { flash_bytes, flash_max, ram_bytes, ram_max }) = response.size {
+                parts.push(format!("Flash {}", usage(flash_bytes, flash_max)));
+                if let Some(ram_bytes) = ram_bytes {
+                    parts.push(format!("RAM {}", usage(ram_bytes, ram_max)));
+                }
+            }
+            parts.push(plural(count(Severity::Warning), "warning"));
+            parts.join(", ")
+        }
+        false => {
+            let first = response.diagnostics.iter().find(|diagnostic| diagnostic.severity == Severity::Error);
+            match first {
+                Some(diagnostic) => {
+                    let location = match (&diagnostic.file, diagnostic.line) {
+                        (Some(file), Some(line)) => format!("{}:{}: ", file, line),
+                        _ => String::new(),
+                    };
+                    format!("{}, {}{}", plural(count(Severity::Error), "error"), location, diagnostic.message)
+                }
+                None =>
+                    response.error
+                        .as_deref()
+                        .and_then(|error| error.lines().find(|line| !line.trim().is_empty()))
+                        .unwrap_or("Compile failed")
+                        .trim()
+                        .to_string(),
+            }
+        }
+    };
+    match text.chars().count() > MAX_DESCRIPTION {
+        true => text.chars().take(MAX_DESCRIPTION - 1).chain(std::iter::once('…')).collect(),
+        false => text,
+    }
+}
+
+async fn post_status(commit: &Commit, build: &Build, state: &str, description: &str, target_url: Option<String>) {
+    let Some(token) = token() else {
+        return;
+    };
+    let url = format!("{}/repos/{}/statuses/{}", api_url(), commit.repository, commit.sha);
+    let mut status = serde_json::json!({ "state": state, "description": description, "context": build.context() });
+    if let Some(target_url) = target_url {
+        status["target_url"] = target_url.into();
+    }
+    let posted = HTTP.post(&url)
+        .bearer_auth(token)
+        .header("Accept", "application/vnd.github+json")
+        .header("User-Agent", "arduino-esp32-cloud-compiler")
+        .json(&status)
+        .send().await;
+    match posted {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => warn!("GitHub answered {} to the status of {} at {}", response.status(), commit.repository, commit.sha),
+        Err(e) => warn!("Failed to post the status of {} at {}: {}", commit.repository, commit.sha, e),
+    }
+}
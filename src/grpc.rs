@@ -0,0 +1,639 @@
+// gRPC API for backend integrations, opted in with CLOUD_COMPILER_GRPC_PORT. The compile, upload,
+// core, library and board operations of the Socket.IO events are served as the
+// `cloudcompiler.v1.CloudCompiler` service of `proto/cloud_compiler.proto`, on a port of its own
+// (plaintext HTTP/2, put TLS in front of it). Long operations stream `JobEvent`s: queue positions
+// and tool output while they run, then the result; a caller hanging up cancels its job.
+//
+// Calls authenticate with `authorization: Bearer <api key or JWT>` metadata and run through the
+// same policy, queue, quotas, rate limits, job history and webhooks as events do. The messages are
+// declared by hand like the ones of the arduino-cli daemon, the proto file is kept in step with them.
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use serde_json::{ json, Value };
+use tokio::sync::mpsc::{ self, UnboundedSender };
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http;
+use tonic::codegen::tokio_stream::wrappers::{ TcpListenerStream, UnboundedReceiverStream };
+use tonic::codegen::Service;
+use tonic::server::{ Grpc, NamedService, ServerStreamingService, UnaryService };
+use tonic::{ Request, Response, Status };
+use tracing::{ info, warn };
+use crate::admin;
+use crate::analytics;
+use crate::auth::{ authenticate, AuthError, AuthMethod };
+use crate::build::BuildOptions;
+use crate::compiler::run_arduino_command;
+use crate::files::{ client_workspace, resolve_client_path };
+use crate::history;
+use crate::models::{ self, ArduinoCommand, CommandResponse, LogStream, Severity };
+use crate::output;
+use crate::queue;
+use crate::ratelimit::{ self, retry_after_secs, Limit };
+use crate::resources::{ current_job, job };
+use crate::shutdown;
+use crate::socketio::{ compile_args, compile_target, install_core, prepare_upload, run_upload, Caller };
+use crate::toolchain;
+use crate::uploads;
+use crate::usage;
+use crate::validation;
+use crate::webhooks;
+
+const SERVICE: &str = "cloudcompiler.v1.CloudCompiler";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CompileRequest {
+    // In the caller's workspace, or the folder of `archive` holding the sketch
+    #[prost(string, tag = "1")]
+    pub sketch_path: String,
+    // The sketch as a ZIP archive, imported like `POST /sketches`
+    #[prost(bytes = "vec", tag = "2")]
+    pub archive: Vec<u8>,
+    #[prost(string, tag = "3")]
+    pub fqbn: String,
+    #[prost(string, tag = "4")]
+    pub profile: String,
+    #[prost(bool, tag = "5")]
+    pub merge: bool,
+    #[prost(string, tag = "6")]
+    pub partitions_csv: String,
+    #[prost(bool, tag = "7")]
+    pub keep_build_dir: bool,
+    #[prost(string, tag = "8")]
+    pub encrypt: String,
+    #[prost(map = "string, string", tag = "9")]
+    pub secrets: std::collections::HashMap<String, String>,
+    #[prost(string, tag = "10")]
+    pub toolchain: String,
+    #[prost(string, tag = "11")]
+    pub priority: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UploadRequest {
+    #[prost(string, tag = "1")]
+    pub sketch_path: String,
+    #[prost(string, tag = "2")]
+    pub fqbn: String,
+    #[prost(string, tag = "3")]
+    pub port: String,
+    #[prost(string, tag = "4")]
+    pub address: String,
+    #[prost(string, tag = "5")]
+    pub ota_password: String,
+    #[prost(string, tag = "6")]
+    pub build_id: String,
+    #[prost(string, tag = "7")]
+    pub toolchain: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct InstallCoreRequest {
+    #[prost(string, tag = "1")]
+    pub core: String,
+    #[prost(string, tag = "2")]
+    pub toolchain: String,
+    #[prost(string, tag = "3")]
+    pub priority: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct InstallLibraryRequest {
+    // `name` or `name@version`
+    #[prost(string, tag = "1")]
+    pub library: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SearchLibrariesRequest {
+    #[prost(string, tag = "1")]
+    pub query: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListLibrariesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListCoresRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListBoardsRequest {
+    // Boards attached to the server instead of every known one
+    #[prost(bool, tag = "1")]
+    pub connected: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum OutputStream {
+    Stdout = 0,
+    Stderr = 1,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct QueueUpdate {
+    // 1 runs next
+    #[prost(uint64, tag = "1")]
+    pub position: u64,
+    #[prost(uint64, tag = "2")]
+    pub eta_secs: u64,
+    #[prost(string, tag = "3")]
+    pub build_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OutputLine {
+    #[prost(enumeration = "OutputStream", tag = "1")]
+    pub stream: i32,
+    #[prost(string, tag = "2")]
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Artifact {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(uint64, tag = "2")]
+    pub size: u64,
+    #[prost(string, tag = "3")]
+    pub sha256: String,
+    #[prost(string, tag = "4")]
+    pub url: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Diagnostic {
+    // error, warning or advisory
+    #[prost(string, tag = "1")]
+    pub severity: String,
+    #[prost(string, tag = "2")]
+    pub code: String,
+    #[prost(string, tag = "3")]
+    pub message: String,
+    #[prost(string, tag = "4")]
+    pub file: String,
+    #[prost(uint32, tag = "5")]
+    pub line: u32,
+}
+
+// The `CommandResponse` of the operation, its common fields typed and all of it as JSON
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CommandResult {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    #[prost(string, tag = "2")]
+    pub output: String,
+    #[prost(string, tag = "3")]
+    pub error: String,
+    #[prost(string, tag = "4")]
+    pub error_code: String,
+    #[prost(string, tag = "5")]
+    pub command: String,
+    #[prost(string, repeated, tag = "6")]
+    pub args: Vec<String>,
+    #[prost(string, tag = "7")]
+    pub build_id: String,
+    #[prost(message, repeated, tag = "8")]
+    pub artifacts: Vec<Artifact>,
+    #[prost(message, repeated, tag = "9")]
+    pub diagnostics: Vec<Diagnostic>,
+    #[prost(string, tag = "10")]
+    pub job_id: String,
+    #[prost(bool, tag = "11")]
+    pub cached: bool,
+    #[prost(string, tag = "12")]
+    pub response_json: String,
+}
+
+// One of the fields is set per message, `result` in the last one
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct JobEvent {
+    #[prost(message, optional, tag = "1")]
+    pub queued: Option<QueueUpdate>,
+    #[prost(message, optional, tag = "2")]
+    pub output: Option<OutputLine>,
+    #[prost(message, optional, tag = "3")]
+    pub result: Option<CommandResult>,
+}
+
+type Events = UnboundedReceiverStream<Result<JobEvent, Status>>;
+
+pub fn port() -> Option<u16> {
+    std::env::var("CLOUD_COMPILER_GRPC_PORT").ok().and_then(|port| port.trim().parse().ok())
+}
+
+// Proto3 has no unset strings, empty ones count as not given
+fn given(value: &str) -> Option<&str> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+fn result_of(response: &CommandResponse) -> CommandResult {
+    CommandResult {
+        success: response.success,
+        output: response.output.clone(),
+        error: response.error.clone().unwrap_or_default(),
+        error_code: response.error_code.map(|code| code.as_str().to_string()).unwrap_or_default(),
+        command: response.command.clone(),
+        args: response.args.clone(),
+        build_id: response.build_id.clone().unwrap_or_default(),
+        artifacts: response.artifacts
+            .iter()
+            .map(|artifact| Artifact {
+                name: artifact.name.clone(),
+                size: artifact.size,
+                sha256: artifact.sha256.clone().unwrap_or_default(),
+                url: artifact.url.clone().unwrap_or_default(),
+            })
+            .collect(),
+        diagnostics: response.diagnostics
+            .iter()
+            .map(|diagnostic| Diagnostic {
+                severity: match diagnostic.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                    Severity::Advisory => "advisory",
+                }.to_string(),
+                code: diagnostic.code.clone(),
+                message: diagnostic.message.clone(),
+                file: diagnostic.file.clone().unwrap_or_default(),
+                line: diagnostic.line.unwrap_or_default(),
+            })
+            .collect(),
+        job_id: response.job_id.clone().unwrap_or_default(),
+        cached: response.cached,
+        response_json: serde_json::to_string(response).unwrap_or_default(),
+    }
+}
+
+// Who is calling, as the Socket.IO handshake would have told
+struct Call {
+    // History and build events tell calls apart by it
+    owner: String,
+    subject: String,
+    // Clients authenticated with an API key or a JWT, metered and with a workspace of their own
+    metered: Option<String>,
+}
+
+// Let a call in, or refuse it like an event would be. `Status` is what every method fails with.
+#[allow(clippy::result_large_err)]
+fn admit<M>(request: &Request<M>, compiles: bool) -> Result<Call, Status> {
+    if shutdown::draining() {
+        return Err(Status::unavailable("Server shutting down"));
+    }
+    if admin::intake_paused() {
+        return Err(Status::unavailable("Server is not accepting new jobs"));
+    }
+    let identity = match authenticate(&Value::Null, &request.metadata().clone().into_headers()) {
+        Ok(identity) => identity,
+        Err(AuthError::Unauthorized(message)) => {
+            return Err(Status::unauthenticated(message));
+        }
+        Err(AuthError::GuestExpired) => {
+            return Err(Status::unauthenticated("Guest session expired"));
+        }
+    };
+    let ip = request.remote_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    let mut limited = ratelimit::check(Limit::Events, &ip).err();
+    if limited.is_none() && compiles {
+        limited = ratelimit::check(Limit::Compiles, &ip).err();
+    }
+    if let Some(wait) = limited {
+        return Err(Status::resource_exhausted(format!("Rate limited, retry in {} s", retry_after_secs(wait))));
+    }
+    let metered = matches!(identity.method, AuthMethod::ApiKey | AuthMethod::Jwt).then(|| identity.subject.clone());
+    Ok(Call { owner: format!("grpc:{}", uuid::Uuid::new_v4()), subject: identity.subject, metered })
+}
+
+// The result event of a job, recorded in the job history and sent to webhooks like a response
+fn finish(caller: &Call, mut response: CommandResponse) -> JobEvent {
+    if let Some(job) = current_job() {
+        history::record(&job, Some(&caller.subject), &caller.owner, &response);
+        webhooks::job_finished(&job, Some(&caller.subject), &response);
+        response.job_id.get_or_insert(job.id);
+    }
+    JobEvent { result: Some(result_of(&response)), ..Default::default() }
+}
+
+fn queued(events: &UnboundedSender<Result<JobEvent, Status>>) -> Box<dyn Fn(Value) + Send + Sync> {
+    let events = events.clone();
+    Box::new(move |update| {
+        let queued = QueueUpdate {
+            position: update["position"].as_u64().unwrap_or_default(),
+            eta_secs: update["eta_secs"].as_u64().unwrap_or_default(),
+            build_id: update["build_id"].as_str().unwrap_or_default().to_string(),
+        };
+        // A hung up caller's job is cancelled right after
+        events.send(Ok(JobEvent { queued: Some(queued), ..Default::default() })).ok();
+    })
+}
+
+fn output_lines(events: &UnboundedSender<Result<JobEvent, Status>>) -> output::Sink {
+    let events = events.clone();
+    std::sync::Arc::new(move |stream, text| {
+        let stream = match stream {
+            LogStream::Stdout => OutputStream::Stdout,
+            LogStream::Stderr => OutputStream::Stderr,
+        };
+        let line = OutputLine { stream: stream as i32, text: text.to_string() };
+        events.send(Ok(JobEvent { output: Some(line), ..Default::default() })).ok();
+    })
+}
+
+// Run the job `fut` of a streaming call, producing its result event; it is dropped, and the
+// processes it started killed, once the caller stops listening
+fn stream_job<F>(caller: Call, name: &'static str, fut: impl FnOnce(Call, Box<dyn Fn(Value) + Send + Sync>) -> F) -> Events
+    where F: Future<Output = JobEvent> + Send + 'static
+{
+    let (events, receiver) = mpsc::unbounded_channel();
+    let owner = caller.owner.clone();
+    let work = fut(caller, queued(&events));
+    let sink = Some(output_lines(&events));
+    tokio::spawn(job(owner, name, output::scope(sink, async move {
+        tokio::select! {
+            result = work => {
+                events.send(Ok(result)).ok();
+            }
+            _ = events.closed() => {}
+        }
+    })));
+    UnboundedReceiverStream::new(receiver)
+}
+
+fn invalid(errors: Vec<models::FieldError>) -> Status {
+    Status::invalid_argument(errors.into_iter().map(|error| error.message).collect::<Vec<_>>().join("; "))
+}
+
+// The sketch path of a compile in the caller's workspace, importing its archive first if it came with one
+async fn sketch_path(request: &CompileRequest, metered: Option<&str>) -> Result<String, Status> {
+    let workspace = client_workspace(metered);
+    let path = match request.archive.is_empty() {
+        true => request.sketch_path.clone(),
+        false => {
+            let (root, archive, subdir) = (workspace.clone(), request.archive.clone(), request.sketch_path.clone());
+            tokio::task
+                ::spawn_blocking(move || uploads::import(&root, &archive, given(&subdir)))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map_err(Status::invalid_argument)?.sketch_path
+        }
+    };
+    if path.is_empty() {
+        return Err(Status::invalid_argument("Missing sketch path"));
+    }
+    resolve_client_path(&workspace, &path)
+        .map(|path| path.to_string_lossy().to_string())
+        .map_err(Status::invalid_argument)
+}
+
+async fn compile(request: Request<CompileRequest>) -> Result<Response<Events>, Status> {
+    let caller = admit(&request, true)?;
+    let request = request.into_inner();
+    let sketch_path = sketch_path(&request, caller.metered.as_deref()).await?;
+    let mut data = json!({ "sketch_path": sketch_path, "merge": request.merge, "keep_build_dir": request.keep_build_dir });
+    for (field, value) in [
+        ("fqbn", &request.fqbn),
+        ("profile", &request.profile),
+        ("partitions_csv", &request.partitions_csv),
+        ("encrypt", &request.encrypt),
+        ("toolchain", &request.toolchain),
+        ("priority", &request.priority),
+    ] {
+        if let Some(value) = given(value) {
+            data[field] = value.into();
+        }
+    }
+    if !request.secrets.is_empty() {
+        data["secrets"] = json!(request.secrets);
+    }
+    let parsed = validation::parse::<models::CompileRequest>(&data).map_err(invalid)?;
+    let mut options = BuildOptions::validated(&data).map_err(Status::invalid_argument)?;
+    let mut args = compile_args(&mut options);
+    let toolchain = toolchain::requested(&data).map_err(Status::invalid_argument)?;
+    if let Some(subject) = &caller.metered && let Err(e) = usage::check_compile(subject) {
+        return Err(Status::resource_exhausted(e));
+    }
+    let ticket = queue::join(queue::priority_for(caller.metered.as_deref(), parsed.priority)).map_err(Status::resource_exhausted)?;
+
+    let events = stream_job(caller, "compile-sketch", |caller, queued| {
+        toolchain::scope(toolchain, async move {
+            let owner = Caller { owner: caller.owner.clone(), queued };
+            let built = compile_target(&owner, ticket, &data, &mut args, &options, caller.metered.as_deref(), None).await;
+            let response = built.unwrap_or_else(|response| response);
+            analytics::record_compile(options.fqbn.as_deref(), &response);
+            finish(&caller, response)
+        })
+    });
+    Ok(Response::new(events))
+}
+
+async fn upload(request: Request<UploadRequest>) -> Result<Response<Events>, Status> {
+    let caller = admit(&request, false)?;
+    let request = request.into_inner();
+    let mut data = json!({});
+    for (field, value) in [
+        ("sketch_path", &request.sketch_path),
+        ("fqbn", &request.fqbn),
+        ("port", &request.port),
+        ("address", &request.address),
+        ("ota_password", &request.ota_password),
+        ("build_id", &request.build_id),
+        ("toolchain", &request.toolchain),
+    ] {
+        if let Some(value) = given(value) {
+            data[field] = value.into();
+        }
+    }
+    let parsed = validation::parse::<models::UploadRequest>(&data).map_err(invalid)?;
+    let workspace = client_workspace(caller.metered.as_deref());
+    let sketch_path = resolve_client_path(&workspace, &parsed.sketch_path).map_err(Status::invalid_argument)?;
+    let upload = prepare_upload(parsed, sketch_path.to_string_lossy().to_string()).map_err(Status::invalid_argument)?;
+    let toolchain = toolchain::requested(&data).map_err(Status::invalid_argument)?;
+    if let Some(subject) = &caller.metered && let Err(e) = usage::check_upload(subject) {
+        return Err(Status::resource_exhausted(e));
+    }
+
+    let events = stream_job(caller, "upload-sketch", |caller, _| {
+        toolchain::scope(toolchain, async move {
+            let response = run_upload(upload, caller.metered.as_deref()).await;
+            finish(&caller, response)
+        })
+    });
+    Ok(Response::new(events))
+}
+
+async fn install_core_rpc(request: Request<InstallCoreRequest>) -> Result<Response<Events>, Status> {
+    let caller = admit(&request, false)?;
+    let request = request.into_inner();
+    let mut data = json!({ "core": request.core });
+    for (field, value) in [("toolchain", &request.toolchain), ("priority", &request.priority)] {
+        if let Some(value) = given(value) {
+            data[field] = value.into();
+        }
+    }
+    let parsed = validation::parse::<models::InstallCoreRequest>(&data).map_err(invalid)?;
+    let toolchain = toolchain::requested(&data).map_err(Status::invalid_argument)?;
+    let ticket = queue::join(queue::priority_for(caller.metered.as_deref(), parsed.priority)).map_err(Status::resource_exhausted)?;
+
+    let events = stream_job(caller, "install-core", |caller, queued| {
+        toolchain::scope(toolchain, async move {
+            let owner = Caller { owner: caller.owner.clone(), queued };
+            let response = install_core(&owner, ticket, &parsed.core).await;
+            finish(&caller, response)
+        })
+    });
+    Ok(Response::new(events))
+}
+
+async fn install_library(request: Request<InstallLibraryRequest>) -> Result<Response<Events>, Status> {
+    let caller = admit(&request, false)?;
+    let library = request.into_inner().library;
+    if library.is_empty() {
+        return Err(Status::invalid_argument("Missing library"));
+    }
+    let ticket = queue::join(queue::priority_for(caller.metered.as_deref(), None)).map_err(Status::resource_exhausted)?;
+
+    let events = stream_job(caller, "install-library", |caller, queued| async move {
+        let _slot = ticket.ready(|update| {
+            queued(json!({ "position": update.position, "eta_secs": update.eta_secs }));
+        }).await;
+        let command = ArduinoCommand { command: "lib".to_string(), args: vec!["install".to_string(), library] };
+        let response = output::live(run_arduino_command(&command)).await;
+        finish(&caller, response)
+    });
+    Ok(Response::new(events))
+}
+
+// Run a quick arduino-cli listing as a job of its own
+async fn listing<M>(request: Request<M>, name: &'static str, args: Vec<String>) -> Result<Response<CommandResult>, Status> {
+    let caller = admit(&request, false)?;
+    let mut args = args;
+    let command = ArduinoCommand { command: args.remove(0), args };
+    let owner = caller.owner.clone();
+    let result = job(owner, name, async move {
+        let response = run_arduino_command(&command).await;
+        finish(&caller, response)
+    }).await;
+    result.result.map(Response::new).ok_or_else(|| Status::internal("No result"))
+}
+
+fn words(words: &[&str]) -> Vec<String> {
+    words.iter().map(|word| word.to_string()).collect()
+}
+
+async fn search_libraries(request: Request<SearchLibrariesRequest>) -> Result<Response<CommandResult>, Status> {
+    let query = request.get_ref().query.clone();
+    if query.is_empty() {
+        return Err(Status::invalid_argument("Missing query"));
+    }
+    let mut args = words(&["lib", "search"]);
+    args.push(query);
+    args.extend(words(&["--format", "json"]));
+    listing(request, "search-libraries", args).await
+}
+
+async fn list_libraries(request: Request<ListLibrariesRequest>) -> Result<Response<CommandResult>, Status> {
+    listing(request, "list-libraries", words(&["lib", "list", "--format", "json"])).await
+}
+
+async fn list_cores(request: Request<ListCoresRequest>) -> Result<Response<CommandResult>, Status> {
+    listing(request, "list-cores", words(&["core", "list", "--format", "json"])).await
+}
+
+async fn list_boards(request: Request<ListBoardsRequest>) -> Result<Response<CommandResult>, Status> {
+    match request.get_ref().connected {
+        true => listing(request, "list-connected", words(&["board", "list", "--format", "json"])).await,
+        false => listing(request, "list-boards", words(&["board", "listall", "--format", "json"])).await,
+    }
+}
+
+// An async fn handling one method, as the service `tonic::server::Grpc` takes
+struct Method<F>(F);
+
+impl<F, Fut, M, R> Service<Request<M>> for Method<F>
+    where F: FnMut(Request<M>) -> Fut, Fut: Future<Output = Result<Response<R>, Status>>
+{
+    type Response = Response<R>;
+    type Error = Status;
+    type Future = Fut;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<M>) -> Fut {
+        (self.0)(request)
+    }
+}
+
+async fn unary<S, M, R>(method: S, request: http::Request<BoxBody>) -> http::Response<BoxBody>
+    where S: UnaryService<M, Response = R>, M: prost::Message + Default + Send + 'static, R: prost::Message + Send + 'static
+{
+    Grpc::new(ProstCodec::<R, M>::default()).unary(method, request).await
+}
+
+async fn streaming<S, M, R>(method: S, request: http::Request<BoxBody>) -> http::Response<BoxBody>
+    where
+        S: ServerStreamingService<M, Response = R>,
+        S::ResponseStream: Send + 'static,
+        M: prost::Message + Default + Send + 'static,
+        R: prost::Message + Send + 'static
+{
+    Grpc::new(ProstCodec::<R, M>::default()).server_streaming(method, request).await
+}
+
+#[derive(Clone)]
+pub struct CloudCompiler;
+
+impl NamedService for CloudCompiler {
+    const NAME: &'static str = SERVICE;
+}
+
+impl Service<http::Request<BoxBody>> for CloudCompiler {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        Box::pin(async move {
+            let method = request.uri().path().rsplit('/').next().unwrap_or_default().to_string();
+            let response = match method.as_str() {
+                "Compile" => streaming(Method(compile), request).await,
+                "Upload" => streaming(Method(upload), request).await,
+                "InstallCore" => streaming(Method(install_core_rpc), request).await,
+                "InstallLibrary" => streaming(Method(install_library), request).await,
+                "SearchLibraries" => unary(Method(search_libraries), request).await,
+                "ListLibraries" => unary(Method(list_libraries), request).await,
+                "ListCores" => unary(Method(list_cores), request).await,
+                "ListBoards" => unary(Method(list_boards), request).await,
+                _ => Status::unimplemented(format!("Unknown method {}", method)).into_http(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+// Serve the API on `bind:port` until the process stops
+pub async fn serve(bind: String, port: u16) {
+    let listener = match tokio::net::TcpListener::bind((bind.as_str(), port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("gRPC API not served, binding {}:{} failed: {}", bind, port, e);
+            return;
+        }
+    };
+    info!("Serving the gRPC API on {}:{}", bind, port);
+    let served = tonic::transport::Server
+        ::builder()
+        .add_service(CloudCompiler)
+        .serve_with_incoming(TcpListenerStream::new(listener)).await;
+    if let Err(e) = served {
+        warn!("gRPC API stopped: {}", e);
+    }
+}
+
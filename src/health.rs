@@ -0,0 +1,282 @@
+// What `/health` reports: whether arduino-cli answers and which version it is, the installed
+// cores, the free space where builds land and the worker pool's occupancy, so a monitor can tell
+// whether compiles would succeed rather than only that the process is up.
+//
+// `/readyz` turns the same facts into a verdict for load balancers and orchestrators: ready
+// while arduino-cli answers, the required cores are installed (CLOUD_COMPILER_READY_CORES, comma
+// separated `id[@version]`, default the provisioning manifest's cores), every directory has at
+// least CLOUD_COMPILER_READY_MIN_FREE_MB free (default 1024), the queue has room, intake isn't
+// paused and the server isn't shutting down. `/livez` only says the process serves requests.
+//
+// Those routes are public, so their answers leave out directories and error texts, which only
+// the admin report carries, and the arduino-cli probe behind them is shared for `PROBE_TTL`.
+use std::path::{ Path, PathBuf };
+use std::sync::LazyLock;
+use std::time::{ Duration, Instant };
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::admin;
+use crate::cache::cache_root;
+use crate::compiler::{ get_arduino_cli_path, query_arduino_cli_version, run_arduino_command, server_data_dir };
+use crate::files::workspace_root;
+use crate::models::ArduinoCommand;
+use crate::provision::{ self, ProvisioningReport };
+use crate::queue::{ self, QueueStats };
+use crate::shutdown;
+use crate::dispatch::{ self, Role };
+use crate::toolchain;
+
+const DEFAULT_MIN_FREE_MB: u64 = 1024;
+// How long one probe of arduino-cli and the installed cores answers for
+const PROBE_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct ArduinoCli {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct InstalledCore {
+    pub id: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct DiskSpace {
+    // What the directory holds: `workspaces`, `cache` or `data`
+    pub purpose: &'static str,
+    // Only in the admin report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct HealthReport {
+    // `ok`, `degraded` (provisioning drift, full queue) or `failing` (arduino-cli doesn't answer)
+    pub status: &'static str,
+    pub arduino_cli: ArduinoCli,
+    pub cores: Vec<InstalledCore>,
+    pub disk: Vec<DiskSpace>,
+    pub queue: QueueStats,
+    pub provisioning: Option<ProvisioningReport>,
+}
+
+// Version of the arduino-cli in use, the selected toolchain's own if it has one
+pub async fn arduino_cli_version() -> Result<String, String> {
+    let arduino_cli = toolchain::arduino_cli().unwrap_or_else(|| get_arduino_cli_path().clone());
+    query_arduino_cli_version(&arduino_cli).await
+}
+
+pub async fn installed_cores() -> Result<Vec<InstalledCore>, String> {
+    let command = ArduinoCommand {
+        command: "core".to_string(),
+        args: vec!["list".to_string(), "--format".to_string(), "json".to_string()],
+    };
+    let response = run_arduino_command(&command).await;
+    if !response.success {
+        return Err(response.error.unwrap_or_default().trim().to_string());
+    }
+    let list: serde_json::Value = serde_json::from_str(&response.output).map_err(|e| e.to_string())?;
+    Ok(
+        list["platforms"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|platform| {
+                Some(InstalledCore {
+                    id: platform["id"].as_str()?.to_string(),
+                    version: platform["installed_version"].as_str()?.to_string(),
+                })
+            })
+            .collect()
+    )
+}
+
+// Bytes available to the server on the filesystem holding `path`, from its nearest existing
+// ancestor since directories are created lazily
+pub fn available_bytes(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|dir| dir.exists())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+        let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // statvfs only writes into `stats`, which is read after it succeeded
+        let stats = unsafe {
+            if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+                return None;
+            }
+            stats.assume_init()
+        };
+        // The field types differ between platforms
+        #[allow(clippy::unnecessary_cast)]
+        let available = (stats.f_bavail as u64) * (stats.f_frsize as u64);
+        Some(available)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = existing;
+        None
+    }
+}
+
+pub fn disk_space() -> Vec<DiskSpace> {
+    [("workspaces", workspace_root()), ("cache", cache_root()), ("data", server_data_dir())]
+        .into_iter()
+        .map(|(purpose, path)| DiskSpace { purpose, available_bytes: available_bytes(&path), path: Some(path) })
+        .collect()
+}
+
+type Probe = (Result<String, String>, Result<Vec<InstalledCore>, String>);
+
+static PROBE: LazyLock<tokio::sync::Mutex<Option<(Instant, Probe)>>> = LazyLock::new(Default::default);
+
+// arduino-cli's version and the installed cores, probed again once `PROBE_TTL` passed. Callers
+// arriving during a probe wait for it rather than starting their own.
+async fn probe() -> Probe {
+    let mut cached = PROBE.lock().await;
+    if let Some((probed_at, probe)) = cached.as_ref() && probed_at.elapsed() < PROBE_TTL {
+        return probe.clone();
+    }
+    let probe = tokio::join!(arduino_cli_version(), installed_cores());
+    *cached = Some((Instant::now(), probe.clone()));
+    probe
+}
+
+// The full report, for admins
+pub async fn report() -> HealthReport {
+    let (version, cores) = probe().await;
+    let queue = queue::stats();
+    let provisioning = provision::report();
+    let status = match (&version, &provisioning) {
+        (Err(_), _) => "failing",
+        (_, Some(report)) if !report.in_sync => "degraded",
+        _ if queue.waiting >= queue.limit => "degraded",
+        _ => "ok",
+    };
+    let arduino_cli = match version {
+        Ok(version) => ArduinoCli { version: Some(version), error: None },
+        Err(error) => ArduinoCli { version: None, error: Some(error) },
+    };
+    HealthReport { status, arduino_cli, cores: cores.unwrap_or_default(), disk: disk_space(), queue, provisioning }
+}
+
+// The report without directories or error texts, which may hold paths, for anyone to read
+pub async fn public_report() -> HealthReport {
+    let mut report = report().await;
+    if report.arduino_cli.error.is_some() {
+        report.arduino_cli.error = Some("arduino-cli does not answer".to_string());
+    }
+    for disk in &mut report.disk {
+        disk.path = None;
+    }
+    if let Some(provisioning) = &mut report.provisioning {
+        provisioning.manifest = Path::new(&provisioning.manifest)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        provisioning.errors.clear();
+    }
+    report
+}
+
+// Outcome of one readiness condition
+#[derive(Serialize, Clone, ToSchema)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+fn check(name: &'static str, result: Result<(), String>) -> Check {
+    match result {
+        Ok(()) => Check { name, ok: true, detail: None },
+        Err(detail) => Check { name, ok: false, detail: Some(detail) },
+    }
+}
+
+fn required_cores() -> Vec<String> {
+    match std::env::var("CLOUD_COMPILER_READY_CORES").ok().filter(|cores| !cores.trim().is_empty()) {
+        Some(cores) => cores.split(',').map(|core| core.trim().to_string()).filter(|core| !core.is_empty()).collect(),
+        None => provision::required_cores(),
+    }
+}
+
+fn min_free_bytes() -> u64 {
+    std::env
+        ::var("CLOUD_COMPILER_READY_MIN_FREE_MB")
+        .ok()
+        .and_then(|mb| mb.trim().parse().ok())
+        .unwrap_or(DEFAULT_MIN_FREE_MB) * 1024 * 1024
+}
+
+pub async fn readiness() -> Readiness {
+    let (version, cores) = probe().await;
+
+    let arduino_cli = version.map(|_| ()).map_err(|_| "arduino-cli does not answer".to_string());
+    let required = required_cores();
+    let cores = cores.and_then(|cores| {
+        let installed: Vec<(String, String)> = cores.into_iter().map(|core| (core.id, core.version)).collect();
+        let missing: Vec<String> = provision
+            ::check(&required, &installed)
+            .into_iter()
+            .filter(|requirement| !requirement.satisfied)
+            .map(|requirement| requirement.spec)
+            .collect();
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Not installed: {}", missing.join(", "))),
+        }
+    });
+    let min_free = min_free_bytes();
+    let low: Vec<String> = disk_space()
+        .into_iter()
+        .filter(|disk| disk.available_bytes.is_some_and(|available| available < min_free))
+        .map(|disk| format!("{} ({} MB free)", disk.purpose, disk.available_bytes.unwrap_or(0) / 1024 / 1024))
+        .collect();
+    let disk = match low.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Low disk space: {}", low.join(", "))),
+    };
+    let stats = queue::stats();
+    let queue = match stats.waiting < stats.limit {
+        true => Ok(()),
+        false => Err(format!("Queue full, {} jobs waiting", stats.waiting)),
+    };
+    let draining = match shutdown::draining() {
+        true => Err("Shutting down".to_string()),
+        false => Ok(()),
+    };
+
+    let intake = match admin::intake_paused() {
+        true => Err("Intake paused by an operator".to_string()),
+        false => Ok(()),
+    };
+
+    let mut checks = vec![
+        check("arduino_cli", arduino_cli),
+        check("cores", cores),
+        check("disk", disk),
+        check("queue", queue),
+        check("intake", intake),
+        check("shutdown", draining)
+    ];
+    // A dispatcher compiles nothing itself
+    if dispatch::role() == Role::Dispatcher {
+        checks.push(check("workers", dispatch::available()));
+    }
+    Readiness { ready: checks.iter().all(|check| check.ok), checks }
+}
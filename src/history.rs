@@ -0,0 +1,352 @@
+// Job history. Every job that answers a client is recorded in an SQLite database,
+// CLOUD_COMPILER_HISTORY_DB (default `<data dir>/history.sqlite3`): who asked, which event and
+// board, how long it took, how it ended, a summary of its diagnostics and the build and
+// artifacts it produced. `GET /history` queries it, so "my compile failed yesterday" can be
+// looked up after the logs are gone.
+//
+// The flash and RAM use of successful compiles is kept per sketch (or project) and board, and
+// every compile is compared against the previous one: the response carries the delta and a
+// warning when flash or RAM grew by more than CLOUD_COMPILER_SIZE_REGRESSION_PERCENT (default 5,
+// 0 disables the warning), or the request's `size_threshold_percent`.
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use rusqlite::{ params, Connection, OptionalExtension, Row };
+use serde::{ Deserialize, Serialize };
+use utoipa::{ IntoParams, ToSchema };
+use tracing::{ info, warn };
+use crate::compiler::server_data_dir;
+use crate::models::{ Artifact, CommandResponse, Diagnostic, Severity, SizeDelta };
+use crate::resources::CurrentJob;
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+// Longest error kept per job, the full output stays out of the database
+const MAX_ERROR_CHARS: usize = 2000;
+const DEFAULT_REGRESSION_PERCENT: f64 = 5.0;
+
+const SCHEMA: &str =
+    "CREATE TABLE IF NOT EXISTS jobs (
+        id TEXT PRIMARY KEY,
+        subject TEXT,
+        socket_id TEXT NOT NULL,
+        event TEXT NOT NULL,
+        command TEXT NOT NULL,
+        fqbn TEXT,
+        started_at INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        success INTEGER NOT NULL,
+        error_code TEXT,
+        error TEXT,
+        errors INTEGER NOT NULL,
+        warnings INTEGER NOT NULL,
+        first_error TEXT,
+        build_id TEXT,
+        artifacts TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS jobs_subject ON jobs (subject, started_at);
+    CREATE INDEX IF NOT EXISTS jobs_started ON jobs (started_at);
+    CREATE TABLE IF NOT EXISTS build_sizes (
+        build_id TEXT PRIMARY KEY,
+        sketch TEXT NOT NULL,
+        fqbn TEXT NOT NULL,
+        flash_bytes INTEGER NOT NULL,
+        ram_bytes INTEGER,
+        recorded_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS build_sizes_sketch ON build_sizes (sketch, fqbn, recorded_at);";
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct JobRecord {
+    pub id: String,
+    pub subject: Option<String>,
+    pub socket_id: String,
+    pub event: String,
+    pub command: String,
+    pub fqbn: Option<String>,
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub success: bool,
+    pub error_code: Option<String>,
+    pub error: Option<String>,
+    pub errors: u32,
+    pub warnings: u32,
+    // Message of the first error diagnostic
+    pub first_error: Option<String>,
+    pub build_id: Option<String>,
+    pub artifacts: Vec<Artifact>,
+}
+
+// Filters of `GET /history`, all optional
+#[derive(Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    pub subject: Option<String>,
+    pub event: Option<String>,
+    pub fqbn: Option<String>,
+    pub success: Option<bool>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+fn database_path() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_HISTORY_DB")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| server_data_dir().join("history.sqlite3"))
+}
+
+fn open() -> Result<Connection, String> {
+    let path = database_path();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let connection = Connection::open(&path).map_err(|e| e.to_string())?;
+    connection.execute_batch(SCHEMA).map_err(|e| e.to_string())?;
+    info!("Job history in {}", path.display());
+    Ok(connection)
+}
+
+// None when the database can't be opened, jobs then go unrecorded
+static DATABASE: LazyLock<Option<Mutex<Connection>>> = LazyLock::new(|| {
+    match open() {
+        Ok(connection) => Some(Mutex::new(connection)),
+        Err(e) => {
+            warn!("Job history disabled, failed to open {}: {}", database_path().display(), e);
+            None
+        }
+    }
+});
+
+// Board a command ran for, from its `--fqbn`/`-b` argument
+fn fqbn(args: &[String]) -> Option<String> {
+    let position = args.iter().position(|arg| arg == "--fqbn" || arg == "-b")?;
+    args.get(position + 1).cloned()
+}
+
+fn record_of(job: &CurrentJob, subject: Option<&str>, socket_id: &str, response: &CommandResponse) -> JobRecord {
+    let count = |severity: Severity| response.diagnostics.iter().filter(|d| d.severity == severity).count() as u32;
+    JobRecord {
+        id: job.id.clone(),
+        subject: subject.map(String::from),
+        socket_id: socket_id.to_string(),
+        event: job.name.clone(),
+        command: response.command.clone(),
+        fqbn: fqbn(&response.args),
+        started_at: job.started_at,
+        duration_ms: job.started.elapsed().as_millis() as u64,
+        success: response.success,
+        error_code: response.error_code.map(|code| code.as_str().to_string()),
+        error: response.error.as_ref().filter(|_| !response.success).map(|error| error.chars().take(MAX_ERROR_CHARS).collect()),
+        errors: count(Severity::Error),
+        warnings: count(Severity::Warning),
+        first_error: response.diagnostics
+            .iter()
+            .find(|d| d.severity == Severity::Error)
+            .map(|d| d.message.clone()),
+        build_id: response.build_id.clone(),
+        artifacts: response.artifacts.clone(),
+    }
+}
+
+fn insert(connection: &Connection, record: &JobRecord) -> rusqlite::Result<usize> {
+    connection.execute(
+        "INSERT OR REPLACE INTO jobs (id, subject, socket_id, event, command, fqbn, started_at, duration_ms,
+            success, error_code, error, errors, warnings, first_error, build_id, artifacts)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        params![
+            record.id,
+            record.subject,
+            record.socket_id,
+            record.event,
+            record.command,
+            record.fqbn,
+            record.started_at,
+            record.duration_ms,
+            record.success,
+            record.error_code,
+            record.error,
+            record.errors,
+            record.warnings,
+            record.first_error,
+            record.build_id,
+            serde_json::to_string(&record.artifacts).unwrap_or_else(|_| "[]".to_string())
+        ]
+    )
+}
+
+// Record how `job` answered, in the background
+pub fn record(job: &CurrentJob, subject: Option<&str>, socket_id: &str, response: &CommandResponse) {
+    let record = record_of(job, subject, socket_id, response);
+    tokio::task::spawn_blocking(move || {
+        let Some(database) = DATABASE.as_ref() else {
+            return;
+        };
+        if let Err(e) = insert(&database.lock().unwrap(), &record) {
+            warn!("Failed to record job {}: {}", record.id, e);
+        }
+    });
+}
+
+fn from_row(row: &Row) -> rusqlite::Result<JobRecord> {
+    let artifacts: String = row.get("artifacts")?;
+    Ok(JobRecord {
+        id: row.get("id")?,
+        subject: row.get("subject")?,
+        socket_id: row.get("socket_id")?,
+        event: row.get("event")?,
+        command: row.get("command")?,
+        fqbn: row.get("fqbn")?,
+        started_at: row.get("started_at")?,
+        duration_ms: row.get("duration_ms")?,
+        success: row.get("success")?,
+        error_code: row.get("error_code")?,
+        error: row.get("error")?,
+        errors: row.get("errors")?,
+        warnings: row.get("warnings")?,
+        first_error: row.get("first_error")?,
+        build_id: row.get("build_id")?,
+        artifacts: serde_json::from_str(&artifacts).unwrap_or_default(),
+    })
+}
+
+// Jobs matching `query`, newest first
+pub fn query(query: &HistoryQuery) -> Result<Vec<JobRecord>, String> {
+    let database = DATABASE.as_ref().ok_or("Job history is not available")?;
+    let connection = database.lock().unwrap();
+    let mut statement = connection
+        .prepare(
+            "SELECT * FROM jobs
+            WHERE (?1 IS NULL OR subject = ?1)
+                AND (?2 IS NULL OR event = ?2)
+                AND (?3 IS NULL OR fqbn = ?3)
+                AND (?4 IS NULL OR success = ?4)
+                AND (?5 IS NULL OR started_at >= ?5)
+                AND (?6 IS NULL OR started_at < ?6)
+            ORDER BY started_at DESC, id
+            LIMIT ?7 OFFSET ?8"
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = statement
+        .query_map(
+            params![
+                query.subject,
+                query.event,
+                query.fqbn,
+                query.success,
+                query.since,
+                query.until,
+                query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+                query.offset.unwrap_or(0)
+            ],
+            from_row
+        )
+        .map_err(|e| e.to_string())?;
+    rows.collect::<rusqlite::Result<Vec<_>>>().map_err(|e| e.to_string())
+}
+
+pub fn get(id: &str) -> Result<Option<JobRecord>, String> {
+    let database = DATABASE.as_ref().ok_or("Job history is not available")?;
+    let connection = database.lock().unwrap();
+    connection
+        .query_row("SELECT * FROM jobs WHERE id = ?1", params![id], from_row)
+        .optional()
+        .map_err(|e| e.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn regression_percent(requested: Option<f64>) -> f64 {
+    requested
+        .or_else(|| std::env::var("CLOUD_COMPILER_SIZE_REGRESSION_PERCENT").ok().and_then(|v| v.trim().parse().ok()))
+        .unwrap_or(DEFAULT_REGRESSION_PERCENT)
+}
+
+fn percent(delta: i64, previous: u64) -> f64 {
+    match previous {
+        0 => 0.0,
+        previous => (delta as f64 * 10000.0 / previous as f64).round() / 100.0,
+    }
+}
+
+// Compare the size of a successful compile with the previous build of `sketch` (a project or
+// sketch folder) for `fqbn`, then record it as the new previous one. Sets `size_delta` and
+// warns about growth beyond the threshold, nothing happens without a size or a history.
+pub async fn track_size(sketch: &str, fqbn: &str, response: &mut CommandResponse, threshold: Option<f64>) {
+    let (Some(size), Some(build_id)) = (response.size, response.build_id.clone()) else {
+        return;
+    };
+    let (sketch, fqbn) = (sketch.to_string(), fqbn.to_string());
+    let previous = tokio::task
+        ::spawn_blocking(move || -> Result<Option<(String, u64, Option<u64>)>, String> {
+            let database = DATABASE.as_ref().ok_or("Job history is not available")?;
+            let connection = database.lock().unwrap();
+            let previous = connection
+                .query_row(
+                    "SELECT build_id, flash_bytes, ram_bytes FROM build_sizes
+                    WHERE sketch = ?1 AND fqbn = ?2 AND build_id != ?3
+                    ORDER BY recorded_at DESC, rowid DESC LIMIT 1",
+                    params![sketch, fqbn, build_id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                )
+                .optional()
+                .map_err(|e| e.to_string())?;
+            connection
+                .execute(
+                    "INSERT OR REPLACE INTO build_sizes (build_id, sketch, fqbn, flash_bytes, ram_bytes, recorded_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![build_id, sketch, fqbn, size.flash_bytes, size.ram_bytes, now()]
+                )
+                .map_err(|e| e.to_string())?;
+            Ok(previous)
+        }).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    let (previous_build_id, previous_flash, previous_ram) = match previous {
+        Ok(Some(previous)) => previous,
+        Ok(None) => {
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to compare build sizes: {}", e);
+            return;
+        }
+    };
+
+    let flash_delta = size.flash_bytes as i64 - previous_flash as i64;
+    let ram = size.ram_bytes.zip(previous_ram).map(|(ram, previous)| (ram as i64 - previous as i64, previous));
+    let threshold = regression_percent(threshold);
+    if threshold > 0.0 {
+        let mut grown = Vec::new();
+        if percent(flash_delta, previous_flash) > threshold {
+            grown.push(format!("flash by {} bytes ({:+}%)", flash_delta, percent(flash_delta, previous_flash)));
+        }
+        if let Some((delta, previous)) = ram && percent(delta, previous) > threshold {
+            grown.push(format!("RAM by {} bytes ({:+}%)", delta, percent(delta, previous)));
+        }
+        if !grown.is_empty() {
+            response.diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                code: "SIZE_REGRESSION".to_string(),
+                message: format!(
+                    "Size grew past the {}% threshold since build {}: {}",
+                    threshold,
+                    previous_build_id,
+                    grown.join(", ")
+                ),
+                file: None,
+                line: None,
+            });
+        }
+    }
+    response.size_delta = Some(SizeDelta {
+        previous_build_id,
+        flash_bytes: flash_delta,
+        flash_percent: percent(flash_delta, previous_flash),
+        ram_bytes: ram.map(|(delta, _)| delta),
+    });
+}
@@ -0,0 +1,1046 @@
+use std::net::SocketAddr;
+use axum::{
+    body::{ Body, Bytes },
+    extract::{ ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, Request },
+    http::{ header, HeaderMap, StatusCode },
+    middleware::{ self, Next },
+    response::{ sse::{ Event, KeepAlive, Sse }, Html, IntoResponse, Response },
+    routing::{ delete, get, post },
+    Json,
+    Router,
+};
+use futures::{ stream, StreamExt };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+use utoipa::{ IntoParams, Modify, OpenApi, ToSchema };
+use utoipa::openapi::security::{ Http, HttpAuthScheme, SecurityScheme };
+use crate::artifacts::{ artifact_path, build_dir, stream_build_dir };
+use crate::artifactstore;
+use crate::esptool::flash_layout;
+use crate::sessions::{ create_guest_session, GuestSessionInfo };
+use crate::resources::{ self, ResourceInfo };
+use crate::admin::{ self, Status };
+use crate::history::{ self, HistoryQuery, JobRecord };
+use crate::webhooks::{ self, Webhook, WebhookRequest };
+use crate::analytics::{ self, Report };
+use crate::auth::{ auth_required, authenticate, authenticate_request, AuthMethod };
+use crate::files::{ client_workspace, MAX_TOTAL_BYTES };
+use crate::uploads::{ import, UploadedSketch };
+use crate::usage::{ self, UsageReport };
+use crate::warmup::{ self, WarmResult };
+use crate::retention::{ self, RetentionStats, RunReport };
+use crate::toolchain;
+use crate::health::{ self, HealthReport, Readiness };
+use crate::ratelimit::{ self, client_ip, retry_after_secs, Limit };
+use crate::monitor::recording_log;
+use crate::output;
+use crate::models::LogLine;
+use crate::firmware;
+use crate::devices;
+use crate::dispatch::{ self, WorkerInfo };
+use crate::github::{ self, Delivery };
+use crate::shutdown;
+
+// ESP Web Tools manifest, see https://esphome.github.io/esp-web-tools/
+#[derive(Serialize, ToSchema)]
+pub struct WebToolsManifest {
+    pub name: String,
+    pub version: String,
+    pub new_install_prompt_erase: bool,
+    pub builds: Vec<WebToolsBuild>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebToolsBuild {
+    pub chip_family: String,
+    pub parts: Vec<WebToolsPart>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WebToolsPart {
+    pub path: String,
+    pub offset: u32,
+}
+
+// HTTP routes serving build artifacts
+pub fn routes() -> Router {
+    Router::new()
+        .route("/builds/{build_id}/manifest.json", get(get_manifest))
+        .route("/builds/{build_id}/artifacts/{name}", get(get_artifact))
+        .route("/builds/{build_id}/build.zip", get(get_build_zip))
+        .route("/artifacts/{sha256}/{name}", get(get_stored_artifact))
+        .route("/recordings/{recording_id}", get(get_recording))
+        .route("/firmware", get(get_assigned_firmware))
+        .route("/firmware/{channel}", get(get_channel_firmware))
+        .route("/guest-sessions", post(create_guest))
+        .route("/admin/status", get(get_status))
+        .route("/admin/health", get(get_admin_health))
+        .route("/admin/intake/pause", post(pause_intake))
+        .route("/admin/intake/resume", post(resume_intake))
+        .route("/admin/resources", get(list_resources))
+        .route("/admin/resources/{id}/release", post(release_resource))
+        .route("/admin/analytics", get(get_analytics))
+        .route("/admin/usage", get(list_usage))
+        .route("/admin/warmup", get(get_warmup).post(start_warmup))
+        .route("/admin/retention", get(get_retention).post(run_retention))
+        .route("/admin/workers", get(list_workers))
+        .route("/usage", get(get_usage))
+        .route("/history", get(list_history))
+        .route("/history/{job_id}", get(get_history))
+        .route("/jobs/{job_id}/logs", get(get_job_logs))
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/{id}", delete(delete_webhook))
+        .route("/github/webhook", post(github_webhook))
+        .route("/sketches", post(upload_sketch).layer(DefaultBodyLimit::max(UPLOAD_BODY_LIMIT)))
+        .route("/toolchains", get(list_toolchains))
+        // `/` used to answer a bare "alive", monitors probing it get the health report too
+        .route("/", get(get_health))
+        .route("/health", get(get_health))
+        .route("/livez", get(get_livez))
+        .route("/readyz", get(get_readyz))
+        .route("/openapi.json", get(get_openapi))
+        .route("/docs", get(get_docs))
+        .layer(middleware::from_fn(rate_limit))
+}
+
+// OpenAPI document of the routes above, generated from their annotations and the response models
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "HTTP routes of the compile server. Every route answers 429 with Retry-After past the per-IP rate limit."),
+    paths(
+        get_manifest,
+        get_artifact,
+        get_build_zip,
+        get_stored_artifact,
+        get_recording,
+        get_assigned_firmware,
+        get_channel_firmware,
+        create_guest,
+        get_status,
+        get_admin_health,
+        pause_intake,
+        resume_intake,
+        list_resources,
+        release_resource,
+        get_analytics,
+        list_usage,
+        get_warmup,
+        start_warmup,
+        get_retention,
+        run_retention,
+        list_workers,
+        get_usage,
+        list_history,
+        get_history,
+        get_job_logs,
+        list_webhooks,
+        create_webhook,
+        delete_webhook,
+        github_webhook,
+        upload_sketch,
+        list_toolchains,
+        get_health,
+        get_livez,
+        get_readyz
+    ),
+    modifiers(&Security),
+    tags(
+        (name = "builds", description = "Artifacts of compiles and web flasher manifests"),
+        (name = "firmware", description = "Over-the-air updates polled by devices"),
+        (name = "sketches", description = "Sketch uploads and toolchains"),
+        (name = "identity", description = "Guest sessions, usage, history and webhooks of the caller"),
+        (name = "admin", description = "Operator routes, disabled without CLOUD_COMPILER_ADMIN_TOKEN"),
+        (name = "health", description = "Health, liveness and readiness probes")
+    )
+)]
+struct ApiDoc;
+
+// The bearer credentials routes require: an API key or JWT of a client, or the admin token
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("identity", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+        components.add_security_scheme("admin", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+}
+
+async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// Swagger UI for the document, its assets loaded from a CDN so the binary doesn't carry them
+async fn get_docs() -> Html<&'static str> {
+    Html(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>arduino-esp32-cloud-compiler API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##
+    )
+}
+
+// Per-IP limit on every HTTP route, answered with 429 and Retry-After
+async fn rate_limit(request: Request, next: Next) -> Response {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    let ip = client_ip(request.headers(), peer).map(|ip| ip.to_string()).unwrap_or_default();
+    match ratelimit::check(Limit::Http, &ip) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = retry_after_secs(wait);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(serde_json::json!({ "error": "rate_limited", "retry_after": retry_after })),
+            ).into_response()
+        }
+    }
+}
+
+// Admin routes require `Authorization: Bearer $CLOUD_COMPILER_ADMIN_TOKEN` and are
+// disabled when no token is configured
+fn admin_authorized(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(admin::token_valid)
+}
+
+// Jobs, connected clients, queue and cache at a glance
+#[utoipa::path(
+    get, path = "/admin/status", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = Status), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn get_status(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(admin::status()).into_response()
+}
+
+// Stop or restart taking new events, running jobs carry on
+#[utoipa::path(
+    post, path = "/admin/intake/pause", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = Status), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn pause_intake(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    admin::set_intake_paused(true);
+    Json(admin::status()).into_response()
+}
+
+#[utoipa::path(
+    post, path = "/admin/intake/resume", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = Status), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn resume_intake(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    admin::set_intake_paused(false);
+    Json(admin::status()).into_response()
+}
+
+// Open serial ports, running processes, jobs and locked workspaces with their owners
+#[utoipa::path(
+    get, path = "/admin/resources", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = Vec<ResourceInfo>), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn list_resources(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(resources::list()).into_response()
+}
+
+// Force-release a resource and everything acquired inside it
+#[utoipa::path(
+    post, path = "/admin/resources/{id}/release", tag = "admin", security(("admin" = [])),
+    params(("id" = u64, Path, description = "Resource id")),
+    responses(
+        (status = 204, description = "Released"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "Unknown resource")
+    )
+)]
+async fn release_resource(headers: HeaderMap, Path(id): Path<u64>) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match resources::release(id) {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => not_found("Unknown resource"),
+    }
+}
+
+// Usage counts of the current period and the recent reports
+#[derive(Serialize, ToSchema)]
+struct Analytics {
+    current: Report,
+    reports: Vec<Report>,
+}
+
+#[utoipa::path(
+    get, path = "/admin/analytics", tag = "admin", security(("admin" = [])),
+    responses(
+        (status = 200, body = Analytics),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "Analytics are not enabled")
+    )
+)]
+async fn get_analytics(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    if !analytics::enabled() {
+        return not_found("Analytics are not enabled");
+    }
+    Json(Analytics {
+        current: analytics::current_report(),
+        reports: analytics::recent_reports(),
+    }).into_response()
+}
+
+// Usage and limits of the identity the request authenticates as
+#[utoipa::path(
+    get, path = "/usage", tag = "identity", security(("identity" = [])),
+    responses((status = 200, body = UsageReport), (status = 401, description = "Not authenticated"))
+)]
+async fn get_usage(headers: HeaderMap) -> Response {
+    match authenticate_request(&headers) {
+        Some(identity) => Json(usage::report(&identity.subject)).into_response(),
+        None => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+// Server health: arduino-cli, installed cores, disk space, queue and provisioning drift. Paths and
+// error texts are left to the admin report.
+#[utoipa::path(
+    get, path = "/health", tag = "health", responses((status = 200, body = HealthReport))
+)]
+async fn get_health() -> Json<HealthReport> {
+    Json(health::public_report().await)
+}
+
+// The health report with the directories behind the disk figures and the error texts
+#[utoipa::path(
+    get, path = "/admin/health", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = HealthReport), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn get_admin_health(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(health::report().await).into_response()
+}
+
+// Liveness: the process is up and serving requests
+#[utoipa::path(
+    get, path = "/livez", tag = "health", responses((status = 200, body = Object, example = json!({ "status": "ok" })))
+)]
+async fn get_livez() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// Readiness: 503 while this instance can't compile, so traffic goes elsewhere
+#[utoipa::path(
+    get, path = "/readyz", tag = "health",
+    responses((status = 200, body = Readiness), (status = 503, body = Readiness, description = "A check failed"))
+)]
+async fn get_readyz() -> Response {
+    let readiness = health::readiness().await;
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness)).into_response()
+}
+
+// Recorded jobs, newest first. Admins see everyone's and may filter by `subject`, other
+// identities only their own.
+#[utoipa::path(
+    get, path = "/history", tag = "identity", security(("identity" = []), ("admin" = [])), params(HistoryQuery),
+    responses(
+        (status = 200, body = Vec<JobRecord>),
+        (status = 401, description = "Not authenticated"),
+        (status = 503, description = "The history database is unavailable")
+    )
+)]
+async fn list_history(headers: HeaderMap, Query(mut query): Query<HistoryQuery>) -> Response {
+    if !admin_authorized(&headers) {
+        let Some(identity) = authenticate_request(&headers) else {
+            return StatusCode::UNAUTHORIZED.into_response();
+        };
+        query.subject = Some(identity.subject);
+    }
+    match history::query(&query) {
+        Ok(jobs) => Json(jobs).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get, path = "/history/{job_id}", tag = "identity", security(("identity" = []), ("admin" = [])),
+    params(("job_id" = String, Path, description = "`job_id` of the response")),
+    responses(
+        (status = 200, body = JobRecord),
+        (status = 401, description = "Not authenticated"),
+        (status = 404, description = "Unknown job"),
+        (status = 503, description = "The history database is unavailable")
+    )
+)]
+async fn get_history(headers: HeaderMap, Path(job_id): Path<String>) -> Response {
+    let admin = admin_authorized(&headers);
+    let subject = authenticate_request(&headers).map(|identity| identity.subject);
+    if !admin && subject.is_none() {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match history::get(&job_id) {
+        Ok(Some(job)) if admin || job.subject == subject => Json(job).into_response(),
+        Ok(_) => not_found("Unknown job"),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e).into_response(),
+    }
+}
+
+// Follow the output of a job as Server-Sent Events: an `output` event per line, the backlog first,
+// then `end` once the job finished. The job id, unguessable like a build id, is what grants access.
+#[utoipa::path(
+    get, path = "/jobs/{job_id}/logs", tag = "identity",
+    params(("job_id" = String, Path, description = "`job_id` of the job's events or response")),
+    responses(
+        (
+            status = 200,
+            description = "`output` events with a `LogLine` each, then an `end` event",
+            content_type = "text/event-stream",
+            body = LogLine,
+        ),
+        (status = 404, description = "Unknown job, or finished too long ago")
+    )
+)]
+async fn get_job_logs(Path(job_id): Path<String>) -> Response {
+    let Some((backlog, receiver)) = output::follow(&job_id) else {
+        return not_found("Unknown job");
+    };
+    let live = stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(line) => {
+                    return Some((line, Some(receiver)));
+                }
+                // A slow client skips what it missed rather than stalling the job
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => {
+                    return None;
+                }
+            }
+        }
+    });
+    let lines = stream::iter(backlog)
+        .chain(live)
+        .map(|line| Event::default().event("output").json_data(&line));
+    let end = stream::once(async { Ok(Event::default().event("end").data(job_id)) });
+    Sse::new(lines.chain(end)).keep_alive(KeepAlive::default()).into_response()
+}
+
+// Webhooks of the identity the request authenticates as
+#[utoipa::path(
+    get, path = "/webhooks", tag = "identity", security(("identity" = [])),
+    responses((status = 200, body = Vec<Webhook>), (status = 401, description = "Not authenticated"))
+)]
+async fn list_webhooks(headers: HeaderMap) -> Response {
+    match authenticate_request(&headers) {
+        Some(identity) => Json(webhooks::list(&identity.subject)).into_response(),
+        None => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+#[utoipa::path(
+    post, path = "/webhooks", tag = "identity", security(("identity" = [])), request_body = WebhookRequest,
+    responses(
+        (status = 201, body = Webhook),
+        (status = 400, description = "Invalid URL or events"),
+        (status = 401, description = "Not authenticated")
+    )
+)]
+async fn create_webhook(headers: HeaderMap, Json(request): Json<WebhookRequest>) -> Response {
+    let Some(identity) = authenticate_request(&headers) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match webhooks::register(&identity.subject, request).await {
+        Ok(webhook) => (StatusCode::CREATED, Json(webhook)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete, path = "/webhooks/{id}", tag = "identity", security(("identity" = [])),
+    params(("id" = String, Path, description = "Webhook id")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, description = "Not authenticated"),
+        (status = 404, description = "Unknown webhook")
+    )
+)]
+async fn delete_webhook(headers: HeaderMap, Path(id): Path<String>) -> Response {
+    let Some(identity) = authenticate_request(&headers) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match webhooks::delete(&identity.subject, &id) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => not_found("Unknown webhook"),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+// Deliveries of a GitHub repository webhook, building the pushed commit, see `github`
+#[utoipa::path(
+    post, path = "/github/webhook", tag = "builds",
+    params(
+        ("X-Hub-Signature-256" = String, Header, description = "HMAC-SHA256 of the body with the webhook secret"),
+        ("X-GitHub-Event" = String, Header, description = "`push` and `pull_request` build, other events are ignored")
+    ),
+    responses(
+        (status = 200, body = Delivery, description = "Nothing to build"),
+        (status = 202, body = Delivery, description = "Builds started"),
+        (status = 400, description = "Invalid payload or build configuration"),
+        (status = 401, description = "Missing or wrong signature"),
+        (status = 404, description = "No webhook secret is configured"),
+        (status = 503, description = "Shutting down or not accepting new jobs")
+    )
+)]
+async fn github_webhook(headers: HeaderMap, body: Bytes) -> Response {
+    let Some(secret) = github::secret() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if !github::verify(&secret, &body, header("X-Hub-Signature-256")) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    // GitHub shows the failed delivery, which can be redelivered later
+    if shutdown::draining() || admin::intake_paused() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    match github::receive(header("X-GitHub-Event"), header("X-GitHub-Delivery"), &body) {
+        Ok(delivery) if delivery.builds == 0 => Json(delivery).into_response(),
+        Ok(delivery) => (StatusCode::ACCEPTED, Json(delivery)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+// Largest `POST /sketches` body: the archive bound plus room for the form around it
+const UPLOAD_BODY_LIMIT: usize = MAX_TOTAL_BYTES as usize + 64 * 1024;
+
+// The `POST /sketches` form, for the OpenAPI document
+#[derive(ToSchema)]
+#[allow(dead_code)]
+struct SketchUpload {
+    #[schema(value_type = String, format = Binary)]
+    archive: Vec<u8>,
+    // Folder of the archive holding the sketch
+    subdir: Option<String>,
+}
+
+// Upload a sketch as a ZIP (multipart field `archive`, optional `subdir`) into the workspace of
+// the requesting identity
+#[utoipa::path(
+    post, path = "/sketches", tag = "sketches", security(("identity" = [])),
+    request_body(content = SketchUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, body = UploadedSketch),
+        (status = 400, description = "Malformed form or missing archive"),
+        (status = 401, description = "Not authenticated"),
+        (status = 422, description = "The archive holds no sketch or breaks the size limits")
+    )
+)]
+async fn upload_sketch(headers: HeaderMap, mut form: Multipart) -> Response {
+    let identity = match authenticate(&serde_json::Value::Null, &headers) {
+        Ok(identity) => identity,
+        Err(_) => {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    let subject = matches!(identity.method, AuthMethod::ApiKey | AuthMethod::Jwt).then_some(identity.subject);
+    let (mut archive, mut subdir) = (None, None);
+    loop {
+        let field = match form.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => {
+                break;
+            }
+            Err(e) => {
+                return (StatusCode::BAD_REQUEST, e.body_text()).into_response();
+            }
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let read = match name.as_str() {
+            "archive" => field.bytes().await.map(|bytes| archive = Some(bytes)),
+            "subdir" => field.text().await.map(|text| subdir = Some(text)),
+            _ => Ok(()),
+        };
+        if let Err(e) = read {
+            return (StatusCode::BAD_REQUEST, e.body_text()).into_response();
+        }
+    }
+    let Some(archive) = archive else {
+        return (StatusCode::BAD_REQUEST, "Missing archive field").into_response();
+    };
+    let workspace = client_workspace(subject.as_deref());
+    let imported = tokio::task::spawn_blocking(move || import(&workspace, &archive, subdir.as_deref())).await;
+    match imported {
+        Ok(Ok(uploaded)) => (StatusCode::CREATED, Json(uploaded)).into_response(),
+        Ok(Err(e)) => (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// Toolchains compile requests can select
+#[utoipa::path(
+    get, path = "/toolchains", tag = "sketches", responses((status = 200, body = Vec<String>))
+)]
+async fn list_toolchains() -> Json<Vec<String>> {
+    Json(toolchain::toolchains())
+}
+
+#[utoipa::path(
+    get, path = "/admin/usage", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = Vec<UsageReport>), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn list_usage(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(usage::all_reports()).into_response()
+}
+
+// Boards to warm, the configured list when the body names none
+#[derive(Serialize, Deserialize, ToSchema)]
+struct WarmupRequest {
+    #[serde(default)]
+    fqbns: Vec<String>,
+}
+
+// Latest warm-up compile per board
+#[utoipa::path(
+    get, path = "/admin/warmup", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = Vec<WarmResult>), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn get_warmup(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(warmup::results()).into_response()
+}
+
+// Start warm-up compiles in the background
+#[utoipa::path(
+    post, path = "/admin/warmup", tag = "admin", security(("admin" = [])), request_body(content = Option<WarmupRequest>),
+    responses(
+        (status = 202, body = WarmupRequest, description = "The boards being warmed"),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 404, description = "No boards to warm up")
+    )
+)]
+async fn start_warmup(headers: HeaderMap, request: Option<Json<WarmupRequest>>) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let fqbns = match request {
+        Some(Json(request)) if !request.fqbns.is_empty() => request.fqbns,
+        _ => warmup::configured_fqbns(),
+    };
+    if fqbns.is_empty() {
+        return not_found("No boards to warm up");
+    }
+    warmup::spawn_warmup(fqbns.clone());
+    (StatusCode::ACCEPTED, Json(WarmupRequest { fqbns })).into_response()
+}
+
+// Retention policy and what the reaper removed so far
+#[utoipa::path(
+    get, path = "/admin/retention", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = RetentionStats), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn get_retention(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(retention::stats()).into_response()
+}
+
+// Apply the retention policy now, answers once the run is done
+#[utoipa::path(
+    post, path = "/admin/retention", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = RunReport), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn run_retention(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match tokio::task::spawn_blocking(retention::run).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// Worker nodes registered with a dispatcher and the health they last reported, empty on other
+// servers
+#[utoipa::path(
+    get, path = "/admin/workers", tag = "admin", security(("admin" = [])),
+    responses((status = 200, body = Vec<WorkerInfo>), (status = 401, description = "Missing or wrong admin token"))
+)]
+async fn list_workers(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(dispatch::workers()).into_response()
+}
+
+// Issue a guest token for "try it now" pages, passed as `guest_token` in the handshake auth. Once
+// authentication is required only authenticated clients issue them, to hand on to visitors.
+#[utoipa::path(
+    post, path = "/guest-sessions", tag = "identity", security((), ("identity" = [])),
+    responses(
+        (status = 200, body = GuestSessionInfo),
+        (status = 401, description = "Not authenticated, and authentication is required"),
+        (status = 429, description = "Too many sessions started from this address"),
+        (status = 503, description = "Too many guest sessions are active")
+    )
+)]
+async fn create_guest(request: Request) -> Response {
+    let (parts, _) = request.into_parts();
+    let authenticated = authenticate_request(&parts.headers).is_some();
+    if auth_required() && !authenticated {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    // Anyone could otherwise renew their quota with a new session
+    if !authenticated {
+        let ip = ratelimit::request_ip(&parts).map(|ip| ip.to_string()).unwrap_or_default();
+        if let Err(wait) = ratelimit::check(Limit::Guests, &ip) {
+            let retry_after = retry_after_secs(wait);
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(serde_json::json!({ "error": "rate_limited", "retry_after": retry_after })),
+            ).into_response();
+        }
+    }
+    match create_guest_session() {
+        Ok(session) => Json(session).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e).into_response(),
+    }
+}
+
+// "esp32s3" -> "ESP32-S3"
+fn chip_family(chip: &str) -> String {
+    match chip.strip_prefix("esp32") {
+        Some("") => "ESP32".to_string(),
+        Some(variant) => format!("ESP32-{}", variant.to_uppercase()),
+        None => chip.to_uppercase(),
+    }
+}
+
+fn not_found(message: &str) -> Response {
+    (StatusCode::NOT_FOUND, message.to_string()).into_response()
+}
+
+#[utoipa::path(
+    get, path = "/builds/{build_id}/manifest.json", tag = "builds",
+    params(("build_id" = String, Path, description = "`build_id` of the compile response")),
+    responses((status = 200, body = WebToolsManifest), (status = 404, description = "Unknown build or no flash layout"))
+)]
+async fn get_manifest(Path(build_id): Path<String>) -> Response {
+    let Some(dir) = build_dir(&build_id) else {
+        return not_found("Unknown build");
+    };
+    let (chip, layout) = match flash_layout(&dir) {
+        Ok(layout) => layout,
+        Err(e) => {
+            return not_found(&e);
+        }
+    };
+
+    let mut parts = Vec::new();
+    for part in layout {
+        let Some(file_name) = part.path.file_name() else {
+            continue;
+        };
+        // Parts outside the build (boot_app0.bin from the core) are copied in so they can be served
+        let served = dir.join(file_name);
+        if part.path != served && std::fs::copy(&part.path, &served).is_err() {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to stage flash parts").into_response();
+        }
+        parts.push(WebToolsPart {
+            path: format!("artifacts/{}", file_name.to_string_lossy()),
+            offset: part.offset,
+        });
+    }
+
+    let name = parts
+        .last()
+        .map(|p| p.path.trim_start_matches("artifacts/").trim_end_matches(".ino.bin").to_string())
+        .unwrap_or_default();
+    let manifest = WebToolsManifest {
+        name,
+        version: build_id,
+        new_install_prompt_erase: true,
+        builds: vec![WebToolsBuild { chip_family: chip_family(chip), parts }],
+    };
+
+    // Web flashers are usually served from another origin
+    ([(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], Json(manifest)).into_response()
+}
+
+#[utoipa::path(
+    get, path = "/builds/{build_id}/artifacts/{name}", tag = "builds",
+    params(("build_id" = String, Path), ("name" = String, Path, description = "Artifact name")),
+    responses(
+        (status = 200, description = "The file", content_type = "application/octet-stream"),
+        (status = 404, description = "Unknown artifact")
+    )
+)]
+async fn get_artifact(Path((build_id, name)): Path<(String, String)>) -> Response {
+    let Some(path) = artifact_path(&build_id, &name) else {
+        return not_found("Unknown artifact");
+    };
+    match tokio::fs::read(&path).await {
+        Ok(bytes) =>
+            (
+                [
+                    (header::CONTENT_TYPE, "application/octet-stream"),
+                    (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+                ],
+                bytes,
+            ).into_response(),
+        Err(_) => not_found("Unknown artifact"),
+    }
+}
+
+// Signature of a local artifact store download URL
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SignedUrl {
+    expires: Option<u64>,
+    signature: Option<String>,
+}
+
+// Download an artifact from the local store with the signed URL a response handed out
+#[utoipa::path(
+    get, path = "/artifacts/{sha256}/{name}", tag = "builds",
+    params(("sha256" = String, Path), ("name" = String, Path), SignedUrl),
+    responses(
+        (status = 200, description = "The file", content_type = "application/octet-stream"),
+        (status = 403, description = "Missing, invalid or expired signature"),
+        (status = 404, description = "Unknown artifact")
+    )
+)]
+async fn get_stored_artifact(Path((sha256, name)): Path<(String, String)>, Query(query): Query<SignedUrl>) -> Response {
+    let (Some(expires), Some(signature)) = (query.expires, query.signature) else {
+        return (StatusCode::FORBIDDEN, "Signed URL required").into_response();
+    };
+    if !artifactstore::verify(&sha256, &name, expires, &signature) {
+        return (StatusCode::FORBIDDEN, "Invalid or expired URL").into_response();
+    }
+    let Some(path) = artifactstore::local_path(&sha256, &name) else {
+        return not_found("Unknown artifact");
+    };
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => {
+            return not_found("Unknown artifact");
+        }
+    };
+    let disposition = format!("attachment; filename=\"{}\"", name);
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ).into_response()
+}
+
+// Stream the whole build directory as a ZIP, for local post-analysis
+#[utoipa::path(
+    get, path = "/builds/{build_id}/build.zip", tag = "builds", params(("build_id" = String, Path)),
+    responses((status = 200, description = "The build directory", content_type = "application/zip"), (status = 404, description = "Unknown build"))
+)]
+async fn get_build_zip(Path(build_id): Path<String>) -> Response {
+    let Some(dir) = build_dir(&build_id) else {
+        return not_found("Unknown build");
+    };
+    // Entries are zipped as the client reads them, a few chunks ahead of it. A failure past the
+    // headers ends the body with an error, so the client sees a broken download rather than a
+    // truncated archive.
+    let (sender, receiver) = tokio::sync::mpsc::channel(ZIP_CHUNKS_AHEAD);
+    tokio::task::spawn_blocking(move || {
+        let out = std::io::BufWriter::with_capacity(ZIP_CHUNK_SIZE, ChunkWriter(sender.clone()));
+        // A closed channel is a client that went away, nothing to report
+        if let Err(e) = stream_build_dir(&dir, out) && !sender.is_closed() {
+            warn!("Failed to stream build {}: {}", dir.display(), e);
+            let _ = sender.blocking_send(Err(std::io::Error::other(e)));
+        }
+    });
+    let chunks = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    let disposition = format!("attachment; filename=\"{}.zip\"", build_id);
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()),
+        ],
+        Body::from_stream(chunks),
+    ).into_response()
+}
+
+const ZIP_CHUNK_SIZE: usize = 64 * 1024;
+const ZIP_CHUNKS_AHEAD: usize = 4;
+
+// Hands what is written to the body of a response, fails once the client is gone
+struct ChunkWriter(tokio::sync::mpsc::Sender<std::io::Result<Bytes>>);
+
+impl std::io::Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Download the log of a serial monitor recording
+#[utoipa::path(
+    get, path = "/recordings/{recording_id}", tag = "builds", params(("recording_id" = String, Path)),
+    responses((status = 200, description = "The recorded log", content_type = "text/plain"), (status = 404, description = "Unknown recording"))
+)]
+async fn get_recording(Path(recording_id): Path<String>) -> Response {
+    let Some(path) = recording_log(&recording_id) else {
+        return not_found("Unknown recording");
+    };
+    match tokio::fs::read(&path).await {
+        Ok(bytes) =>
+            (
+                [
+                    (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+                    (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+                ],
+                bytes,
+            ).into_response(),
+        Err(_) => not_found("Unknown recording"),
+    }
+}
+
+// Identification sent by polling devices, as query parameters for clients without HTTPUpdate
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FirmwareQuery {
+    version: Option<String>,
+    mac: Option<String>,
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// Answer an HTTPUpdate poll: 304 if the device runs the newest release, else the image
+async fn serve_firmware(channel: &str, query: &FirmwareQuery, headers: &HeaderMap) -> Response {
+    let Some(release) = firmware::latest_release(channel) else {
+        return not_found("No firmware published on this channel");
+    };
+    serve_release(release, query, headers).await
+}
+
+async fn serve_release(release: firmware::Release, query: &FirmwareQuery, headers: &HeaderMap) -> Response {
+    let version = query.version.as_deref().or_else(|| header_str(headers, "x-esp32-version"));
+    let md5 = header_str(headers, "x-esp32-sketch-md5");
+    if firmware::is_current(&release, version, md5) {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+
+    let Some(path) = firmware::release_image(&release) else {
+        return not_found("Firmware image missing");
+    };
+    match tokio::fs::read(&path).await {
+        Ok(bytes) =>
+            (
+                [
+                    (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                    (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.bin\"", release.version)),
+                    // HTTPUpdate verifies the download against this
+                    (header::HeaderName::from_static("x-md5"), release.md5.clone()),
+                    (header::HeaderName::from_static("x-firmware-version"), release.version.clone()),
+                ],
+                bytes,
+            ).into_response(),
+        Err(_) => not_found("Firmware image missing"),
+    }
+}
+
+// Devices poll this with their MAC, the channel comes from their assignment
+#[utoipa::path(
+    get, path = "/firmware", tag = "firmware", params(FirmwareQuery),
+    responses(
+        (status = 200, description = "The firmware image", content_type = "application/octet-stream"),
+        (status = 304, description = "The device runs the newest release"),
+        (status = 404, description = "No firmware published")
+    )
+)]
+async fn get_assigned_firmware(Query(query): Query<FirmwareQuery>, headers: HeaderMap) -> Response {
+    let mac = query.mac.as_deref().or_else(|| header_str(&headers, "x-esp32-sta-mac"));
+    // Devices of a group under rollout get its release once the rollout reaches them
+    if let Some(mac) = mac {
+        let version = query.version.as_deref().or_else(|| header_str(&headers, "x-esp32-version"));
+        devices::record_poll(mac, version);
+        match devices::rollout_decision(mac) {
+            Some(devices::Decision::Update(release)) => {
+                return serve_release(release, &query, &headers).await;
+            }
+            Some(devices::Decision::Hold) => {
+                return StatusCode::NOT_MODIFIED.into_response();
+            }
+            None => {}
+        }
+    }
+    let channel = mac.and_then(firmware::assigned_channel).unwrap_or_else(|| firmware::DEFAULT_CHANNEL.to_string());
+    serve_firmware(&channel, &query, &headers).await
+}
+
+#[utoipa::path(
+    get, path = "/firmware/{channel}", tag = "firmware", params(("channel" = String, Path), FirmwareQuery),
+    responses(
+        (status = 200, description = "The firmware image", content_type = "application/octet-stream"),
+        (status = 304, description = "The device runs the newest release"),
+        (status = 404, description = "No firmware published on this channel")
+    )
+)]
+async fn get_channel_firmware(
+    Path(channel): Path<String>,
+    Query(query): Query<FirmwareQuery>,
+    headers: HeaderMap
+) -> Response {
+    serve_firmware(&channel, &query, &headers).await
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use serde::de::{ Deserialize, Deserializer, MapAccess, Visitor };
+use tracing::info;
+use crate::compiler::server_data_dir;
+use crate::models::*;
+
+// Catalogs map the English message to its translation. `{}` in a message stands for a
+// dynamic part (a path, a name, an underlying error) that is carried over verbatim.
+struct Catalog {
+    exact: HashMap<String, String>,
+    // Entries with a `{}`, the most specific (longest fixed text) first and in file order
+    // otherwise, so "Invalid project name: {}" wins over "Invalid {}" whatever the file order
+    patterns: Vec<(String, String)>,
+}
+
+impl Catalog {
+    fn new(entries: Vec<(String, String)>) -> Catalog {
+        let (mut patterns, exact): (Vec<_>, Vec<_>) = entries.into_iter().partition(|(pattern, _)| pattern.contains("{}"));
+        patterns.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.len()));
+        Catalog { exact: exact.into_iter().collect(), patterns }
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.patterns.len()
+    }
+}
+
+// The entries of a catalog file in the order they are written
+struct Entries(Vec<(String, String)>);
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = Entries;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an object of messages")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Entries, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Entries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+static CATALOGS: LazyLock<HashMap<String, Catalog>> = LazyLock::new(load_catalogs);
+
+// Language a socket wants its messages in, stored in its extensions
+#[derive(Clone)]
+pub struct Locale(pub String);
+
+// Catalogs are `<lang>.json` files, e.g. `es.json` or `pt-BR.json`
+pub fn locales_dir() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_LOCALES_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| server_data_dir().join("locales"))
+}
+
+fn load_catalogs() -> HashMap<String, Catalog> {
+    let mut catalogs = HashMap::new();
+    let Ok(entries) = std::fs::read_dir(locales_dir()) else {
+        return catalogs;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Some(lang) = path.file_stem().map(|s| s.to_string_lossy().to_lowercase()) else {
+            continue;
+        };
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match std::fs::read_to_string(&path).map(|s| serde_json::from_str::<Entries>(&s)) {
+            Ok(Ok(Entries(entries))) => {
+                let catalog = Catalog::new(entries);
+                info!("Loaded {} messages for locale {}", catalog.len(), lang);
+                catalogs.insert(lang, catalog);
+            }
+            _ => info!("Ignoring invalid catalog {}", path.display()),
+        }
+    }
+    catalogs
+}
+
+// Load the catalogs now rather than on the first request
+pub fn init() {
+    LazyLock::force(&CATALOGS);
+}
+
+// Pick the first language of an Accept-Language style list that has a catalog
+pub fn negotiate_locale(requested: &str) -> Option<Locale> {
+    requested
+        .split(',')
+        .map(|part| part.split(';').next().unwrap_or_default().trim().to_lowercase())
+        .find_map(|lang| {
+            let base = lang.split('-').next().unwrap_or_default().to_string();
+            [lang, base].into_iter().find(|l| CATALOGS.contains_key(l))
+        })
+        .map(Locale)
+}
+
+// Match `message` against a catalog entry, substituting the `{}` parts into the translation
+fn apply(pattern: &str, translation: &str, message: &str) -> Option<String> {
+    let Some((prefix, suffix)) = pattern.split_once("{}") else {
+        return (pattern == message).then(|| translation.to_string());
+    };
+    if prefix.is_empty() && suffix.is_empty() {
+        return None;
+    }
+    let captured = message.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    Some(translation.replacen("{}", captured, 1))
+}
+
+pub fn translate(locale: &Locale, message: &str) -> String {
+    let Some(catalog) = CATALOGS.get(&locale.0) else {
+        return message.to_string();
+    };
+    if let Some(translation) = catalog.exact.get(message) {
+        return translation.clone();
+    }
+    catalog.patterns
+        .iter()
+        .find_map(|(pattern, translation)| apply(pattern, translation, message))
+        .unwrap_or_else(|| message.to_string())
+}
+
+// Translate the server-generated texts of a response
+pub fn localize_response(response: &mut CommandResponse, locale: &Locale) {
+    if let Some(error) = &response.error {
+        response.error = Some(translate(locale, error));
+    }
+    // The error of a rejected request joins the messages of its fields
+    if !response.invalid_fields.is_empty() {
+        let mut messages = Vec::new();
+        for field in &mut response.invalid_fields {
+            field.message = translate(locale, &field.message);
+            messages.push(field.message.clone());
+        }
+        response.error = Some(messages.join("; "));
+    }
+    for diagnostic in &mut response.diagnostics {
+        diagnostic.message = translate(locale, &diagnostic.message);
+    }
+    for explanation in &mut response.explanations {
+        explanation.title = translate(locale, &explanation.title);
+        explanation.explanation = translate(locale, &explanation.explanation);
+    }
+}
@@ -0,0 +1,99 @@
+// Keepalives for long operations. A core download or the link step prints nothing for minutes,
+// long enough for a client or a proxy to take the quiet connection for a dead one. A job run in
+// `scope` reports every CLOUD_COMPILER_KEEPALIVE_SECS (default 15, 0 disables) it went without
+// sending anything: the phase it is in and how long the job and the phase have been running.
+use std::future::Future;
+use std::sync::{ Arc, LazyLock, Mutex };
+use std::time::{ Duration, Instant };
+use serde::Serialize;
+use serde_json::Value;
+use crate::resources::{ current_job, current_request_id };
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+
+pub type Emit = Arc<dyn Fn(&Keepalive) + Send + Sync>;
+
+#[derive(Serialize)]
+pub struct Keepalive {
+    pub event: String,
+    pub job_id: Option<String>,
+    pub request_id: Option<Value>,
+    pub phase: String,
+    pub elapsed_secs: u64,
+    pub phase_elapsed_secs: u64,
+}
+
+struct Progress {
+    phase: String,
+    since: Instant,
+    // Last time the client heard of the job
+    active: Instant,
+}
+
+tokio::task_local! {
+    static PROGRESS: Arc<Mutex<Progress>>;
+}
+
+// None when disabled
+static INTERVAL: LazyLock<Option<Duration>> = LazyLock::new(|| {
+    match std::env::var("CLOUD_COMPILER_KEEPALIVE_SECS").ok().and_then(|secs| secs.trim().parse::<u64>().ok()) {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(DEFAULT_INTERVAL),
+    }
+});
+
+// Run `fut`, starting in `phase`, with keepalives going to `emit` while it is quiet
+pub async fn scope<F: Future>(emit: Emit, phase: &str, fut: F) -> F::Output {
+    let Some(interval) = *INTERVAL else {
+        return fut.await;
+    };
+    let started = Instant::now();
+    let progress = Arc::new(Mutex::new(Progress { phase: phase.to_string(), since: started, active: started }));
+    PROGRESS.scope(progress.clone(), async move {
+        let mut fut = std::pin::pin!(fut);
+        loop {
+            let quiet_until = progress.lock().unwrap().active + interval;
+            tokio::select! {
+                output = &mut fut => {
+                    return output;
+                }
+                _ = tokio::time::sleep_until(quiet_until.into()) => {}
+            }
+            let keepalive = {
+                let mut progress = progress.lock().unwrap();
+                if progress.active.elapsed() < interval {
+                    continue;
+                }
+                progress.active = Instant::now();
+                Keepalive {
+                    event: current_job().map(|job| job.name).unwrap_or_default(),
+                    job_id: current_job().map(|job| job.id),
+                    request_id: current_request_id(),
+                    phase: progress.phase.clone(),
+                    elapsed_secs: started.elapsed().as_secs(),
+                    phase_elapsed_secs: progress.since.elapsed().as_secs(),
+                }
+            };
+            emit(&keepalive);
+        }
+    }).await
+}
+
+// The current job moved on to `phase`
+pub fn phase(phase: &str) {
+    PROGRESS.try_with(|progress| {
+        let mut progress = progress.lock().unwrap();
+        if progress.phase != phase {
+            progress.phase = phase.to_string();
+            progress.since = Instant::now();
+        }
+    }).ok();
+}
+
+// The client heard of the current job, the next keepalive can wait
+pub fn activity() {
+    PROGRESS.try_with(|progress| {
+        progress.lock().unwrap().active = Instant::now();
+    }).ok();
+}
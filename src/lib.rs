@@ -2,3 +2,84 @@
 pub mod models;
 pub mod socketio;
 pub mod compiler;
+pub mod protocol;
+pub mod esptool;
+pub mod artifacts;
+pub mod zipstream;
+pub mod encryption;
+pub mod partitions;
+pub mod build;
+pub mod psram;
+pub mod http;
+pub mod files;
+pub mod filesystem;
+pub mod sessions;
+pub mod i18n;
+pub mod nvs;
+pub mod resources;
+pub mod signing;
+pub mod teaching;
+pub mod projects;
+pub mod discovery;
+pub mod monitor;
+pub mod firmware;
+pub mod power;
+pub mod devices;
+pub mod notifications;
+pub mod analytics;
+pub mod auth;
+pub mod usage;
+pub mod ratelimit;
+pub mod sandbox;
+pub mod queue;
+pub mod blobstore;
+pub mod cache;
+pub mod warmup;
+pub mod daemon;
+pub mod toolchain;
+pub mod bootstrap;
+pub mod provision;
+pub mod config;
+pub mod tls;
+pub mod cors;
+pub mod shutdown;
+pub mod telemetry;
+pub mod health;
+pub mod admin;
+pub mod status;
+pub mod history;
+pub mod webhooks;
+pub mod artifactstore;
+pub mod retention;
+pub mod git;
+pub mod uploads;
+pub mod transfers;
+pub mod examples;
+pub mod sketches;
+pub mod profiles;
+pub mod matrix;
+pub mod manifests;
+pub mod secrets;
+pub mod templates;
+pub mod patches;
+pub mod format;
+pub mod lint;
+pub mod analysis;
+pub mod check;
+pub mod compiledb;
+pub mod lsp;
+pub mod msgpack;
+pub mod validation;
+pub mod errors;
+pub mod output;
+pub mod keepalive;
+pub mod grpc;
+pub mod connection;
+pub mod ws;
+pub mod cluster;
+pub mod dispatch;
+pub mod worker;
+pub mod nats;
+pub mod github;
+#[cfg(feature = "client")]
+pub mod client;
@@ -0,0 +1,127 @@
+// Project checks with arduino-lint. `lint-project` checks the structure and metadata of a
+// sketch, library or platform, a folder of the workspace or files sent with the event, and
+// answers with arduino-lint's JSON report. Library authors get the Library Manager submission
+// checks with `library_manager: "submit"`.
+use std::path::PathBuf;
+use serde_json::Value;
+use crate::models::LintRequest;
+use crate::compiler::{ is_safe_name, run_program, server_data_dir };
+use crate::files::{ extract_zip_base64, write_files, TempTree };
+
+const PROJECT_TYPES: &[&str] = &["all", "sketch", "library", "platform"];
+const COMPLIANCE_LEVELS: &[&str] = &["permissive", "specification", "strict"];
+const LIBRARY_MANAGER_MODES: &[&str] = &["submit", "update", "false"];
+// Folder of sent projects without a `name`
+const DEFAULT_NAME: &str = "project";
+
+fn arduino_lint_binary() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_ARDUINO_LINT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("arduino-lint"))
+}
+
+fn choice(field: &str, value: Option<&str>, allowed: &[&str]) -> Result<Option<String>, String> {
+    match value {
+        Some(value) if !allowed.contains(&value) => Err(format!("{} must be one of {}", field, allowed.join(", "))),
+        value => Ok(value.map(String::from)),
+    }
+}
+
+// Write sent files or a ZIP into a temporary folder named after the project. An archive of a
+// single folder, like a GitHub download, is linted as that folder.
+fn write_project(request: &LintRequest) -> Result<(TempTree, PathBuf), String> {
+    let name = request.name.as_deref().unwrap_or(DEFAULT_NAME);
+    if !is_safe_name(name) {
+        return Err(format!("Invalid project name: {}", name));
+    }
+    let dir = server_data_dir().join("lint").join(uuid::Uuid::new_v4().to_string());
+    let tree = TempTree { tree: dir.join(name), dir };
+    std::fs::create_dir_all(&tree.tree).map_err(|e| e.to_string())?;
+    write_files(&tree.tree, &request.files)?;
+    if let Some(zip) = &request.zip {
+        extract_zip_base64(&tree.tree, zip)?;
+    }
+
+    let entries: Vec<PathBuf> = std::fs
+        ::read_dir(&tree.tree)
+        .map_err(|e| e.to_string())?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .collect();
+    let project = match entries.as_slice() {
+        [] => {
+            return Err("No project files sent".to_string());
+        }
+        [only] if only.is_dir() => only.clone(),
+        _ => tree.tree.clone(),
+    };
+    Ok((tree, project))
+}
+
+// Paths below `prefix` in the report, also inside messages, become relative to it. Temporary
+// and workspace folders mean nothing to the client.
+fn relativize(value: &mut Value, prefix: &str) {
+    match value {
+        Value::String(text) if text.contains(prefix) => {
+            *text = text.replace(prefix, "");
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| relativize(item, prefix)),
+        Value::Object(fields) => fields.values_mut().for_each(|field| relativize(field, prefix)),
+        _ => {}
+    }
+}
+
+// Run arduino-lint over a workspace folder (`sketch_path`, already resolved) or the sent files
+pub async fn lint_project(request: &LintRequest) -> Result<Value, String> {
+    let project_type = choice("project_type", request.project_type.as_deref(), PROJECT_TYPES)?;
+    let compliance = choice("compliance", request.compliance.as_deref(), COMPLIANCE_LEVELS)?;
+    let library_manager = choice("library_manager", request.library_manager.as_deref(), LIBRARY_MANAGER_MODES)?;
+
+    let sent = !request.files.is_empty() || request.zip.is_some();
+    let (_tree, project) = match (&request.sketch_path, sent) {
+        (Some(_), true) => {
+            return Err("Send either sketch_path or files".to_string());
+        }
+        (Some(path), false) => {
+            let path = PathBuf::from(path);
+            if !path.is_dir() {
+                return Err("sketch_path must be a folder".to_string());
+            }
+            (None, path)
+        }
+        (None, true) => {
+            let (tree, project) = write_project(request)?;
+            (Some(tree), project)
+        }
+        (None, false) => {
+            return Err("Missing sketch_path or files".to_string());
+        }
+    };
+
+    let mut args = vec!["--format".to_string(), "json".to_string()];
+    for (flag, value) in [
+        ("--project-type", project_type),
+        ("--compliance", compliance),
+        ("--library-manager", library_manager),
+    ] {
+        if let Some(value) = value {
+            args.push(flag.to_string());
+            args.push(value);
+        }
+    }
+    if request.recursive {
+        args.push("--recursive".to_string());
+    }
+    args.push(project.to_string_lossy().to_string());
+
+    // Failed checks exit non-zero with a full report, only a missing report is an error
+    let response = run_program(&arduino_lint_binary(), "arduino-lint", &args).await;
+    let mut report: Value = serde_json::from_str(&response.output).map_err(|_| {
+        let error = response.error.clone().unwrap_or_default();
+        format!("arduino-lint failed: {}", error.trim())
+    })?;
+    if let Some(parent) = project.parent() {
+        relativize(&mut report, &format!("{}{}", parent.display(), std::path::MAIN_SEPARATOR));
+    }
+    Ok(report)
+}
@@ -0,0 +1,386 @@
+// clangd language servers for browser IDEs, opted in with `CLOUD_COMPILER_LSP`. A client of the
+// `/lsp` namespace starts a server for a sketch and board: arduino-cli writes the sketch's
+// compilation database, clangd runs over the client's workspace with it, and LSP messages travel
+// as `lsp-message` events both ways. Paths in messages are rewritten between the server's folders
+// and the client's, as in `compilation-database`.
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::process::Stdio;
+use std::sync::{ Arc, LazyLock, Mutex };
+use serde_json::Value;
+use tokio::io::{ AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader };
+use tokio::process::{ Child, ChildStdin, ChildStdout, Command as TokioCommand };
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::compiledb::{ client_mappings, ClientPaths };
+use crate::compiler::{
+    apply_limits,
+    arduino_data_dir,
+    arduino_user_dir,
+    compilation_database,
+    hide_console,
+    server_data_dir,
+    sketch_dir,
+    COMPILATION_DATABASE,
+};
+use crate::files::{ self, TempTree };
+use crate::resources::{ acquire, release, ResourceGuard, ResourceKind };
+
+const DEFAULT_MAX_SESSIONS: usize = 8;
+// Largest file `lsp-read-file` answers with
+const MAX_READ_BYTES: u64 = 4 * 1024 * 1024;
+// Largest message taken from clangd
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+// A running server: messages for clangd, its process resource, and what the client may read
+struct Session {
+    sender: mpsc::UnboundedSender<Value>,
+    resource: u64,
+    paths: Arc<PathMap>,
+}
+
+// Servers by owner socket, `None` while one starts
+static SESSIONS: LazyLock<Mutex<HashMap<String, Option<Session>>>> = LazyLock::new(Default::default);
+
+pub fn enabled() -> bool {
+    std::env::var("CLOUD_COMPILER_LSP").is_ok_and(|v| v == "1" || v == "true")
+}
+
+fn clangd_binary() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_CLANGD")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("clangd"))
+}
+
+fn max_sessions() -> usize {
+    std::env
+        ::var("CLOUD_COMPILER_LSP_MAX_SESSIONS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_SESSIONS)
+}
+
+fn file_uri(path: &str) -> String {
+    match path.starts_with('/') {
+        true => format!("file://{}", path),
+        false => format!("file:///{}", path),
+    }
+}
+
+// Prefix pairs rewriting paths and file URIs in either direction, with the server folders a
+// client may read through `lsp-read-file`
+struct PathMap {
+    to_client: Vec<(String, String)>,
+    to_server: Vec<(String, String)>,
+    readable: Vec<PathBuf>,
+}
+
+impl PathMap {
+    fn new(mappings: Vec<(PathBuf, String)>) -> Self {
+        let mut map = PathMap { to_client: Vec::new(), to_server: Vec::new(), readable: Vec::new() };
+        for (server, client) in &mappings {
+            let server = server.to_string_lossy().to_string();
+            map.to_client.push((file_uri(&server), file_uri(client)));
+            map.to_client.push((server.clone(), client.clone()));
+            map.to_server.push((file_uri(client), file_uri(&server)));
+            map.to_server.push((client.clone(), server));
+        }
+        map.readable = mappings
+            .into_iter()
+            .map(|(server, _)| server)
+            .chain([arduino_data_dir(), arduino_user_dir()])
+            .filter_map(|dir| files::canonical(&dir).ok())
+            .collect();
+        map
+    }
+}
+
+// `text` with the first matching prefix replaced, for whole paths and URIs only
+fn swap(text: &str, pairs: &[(String, String)]) -> Option<String> {
+    pairs.iter().find_map(|(from, to)| {
+        let rest = text.strip_prefix(from.as_str())?;
+        (rest.is_empty() || rest.starts_with(['/', '\\'])).then(|| format!("{}{}", to, rest))
+    })
+}
+
+fn decode_percent(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// Rewrite paths and URIs in strings and object keys. From the client, file URIs must lie in a
+// mapped folder, without `..`, so clangd opens nothing else on the server.
+fn rewrite(value: &mut Value, pairs: &[(String, String)], from_client: bool) -> Result<(), String> {
+    let rewritten = |text: &str| -> Result<Option<String>, String> {
+        let swapped = swap(text, pairs);
+        if from_client && text.starts_with("file://") {
+            let escapes = decode_percent(text).split(['/', '\\']).any(|segment| segment == "..");
+            if swapped.is_none() || escapes {
+                return Err(format!("{} is outside the workspace", text));
+            }
+        }
+        Ok(swapped)
+    };
+    match value {
+        Value::String(text) => {
+            if let Some(swapped) = rewritten(text)? {
+                *text = swapped;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rewrite(item, pairs, from_client)?;
+            }
+        }
+        Value::Object(fields) => {
+            let mut rewritten_fields = serde_json::Map::new();
+            for (key, mut field) in std::mem::take(fields) {
+                rewrite(&mut field, pairs, from_client)?;
+                rewritten_fields.insert(rewritten(&key)?.unwrap_or(key), field);
+            }
+            *fields = rewritten_fields;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// The sketch's .ino files have no entry, arduino-cli compiles them concatenated as
+// `<name>.ino.cpp`. Each gets the flags of that entry, as C++ with Arduino.h included.
+fn add_ino_entries(build_dir: &Path, sketch: &Path) -> Result<(), String> {
+    let path = build_dir.join(COMPILATION_DATABASE);
+    let mut database: Vec<Value> = std::fs
+        ::read(&path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or("arduino-cli wrote no compilation database")?;
+    let generated = database
+        .iter()
+        .find(|entry| entry["file"].as_str().is_some_and(|file| file.ends_with(".ino.cpp")))
+        .cloned();
+    let Some(generated) = generated else {
+        return Ok(());
+    };
+    let generated_file = generated["file"].as_str().unwrap_or_default().to_string();
+    let inos: Vec<PathBuf> = std::fs
+        ::read_dir(sketch)
+        .map_err(|e| e.to_string())?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "ino" || extension == "pde"))
+        .collect();
+    for ino in inos {
+        let ino = ino.to_string_lossy().to_string();
+        let mut entry = generated.clone();
+        if let Some(arguments) = entry["arguments"].as_array_mut() {
+            let source = ["-x", "c++", "-include", "Arduino.h", &ino].map(Value::from);
+            *arguments = arguments
+                .drain(..)
+                .flat_map(|argument| match argument.as_str() == Some(&generated_file) {
+                    true => source.to_vec(),
+                    false => vec![argument],
+                })
+                .collect();
+        }
+        entry["file"] = ino.into();
+        database.push(entry);
+    }
+    let bytes = serde_json::to_vec(&database).map_err(|e| e.to_string())?;
+    std::fs::write(&path, bytes).map_err(|e| e.to_string())
+}
+
+async fn read_message(stdout: &mut BufReader<ChildStdout>) -> Result<Option<Value>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if stdout.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') && name.eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.filter(|length| *length <= MAX_MESSAGE_BYTES).ok_or("clangd sent an oversized message")?;
+    let mut body = vec![0u8; length];
+    stdout.read_exact(&mut body).await.map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| format!("clangd sent invalid JSON: {}", e))
+}
+
+async fn write_message(stdin: &mut ChildStdin, message: &Value) -> std::io::Result<()> {
+    let body = serde_json::to_vec(message)?;
+    stdin.write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes()).await?;
+    stdin.write_all(&body).await?;
+    stdin.flush().await
+}
+
+// Owner's entry in `SESSIONS`, removed when the server ends or fails to start
+struct Registration(String);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        SESSIONS.lock().unwrap().remove(&self.0);
+    }
+}
+
+// A started clangd, the process is killed and the build folder removed when dropped
+pub struct Server {
+    stdout: BufReader<ChildStdout>,
+    writer: JoinHandle<()>,
+    paths: Arc<PathMap>,
+    process: ResourceGuard,
+    _child: Child,
+    _build: TempTree,
+    _registration: Registration,
+}
+
+// Start clangd for `owner` over `sketch` on `fqbn`, in the workspace folder `workspace`
+pub async fn open(owner: &str, fqbn: &str, sketch: &str, workspace: &Path, client: &ClientPaths) -> Result<Server, String> {
+    {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if sessions.contains_key(owner) {
+            return Err("A language server is already running, stop it first".to_string());
+        }
+        if sessions.len() >= max_sessions() {
+            return Err("Too many language servers running, try again later".to_string());
+        }
+        sessions.insert(owner.to_string(), None);
+    }
+    let registration = Registration(owner.to_string());
+
+    let dir = server_data_dir().join("lsp").join(uuid::Uuid::new_v4().to_string());
+    let build = TempTree { tree: dir.join("build"), dir };
+    std::fs::create_dir_all(&build.tree).map_err(|e| e.to_string())?;
+    let paths = Arc::new(PathMap::new(client_mappings(sketch, workspace, &build.tree, client)?));
+    let response = compilation_database(fqbn, sketch, &build.tree).await;
+    if !response.success {
+        return Err(response.error.unwrap_or_default().trim().to_string());
+    }
+    add_ino_entries(&build.tree, sketch_dir(Path::new(sketch)))?;
+
+    let args = vec![
+        format!("--compile-commands-dir={}", build.tree.display()),
+        // System headers come from the board's gcc, only the installed toolchains are run
+        format!("--query-driver={}", arduino_data_dir().join("packages").join("**").display()),
+        // `.clangd` files in the workspace could point clangd anywhere
+        "--enable-config=false".to_string(),
+        "--background-index=false".to_string(),
+        "--header-insertion=never".to_string(),
+        "--pch-storage=memory".to_string(),
+        "--log=error".to_string(),
+    ];
+    let process = acquire(ResourceKind::Process, format!("clangd {}", sketch));
+    let mut command = TokioCommand::new(clangd_binary());
+    command
+        .args(&args)
+        .current_dir(sketch_dir(Path::new(sketch)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+    apply_limits(&mut command);
+    hide_console(&mut command);
+    let mut child = command.spawn().map_err(|e| format!("Failed to start clangd: {}", e))?;
+    let mut stdin = child.stdin.take().ok_or("clangd has no input")?;
+    let stdout = BufReader::new(child.stdout.take().ok_or("clangd has no output")?);
+
+    // Writes go through their own task, reads are cancelled mid-message when the server stops
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            if write_message(&mut stdin, &message).await.is_err() {
+                break;
+            }
+        }
+    });
+    SESSIONS.lock()
+        .unwrap()
+        .insert(owner.to_string(), Some(Session { sender, resource: process.id(), paths: paths.clone() }));
+    Ok(Server { stdout, writer, paths, process, _child: child, _build: build, _registration: registration })
+}
+
+// The workspace root as the client sees it, for `rootUri`
+pub fn root_uri(server: &Server, workspace: &Path) -> Option<String> {
+    let workspace = files::canonical(workspace).ok()?;
+    swap(&file_uri(&workspace.to_string_lossy()), &server.paths.to_client)
+}
+
+// Pass clangd's messages to `emit` until it exits or is stopped
+pub async fn serve(mut server: Server, mut emit: impl FnMut(Value)) -> Result<(), String> {
+    let result = loop {
+        let message = tokio::select! {
+            message = read_message(&mut server.stdout) => message,
+            _ = server.process.cancelled() => break Ok(()),
+        };
+        match message {
+            Ok(Some(mut message)) => {
+                rewrite(&mut message, &server.paths.to_client, false)?;
+                emit(message);
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    server.writer.abort();
+    result
+}
+
+// Forward a client message to the owner's clangd
+pub fn send(owner: &str, mut message: Value) -> Result<(), String> {
+    let sessions = SESSIONS.lock().unwrap();
+    let Some(Some(session)) = sessions.get(owner) else {
+        return Err("No language server running".to_string());
+    };
+    rewrite(&mut message, &session.paths.to_server, true)?;
+    session.sender.send(message).map_err(|_| "Language server stopped".to_string())
+}
+
+pub fn stop(owner: &str) -> Result<(), String> {
+    let resource = match SESSIONS.lock().unwrap().get(owner) {
+        Some(Some(session)) => session.resource,
+        _ => {
+            return Err("No language server running".to_string());
+        }
+    };
+    release(resource);
+    Ok(())
+}
+
+// A file a definition or reference points to, sketch, build, core or library, by client URI
+pub fn read_file(owner: &str, uri: &str) -> Result<String, String> {
+    let paths = match SESSIONS.lock().unwrap().get(owner) {
+        Some(Some(session)) => session.paths.clone(),
+        _ => {
+            return Err("No language server running".to_string());
+        }
+    };
+    let server_uri = swap(uri, &paths.to_server).unwrap_or_else(|| uri.to_string());
+    let path = decode_percent(server_uri.strip_prefix("file://").unwrap_or(&server_uri));
+    let path = files::canonical(&PathBuf::from(path)).map_err(|_| format!("No such file: {}", uri))?;
+    if !paths.readable.iter().any(|dir| path.starts_with(dir)) {
+        return Err(format!("{} is outside the workspace", uri));
+    }
+    let metadata = std::fs::metadata(&path).map_err(|e| e.to_string())?;
+    if !metadata.is_file() || metadata.len() > MAX_READ_BYTES {
+        return Err(format!("{} is not a readable file", uri));
+    }
+    std::fs::read_to_string(&path).map_err(|_| format!("{} is not text", uri))
+}
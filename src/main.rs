@@ -1,13 +1,35 @@
+use axum::middleware;
 use axum::routing::get;
-use socketioxide::SocketIo;
+use futures::FutureExt;
+use socketioxide::{ ParserConfig, SocketIo };
+use socketioxide::adapter::{ Adapter, LocalAdapter };
+use socketioxide::extract::SocketRef;
+use socketioxide_core::adapter::{ DefinedAdapter, Spawnable };
 use tracing::info;
 use tracing_subscriber::FmtSubscriber;
-use arduino_esp32_cloud_compiler::socketio::on_connect;
-use arduino_esp32_cloud_compiler::compiler::{ get_arduino_cli_path, health_check };
+use arduino_esp32_cloud_compiler::socketio::{ on_connect, on_lsp_connect, on_msgpack_connect, on_msgpack_lsp_connect };
+use arduino_esp32_cloud_compiler::compiler::health_check;
+use arduino_esp32_cloud_compiler::cluster::ClusterAdapter;
+use arduino_esp32_cloud_compiler::connection::Connection;
+use arduino_esp32_cloud_compiler::{ admin, analytics, auth, bootstrap, build, cluster, config, cors, dispatch, grpc, http, i18n, lsp, msgpack, nats, notifications, provision, retention, sessions, shutdown, status, telemetry, tls, usage, warmup, worker, ws };
+use arduino_esp32_cloud_compiler::dispatch::Role;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing::subscriber::set_global_default(FmtSubscriber::default())?;
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Settings land in the environment before the runtime starts any thread, logged to stdout
+    // until the real subscriber is installed
+    let listen = tracing::subscriber::with_default(FmtSubscriber::default(), config::load)?;
+    tokio::runtime::Runtime::new()?.block_on(serve(listen))
+}
+
+async fn serve((bind, port): (String, u16)) -> Result<(), Box<dyn std::error::Error>> {
+    // Logs, and trace export if an OTLP endpoint is configured
+    telemetry::init()?;
+    let tls = tls::configured()?;
+    // Install arduino-cli first when the binary ships without one
+    if let Err(e) = bootstrap::ensure_arduino_cli().await {
+        info!("arduino-cli bootstrap failed: {}", e);
+        std::process::exit(1);
+    }
     // Health check for arduino-cli
     match health_check() {
         true => info!("arduino-cli initialized successfully"),
@@ -17,23 +39,121 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    let (layer, io) = SocketIo::new_layer();
+    // A worker compiles for its dispatcher instead of serving clients
+    if dispatch::role() == Role::Worker {
+        provision::run().await;
+        warmup::spawn_warmup(warmup::configured_fqbns());
+        worker::run().await?;
+        telemetry::shutdown();
+        return Ok(());
+    }
+
+    // Handshake credentials and the JWKS refresh
+    auth::init();
+
+    // Message catalogs for localized responses
+    i18n::init();
+
+    // Sketch copies of builds interrupted by the last shutdown
+    build::remove_stale_copies();
 
-    io.ns("/", on_connect);
-    io.ns("/custom", on_connect);
+    // Storage of the builds on disk, counted against their identities' quotas
+    usage::load_storage();
 
-    let app = axum::Router
-        ::new()
-        .route(
-            "/",
-            get(|| async { "alive" })
-        )
-        .layer(layer);
+    // Delete guest builds once their session expires
+    sessions::spawn_reaper();
 
-    info!("Starting server");
+    // Delete builds, stored artifacts and workspaces past the retention policy
+    retention::spawn_reaper();
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // Build and firmware notifications, if a broker is configured
+    notifications::init();
+
+    // Periodic usage reports, if the operator opted in
+    analytics::spawn_reporter();
+
+    // Install the manifest's cores and libraries, then prime the toolchains of the configured
+    // boards before the first user compile
+    tokio::spawn(async {
+        provision::run().await;
+        warmup::spawn_warmup(warmup::configured_fqbns());
+    });
+
+    let mut app = axum::Router::new().merge(http::routes()).route(ws::PATH, get(ws::upgrade));
+    // The routes workers pull compiles from
+    if dispatch::role() == Role::Dispatcher {
+        app = app.merge(dispatch::routes());
+        dispatch::spawn_reaper();
+    }
+    // Socket.IO rooms and broadcasts across instances, if the operator configured a Redis
+    let (mut app, drained) = match cluster::redis_url() {
+        Some(url) => {
+            let framed = match msgpack::enabled() {
+                true => Some(cluster::adapter(&url).await?),
+                false => None,
+            };
+            let (app, ios) = socket_io::<ClusterAdapter>(app, cluster::adapter(&url).await?, framed);
+            status::spawn_broadcaster(ios.clone());
+            (app, shutdown::drain(ios).boxed())
+        }
+        None => {
+            let (app, ios) = socket_io::<LocalAdapter>(app, (), msgpack::enabled().then_some(()));
+            status::spawn_broadcaster(ios.clone());
+            (app, shutdown::drain(ios).boxed())
+        }
+    };
+    app = app.layer(middleware::from_fn(cors::check_socket_origin));
+    // Outermost, so preflights and the Socket.IO handshake get CORS headers too
+    if let Some(cors) = cors::layer() {
+        app = app.layer(cors);
+    }
+
+    // The gRPC API for backends, on its own port if the operator opted in
+    if let Some(grpc_port) = grpc::port() {
+        tokio::spawn(grpc::serve(bind.clone(), grpc_port));
+    }
+
+    // Compile jobs from a NATS bus, if the operator configured one
+    if let Some(url) = nats::url() {
+        tokio::spawn(nats::serve(url));
+    }
+
+    info!("Starting server on {}:{}", bind, port);
+
+    // Drain running jobs on SIGTERM/SIGINT before the server stops
+    tls::serve(tls, &bind, port, app, drained).await?;
+    telemetry::shutdown();
 
     Ok(())
 }
+
+// The Socket.IO servers on `A` adapters, the MessagePack framed one if it has a state
+fn socket_io<A: Adapter + DefinedAdapter>(app: axum::Router, state: A::State, framed: Option<A::State>) -> (axum::Router, Vec<SocketIo<A>>)
+    where Connection: From<SocketRef<A>>
+{
+    let (layer, io) = SocketIo::builder().with_adapter::<A>(state).build_layer();
+
+    io.ns("/", on_connect::<A>).spawn();
+    io.ns("/custom", on_connect::<A>).spawn();
+    // Operator introspection and controls
+    io.ns("/admin", admin::on_connect::<A>).spawn();
+    // clangd for browser IDEs, if the operator opted in
+    if lsp::enabled() {
+        io.ns("/lsp", on_lsp_connect::<A>).spawn();
+    }
+
+    let mut app = app.layer(layer);
+    let mut ios = vec![io];
+    // The client namespaces again with MessagePack framing, on their own path
+    if let Some(state) = framed {
+        let (layer, io) = SocketIo::builder().with_parser(ParserConfig::msgpack()).req_path(msgpack::PATH).with_adapter::<A>(state).build_layer();
+        io.ns("/", on_msgpack_connect::<A>).spawn();
+        io.ns("/custom", on_msgpack_connect::<A>).spawn();
+        if lsp::enabled() {
+            io.ns("/lsp", on_msgpack_lsp_connect::<A>).spawn();
+        }
+        app = app.layer(layer);
+        ios.push(io);
+    }
+    (app, ios)
+}
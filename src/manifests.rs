@@ -0,0 +1,369 @@
+// Reproducible builds and pinned dependencies. Every compile writes `build-manifest.json` among its artifacts: the
+// arduino-cli version, board, options and arguments of the build, and the platforms and libraries
+// it used with their versions, from the "Used library" and "Used platform" tables arduino-cli
+// prints. `replay-build` compiles a build again from its manifest, with exactly those versions.
+// `generate-lockfile` resolves the same for a sketch into a lockfile, which compile requests can
+// pass as `lockfile` to build with those versions.
+//
+// Pinned versions are installed next to the server's own ones rather than over them. Platforms
+// the default data directory doesn't hold go into a toolchain named after the board platform
+// (`esp32@3.0.7`, see toolchain.rs), libraries into `<data dir>/pinned-libraries/<name>@<version>`,
+// which the compile is given with `--library`.
+use std::path::{ Path, PathBuf };
+use std::sync::LazyLock;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ Map, Value };
+use crate::artifacts::build_dir;
+use crate::compiler::{ arduino_data_dir, arduino_user_dir, get_arduino_cli_path, is_safe_name, run_program_with_env, server_data_dir };
+use crate::health::arduino_cli_version;
+use crate::provision;
+use crate::toolchain;
+
+pub const MANIFEST_FILE: &str = "build-manifest.json";
+const FORMAT: u32 = 1;
+
+// Request fields a replay compiles with again. Signing keys only by name, a PEM sent with the
+// request is never written down.
+const REPLAYED_OPTIONS: &[&str] = &["fqbn", "profile", "merge", "partitions_csv", "keep_build_dir", "teaching", "encrypt", "variables"];
+
+// Installs of pinned versions, one at a time so two replays don't install into the same place
+static PINNING: LazyLock<tokio::sync::Mutex<()>> = LazyLock::new(|| tokio::sync::Mutex::new(()));
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Dependency {
+    // `vendor:architecture` of a platform, the library name of a library
+    pub name: String,
+    pub version: String,
+    // Library bundled with a platform, pinned by the platform's version
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bundled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BuildManifest {
+    pub format: u32,
+    pub build_id: String,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arduino_cli: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fqbn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<String>,
+    pub sketch_path: String,
+    // Compile options of the request
+    #[serde(default)]
+    pub options: Map<String, Value>,
+    // arduino-cli arguments, without the server's output directories
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub platforms: Vec<Dependency>,
+    #[serde(default)]
+    pub libraries: Vec<Dependency>,
+    // Board manager URLs the server had configured
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub index_urls: Vec<String>,
+}
+
+// The versions a sketch builds with, as `<name>@<version>` specs. Libraries bundled with a
+// platform are left out, the platform's version pins them.
+#[derive(Serialize, Deserialize)]
+pub struct Lockfile {
+    pub format: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fqbn: Option<String>,
+    #[serde(default)]
+    pub cores: Vec<String>,
+    #[serde(default)]
+    pub libraries: Vec<String>,
+    #[serde(default)]
+    pub index_urls: Vec<String>,
+}
+
+// Versions a replay activates: the toolchain to compile in, if the default one doesn't do, and
+// the library folders to pass with `--library`
+#[derive(Default)]
+pub struct Pins {
+    pub toolchain: Option<String>,
+    pub libraries: Vec<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Rows of the `<title>  Version  Path` table in `output`. Names may hold spaces, so the columns
+// are cut at the offsets of the header.
+fn used_table(output: &str, title: &str) -> Vec<(String, String, String)> {
+    let mut lines = output.lines().skip_while(|line| !line.starts_with(title));
+    let Some(header) = lines.next() else {
+        return Vec::new();
+    };
+    let (Some(version), Some(path)) = (header.find("Version"), header.find("Path")) else {
+        return Vec::new();
+    };
+    lines
+        .take_while(|line| !line.trim().is_empty() && !line.starts_with("Used "))
+        .filter_map(|line| {
+            let name = line.get(..version)?.trim();
+            let version = line.get(version..path).unwrap_or_default().trim();
+            let path = line.get(path..).unwrap_or_default().trim();
+            (!name.is_empty()).then(|| (name.to_string(), version.to_string(), path.to_string()))
+        })
+        .collect()
+}
+
+// Platforms and libraries of a compile's output
+pub fn used_dependencies(output: &str) -> (Vec<Dependency>, Vec<Dependency>) {
+    let platforms = used_table(output, "Used platform")
+        .into_iter()
+        .map(|(name, version, _)| Dependency { name, version, bundled: false })
+        .collect();
+    let libraries = used_table(output, "Used library")
+        .into_iter()
+        .map(|(name, version, path)| {
+            let bundled = Path::new(&path).components().any(|part| part.as_os_str() == "hardware");
+            Dependency { name, version, bundled }
+        })
+        .collect();
+    (platforms, libraries)
+}
+
+fn replayed_options(request: &Value) -> Map<String, Value> {
+    let mut options: Map<String, Value> = REPLAYED_OPTIONS
+        .iter()
+        .filter_map(|key| Some((key.to_string(), request.get(*key).filter(|v| !v.is_null())?.clone())))
+        .collect();
+    if let Some(stored) = request.get("sign").and_then(|sign| sign.get("stored")) {
+        options.insert("sign".to_string(), serde_json::json!({ "stored": stored }));
+    }
+    options
+}
+
+// Board manager URLs configured on the server: the provisioning manifest's and arduino-cli's
+// ARDUINO_BOARD_MANAGER_ADDITIONAL_URLS
+pub fn index_urls() -> Vec<String> {
+    let mut urls = provision::board_urls();
+    let configured = std::env::var("ARDUINO_BOARD_MANAGER_ADDITIONAL_URLS").unwrap_or_default();
+    for url in configured.split([',', ' ']).filter(|url| !url.is_empty()) {
+        if !urls.iter().any(|known| known == url) {
+            urls.push(url.to_string());
+        }
+    }
+    urls
+}
+
+// Write the manifest of build `build_id` into `build_dir`, from its request, arguments and
+// compile output
+pub async fn write(build_id: &str, build_dir: &Path, request: &Value, args: &[String], output: &str) -> Result<(), String> {
+    let (platforms, libraries) = used_dependencies(output);
+    let mut recorded = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if matches!(arg.as_str(), "--output-dir" | "--build-path") {
+            args.next();
+        } else {
+            recorded.push(arg.clone());
+        }
+    }
+    let manifest = BuildManifest {
+        format: FORMAT,
+        build_id: build_id.to_string(),
+        created_at: now(),
+        arduino_cli: arduino_cli_version().await.ok(),
+        fqbn: request.get("fqbn").and_then(|v| v.as_str()).map(String::from),
+        toolchain: request.get("toolchain").and_then(|v| v.as_str()).map(String::from),
+        sketch_path: request.get("sketch_path").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+        options: replayed_options(request),
+        args: recorded,
+        platforms,
+        libraries,
+        index_urls: index_urls(),
+    };
+    let json = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    std::fs::write(build_dir.join(MANIFEST_FILE), json).map_err(|e| format!("Failed to write {}: {}", MANIFEST_FILE, e))
+}
+
+pub fn load(build_id: &str) -> Result<BuildManifest, String> {
+    let dir = build_dir(build_id).ok_or_else(|| format!("Unknown build {}", build_id))?;
+    let json = std::fs::read(dir.join(MANIFEST_FILE)).map_err(|_| format!("Build {} has no manifest", build_id))?;
+    serde_json::from_slice(&json).map_err(|e| format!("Invalid manifest of build {}: {}", build_id, e))
+}
+
+impl Lockfile {
+    // Lockfile of a compile of `fqbn` from its output
+    pub fn from_output(fqbn: Option<String>, output: &str) -> Lockfile {
+        let (platforms, libraries) = used_dependencies(output);
+        let spec = |dependency: &Dependency| format!("{}@{}", dependency.name, dependency.version);
+        Lockfile {
+            format: FORMAT,
+            fqbn,
+            cores: platforms.iter().map(spec).collect(),
+            libraries: libraries.iter().filter(|library| !library.bundled).map(spec).collect(),
+            index_urls: index_urls(),
+        }
+    }
+
+    // The `lockfile` of a request, as an object or its JSON text
+    pub fn from_request(data: &Value) -> Result<Option<Lockfile>, String> {
+        let parsed = match data.get("lockfile") {
+            None | Some(Value::Null) => return Ok(None),
+            Some(Value::String(text)) => serde_json::from_str(text),
+            Some(lockfile) => serde_json::from_value(lockfile.clone()),
+        };
+        parsed.map(Some).map_err(|e| format!("Invalid lockfile: {}", e))
+    }
+
+    // Platforms and libraries it pins, every spec needs a version
+    pub fn dependencies(&self) -> Result<(Vec<Dependency>, Vec<Dependency>), String> {
+        let parse = |specs: &[String]| {
+            specs
+                .iter()
+                .map(|spec| match spec.rsplit_once('@') {
+                    Some((name, version)) if !name.trim().is_empty() && !version.trim().is_empty() =>
+                        Ok(Dependency { name: name.trim().to_string(), version: version.trim().to_string(), bundled: false }),
+                    _ => Err(format!("Lockfile entry without a version: {}", spec)),
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+        Ok((parse(&self.cores)?, parse(&self.libraries)?))
+    }
+}
+
+// Versions are passed to arduino-cli as arguments, nothing that could read as an option
+fn valid_version(version: &str) -> bool {
+    is_safe_name(version) && !version.starts_with('-')
+}
+
+fn valid_library_name(name: &str) -> bool {
+    !name.is_empty() &&
+        name.chars().next().is_some_and(|c| c.is_ascii_alphanumeric()) &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.'))
+}
+
+fn platform_dir(data_dir: &Path, platform: &Dependency) -> Option<PathBuf> {
+    let (vendor, architecture) = platform.name.split_once(':')?;
+    Some(data_dir.join("packages").join(vendor).join("hardware").join(architecture).join(&platform.version))
+}
+
+// Install the platforms the default data directory lacks into the toolchain of the board
+// platform's version, None when everything is already there. `index_urls` are the board manager
+// URLs to install from besides the configured ones.
+async fn pin_platforms(platforms: &[Dependency], index_urls: &[String], progress: &impl Fn(&str)) -> Result<Option<String>, String> {
+    for platform in platforms {
+        let valid = platform.name.split_once(':').is_some_and(|(vendor, architecture)| is_safe_name(vendor) && is_safe_name(architecture));
+        if !valid || !valid_version(&platform.version) {
+            return Err(format!("Invalid platform {}@{}", platform.name, platform.version));
+        }
+    }
+    let default = arduino_data_dir();
+    let missing: Vec<&Dependency> = platforms
+        .iter()
+        .filter(|platform| !platform_dir(&default, platform).is_some_and(|dir| dir.is_dir()))
+        .collect();
+    let Some(board) = platforms.first().filter(|_| !missing.is_empty()) else {
+        return Ok(None);
+    };
+    let name = format!("{}@{}", board.name.split_once(':').map_or(board.name.as_str(), |(_, architecture)| architecture), board.version);
+    let dir = toolchain::create(&name)?;
+    let urls: Vec<String> = match index_urls.is_empty() {
+        true => Vec::new(),
+        false => vec!["--additional-urls".to_string(), index_urls.join(",")],
+    };
+    let mut indexed = index_urls.is_empty();
+    for platform in platforms {
+        if platform_dir(&dir, platform).is_some_and(|dir| dir.is_dir()) {
+            continue;
+        }
+        progress(&format!("{}@{}", platform.name, platform.version));
+        if !indexed {
+            let args = [vec!["core".to_string(), "update-index".to_string()], urls.clone()].concat();
+            arduino_cli(&args, ("ARDUINO_DIRECTORIES_DATA", &dir)).await.map_err(|e| format!("Failed to update the package indexes: {}", e))?;
+            indexed = true;
+        }
+        let spec = format!("{}@{}", platform.name, platform.version);
+        let args = [vec!["core".to_string(), "install".to_string(), spec.clone()], urls.clone()].concat();
+        arduino_cli(&args, ("ARDUINO_DIRECTORIES_DATA", &dir)).await.map_err(|e| format!("Failed to install {}: {}", spec, e))?;
+    }
+    Ok(Some(name))
+}
+
+// Folder in `libraries` of the library `name` at `version`, by its library.properties
+fn find_library(libraries: &Path, name: &str, version: &str) -> Option<PathBuf> {
+    std::fs
+        ::read_dir(libraries)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|dir| {
+            let properties = std::fs::read_to_string(dir.join("library.properties")).unwrap_or_default();
+            let field = |key: &str| {
+                properties.lines().find_map(|line| line.trim().strip_prefix(key).map(|value| value.trim().to_string()))
+            };
+            field("name=").is_some_and(|found| found.eq_ignore_ascii_case(name)) && field("version=").as_deref() == Some(version)
+        })
+}
+
+// arduino-cli install steps, with one Arduino directory moved to where the pins go
+async fn arduino_cli(args: &[String], directory: (&str, &Path)) -> Result<(), String> {
+    let command = args.first().map(String::as_str).unwrap_or_default();
+    let dir = directory.1.to_string_lossy();
+    let response = run_program_with_env(get_arduino_cli_path(), command, args, &[(directory.0, &dir)]).await;
+    match response.success {
+        true => Ok(()),
+        false => Err(response.error.unwrap_or_default().trim().to_string()),
+    }
+}
+
+// Library folders holding the pinned versions the sketchbook doesn't have, installed on demand
+async fn pin_libraries(libraries: &[Dependency], progress: &impl Fn(&str)) -> Result<Vec<String>, String> {
+    let sketchbook = arduino_user_dir().join("libraries");
+    let mut folders = Vec::new();
+    let mut indexed = false;
+    for library in libraries.iter().filter(|library| !library.bundled && !library.version.is_empty()) {
+        if !valid_library_name(&library.name) || !valid_version(&library.version) {
+            return Err(format!("Invalid library {}@{}", library.name, library.version));
+        }
+        if find_library(&sketchbook, &library.name, &library.version).is_some() {
+            continue;
+        }
+        let root = server_data_dir()
+            .join("pinned-libraries")
+            .join(format!("{}@{}", library.name.replace(' ', "_"), library.version));
+        if let Some(folder) = find_library(&root.join("libraries"), &library.name, &library.version) {
+            folders.push(folder.to_string_lossy().to_string());
+            continue;
+        }
+        progress(&format!("{}@{}", library.name, library.version));
+        std::fs::create_dir_all(&root).map_err(|e| e.to_string())?;
+        if !indexed {
+            let args = ["lib", "update-index"].map(String::from);
+            arduino_cli(&args, ("ARDUINO_DIRECTORIES_USER", &root)).await.map_err(|e| format!("Failed to update the library index: {}", e))?;
+            indexed = true;
+        }
+        let spec = format!("{}@{}", library.name, library.version);
+        let args = ["lib".to_string(), "install".to_string(), "--no-deps".to_string(), spec.clone()];
+        arduino_cli(&args, ("ARDUINO_DIRECTORIES_USER", &root)).await.map_err(|e| format!("Failed to install {}: {}", spec, e))?;
+        let folder = find_library(&root.join("libraries"), &library.name, &library.version)
+            .ok_or_else(|| format!("Installed {} not found", spec))?;
+        folders.push(folder.to_string_lossy().to_string());
+    }
+    Ok(folders)
+}
+
+// Make the given versions available for a compile, installing what is missing. `progress` is
+// told each version it installs.
+pub async fn activate(platforms: &[Dependency], libraries: &[Dependency], index_urls: &[String], progress: impl Fn(&str)) -> Result<Pins, String> {
+    for url in index_urls {
+        let valid = reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "https" | "http"));
+        if !valid || url.contains(',') {
+            return Err(format!("Invalid index URL: {}", url));
+        }
+    }
+    let _pinning = PINNING.lock().await;
+    let toolchain = pin_platforms(platforms, index_urls, &progress).await?;
+    let libraries = pin_libraries(libraries, &progress).await?;
+    Ok(Pins { toolchain, libraries })
+}
@@ -0,0 +1,84 @@
+// Build matrices. `compile-matrix` compiles one sketch for a list of targets, each a board with
+// its own compile options on top of the request's, in parallel as far as the worker pool allows,
+// and answers with one report covering every target.
+use serde::Serialize;
+use serde_json::Value;
+use crate::models::{ Artifact, Diagnostic, MemoryUsage, SizeDelta };
+
+pub const MAX_TARGETS: usize = 32;
+
+#[derive(Serialize)]
+pub struct TargetReport {
+    // Target label, the FQBN unless the target names itself
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fqbn: Option<String>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<MemoryUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_delta: Option<SizeDelta>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<Artifact>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+}
+
+#[derive(Serialize)]
+pub struct MatrixReport {
+    pub passed: usize,
+    pub failed: usize,
+    pub targets: Vec<TargetReport>,
+}
+
+// The compile request of every target: the request without `targets`, overridden by the
+// target's fields. A target is an object with at least `fqbn` or `profile`, or a bare FQBN.
+pub fn expand(data: &Value) -> Result<Vec<Value>, String> {
+    let targets = data.get("targets").and_then(|v| v.as_array()).ok_or("Missing targets")?;
+    if targets.is_empty() || targets.len() > MAX_TARGETS {
+        return Err(format!("A matrix takes 1 to {} targets", MAX_TARGETS));
+    }
+    let mut base = data.clone();
+    if let Some(fields) = base.as_object_mut() {
+        fields.remove("targets");
+    }
+    targets
+        .iter()
+        .map(|target| {
+            let mut request = base.clone();
+            match target {
+                Value::String(fqbn) => {
+                    request["fqbn"] = fqbn.clone().into();
+                }
+                Value::Object(fields) if fields.contains_key("fqbn") || fields.contains_key("profile") => {
+                    for (key, value) in fields {
+                        request[key] = value.clone();
+                    }
+                }
+                _ => {
+                    return Err(format!("Invalid target: {}", target));
+                }
+            }
+            // Lockfiles are installed ahead of a single compile, not per target
+            if request.get("lockfile").is_some_and(|lockfile| !lockfile.is_null()) {
+                return Err("lockfile is not supported in a matrix".to_string());
+            }
+            Ok(request)
+        })
+        .collect()
+}
+
+// Label of a target in the report
+pub fn target_name(request: &Value) -> String {
+    ["name", "fqbn", "profile"]
+        .iter()
+        .find_map(|key| request.get(*key).and_then(|v| v.as_str()))
+        .unwrap_or_default()
+        .to_string()
+}
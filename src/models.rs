@@ -1,17 +1,1238 @@
 use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use ts_rs::TS;
+use utoipa::ToSchema;
+use crate::queue::Priority;
+use crate::validation::{ invalid, missing, optional, required, Field, Kind, Schema, PRIORITY, TOOLCHAIN };
 // Response structures
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug, TS)]
 pub struct CommandResponse {
     pub success: bool,
     pub output: String,
     pub error: Option<String>,
     pub command: String,
     pub args: Vec<String>,
+    // Set for compiles, identifies the build directory holding the artifacts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub build_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub artifacts: Vec<Artifact>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub diagnostics: Vec<Diagnostic>,
+    // Teaching mode: beginner-friendly explanations of the errors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub explanations: Vec<Explanation>,
+    // Machine-readable kind of the failure, for clients to branch on rather than `error`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub error_code: Option<ErrorCode>,
+    // Seconds to wait before retrying a rate limited request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub retry_after: Option<u64>,
+    // Compile answered from the result cache
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[ts(as = "Option<_>", optional)]
+    pub cached: bool,
+    // Correlation id of the job that produced the response, as found in the server logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub job_id: Option<String>,
+    // What the process printed to stderr alone, `error` also carries the server's own messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub stderr: Option<String>,
+    // Exit code of the process, none when it was killed by a signal or never ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub exit_code: Option<i32>,
+    // When the process started and finished, in Unix milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub started_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub finished_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub duration_ms: Option<u64>,
+    // Version of the arduino-cli that ran the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub arduino_cli_version: Option<String>,
+    // Memory used by a compiled sketch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub size: Option<MemoryUsage>,
+    // Change from the previous build of the same sketch and board, from the job history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub size_delta: Option<SizeDelta>,
+    // Fields of an `invalid_request`, with what is wrong with each
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub invalid_fields: Vec<FieldError>,
+    // Where the time of a compile went, for requests with `timings: true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub timings: Option<Timings>,
+}
+
+// Milliseconds a compile spent in each of its phases. A cached compile only spends them in
+// setup (finding the cached build) and packaging.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, TS)]
+pub struct Timings {
+    // Waiting in the queue for a worker
+    #[ts(type = "number")]
+    pub queue_ms: u64,
+    // Locking the workspace and preparing the sketch: partition table, templates, secrets
+    #[ts(type = "number")]
+    pub setup_ms: u64,
+    // arduino-cli, here or on a worker
+    #[ts(type = "number")]
+    pub compile_ms: u64,
+    // Diagnostics, merging, signing, encryption and patches
+    #[ts(type = "number")]
+    pub post_process_ms: u64,
+    // The build manifest and the artifacts
+    #[ts(type = "number")]
+    pub packaging_ms: u64,
+    #[ts(type = "number")]
+    pub total_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldProblem {
+    Missing,
+    Invalid,
+}
+
+// A field of a rejected request. `field` is empty when the problem is with the whole payload.
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+pub struct FieldError {
+    pub field: String,
+    pub problem: FieldProblem,
+    pub message: String,
+}
+
+// Stable codes of `error_code`, see `errors::CompilerError`. Codes are only ever added.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The request itself: missing or mistyped fields, paths outside the workspace, ...
+    InvalidRequest,
+    Unauthorized,
+    RateLimited,
+    QueueFull,
+    ShuttingDown,
+    IntakePaused,
+    UnknownEvent,
+    PolicyViolation,
+    Timeout,
+    Cancelled,
+    WorkerFailed,
+    // The board's platform isn't installed on the server
+    CoreNotInstalled,
+    // Another program holds the serial port
+    PortBusy,
+    PortNotFound,
+    // The sketch doesn't fit the board's flash or RAM
+    SketchTooLarge,
+    // The sketch has errors, see `diagnostics`
+    CompileFailed,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::QueueFull => "queue_full",
+            ErrorCode::ShuttingDown => "shutting_down",
+            ErrorCode::IntakePaused => "intake_paused",
+            ErrorCode::UnknownEvent => "unknown_event",
+            ErrorCode::PolicyViolation => "policy_violation",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Cancelled => "cancelled",
+            ErrorCode::WorkerFailed => "worker_failed",
+            ErrorCode::CoreNotInstalled => "core_not_installed",
+            ErrorCode::PortBusy => "port_busy",
+            ErrorCode::PortNotFound => "port_not_found",
+            ErrorCode::SketchTooLarge => "sketch_too_large",
+            ErrorCode::CompileFailed => "compile_failed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Advisory,
+}
+
+// A finding about the build, from the compiler or from server-side checks
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub line: Option<u32>,
+}
+
+// Plain-language explanation of a compile error, with a link to learn more
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+pub struct Explanation {
+    pub code: String,
+    pub title: String,
+    pub explanation: String,
+    pub link: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub line: Option<u32>,
+}
+
+// A file produced by a build
+#[derive(Serialize, Deserialize, Clone, Debug, TS, ToSchema)]
+pub struct Artifact {
+    pub name: String,
+    #[ts(type = "number")]
+    pub size: u64,
+    // Content hash, the artifact store's key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub sha256: Option<String>,
+    // Temporary download URL, valid until `expires_at`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub expires_at: Option<u64>,
+}
+
+// Program storage and static RAM used by a build, from arduino-cli's size report
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, TS)]
+pub struct MemoryUsage {
+    #[ts(type = "number")]
+    pub flash_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub flash_max: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub ram_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub ram_max: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS)]
+pub struct SizeDelta {
+    pub previous_build_id: String,
+    #[ts(type = "number")]
+    pub flash_bytes: i64,
+    // Relative to the previous build's flash use
+    pub flash_percent: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub ram_bytes: Option<i64>,
 }
 
 // Request structures
-#[derive(Deserialize)]
+#[derive(Deserialize, TS)]
 pub struct ArduinoCommand {
     pub command: String,
     pub args: Vec<String>,
 }
+
+// Payload of `install-core`
+#[derive(Serialize, Deserialize, TS)]
+pub struct InstallCoreRequest {
+    // `vendor:arch`, optionally `@version`
+    pub core: String,
+    #[ts(optional)]
+    pub toolchain: Option<String>,
+    #[ts(optional)]
+    pub priority: Option<Priority>,
+}
+
+impl Schema for InstallCoreRequest {
+    const FIELDS: &'static [Field] = &[required("core", Kind::String), TOOLCHAIN, PRIORITY];
+}
+
+// Payload of `compile-sketch`. The build options are still read from the raw data, which also
+// goes into the build manifest; their fields here give the request its full shape.
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct CompileRequest {
+    #[ts(optional)]
+    pub sketch_path: Option<String>,
+    // Stored project to compile, its sketch path and board fill in missing fields
+    #[ts(optional)]
+    pub project: Option<String>,
+    #[ts(optional)]
+    pub fqbn: Option<String>,
+    #[ts(optional)]
+    pub profile: Option<String>,
+    // `sketch.lock` as text or parsed
+    #[ts(optional)]
+    pub lockfile: Option<Value>,
+    #[ts(optional)]
+    pub merge: Option<bool>,
+    // Name of a stored flash encryption key
+    #[ts(optional)]
+    pub encrypt: Option<String>,
+    #[ts(optional)]
+    pub sign: Option<Value>,
+    #[ts(optional)]
+    pub partitions_csv: Option<String>,
+    #[ts(optional)]
+    pub keep_build_dir: Option<bool>,
+    #[ts(optional)]
+    pub teaching: Option<bool>,
+    // Answer with the `timings` of the compile's phases
+    #[ts(optional)]
+    pub timings: Option<bool>,
+    // Send arduino-cli's output as `compile-output` events while it runs
+    #[ts(optional)]
+    pub stream_output: Option<bool>,
+    #[ts(optional)]
+    pub secrets: Option<std::collections::BTreeMap<String, String>>,
+    #[ts(optional)]
+    pub variables: Option<Value>,
+    #[ts(optional)]
+    pub patches: Option<Value>,
+    #[ts(optional)]
+    pub size_threshold_percent: Option<f64>,
+    #[ts(optional)]
+    pub toolchain: Option<String>,
+    #[ts(optional)]
+    pub priority: Option<Priority>,
+}
+
+impl Schema for CompileRequest {
+    const FIELDS: &'static [Field] = &[
+        optional("sketch_path", Kind::String),
+        optional("project", Kind::String),
+        optional("fqbn", Kind::String),
+        optional("profile", Kind::String),
+        optional("lockfile", Kind::StringOrObject),
+        optional("merge", Kind::Bool),
+        optional("encrypt", Kind::String),
+        optional("sign", Kind::Object),
+        optional("partitions_csv", Kind::String),
+        optional("keep_build_dir", Kind::Bool),
+        optional("teaching", Kind::Bool),
+        optional("timings", Kind::Bool),
+        optional("stream_output", Kind::Bool),
+        optional("secrets", Kind::StringMap),
+        optional("variables", Kind::Object),
+        optional("patches", Kind::Object),
+        optional("size_threshold_percent", Kind::Number),
+        TOOLCHAIN,
+        PRIORITY,
+    ];
+
+    fn check(&self) -> Vec<FieldError> {
+        match self.sketch_path.is_none() && self.project.is_none() {
+            true => vec![missing("sketch_path")],
+            false => Vec::new(),
+        }
+    }
+}
+
+// Payload of `upload-sketch`
+#[derive(Serialize, Deserialize, TS)]
+pub struct UploadRequest {
+    pub sketch_path: String,
+    pub fqbn: String,
+    #[ts(optional)]
+    pub port: Option<String>,
+    // LAN address of a device to upload to over the network, instead of `port`
+    #[ts(optional)]
+    pub address: Option<String>,
+    // Flash the binaries of this build instead of rebuilding
+    #[ts(optional)]
+    pub build_id: Option<String>,
+    #[ts(optional)]
+    pub ota_password: Option<String>,
+    #[ts(optional)]
+    pub toolchain: Option<String>,
+}
+
+impl Schema for UploadRequest {
+    const FIELDS: &'static [Field] = &[
+        required("sketch_path", Kind::String),
+        required("fqbn", Kind::String),
+        optional("port", Kind::String),
+        optional("address", Kind::String),
+        optional("build_id", Kind::String),
+        optional("ota_password", Kind::String),
+        TOOLCHAIN,
+    ];
+
+    fn check(&self) -> Vec<FieldError> {
+        match self.port.is_none() && self.address.is_none() {
+            true => vec![missing("port")],
+            false => Vec::new(),
+        }
+    }
+}
+
+// Structured log line, part of the v2 response shape
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, TS, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, TS, ToSchema)]
+pub struct LogLine {
+    pub stream: LogStream,
+    pub text: String,
+}
+
+// v2 response: structured logs next to the flat `output` string of v1
+#[derive(Serialize, TS)]
+pub struct CommandResponseV2<'a> {
+    pub version: u32,
+    pub success: bool,
+    pub output: &'a str,
+    pub logs: Vec<LogLine>,
+    pub error: Option<&'a str>,
+    pub command: &'a str,
+    pub args: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub build_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[Artifact]>::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub artifacts: &'a [Artifact],
+    #[serde(skip_serializing_if = "<[Diagnostic]>::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub diagnostics: &'a [Diagnostic],
+    #[serde(skip_serializing_if = "<[Explanation]>::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub explanations: &'a [Explanation],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub error_code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub retry_after: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[ts(as = "Option<_>", optional)]
+    pub cached: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub job_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub finished_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(as = "Option<f64>", optional)]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub arduino_cli_version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub size: Option<MemoryUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub size_delta: Option<&'a SizeDelta>,
+    #[serde(skip_serializing_if = "<[FieldError]>::is_empty")]
+    #[ts(as = "Option<_>", optional)]
+    pub invalid_fields: &'a [FieldError],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub timings: Option<Timings>,
+}
+
+// Payload of the esptool maintenance events
+#[derive(Serialize, Deserialize, TS)]
+pub struct EsptoolRequest {
+    pub port: String,
+    #[ts(optional)]
+    pub baud: Option<u32>,
+    #[ts(optional)]
+    pub chip: Option<String>,
+}
+
+impl Schema for EsptoolRequest {
+    const FIELDS: &'static [Field] = &[
+        required("port", Kind::String),
+        optional("baud", Kind::Unsigned),
+        optional("chip", Kind::String),
+    ];
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetMode {
+    // Hard reset into the application
+    #[default]
+    Run,
+    // Reset into the ROM download mode
+    Bootloader,
+}
+
+// Payload of `cancel-job`
+#[derive(Serialize, Deserialize, TS)]
+pub struct JobRequest {
+    pub job_id: String,
+}
+
+impl Schema for JobRequest {
+    const FIELDS: &'static [Field] = &[required("job_id", Kind::String)];
+}
+
+// Payload of `archive-begin`: the size and SHA-256 (hex) of the whole archive
+#[derive(Serialize, Deserialize, TS)]
+pub struct TransferBeginRequest {
+    #[ts(type = "number")]
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Schema for TransferBeginRequest {
+    const FIELDS: &'static [Field] = &[required("size", Kind::Unsigned), required("sha256", Kind::String)];
+
+    fn check(&self) -> Vec<FieldError> {
+        match self.sha256.len() == 64 && self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            true => Vec::new(),
+            false => vec![invalid("sha256", "Invalid sha256: expected 64 hex digits")],
+        }
+    }
+}
+
+// Payload of `archive-chunk`: chunk `seq` (from 0), base64 or binary, with the SHA-256 of its bytes
+#[derive(Serialize, Deserialize, TS)]
+pub struct TransferChunkRequest {
+    pub transfer_id: String,
+    #[ts(type = "number")]
+    pub seq: u64,
+    #[ts(type = "string | Uint8Array")]
+    pub data: String,
+    pub sha256: String,
+}
+
+impl Schema for TransferChunkRequest {
+    const FIELDS: &'static [Field] = &[
+        required("transfer_id", Kind::String),
+        required("seq", Kind::Unsigned),
+        required("data", Kind::String),
+        required("sha256", Kind::String),
+    ];
+}
+
+// Payload of `archive-end`, `subdir` names the sketch's directory inside the archive
+#[derive(Serialize, Deserialize, TS)]
+pub struct ArchiveEndRequest {
+    pub transfer_id: String,
+    #[ts(optional)]
+    pub subdir: Option<String>,
+}
+
+impl Schema for ArchiveEndRequest {
+    const FIELDS: &'static [Field] = &[required("transfer_id", Kind::String), optional("subdir", Kind::String)];
+}
+
+// Payload of `download-begin`
+#[derive(Serialize, Deserialize, TS)]
+pub struct DownloadRequest {
+    pub build_id: String,
+    pub name: String,
+}
+
+impl Schema for DownloadRequest {
+    const FIELDS: &'static [Field] = &[required("build_id", Kind::String), required("name", Kind::String)];
+}
+
+// Payload of `download-chunk`
+#[derive(Serialize, Deserialize, TS)]
+pub struct DownloadChunkRequest {
+    pub transfer_id: String,
+    #[ts(type = "number")]
+    pub seq: u64,
+}
+
+impl Schema for DownloadChunkRequest {
+    const FIELDS: &'static [Field] = &[required("transfer_id", Kind::String), required("seq", Kind::Unsigned)];
+}
+
+// Payload of `download-end`
+#[derive(Serialize, Deserialize, TS)]
+pub struct TransferRequest {
+    pub transfer_id: String,
+}
+
+impl Schema for TransferRequest {
+    const FIELDS: &'static [Field] = &[required("transfer_id", Kind::String)];
+}
+
+// Payload of `reset-board`
+#[derive(Serialize, Deserialize, TS)]
+pub struct ResetRequest {
+    pub port: String,
+    #[ts(optional)]
+    pub chip: Option<String>,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub mode: ResetMode,
+}
+
+impl Schema for ResetRequest {
+    const FIELDS: &'static [Field] = &[
+        required("port", Kind::String),
+        optional("chip", Kind::String),
+        optional("mode", Kind::OneOf(&["run", "bootloader"])),
+    ];
+}
+
+// A file sent by the client, content is base64 unless `encoding` is "utf8"
+#[derive(Serialize, Deserialize, Clone, TS)]
+pub struct FilePayload {
+    pub path: String,
+    pub content: String,
+    #[serde(default)]
+    #[ts(optional)]
+    pub encoding: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum FilesystemKind {
+    #[default]
+    Littlefs,
+    Spiffs,
+}
+
+// Payload of `build-filesystem`: the data/ folder as files or a base64 ZIP
+#[derive(Serialize, Deserialize, TS)]
+pub struct FilesystemRequest {
+    pub build_id: String,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub filesystem: FilesystemKind,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub files: Vec<FilePayload>,
+    #[ts(optional)]
+    pub zip: Option<String>,
+    // Flash the image to this port instead of only returning it
+    #[ts(optional)]
+    pub port: Option<String>,
+}
+
+impl Schema for FilesystemRequest {
+    const FIELDS: &'static [Field] = &[
+        required("build_id", Kind::String),
+        optional("filesystem", Kind::OneOf(&["littlefs", "spiffs"])),
+        optional("files", Kind::Files),
+        optional("zip", Kind::String),
+        optional("port", Kind::String),
+    ];
+}
+
+// One NVS key, `encoding` as in the nvs_partition_gen CSV (string, u8, i32, hex2bin, base64, ...)
+#[derive(Serialize, Deserialize, TS)]
+pub struct NvsEntry {
+    pub namespace: String,
+    pub key: String,
+    pub encoding: String,
+    pub value: Value,
+}
+
+// Payload of `generate-nvs`: a ready CSV or a list of entries
+#[derive(Serialize, Deserialize, TS)]
+pub struct NvsRequest {
+    // Build whose partition table gives the NVS size and offset
+    #[ts(optional)]
+    pub build_id: Option<String>,
+    #[ts(optional)]
+    pub csv: Option<String>,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub entries: Vec<NvsEntry>,
+    // Partition size when there is no build to take it from
+    #[ts(optional)]
+    pub size: Option<u32>,
+    #[ts(optional)]
+    pub offset: Option<u32>,
+    #[ts(optional)]
+    pub port: Option<String>,
+    #[ts(optional)]
+    pub chip: Option<String>,
+}
+
+impl Schema for NvsRequest {
+    const FIELDS: &'static [Field] = &[
+        optional("build_id", Kind::String),
+        optional("csv", Kind::String),
+        optional("entries", Kind::Array),
+        optional("size", Kind::Unsigned),
+        optional("offset", Kind::Unsigned),
+        optional("port", Kind::String),
+        optional("chip", Kind::String),
+    ];
+}
+
+// Payload of `format-sketch`: sources to format, answered with their diffs when `diff` is set
+#[derive(Serialize, Deserialize, TS)]
+pub struct FormatRequest {
+    pub files: Vec<FilePayload>,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub diff: bool,
+}
+
+impl Schema for FormatRequest {
+    const FIELDS: &'static [Field] = &[required("files", Kind::Files), optional("diff", Kind::Bool)];
+}
+
+// Payload of `lint-project`: a folder of the workspace, or files or a ZIP of the project
+#[derive(Serialize, Deserialize, TS)]
+pub struct LintRequest {
+    #[ts(optional)]
+    pub sketch_path: Option<String>,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub files: Vec<FilePayload>,
+    #[ts(optional)]
+    pub zip: Option<String>,
+    // Folder the sent files are checked in, arduino-lint compares it with the sketch or library
+    #[ts(optional)]
+    pub name: Option<String>,
+    #[ts(optional)]
+    pub project_type: Option<String>,
+    #[ts(optional)]
+    pub compliance: Option<String>,
+    #[ts(optional)]
+    pub library_manager: Option<String>,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub recursive: bool,
+}
+
+impl Schema for LintRequest {
+    const FIELDS: &'static [Field] = &[
+        optional("sketch_path", Kind::String),
+        optional("files", Kind::Files),
+        optional("zip", Kind::String),
+        optional("name", Kind::String),
+        optional("project_type", Kind::String),
+        optional("compliance", Kind::String),
+        optional("library_manager", Kind::String),
+        optional("recursive", Kind::Bool),
+    ];
+}
+
+// Payload of `project-save`: the sketch as a server path or as files to store with it
+#[derive(Serialize, Deserialize, TS)]
+pub struct ProjectRequest {
+    pub name: String,
+    #[ts(optional)]
+    pub sketch_path: Option<String>,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub files: Vec<FilePayload>,
+    #[ts(optional)]
+    pub tags: Option<Vec<String>>,
+    #[ts(optional)]
+    pub board: Option<String>,
+    #[ts(optional)]
+    pub metadata: Option<std::collections::BTreeMap<String, String>>,
+}
+
+impl Schema for ProjectRequest {
+    const FIELDS: &'static [Field] = &[
+        required("name", Kind::String),
+        optional("sketch_path", Kind::String),
+        optional("files", Kind::Files),
+        optional("tags", Kind::Strings),
+        optional("board", Kind::String),
+        optional("metadata", Kind::StringMap),
+    ];
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildStatus {
+    Success,
+    Failed,
+    Never,
+}
+
+// Payload of `project-search`, every given criterion must match
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct ProjectQuery {
+    // Substring of the name or of a metadata value
+    #[ts(optional)]
+    pub text: Option<String>,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub tags: Vec<String>,
+    // FQBN or FQBN prefix ("esp32:esp32")
+    #[ts(optional)]
+    pub board: Option<String>,
+    #[ts(optional)]
+    pub status: Option<BuildStatus>,
+    #[ts(as = "Option<f64>", optional)]
+    pub limit: Option<usize>,
+}
+
+impl Schema for ProjectQuery {
+    const FIELDS: &'static [Field] = &[
+        optional("text", Kind::String),
+        optional("tags", Kind::Strings),
+        optional("board", Kind::String),
+        optional("status", Kind::OneOf(&["success", "failed", "never"])),
+        optional("limit", Kind::Unsigned),
+    ];
+}
+
+// Payload of `monitor-start`
+#[derive(Serialize, Deserialize, TS)]
+pub struct MonitorRequest {
+    pub port: String,
+    #[ts(optional)]
+    pub baud: Option<u32>,
+}
+
+impl Schema for MonitorRequest {
+    const FIELDS: &'static [Field] = &[required("port", Kind::String), optional("baud", Kind::Unsigned)];
+}
+
+// Payload of `monitor-stop`
+#[derive(Serialize, Deserialize, TS)]
+pub struct MonitorStopRequest {
+    pub recording_id: String,
+}
+
+impl Schema for MonitorStopRequest {
+    const FIELDS: &'static [Field] = &[required("recording_id", Kind::String)];
+}
+
+// Payload of `list-recordings`, the recordings of a build or of a port
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct RecordingsQuery {
+    #[ts(optional)]
+    pub build_id: Option<String>,
+    #[ts(optional)]
+    pub port: Option<String>,
+}
+
+impl Schema for RecordingsQuery {
+    const FIELDS: &'static [Field] = &[optional("build_id", Kind::String), optional("port", Kind::String)];
+}
+
+// Payload of `set-locale`, no locale goes back to the server's default
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct LocaleRequest {
+    #[ts(optional)]
+    pub locale: Option<String>,
+}
+
+impl Schema for LocaleRequest {
+    const FIELDS: &'static [Field] = &[optional("locale", Kind::String)];
+}
+
+// Payload of the events running the toolchain over a sketch without building it:
+// `generate-lockfile`, `preprocess-sketch` and `check-sketch`
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct SketchRequest {
+    #[ts(optional)]
+    pub sketch_path: Option<String>,
+    // Stored project standing in for the sketch path and board
+    #[ts(optional)]
+    pub project: Option<String>,
+    #[ts(optional)]
+    pub fqbn: Option<String>,
+    #[ts(optional)]
+    pub toolchain: Option<String>,
+    #[ts(optional)]
+    pub priority: Option<Priority>,
+}
+
+impl Schema for SketchRequest {
+    const FIELDS: &'static [Field] = &[
+        optional("sketch_path", Kind::String),
+        optional("project", Kind::String),
+        optional("fqbn", Kind::String),
+        TOOLCHAIN,
+        PRIORITY,
+    ];
+
+    fn check(&self) -> Vec<FieldError> {
+        match self.sketch_path.is_none() && self.project.is_none() {
+            true => vec![missing("sketch_path")],
+            false => Vec::new(),
+        }
+    }
+}
+
+// Payload of `lsp-start` and `compilation-database`. Server paths in what they answer are
+// rewritten to the client's folders where it names them.
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct IdeRequest {
+    #[ts(optional)]
+    pub sketch_path: Option<String>,
+    #[ts(optional)]
+    pub project: Option<String>,
+    #[ts(optional)]
+    pub fqbn: Option<String>,
+    #[ts(optional)]
+    pub workspace_root: Option<String>,
+    #[ts(optional)]
+    pub arduino_data_dir: Option<String>,
+    #[ts(optional)]
+    pub arduino_user_dir: Option<String>,
+    #[ts(optional)]
+    pub toolchain: Option<String>,
+    #[ts(optional)]
+    pub priority: Option<Priority>,
+}
+
+impl Schema for IdeRequest {
+    const FIELDS: &'static [Field] = &[
+        optional("sketch_path", Kind::String),
+        optional("project", Kind::String),
+        optional("fqbn", Kind::String),
+        optional("workspace_root", Kind::String),
+        optional("arduino_data_dir", Kind::String),
+        optional("arduino_user_dir", Kind::String),
+        TOOLCHAIN,
+        PRIORITY,
+    ];
+
+    fn check(&self) -> Vec<FieldError> {
+        match self.sketch_path.is_none() && self.project.is_none() {
+            true => vec![missing("sketch_path")],
+            false => Vec::new(),
+        }
+    }
+}
+
+// Payload of `analyze-sketch`, all checks run unless some are named
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct AnalyzeRequest {
+    #[ts(optional)]
+    pub sketch_path: Option<String>,
+    #[ts(optional)]
+    pub project: Option<String>,
+    #[ts(optional)]
+    pub fqbn: Option<String>,
+    #[ts(optional)]
+    pub checks: Option<Vec<String>>,
+    #[ts(optional)]
+    pub toolchain: Option<String>,
+    #[ts(optional)]
+    pub priority: Option<Priority>,
+}
+
+impl Schema for AnalyzeRequest {
+    const FIELDS: &'static [Field] = &[
+        optional("sketch_path", Kind::String),
+        optional("project", Kind::String),
+        optional("fqbn", Kind::String),
+        optional("checks", Kind::Strings),
+        TOOLCHAIN,
+        PRIORITY,
+    ];
+
+    fn check(&self) -> Vec<FieldError> {
+        match self.sketch_path.is_none() && self.project.is_none() {
+            true => vec![missing("sketch_path")],
+            false => Vec::new(),
+        }
+    }
+}
+
+// Payload of `lsp-read-file`
+#[derive(Serialize, Deserialize, TS)]
+pub struct LspFileRequest {
+    pub uri: String,
+}
+
+impl Schema for LspFileRequest {
+    const FIELDS: &'static [Field] = &[required("uri", Kind::String)];
+}
+
+// Payload of `list-examples`, optionally of one library or platform
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct ExamplesQuery {
+    #[ts(optional)]
+    pub library: Option<String>,
+    #[ts(optional)]
+    pub platform: Option<String>,
+}
+
+impl Schema for ExamplesQuery {
+    const FIELDS: &'static [Field] = &[optional("library", Kind::String), optional("platform", Kind::String)];
+}
+
+// Payload of `compile-example`: the example's id and the options of `compile-sketch`
+#[derive(Serialize, Deserialize, TS)]
+pub struct CompileExampleRequest {
+    pub example: String,
+}
+
+impl Schema for CompileExampleRequest {
+    const FIELDS: &'static [Field] = &[required("example", Kind::String)];
+}
+
+// Payload of `replay-build`. `sketch_path` points at where the sketch is now if it moved, the
+// priority and the build inputs kept out of the manifest are taken as in `compile-sketch`.
+#[derive(Serialize, Deserialize, TS)]
+pub struct ReplayRequest {
+    pub build_id: String,
+    #[ts(optional)]
+    pub sketch_path: Option<String>,
+}
+
+impl Schema for ReplayRequest {
+    const FIELDS: &'static [Field] = &[required("build_id", Kind::String), optional("sketch_path", Kind::String)];
+}
+
+// Payload of `sketch-new`, the new sketch's folder in the workspace
+#[derive(Serialize, Deserialize, TS)]
+pub struct SketchNewRequest {
+    pub path: String,
+}
+
+impl Schema for SketchNewRequest {
+    const FIELDS: &'static [Field] = &[required("path", Kind::String)];
+}
+
+// Payload of `sketch-archive`
+#[derive(Serialize, Deserialize, TS)]
+pub struct SketchArchiveRequest {
+    pub sketch_path: String,
+}
+
+impl Schema for SketchArchiveRequest {
+    const FIELDS: &'static [Field] = &[required("sketch_path", Kind::String)];
+}
+
+// Payload of `profile-save`, `default` makes the profile the sketch's default one
+#[derive(Serialize, Deserialize, TS)]
+pub struct ProfileRequest {
+    pub sketch_path: String,
+    pub fqbn: String,
+    pub profile: String,
+    #[serde(default)]
+    #[ts(as = "Option<_>", optional)]
+    pub default: bool,
+    #[ts(optional)]
+    pub priority: Option<Priority>,
+}
+
+impl Schema for ProfileRequest {
+    const FIELDS: &'static [Field] = &[
+        required("sketch_path", Kind::String),
+        required("fqbn", Kind::String),
+        required("profile", Kind::String),
+        optional("default", Kind::Bool),
+        PRIORITY,
+    ];
+}
+
+// Payload of `power-cycle`
+#[derive(Serialize, Deserialize, TS)]
+pub struct PowerCycleRequest {
+    pub port: String,
+}
+
+impl Schema for PowerCycleRequest {
+    const FIELDS: &'static [Field] = &[required("port", Kind::String)];
+}
+
+// Payload of `encryption-key-create`, `encryption-key-get` and `encryption-key-delete`
+#[derive(Serialize, Deserialize, TS)]
+pub struct EncryptionKeyRequest {
+    pub project: String,
+}
+
+impl Schema for EncryptionKeyRequest {
+    const FIELDS: &'static [Field] = &[required("project", Kind::String)];
+}
+
+// Payload of `encryption-key-import`, the public key as PEM
+#[derive(Serialize, Deserialize, TS)]
+pub struct EncryptionKeyImportRequest {
+    pub project: String,
+    pub public_key: String,
+}
+
+impl Schema for EncryptionKeyImportRequest {
+    const FIELDS: &'static [Field] = &[required("project", Kind::String), required("public_key", Kind::String)];
+}
+
+// Payload of `signing-key-generate` and `signing-key-delete`
+#[derive(Serialize, Deserialize, TS)]
+pub struct SigningKeyRequest {
+    pub name: String,
+}
+
+impl Schema for SigningKeyRequest {
+    const FIELDS: &'static [Field] = &[required("name", Kind::String)];
+}
+
+// Payload of `signing-key-import`, the private key as PEM
+#[derive(Serialize, Deserialize, TS)]
+pub struct SigningKeyImportRequest {
+    pub name: String,
+    pub private_key: String,
+}
+
+impl Schema for SigningKeyImportRequest {
+    const FIELDS: &'static [Field] = &[required("name", Kind::String), required("private_key", Kind::String)];
+}
+
+// Payload of `project-get` and `project-delete`
+#[derive(Serialize, Deserialize, TS)]
+pub struct ProjectNameRequest {
+    pub name: String,
+}
+
+impl Schema for ProjectNameRequest {
+    const FIELDS: &'static [Field] = &[required("name", Kind::String)];
+}
+
+// Payload of `firmware-publish`, to the default channel unless one is named
+#[derive(Serialize, Deserialize, TS)]
+pub struct FirmwarePublishRequest {
+    pub build_id: String,
+    pub version: String,
+    #[ts(optional)]
+    pub channel: Option<String>,
+}
+
+impl Schema for FirmwarePublishRequest {
+    const FIELDS: &'static [Field] = &[
+        required("build_id", Kind::String),
+        required("version", Kind::String),
+        optional("channel", Kind::String),
+    ];
+}
+
+// Payload of `firmware-list`
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct ReleasesQuery {
+    #[ts(optional)]
+    pub channel: Option<String>,
+}
+
+impl Schema for ReleasesQuery {
+    const FIELDS: &'static [Field] = &[optional("channel", Kind::String)];
+}
+
+// Payload of `firmware-assign`
+#[derive(Serialize, Deserialize, TS)]
+pub struct FirmwareAssignRequest {
+    pub mac: String,
+    pub channel: String,
+}
+
+impl Schema for FirmwareAssignRequest {
+    const FIELDS: &'static [Field] = &[required("mac", Kind::String), required("channel", Kind::String)];
+}
+
+// Payload of `device-register`
+#[derive(Serialize, Deserialize, TS)]
+pub struct DeviceRequest {
+    pub mac: String,
+    #[ts(optional)]
+    pub name: Option<String>,
+    #[ts(optional)]
+    pub group: Option<String>,
+}
+
+impl Schema for DeviceRequest {
+    const FIELDS: &'static [Field] = &[
+        required("mac", Kind::String),
+        optional("name", Kind::String),
+        optional("group", Kind::String),
+    ];
+}
+
+// Payload of `device-list`, the devices of one group or all of them
+#[derive(Serialize, Deserialize, Default, TS)]
+pub struct DeviceQuery {
+    #[ts(optional)]
+    pub group: Option<String>,
+}
+
+impl Schema for DeviceQuery {
+    const FIELDS: &'static [Field] = &[optional("group", Kind::String)];
+}
+
+// Payload of `device-delete`
+#[derive(Serialize, Deserialize, TS)]
+pub struct DeviceDeleteRequest {
+    pub mac: String,
+}
+
+impl Schema for DeviceDeleteRequest {
+    const FIELDS: &'static [Field] = &[required("mac", Kind::String)];
+}
+
+// Payload of `rollout-set`: a version of a channel to `percentage` of a group, all of it by default
+#[derive(Serialize, Deserialize, TS)]
+pub struct RolloutRequest {
+    pub group: String,
+    pub version: String,
+    #[ts(optional)]
+    pub channel: Option<String>,
+    #[ts(optional, type = "number")]
+    pub percentage: Option<u64>,
+}
+
+impl Schema for RolloutRequest {
+    const FIELDS: &'static [Field] = &[
+        required("group", Kind::String),
+        required("version", Kind::String),
+        optional("channel", Kind::String),
+        optional("percentage", Kind::Unsigned),
+    ];
+
+    fn check(&self) -> Vec<FieldError> {
+        match self.percentage.is_some_and(|percentage| percentage > 100) {
+            true => vec![invalid("percentage", "Percentage must be between 0 and 100")],
+            false => Vec::new(),
+        }
+    }
+}
+
+// Payload of `rollout-status` and `rollout-delete`
+#[derive(Serialize, Deserialize, TS)]
+pub struct RolloutGroupRequest {
+    pub group: String,
+}
+
+impl Schema for RolloutGroupRequest {
+    const FIELDS: &'static [Field] = &[required("group", Kind::String)];
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::process::Command as TokioCommand;
+use crate::models::*;
+use crate::compiler::{ get_arduino_cli_path, hide_console, is_safe_name, port_name, server_data_dir };
+use crate::resources::{ acquire, release, ResourceKind };
+
+// Serial monitor sessions are recorded to disk and linked to the firmware last flashed to
+// their port, so a build can be traced to the runtime logs observed after flashing it.
+
+// Recordings stop growing past this size, the stream to the client continues
+const MAX_RECORDING_BYTES: u64 = 16 * 1024 * 1024;
+const DEFAULT_BAUD: u32 = 115_200;
+
+// Firmware last flashed to a port by this server
+#[derive(Clone)]
+struct Flash {
+    build_id: Option<String>,
+    sketch_path: String,
+    flashed_at: u64,
+}
+
+static LAST_FLASHED: LazyLock<Mutex<HashMap<String, Flash>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+// Running monitors: recording id -> (owner socket, serial port resource id)
+static ACTIVE: LazyLock<Mutex<HashMap<String, (String, u64)>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Recording {
+    pub id: String,
+    pub port: String,
+    pub baud: u32,
+    pub started_at: u64,
+    #[serde(default)]
+    pub ended_at: Option<u64>,
+    #[serde(default)]
+    pub bytes: u64,
+    // Firmware running on the device when the recording started
+    #[serde(default)]
+    pub build_id: Option<String>,
+    #[serde(default)]
+    pub sketch_path: Option<String>,
+    #[serde(default)]
+    pub flashed_at: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn recordings_root() -> PathBuf {
+    server_data_dir().join("recordings")
+}
+
+fn write_metadata(recording: &Recording) -> Result<(), String> {
+    std::fs::create_dir_all(recordings_root()).map_err(|e| e.to_string())?;
+    let json = serde_json::to_vec_pretty(recording).map_err(|e| e.to_string())?;
+    std::fs::write(recordings_root().join(format!("{}.json", recording.id)), json).map_err(|e| e.to_string())
+}
+
+// Remember what was flashed to `port`, for the recordings that follow
+pub fn record_flash(port: &str, build_id: Option<String>, sketch_path: &str) {
+    LAST_FLASHED.lock()
+        .unwrap()
+        .insert(port.to_string(), Flash { build_id, sketch_path: sketch_path.to_string(), flashed_at: now() });
+}
+
+// Path of the log of a recording
+pub fn recording_log(id: &str) -> Option<PathBuf> {
+    if !is_safe_name(id) {
+        return None;
+    }
+    let path = recordings_root().join(format!("{}.log", id));
+    path.is_file().then_some(path)
+}
+
+// Recordings linked to a build and/or made on a port, newest first
+pub fn list_recordings(build_id: Option<&str>, port: Option<&str>) -> Vec<Recording> {
+    let port = port.map(port_name);
+    let mut recordings: Vec<Recording> = std::fs
+        ::read_dir(recordings_root())
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().ends_with(".json"))
+                .filter_map(|e| std::fs::read(e.path()).ok())
+                .filter_map(|json| serde_json::from_slice::<Recording>(&json).ok())
+                .filter(|r| build_id.is_none() || r.build_id.as_deref() == build_id)
+                .filter(|r| port.as_ref().is_none_or(|port| r.port == *port))
+                .collect()
+        })
+        .unwrap_or_default();
+    recordings.sort_by_key(|r| std::cmp::Reverse(r.started_at));
+    recordings
+}
+
+// Create the recording for a new monitor session on `request.port`
+pub fn start_recording(request: &MonitorRequest) -> Result<Recording, String> {
+    let port = port_name(&request.port);
+    let flash = LAST_FLASHED.lock().unwrap().get(&port).cloned();
+    let recording = Recording {
+        id: uuid::Uuid::new_v4().to_string(),
+        port,
+        baud: request.baud.unwrap_or(DEFAULT_BAUD),
+        started_at: now(),
+        ended_at: None,
+        bytes: 0,
+        build_id: flash.as_ref().and_then(|f| f.build_id.clone()),
+        sketch_path: flash.as_ref().map(|f| f.sketch_path.clone()),
+        flashed_at: flash.map(|f| f.flashed_at),
+    };
+    write_metadata(&recording)?;
+    Ok(recording)
+}
+
+// Stream the port into the recording until the monitor exits or is stopped. `on_data`
+// receives every chunk read from the board.
+pub async fn run_monitor(
+    mut recording: Recording,
+    owner: &str,
+    mut on_data: impl FnMut(&[u8])
+) -> Result<Recording, String> {
+    let serial_port = acquire(ResourceKind::SerialPort, recording.port.clone());
+    ACTIVE.lock()
+        .unwrap()
+        .insert(recording.id.clone(), (owner.to_string(), serial_port.id()));
+
+    let result = serial_port.scope(async {
+        let args = vec![
+            "monitor".to_string(),
+            "--port".to_string(),
+            recording.port.clone(),
+            "--config".to_string(),
+            format!("baudrate={}", recording.baud),
+            "--quiet".to_string(),
+        ];
+        let process_guard = acquire(ResourceKind::Process, format!("monitor {}", recording.port));
+        // stdin stays open: arduino-cli ends the monitor when its input closes
+        let mut command = TokioCommand::new(get_arduino_cli_path());
+        command.args(&args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).kill_on_drop(true);
+        hide_console(&mut command);
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("Failed to start monitor: {}", e))?;
+        let mut stdout = child.stdout.take().ok_or("Monitor has no output")?;
+
+        let log_path = recordings_root().join(format!("{}.log", recording.id));
+        let mut log = tokio::fs::File::create(&log_path).await.map_err(|e| e.to_string())?;
+        let mut buffer = vec![0u8; 4096];
+        loop {
+            let read = tokio::select! {
+                read = stdout.read(&mut buffer) => read.map_err(|e| e.to_string())?,
+                _ = process_guard.cancelled() => 0,
+            };
+            if read == 0 {
+                break;
+            }
+            if recording.bytes < MAX_RECORDING_BYTES {
+                log.write_all(&buffer[..read]).await.map_err(|e| e.to_string())?;
+            }
+            recording.bytes += read as u64;
+            on_data(&buffer[..read]);
+        }
+        log.flush().await.ok();
+        Ok::<(), String>(())
+    }).await;
+
+    ACTIVE.lock().unwrap().remove(&recording.id);
+    recording.ended_at = Some(now());
+    write_metadata(&recording)?;
+    result.map(|_| recording)
+}
+
+// Stop a running monitor, only its owner may stop it
+pub fn stop_monitor(id: &str, owner: &str) -> Result<(), String> {
+    let active = ACTIVE.lock().unwrap().get(id).cloned();
+    match active {
+        Some((started_by, resource)) if started_by == owner => {
+            release(resource);
+            Ok(())
+        }
+        _ => Err(format!("No running monitor {}", id)),
+    }
+}
@@ -0,0 +1,72 @@
+// MessagePack framing for Socket.IO, opted in with `CLOUD_COMPILER_MSGPACK`. The same
+// namespaces and events are served on a second path with socket.io-msgpack-parser framing, where
+// file contents, archives and serial data travel as raw binary instead of base64 in JSON.
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use serde::{ de::DeserializeOwned, Serialize };
+use socketioxide::handler::Value;
+
+// `path` of the Socket.IO client for MessagePack framing
+pub const PATH: &str = "/socket.io-msgpack";
+
+// Marks sockets connected with MessagePack framing, in their extensions
+#[derive(Clone, Copy)]
+pub struct MessagePack;
+
+// Bytes serialized as a MessagePack binary
+pub struct Binary<'a>(pub &'a [u8]);
+
+impl Serialize for Binary<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+pub fn enabled() -> bool {
+    std::env::var("CLOUD_COMPILER_MSGPACK").is_ok_and(|v| v == "1" || v == "true")
+}
+
+// Replace binaries with base64 strings, true if there were any
+fn inline(value: &mut rmpv::Value) -> bool {
+    match value {
+        rmpv::Value::Binary(bytes) => {
+            *value = rmpv::Value::from(BASE64.encode(bytes));
+            true
+        }
+        // Every item is visited, `any` would stop at the first binary
+        rmpv::Value::Array(items) => items.iter_mut().map(inline).filter(|found| *found).count() > 0,
+        rmpv::Value::Map(entries) => entries.iter_mut().map(|(_, entry)| inline(entry)).filter(|found| *found).count() > 0,
+        _ => false,
+    }
+}
+
+// Event data with its binaries as base64 strings, which handlers read like the content of files
+// sent as JSON. Events without binaries, and JSON framed events, are left as they are.
+pub fn inline_binaries(data: Value) -> Value {
+    let Value::Bytes(bytes) = &data else {
+        return data;
+    };
+    let Ok(mut decoded) = rmpv::decode::read_value(&mut bytes.as_ref()) else {
+        return data;
+    };
+    if !inline(&mut decoded) {
+        return data;
+    }
+    let mut encoded = Vec::with_capacity(bytes.len() * 4 / 3);
+    match rmpv::encode::write_value(&mut encoded, &decoded) {
+        Ok(()) => Value::Bytes(encoded.into()),
+        Err(_) => data,
+    }
+}
+
+// A MessagePack message of the WebSocket protocol, its binaries read as base64 strings like
+// `inline_binaries` leaves them
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    let mut decoded = rmpv::decode::read_value(&mut &bytes[..]).map_err(|e| e.to_string())?;
+    inline(&mut decoded);
+    rmpv::ext::from_value(decoded).map_err(|e| e.to_string())
+}
+
+// Structs go out as maps, like their JSON
+pub fn encode<T: ?Sized + Serialize>(message: &T) -> Result<Vec<u8>, String> {
+    rmp_serde::to_vec_named(message).map_err(|e| e.to_string())
+}
@@ -0,0 +1,138 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+use rumqttc::{ AsyncClient, Event, MqttOptions, QoS };
+use serde::Serialize;
+use serde_json::json;
+use tracing::{ info, warn };
+use crate::models::*;
+use crate::devices::Rollout;
+use crate::firmware::Release;
+
+// Optional MQTT notifications, enabled by `CLOUD_COMPILER_MQTT_URL`
+// (`mqtt://broker:1883`, `mqtts://...`). Topics live under `CLOUD_COMPILER_MQTT_PREFIX`:
+//   <prefix>/builds/<build_id>         job lifecycle (queued, building, success, failure)
+//   <prefix>/firmware/<channel>        a release was published on the channel
+//   <prefix>/devices/<mac>/firmware    new firmware is available for a device
+const DEFAULT_PREFIX: &str = "arduino-cloud-compiler";
+
+static CLIENT: OnceLock<AsyncClient> = OnceLock::new();
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Building,
+    Success,
+    Failure,
+}
+
+fn prefix() -> String {
+    std::env::var("CLOUD_COMPILER_MQTT_PREFIX").unwrap_or_else(|_| DEFAULT_PREFIX.to_string())
+}
+
+// Base URL clients and devices reach the server on, for links in notifications
+pub fn public_url() -> String {
+    std::env
+        ::var("CLOUD_COMPILER_PUBLIC_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_default()
+}
+
+// Connect to the broker if one is configured, reconnecting in the background
+pub fn init() {
+    let Ok(url) = std::env::var("CLOUD_COMPILER_MQTT_URL") else {
+        return;
+    };
+    // The client id is part of the URL for rumqttc, generate one if it is missing
+    let url = match url.contains("client_id=") {
+        true => url,
+        false => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!("{}{}client_id=cloud-compiler-{}", url, separator, uuid::Uuid::new_v4().simple())
+        }
+    };
+    let mut options = match MqttOptions::parse_url(url) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Invalid MQTT URL, notifications disabled: {}", e);
+            return;
+        }
+    };
+    options.set_keep_alive(Duration::from_secs(30));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    CLIENT.set(client).ok();
+    tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(rumqttc::Packet::ConnAck(_))) => info!("Connected to MQTT broker"),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+}
+
+// Publish without waiting, notifications are dropped while the broker is unreachable
+fn publish(topic: &str, payload: serde_json::Value) {
+    let Some(client) = CLIENT.get() else {
+        return;
+    };
+    let topic = format!("{}/{}", prefix(), topic);
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, false, payload.to_string()) {
+        warn!("Dropped MQTT notification: {}", e);
+    }
+}
+
+pub fn build_event(build_id: &str, owner: &str, status: JobStatus, response: Option<&CommandResponse>) {
+    let base = public_url();
+    let artifacts: Vec<serde_json::Value> = response
+        .map(|r| r.artifacts.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|a| {
+            json!({
+                "name": a.name,
+                "size": a.size,
+                "url": a.url.clone().unwrap_or_else(|| format!("{}/builds/{}/artifacts/{}", base, build_id, a.name)),
+            })
+        })
+        .collect();
+    publish(
+        &format!("builds/{}", build_id),
+        json!({
+            "build_id": build_id,
+            "owner": owner,
+            "status": status,
+            "error": response.and_then(|r| r.error.as_deref()).filter(|_| matches!(status, JobStatus::Failure)),
+            "artifacts": artifacts,
+        })
+    );
+}
+
+fn release_payload(release: &Release) -> serde_json::Value {
+    json!({
+        "channel": release.channel,
+        "version": release.version,
+        "md5": release.md5,
+        "size": release.size,
+        "url": format!("{}/firmware/{}", public_url(), release.channel),
+    })
+}
+
+pub fn release_published(release: &Release) {
+    publish(&format!("firmware/{}", release.channel), release_payload(release));
+}
+
+// Tell the devices a rollout selected that their update is ready
+pub fn rollout_targets(rollout: &Rollout, release: &Release, macs: &[String]) {
+    let mut payload = release_payload(release);
+    payload["group"] = json!(rollout.group);
+    payload["url"] = json!(format!("{}/firmware", public_url()));
+    for mac in macs {
+        publish(&format!("devices/{}/firmware", mac.replace(':', "")), payload.clone());
+    }
+}
@@ -0,0 +1,158 @@
+use std::path::{ Path, PathBuf };
+use serde_json::Value;
+use crate::models::*;
+use crate::artifacts::{ attach_artifacts, build_dir, new_build_dir };
+use crate::compiler::{ error_response, run_program };
+use crate::esptool::{ flash_layout, write_flash };
+use crate::partitions::{ nvs_partition, read_build_partitions };
+
+// NVS partitions are made of 4 KiB pages and need at least three of them
+const NVS_PAGE_SIZE: u32 = 0x1000;
+const NVS_MIN_SIZE: u32 = 0x3000;
+// Offset of the `nvs` partition in the stock arduino-esp32 partition schemes
+const DEFAULT_NVS_OFFSET: u32 = 0x9000;
+
+#[cfg(target_os = "windows")]
+const PYTHON: &str = "python";
+#[cfg(not(target_os = "windows"))]
+const PYTHON: &str = "python3";
+
+// Arguments that start the generator: the IDF script if on PATH, else the pip package
+fn generator_args() -> Vec<String> {
+    let script = std::env::var_os("PATH").and_then(|path| {
+        std::env::split_paths(&path)
+            .map(|dir| dir.join("nvs_partition_gen.py"))
+            .find(|candidate| candidate.is_file())
+    });
+    match script {
+        Some(script) => vec![script.to_string_lossy().to_string()],
+        None => vec!["-m".to_string(), "esp_idf_nvs_partition_gen".to_string()],
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Render entries in the nvs_partition_gen CSV layout, one namespace row before its keys
+pub fn entries_to_csv(entries: &[NvsEntry]) -> Result<String, String> {
+    let mut csv = String::from("key,type,encoding,value\n");
+    let mut namespace: Option<&str> = None;
+    for entry in entries {
+        if entry.key.is_empty() || entry.key.len() > 15 {
+            return Err(format!("Invalid NVS key: {}", entry.key));
+        }
+        if namespace != Some(entry.namespace.as_str()) {
+            csv.push_str(&format!("{},namespace,,\n", csv_field(&entry.namespace)));
+            namespace = Some(&entry.namespace);
+        }
+        let value = match &entry.value {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => u8::from(*b).to_string(),
+            _ => {
+                return Err(format!("Unsupported value for NVS key {}", entry.key));
+            }
+        };
+        csv.push_str(
+            &format!("{},data,{},{}\n", csv_field(&entry.key), csv_field(&entry.encoding), csv_field(&value))
+        );
+    }
+    Ok(csv)
+}
+
+// Size and offset of the NVS partition, from the build if there is one
+fn nvs_layout(request: &NvsRequest, dir: Option<&Path>) -> Result<(u32, u32), String> {
+    if let Some(dir) = dir {
+        let partitions = read_build_partitions(dir)?;
+        let partition = nvs_partition(&partitions).ok_or("The partition scheme has no NVS partition")?;
+        return Ok((partition.size, partition.offset));
+    }
+    let size = request.size.ok_or("Either build_id or size is required")?;
+    if size < NVS_MIN_SIZE || size % NVS_PAGE_SIZE != 0 {
+        return Err("NVS size must be a multiple of 0x1000 and at least 0x3000".to_string());
+    }
+    Ok((size, request.offset.unwrap_or(DEFAULT_NVS_OFFSET)))
+}
+
+// Generate an NVS partition image, optionally flashing it at the partition offset
+pub async fn generate_nvs(request: &NvsRequest) -> CommandResponse {
+    let fail = |message: &str| error_response("generate-nvs", vec![], message);
+
+    let build: Option<(String, PathBuf)> = match &request.build_id {
+        Some(build_id) =>
+            match build_dir(build_id) {
+                Some(dir) => Some((build_id.clone(), dir)),
+                None => {
+                    return fail("Unknown build");
+                }
+            }
+        None => None,
+    };
+    let (size, offset) = match nvs_layout(request, build.as_ref().map(|(_, dir)| dir.as_path())) {
+        Ok(layout) => layout,
+        Err(e) => {
+            return fail(&e);
+        }
+    };
+    let csv = match (&request.csv, request.entries.is_empty()) {
+        (Some(csv), _) => csv.clone(),
+        (None, false) =>
+            match entries_to_csv(&request.entries) {
+                Ok(csv) => csv,
+                Err(e) => {
+                    return fail(&e);
+                }
+            }
+        (None, true) => {
+            return fail("Missing NVS csv or entries");
+        }
+    };
+
+    // Without a build the image gets a build directory of its own
+    let (build_id, dir) = match build {
+        Some(build) => build,
+        None =>
+            match new_build_dir() {
+                Ok(build) => build,
+                Err(e) => {
+                    return fail(&e.to_string());
+                }
+            }
+    };
+    let csv_path = dir.join("nvs.csv");
+    let image = dir.join("nvs.bin");
+    if let Err(e) = std::fs::write(&csv_path, csv) {
+        return fail(&e.to_string());
+    }
+
+    let mut args = generator_args();
+    args.extend([
+        "generate".to_string(),
+        csv_path.to_string_lossy().to_string(),
+        image.to_string_lossy().to_string(),
+        format!("0x{:x}", size),
+    ]);
+    let mut response = run_program(Path::new(PYTHON), "generate-nvs", &args).await;
+    // The CSV may hold credentials, don't keep it around
+    let _ = std::fs::remove_file(&csv_path);
+
+    if response.success && let Some(port) = &request.port {
+        let chip = match (&request.chip, flash_layout(&dir)) {
+            (Some(chip), _) => chip.clone(),
+            (None, Ok((chip, _))) => chip.to_string(),
+            (None, Err(_)) => "auto".to_string(),
+        };
+        let flashed = write_flash(port, &chip, offset, &image).await;
+        response.success = flashed.success;
+        response.output.push_str(&flashed.output);
+        response.error = flashed.error.or(response.error);
+    }
+
+    attach_artifacts(&mut response, &build_id, &dir).await;
+    response
+}
@@ -0,0 +1,163 @@
+// Live tool output. What the main arduino-cli run of a job prints goes, as it arrives, to the
+// job's log, besides being collected for the response, so a client can follow a long compile
+// instead of waiting for the whole log at the end. The log feeds both transports: the job's sink
+// (`compile-output` events, gRPC output lines) and the subscribers of `GET /jobs/{id}/logs`. Only
+// runs wrapped in `live` are logged, not the property and version lookups around them. Runs on
+// sketches with build secrets go through `live_masked`, which masks them in every line before it
+// reaches the log or the sink.
+use std::collections::{ HashMap, VecDeque };
+use std::future::Future;
+use std::sync::{ Arc, LazyLock, Mutex };
+use tokio::sync::broadcast;
+use crate::keepalive;
+use crate::models::{ LogLine, LogStream };
+use crate::resources::current_job_id;
+use crate::secrets::{ Mask, Secrets };
+
+pub type Sink = Arc<dyn Fn(LogStream, &str) + Send + Sync>;
+
+// Backlog replayed to subscribers that join late, the oldest lines dropped past it
+const BACKLOG_BYTES: usize = 1024 * 1024;
+// Lines a subscriber may fall behind by before it skips ahead
+const SUBSCRIBER_BUFFER: usize = 1024;
+// Logs of finished jobs kept for clients that connect after the end
+const FINISHED_KEPT: usize = 64;
+
+tokio::task_local! {
+    // The sink of the job
+    static REQUESTED: Option<Sink>;
+    // Set while a live run is in progress
+    static LIVE: bool;
+    // Secrets of the live run, masked in its output
+    static MASKED: Arc<Mask>;
+}
+
+struct JobLog {
+    lines: VecDeque<LogLine>,
+    bytes: usize,
+    // Dropped once the job finishes, which ends the subscriptions
+    sender: Option<broadcast::Sender<LogLine>>,
+}
+
+#[derive(Default)]
+struct Logs {
+    jobs: HashMap<String, JobLog>,
+    // Finished jobs, oldest first
+    finished: VecDeque<String>,
+}
+
+static LOGS: LazyLock<Mutex<Logs>> = LazyLock::new(Default::default);
+
+// The log of a running job, closed when dropped
+pub struct LogGuard {
+    job_id: String,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        let mut logs = LOGS.lock().unwrap();
+        let Some(log) = logs.jobs.get_mut(&self.job_id) else {
+            return;
+        };
+        log.sender = None;
+        logs.finished.push_back(self.job_id.clone());
+        while logs.finished.len() > FINISHED_KEPT {
+            if let Some(oldest) = logs.finished.pop_front() {
+                logs.jobs.remove(&oldest);
+            }
+        }
+    }
+}
+
+// Start the log of the job `job_id`
+pub fn open(job_id: &str) -> LogGuard {
+    let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+    let log = JobLog { lines: VecDeque::new(), bytes: 0, sender: Some(sender) };
+    LOGS.lock().unwrap().jobs.insert(job_id.to_string(), log);
+    LogGuard { job_id: job_id.to_string() }
+}
+
+// What the job logged so far, and the lines still to come while it runs. `None` for jobs that
+// are unknown or finished too long ago.
+pub fn follow(job_id: &str) -> Option<(Vec<LogLine>, Option<broadcast::Receiver<LogLine>>)> {
+    let logs = LOGS.lock().unwrap();
+    let log = logs.jobs.get(job_id)?;
+    Some((log.lines.iter().cloned().collect(), log.sender.as_ref().map(|sender| sender.subscribe())))
+}
+
+// Run the job `fut` with the output of its live runs also going to `sink`, if any
+pub fn scope<F: Future>(sink: Option<Sink>, fut: F) -> impl Future<Output = F::Output> {
+    REQUESTED.scope(sink, fut)
+}
+
+// Run `fut` with its tool output logged
+pub fn live<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    LIVE.scope(true, fut)
+}
+
+// Run `fut` with its tool output logged, the values of `secrets` masked in it
+pub fn live_masked<F: Future>(secrets: &Secrets, fut: F) -> impl Future<Output = F::Output> {
+    MASKED.scope(Arc::new(Mask::new(secrets)), LIVE.scope(true, fut))
+}
+
+// Run `fut` with its tool output kept out of the job's log, for runs the server makes for itself
+pub fn quiet<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    LIVE.scope(false, fut)
+}
+
+fn publish(job_id: &str, line: &LogLine) {
+    let mut logs = LOGS.lock().unwrap();
+    let Some(log) = logs.jobs.get_mut(job_id) else {
+        return;
+    };
+    if let Some(sender) = &log.sender {
+        // Nobody following is fine
+        sender.send(line.clone()).ok();
+    }
+    log.bytes += line.text.len();
+    log.lines.push_back(line.clone());
+    while log.bytes > BACKLOG_BYTES {
+        match log.lines.pop_front() {
+            Some(dropped) => log.bytes -= dropped.text.len(),
+            None => break,
+        }
+    }
+}
+
+// Pass `text` printed on `stream` to the log of the current run's job
+pub fn forward(stream: LogStream, text: &str) {
+    if !LIVE.try_with(|live| *live).unwrap_or(false) {
+        return;
+    }
+    let mut text = text.to_string();
+    MASKED.try_with(|mask| mask.apply(&mut text)).ok();
+    if let Some(job_id) = current_job_id() {
+        publish(&job_id, &LogLine { stream, text: text.clone() });
+    }
+    REQUESTED.try_with(|sink| {
+        if let Some(sink) = sink {
+            sink(stream, &text);
+            keepalive::activity();
+        }
+    }).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn live_output_of_masked_runs_hides_secrets() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink: Sink = {
+            let lines = lines.clone();
+            Arc::new(move |_, text| lines.lock().unwrap().push(text.to_string()))
+        };
+        let secrets = Secrets::from([("API_TOKEN".to_string(), "tok-123456".to_string())]);
+        scope(Some(sink), live_masked(&secrets, async {
+            forward(LogStream::Stderr, "secrets.h:3: note: #define API_TOKEN \"tok-123456\"\n");
+        })).await;
+
+        assert_eq!(*lines.lock().unwrap(), ["secrets.h:3: note: #define API_TOKEN \"[secret]\"\n"]);
+    }
+}
@@ -0,0 +1,272 @@
+use std::path::Path;
+use serde::Serialize;
+use crate::artifacts::find_app_binary;
+
+// Binary partition table layout (ESP-IDF gen_esp32part.py)
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+const ENTRY_SIZE: usize = 32;
+const ENTRY_MAGIC: [u8; 2] = [0xaa, 0x50];
+
+pub const TYPE_APP: u8 = 0x00;
+pub const TYPE_DATA: u8 = 0x01;
+pub const SUBTYPE_OTADATA: u8 = 0x00;
+pub const SUBTYPE_NVS: u8 = 0x02;
+pub const SUBTYPE_SPIFFS: u8 = 0x82;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Partition {
+    pub label: String,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+}
+
+// Parse a `partitions.bin`, stopping at the MD5 entry or the first blank entry
+pub fn parse_partition_table(table: &[u8]) -> Vec<Partition> {
+    table
+        .chunks_exact(ENTRY_SIZE)
+        .take_while(|entry| entry[..2] == ENTRY_MAGIC)
+        .map(|entry| {
+            let label = &entry[12..28];
+            let end = label
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(label.len());
+            Partition {
+                label: String::from_utf8_lossy(&label[..end]).to_string(),
+                kind: entry[2],
+                subtype: entry[3],
+                offset: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+                size: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+            }
+        })
+        .collect()
+}
+
+// The partition the bootloader boots first: factory if present, otherwise the first app slot
+pub fn boot_app_partition(partitions: &[Partition]) -> Option<&Partition> {
+    partitions
+        .iter()
+        .find(|p| p.kind == TYPE_APP && p.subtype == 0x00)
+        .or_else(|| partitions.iter().find(|p| p.kind == TYPE_APP))
+}
+
+pub fn otadata_partition(partitions: &[Partition]) -> Option<&Partition> {
+    partitions.iter().find(|p| p.kind == TYPE_DATA && p.subtype == SUBTYPE_OTADATA)
+}
+
+// Partition table of a finished build
+pub fn read_build_partitions(build_dir: &Path) -> Result<Vec<Partition>, String> {
+    let app = find_app_binary(build_dir).ok_or("No application binary produced")?;
+    let table = std::fs::read(app.with_extension("partitions.bin")).map_err(|_| "No partition table produced")?;
+    Ok(parse_partition_table(&table))
+}
+
+// The data partition LittleFS and SPIFFS images are written to
+pub fn filesystem_partition(partitions: &[Partition]) -> Option<&Partition> {
+    partitions.iter().find(|p| p.kind == TYPE_DATA && p.subtype == SUBTYPE_SPIFFS)
+}
+
+// The default NVS partition, where Preferences and the WiFi stack keep their data
+pub fn nvs_partition(partitions: &[Partition]) -> Option<&Partition> {
+    partitions
+        .iter()
+        .find(|p| p.kind == TYPE_DATA && p.subtype == SUBTYPE_NVS && p.label == "nvs")
+        .or_else(|| partitions.iter().find(|p| p.kind == TYPE_DATA && p.subtype == SUBTYPE_NVS))
+}
+
+// First offset available to partitions, the table itself lives at 0x8000
+const FIRST_PARTITION_OFFSET: u32 = PARTITION_TABLE_OFFSET + 0x1000;
+const APP_ALIGNMENT: u32 = 0x10000;
+const DATA_ALIGNMENT: u32 = 0x1000;
+const MAX_LABEL_LEN: usize = 16;
+
+fn parse_type(value: &str) -> Option<u8> {
+    match value {
+        "app" => Some(TYPE_APP),
+        "data" => Some(TYPE_DATA),
+        _ => parse_number(value).and_then(|n| u8::try_from(n).ok()),
+    }
+}
+
+fn parse_subtype(kind: u8, value: &str) -> Option<u8> {
+    let named = match (kind, value) {
+        (TYPE_APP, "factory") => Some(0x00),
+        (TYPE_APP, "test") => Some(0x20),
+        (TYPE_APP, ota) if ota.starts_with("ota_") => {
+            ota[4..]
+                .parse::<u8>()
+                .ok()
+                .filter(|n| *n < 16)
+                .map(|n| 0x10 + n)
+        }
+        (TYPE_DATA, "ota") => Some(SUBTYPE_OTADATA),
+        (TYPE_DATA, "phy") => Some(0x01),
+        (TYPE_DATA, "nvs") => Some(SUBTYPE_NVS),
+        (TYPE_DATA, "coredump") => Some(0x03),
+        (TYPE_DATA, "nvs_keys") => Some(0x04),
+        (TYPE_DATA, "efuse") => Some(0x05),
+        (TYPE_DATA, "undefined") => Some(0x06),
+        (TYPE_DATA, "esphttpd") => Some(0x80),
+        (TYPE_DATA, "fat") => Some(0x81),
+        (TYPE_DATA, "spiffs") => Some(SUBTYPE_SPIFFS),
+        (TYPE_DATA, "littlefs") => Some(0x83),
+        _ => None,
+    };
+    named.or_else(|| parse_number(value).and_then(|n| u8::try_from(n).ok()))
+}
+
+// Numbers as written in partition CSVs: 0x9000, 36864, 20K, 1M
+pub fn parse_number(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last()? {
+        'K' | 'k' => (&value[..value.len() - 1], 1024),
+        'M' | 'm' => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    let number = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u32>().ok()?,
+    };
+    number.checked_mul(multiplier)
+}
+
+// Parse and validate a partitions.csv, filling in omitted offsets the way gen_esp32part.py does.
+// `flash_size` bounds the layout when known.
+pub fn parse_partition_csv(csv: &str, flash_size: Option<u32>) -> Result<Vec<Partition>, String> {
+    let mut partitions: Vec<Partition> = Vec::new();
+    let mut next_offset = FIRST_PARTITION_OFFSET;
+
+    for (index, line) in csv.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() < 5 {
+            return Err(format!("Line {}: expected name, type, subtype, offset, size", line_no));
+        }
+
+        let label = fields[0].to_string();
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(format!("Line {}: partition name must be 1-{} characters", line_no, MAX_LABEL_LEN));
+        }
+        if partitions.iter().any(|p| p.label == label) {
+            return Err(format!("Line {}: duplicate partition name {}", line_no, label));
+        }
+        let kind = parse_type(fields[1]).ok_or_else(||
+            format!("Line {}: unknown partition type {}", line_no, fields[1])
+        )?;
+        let subtype = parse_subtype(kind, fields[2]).ok_or_else(||
+            format!("Line {}: unknown subtype {} for type {}", line_no, fields[2], fields[1])
+        )?;
+        let alignment = if kind == TYPE_APP { APP_ALIGNMENT } else { DATA_ALIGNMENT };
+
+        let offset = match fields[3] {
+            "" => next_offset.div_ceil(alignment) * alignment,
+            value => parse_number(value).ok_or_else(|| format!("Line {}: invalid offset {}", line_no, value))?,
+        };
+        if offset % alignment != 0 {
+            return Err(
+                format!("Line {}: {} must be aligned to 0x{:x} (offset 0x{:x})", line_no, label, alignment, offset)
+            );
+        }
+        if offset < FIRST_PARTITION_OFFSET {
+            return Err(format!("Line {}: {} overlaps the partition table at 0x8000", line_no, label));
+        }
+        let size = parse_number(fields[4])
+            .filter(|size| *size > 0)
+            .ok_or_else(|| format!("Line {}: invalid size {}", line_no, fields[4]))?;
+        let end = offset.checked_add(size).ok_or_else(|| format!("Line {}: {} is too large", line_no, label))?;
+
+        if let Some(other) = partitions.iter().find(|p| offset < p.offset + p.size && p.offset < end) {
+            return Err(format!("Line {}: {} overlaps partition {}", line_no, label, other.label));
+        }
+        if let Some(flash_size) = flash_size && end > flash_size {
+            return Err(
+                format!(
+                    "Line {}: {} ends at 0x{:x}, beyond the 0x{:x} byte flash",
+                    line_no,
+                    label,
+                    end,
+                    flash_size
+                )
+            );
+        }
+
+        next_offset = end;
+        partitions.push(Partition { label, kind, subtype, offset, size });
+    }
+
+    if !partitions.iter().any(|p| p.kind == TYPE_APP) {
+        return Err("Partition table has no app partition".to_string());
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT_TABLE: &str = "\
+# Name,   Type, SubType, Offset,  Size
+nvs,      data, nvs,     ,        0x5000
+otadata,  data, ota,     ,        0x2000
+
+app0,     app,  ota_0,   ,        1M
+spiffs,   data, spiffs,  ,        64K  # trailing comment
+";
+
+    #[test]
+    fn fills_in_offsets_like_gen_esp32part() {
+        let partitions = parse_partition_csv(DEFAULT_TABLE, Some(4 * 1024 * 1024)).unwrap();
+        let layout: Vec<(&str, u8, u8, u32, u32)> = partitions
+            .iter()
+            .map(|p| (p.label.as_str(), p.kind, p.subtype, p.offset, p.size))
+            .collect();
+        assert_eq!(layout, vec![
+            ("nvs", TYPE_DATA, SUBTYPE_NVS, 0x9000, 0x5000),
+            ("otadata", TYPE_DATA, SUBTYPE_OTADATA, 0xe000, 0x2000),
+            // App partitions are aligned to 64K
+            ("app0", TYPE_APP, 0x10, 0x10000, 0x100000),
+            ("spiffs", TYPE_DATA, SUBTYPE_SPIFFS, 0x110000, 0x10000),
+        ]);
+    }
+
+    #[test]
+    fn takes_explicit_offsets_and_numeric_types() {
+        let csv = "factory, 0x00, 0x00, 0x10000, 0x100000\nstorage, 0x01, 0x99, 0x110000, 0x1000\n";
+        let partitions = parse_partition_csv(csv, None).unwrap();
+        assert_eq!(partitions[0].offset, 0x10000);
+        assert_eq!(partitions[1].subtype, 0x99);
+    }
+
+    #[test]
+    fn rejects_invalid_tables() {
+        let invalid = [
+            ("nvs, data, nvs, 0x9000", "Line 1: expected"),
+            ("nvs, data, nvs, 0x9000, 0x5000\nnvs, data, nvs, , 0x5000", "Line 2: duplicate"),
+            ("a_very_long_partition_name, data, nvs, , 0x5000", "Line 1: partition name"),
+            ("nvs, config, nvs, , 0x5000", "Line 1: unknown partition type"),
+            ("app0, app, ota_16, , 1M", "Line 1: unknown subtype"),
+            ("app0, app, factory, 0x11000, 1M", "Line 1: app0 must be aligned"),
+            ("nvs, data, nvs, 0x7000, 0x1000", "Line 1: nvs overlaps the partition table"),
+            ("nvs, data, nvs, 0x9000, 0", "Line 1: invalid size"),
+            ("nvs, data, nvs, 0x9000, 0x5000\nphy, data, phy, 0xa000, 0x1000", "Line 2: phy overlaps partition nvs"),
+            ("nvs, data, nvs, , 0x5000", "Partition table has no app partition"),
+        ];
+        for (csv, error) in invalid {
+            let result = parse_partition_csv(csv, None);
+            assert!(result.as_ref().is_err_and(|e| e.starts_with(error)), "{:?}: {:?}", csv, result.err());
+        }
+    }
+
+    #[test]
+    fn bounds_the_layout_by_the_flash_size() {
+        let error = parse_partition_csv(DEFAULT_TABLE, Some(1024 * 1024)).unwrap_err();
+        assert!(error.starts_with("Line 5: app0 ends at 0x110000"), "{}", error);
+    }
+}
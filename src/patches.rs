@@ -0,0 +1,271 @@
+// Binary placeholder patching. Firmware reserves fixed-size blocks marked with
+// `@@patch:<name>:<size>@@`, and a compile request's `patches` overwrite them in the compiled app
+// image, so one (cached) compile serves any number of personalized devices:
+//
+//   #define CLOUD_PATCH(var, name, size) \
+//     __attribute__((used)) volatile const char var[size] = "@@patch:" name ":" #size "@@"
+//   CLOUD_PATCH(DEVICE_ID, "device_id", 64);
+//
+// `volatile` keeps the compiler from folding the marker text into the code reading it. A block
+// is `<size>` bytes from the start of its marker. Values are strings, written NUL-padded with
+// room for the terminator, or raw bytes (`{hex}` or `{base64}`) zero-padded to the block.
+//
+// The image's checksum and appended SHA-256 are recomputed, and a merged image gets the patched
+// app at the offset it holds it. The ELF keeps the markers.
+use std::path::Path;
+use base64::Engine;
+use serde_json::Value;
+use sha2::{ Digest, Sha256 };
+use crate::artifacts::find_app_binary;
+
+const MARKER: &[u8] = b"@@patch:";
+const MAX_PATCHES: usize = 64;
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+// ESP image layout: header, segments of an 8-byte header and their data, a checksum byte ending a
+// 16-byte aligned block, and optionally the SHA-256 of everything before it
+const IMAGE_MAGIC: u8 = 0xe9;
+const IMAGE_HEADER_SIZE: usize = 24;
+const HASH_APPENDED_OFFSET: usize = 23;
+const CHECKSUM_SEED: u8 = 0xef;
+// Merged images hold the app at a 4 KiB aligned flash offset
+const FLASH_SECTOR: usize = 0x1000;
+
+pub struct Patch {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    // Strings need a NUL terminator inside the block
+    pub text: bool,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-'))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+// The `patches` of a request, empty without
+pub fn from_request(data: &Value) -> Result<Vec<Patch>, String> {
+    let fields = match data.get("patches") {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(Value::Object(fields)) => fields,
+        Some(_) => return Err("patches must be an object of placeholder names to values".to_string()),
+    };
+    if fields.len() > MAX_PATCHES {
+        return Err(format!("At most {} patches per build", MAX_PATCHES));
+    }
+    fields
+        .iter()
+        .map(|(name, value)| {
+            if !valid_name(name) {
+                return Err(format!("Invalid placeholder name: {}", name));
+            }
+            let (bytes, text) = match value {
+                Value::String(text) => (text.as_bytes().to_vec(), true),
+                Value::Object(encoded) =>
+                    match (encoded.get("hex").and_then(|v| v.as_str()), encoded.get("base64").and_then(|v| v.as_str())) {
+                        (Some(hex), None) => (decode_hex(hex).ok_or_else(|| format!("Invalid hex for {}", name))?, false),
+                        (None, Some(encoded)) => (
+                            base64::engine::general_purpose::STANDARD.decode(encoded).map_err(|_| format!("Invalid base64 for {}", name))?,
+                            false,
+                        ),
+                        _ => return Err(format!("Patch {} must be a string, {{hex}} or {{base64}}", name)),
+                    }
+                _ => return Err(format!("Patch {} must be a string, {{hex}} or {{base64}}", name)),
+            };
+            Ok(Patch { name: name.clone(), bytes, text })
+        })
+        .collect()
+}
+
+// Blocks marked `@@patch:<name>:<size>@@` in `image`, as (offset, size)
+fn find_blocks(image: &[u8], name: &str) -> Vec<(usize, usize)> {
+    let prefix = [MARKER, name.as_bytes(), b":"].concat();
+    let mut blocks = Vec::new();
+    let mut at = 0;
+    while let Some(found) = image[at..].windows(prefix.len()).position(|window| window == prefix.as_slice()) {
+        let start = at + found;
+        let digits: Vec<u8> = image[start + prefix.len()..].iter().take_while(|b| b.is_ascii_digit()).copied().collect();
+        let end = start + prefix.len() + digits.len();
+        let size = std::str::from_utf8(&digits).ok().and_then(|digits| digits.parse::<usize>().ok());
+        if let Some(size) = size && image[end..].starts_with(b"@@") && size >= end + 2 - start && size <= MAX_BLOCK_SIZE {
+            blocks.push((start, size));
+        }
+        at = end.max(start + 1);
+    }
+    blocks
+}
+
+// Recompute the checksum and appended hash of an ESP app image after its data changed
+fn reseal(image: &mut [u8]) -> Result<(), String> {
+    if image.len() < IMAGE_HEADER_SIZE || image[0] != IMAGE_MAGIC {
+        return Err("Not an ESP app image".to_string());
+    }
+    let segments = image[1] as usize;
+    let mut offset = IMAGE_HEADER_SIZE;
+    let mut checksum = CHECKSUM_SEED;
+    for _ in 0..segments {
+        let header = image.get(offset..offset + 8).ok_or("Truncated app image")?;
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let data = image.get(offset + 8..offset + 8 + length).ok_or("Truncated app image")?;
+        checksum = data.iter().fold(checksum, |sum, byte| sum ^ byte);
+        offset += 8 + length;
+    }
+    let checksum_at = ((offset + 16) & !15) - 1;
+    *image.get_mut(checksum_at).ok_or("Truncated app image")? = checksum;
+    if image[HASH_APPENDED_OFFSET] == 1 {
+        let hash = Sha256::digest(&image[..=checksum_at]);
+        image
+            .get_mut(checksum_at + 1..checksum_at + 33)
+            .ok_or("Truncated app image")?
+            .copy_from_slice(&hash);
+    }
+    Ok(())
+}
+
+// Write `patches` into `image`, every block of each name
+fn patch_image(image: &mut [u8], patches: &[Patch]) -> Result<(), String> {
+    for patch in patches {
+        let blocks = find_blocks(image, &patch.name);
+        if blocks.is_empty() {
+            return Err(format!("No placeholder {} in the firmware", patch.name));
+        }
+        for (start, size) in blocks {
+            let room = if patch.text { size - 1 } else { size };
+            if patch.bytes.len() > room {
+                return Err(format!("Patch {} is {} bytes, its placeholder holds {}", patch.name, patch.bytes.len(), room));
+            }
+            let block = &mut image[start..start + size];
+            block.fill(0);
+            block[..patch.bytes.len()].copy_from_slice(&patch.bytes);
+        }
+    }
+    reseal(image)
+}
+
+// Patch the app image of a build, and the merged image holding it
+pub fn apply(build_dir: &Path, patches: &[Patch]) -> Result<(), String> {
+    let app = find_app_binary(build_dir).ok_or("No application binary produced")?;
+    let original = std::fs::read(&app).map_err(|e| e.to_string())?;
+    let mut patched = original.clone();
+    patch_image(&mut patched, patches)?;
+
+    let merged_path = app.with_extension("merged.bin");
+    if let Ok(mut merged) = std::fs::read(&merged_path) {
+        let offset = (0..merged.len())
+            .step_by(FLASH_SECTOR)
+            .find(|offset| merged[*offset..].starts_with(&original))
+            .ok_or("The app image was not found in the merged image")?;
+        merged[offset..offset + patched.len()].copy_from_slice(&patched);
+        std::fs::write(&merged_path, merged).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(&app, patched).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    const BLOCK: usize = 32;
+
+    // An app image with one segment holding a `device_id` placeholder, sealed with its hash
+    fn app_image() -> Vec<u8> {
+        let mut data = vec![0x55; 128];
+        let marker = format!("@@patch:device_id:{}@@", BLOCK);
+        data[16..16 + marker.len()].copy_from_slice(marker.as_bytes());
+        let mut image = vec![0; IMAGE_HEADER_SIZE];
+        image[0] = IMAGE_MAGIC;
+        image[1] = 1;
+        image[HASH_APPENDED_OFFSET] = 1;
+        image.extend_from_slice(&0x3f40_0020u32.to_le_bytes());
+        image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        image.extend_from_slice(&data);
+        // Checksum byte ending a 16-byte block, then the SHA-256
+        image.resize((image.len() + 16) & !15, 0);
+        image.resize(image.len() + 32, 0);
+        reseal(&mut image).unwrap();
+        image
+    }
+
+    fn build_dir(image: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cloud-compiler-test-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Blink.ino.bin"), image).unwrap();
+        dir
+    }
+
+    fn text(name: &str, value: &str) -> Patch {
+        Patch { name: name.to_string(), bytes: value.as_bytes().to_vec(), text: true }
+    }
+
+    #[test]
+    fn writes_the_value_and_reseals_the_image() {
+        let original = app_image();
+        let dir = build_dir(&original);
+        apply(&dir, &[text("device_id", "device-42")]).unwrap();
+
+        let patched = std::fs::read(dir.join("Blink.ino.bin")).unwrap();
+        let block = IMAGE_HEADER_SIZE + 8 + 16;
+        assert_eq!(&patched[block..block + 10], b"device-42\0");
+        assert!(patched[block + 10..block + BLOCK].iter().all(|b| *b == 0));
+        // Everything outside the block is kept
+        assert_eq!(patched[..block], original[..block]);
+        assert_eq!(patched[block + BLOCK..IMAGE_HEADER_SIZE + 8 + 128], original[block + BLOCK..IMAGE_HEADER_SIZE + 8 + 128]);
+        // Resealing again changes nothing: checksum and hash match the new data
+        let mut resealed = patched.clone();
+        reseal(&mut resealed).unwrap();
+        assert_eq!(resealed, patched);
+        assert_ne!(patched[patched.len() - 32..], original[original.len() - 32..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn patches_the_app_inside_the_merged_image() {
+        let original = app_image();
+        let dir = build_dir(&original);
+        let mut merged = vec![0xff; 0x10000];
+        merged[0x2000..0x2000 + original.len()].copy_from_slice(&original);
+        std::fs::write(dir.join("Blink.ino.merged.bin"), &merged).unwrap();
+        apply(&dir, &[Patch { name: "device_id".to_string(), bytes: vec![1, 2, 3], text: false }]).unwrap();
+
+        let patched = std::fs::read(dir.join("Blink.ino.bin")).unwrap();
+        let merged = std::fs::read(dir.join("Blink.ino.merged.bin")).unwrap();
+        assert_eq!(merged[0x2000..0x2000 + patched.len()], patched[..]);
+        assert!(merged[..0x2000].iter().all(|b| *b == 0xff));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_values_that_dont_fit() {
+        let original = app_image();
+        let dir = build_dir(&original);
+        // A string needs its terminator inside the block
+        let error = apply(&dir, &[text("device_id", &"x".repeat(BLOCK))]).unwrap_err();
+        assert_eq!(error, format!("Patch device_id is {} bytes, its placeholder holds {}", BLOCK, BLOCK - 1));
+        let error = apply(&dir, &[text("serial", "42")]).unwrap_err();
+        assert_eq!(error, "No placeholder serial in the firmware");
+        // Nothing was written
+        assert_eq!(std::fs::read(dir.join("Blink.ino.bin")).unwrap(), original);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_patches_from_the_request() {
+        let patches = from_request(&serde_json::json!({
+            "patches": { "device_id": "abc", "key": { "hex": "00ff" }, "cert": { "base64": "AQI=" } },
+        })).unwrap();
+        let found: Vec<(&str, &[u8], bool)> = patches.iter().map(|p| (p.name.as_str(), p.bytes.as_slice(), p.text)).collect();
+        assert_eq!(found, vec![("cert", &[1u8, 2][..], false), ("device_id", &b"abc"[..], true), ("key", &[0u8, 0xff][..], false)]);
+        assert!(from_request(&serde_json::json!({ "patches": { "bad name": "x" } })).is_err());
+        assert!(from_request(&serde_json::json!({ "patches": { "key": { "hex": "0f0" } } })).is_err());
+        assert!(from_request(&serde_json::json!({ "patches": ["x"] })).is_err());
+    }
+}
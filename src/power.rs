@@ -0,0 +1,28 @@
+use std::path::Path;
+use crate::models::*;
+use crate::compiler::{ error_response, port_name, run_program };
+use crate::resources::{ acquire, ResourceKind };
+
+// Power control is site specific (relay boards, uhubctl on a switchable USB hub, ...), so
+// agents configure the command that cuts and restores power, e.g.
+// `CLOUD_COMPILER_POWER_CYCLE_COMMAND="uhubctl --action cycle --location 1-1 --ports {port}"`.
+// `{port}` is replaced with the serial port of the board. No shell is involved.
+const POWER_CYCLE_COMMAND_VAR: &str = "CLOUD_COMPILER_POWER_CYCLE_COMMAND";
+
+pub fn power_control_available() -> bool {
+    std::env::var(POWER_CYCLE_COMMAND_VAR).is_ok_and(|command| !command.trim().is_empty())
+}
+
+// Cut and restore power of the board on `port` with the configured command
+pub async fn power_cycle(port: &str) -> CommandResponse {
+    let port = &port_name(port);
+    let template = std::env::var(POWER_CYCLE_COMMAND_VAR).unwrap_or_default();
+    let mut words = template.split_whitespace().map(|word| word.replace("{port}", port));
+    let Some(program) = words.next() else {
+        return error_response("power-cycle", vec![port.to_string()], "Power control is not configured on this server");
+    };
+    let args: Vec<String> = words.collect();
+
+    let serial_port = acquire(ResourceKind::SerialPort, port);
+    serial_port.scope(run_program(Path::new(&program), "power-cycle", &args)).await
+}
@@ -0,0 +1,128 @@
+// Build profiles of `sketch.yaml`, arduino-cli's pinned platform and library versions for
+// reproducible builds. `compile-sketch {profile}` compiles with `--profile`, `profile-save`
+// compiles with `--dump-profile` and writes the resulting profile into the sketch's
+// `sketch.yaml`, adding it or replacing the one of the same name.
+//
+// arduino-cli writes these files with two-space indentation, the edits here are line based on
+// that layout: every profile is a `  <name>:` line below the top-level `profiles:`.
+use std::path::{ Path, PathBuf };
+use crate::compiler::sketch_dir;
+
+const PROJECT_FILE: &str = "sketch.yaml";
+const MAX_NAME_LENGTH: usize = 64;
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() &&
+        name.len() <= MAX_NAME_LENGTH &&
+        !name.starts_with('-') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+pub fn project_file(sketch: &Path) -> PathBuf {
+    sketch_dir(sketch).join(PROJECT_FILE)
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+// Line range of profile `name` in `lines`: its `  <name>:` line up to the next profile or the end
+// of the `profiles:` section. Without that profile the error is the end of the section, where a
+// new one goes, and None when there is no section.
+fn profile_range(lines: &[&str], name: &str) -> Result<(usize, usize), Option<usize>> {
+    let Some(section) = lines.iter().position(|line| line.trim_end() == "profiles:") else {
+        return Err(None);
+    };
+    let end = lines[section + 1..]
+        .iter()
+        .position(|line| !line.trim().is_empty() && indentation(line) == 0)
+        .map_or(lines.len(), |offset| section + 1 + offset);
+    let header = format!("  {}:", name);
+    let Some(start) = (section + 1..end).find(|i| lines[*i].trim_end() == header) else {
+        return Err(Some(end));
+    };
+    let mut stop = (start + 1..end)
+        .find(|i| !lines[*i].trim().is_empty() && indentation(lines[*i]) <= 2)
+        .unwrap_or(end);
+    // Blank lines separating it from what follows are kept
+    while stop > start + 1 && lines[stop - 1].trim().is_empty() {
+        stop -= 1;
+    }
+    Ok((start, stop))
+}
+
+// Board of profile `name` in the sketch's `sketch.yaml`
+pub fn profile_fqbn(sketch: &Path, name: &str) -> Option<String> {
+    let yaml = std::fs::read_to_string(project_file(sketch)).ok()?;
+    let lines: Vec<&str> = yaml.lines().collect();
+    let (start, stop) = profile_range(&lines, name).ok()?;
+    lines[start + 1..stop]
+        .iter()
+        .find_map(|line| line.trim().strip_prefix("fqbn:"))
+        .map(|fqbn| fqbn.trim().trim_matches('"').to_string())
+        .filter(|fqbn| !fqbn.is_empty())
+}
+
+// The profile printed by `compile --dump-profile`, renamed to `name`
+fn dumped_profile(output: &str, name: &str) -> Result<Vec<String>, String> {
+    let lines: Vec<&str> = output.lines().collect();
+    let section = lines.iter().position(|line| line.trim_end() == "profiles:").ok_or("arduino-cli printed no profile")?;
+    let mut profile: Vec<String> = lines[section + 1..]
+        .iter()
+        .take_while(|line| line.trim().is_empty() || indentation(line) >= 2)
+        .map(|line| line.trim_end().to_string())
+        .collect();
+    while profile.last().is_some_and(|line| line.is_empty()) {
+        profile.pop();
+    }
+    match profile.first() {
+        Some(header) if indentation(header) == 2 && header.ends_with(':') => {
+            profile[0] = format!("  {}:", name);
+            Ok(profile)
+        }
+        _ => Err("arduino-cli printed no profile".to_string()),
+    }
+}
+
+// Write the profile dumped in `output` as `name` into the sketch's `sketch.yaml`, as its default
+// profile when `make_default`. Returns the new file.
+pub fn save_profile(sketch: &Path, name: &str, output: &str, make_default: bool) -> Result<String, String> {
+    let profile = dumped_profile(output, name)?;
+    let path = project_file(sketch);
+    let existing = std::fs::read_to_string(&path).unwrap_or_default();
+    let mut lines: Vec<String> = existing.lines().map(String::from).collect();
+
+    let borrowed: Vec<&str> = lines.iter().map(String::as_str).collect();
+    match profile_range(&borrowed, name) {
+        Ok((start, stop)) => {
+            lines.splice(start..stop, profile);
+        }
+        Err(Some(end)) => {
+            let mut at = end;
+            while at > 0 && lines[at - 1].trim().is_empty() {
+                at -= 1;
+            }
+            // Separated from the profile before it
+            if at > 0 && lines[at - 1].trim_end() != "profiles:" {
+                lines.insert(at, String::new());
+                at += 1;
+            }
+            lines.splice(at..at, profile);
+        }
+        Err(None) => {
+            lines.push("profiles:".to_string());
+            lines.extend(profile);
+        }
+    }
+    if make_default {
+        let default = format!("default_profile: {}", name);
+        match lines.iter().position(|line| line.starts_with("default_profile:")) {
+            Some(i) => lines[i] = default,
+            None => lines.push(default),
+        }
+    }
+
+    let yaml = lines.join("\n") + "\n";
+    std::fs::write(&path, &yaml).map_err(|e| format!("Failed to write {}: {}", PROJECT_FILE, e))?;
+    Ok(yaml)
+}
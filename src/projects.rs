@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use crate::models::*;
+use crate::compiler::{ is_safe_name, server_data_dir };
+use crate::files::{ subject_dir, write_files };
+
+// Stored projects: a sketch on the server plus searchable metadata. Each project is a
+// directory holding `project.json`; sketches uploaded with it live in `<name>/<name>/`
+// so arduino-cli finds `<name>.ino`. Every identity has a store of its own.
+
+const METADATA_FILE: &str = "project.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LastBuild {
+    pub build_id: String,
+    pub success: bool,
+    pub finished_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Project {
+    pub name: String,
+    pub sketch_path: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub board: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub last_build: Option<LastBuild>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Projects of an identity, below a folder no project name can take. Anonymous clients share
+// the root.
+fn projects_root(owner: Option<&str>) -> PathBuf {
+    let root = server_data_dir().join("projects");
+    match owner {
+        Some(_) => subject_dir(root.join(".owners"), owner),
+        None => root,
+    }
+}
+
+fn project_dir(owner: Option<&str>, name: &str) -> Result<PathBuf, String> {
+    if !is_safe_name(name) {
+        return Err(format!("Invalid project name: {}", name));
+    }
+    Ok(projects_root(owner).join(name))
+}
+
+// Folder of the sketch uploaded with a project
+pub fn uploaded_sketch(owner: Option<&str>, name: &str) -> Result<PathBuf, String> {
+    Ok(project_dir(owner, name)?.join(name))
+}
+
+fn write_project(owner: Option<&str>, project: &Project) -> Result<(), String> {
+    let dir = project_dir(owner, &project.name)?;
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let json = serde_json::to_vec_pretty(project).map_err(|e| e.to_string())?;
+    std::fs::write(dir.join(METADATA_FILE), json).map_err(|e| e.to_string())
+}
+
+pub fn get_project(owner: Option<&str>, name: &str) -> Result<Project, String> {
+    let path = project_dir(owner, name)?.join(METADATA_FILE);
+    let json = std::fs::read(path).map_err(|_| format!("No project named {}", name))?;
+    serde_json::from_slice(&json).map_err(|e| e.to_string())
+}
+
+// Tags are compared case-insensitively, stored lowercase and without duplicates
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// Create or update a project, fields missing from the request keep their stored value
+pub fn save_project(owner: Option<&str>, request: &ProjectRequest) -> Result<Project, String> {
+    let existing = get_project(owner, &request.name).ok();
+
+    let sketch_path = if !request.files.is_empty() {
+        let sketch = uploaded_sketch(owner, &request.name)?;
+        write_files(&sketch, &request.files)?;
+        sketch.to_string_lossy().to_string()
+    } else if let Some(path) = &request.sketch_path {
+        path.clone()
+    } else if let Some(existing) = &existing {
+        existing.sketch_path.clone()
+    } else {
+        return Err("Missing sketch path or files".to_string());
+    };
+
+    let now = now();
+    let project = Project {
+        name: request.name.clone(),
+        sketch_path,
+        tags: match &request.tags {
+            Some(tags) => normalize_tags(tags),
+            None => existing.as_ref().map(|p| p.tags.clone()).unwrap_or_default(),
+        },
+        board: request.board.clone().or_else(|| existing.as_ref().and_then(|p| p.board.clone())),
+        metadata: match &request.metadata {
+            Some(metadata) => metadata.clone(),
+            None => existing.as_ref().map(|p| p.metadata.clone()).unwrap_or_default(),
+        },
+        created_at: existing.as_ref().map(|p| p.created_at).unwrap_or(now),
+        updated_at: now,
+        last_build: existing.and_then(|p| p.last_build),
+    };
+    write_project(owner, &project)?;
+    Ok(project)
+}
+
+pub fn delete_project(owner: Option<&str>, name: &str) -> Result<(), String> {
+    let dir = project_dir(owner, name)?;
+    if !dir.join(METADATA_FILE).is_file() {
+        return Err(format!("No project named {}", name));
+    }
+    std::fs::remove_dir_all(dir).map_err(|e| e.to_string())
+}
+
+// Remember the outcome of the latest compile of a project
+pub fn record_build(owner: Option<&str>, name: &str, build_id: &str, success: bool) -> Result<(), String> {
+    let mut project = get_project(owner, name)?;
+    project.last_build = Some(LastBuild { build_id: build_id.to_string(), success, finished_at: now() });
+    write_project(owner, &project)
+}
+
+fn matches(project: &Project, query: &ProjectQuery) -> bool {
+    let text = query.text.as_deref().map(str::to_lowercase).unwrap_or_default();
+    let text_matches =
+        text.is_empty() ||
+        project.name.to_lowercase().contains(&text) ||
+        project.metadata.values().any(|v| v.to_lowercase().contains(&text));
+    let tags = normalize_tags(&query.tags);
+    let board_matches = match (&query.board, &project.board) {
+        (None, _) => true,
+        (Some(board), Some(project_board)) => project_board.starts_with(board.as_str()),
+        (Some(_), None) => false,
+    };
+    let status_matches = match (query.status, &project.last_build) {
+        (None, _) => true,
+        (Some(BuildStatus::Never), None) => true,
+        (Some(BuildStatus::Success), Some(build)) => build.success,
+        (Some(BuildStatus::Failed), Some(build)) => !build.success,
+        _ => false,
+    };
+    text_matches && tags.iter().all(|t| project.tags.contains(t)) && board_matches && status_matches
+}
+
+// Stored projects matching every given criterion, most recently updated first
+pub fn search_projects(owner: Option<&str>, query: &ProjectQuery) -> Vec<Project> {
+    let mut found: Vec<Project> = std::fs
+        ::read_dir(projects_root(owner))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| std::fs::read(e.path().join(METADATA_FILE)).ok())
+                .filter_map(|json| serde_json::from_slice::<Project>(&json).ok())
+                .filter(|project| matches(project, query))
+                .collect()
+        })
+        .unwrap_or_default();
+    found.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.name.cmp(&b.name)));
+    if let Some(limit) = query.limit {
+        found.truncate(limit);
+    }
+    found
+}
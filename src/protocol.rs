@@ -0,0 +1,104 @@
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use crate::models::*;
+
+// Payload versions this server is able to produce
+pub const SUPPORTED_EVENT_VERSIONS: &[u32] = &[1];
+pub const SUPPORTED_RESPONSE_VERSIONS: &[u32] = &[1, 2];
+
+// What the client declared in the `accepts` field of its handshake auth data
+#[derive(Deserialize, Default)]
+pub struct Accepts {
+    #[serde(default)]
+    pub events: Vec<u32>,
+    #[serde(default)]
+    pub responses: Vec<u32>,
+}
+
+// Versions negotiated for a socket, stored in its extensions
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    pub events: u32,
+    pub responses: u32,
+}
+
+impl Default for Protocol {
+    // Clients that don't declare anything are treated as the original v1 clients
+    fn default() -> Self {
+        Protocol { events: 1, responses: 1 }
+    }
+}
+
+// Pick the highest version both sides understand, falling back to v1
+fn pick(declared: &[u32], supported: &[u32]) -> u32 {
+    declared
+        .iter()
+        .filter(|v| supported.contains(v))
+        .max()
+        .copied()
+        .unwrap_or(1)
+}
+
+// Negotiate the protocol from the handshake auth payload
+pub fn negotiate(auth: &Value) -> Protocol {
+    let accepts: Accepts = auth
+        .get("accepts")
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+        .unwrap_or_default();
+
+    Protocol {
+        events: pick(&accepts.events, SUPPORTED_EVENT_VERSIONS),
+        responses: pick(&accepts.responses, SUPPORTED_RESPONSE_VERSIONS),
+    }
+}
+
+// Serialize a command response in the shape the client negotiated
+pub fn render_response(response: &CommandResponse, protocol: &Protocol) -> Value {
+    match protocol.responses {
+        1 => serde_json::to_value(response).unwrap_or(Value::Null),
+        _ => {
+            let stdout = response.output.lines().map(|line| LogLine {
+                stream: LogStream::Stdout,
+                text: line.to_string(),
+            });
+            // The process's own stderr when known, the server's messages stay in `error`
+            let stderr = response.stderr
+                .as_ref()
+                .or(response.error.as_ref())
+                .into_iter()
+                .flat_map(|e| e.lines())
+                .map(|line| LogLine {
+                    stream: LogStream::Stderr,
+                    text: line.to_string(),
+                });
+
+            let v2 = CommandResponseV2 {
+                version: 2,
+                success: response.success,
+                output: &response.output,
+                logs: stdout.chain(stderr).collect(),
+                error: response.error.as_deref(),
+                command: &response.command,
+                args: &response.args,
+                build_id: response.build_id.as_deref(),
+                artifacts: &response.artifacts,
+                diagnostics: &response.diagnostics,
+                explanations: &response.explanations,
+                error_code: response.error_code,
+                retry_after: response.retry_after,
+                cached: response.cached,
+                job_id: response.job_id.as_deref(),
+                exit_code: response.exit_code,
+                started_at: response.started_at,
+                finished_at: response.finished_at,
+                duration_ms: response.duration_ms,
+                arduino_cli_version: response.arduino_cli_version.as_deref(),
+                size: response.size,
+                size_delta: response.size_delta.as_ref(),
+                invalid_fields: &response.invalid_fields,
+                timings: response.timings,
+            };
+            serde_json::to_value(v2).unwrap_or(Value::Null)
+        }
+    }
+}
@@ -0,0 +1,229 @@
+// Declarative provisioning. A TOML manifest, CLOUD_COMPILER_PROVISION_MANIFEST (default
+// `<data dir>/provision.toml` when it exists), lists the board manager URLs, cores and libraries
+// the server needs:
+//
+//   board_urls = ["https://espressif.github.io/arduino-esp32/package_esp32_index.json"]
+//   cores = ["esp32:esp32@3.0.7"]
+//   libraries = ["ArduinoJson@7.0.4", "PubSubClient"]
+//
+// At startup whatever is missing, or installed in another version than pinned, is installed;
+// the result of the final check is kept and reported as drift on /health.
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use utoipa::ToSchema;
+use tracing::{ info, warn };
+use crate::compiler::{ get_arduino_cli_path, run_program, server_data_dir };
+use crate::daemon;
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    board_urls: Vec<String>,
+    #[serde(default)]
+    cores: Vec<String>,
+    #[serde(default)]
+    libraries: Vec<String>,
+}
+
+// A manifest entry and what is installed for it
+#[derive(Serialize, Clone, ToSchema)]
+pub struct Requirement {
+    pub spec: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installed: Option<String>,
+    pub satisfied: bool,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct ProvisioningReport {
+    pub manifest: String,
+    pub in_sync: bool,
+    pub cores: Vec<Requirement>,
+    pub libraries: Vec<Requirement>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    pub checked_at: u64,
+}
+
+static REPORT: LazyLock<Mutex<Option<ProvisioningReport>>> = LazyLock::new(|| Mutex::new(None));
+
+fn manifest_path() -> Option<PathBuf> {
+    match std::env::var_os("CLOUD_COMPILER_PROVISION_MANIFEST").filter(|path| !path.is_empty()) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(server_data_dir().join("provision.toml")).filter(|path| path.is_file()),
+    }
+}
+
+// Result of the last provisioning check, None without a manifest or before the first check
+pub fn report() -> Option<ProvisioningReport> {
+    REPORT.lock().unwrap().clone()
+}
+
+// `name@version` -> (name, Some(version))
+fn split_spec(spec: &str) -> (&str, Option<&str>) {
+    match spec.rsplit_once('@') {
+        Some((name, version)) => (name.trim(), Some(version.trim())),
+        None => (spec.trim(), None),
+    }
+}
+
+// arduino-cli invocation with the manifest's board URLs
+async fn arduino_cli(manifest: &Manifest, args: &[&str]) -> Result<String, String> {
+    let command = args.first().copied().unwrap_or_default();
+    let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    if !manifest.board_urls.is_empty() {
+        args.push("--additional-urls".to_string());
+        args.push(manifest.board_urls.join(","));
+    }
+    let response = run_program(get_arduino_cli_path(), command, &args).await;
+    match response.success {
+        true => Ok(response.output),
+        // The last line says what failed, the ones before are index warnings
+        false => Err(response.error.unwrap_or_default().trim().lines().last().unwrap_or_default().to_string()),
+    }
+}
+
+// Installed (name, version) pairs of cores and libraries
+async fn installed(manifest: &Manifest) -> Result<(Vec<(String, String)>, Vec<(String, String)>), String> {
+    let parse = |output: String| serde_json::from_str::<serde_json::Value>(&output).map_err(|e| e.to_string());
+    let cores = parse(arduino_cli(manifest, &["core", "list", "--format", "json"]).await?)?;
+    let libraries = parse(arduino_cli(manifest, &["lib", "list", "--format", "json"]).await?)?;
+    let pairs = |items: Option<&Vec<serde_json::Value>>, name: &str, version: &str| {
+        items
+            .into_iter()
+            .flatten()
+            .filter_map(|item| {
+                let item = item.get("library").unwrap_or(item);
+                Some((item.get(name)?.as_str()?.to_string(), item.get(version)?.as_str()?.to_string()))
+            })
+            .collect::<Vec<_>>()
+    };
+    Ok((
+        pairs(cores.get("platforms").and_then(|v| v.as_array()), "id", "installed_version"),
+        pairs(libraries.get("installed_libraries").and_then(|v| v.as_array()), "name", "version"),
+    ))
+}
+
+// Which of `specs` the installed (name, version) pairs satisfy
+pub fn check(specs: &[String], installed: &[(String, String)]) -> Vec<Requirement> {
+    specs
+        .iter()
+        .map(|spec| {
+            let (name, version) = split_spec(spec);
+            let installed = installed
+                .iter()
+                .find(|(installed, _)| installed.eq_ignore_ascii_case(name))
+                .map(|(_, version)| version.clone());
+            let satisfied = match (version, &installed) {
+                (_, None) => false,
+                (None, Some(_)) => true,
+                (Some(wanted), Some(installed)) => wanted == installed,
+            };
+            Requirement { spec: spec.clone(), installed, satisfied }
+        })
+        .collect()
+}
+
+// Install what the manifest lists and isn't there, then record what is
+async fn provision(manifest: &Manifest, path: &str) -> ProvisioningReport {
+    let mut errors = Vec::new();
+    if let Err(e) = arduino_cli(manifest, &["core", "update-index"]).await {
+        errors.push(format!("core update-index: {}", e));
+    }
+    if !manifest.libraries.is_empty() && let Err(e) = arduino_cli(manifest, &["lib", "update-index"]).await {
+        errors.push(format!("lib update-index: {}", e));
+    }
+
+    match installed(manifest).await {
+        Ok((cores, libraries)) => {
+            let missing = [("core", check(&manifest.cores, &cores)), ("lib", check(&manifest.libraries, &libraries))];
+            for (kind, requirements) in missing {
+                for requirement in requirements.iter().filter(|requirement| !requirement.satisfied) {
+                    info!("Provisioning {} {}", kind, requirement.spec);
+                    if let Err(e) = arduino_cli(manifest, &[kind, "install", &requirement.spec]).await {
+                        errors.push(format!("{} install {}: {}", kind, requirement.spec, e));
+                    }
+                }
+            }
+        }
+        Err(e) => errors.push(e),
+    }
+    daemon::reload().await;
+
+    let (cores, libraries) = match installed(manifest).await {
+        Ok((cores, libraries)) => (check(&manifest.cores, &cores), check(&manifest.libraries, &libraries)),
+        Err(e) => {
+            errors.push(e);
+            (check(&manifest.cores, &[]), check(&manifest.libraries, &[]))
+        }
+    };
+    ProvisioningReport {
+        manifest: path.to_string(),
+        in_sync: cores.iter().chain(&libraries).all(|requirement| requirement.satisfied),
+        cores,
+        libraries,
+        errors,
+        checked_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    }
+}
+
+fn read_manifest(path: &PathBuf) -> Result<Manifest, String> {
+    std::fs
+        ::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| toml::from_str::<Manifest>(&text).map_err(|e| e.to_string()))
+}
+
+// Cores the manifest requires, none without a valid manifest
+pub fn required_cores() -> Vec<String> {
+    manifest_path()
+        .and_then(|path| read_manifest(&path).ok())
+        .map(|manifest| manifest.cores)
+        .unwrap_or_default()
+}
+
+// Board manager URLs the manifest adds, none without a valid manifest
+pub fn board_urls() -> Vec<String> {
+    manifest_path()
+        .and_then(|path| read_manifest(&path).ok())
+        .map(|manifest| manifest.board_urls)
+        .unwrap_or_default()
+}
+
+// Apply the manifest, if there is one
+pub async fn run() {
+    let Some(path) = manifest_path() else {
+        return;
+    };
+    let name = path.to_string_lossy().to_string();
+    let manifest = match read_manifest(&path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            warn!("Invalid provisioning manifest {}: {}", name, e);
+            *REPORT.lock().unwrap() = Some(ProvisioningReport {
+                manifest: name,
+                in_sync: false,
+                cores: Vec::new(),
+                libraries: Vec::new(),
+                errors: vec![e],
+                checked_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            });
+            return;
+        }
+    };
+
+    info!("Provisioning from {}", name);
+    let report = provision(&manifest, &name).await;
+    match report.in_sync {
+        true => info!("Provisioning complete, all cores and libraries present"),
+        false => {
+            let drifted = report.cores.iter().chain(&report.libraries).filter(|requirement| !requirement.satisfied);
+            let specs: Vec<&str> = drifted.map(|requirement| requirement.spec.as_str()).collect();
+            warn!("Provisioning drift, not satisfied: {}", specs.join(", "));
+        }
+    }
+    *REPORT.lock().unwrap() = Some(report);
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::path::Path;
+use crate::models::*;
+use crate::artifacts::find_app_binary;
+use crate::compiler::{ build_properties, sketch_dir };
+
+// Statically allocated buffers above this size belong in PSRAM
+const LARGE_BUFFER_BYTES: u64 = 32 * 1024;
+
+fn advisory(code: &str, message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Advisory,
+        code: code.to_string(),
+        message,
+        file: None,
+        line: None,
+    }
+}
+
+// Whether the resolved build enables PSRAM
+fn psram_enabled(properties: &HashMap<String, String>) -> bool {
+    properties.values().any(|v| v.contains("-DBOARD_HAS_PSRAM"))
+}
+
+// Statically allocated .bss/.data symbols from sketch objects, as (symbol, size)
+fn large_static_buffers(map: &str) -> Vec<(String, u64)> {
+    let mut found = Vec::new();
+    let mut pending: Option<String> = None;
+
+    for line in map.lines() {
+        let mut tokens = line.split_whitespace().peekable();
+        let name = match tokens.peek() {
+            Some(t) if t.starts_with(".bss.") || t.starts_with(".data.") || t.starts_with(".dram1.") => {
+                let name = t.to_string();
+                tokens.next();
+                name
+            }
+            // Long section names are wrapped, their address/size follow on the next line
+            Some(t) if t.starts_with("0x") && pending.is_some() => pending.take().unwrap(),
+            _ => {
+                pending = None;
+                continue;
+            }
+        };
+
+        let parsed: Vec<&str> = tokens.collect();
+        if parsed.is_empty() {
+            pending = Some(name);
+            continue;
+        }
+        let [_address, size, object, ..] = parsed[..] else {
+            continue;
+        };
+        let from_sketch = object.contains("/sketch/") || object.contains("\\sketch\\");
+        match u64::from_str_radix(size.trim_start_matches("0x"), 16) {
+            Ok(size) if from_sketch && size >= LARGE_BUFFER_BYTES => {
+                let symbol = name.split_once('.').map(|(_, rest)| rest).unwrap_or(&name);
+                let symbol = symbol.split_once('.').map(|(_, rest)| rest).unwrap_or(symbol);
+                found.push((symbol.to_string(), size));
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+// Sketch sources use the PSRAM allocators
+fn uses_psram_allocators(sketch_path: &Path) -> bool {
+    std::fs
+        ::read_dir(sketch_dir(sketch_path))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| {
+                    let name = e.file_name().to_string_lossy().to_string();
+                    [".ino", ".cpp", ".c", ".h"].iter().any(|ext| name.ends_with(ext))
+                })
+                .filter_map(|e| std::fs::read_to_string(e.path()).ok())
+                .any(|source| source.contains("ps_malloc") || source.contains("MALLOC_CAP_SPIRAM"))
+        })
+        .unwrap_or(false)
+}
+
+// Advisory checks for common PSRAM pitfalls on ESP32 boards
+pub async fn check(build_dir: &Path, sketch_path: &str, fqbn: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let properties = match build_properties(fqbn, sketch_path).await {
+        Ok(properties) => properties,
+        Err(_) => {
+            return diagnostics;
+        }
+    };
+    let psram = psram_enabled(&properties);
+    let mcu = properties.get("build.mcu").map(String::as_str).unwrap_or_default();
+
+    // The original ESP32 needs the cache workaround whenever PSRAM is used
+    if psram && mcu == "esp32" && !properties.values().any(|v| v.contains("-mfix-esp32-psram-cache-issue")) {
+        diagnostics.push(
+            advisory(
+                "PSRAM_CACHE_FIX_MISSING",
+                "PSRAM is enabled on an ESP32 but -mfix-esp32-psram-cache-issue is not set; \
+                 older silicon revisions can corrupt PSRAM accesses".to_string()
+            )
+        );
+    }
+
+    if !psram && uses_psram_allocators(Path::new(sketch_path)) {
+        diagnostics.push(
+            advisory(
+                "PSRAM_DISABLED",
+                "The sketch allocates from PSRAM but PSRAM is not enabled for this board; \
+                 ps_malloc will return NULL (enable it with the PSRAM board option)".to_string()
+            )
+        );
+    }
+
+    if psram {
+        let map = find_app_binary(build_dir)
+            .map(|app| app.with_extension("map"))
+            .and_then(|map| std::fs::read_to_string(map).ok())
+            .unwrap_or_default();
+        for (symbol, size) in large_static_buffers(&map) {
+            diagnostics.push(
+                advisory(
+                    "LARGE_STATIC_BUFFER",
+                    format!(
+                        "`{}` statically occupies {} KiB of internal RAM; allocate it with ps_malloc \
+                         or mark it EXT_RAM_BSS_ATTR to place it in PSRAM",
+                        symbol,
+                        size / 1024
+                    )
+                )
+            );
+        }
+    }
+
+    diagnostics
+}
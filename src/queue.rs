@@ -0,0 +1,306 @@
+// Worker pool for arduino-cli jobs: a fixed number run at once, the rest wait and new jobs are
+// refused once the queue is full. Waiting jobs run by priority class, oldest first within a class;
+// every aging period spent waiting promotes a job one class so bulk jobs can't starve. Waiting
+// jobs get their position and an estimated wait from the durations of recent jobs.
+//
+// CLOUD_COMPILER_WORKERS sets the concurrency (default half the CPUs, at least one), on a
+// dispatcher it is the slots of the registered worker nodes instead (see `dispatch`),
+// CLOUD_COMPILER_QUEUE_LIMIT the number of jobs allowed to wait (default 64),
+// CLOUD_COMPILER_PRIORITY_AGING_SECS the aging period (default 120) and
+// CLOUD_COMPILER_IDENTITY_PRIORITIES the class of identities (`ci=bulk,lab=interactive`).
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ LazyLock, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::oneshot;
+use tracing::info;
+use ts_rs::TS;
+use utoipa::ToSchema;
+use crate::dispatch::{ role, Role };
+use crate::resources;
+
+const DEFAULT_QUEUE_LIMIT: usize = 64;
+const DEFAULT_AGING: Duration = Duration::from_secs(120);
+const UPDATE_INTERVAL: Duration = Duration::from_secs(2);
+// Jobs the wait estimate averages over, and the guess before any finished
+const RECENT_JOBS: usize = 20;
+const INITIAL_ESTIMATE: Duration = Duration::from_secs(60);
+
+// Scheduling class of a job, interactive IDE compiles run ahead of bulk/CI ones
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Interactive,
+    #[default]
+    Normal,
+    Bulk,
+}
+
+impl Priority {
+    fn parse(value: &str) -> Option<Priority> {
+        serde_json::from_value(value.trim().to_lowercase().into()).ok()
+    }
+}
+
+struct Waiting {
+    id: u64,
+    priority: Priority,
+    since: Instant,
+    start: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    waiting: Vec<Waiting>,
+    next_ticket: u64,
+    recent: VecDeque<Duration>,
+}
+
+struct Pool {
+    workers: AtomicUsize,
+    queue_limit: usize,
+    aging: Duration,
+    identities: HashMap<String, Priority>,
+    state: Mutex<State>,
+}
+
+static POOL: LazyLock<Pool> = LazyLock::new(|| {
+    let read = |name: &str| std::env::var(name).ok().and_then(|value| value.trim().parse::<u64>().ok());
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+    let workers = read("CLOUD_COMPILER_WORKERS").map_or(cpus / 2, |n| n as usize).max(1);
+    let queue_limit = read("CLOUD_COMPILER_QUEUE_LIMIT").map_or(DEFAULT_QUEUE_LIMIT, |n| n as usize);
+    let aging = read("CLOUD_COMPILER_PRIORITY_AGING_SECS")
+        .filter(|secs| *secs > 0)
+        .map_or(DEFAULT_AGING, Duration::from_secs);
+    let identities = std::env
+        ::var("CLOUD_COMPILER_IDENTITY_PRIORITIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(subject, priority)| Some((subject.trim().to_string(), Priority::parse(priority)?)))
+        .collect();
+    let workers = match role() {
+        Role::Dispatcher => {
+            info!("Worker pool: slots of the registered workers, up to {} queued jobs", queue_limit);
+            0
+        }
+        _ => {
+            info!("Worker pool: {} workers, up to {} queued jobs", workers, queue_limit);
+            workers
+        }
+    };
+    Pool { workers: AtomicUsize::new(workers), queue_limit, aging, identities, state: Mutex::new(State::default()) }
+});
+
+// Class a job runs in: an identity's configured class is both its default and the highest it
+// may ask for. Other clients, anonymous and guests included, get `normal` the same way, so only
+// configured identities can jump the queue.
+pub fn priority_for(subject: Option<&str>, requested: Option<Priority>) -> Priority {
+    let ceiling = subject
+        .and_then(|subject| POOL.identities.get(subject))
+        .copied()
+        .unwrap_or_default();
+    requested.map_or(ceiling, |requested| requested.max(ceiling))
+}
+
+// Order key of a waiting job at `now`, lowest runs first
+fn rank(job: &Waiting, now: Instant) -> (u8, Instant) {
+    let promotions = now.duration_since(job.since).as_secs() / POOL.aging.as_secs();
+    ((job.priority as u8).saturating_sub(promotions.min(u8::MAX as u64) as u8), job.since)
+}
+
+// Start waiting jobs while workers are free
+fn dispatch(state: &mut State) {
+    let now = Instant::now();
+    while state.running < POOL.workers.load(Ordering::Relaxed) {
+        let Some(next) = state.waiting
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, job)| rank(job, now))
+            .map(|(index, _)| index) else {
+            break;
+        };
+        let job = state.waiting.swap_remove(next);
+        state.running += 1;
+        // A ticket dropped in the meantime gives the worker back itself
+        job.start.send(()).ok();
+    }
+}
+
+// Position and wait estimate of the waiting job `id`
+fn update(id: u64) -> Option<QueueUpdate> {
+    let state = POOL.state.lock().unwrap();
+    let now = Instant::now();
+    let own = rank(state.waiting.iter().find(|job| job.id == id)?, now);
+    let position = state.waiting.iter().filter(|job| rank(job, now) < own).count() + 1;
+    let average = match state.recent.len() {
+        0 => INITIAL_ESTIMATE,
+        n => state.recent.iter().sum::<Duration>() / (n as u32),
+    };
+    // Every `workers` jobs ahead take about one average job to clear
+    let rounds = position.div_ceil(POOL.workers.load(Ordering::Relaxed).max(1)) as u32;
+    Some(QueueUpdate { position, eta_secs: (average * rounds).as_secs() })
+}
+
+// Place in the queue, leaves it when dropped
+pub struct Ticket {
+    id: u64,
+    // Fires once a worker is assigned, taken when the ticket turns into a slot
+    start: Option<oneshot::Receiver<()>>,
+}
+
+// A running job, frees its worker when dropped
+pub struct Slot {
+    started: Instant,
+    // False for a job cancelled while it waited, which never got a worker
+    held: bool,
+}
+
+// Progress of a waiting job, `position` 1 runs next
+#[derive(Clone, Copy)]
+pub struct QueueUpdate {
+    pub position: usize,
+    pub eta_secs: u64,
+}
+
+// Occupancy of the pool, for health reports
+#[derive(Serialize, Clone, Copy, ToSchema)]
+pub struct QueueStats {
+    pub workers: usize,
+    pub running: usize,
+    pub waiting: usize,
+    pub limit: usize,
+}
+
+pub fn stats() -> QueueStats {
+    let state = POOL.state.lock().unwrap();
+    QueueStats { workers: POOL.workers.load(Ordering::Relaxed), running: state.running, waiting: state.waiting.len(), limit: POOL.queue_limit }
+}
+
+// Change the number of jobs run at once, as workers come and go. Jobs running past it finish.
+pub fn set_workers(workers: usize) {
+    let mut state = POOL.state.lock().unwrap();
+    POOL.workers.store(workers, Ordering::Relaxed);
+    dispatch(&mut state);
+}
+
+// Join the queue, or fail right away when it is full
+pub fn join(priority: Priority) -> Result<Ticket, String> {
+    let mut state = POOL.state.lock().unwrap();
+    if state.waiting.len() >= POOL.queue_limit {
+        return Err(format!("Server busy, {} jobs already queued", state.waiting.len()));
+    }
+    let id = state.next_ticket;
+    state.next_ticket += 1;
+    let (start, started) = oneshot::channel();
+    state.waiting.push(Waiting { id, priority, since: Instant::now(), start });
+    dispatch(&mut state);
+    Ok(Ticket { id, start: Some(started) })
+}
+
+impl Ticket {
+    // Wait for a free worker, reporting the queue position every few seconds meanwhile. A job
+    // cancelled while waiting leaves the queue right away, whatever it runs next is cancelled too.
+    pub async fn ready(mut self, mut on_update: impl FnMut(QueueUpdate)) -> Slot {
+        let id = self.id;
+        let started = self.start.as_mut().expect("ticket already used");
+        let mut updates = tokio::time::interval(UPDATE_INTERVAL);
+        resources::set_job_queued(true);
+        loop {
+            tokio::select! {
+                // A free worker wins over the first update, jobs that don't wait report nothing
+                biased;
+                _ = &mut *started => {
+                    break;
+                }
+                _ = resources::cancelled() => {
+                    return Slot { started: Instant::now(), held: false };
+                }
+                _ = updates.tick() => {
+                    if let Some(update) = update(id) {
+                        on_update(update);
+                    }
+                }
+            }
+        }
+        self.start = None;
+        resources::set_job_queued(false);
+        Slot { started: Instant::now(), held: true }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = POOL.state.lock().unwrap();
+        let queued = state.waiting.len();
+        state.waiting.retain(|job| job.id != self.id);
+        // Assigned a worker but dropped before running (a cancelled job): give it back
+        if
+            let Some(start) = &mut self.start &&
+            state.waiting.len() == queued &&
+            start.try_recv().is_ok()
+        {
+            state.running -= 1;
+            dispatch(&mut state);
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.held {
+            return;
+        }
+        let mut state = POOL.state.lock().unwrap();
+        state.recent.push_back(self.started.elapsed());
+        if state.recent.len() > RECENT_JOBS {
+            state.recent.pop_front();
+        }
+        state.running -= 1;
+        dispatch(&mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unconfigured_callers_are_capped_at_normal() {
+        for subject in [None, Some("anonymous"), Some("guest:1234")] {
+            assert_eq!(priority_for(subject, None), Priority::Normal);
+            assert_eq!(priority_for(subject, Some(Priority::Interactive)), Priority::Normal);
+            assert_eq!(priority_for(subject, Some(Priority::Bulk)), Priority::Bulk);
+        }
+    }
+
+    fn waiting(id: u64, priority: Priority, since: Instant) -> Waiting {
+        Waiting { id, priority, since, start: oneshot::channel().0 }
+    }
+
+    #[test]
+    fn waiting_promotes_jobs_one_class_per_aging_period() {
+        let start = Instant::now();
+        let now = start + POOL.aging * 2;
+        let bulk = waiting(1, Priority::Bulk, start);
+        let aged_bulk = rank(&bulk, now);
+
+        // Two periods made it interactive, and it waited longer than a new interactive job
+        let interactive = waiting(2, Priority::Interactive, now);
+        assert_eq!(aged_bulk.0, Priority::Interactive as u8);
+        assert!(aged_bulk < rank(&interactive, now));
+
+        // One period only gets it to normal: ahead of newer normal jobs, behind interactive ones
+        let newer_bulk = waiting(3, Priority::Bulk, start + POOL.aging);
+        let normal = waiting(4, Priority::Normal, now);
+        assert_eq!(rank(&newer_bulk, now).0, Priority::Normal as u8);
+        assert!(rank(&newer_bulk, now) < rank(&normal, now));
+        assert!(rank(&interactive, now) < rank(&newer_bulk, now));
+
+        // Interactive is as far as aging goes
+        let old_interactive = waiting(5, Priority::Interactive, start);
+        assert_eq!(rank(&old_interactive, now + POOL.aging * 10).0, Priority::Interactive as u8);
+    }
+}
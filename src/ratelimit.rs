@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, Instant };
+use axum::{ extract::ConnectInfo, http::{ request::Parts, HeaderMap } };
+
+// Token buckets limiting how fast clients can make the server work:
+//   CLOUD_COMPILER_RATE_LIMIT_EVENTS     every Socket.IO event, per socket (default 30/10s)
+//   CLOUD_COMPILER_RATE_LIMIT_COMPILES   compiles, per client IP (default 10/m)
+//   CLOUD_COMPILER_RATE_LIMIT_HTTP       HTTP routes, per client IP (default 120/m)
+//   CLOUD_COMPILER_RATE_LIMIT_GUESTS     guest sessions started by unauthenticated clients, per
+//                                        client IP (default 5/h)
+// Rates are `<count>/<period>` with the period in s, m or h (`30/10s`, `10/m`), or `off`.
+// Clients are told apart by their address. X-Forwarded-For is only believed from the proxies in
+// CLOUD_COMPILER_TRUSTED_PROXIES (addresses or CIDR ranges, comma separated), anyone else could
+// send a new one with every request.
+
+// Buckets left untouched this long are dropped once the table grows
+const IDLE_BUCKET: Duration = Duration::from_secs(10 * 60);
+const PRUNE_ABOVE: usize = 10_000;
+
+#[derive(Clone, Copy)]
+pub struct Rate {
+    count: u32,
+    period: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    Events,
+    Compiles,
+    Http,
+    Guests,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+static BUCKETS: LazyLock<Mutex<HashMap<(Limit, String), Bucket>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static RATES: LazyLock<HashMap<Limit, Option<Rate>>> = LazyLock::new(|| {
+    [
+        (Limit::Events, "CLOUD_COMPILER_RATE_LIMIT_EVENTS", "30/10s"),
+        (Limit::Compiles, "CLOUD_COMPILER_RATE_LIMIT_COMPILES", "10/m"),
+        (Limit::Http, "CLOUD_COMPILER_RATE_LIMIT_HTTP", "120/m"),
+        (Limit::Guests, "CLOUD_COMPILER_RATE_LIMIT_GUESTS", "5/h"),
+    ]
+        .into_iter()
+        .map(|(limit, var, default)| {
+            let configured = std::env::var(var).unwrap_or_else(|_| default.to_string());
+            (limit, parse_rate(&configured).or_else(|| parse_rate(default)).filter(|_| configured != "off"))
+        })
+        .collect()
+});
+
+// Proxies whose X-Forwarded-For is believed, as (network, prefix length)
+static TRUSTED_PROXIES: LazyLock<Vec<(IpAddr, u32)>> = LazyLock::new(|| {
+    std::env
+        ::var("CLOUD_COMPILER_TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let network = parse_network(entry);
+            if network.is_none() {
+                tracing::warn!("Ignoring invalid trusted proxy {}", entry.trim());
+            }
+            network
+        })
+        .collect()
+});
+
+// "10.0.0.0/8" -> (10.0.0.0, 8), a bare address is a network of its own
+pub fn parse_network(value: &str) -> Option<(IpAddr, u32)> {
+    let (address, prefix) = value.trim().split_once('/').unwrap_or((value.trim(), ""));
+    let address: IpAddr = address.parse().ok()?;
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        "" => bits,
+        prefix => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+    };
+    Some((address.to_canonical(), prefix))
+}
+
+fn in_network(ip: IpAddr, (network, prefix): (IpAddr, u32)) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+// "30/10s" -> 30 per 10 seconds
+pub fn parse_rate(value: &str) -> Option<Rate> {
+    let (count, period) = value.trim().split_once('/')?;
+    let count: u32 = count.trim().parse().ok().filter(|c| *c > 0)?;
+    let period = period.trim();
+    let unit = period.chars().last()?;
+    let amount = &period[..period.len() - unit.len_utf8()];
+    let amount: u64 = if amount.is_empty() { 1 } else { amount.parse().ok().filter(|a| *a > 0)? };
+    let seconds = match unit {
+        's' => amount,
+        'm' => amount * 60,
+        'h' => amount * 60 * 60,
+        _ => {
+            return None;
+        }
+    };
+    Some(Rate { count, period: Duration::from_secs(seconds) })
+}
+
+// Take a token from the `limit` bucket of `key`, or return how long until one is available
+pub fn check(limit: Limit, key: &str) -> Result<(), Duration> {
+    let Some(rate) = RATES.get(&limit).copied().flatten() else {
+        return Ok(());
+    };
+    let capacity = rate.count as f64;
+    let per_second = capacity / rate.period.as_secs_f64();
+    let now = Instant::now();
+
+    let mut buckets = BUCKETS.lock().unwrap();
+    if buckets.len() > PRUNE_ABOVE {
+        buckets.retain(|_, bucket| now.duration_since(bucket.updated) < IDLE_BUCKET);
+    }
+    let bucket = buckets
+        .entry((limit, key.to_string()))
+        .or_insert(Bucket { tokens: capacity, updated: now });
+    bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second).min(capacity);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+    }
+}
+
+// Whole seconds to advertise in a retry-after, never zero
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+// Address of the client behind a request: the peer, unless it is a trusted proxy. Then the
+// X-Forwarded-For hops are walked from the nearest, past the trusted proxies, to the first
+// address one of them vouches for.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+    forwarded_ip(headers, peer?.ip(), &TRUSTED_PROXIES)
+}
+
+fn forwarded_ip(headers: &HeaderMap, peer: IpAddr, trusted: &[(IpAddr, u32)]) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|network| in_network(ip, *network));
+    let mut client = peer.to_canonical();
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect::<Vec<_>>();
+    for hop in hops.into_iter().rev() {
+        if !is_trusted(client) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip.to_canonical();
+            }
+            Err(_) => {
+                break;
+            }
+        }
+    }
+    Some(client)
+}
+
+pub fn request_ip(parts: &Parts) -> Option<IpAddr> {
+    let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    client_ip(&parts.headers, peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(value: &str) -> Option<(u32, u64)> {
+        parse_rate(value).map(|rate| (rate.count, rate.period.as_secs()))
+    }
+
+    #[test]
+    fn parses_count_and_period() {
+        assert_eq!(parsed("30/10s"), Some((30, 10)));
+        assert_eq!(parsed("10/m"), Some((10, 60)));
+        assert_eq!(parsed("5/2h"), Some((5, 2 * 60 * 60)));
+        assert_eq!(parsed(" 120 / m "), Some((120, 60)));
+    }
+
+    #[test]
+    fn rejects_malformed_rates() {
+        for value in ["", "off", "30", "30/", "/s", "0/s", "-1/s", "30/0s", "30/10", "30/10d", "x/s", "30/xs", "30/5é"] {
+            assert!(parse_rate(value).is_none(), "{} should be rejected", value);
+        }
+    }
+}
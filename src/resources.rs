@@ -0,0 +1,399 @@
+use std::collections::{ HashMap, VecDeque };
+use std::future::Future;
+use std::sync::{ LazyLock, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+use serde::Serialize;
+use serde_json::Value;
+use utoipa::ToSchema;
+use tokio_util::sync::CancellationToken;
+use tracing::{ info_span, Instrument };
+use crate::models::{ Artifact, CommandResponse };
+use crate::output;
+
+// Everything long-lived a request holds is registered here so it can be audited and
+// force-released at runtime. Releasing cancels the resource's token, which also cancels
+// every resource acquired inside it (a job's processes, a port's esptool run).
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceKind {
+    Job,
+    Process,
+    SerialPort,
+    Workspace,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct ResourceInfo {
+    pub id: u64,
+    pub kind: ResourceKind,
+    pub name: String,
+    pub owner: Option<String>,
+    pub parent: Option<u64>,
+    pub started_at: u64,
+    // Correlation id of a job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+}
+
+// Where a job is at, as `my-jobs` reports it
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+// A job of a client, running or finished recently
+#[derive(Serialize, Clone)]
+pub struct JobSummary {
+    pub job_id: String,
+    pub event: String,
+    pub request_id: Option<Value>,
+    pub state: JobState,
+    // Percentage done, known for daemon compiles while they run
+    pub progress: Option<u8>,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub error_code: Option<String>,
+    pub build_id: Option<String>,
+    pub artifacts: Vec<Artifact>,
+}
+
+struct Entry {
+    info: ResourceInfo,
+    token: CancellationToken,
+    // The request a job serves
+    request: Request,
+    // Progress and answer of a job
+    summary: Option<JobSummary>,
+}
+
+// A finished job, kept for its client to look up
+struct Finished {
+    owner: Option<String>,
+    session: Option<String>,
+    summary: JobSummary,
+}
+
+// Finished jobs kept for `my-jobs`, the oldest dropped past it
+const FINISHED_KEPT: usize = 256;
+
+static REGISTRY: LazyLock<Mutex<HashMap<u64, Entry>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static FINISHED: LazyLock<Mutex<VecDeque<Finished>>> = LazyLock::new(Default::default);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Who the current task works for and which resource it runs inside
+#[derive(Clone)]
+struct Context {
+    owner: Option<String>,
+    parent: Option<u64>,
+    token: CancellationToken,
+}
+
+// The job the current task runs in
+#[derive(Clone)]
+pub struct CurrentJob {
+    pub id: String,
+    pub name: String,
+    pub started_at: u64,
+    pub started: Instant,
+    pub request: Request,
+}
+
+// The client request being handled, which the jobs it starts inherit
+#[derive(Clone, Default)]
+pub struct Request {
+    // See `connection::request_id`
+    pub id: Option<Value>,
+    // Its jobs carry on when the client disconnects
+    pub detach: bool,
+    // Session of the client's socket, whose later sockets may cancel its jobs too
+    pub session: Option<String>,
+}
+
+tokio::task_local! {
+    static CONTEXT: Context;
+    static JOB: CurrentJob;
+    static REQUEST: Request;
+}
+
+fn current() -> Option<Context> {
+    CONTEXT.try_with(|c| c.clone()).ok()
+}
+
+// Correlation id of the job the current task runs in, carried by its log lines and responses
+pub fn current_job_id() -> Option<String> {
+    JOB.try_with(|job| job.id.clone()).ok()
+}
+
+pub fn current_job() -> Option<CurrentJob> {
+    JOB.try_with(|job| job.clone()).ok()
+}
+
+// The id the client gave the request the current task serves, to echo on what it sends
+pub fn current_request_id() -> Option<Value> {
+    current_request().id
+}
+
+// The request being handled, or else the one of the job the current task runs in
+fn current_request() -> Request {
+    REQUEST.try_with(|request| request.clone())
+        .or_else(|_| JOB.try_with(|job| job.request.clone()))
+        .unwrap_or_default()
+}
+
+// Run `f` handling `request`, the jobs it starts keep its id and whether they detach
+pub fn with_request<R>(request: Request, f: impl FnOnce() -> R) -> R {
+    REQUEST.sync_scope(request, f)
+}
+
+// Resolves once the resource the current task runs in is released, never outside of one
+pub async fn cancelled() {
+    match current() {
+        Some(context) => context.token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Owner of the current task, if it runs on behalf of a client
+pub fn current_owner() -> Option<String> {
+    current().and_then(|c| c.owner)
+}
+
+// Registration of a resource, removed from the registry when dropped
+pub struct ResourceGuard {
+    id: u64,
+    owner: Option<String>,
+    token: CancellationToken,
+}
+
+impl ResourceGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    // Resolves once this resource (or one it was acquired in) is released
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    // Run `fut` inside this resource, so what it acquires is released along with it
+    pub async fn scope<F: Future>(&self, fut: F) -> F::Output {
+        let context = Context { owner: self.owner.clone(), parent: Some(self.id), token: self.token.clone() };
+        CONTEXT.scope(context, fut).await
+    }
+}
+
+impl Drop for ResourceGuard {
+    fn drop(&mut self) {
+        let Some(entry) = REGISTRY.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+        let Some(mut summary) = entry.summary else {
+            return;
+        };
+        // A job that answered may still have been cancelled afterwards, like a language server
+        summary.state = match summary.state {
+            _ if entry.token.is_cancelled() => JobState::Cancelled,
+            JobState::Queued | JobState::Running => JobState::Succeeded,
+            state => state,
+        };
+        if summary.state == JobState::Succeeded {
+            summary.progress = Some(100);
+        }
+        summary.finished_at = Some(now());
+        let mut finished = FINISHED.lock().unwrap();
+        finished.push_back(Finished { owner: entry.info.owner, session: entry.request.session, summary });
+        while finished.len() > FINISHED_KEPT {
+            finished.pop_front();
+        }
+    }
+}
+
+// Register a resource held by the current task
+pub fn acquire(kind: ResourceKind, name: impl Into<String>) -> ResourceGuard {
+    let context = current();
+    let owner = context.as_ref().and_then(|c| c.owner.clone());
+    let token = context
+        .as_ref()
+        .map(|c| c.token.child_token())
+        .unwrap_or_default();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let info = ResourceInfo {
+        id,
+        kind,
+        name: name.into(),
+        owner: owner.clone(),
+        parent: context.and_then(|c| c.parent),
+        started_at: now(),
+        job_id: None,
+    };
+    REGISTRY.lock().unwrap().insert(id, Entry { info, token: token.clone(), request: Request::default(), summary: None });
+
+    ResourceGuard { id, owner, token }
+}
+
+// Run `fut` as a job owned by `owner`, under a fresh correlation id. Its span is opened right
+// away, under the span of the event that started it.
+pub fn job<F: Future>(owner: String, name: &str, fut: F) -> impl Future<Output = F::Output> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let request = current_request();
+    let span = info_span!("job", job_id = %id, job = name, owner = %owner, request_id = tracing::field::Empty);
+    if let Some(request_id) = &request.id {
+        span.record("request_id", tracing::field::display(request_id));
+    }
+    let log = id.clone();
+    let current = CurrentJob { id: id.clone(), name: name.to_string(), started_at: now(), started: Instant::now(), request: request.clone() };
+    let name = name.to_string();
+    let context = Context { owner: Some(owner), parent: None, token: CancellationToken::new() };
+    JOB.scope(current, CONTEXT.scope(context, async move {
+        let guard = acquire(ResourceKind::Job, name);
+        if let Some(entry) = REGISTRY.lock().unwrap().get_mut(&guard.id) {
+            entry.summary = Some(JobSummary {
+                job_id: id.clone(),
+                event: entry.info.name.clone(),
+                request_id: request.id.clone(),
+                state: JobState::Running,
+                progress: None,
+                started_at: entry.info.started_at,
+                finished_at: None,
+                error_code: None,
+                build_id: None,
+                artifacts: Vec::new(),
+            });
+            entry.info.job_id = Some(id);
+            entry.request = request;
+        }
+        // Open for as long as the job runs, also when it is dropped half way
+        let _log = output::open(&log);
+        guard.scope(fut).await
+    })).instrument(span)
+}
+
+pub fn list() -> Vec<ResourceInfo> {
+    let mut resources: Vec<ResourceInfo> = REGISTRY.lock()
+        .unwrap()
+        .values()
+        .map(|e| e.info.clone())
+        .collect();
+    resources.sort_by_key(|r| r.id);
+    resources
+}
+
+// Release the jobs `owner` started that don't detach, returns how many
+pub fn release_jobs_of(owner: &str) -> usize {
+    let registry = REGISTRY.lock().unwrap();
+    let jobs = registry
+        .values()
+        .filter(|e| e.info.kind == ResourceKind::Job && !e.request.detach && e.info.owner.as_deref() == Some(owner));
+    let mut released = 0;
+    for entry in jobs {
+        entry.token.cancel();
+        released += 1;
+    }
+    released
+}
+
+// Whether the socket `owner` of session `session` may see a job started by `started_by` in
+// `started_in`: its own jobs and those of earlier sockets of its session
+fn owns(started_by: Option<&str>, started_in: Option<&str>, owner: &str, session: Option<&str>) -> bool {
+    started_by == Some(owner) || (session.is_some() && started_in == session)
+}
+
+// Update the summary of the job the current task runs in
+fn update_current_job(update: impl FnOnce(&mut JobSummary)) {
+    let Some(job_id) = current_job_id() else {
+        return;
+    };
+    let mut registry = REGISTRY.lock().unwrap();
+    let summary = registry
+        .values_mut()
+        .filter_map(|e| e.summary.as_mut())
+        .find(|summary| summary.job_id == job_id);
+    if let Some(summary) = summary {
+        update(summary);
+    }
+}
+
+// The current job waits for a worker (`queued`) or got one
+pub fn set_job_queued(queued: bool) {
+    update_current_job(|summary| {
+        summary.state = if queued { JobState::Queued } else { JobState::Running };
+    });
+}
+
+// How far the current job got, in percent
+pub fn set_job_progress(percent: f32) {
+    update_current_job(|summary| {
+        summary.progress = Some(percent.clamp(0.0, 100.0).round() as u8);
+    });
+}
+
+// The current job answered with `response`, which decides how it ends
+pub fn set_job_answer(response: &CommandResponse) {
+    update_current_job(|summary| {
+        summary.state = if response.success { JobState::Succeeded } else { JobState::Failed };
+        summary.error_code = response.error_code.map(|code| code.as_str().to_string());
+        summary.build_id = response.build_id.clone();
+        summary.artifacts = response.artifacts.clone();
+    });
+}
+
+// The running and recently finished jobs of the socket `owner` of session `session`, newest first
+pub fn jobs_of(owner: &str, session: Option<&str>) -> Vec<JobSummary> {
+    let mut jobs: Vec<JobSummary> = REGISTRY.lock()
+        .unwrap()
+        .values()
+        .filter(|e| owns(e.info.owner.as_deref(), e.request.session.as_deref(), owner, session))
+        .filter_map(|e| e.summary.clone())
+        .collect();
+    jobs.extend(
+        FINISHED.lock()
+            .unwrap()
+            .iter()
+            .filter(|f| owns(f.owner.as_deref(), f.session.as_deref(), owner, session))
+            .map(|f| f.summary.clone())
+    );
+    jobs.sort_by_key(|job| std::cmp::Reverse(job.started_at));
+    jobs
+}
+
+// Release the job `job_id` for the socket `owner` of session `session`, which must have started
+// it, returning the job's name and request id
+pub fn cancel_job(job_id: &str, owner: &str, session: Option<&str>) -> Result<(String, Option<Value>), String> {
+    let registry = REGISTRY.lock().unwrap();
+    let entry = registry
+        .values()
+        .find(|e| e.info.kind == ResourceKind::Job && e.info.job_id.as_deref() == Some(job_id))
+        .ok_or_else(|| format!("No running job {}", job_id))?;
+    if !owns(entry.info.owner.as_deref(), entry.request.session.as_deref(), owner, session) {
+        // Someone else's job looks like no job at all
+        return Err(format!("No running job {}", job_id));
+    }
+    entry.token.cancel();
+    Ok((entry.info.name.clone(), entry.request.id.clone()))
+}
+
+// Force-release a resource, returns false if it is not registered
+pub fn release(id: u64) -> bool {
+    match REGISTRY.lock().unwrap().get(&id) {
+        Some(entry) => {
+            entry.token.cancel();
+            true
+        }
+        None => false,
+    }
+}
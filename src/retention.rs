@@ -0,0 +1,314 @@
+// Retention of stored outputs. A background reaper deletes build directories, artifact store
+// entries and idle workspaces according to the policy, so the disk doesn't fill with stale
+// builds:
+//   CLOUD_COMPILER_RETENTION_MAX_AGE_HOURS        builds and stored artifacts (default 168)
+//   CLOUD_COMPILER_RETENTION_MAX_TOTAL_MB         builds plus stored artifacts, oldest go first
+//   CLOUD_COMPILER_RETENTION_MAX_USER_MB          builds of one identity, its oldest go first
+//   CLOUD_COMPILER_RETENTION_WORKSPACE_MAX_AGE_HOURS  workspaces untouched that long
+//   CLOUD_COMPILER_RETENTION_INTERVAL_SECS        time between runs (default 3600)
+// Unset limits don't apply. `/admin/retention` reports the policy and what the runs removed,
+// and starts a run on demand.
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use serde::Serialize;
+use utoipa::ToSchema;
+use tracing::{ info, warn };
+use crate::artifacts::builds_root;
+use crate::artifactstore::store_root;
+use crate::files::workspace_root;
+use crate::resources::{ self, ResourceKind };
+use crate::usage::{ self, build_owner, dir_size };
+
+const DEFAULT_MAX_AGE_HOURS: u64 = 168;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(3600);
+// Anything this recent may belong to a running job and is never removed for size
+const PROTECTED_AGE: u64 = 3600;
+
+#[derive(Serialize, Clone, Copy, ToSchema)]
+pub struct Policy {
+    pub max_age_secs: Option<u64>,
+    pub max_total_bytes: Option<u64>,
+    pub max_user_bytes: Option<u64>,
+    pub workspace_max_age_secs: Option<u64>,
+    pub interval_secs: u64,
+}
+
+#[derive(Serialize, Clone, Default, ToSchema)]
+pub struct RunReport {
+    pub finished_at: u64,
+    pub duration_ms: u64,
+    pub removed_builds: u64,
+    pub removed_artifacts: u64,
+    pub removed_workspaces: u64,
+    pub freed_bytes: u64,
+    // What is left after the run
+    pub builds_bytes: u64,
+    pub artifacts_bytes: u64,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct RetentionStats {
+    pub policy: Policy,
+    pub runs: u64,
+    pub removed_builds: u64,
+    pub removed_artifacts: u64,
+    pub removed_workspaces: u64,
+    pub freed_bytes: u64,
+    pub last_run: Option<RunReport>,
+}
+
+#[derive(Default)]
+struct Totals {
+    runs: u64,
+    removed_builds: u64,
+    removed_artifacts: u64,
+    removed_workspaces: u64,
+    freed_bytes: u64,
+    last_run: Option<RunReport>,
+}
+
+static TOTALS: LazyLock<Mutex<Totals>> = LazyLock::new(|| Mutex::new(Totals::default()));
+// Held for the duration of a run, so a manual trigger waits for the periodic one
+static RUNNING: Mutex<()> = Mutex::new(());
+
+#[derive(PartialEq, Clone, Copy)]
+enum Kind {
+    Build,
+    Artifact,
+}
+
+struct Entry {
+    kind: Kind,
+    path: PathBuf,
+    owner: Option<String>,
+    size: u64,
+    modified: u64,
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.trim().parse().ok())
+}
+
+pub fn policy() -> Policy {
+    let max_age_hours = match std::env::var("CLOUD_COMPILER_RETENTION_MAX_AGE_HOURS") {
+        Ok(hours) => hours.trim().parse().ok(),
+        Err(_) => Some(DEFAULT_MAX_AGE_HOURS),
+    };
+    Policy {
+        max_age_secs: max_age_hours.filter(|hours| *hours > 0).map(|hours| hours * 3600),
+        max_total_bytes: env_u64("CLOUD_COMPILER_RETENTION_MAX_TOTAL_MB").map(|mb| mb * 1024 * 1024),
+        max_user_bytes: env_u64("CLOUD_COMPILER_RETENTION_MAX_USER_MB").map(|mb| mb * 1024 * 1024),
+        workspace_max_age_secs: env_u64("CLOUD_COMPILER_RETENTION_WORKSPACE_MAX_AGE_HOURS")
+            .filter(|hours| *hours > 0)
+            .map(|hours| hours * 3600),
+        interval_secs: env_u64("CLOUD_COMPILER_RETENTION_INTERVAL_SECS")
+            .filter(|secs| *secs > 0)
+            .unwrap_or(DEFAULT_INTERVAL.as_secs()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn modified_secs(path: &Path) -> u64 {
+    std::fs
+        ::symlink_metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Latest modification of `path` or anything below it
+fn last_modified(path: &Path) -> u64 {
+    let own = modified_secs(path);
+    match std::fs::read_dir(path) {
+        Ok(entries) if path.is_dir() => entries
+            .filter_map(|e| e.ok())
+            .map(|e| last_modified(&e.path()))
+            .fold(own, u64::max),
+        _ => own,
+    }
+}
+
+// Visible subdirectories of `root`
+fn subdirectories(root: &Path) -> Vec<PathBuf> {
+    std::fs
+        ::read_dir(root)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                .map(|e| e.path())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn entries() -> Vec<Entry> {
+    let builds = subdirectories(&builds_root()).into_iter().map(|path| Entry {
+        kind: Kind::Build,
+        owner: build_owner(&path),
+        size: dir_size(&path),
+        modified: last_modified(&path),
+        path,
+    });
+    let artifacts = subdirectories(&store_root()).into_iter().map(|path| Entry {
+        kind: Kind::Artifact,
+        owner: None,
+        size: dir_size(&path),
+        modified: last_modified(&path),
+        path,
+    });
+    builds.chain(artifacts).collect()
+}
+
+fn remove(entry: &Entry, report: &mut RunReport) {
+    if let Err(e) = std::fs::remove_dir_all(&entry.path) {
+        warn!("Failed to remove {}: {}", entry.path.display(), e);
+        return;
+    }
+    match entry.kind {
+        Kind::Build => {
+            usage::forget_build(&entry.path);
+            report.removed_builds += 1;
+        }
+        Kind::Artifact => report.removed_artifacts += 1,
+    }
+    report.freed_bytes += entry.size;
+}
+
+// Workspaces (one per identity, or the sketches of unauthenticated clients) nothing touched for
+// `max_age` and no job has locked
+fn reap_workspaces(max_age: u64, now: u64, report: &mut RunReport) {
+    let locked: Vec<PathBuf> = resources
+        ::list()
+        .into_iter()
+        .filter(|resource| resource.kind == ResourceKind::Workspace)
+        .map(|resource| PathBuf::from(resource.name))
+        .collect();
+    for path in subdirectories(&workspace_root()) {
+        // Locked sketches are recorded by their canonical path
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if last_modified(&path) + max_age > now || locked.iter().any(|sketch| sketch.starts_with(&canonical)) {
+            continue;
+        }
+        let size = dir_size(&path);
+        match std::fs::remove_dir_all(&path) {
+            Ok(()) => {
+                report.removed_workspaces += 1;
+                report.freed_bytes += size;
+            }
+            Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+}
+
+// Apply the policy once, blocking
+pub fn run() -> RunReport {
+    let _running = RUNNING.lock().unwrap();
+    let started = Instant::now();
+    let policy = policy();
+    let now = now();
+    let mut report = RunReport::default();
+
+    // Oldest first, every pass below removes from the front
+    let mut kept = entries();
+    kept.sort_by_key(|entry| entry.modified);
+
+    if let Some(max_age) = policy.max_age_secs {
+        let (expired, fresh): (Vec<Entry>, Vec<Entry>) = kept.into_iter().partition(|entry| entry.modified + max_age <= now);
+        expired.iter().for_each(|entry| remove(entry, &mut report));
+        kept = fresh;
+    }
+
+    let removable = |entry: &Entry| entry.modified + PROTECTED_AGE <= now;
+    if let Some(max_user) = policy.max_user_bytes {
+        let mut used: HashMap<String, u64> = HashMap::new();
+        for entry in kept.iter().filter(|entry| entry.kind == Kind::Build) {
+            if let Some(owner) = &entry.owner {
+                *used.entry(owner.clone()).or_default() += entry.size;
+            }
+        }
+        kept.retain(|entry| {
+            let Some(used) = entry.owner.as_ref().and_then(|owner| used.get_mut(owner)) else {
+                return true;
+            };
+            if *used <= max_user || !removable(entry) {
+                return true;
+            }
+            *used -= entry.size;
+            remove(entry, &mut report);
+            false
+        });
+    }
+
+    if let Some(max_total) = policy.max_total_bytes {
+        let mut total: u64 = kept.iter().map(|entry| entry.size).sum();
+        kept.retain(|entry| {
+            if total <= max_total || !removable(entry) {
+                return true;
+            }
+            total -= entry.size;
+            remove(entry, &mut report);
+            false
+        });
+    }
+
+    if let Some(max_age) = policy.workspace_max_age_secs {
+        reap_workspaces(max_age, now, &mut report);
+    }
+
+    let remaining = |kind: Kind| kept.iter().filter(|entry| entry.kind == kind).map(|entry| entry.size).sum();
+    report.builds_bytes = remaining(Kind::Build);
+    report.artifacts_bytes = remaining(Kind::Artifact);
+    report.finished_at = now + started.elapsed().as_secs();
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    if report.removed_builds + report.removed_artifacts + report.removed_workspaces > 0 {
+        info!(
+            "Retention removed {} builds, {} artifacts and {} workspaces, {} MB freed",
+            report.removed_builds,
+            report.removed_artifacts,
+            report.removed_workspaces,
+            report.freed_bytes / 1024 / 1024
+        );
+    }
+
+    let mut totals = TOTALS.lock().unwrap();
+    totals.runs += 1;
+    totals.removed_builds += report.removed_builds;
+    totals.removed_artifacts += report.removed_artifacts;
+    totals.removed_workspaces += report.removed_workspaces;
+    totals.freed_bytes += report.freed_bytes;
+    totals.last_run = Some(report.clone());
+    report
+}
+
+pub fn stats() -> RetentionStats {
+    let totals = TOTALS.lock().unwrap();
+    RetentionStats {
+        policy: policy(),
+        runs: totals.runs,
+        removed_builds: totals.removed_builds,
+        removed_artifacts: totals.removed_artifacts,
+        removed_workspaces: totals.removed_workspaces,
+        freed_bytes: totals.freed_bytes,
+        last_run: totals.last_run.clone(),
+    }
+}
+
+// Apply the policy periodically, starting at startup
+pub fn spawn_reaper() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(policy().interval_secs));
+        loop {
+            interval.tick().await;
+            tokio::task::spawn_blocking(run).await.ok();
+        }
+    });
+}
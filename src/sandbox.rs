@@ -0,0 +1,197 @@
+// Execution backends for compiles. Small deployments spawn arduino-cli directly; a container
+// runtime (docker, podman) or nsjail runs each compile isolated instead, without network, with
+// the Arduino data and user directories read-only and only the job's own sketch folder and build
+// outputs writable. Nothing else of the workspaces or the server data directory (keys, other
+// clients' builds) is visible inside.
+//
+// CLOUD_COMPILER_SANDBOX=direct|docker|podman|nsjail selects the backend,
+// CLOUD_COMPILER_SANDBOX_PROGRAM overrides the runtime binary and CLOUD_COMPILER_SANDBOX_IMAGE
+// the container image. The embedded arduino-cli is static, so any image with coreutils works.
+use std::path::{ Path, PathBuf };
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::process::Command as TokioCommand;
+use tracing::info;
+use crate::compiler::{ arduino_data_dir, arduino_user_dir, memory_limit, sketch_dir };
+
+const DEFAULT_IMAGE: &str = "debian:bookworm-slim";
+
+// System directories an nsjail needs to run anything at all
+const NSJAIL_SYSTEM_DIRS: &[&str] = &["/bin", "/lib", "/lib64", "/usr", "/etc"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sandbox {
+    Direct,
+    Container {
+        program: String,
+        image: String,
+    },
+    Nsjail {
+        program: String,
+    },
+}
+
+static SANDBOX: LazyLock<Sandbox> = LazyLock::new(|| {
+    let backend = std::env::var("CLOUD_COMPILER_SANDBOX").unwrap_or_default().trim().to_lowercase();
+    let program = std::env::var("CLOUD_COMPILER_SANDBOX_PROGRAM").ok().filter(|p| !p.trim().is_empty());
+    let sandbox = match backend.as_str() {
+        "docker" | "podman" => Sandbox::Container {
+            program: program.unwrap_or(backend),
+            image: std::env
+                ::var("CLOUD_COMPILER_SANDBOX_IMAGE")
+                .ok()
+                .filter(|i| !i.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_IMAGE.to_string()),
+        },
+        "nsjail" => Sandbox::Nsjail { program: program.unwrap_or(backend) },
+        "" | "direct" => Sandbox::Direct,
+        other => {
+            tracing::warn!("Unknown sandbox backend {}, spawning compiles directly", other);
+            Sandbox::Direct
+        }
+    };
+    info!("Compile sandbox: {}", sandbox.name());
+    sandbox
+});
+
+// Backend compiles run in
+pub fn sandbox() -> &'static Sandbox {
+    &SANDBOX
+}
+
+// Host directories of a job besides the Arduino data and user directories, mounted at the same
+// path so arguments stay valid
+#[derive(Default)]
+pub struct Mounts {
+    read_only: Vec<PathBuf>,
+    writable: Vec<PathBuf>,
+}
+
+impl Mounts {
+    // Of an arduino-cli compile: its sketch folder, output and build paths writable, pinned
+    // library folders read-only
+    pub fn compile(args: &[String]) -> Mounts {
+        let mut mounts = Mounts::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output-dir" | "--build-path" => mounts.writable.extend(args.next().map(PathBuf::from)),
+                "--library" => mounts.read_only.extend(args.next().map(PathBuf::from)),
+                "--fqbn" | "--profile" | "--build-property" => {
+                    args.next();
+                }
+                flag if flag.starts_with('-') => {}
+                sketch => mounts.writable.push(sketch_dir(Path::new(sketch)).to_path_buf()),
+            }
+        }
+        mounts
+    }
+
+    pub fn writable(dirs: Vec<PathBuf>) -> Mounts {
+        Mounts { read_only: Vec::new(), writable: dirs }
+    }
+
+    // Everything mounted read-only and writable, writable directories created first so the
+    // runtime doesn't create them as root
+    fn resolve(&self) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let read_only = [arduino_data_dir(), arduino_user_dir()]
+            .into_iter()
+            .chain(self.read_only.iter().cloned())
+            .filter(|dir| dir.is_dir())
+            .collect();
+        let writable = self.writable
+            .iter()
+            .filter(|dir| std::fs::create_dir_all(dir).is_ok())
+            .cloned()
+            .collect();
+        (read_only, writable)
+    }
+}
+
+fn path_arg(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+impl Sandbox {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sandbox::Direct => "direct",
+            Sandbox::Container { .. } => "container",
+            Sandbox::Nsjail { .. } => "nsjail",
+        }
+    }
+
+    // Command running `program args` inside this backend with `mounts`, ended from inside after
+    // `timeout`: killing the runtime client alone would leave the sandboxed compile running
+    pub fn command(&self, program: &Path, args: &[String], mounts: &Mounts, timeout: Duration) -> TokioCommand {
+        let (read_only, writable) = mounts.resolve();
+        let environment = [
+            ("ARDUINO_DIRECTORIES_DATA", arduino_data_dir()),
+            ("ARDUINO_DIRECTORIES_USER", arduino_user_dir()),
+            ("HOME", PathBuf::from("/tmp")),
+        ];
+
+        match self {
+            Sandbox::Direct => {
+                let mut process = TokioCommand::new(program);
+                process.args(args);
+                process
+            }
+            Sandbox::Container { program: runtime, image } => {
+                let mut process = TokioCommand::new(runtime);
+                process.args(["run", "--rm", "--network", "none"]);
+                // Outputs belong to the server user, not root
+                #[cfg(unix)]
+                {
+                    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+                    process.arg("--user").arg(format!("{}:{}", uid, gid));
+                }
+                if let Some(bytes) = memory_limit() {
+                    process.arg("--memory").arg(bytes.to_string());
+                }
+                process.arg("-v").arg(format!("{0}:{0}:ro", path_arg(program)));
+                for dir in &read_only {
+                    process.arg("-v").arg(format!("{0}:{0}:ro", path_arg(dir)));
+                }
+                for dir in &writable {
+                    process.arg("-v").arg(format!("{0}:{0}", path_arg(dir)));
+                }
+                for (name, value) in &environment {
+                    process.arg("-e").arg(format!("{}={}", name, path_arg(value)));
+                }
+                process.arg(image);
+                process.args(["timeout", "-s", "KILL", &timeout.as_secs().to_string()]);
+                process.arg(program).args(args);
+                process
+            }
+            Sandbox::Nsjail { program: nsjail } => {
+                let mut process = TokioCommand::new(nsjail);
+                process.args(["--mode", "o", "--quiet", "--tmpfsmount", "/tmp"]);
+                process.arg("--time_limit").arg(timeout.as_secs().to_string());
+                // nsjail's own defaults are far too small for a toolchain
+                for limit in ["--rlimit_cpu", "--rlimit_fsize", "--rlimit_nofile", "--rlimit_nproc"] {
+                    process.args([limit, "max"]);
+                }
+                match memory_limit() {
+                    Some(bytes) => process.arg("--rlimit_as").arg((bytes / 1024 / 1024).to_string()),
+                    None => process.args(["--rlimit_as", "max"]),
+                };
+                for dir in NSJAIL_SYSTEM_DIRS.iter().map(Path::new).filter(|dir| dir.exists()) {
+                    process.arg("-R").arg(dir);
+                }
+                process.arg("-R").arg(program);
+                for dir in &read_only {
+                    process.arg("-R").arg(dir);
+                }
+                for dir in &writable {
+                    process.arg("-B").arg(dir);
+                }
+                for (name, value) in &environment {
+                    process.arg("--env").arg(format!("{}={}", name, path_arg(value)));
+                }
+                process.arg("--").arg(program).args(args);
+                process
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+// Build-time secrets. A compile request's `secrets` map (WiFi credentials, API tokens) becomes a
+// `secrets.h` in the sketch folder for the duration of the build, one `#define NAME "value"` per
+// entry, so credentials don't have to live in the uploaded sources:
+//
+//   #include "secrets.h"
+//   WiFi.begin(WIFI_SSID, WIFI_PASSWORD);
+//
+// Secrets never go into arguments, the cache, build manifests or the job history, and every value
+// is masked in the output and diagnostics of the response.
+use std::collections::BTreeMap;
+use serde_json::Value;
+use crate::models::CommandResponse;
+
+pub const HEADER_FILE: &str = "secrets.h";
+const MASK: &str = "[secret]";
+const MAX_SECRETS: usize = 64;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_VALUE_LENGTH: usize = 4096;
+// Shorter values would mask unrelated text all over the output
+const MIN_VALUE_LENGTH: usize = 4;
+
+pub type Secrets = BTreeMap<String, String>;
+
+// Macro names: C identifiers
+fn valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LENGTH &&
+        name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// The `secrets` of a request, empty without
+pub fn from_request(data: &Value) -> Result<Secrets, String> {
+    let fields = match data.get("secrets") {
+        None | Some(Value::Null) => return Ok(Secrets::new()),
+        Some(Value::Object(fields)) => fields,
+        Some(_) => return Err("secrets must be an object of names to strings".to_string()),
+    };
+    if fields.len() > MAX_SECRETS {
+        return Err(format!("At most {} secrets per build", MAX_SECRETS));
+    }
+    let mut secrets = Secrets::new();
+    for (name, value) in fields {
+        if !valid_name(name) {
+            return Err(format!("Invalid secret name: {}", name));
+        }
+        // The value is never echoed in errors
+        let value = value.as_str().ok_or_else(|| format!("Secret {} must be a string", name))?;
+        if value.chars().count() < MIN_VALUE_LENGTH || value.len() > MAX_VALUE_LENGTH {
+            return Err(format!("Secret {} must be {} to {} characters", name, MIN_VALUE_LENGTH, MAX_VALUE_LENGTH));
+        }
+        secrets.insert(name.clone(), value.to_string());
+    }
+    Ok(secrets)
+}
+
+// C string literal contents. Control characters become octal escapes, which unlike `\x` can't
+// run into the characters after them, and `?` is escaped against trigraphs.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '?' => escaped.push_str("\\?"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let mut bytes = [0; 4];
+                for byte in c.encode_utf8(&mut bytes).bytes() {
+                    escaped.push_str(&format!("\\{:03o}", byte));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn header(secrets: &Secrets) -> String {
+    let mut header = String::from("// Generated by the cloud compiler for this build only\n#pragma once\n\n");
+    for (name, value) in secrets {
+        header.push_str(&format!("#define {} \"{}\"\n", name, escape(value)));
+    }
+    header
+}
+
+// The values of a set of secrets as given and as written into the header, to mask in text
+#[derive(Default)]
+pub struct Mask {
+    values: Vec<String>,
+}
+
+impl Mask {
+    pub fn new(secrets: &Secrets) -> Self {
+        let mut values: Vec<String> = secrets.values().flat_map(|value| [value.clone(), escape(value)]).collect();
+        // Longest first, a secret containing another is masked whole
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        values.dedup();
+        Mask { values }
+    }
+
+    pub fn apply(&self, text: &mut String) {
+        for value in &self.values {
+            if text.contains(value.as_str()) {
+                *text = text.replace(value.as_str(), MASK);
+            }
+        }
+    }
+}
+
+// Mask every secret in the text of a response
+pub fn scrub(response: &mut CommandResponse, secrets: &Secrets) {
+    if secrets.is_empty() {
+        return;
+    }
+    let values = Mask::new(secrets);
+    let mask = |text: &mut String| values.apply(text);
+    mask(&mut response.output);
+    if let Some(error) = &mut response.error {
+        mask(error);
+    }
+    // gcc echoes the `#define` lines of the header in its notes
+    if let Some(stderr) = &mut response.stderr {
+        mask(stderr);
+    }
+    for arg in &mut response.args {
+        mask(arg);
+    }
+    for diagnostic in &mut response.diagnostics {
+        mask(&mut diagnostic.message);
+    }
+    for explanation in &mut response.explanations {
+        mask(&mut explanation.title);
+        mask(&mut explanation.explanation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ Diagnostic, Explanation, Severity };
+
+    #[test]
+    fn headers_hold_escaped_literals() {
+        let secrets = Secrets::from([("TOKEN".to_string(), "a\"b\\c?\n\u{1}".to_string())]);
+        assert!(header(&secrets).ends_with("#define TOKEN \"a\\\"b\\\\c\\?\\n\\001\"\n"));
+    }
+
+    #[test]
+    fn requests_are_validated_without_echoing_values() {
+        assert!(from_request(&serde_json::json!({})).unwrap().is_empty());
+        assert_eq!(from_request(&serde_json::json!({ "secrets": { "WIFI_SSID": "home-net" } })).unwrap().len(), 1);
+        for secrets in [
+            serde_json::json!({ "1ABC": "long enough" }),
+            serde_json::json!({ "NAME-X": "long enough" }),
+            serde_json::json!({ "SHORT": "abc" }),
+            serde_json::json!({ "NUMBER": 12345 }),
+            serde_json::json!(["list"]),
+        ] {
+            let error = from_request(&serde_json::json!({ "secrets": secrets })).unwrap_err();
+            assert!(!error.contains("long enough") && !error.contains("abc"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn every_text_of_a_response_is_masked() {
+        let secrets = Secrets::from([
+            ("API_KEY".to_string(), "key-1234".to_string()),
+            ("API_KEY_FULL".to_string(), "key-1234-5678".to_string()),
+        ]);
+        let mut response = CommandResponse {
+            output: "sent key-1234-5678".to_string(),
+            error: Some("bad key-1234".to_string()),
+            stderr: Some("key-1234".to_string()),
+            args: vec!["--build-property=key-1234".to_string()],
+            diagnostics: vec![Diagnostic {
+                severity: Severity::Error,
+                code: String::new(),
+                message: "key-1234 undeclared".to_string(),
+                file: None,
+                line: None,
+            }],
+            explanations: vec![Explanation {
+                code: String::new(),
+                title: "key-1234".to_string(),
+                explanation: "uses key-1234".to_string(),
+                link: String::new(),
+                file: None,
+                line: None,
+            }],
+            ..CommandResponse::default()
+        };
+        scrub(&mut response, &secrets);
+
+        let text = serde_json::to_string(&response).unwrap();
+        assert!(!text.contains("key-1234"), "{}", text);
+        // The longer secret is masked whole, not as the shorter one plus a tail
+        assert_eq!(response.output, "sent [secret]");
+    }
+
+    #[test]
+    fn secrets_on_stderr_are_masked() {
+        let secrets = Secrets::from([("WIFI_PASSWORD".to_string(), "hunter2\"pw".to_string())]);
+        let mut response = CommandResponse {
+            stderr: Some(format!("note: in expansion of macro 'WIFI_PASSWORD'\n#define WIFI_PASSWORD \"{}\"", escape("hunter2\"pw"))),
+            ..CommandResponse::default()
+        };
+        scrub(&mut response, &secrets);
+
+        let stderr = response.stderr.unwrap();
+        assert!(!stderr.contains("hunter2"));
+        assert!(stderr.contains("#define WIFI_PASSWORD \"[secret]\""));
+    }
+}
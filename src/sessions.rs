@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use serde::Serialize;
+use sha2::{ Digest, Sha256 };
+use utoipa::ToSchema;
+use tracing::info;
+use crate::artifacts::builds_root;
+use crate::compiler::server_data_dir;
+
+// Guest sessions are meant for "try it now" pages: short-lived and tightly limited
+pub const GUEST_TTL: Duration = Duration::from_secs(30 * 60);
+pub const GUEST_MAX_COMPILES: u32 = 10;
+// Live sessions at once, bounding the sessions and guest workspaces anyone can make the server hold
+pub const MAX_GUEST_SESSIONS: usize = 1000;
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+// Marker written into build directories and workspaces owned by a guest, holds the expiry as
+// unix seconds
+const GUEST_MARKER: &str = ".guest-expires";
+
+struct GuestSession {
+    expires_at: SystemTime,
+    compiles: u32,
+}
+
+static GUEST_SESSIONS: LazyLock<Mutex<HashMap<String, GuestSession>>> = LazyLock::new(||
+    Mutex::new(HashMap::new())
+);
+
+// Token of the guest session a socket belongs to, stored in its extensions
+#[derive(Clone)]
+pub struct GuestToken(pub String);
+
+#[derive(Serialize, ToSchema)]
+pub struct GuestSessionInfo {
+    pub token: String,
+    pub expires_at: u64,
+    pub max_compiles: u32,
+}
+
+// Name of a session in identities, logs, listings and paths. The token is the credential, so it
+// never shows where others could read it, only a digest of it.
+pub fn guest_session_id(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))[..16].to_string()
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+pub fn create_guest_session() -> Result<GuestSessionInfo, String> {
+    let now = SystemTime::now();
+    let mut sessions = GUEST_SESSIONS.lock().unwrap();
+    sessions.retain(|_, s| s.expires_at > now);
+    if sessions.len() >= MAX_GUEST_SESSIONS {
+        return Err("Too many guest sessions are active, try again later".to_string());
+    }
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let expires_at = now + GUEST_TTL;
+    sessions.insert(token.clone(), GuestSession { expires_at, compiles: 0 });
+
+    Ok(GuestSessionInfo { token, expires_at: unix_secs(expires_at), max_compiles: GUEST_MAX_COMPILES })
+}
+
+// Expiry of a live session, None once it is unknown or expired
+pub fn guest_expiry(token: &str) -> Option<SystemTime> {
+    let sessions = GUEST_SESSIONS.lock().unwrap();
+    sessions
+        .get(token)
+        .map(|s| s.expires_at)
+        .filter(|expires_at| *expires_at > SystemTime::now())
+}
+
+// Count a compile against the guest quota
+pub fn record_guest_compile(token: &str) -> Result<SystemTime, String> {
+    let mut sessions = GUEST_SESSIONS.lock().unwrap();
+    let session = sessions
+        .get_mut(token)
+        .filter(|s| s.expires_at > SystemTime::now())
+        .ok_or("Guest session expired")?;
+    if session.compiles >= GUEST_MAX_COMPILES {
+        return Err(format!("Guest sessions are limited to {} compiles", GUEST_MAX_COMPILES));
+    }
+    session.compiles += 1;
+    Ok(session.expires_at)
+}
+
+// Tag a build directory so it is deleted when the guest session expires
+pub fn mark_guest_build(dir: &Path, expires_at: SystemTime) -> std::io::Result<()> {
+    std::fs::write(dir.join(GUEST_MARKER), unix_secs(expires_at).to_string())
+}
+
+// Whether a build belongs to a guest. Its outputs stay in the build directory, out of the
+// artifact store and the compile cache, so nothing of it outlives the session.
+pub fn is_guest_build(dir: &Path) -> bool {
+    dir.join(GUEST_MARKER).is_file()
+}
+
+fn guests_root() -> PathBuf {
+    server_data_dir().join("guests")
+}
+
+// Workspace of a guest session, apart from every other client's and deleted with the session
+pub fn guest_workspace(token: &str) -> PathBuf {
+    let dir = guests_root().join(guest_session_id(token));
+    if !dir.join(GUEST_MARKER).is_file() && let Some(expires_at) = guest_expiry(token) {
+        let _ = std::fs::create_dir_all(&dir).and_then(|_| mark_guest_build(&dir, expires_at));
+    }
+    dir
+}
+
+// Expiry recorded in a guest's directory
+fn marked_expiry(dir: &Path) -> Option<u64> {
+    std::fs
+        ::read_to_string(dir.join(GUEST_MARKER))
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+}
+
+// Drop expired sessions and delete every guest build and workspace past its expiry. They are
+// found through their marker, so this also covers sessions lost in a restart.
+pub fn reap_expired() {
+    let now = SystemTime::now();
+    GUEST_SESSIONS.lock()
+        .unwrap()
+        .retain(|_, s| s.expires_at > now);
+
+    for entry in std::fs::read_dir(builds_root()).into_iter().flatten().filter_map(|e| e.ok()) {
+        if let Some(expires_at) = marked_expiry(&entry.path()) && expires_at <= unix_secs(now) {
+            info!("Removing expired guest build {}", entry.file_name().to_string_lossy());
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+
+    // A workspace without its marker was left half created, it is gone after a session's time
+    for entry in std::fs::read_dir(guests_root()).into_iter().flatten().filter_map(|e| e.ok()) {
+        let expired = match marked_expiry(&entry.path()) {
+            Some(expires_at) => expires_at <= unix_secs(now),
+            None =>
+                entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| modified + GUEST_TTL <= now),
+        };
+        if expired {
+            info!("Removing expired guest workspace {}", entry.file_name().to_string_lossy());
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+// Periodically clean up after expired guests
+pub fn spawn_reaper() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            tokio::task::spawn_blocking(reap_expired).await.ok();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_ids_hide_the_token() {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let id = guest_session_id(&token);
+        assert_eq!(id, guest_session_id(&token));
+        assert_ne!(id, guest_session_id(&uuid::Uuid::new_v4().simple().to_string()));
+        assert!(!token.contains(&id) && !id.contains(&token));
+    }
+
+    #[test]
+    fn sessions_and_their_compiles_are_capped() {
+        let session = create_guest_session().unwrap();
+        for _ in 0..GUEST_MAX_COMPILES {
+            record_guest_compile(&session.token).unwrap();
+        }
+        assert!(record_guest_compile(&session.token).is_err());
+        assert!(record_guest_compile("unknown").is_err());
+
+        while GUEST_SESSIONS.lock().unwrap().len() < MAX_GUEST_SESSIONS {
+            create_guest_session().unwrap();
+        }
+        assert!(create_guest_session().is_err());
+        GUEST_SESSIONS.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,136 @@
+// Graceful shutdown for rolling deploys. On SIGTERM or SIGINT (on Windows Ctrl+C, Ctrl+Break or
+// the console closing) the server stops taking events (they are answered with `shutting_down`),
+// tells connected clients with a `server-shutdown` event, lets running jobs finish for up to
+// CLOUD_COMPILER_SHUTDOWN_GRACE_SECS (default 300), cancels whatever still runs after that, closes
+// the sockets and stops serving.
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+use socketioxide::SocketIo;
+use socketioxide::adapter::Adapter;
+use tracing::{ info, warn };
+use crate::resources::{ self, ResourceInfo, ResourceKind };
+use crate::ws;
+
+const DEFAULT_GRACE: Duration = Duration::from_secs(300);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Time cancelled jobs get to kill their processes and answer
+const CANCEL_WAIT: Duration = Duration::from_secs(2);
+
+// Jobs without an end of their own, cancelled right away
+const OPEN_ENDED: &[&str] = &["monitor", "warmup", "lsp"];
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+// True once shutdown started, new events are refused
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+fn grace_period() -> Duration {
+    std::env
+        ::var("CLOUD_COMPILER_SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|secs| secs.trim().parse().ok())
+        .map_or(DEFAULT_GRACE, Duration::from_secs)
+}
+
+async fn signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{ signal, SignalKind };
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = interrupt => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                interrupt.await.ok();
+            }
+        }
+    }
+    // Ctrl+Break, a closed console window or a logoff/shutdown of the machine. Windows ends the
+    // process a few seconds after the last two, which cuts the grace period short.
+    #[cfg(windows)]
+    {
+        use tokio::signal::windows::{ ctrl_break, ctrl_close, ctrl_shutdown };
+        match (ctrl_break(), ctrl_close(), ctrl_shutdown()) {
+            (Ok(mut brk), Ok(mut close), Ok(mut shutdown)) => {
+                tokio::select! {
+                    _ = interrupt => {}
+                    _ = brk.recv() => {}
+                    _ = close.recv() => {}
+                    _ = shutdown.recv() => {}
+                }
+            }
+            _ => {
+                interrupt.await.ok();
+            }
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    interrupt.await.ok();
+}
+
+fn running_jobs() -> Vec<ResourceInfo> {
+    resources::list().into_iter().filter(|resource| resource.kind == ResourceKind::Job).collect()
+}
+
+// Resolves once a shutdown signal arrived and running jobs drained, for the server's graceful
+// shutdown
+pub async fn drain<A: Adapter>(ios: Vec<SocketIo<A>>) {
+    signalled().await;
+    let grace = grace_period();
+
+    let notice = serde_json::json!({ "grace_secs": grace.as_secs() });
+    for io in &ios {
+        for namespace in ["/", "/custom"] {
+            if let Some(sockets) = io.of(namespace) {
+                // Only this instance is going away
+                sockets.local().emit("server-shutdown", &notice).await.ok();
+            }
+        }
+    }
+    ws::broadcast("server-shutdown", &notice);
+    finish_jobs().await;
+
+    for io in ios {
+        io.close().await;
+    }
+    ws::close_all();
+    info!("Drained, stopping the server");
+}
+
+// Resolves once a shutdown signal arrived, new events are refused from then on
+pub async fn signalled() {
+    signal().await;
+    DRAINING.store(true, Ordering::Relaxed);
+    info!("Shutting down, waiting up to {} s for running jobs", grace_period().as_secs());
+}
+
+// Let running jobs finish within the grace period and cancel the rest
+pub async fn finish_jobs() {
+    let grace = grace_period();
+    for job in running_jobs().iter().filter(|job| OPEN_ENDED.contains(&job.name.as_str())) {
+        resources::release(job.id);
+    }
+
+    let deadline = Instant::now() + grace;
+    loop {
+        let jobs = running_jobs();
+        if jobs.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!("Cancelling {} jobs still running after the grace period", jobs.len());
+            for job in &jobs {
+                resources::release(job.id);
+            }
+            tokio::time::sleep(CANCEL_WAIT).await;
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
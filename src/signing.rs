@@ -0,0 +1,147 @@
+use std::path::{ Path, PathBuf };
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::{ DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding },
+    traits::PublicKeyParts,
+    RsaPrivateKey,
+    RsaPublicKey,
+};
+use serde::Deserialize;
+use crate::artifacts::find_app_binary;
+use crate::compiler::{ is_safe_name, run_program, server_data_dir };
+use crate::esptool::find_core_tool;
+use crate::files::subject_dir;
+
+// Secure Boot V2 RSA keys are 3072 bit
+const RSA_BITS: usize = 3072;
+
+#[cfg(target_os = "windows")]
+const ESPSECURE_NAMES: &[&str] = &["espsecure.exe"];
+#[cfg(not(target_os = "windows"))]
+const ESPSECURE_NAMES: &[&str] = &["espsecure", "espsecure.py"];
+
+// Key used to sign a build: one stored on the server or a PEM sent with the request
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningKey {
+    Stored(String),
+    Pem(String),
+}
+
+// Keys of an identity, the shared root for anonymous clients
+fn keys_dir(owner: Option<&str>) -> PathBuf {
+    subject_dir(server_data_dir().join("signing-keys"), owner)
+}
+
+fn key_path(owner: Option<&str>, name: &str) -> Result<PathBuf, String> {
+    if !is_safe_name(name) {
+        return Err(format!("Invalid key name: {}", name));
+    }
+    Ok(keys_dir(owner).join(format!("{}.pem", name)))
+}
+
+// Private keys are only readable by the server user
+fn write_private(path: &Path, pem: &str) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(path, pem).map_err(|e| e.to_string())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn public_pem(key: &RsaPrivateKey) -> Result<String, String> {
+    RsaPublicKey::from(key)
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| e.to_string())
+}
+
+// Generate and store a signing key, returns its public key for provisioning the device
+pub fn generate_key(owner: Option<&str>, name: &str) -> Result<String, String> {
+    let path = key_path(owner, name)?;
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), RSA_BITS).map_err(|e| e.to_string())?;
+    let pem = key.to_pkcs8_pem(LineEnding::LF).map_err(|e| e.to_string())?;
+    write_private(&path, &pem)?;
+    public_pem(&key)
+}
+
+// Store an existing RSA-3072 private key (PKCS#1 or PKCS#8 PEM), returns its public key
+pub fn import_key(owner: Option<&str>, name: &str, private_key_pem: &str) -> Result<String, String> {
+    let path = key_path(owner, name)?;
+    let key = RsaPrivateKey::from_pkcs8_pem(private_key_pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(private_key_pem))
+        .map_err(|e| format!("Invalid private key: {}", e))?;
+    if key.size() * 8 != RSA_BITS {
+        return Err(format!("Secure Boot V2 keys must be RSA-{}", RSA_BITS));
+    }
+    write_private(&path, private_key_pem)?;
+    public_pem(&key)
+}
+
+pub fn delete_key(owner: Option<&str>, name: &str) -> Result<(), String> {
+    std::fs::remove_file(key_path(owner, name)?).map_err(|_| format!("No signing key named {}", name))
+}
+
+pub fn list_keys(owner: Option<&str>) -> Vec<String> {
+    let mut names: Vec<String> = std::fs
+        ::read_dir(keys_dir(owner))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file())
+                .filter_map(|e| e.file_name().to_string_lossy().strip_suffix(".pem").map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+// Sign the app image of a finished build into `<sketch>.ino.signed.bin`, a stored key is one of
+// `owner`'s
+pub async fn sign_app_binary(key: &SigningKey, owner: Option<&str>, build_dir: &Path) -> Result<(), String> {
+    let app = find_app_binary(build_dir).ok_or("No application binary produced")?;
+    let espsecure = find_core_tool("esptool_py", ESPSECURE_NAMES).ok_or(
+        "espsecure not found, install the esp32 core first"
+    )?;
+
+    // Inline keys only exist on disk for the duration of the signing
+    let (keyfile, temporary) = match key {
+        SigningKey::Stored(name) => {
+            let path = key_path(owner, name)?;
+            if !path.is_file() {
+                return Err(format!("No signing key named {}", name));
+            }
+            (path, false)
+        }
+        SigningKey::Pem(pem) => {
+            let path = build_dir.join(".signing-key.pem");
+            write_private(&path, pem)?;
+            (path, true)
+        }
+    };
+
+    let args = vec![
+        "sign_data".to_string(),
+        "--version".to_string(),
+        "2".to_string(),
+        "--keyfile".to_string(),
+        keyfile.to_string_lossy().to_string(),
+        "--output".to_string(),
+        app.with_extension("signed.bin").to_string_lossy().to_string(),
+        app.to_string_lossy().to_string()
+    ];
+    let response = run_program(&espsecure, "espsecure", &args).await;
+    if temporary {
+        let _ = std::fs::remove_file(&keyfile);
+    }
+
+    match response.success {
+        true => Ok(()),
+        false => Err(response.error.unwrap_or_default()),
+    }
+}
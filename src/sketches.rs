@@ -0,0 +1,59 @@
+// Sketch lifecycle in a client's workspace, like `arduino-cli sketch new` and `sketch archive`:
+// `sketch-new` creates the skeleton of a fresh sketch, `sketch-archive` zips one for download.
+use std::path::{ Path, PathBuf };
+use serde::Serialize;
+use crate::artifacts::zip_dir;
+use crate::compiler::sketch_dir;
+use crate::files::{ resolve_client_path, safe_relative_path };
+
+// What `arduino-cli sketch new` writes
+const SKETCH_TEMPLATE: &str = "void setup() {\n\n}\n\nvoid loop() {\n\n}\n";
+const MAX_NAME_LENGTH: usize = 63;
+
+#[derive(Serialize)]
+pub struct NewSketch {
+    pub name: String,
+    // Relative to the client's workspace, as `compile-sketch` takes it
+    pub sketch_path: String,
+}
+
+// Sketch names of the Arduino sketch specification: letters, digits, `_`, `-` and `.`, not
+// starting with `-` or `.`
+fn valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LENGTH &&
+        name.chars().next().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+// Create the sketch at `path` (`Blink`, `projects/Blink`) in `workspace`, its folder name being
+// the sketch name
+pub fn create(workspace: &Path, path: &str) -> Result<NewSketch, String> {
+    let relative = safe_relative_path(path).ok_or_else(|| format!("Invalid path: {}", path))?;
+    let name = relative.file_name().unwrap_or_default().to_string_lossy().to_string();
+    if !valid_name(&name) {
+        return Err(format!("Invalid sketch name: {}", name));
+    }
+    let parent = relative.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(workspace.join(parent)).map_err(|e| e.to_string())?;
+    // The parent may not be a link leading out of the workspace
+    let dir = resolve_client_path(workspace, &parent.to_string_lossy())?.join(&name);
+    if dir.exists() {
+        return Err(format!("Sketch already exists: {}", path));
+    }
+    std::fs::create_dir(&dir).map_err(|e| e.to_string())?;
+    std::fs::write(dir.join(format!("{}.ino", name)), SKETCH_TEMPLATE).map_err(|e| e.to_string())?;
+    Ok(NewSketch { name, sketch_path: relative.to_string_lossy().to_string() })
+}
+
+// Zip the sketch folder of `sketch` (the folder or its main .ino) into `output_dir`, as
+// `<name>.zip` holding the `<name>/` folder. Returns the archive's path.
+pub fn archive(sketch: &Path, output_dir: &Path) -> Result<PathBuf, String> {
+    let dir = sketch_dir(sketch);
+    let name = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+    if !dir.join(format!("{}.ino", name)).is_file() {
+        return Err(format!("Not a sketch, {} has no {}.ino", name, name));
+    }
+    let output = output_dir.join(format!("{}.zip", name));
+    zip_dir(dir, &name, &output)?;
+    Ok(output)
+}
@@ -1,191 +1,2573 @@
+use std::time::Duration;
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
 use serde_json::Value;
-use socketioxide::{ extract::{ AckSender, Data, SocketRef }, SocketIo };
-use tracing::info;
+use std::sync::Arc;
+use socketioxide::adapter::Adapter;
+use socketioxide::extract::{ Data, SocketRef };
+use tracing::{ info, info_span, warn, Instrument };
 use crate::models::*;
-use crate::compiler::run_arduino_command;
+use crate::cluster::Session;
+use crate::connection::{ Ack, Connection };
+use crate::compiler::{ check_policy, compiler_diagnostics, error_response, port_name, redact_args, run_arduino_command, sketch_dir };
+use crate::errors::CompilerError;
+use crate::esptool::{ reset_board, run_esptool };
+use crate::power::{ power_control_available, power_cycle };
+use crate::artifacts::{ artifact_path, attach_artifacts, build_dir, new_build_dir, BUILD_PATH_DIR };
+use crate::build::{ post_process, prepare, BuildOptions };
+use crate::encryption;
+use crate::signing;
+use crate::projects;
+use crate::profiles;
+use crate::monitor;
+use crate::firmware;
+use crate::devices;
+use crate::notifications::{ self, JobStatus };
+use crate::analytics;
+use crate::discovery::{ discover_ota_devices, DEFAULT_BROWSE_TIME, MAX_BROWSE_TIME };
+use crate::filesystem::build_filesystem;
+use crate::format::format_files;
+use crate::lint::lint_project;
+use crate::analysis;
+use crate::check;
+use crate::compiledb;
+use crate::lsp;
+use crate::msgpack::{ Binary, MessagePack };
+use crate::nvs::generate_nvs;
+use crate::output;
+use crate::keepalive;
+use crate::sessions::*;
+use crate::auth::{ auth_required, authenticate, redact_credentials, AuthError, AuthMethod, Identity };
+use crate::usage;
+use crate::cache::{ self, Flight };
+use crate::toolchain;
+use crate::shutdown;
+use crate::status;
+use crate::admin;
+use crate::health;
+use crate::history;
+use crate::examples;
+use crate::manifests::{ self, Lockfile };
+use crate::matrix;
+use crate::patches;
+use crate::dispatch;
+use crate::secrets;
+use crate::sketches;
+use crate::git::{ self, GitSource };
+use crate::webhooks;
+use crate::queue::{ self, Priority, Ticket };
+use crate::files::{ client_workspace, resolve_client_path, TempTree };
+use crate::transfers;
+use crate::uploads;
+use crate::ratelimit::{ self, request_ip, retry_after_secs, Limit };
+use crate::i18n::{ localize_response, negotiate_locale, Locale };
+use crate::resources::{ self, acquire, current_job, current_job_id, current_request_id, job, ResourceKind };
+use crate::protocol::{ negotiate, render_response, Protocol };
+use crate::validation::{ self, invalid_response };
 
-pub fn on_connect(socket: SocketRef, Data(data): Data<Value>) {
-    info!(ns = socket.ns(), ?socket.id, "Socket.IO connected");
-    socket.emit("auth", &data).ok();
+// Authenticate a new connection and record its identity, disconnecting it unless it carries
+// valid credentials (or auth is disabled)
+fn admit(socket: &Connection, data: &Value) -> bool {
+    info!(ns = socket.ns(), socket.id = ?socket.id(), "Client connected");
 
-    socket.on("message", |Data::<Value>(data), socket: SocketRef| {
+    let identity = match authenticate(data, &socket.req_parts().headers) {
+        Ok(identity) => identity,
+        Err(AuthError::GuestExpired) => {
+            socket.emit("guest-expired", &()).ok();
+            socket.clone().disconnect().ok();
+            return false;
+        }
+        Err(AuthError::Unauthorized(message)) => {
+            info!(socket.id = ?socket.id(), "Rejected connection: {}", message);
+            socket.emit("unauthorized", &serde_json::json!({ "error": message })).ok();
+            socket.clone().disconnect().ok();
+            return false;
+        }
+    };
+    let mut echoed = redact_credentials(data);
+    if let Some(fields) = echoed.as_object_mut() {
+        fields.insert("identity".to_string(), serde_json::json!(identity));
+    }
+    socket.emit("auth", &echoed).ok();
+    admin::client_connected(&socket.id().to_string(), &identity.subject, socket.ns());
+    if identity.method == AuthMethod::Guest && let Some(token) = data.get("guest_token").and_then(|v| v.as_str()) {
+        socket.extensions().insert(GuestToken(token.to_string()));
+    }
+    socket.extensions().insert(identity);
+    true
+}
+
+pub fn on_connect<A: Adapter>(socket: SocketRef<A>, Data(data): Data<Value>) where Connection: From<SocketRef<A>> {
+    on_connection(Connection::from(socket), data);
+}
+
+// A client of the `/` namespace, over Socket.IO or the WebSocket protocol
+pub fn on_connection(socket: Connection, data: Value) {
+    if !admit(&socket, &data) {
+        return;
+    }
+    // Jobs sent without `detach` stop with the socket, their processes killed and queue places freed
+    socket.on_disconnect(|socket: Connection| {
+        let id = socket.id().to_string();
+        let cancelled = resources::release_jobs_of(&id);
+        if cancelled > 0 {
+            info!(socket = id, cancelled, "Cancelled the jobs of a disconnected socket");
+        }
+        transfers::close_all_of(&id);
+        admin::client_disconnected(&id);
+    });
+    // Detached jobs that outlive the socket report to the client's next one. The session is
+    // scoped by identity, another client naming the same session gets a room of its own.
+    if let Some(session) = data.get("session").and_then(|v| v.as_str()).filter(|session| !session.is_empty()) {
+        let subject = socket.extensions().get::<Identity>().map(|identity| identity.subject).unwrap_or_default();
+        socket.join_session(&format!("{}/{}", subject, session));
+    }
+
+    // Negotiate payload versions from the `accepts` declaration
+    let protocol = negotiate(&data);
+    socket.extensions().insert(protocol);
+    socket.emit("protocol", &protocol).ok();
+
+    // Language for server messages: explicit `locale`, else the browser's Accept-Language
+    let requested_locale = data
+        .get("locale")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .or_else(|| {
+            socket
+                .req_parts()
+                .headers.get("accept-language")
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        });
+    if let Some(locale) = requested_locale.as_deref().and_then(negotiate_locale) {
+        socket.extensions().insert(locale);
+    }
+
+    on(&socket, "message", |socket: Connection, data: Value, _: Ack| {
         info!(?data, "Received event:");
         socket.emit("message-back", &data).ok();
     });
 
-    socket.on("message-with-ack", |Data::<Value>(data), ack: AckSender| {
+    on(&socket, "message-with-ack", |_: Connection, data: Value, ack: Ack| {
         info!(?data, "Received event");
         ack.send(&data).ok();
     });
+    // Switch the language of server messages
+    on(&socket, "set-locale", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<LocaleRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("set-locale", errors));
+                return;
+            }
+        };
+        match negotiate_locale(request.locale.as_deref().unwrap_or_default()) {
+            Some(locale) => {
+                ack.send(&serde_json::json!({ "locale": locale.0 })).ok();
+                socket.extensions().insert(locale);
+            }
+            None => {
+                socket.extensions().remove::<Locale>();
+                ack.send(&serde_json::json!({ "locale": null })).ok();
+            }
+        }
+    });
+
+    // Compile time, uploads and storage used by this identity, with its limits
+    on(&socket, "usage", |socket: Connection, _: Value, ack: Ack| {
+        let result = metered_subject(&socket)
+            .map(|subject| usage::report(&subject))
+            .ok_or_else(|| "Usage is only metered for authenticated clients".to_string());
+        send_response(&socket, ack, &json_response("usage", "", result));
+    });
+
+    // `server-status` events every few seconds until unsubscribed. Served while intake is paused
+    // or the server drains, when availability matters most. Not to guests, the status describes
+    // the whole server.
+    on(&socket, "status-subscribe", |socket: Connection, _: Value, ack: Ack| {
+        socket.join(status::ROOM);
+        send_response(&socket, ack, &json_response("status-subscribe", "", Ok(status::status())));
+    });
+    on(&socket, "status-unsubscribe", |socket: Connection, _: Value, ack: Ack| {
+        socket.leave(status::ROOM);
+        send_response(&socket, ack, &key_response("status-unsubscribe", "", Ok(String::new())));
+    });
+
+    // Start a guest session, bound to the socket when it is anonymous. Once authentication is
+    // required only authenticated clients start them, to hand the token on to a visitor.
+    on(&socket, "create-guest-session", |socket: Connection, _: Value, ack: Ack| {
+        let metered = metered_subject(&socket);
+        if metered.is_none() && auth_required() {
+            let message = "Guest sessions are started by authenticated clients".to_string();
+            let response = CompilerError::Unauthorized(message).response("create-guest-session", vec![]);
+            send_response(&socket, ack, &response);
+            return;
+        }
+        // Anyone could otherwise renew their quota with a new session
+        if metered.is_none() {
+            let ip = request_ip(socket.req_parts()).map(|ip| ip.to_string()).unwrap_or_default();
+            if let Err(wait) = ratelimit::check(Limit::Guests, &ip) {
+                let response = CompilerError::RateLimited(retry_after_secs(wait)).response("create-guest-session", vec![]);
+                send_response(&socket, ack, &response);
+                return;
+            }
+        }
+        let session = match create_guest_session() {
+            Ok(session) => session,
+            Err(e) => {
+                send_response(&socket, ack, &CompilerError::QueueFull(e).response("create-guest-session", vec![]));
+                return;
+            }
+        };
+        if metered.is_none() {
+            socket.extensions().insert(GuestToken(session.token.clone()));
+        }
+        ack.send(&session).ok();
+    });
+
     // Specific commands for common Arduino CLI operations
     register_arduino_handlers(&socket);
+    register_esptool_handlers(&socket);
+    register_encryption_handlers(&socket);
+    register_signing_handlers(&socket);
+    register_image_handlers(&socket);
+    register_project_handlers(&socket);
+    register_monitor_handlers(&socket);
+    register_firmware_handlers(&socket);
+    register_device_handlers(&socket);
+    register_tooling_handlers(&socket);
+    register_job_handlers(&socket);
+    register_transfer_handlers(&socket);
+}
+
+// The `/lsp` namespace: a clangd language server per socket, stopped with it
+pub fn on_lsp_connect<A: Adapter>(socket: SocketRef<A>, Data(data): Data<Value>) where Connection: From<SocketRef<A>> {
+    let socket = Connection::from(socket);
+    if !admit(&socket, &data) {
+        return;
+    }
+    socket.on_disconnect(|socket: Connection| {
+        let _ = lsp::stop(&socket.id().to_string());
+        admin::client_disconnected(&socket.id().to_string());
+    });
+
+    // Start clangd for a sketch and board, acks once it runs and streams `lsp-message` until
+    // `lsp-closed`
+    on(&socket, "lsp-start", |socket: Connection, mut data: Value, ack: Ack| {
+        let request = match validation::parse::<IdeRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("lsp-start", errors));
+                return;
+            }
+        };
+        let resolved = resolve_sketch(&socket, &mut data).and_then(|_| {
+            let sketch = data.get("sketch_path").and_then(|v| v.as_str()).ok_or("Missing sketch path")?;
+            let fqbn = data.get("fqbn").and_then(|v| v.as_str()).ok_or("Missing FQBN")?;
+            Ok((sketch.to_string(), fqbn.to_string(), toolchain::requested(&data)?))
+        });
+        let (sketch, fqbn, toolchain) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let error_response = error_response("lsp-start", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let client = compiledb::ClientPaths {
+            workspace_root: request.workspace_root,
+            arduino_data_dir: request.arduino_data_dir,
+            arduino_user_dir: request.arduino_user_dir,
+        };
+        let workspace = workspace(&socket);
+        let ticket = match queue::join(queue::priority_for(metered_subject(&socket).as_deref(), request.priority)) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("lsp-start", vec![]);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "lsp", toolchain::scope(toolchain, async move {
+            let owner = socket.id().to_string();
+            // Only writing the compilation database takes a worker
+            let server = {
+                let _slot = ticket.ready(|_| {}).await;
+                let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+                lsp::open(&owner, &fqbn, &sketch, &workspace, &client).await
+            };
+            let server = match server {
+                Ok(server) => server,
+                Err(e) => {
+                    let error_response = error_response("lsp-start", vec![sketch], &e);
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let started = serde_json::json!({ "root_uri": lsp::root_uri(&server, &workspace) });
+            send_response(&socket, ack, &json_response("lsp-start", &sketch, Ok(started)));
+            let result = lsp::serve(server, |message| {
+                socket.emit("lsp-message", &message).ok();
+            }).await;
+            socket.emit("lsp-closed", &serde_json::json!({ "error": result.err() })).ok();
+        })));
+    });
+
+    // LSP traffic is too frequent for the event rate limit, clangd queues it
+    socket.on("lsp-message", |socket: Connection, message: Value, _: Ack| {
+        if let Err(e) = lsp::send(&socket.id().to_string(), message) {
+            warn!(socket.id = ?socket.id(), "Dropped LSP message: {}", e);
+        }
+    });
+
+    on(&socket, "lsp-stop", |socket: Connection, _: Value, ack: Ack| {
+        let result = lsp::stop(&socket.id().to_string()).map(|_| String::new());
+        send_response(&socket, ack, &key_response("lsp-stop", "", result));
+    });
+
+    // Files outside the client's copy a definition leads to, like core and library headers
+    on(&socket, "lsp-read-file", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<LspFileRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("lsp-read-file", errors));
+                return;
+            }
+        };
+        let uri = request.uri.as_str();
+        let result = lsp::read_file(&socket.id().to_string(), uri).map(|text| serde_json::json!({ "uri": uri, "text": text }));
+        send_response(&socket, ack, &json_response("lsp-read-file", uri, result));
+    });
+}
+
+// Connections on the MessagePack path, marked so binary data goes out raw
+pub fn on_msgpack_connect<A: Adapter>(socket: SocketRef<A>, data: Data<Value>) where Connection: From<SocketRef<A>> {
+    socket.extensions.insert(MessagePack);
+    on_connect(socket, data);
+}
+
+pub fn on_msgpack_lsp_connect<A: Adapter>(socket: SocketRef<A>, data: Data<Value>) where Connection: From<SocketRef<A>> {
+    socket.extensions.insert(MessagePack);
+    on_lsp_connect(socket, data);
+}
+
+// Events that also count against the per-IP compile limit: everything running the toolchain
+const COMPILE_EVENTS: &[&str] = &[
+    "compile-sketch",
+    "compile-matrix",
+    "compile-from-git",
+    "compile-example",
+    "replay-build",
+    "check-sketch",
+    "preprocess-sketch",
+    "compilation-database",
+    "analyze-sketch",
+    "generate-lockfile",
+    "profile-save",
+    "lsp-start",
+];
+
+// Events guests may send: compiles counted against their session, listing and cancelling
+// them, and uploading the sketch to compile into their workspace. Everything else needs an
+// identity of its own.
+const GUEST_EVENTS: &[&str] = &[
+    "compile-sketch",
+    "compile-example",
+    "compile-matrix",
+    "archive-begin",
+    "archive-chunk",
+    "archive-end",
+    "list-examples",
+    "set-locale",
+    "cancel-job",
+    "my-jobs",
+];
+
+// Events still served while intake is paused or the server drains
+const ALWAYS_SERVED_EVENTS: &[&str] = &["status-subscribe", "status-unsubscribe", "cancel-job", "my-jobs"];
+
+// Events exempt from the per-socket event limit: the chunks and ends of transfers come too fast
+// for it, only their beginnings count
+const UNLIMITED_EVENTS: &[&str] = &["archive-chunk", "archive-end", "download-chunk", "download-end"];
+
+// Answer an event refused before its handler ran: the server is draining or paused, or the
+// client waits `limited` for its rate limit
+fn reject_event(socket: Connection, ack: Ack, limited: Option<Duration>) {
+    if shutdown::draining() {
+        let response = CompilerError::ShuttingDown.response("", vec![]);
+        send_response(&socket, ack, &response);
+        return;
+    }
+    if admin::intake_paused() {
+        let response = CompilerError::IntakePaused.response("", vec![]);
+        send_response(&socket, ack, &response);
+        return;
+    }
+    let retry_after = retry_after_secs(limited.unwrap_or_default());
+    let response = CompilerError::RateLimited(retry_after).response("", vec![]);
+    send_response(&socket, ack, &response);
+}
+
+// Register an event handler behind the rate limits of the connection (or, for compiles, its
+// IP) unless it is one of `UNLIMITED_EVENTS`, refused while intake is paused or once shutdown
+// started unless it is one of `ALWAYS_SERVED_EVENTS`, and to guests unless it is one of
+// `GUEST_EVENTS`
+fn on(socket: &Connection, event: &'static str, handler: impl Fn(Connection, Value, Ack) + Send + Sync + 'static) {
+    socket.on(event, move |socket, data, ack| {
+        // Parent of the jobs the handler starts
+        let _span = info_span!("event", event, socket = %socket.id()).entered();
+        admin::client_event(&socket.id().to_string(), event);
+        let guest = socket.extensions().get::<Identity>().is_some_and(|identity| identity.method == AuthMethod::Guest);
+        if guest && !GUEST_EVENTS.contains(&event) {
+            let message = format!("Guest sessions can't use {}", event);
+            return send_response(&socket, ack, &CompilerError::Unauthorized(message).response(event, vec![]));
+        }
+        if (shutdown::draining() || admin::intake_paused()) && !ALWAYS_SERVED_EVENTS.contains(&event) {
+            return reject_event(socket, ack, None);
+        }
+        let mut limited = match UNLIMITED_EVENTS.contains(&event) {
+            true => None,
+            false => ratelimit::check(Limit::Events, &socket.id().to_string()).err(),
+        };
+        if limited.is_none() && COMPILE_EVENTS.contains(&event) {
+            let ip = request_ip(socket.req_parts()).map(|ip| ip.to_string()).unwrap_or_default();
+            limited = ratelimit::check(Limit::Compiles, &ip).err();
+        }
+        match limited {
+            None => handler(socket, data, ack),
+            Some(wait) => reject_event(socket, ack, Some(wait)),
+        }
+    });
+}
+
+// Identity usage is metered for: clients authenticated with an API key or a JWT
+fn metered_subject(socket: &Connection) -> Option<String> {
+    socket.extensions()
+        .get::<Identity>()
+        .filter(|identity| matches!(identity.method, AuthMethod::ApiKey | AuthMethod::Jwt))
+        .map(|identity| identity.subject)
+}
+
+// Scheduling class asked for in a request (`priority: "interactive" | "normal" | "bulk"`)
+fn requested_priority(data: &Value) -> Option<Priority> {
+    data.get("priority").and_then(|v| serde_json::from_value(v.clone()).ok())
+}
+
+// Workspace of the socket's client, a guest's own for guests
+fn workspace(socket: &Connection) -> std::path::PathBuf {
+    match socket.extensions().get::<GuestToken>() {
+        Some(GuestToken(token)) => guest_workspace(&token),
+        None => client_workspace(metered_subject(socket).as_deref()),
+    }
+}
+
+// Resolve a sketch path sent by the client inside its workspace
+fn client_path(socket: &Connection, path: &str) -> Result<String, String> {
+    let workspace = workspace(socket);
+    resolve_client_path(&workspace, path).map(|path| path.to_string_lossy().to_string())
+}
+
+// Tool output of the socket's job as `compile-output` events
+fn output_events(socket: &Connection) -> output::Sink {
+    let socket = socket.clone();
+    Arc::new(move |stream, text| {
+        socket.emit("compile-output", &serde_json::json!({
+            "job_id": current_job_id(),
+            "request_id": current_request_id(),
+            "stream": stream,
+            "text": text,
+        })).ok();
+    })
+}
+
+// Keepalives of the socket's job as `job-keepalive` events
+fn keepalive_events(socket: &Connection) -> keepalive::Emit {
+    let socket = socket.clone();
+    Arc::new(move |keepalive| {
+        socket.emit("job-keepalive", keepalive).ok();
+    })
+}
+
+// Compile a sketch. `checkout` is a temporary source tree (a git clone, a copied example)
+// removed with the job, `libraries` are pinned library folders to compile with.
+fn compile_sketch(socket: Connection, mut data: Value, ack: Ack, checkout: Option<TempTree>, libraries: Vec<String>) {
+    let request = match validation::parse::<CompileRequest>(&data) {
+        Ok(request) => request,
+        Err(errors) => {
+            send_response(&socket, ack, &invalid_response("compile", errors));
+            return;
+        }
+    };
+    match Lockfile::from_request(&data) {
+        Ok(Some(lockfile)) => {
+            compile_locked(socket, data, ack, checkout, lockfile);
+            return;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            let error_response = error_response("compile", vec![], &e);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    }
+    if let Err(e) = resolve_sketch(&socket, &mut data) {
+        let error_response = error_response("compile", vec![], &e);
+        send_response(&socket, ack, &error_response);
+        return;
+    }
+    let project = request.project;
+    let size_threshold = request.size_threshold_percent;
+
+    // The sketch path as resolved in the client's workspace, or of the project
+    let sketch_path = match data.get("sketch_path").and_then(|v| v.as_str()) {
+        Some(path) => path.to_string(),
+        None => {
+            let error_response = error_response("compile", vec![], "Missing sketch path");
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    };
+
+    // Optional post-compile steps (merged image, encryption)
+    let mut options = match BuildOptions::validated(&data) {
+        Ok(options) => options,
+        Err(e) => {
+            let error_response = error_response("compile", vec![], &e);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    };
+    options.libraries = libraries;
+    let mut args = compile_args(&mut options);
+    let toolchain = match toolchain::requested(&data) {
+        Ok(toolchain) => toolchain,
+        Err(e) => {
+            let error_response = error_response("compile", args, &e);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    };
+
+    // Bounded concurrency, refused before any quota is charged when the queue is full
+    let priority = queue::priority_for(metered_subject(&socket).as_deref(), requested_priority(&data));
+    let ticket = match queue::join(priority) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            let error_response = CompilerError::QueueFull(e).response("compile", vec![]);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    };
+    // Guests have a compile quota and their builds are removed when the session expires
+    let guest_expiry = match socket.extensions().get::<GuestToken>() {
+        Some(GuestToken(token)) =>
+            match record_guest_compile(&token) {
+                Ok(expires_at) => Some(expires_at),
+                Err(e) => {
+                    let error_response = error_response("compile", vec![], &e);
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            }
+        None => None,
+    };
+    // Authenticated identities are metered against their quotas
+    let metered = metered_subject(&socket);
+    if let Some(subject) = &metered && let Err(e) = usage::check_compile(subject) {
+        let error_response = error_response("compile", vec![], &e);
+        send_response(&socket, ack, &error_response);
+        return;
+    }
+
+    // arduino-cli's output as `compile-output` events while it runs, for clients that asked
+    let sink = request.stream_output.unwrap_or(false).then(|| output_events(&socket));
+    let keepalives = keepalive_events(&socket);
+
+    tokio::spawn(job(socket.id().to_string(), "compile-sketch", toolchain::scope(toolchain, output::scope(sink, keepalive::scope(keepalives, "queued", async move {
+        let _checkout = checkout;
+        let owner = socket.id().to_string();
+        let (build_id, build_dir) = match new_build_dir() {
+            Ok(build) => build,
+            Err(e) => {
+                let message = format!("Failed to create build directory: {}", e);
+                let error_response = error_response("compile", args, &message);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        notifications::build_event(&build_id, &owner, JobStatus::Queued, None);
+        if let Some(expires_at) = guest_expiry {
+            mark_guest_build(&build_dir, expires_at).ok();
+        }
+        if let Some(subject) = &metered {
+            usage::mark_build_owner(&build_dir, subject).ok();
+        }
+        args.push("--output-dir".to_string());
+        args.push(build_dir.to_string_lossy().to_string());
+        // A private build path keeps every intermediate file, at the cost of incremental builds
+        if options.keep_build_dir {
+            args.push("--build-path".to_string());
+            args.push(build_dir.join(BUILD_PATH_DIR).to_string_lossy().to_string());
+        }
+        args.push(sketch_path);
+
+        let command = ArduinoCommand {
+            command: "compile".to_string(),
+            args,
+        };
+
+        let started = std::time::Instant::now();
+        let mut timings = Timings::default();
+        let caller = Caller::socket(&socket);
+        let mut response = match run_compile(&caller, ticket, &command, &build_id, &build_dir, &options, metered.as_deref(), &mut timings).await {
+            Ok(response) => response,
+            Err(error_response) => {
+                notifications::build_event(&build_id, &owner, JobStatus::Failure, Some(&error_response));
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let packaging = std::time::Instant::now();
+        keepalive::phase("packaging");
+        async {
+            if let Err(e) = manifests::write(&build_id, &build_dir, &data, &command.args, &response.output).await {
+                warn!(build_id, "{}", e);
+            }
+            attach_artifacts(&mut response, &build_id, &build_dir).await;
+            if let Some(subject) = &metered {
+                usage::record_build(subject, &build_dir).await;
+            }
+        }.instrument(info_span!("packaging")).await;
+        timings.packaging_ms = millis(packaging);
+        if let Some(fqbn) = &options.fqbn {
+            history::track_size(&size_key(project.as_deref(), &options), fqbn, &mut response, size_threshold).await;
+        }
+        if options.timings {
+            timings.total_ms = millis(started);
+            response.timings = Some(timings);
+        }
+        if let Some(name) = &project {
+            projects::record_build(metered_subject(&socket).as_deref(), name, &build_id, response.success).ok();
+        }
+        analytics::record_compile(options.fqbn.as_deref(), &response);
+        let status = if response.success { JobStatus::Success } else { JobStatus::Failure };
+        notifications::build_event(&build_id, &owner, status, Some(&response));
+        send_response(&socket, ack, &response);
+    })))));
+}
+
+// Compile with the versions of a lockfile, installing the missing ones first. The lockfile picks
+// the toolchain, and the board unless the request names one.
+fn compile_locked(socket: Connection, mut data: Value, ack: Ack, checkout: Option<TempTree>, lockfile: Lockfile) {
+    let dependencies = match data.get("toolchain") {
+        Some(_) => Err("A lockfile can't be combined with a toolchain".to_string()),
+        None => lockfile.dependencies(),
+    };
+    let (platforms, libraries) = match dependencies {
+        Ok(dependencies) => dependencies,
+        Err(e) => {
+            let error_response = error_response("compile", vec![], &e);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    };
+    if let Some(fields) = data.as_object_mut() {
+        fields.remove("lockfile");
+        if let Some(fqbn) = lockfile.fqbn {
+            fields.entry("fqbn").or_insert(fqbn.into());
+        }
+    }
+
+    let keepalives = keepalive_events(&socket);
+    tokio::spawn(job(socket.id().to_string(), "lockfile", keepalive::scope(keepalives, "installing", async move {
+        let progress = |version: &str| {
+            socket.emit("lockfile-progress", &serde_json::json!({
+                "job_id": current_job_id(),
+                "request_id": current_request_id(),
+                "stage": "installing",
+                "detail": version,
+            })).ok();
+        };
+        let pins = match manifests::activate(&platforms, &libraries, &lockfile.index_urls, progress).await {
+            Ok(pins) => pins,
+            Err(e) => {
+                let error_response = error_response("compile", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        if let Some(toolchain) = pins.toolchain {
+            data["toolchain"] = toolchain.into();
+        }
+        compile_sketch(socket, data, ack, checkout, pins.libraries);
+    })));
+}
+
+// Resolve the sketch of a compile request in place. Client paths must stay inside the
+// workspace, and so must the path of a stored project unless it is the sketch uploaded with it.
+// One of the client's stored projects stands in for the sketch path and default board.
+fn resolve_sketch(socket: &Connection, data: &mut Value) -> Result<(), String> {
+    if let Some(path) = data.get("sketch_path").and_then(|v| v.as_str()) {
+        data["sketch_path"] = client_path(socket, path)?.into();
+    }
+    if let Some(name) = data.get("project").and_then(|v| v.as_str()) {
+        let owner = metered_subject(socket);
+        let stored = projects::get_project(owner.as_deref(), name)?;
+        let sketch_path = match std::path::Path::new(&stored.sketch_path) == projects::uploaded_sketch(owner.as_deref(), name)? {
+            true => stored.sketch_path,
+            false => client_path(socket, &stored.sketch_path)?,
+        };
+        if let Some(fields) = data.as_object_mut() {
+            fields.entry("sketch_path").or_insert(sketch_path.into());
+            if let Some(board) = stored.board {
+                fields.entry("fqbn").or_insert(board.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Compile a sketch for every target of a matrix, answered with the report of all of them
+fn compile_matrix(socket: Connection, mut data: Value, ack: Ack) {
+    let requests = match resolve_sketch(&socket, &mut data).and_then(|_| matrix::expand(&data)) {
+        Ok(requests) => requests,
+        Err(e) => {
+            let error_response = error_response("compile-matrix", vec![], &e);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    };
+    if data.get("sketch_path").and_then(|v| v.as_str()).is_none() {
+        let error_response = error_response("compile-matrix", vec![], "Missing sketch path");
+        send_response(&socket, ack, &error_response);
+        return;
+    }
+    let metered = metered_subject(&socket);
+    if let Some(subject) = &metered && let Err(e) = usage::check_compile(subject) {
+        let error_response = error_response("compile-matrix", vec![], &e);
+        send_response(&socket, ack, &error_response);
+        return;
+    }
+    // Every target queues right away, the whole matrix is refused when they don't all fit
+    let priority = queue::priority_for(metered.as_deref(), requested_priority(&data));
+    let tickets: Result<Vec<Ticket>, String> = requests.iter().map(|_| queue::join(priority)).collect();
+    let tickets = match tickets {
+        Ok(tickets) => tickets,
+        Err(e) => {
+            let error_response = CompilerError::QueueFull(e).response("compile-matrix", vec![]);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+    };
+    let guest = socket.extensions().get::<GuestToken>();
+
+    let keepalives = keepalive_events(&socket);
+    tokio::spawn(job(socket.id().to_string(), "compile-matrix", keepalive::scope(keepalives, "queued", async move {
+        let caller = Caller::socket(&socket);
+        let targets = requests.into_iter().zip(tickets).map(|(request, ticket)| {
+            let (caller, metered, guest) = (&caller, metered.as_deref(), guest.as_ref());
+            async move {
+                let name = matrix::target_name(&request);
+                let (mut options, invalid) = match BuildOptions::validated(&request) {
+                    Ok(options) => (options, None),
+                    Err(e) => (BuildOptions::from_request(&request), Some(e)),
+                };
+                let mut args = compile_args(&mut options);
+                let built = match invalid.map_or_else(|| toolchain::requested(&request), Err) {
+                    Ok(toolchain) => toolchain::scope(toolchain, compile_target(caller, ticket, &request, &mut args, &options, metered, guest)).await,
+                    Err(e) => Err(error_response("compile", args.clone(), &e)),
+                };
+                let response = built.unwrap_or_else(|response| response);
+                analytics::record_compile(options.fqbn.as_deref(), &response);
+                matrix::TargetReport {
+                    name,
+                    fqbn: options.fqbn,
+                    success: response.success,
+                    error: response.error.filter(|_| !response.success),
+                    build_id: response.build_id,
+                    size: response.size,
+                    size_delta: response.size_delta,
+                    diagnostics: response.diagnostics,
+                    artifacts: response.artifacts,
+                    cached: response.cached,
+                }
+            }
+        });
+        let targets = futures::future::join_all(targets).await;
+        let failed = targets.iter().filter(|target| !target.success).count();
+        let total = targets.len();
+        let report = matrix::MatrixReport { passed: total - failed, failed, targets };
+        let mut response = json_response("compile-matrix", "", Ok(report));
+        if failed > 0 {
+            response.success = false;
+            response.error = Some(format!("{} of {} targets failed", failed, total));
+        }
+        send_response(&socket, ack, &response);
+    })));
+}
+
+// Who a build runs for: the owner of its build events, and where its queue updates go
+pub struct Caller {
+    pub owner: String,
+    pub queued: Box<dyn Fn(Value) + Send + Sync>,
+}
+
+impl Caller {
+    fn socket(socket: &Connection) -> Caller {
+        let emitter = socket.clone();
+        Caller {
+            owner: socket.id().to_string(),
+            queued: Box::new(move |update| {
+                emitter.emit("queue-update", &update).ok();
+                keepalive::activity();
+            }),
+        }
+    }
+}
+
+// One target of a matrix, or a gRPC compile, built like `compile-sketch` builds
+pub async fn compile_target(
+    caller: &Caller,
+    ticket: Ticket,
+    request: &Value,
+    args: &mut Vec<String>,
+    options: &BuildOptions,
+    metered: Option<&str>,
+    guest: Option<&GuestToken>
+) -> Result<CommandResponse, CommandResponse> {
+    let guest_expiry = match guest {
+        Some(GuestToken(token)) => Some(record_guest_compile(token).map_err(|e| error_response("compile", args.clone(), &e))?),
+        None => None,
+    };
+    let owner = caller.owner.clone();
+    let (build_id, build_dir) = new_build_dir().map_err(|e| {
+        error_response("compile", args.clone(), &format!("Failed to create build directory: {}", e))
+    })?;
+    notifications::build_event(&build_id, &owner, JobStatus::Queued, None);
+    if let Some(expires_at) = guest_expiry {
+        mark_guest_build(&build_dir, expires_at).ok();
+    }
+    if let Some(subject) = metered {
+        usage::mark_build_owner(&build_dir, subject).ok();
+    }
+    args.push("--output-dir".to_string());
+    args.push(build_dir.to_string_lossy().to_string());
+    if options.keep_build_dir {
+        args.push("--build-path".to_string());
+        args.push(build_dir.join(BUILD_PATH_DIR).to_string_lossy().to_string());
+    }
+    args.push(options.sketch_path.clone());
+    let command = ArduinoCommand { command: "compile".to_string(), args: args.clone() };
+
+    let started = std::time::Instant::now();
+    let mut timings = Timings::default();
+    let mut response = match run_compile(caller, ticket, &command, &build_id, &build_dir, options, metered, &mut timings).await {
+        Ok(response) => response,
+        Err(error_response) => {
+            notifications::build_event(&build_id, &owner, JobStatus::Failure, Some(&error_response));
+            return Err(error_response);
+        }
+    };
+    let packaging = std::time::Instant::now();
+    async {
+        if let Err(e) = manifests::write(&build_id, &build_dir, request, &command.args, &response.output).await {
+            warn!(build_id, "{}", e);
+        }
+        attach_artifacts(&mut response, &build_id, &build_dir).await;
+        if let Some(subject) = metered {
+            usage::record_build(subject, &build_dir).await;
+        }
+    }.instrument(info_span!("packaging")).await;
+    timings.packaging_ms = millis(packaging);
+    if let Some(fqbn) = &options.fqbn {
+        let project = request.get("project").and_then(|v| v.as_str());
+        let size_threshold = request.get("size_threshold_percent").and_then(|v| v.as_f64());
+        history::track_size(&size_key(project, options), fqbn, &mut response, size_threshold).await;
+    }
+    if options.timings {
+        timings.total_ms = millis(started);
+        response.timings = Some(timings);
+    }
+    let status = if response.success { JobStatus::Success } else { JobStatus::Failure };
+    notifications::build_event(&build_id, &owner, status, Some(&response));
+    Ok(response)
+}
+
+fn millis(since: std::time::Instant) -> u64 {
+    since.elapsed().as_millis() as u64
+}
+
+// What the sizes of a compile are compared across: its stored project, or its sketch folder
+fn size_key(project: Option<&str>, options: &BuildOptions) -> String {
+    match project {
+        Some(project) => format!("project:{}", project),
+        None => format!("sketch:{}", sketch_dir(std::path::Path::new(&options.sketch_path)).display()),
+    }
+}
+
+// Board and profile arguments of a compile. A sketch.yaml profile pins the platform and
+// libraries, and the board unless one is given.
+pub fn compile_args(options: &mut BuildOptions) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(fqbn) = &options.fqbn {
+        args.push("--fqbn".to_string());
+        args.push(fqbn.clone());
+    }
+    if let Some(profile) = &options.profile {
+        args.push("--profile".to_string());
+        args.push(profile.clone());
+        if options.fqbn.is_none() {
+            options.fqbn = profiles::profile_fqbn(std::path::Path::new(&options.sketch_path), profile);
+        }
+    }
+    for library in &options.libraries {
+        args.push("--library".to_string());
+        args.push(library.clone());
+    }
+    args
+}
+
+// Build `command` into `build_dir`, then patch the request's placeholders into the image. An
+// identical earlier or running build answers without a compile of its own, otherwise it compiles
+// once `ticket` gets a worker. A sketch that can't be prepared is the error. The time each phase
+// took goes into `timings`.
+#[allow(clippy::too_many_arguments)]
+async fn run_compile(
+    caller: &Caller,
+    ticket: Ticket,
+    command: &ArduinoCommand,
+    build_id: &str,
+    build_dir: &std::path::Path,
+    options: &BuildOptions,
+    metered: Option<&str>,
+    timings: &mut Timings
+) -> Result<CommandResponse, CommandResponse> {
+    let mut response = build_or_reuse(caller, ticket, command, build_id, build_dir, options, metered, timings).await?;
+    // Patched after caching, the cached build serves every set of patches
+    let patching = std::time::Instant::now();
+    if response.success && !options.patches.is_empty() && let Err(e) = patches::apply(build_dir, &options.patches) {
+        response.success = false;
+        response.error = Some(format!("Patching failed: {}", e));
+    }
+    timings.post_process_ms += millis(patching);
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn build_or_reuse(
+    caller: &Caller,
+    ticket: Ticket,
+    command: &ArduinoCommand,
+    build_id: &str,
+    build_dir: &std::path::Path,
+    options: &BuildOptions,
+    metered: Option<&str>,
+    timings: &mut Timings
+) -> Result<CommandResponse, CommandResponse> {
+    let setup = std::time::Instant::now();
+    let cache_key = cache::cache_key(options);
+    let mut leader = None;
+    if let Some(key) = &cache_key {
+        if let Some(response) = cache::lookup(key, build_dir, options).await {
+            timings.setup_ms = millis(setup);
+            return Ok(response);
+        }
+        match cache::coalesce(key) {
+            Flight::Leader(flight) => {
+                leader = Some(flight);
+            }
+            Flight::Follower(flight) => {
+                if let Some(response) = flight.result(build_dir, options).await {
+                    timings.queue_ms = millis(setup);
+                    return Ok(response);
+                }
+            }
+        }
+    }
+
+    let event = current_job().map(|job| job.name).unwrap_or_default();
+    timings.setup_ms = millis(setup);
+    let waiting = std::time::Instant::now();
+    let _slot = ticket.ready(|update| {
+        (caller.queued)(serde_json::json!({
+            "event": event,
+            "job_id": current_job_id(),
+            "request_id": current_request_id(),
+            "build_id": build_id,
+            "position": update.position,
+            "eta_secs": update.eta_secs,
+        }));
+    }).instrument(info_span!("queue_wait")).await;
+    timings.queue_ms = millis(waiting);
+    keepalive::phase("setup");
+    let setup = std::time::Instant::now();
+    let _workspace = acquire(
+        ResourceKind::Workspace,
+        sketch_dir(std::path::Path::new(&options.sketch_path)).to_string_lossy()
+    );
+    let prepared = match prepare(options).instrument(info_span!("setup")).await {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            return Err(error_response("compile", command.args.clone(), &e));
+        }
+    };
+    timings.setup_ms += millis(setup);
+    notifications::build_event(build_id, &caller.owner, JobStatus::Building, None);
+    keepalive::phase("compiling");
+    let started = std::time::Instant::now();
+    let command = prepared.command(command, options);
+    let mut response = output::live_masked(&options.secrets, dispatch::compile(&command, prepared.sketch_path(), build_dir)).await;
+    timings.compile_ms = millis(started);
+    if let Some(subject) = metered {
+        usage::record_compile(subject, started.elapsed());
+    }
+    prepared.relocate(&mut response, options);
+    drop(prepared);
+    let post = std::time::Instant::now();
+    keepalive::phase("post-processing");
+    post_process(&mut response, build_dir, options, metered).instrument(info_span!("post_process")).await;
+    timings.post_process_ms = millis(post);
+    secrets::scrub(&mut response, &options.secrets);
+    if let Some(key) = &cache_key {
+        cache::store(key, build_dir, options, &response).await;
+    }
+    if let Some(leader) = leader {
+        leader.land(&response, build_dir, options);
+    }
+    Ok(response)
+}
+
+// Acknowledge with a response localized and rendered for the socket's negotiated protocol,
+// tagged with the id of the job sending it, which goes into the job history and out to webhooks
+fn send_response(socket: &Connection, ack: Ack, response: &CommandResponse) {
+    let protocol = socket.extensions().get::<Protocol>().unwrap_or_default();
+    let mut response = response.clone();
+    if let Some(job) = current_job() {
+        let subject = socket.extensions().get::<Identity>().map(|identity| identity.subject);
+        history::record(&job, subject.as_deref(), &socket.id().to_string(), &response);
+        webhooks::job_finished(&job, subject.as_deref(), &response);
+        resources::set_job_answer(&response);
+        response.job_id.get_or_insert(job.id);
+    }
+    if let Some(locale) = socket.extensions().get::<Locale>() {
+        localize_response(&mut response, &locale);
+    }
+    ack.send(&render_response(&response, &protocol)).ok();
+}
+
+// Install a core once `ticket` gets a worker
+pub async fn install_core(caller: &Caller, ticket: Ticket, core_name: &str) -> CommandResponse {
+    let _slot = ticket.ready(|update| {
+        (caller.queued)(serde_json::json!({
+            "event": "install-core",
+            "job_id": current_job_id(),
+            "request_id": current_request_id(),
+            "position": update.position,
+            "eta_secs": update.eta_secs,
+        }));
+    }).await;
+    keepalive::phase("installing-core");
+    let command = ArduinoCommand {
+        command: "core".to_string(),
+        args: vec!["install".to_string(), core_name.to_string()],
+    };
+
+    // Archives other instances already downloaded save fetching them upstream
+    let shared = check_policy(&command).is_ok();
+    let staged = match shared {
+        true => cache::restore_archives(core_name).await,
+        false => Default::default(),
+    };
+    let response = output::live(run_arduino_command(&command)).await;
+    if shared && response.success {
+        cache::share_archives(core_name, &staged).await;
+    }
+    response
+}
+
+// An upload ready to run: its command and the port it takes
+pub struct Upload {
+    command: ArduinoCommand,
+    port: String,
+    build_id: Option<String>,
+    sketch_path: String,
+}
+
+// The upload of the sketch at `sketch_path` (resolved in the client's workspace) that `request` asks for
+pub fn prepare_upload(request: UploadRequest, sketch_path: String) -> Result<Upload, String> {
+    // A LAN address instead of a serial port uploads over the network (ArduinoOTA)
+    let network = request.address.is_some() && request.port.is_none();
+    let port = request.port.map(|port| port_name(&port)).or(request.address).unwrap_or_default();
+
+    let mut args = vec!["--port".to_string(), port.clone(), "--fqbn".to_string(), request.fqbn];
+    // Flash the binaries of a previous compile instead of rebuilding
+    let build_id = request.build_id;
+    if let Some(id) = &build_id {
+        let dir = build_dir(id).ok_or("Unknown build")?;
+        args.push("--input-dir".to_string());
+        args.push(dir.to_string_lossy().to_string());
+    }
+    if network {
+        // espota always asks for the password field, devices without one accept it empty
+        let password = request.ota_password.unwrap_or_default();
+        args.push("--protocol".to_string());
+        args.push("network".to_string());
+        args.push("--upload-field".to_string());
+        args.push(format!("password={}", password));
+    }
+    args.push(sketch_path.clone());
+    let command = ArduinoCommand { command: "upload".to_string(), args };
+    Ok(Upload { command, port, build_id, sketch_path })
+}
+
+// Flash an upload once its port is free, metered against `metered`
+pub async fn run_upload(upload: Upload, metered: Option<&str>) -> CommandResponse {
+    let serial_port = acquire(ResourceKind::SerialPort, upload.port.clone());
+    let response = serial_port.scope(output::live(run_arduino_command(&upload.command))).await;
+    if response.success {
+        monitor::record_flash(&upload.port, upload.build_id, &upload.sketch_path);
+    }
+    if let Some(subject) = metered {
+        usage::record_upload(subject);
+    }
+    response
 }
 
 // Register specific handlers for common Arduino CLI operations
-fn register_arduino_handlers(socket: &SocketRef) {
+fn register_arduino_handlers(socket: &Connection) {
     // List all available boards
-    socket.on("list-boards", |ack: AckSender| {
-        tokio::spawn(async move {
+    on(socket, "list-boards", |socket: Connection, _: Value, ack: Ack| {
+        tokio::spawn(job(socket.id().to_string(), "list-boards", async move {
             let command = ArduinoCommand {
                 command: "board".to_string(),
                 args: vec!["listall".to_string(), "--format".to_string(), "json".to_string()],
             };
 
             let response = run_arduino_command(&command).await;
-            ack.send(&response).ok();
-        });
+            send_response(&socket, ack, &response);
+        }));
     });
 
     // List connected boards
-    socket.on("list-connected", |ack: AckSender| {
-        tokio::spawn(async move {
+    on(socket, "list-connected", |socket: Connection, _: Value, ack: Ack| {
+        tokio::spawn(job(socket.id().to_string(), "list-connected", async move {
             let command = ArduinoCommand {
                 command: "board".to_string(),
                 args: vec!["list".to_string(), "--format".to_string(), "json".to_string()],
             };
 
             let response = run_arduino_command(&command).await;
-            ack.send(&response).ok();
-        });
+            send_response(&socket, ack, &response);
+        }));
     });
 
     // List installed cores
-    socket.on("list-cores", |ack: AckSender| {
-        tokio::spawn(async move {
+    on(socket, "list-cores", |socket: Connection, _: Value, ack: Ack| {
+        tokio::spawn(job(socket.id().to_string(), "list-cores", async move {
             let command = ArduinoCommand {
                 command: "core".to_string(),
                 args: vec!["list".to_string(), "--format".to_string(), "json".to_string()],
             };
 
             let response = run_arduino_command(&command).await;
-            ack.send(&response).ok();
-        });
+            send_response(&socket, ack, &response);
+        }));
     });
 
     // Install a core
-    socket.on("install-core", |Data::<Value>(data), ack: AckSender| {
-        let core_name = match data.get("core").and_then(|v| v.as_str()) {
-            Some(name) => name.to_string(),
-            None => {
-                let error_response = CommandResponse {
-                    success: false,
-                    output: String::new(),
-                    error: Some("Missing core name".to_string()),
-                    command: "core".to_string(),
-                    args: vec!["install".to_string()],
-                };
-                ack.send(&error_response).ok();
+    on(socket, "install-core", |socket: Connection, data: Value, ack: Ack| {
+        let core_name = match validation::parse::<InstallCoreRequest>(&data) {
+            Ok(request) => request.core,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("core", errors));
                 return;
             }
         };
 
-        tokio::spawn(async move {
-            let command = ArduinoCommand {
-                command: "core".to_string(),
-                args: vec!["install".to_string(), core_name],
-            };
-
-            let response = run_arduino_command(&command).await;
-            ack.send(&response).ok();
-        });
-    });
+        let toolchain = match toolchain::requested(&data) {
+            Ok(toolchain) => toolchain,
+            Err(e) => {
+                let error_response = error_response("core", vec!["install".to_string()], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
 
-    // Compile a sketch
-    socket.on("compile-sketch", |Data::<Value>(data), ack: AckSender| {
-        // Extract sketch path and optional FQBN
-        let sketch_path = match data.get("sketch_path").and_then(|v| v.as_str()) {
-            Some(path) => path.to_string(),
-            None => {
-                let error_response = CommandResponse {
-                    success: false,
-                    output: String::new(),
-                    error: Some("Missing sketch path".to_string()),
-                    command: "compile".to_string(),
-                    args: vec![],
-                };
-                ack.send(&error_response).ok();
+        let priority = queue::priority_for(metered_subject(&socket).as_deref(), requested_priority(&data));
+        let ticket = match queue::join(priority) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("core", vec!["install".to_string()]);
+                send_response(&socket, ack, &error_response);
                 return;
             }
         };
 
-        let mut args = vec![];
+        let keepalives = keepalive_events(&socket);
+        tokio::spawn(job(socket.id().to_string(), "install-core", toolchain::scope(toolchain, keepalive::scope(keepalives, "queued", async move {
+            let response = install_core(&Caller::socket(&socket), ticket, &core_name).await;
+            send_response(&socket, ack, &response);
+        }))));
+    });
 
-        // Add FQBN if provided
-        if let Some(fqbn) = data.get("fqbn").and_then(|v| v.as_str()) {
-            args.push("--fqbn".to_string());
-            args.push(fqbn.to_string());
-        }
+    // Compile a sketch
+    on(socket, "compile-sketch", |socket: Connection, data: Value, ack: Ack| {
+        compile_sketch(socket, data, ack, None, Vec::new());
+    });
 
-        args.push(sketch_path);
+    // Fetch a git repository and compile the sketch in it
+    on(socket, "compile-from-git", |socket: Connection, mut data: Value, ack: Ack| {
+        let source = match GitSource::from_request(&data) {
+            Ok(source) => source,
+            Err(e) => {
+                let error_response = error_response("git", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        // Checkouts live in the client's workspace, where compile paths have to resolve
+        let parent = workspace(&socket).join("git");
 
-        tokio::spawn(async move {
-            let command = ArduinoCommand {
-                command: "compile".to_string(),
-                args,
+        tokio::spawn(job(socket.id().to_string(), "compile-from-git", async move {
+            let progress = |stage: &str| {
+                socket.emit("git-progress", &serde_json::json!({
+                    "job_id": current_job_id(),
+                    "request_id": current_request_id(),
+                    "url": source.url,
+                    "ref": source.reference,
+                    "stage": stage,
+                })).ok();
             };
-
-            let response = run_arduino_command(&command).await;
-            ack.send(&response).ok();
-        });
+            let mut checkout = match git::fetch(&source, &parent, progress).await {
+                Ok(checkout) => checkout,
+                Err(e) => {
+                    let error_response = error_response("git", vec![], &e);
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let sketch = match git::locate_sketch(&mut checkout, &source) {
+                Ok(sketch) => sketch,
+                Err(e) => {
+                    let error_response = error_response("git", vec![], &e);
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            progress("compiling");
+            if let Some(fields) = data.as_object_mut() {
+                fields.remove("project");
+                fields.insert("sketch_path".to_string(), sketch.to_string_lossy().to_string().into());
+            }
+            compile_sketch(socket, data, ack, Some(checkout), Vec::new());
+        }));
     });
 
-    // Upload a sketch
-    socket.on("upload-sketch", |Data::<Value>(data), ack: AckSender| {
-        let sketch_path = match data.get("sketch_path").and_then(|v| v.as_str()) {
-            Some(path) => path.to_string(),
-            None => {
-                let error_response = CommandResponse {
-                    success: false,
-                    output: String::new(),
-                    error: Some("Missing sketch path".to_string()),
-                    command: "upload".to_string(),
-                    args: vec![],
-                };
-                ack.send(&error_response).ok();
+    // Examples of the installed platforms and libraries, optionally of one library or platform
+    on(socket, "list-examples", |socket: Connection, data: Value, ack: Ack| {
+        let query = match validation::parse::<ExamplesQuery>(&data) {
+            Ok(query) => query,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("list-examples", errors));
                 return;
             }
         };
+        let library = query.library.map(|library| library.to_lowercase());
+        let platform = query.platform.as_deref();
+        let examples: Vec<examples::Example> = examples
+            ::list()
+            .into_iter()
+            .filter(|example| library.as_ref().is_none_or(|library| example.library.to_lowercase() == *library))
+            .filter(|example| platform.is_none_or(|platform| example.platform.as_deref() == Some(platform)))
+            .collect();
+        send_response(&socket, ack, &json_response("list-examples", "", Ok(examples)));
+    });
 
-        let port = match data.get("port").and_then(|v| v.as_str()) {
-            Some(port) => port.to_string(),
-            None => {
-                let error_response = CommandResponse {
-                    success: false,
-                    output: String::new(),
-                    error: Some("Missing port".to_string()),
-                    command: "upload".to_string(),
-                    args: vec![],
-                };
-                ack.send(&error_response).ok();
+    // Compile an example of an installed platform or library
+    on(socket, "compile-example", |socket: Connection, mut data: Value, ack: Ack| {
+        let request = match validation::parse::<CompileExampleRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("compile", errors));
                 return;
             }
         };
-
-        let fqbn = match data.get("fqbn").and_then(|v| v.as_str()) {
-            Some(fqbn) => fqbn.to_string(),
-            None => {
-                let error_response = CommandResponse {
-                    success: false,
-                    output: String::new(),
-                    error: Some("Missing FQBN".to_string()),
-                    command: "upload".to_string(),
-                    args: vec![],
-                };
-                ack.send(&error_response).ok();
+        let parent = workspace(&socket).join("examples");
+        let copied = match examples::find(&request.example).and_then(|example| examples::copy(&example, &parent)) {
+            Ok(copied) => copied,
+            Err(e) => {
+                let error_response = error_response("compile", vec![], &e);
+                send_response(&socket, ack, &error_response);
                 return;
             }
         };
+        if let Some(fields) = data.as_object_mut() {
+            fields.remove("project");
+            fields.insert("sketch_path".to_string(), copied.tree.to_string_lossy().to_string().into());
+        }
+        compile_sketch(socket, data, ack, Some(copied), Vec::new());
+    });
 
-        let args = vec!["--port".to_string(), port, "--fqbn".to_string(), fqbn, sketch_path];
+    // Compile a build again from its manifest, with the platform and library versions it used
+    on(socket, "replay-build", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<ReplayRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("compile", errors));
+                return;
+            }
+        };
+        let build_id = request.build_id;
+        // Only the caller's own builds, which are metered against the same identity
+        let owned = build_dir(&build_id).is_some_and(|dir| usage::build_owner(&dir) == metered_subject(&socket));
+        let manifest = match owned {
+            true => manifests::load(&build_id),
+            false => Err("Unknown build".to_string()),
+        };
+        let manifest = match manifest {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let error_response = error_response("compile", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        // The sketch may have moved since, the client can point at its new place in its workspace
+        let moved = request.sketch_path.as_deref().map(|path| client_path(&socket, path)).transpose();
+        let sketch_path = match moved {
+            Ok(moved) => moved.unwrap_or_else(|| manifest.sketch_path.clone()),
+            Err(e) => {
+                let error_response = error_response("compile", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
 
-        tokio::spawn(async move {
-            let command = ArduinoCommand {
-                command: "upload".to_string(),
-                args,
+        tokio::spawn(job(socket.id().to_string(), "replay-build", async move {
+            let progress = |stage: &str, detail: Option<&str>| {
+                socket.emit("replay-progress", &serde_json::json!({
+                    "job_id": current_job_id(),
+                    "request_id": current_request_id(),
+                    "build_id": build_id,
+                    "stage": stage,
+                    "detail": detail,
+                })).ok();
             };
-
-            let response = run_arduino_command(&command).await;
-            ack.send(&response).ok();
+            let pins = match manifests::activate(&manifest.platforms, &manifest.libraries, &manifest.index_urls, |version| progress("installing", Some(version))).await {
+                Ok(pins) => pins,
+                Err(e) => {
+                    let error_response = error_response("compile", vec![], &e);
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let mut request = Value::Object(manifest.options);
+            request["sketch_path"] = sketch_path.into();
+            if let Some(toolchain) = pins.toolchain.or(manifest.toolchain) {
+                request["toolchain"] = toolchain.into();
+            }
+            for key in ["priority", "size_threshold_percent", "secrets", "variables", "patches"] {
+                if let Some(value) = data.get(key) {
+                    request[key] = value.clone();
+                }
+            }
+            // arduino-cli itself can't be pinned, a different one is pointed out
+            let toolchain = toolchain::requested(&request).ok().flatten();
+            let version = toolchain::scope(toolchain, health::arduino_cli_version()).await.ok();
+            if let (Some(built), Some(current)) = (&manifest.arduino_cli, &version) && built != current {
+                progress("arduino-cli-mismatch", Some(&format!("built with {}, replaying with {}", built, current)));
+            }
+            progress("compiling", None);
+            compile_sketch(socket, request, ack, None, pins.libraries);
+        }));
+    });
+
+    // Create a sketch skeleton in the client's workspace
+    on(socket, "sketch-new", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<SketchNewRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("sketch-new", errors));
+                return;
+            }
+        };
+        let workspace = workspace(&socket);
+        let result = sketches::create(&workspace, &request.path);
+        send_response(&socket, ack, &json_response("sketch-new", &request.path, result));
+    });
+
+    // Zip a sketch of the workspace, answered like a build with the archive as its artifact
+    on(socket, "sketch-archive", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<SketchArchiveRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("sketch-archive", errors));
+                return;
+            }
+        };
+        let sketch = match client_path(&socket, &request.sketch_path) {
+            Ok(sketch) => sketch,
+            Err(e) => {
+                let error_response = error_response("sketch-archive", vec![request.sketch_path], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "sketch-archive", async move {
+            let (build_id, dir) = match new_build_dir() {
+                Ok(build) => build,
+                Err(e) => {
+                    let error_response = error_response("sketch-archive", vec![sketch], &e.to_string());
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+            let archived = {
+                let (sketch, output) = (sketch.clone(), dir.clone());
+                tokio::task
+                    ::spawn_blocking(move || sketches::archive(std::path::Path::new(&sketch), &output)).await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+            };
+            let response = match archived {
+                Ok(_) => {
+                    let mut response = key_response("sketch-archive", &sketch, Ok(String::new()));
+                    attach_artifacts(&mut response, &build_id, &dir).await;
+                    response
+                }
+                Err(e) => {
+                    let _ = std::fs::remove_dir_all(&dir);
+                    error_response("sketch-archive", vec![sketch], &e)
+                }
+            };
+            send_response(&socket, ack, &response);
+        }));
+    });
+
+    // Compile with `--dump-profile` and save the pinned versions as a profile of sketch.yaml
+    on(socket, "profile-save", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<ProfileRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("profile-save", errors));
+                return;
+            }
+        };
+        let ProfileRequest { sketch_path, fqbn, profile, default: make_default, priority } = request;
+        let checked = match profiles::valid_name(&profile) {
+            false => Err(format!("Invalid profile name: {}", profile)),
+            true => client_path(&socket, &sketch_path),
+        };
+        let sketch = match checked {
+            Ok(sketch) => sketch,
+            Err(e) => {
+                let error_response = error_response("profile-save", vec![profile], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let ticket = match queue::join(queue::priority_for(metered_subject(&socket).as_deref(), priority)) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("profile-save", vec![profile]);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "profile-save", async move {
+            let (_, build_dir) = match new_build_dir() {
+                Ok(build) => build,
+                Err(e) => {
+                    let error_response = error_response("profile-save", vec![profile], &e.to_string());
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let _slot = ticket.ready(|_| {}).await;
+            let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+            let command = ArduinoCommand {
+                command: "compile".to_string(),
+                args: vec![
+                    "--fqbn".to_string(),
+                    fqbn,
+                    "--dump-profile".to_string(),
+                    "--output-dir".to_string(),
+                    build_dir.to_string_lossy().to_string(),
+                    sketch.clone(),
+                ],
+            };
+            let mut response = run_arduino_command(&command).await;
+            response.command = "profile-save".to_string();
+            // Only the profile is wanted, not the build
+            let _ = std::fs::remove_dir_all(&build_dir);
+            if response.success {
+                match profiles::save_profile(std::path::Path::new(&sketch), &profile, &response.output, make_default) {
+                    Ok(yaml) => response.output = yaml,
+                    Err(e) => response = error_response("profile-save", response.args, &e),
+                }
+            }
+            send_response(&socket, ack, &response);
+        }));
+    });
+
+    // Resolve the platform and library versions a sketch builds with into a lockfile
+    on(socket, "generate-lockfile", |socket: Connection, mut data: Value, ack: Ack| {
+        let request = match validation::parse::<SketchRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("generate-lockfile", errors));
+                return;
+            }
+        };
+        let resolved = resolve_sketch(&socket, &mut data).and_then(|_| {
+            let sketch = data.get("sketch_path").and_then(|v| v.as_str()).ok_or("Missing sketch path")?;
+            let fqbn = data.get("fqbn").and_then(|v| v.as_str()).ok_or("Missing FQBN")?;
+            Ok((sketch.to_string(), fqbn.to_string(), toolchain::requested(&data)?))
         });
+        let (sketch, fqbn, toolchain) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let error_response = error_response("generate-lockfile", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let ticket = match queue::join(queue::priority_for(metered_subject(&socket).as_deref(), request.priority)) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("generate-lockfile", vec![]);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "generate-lockfile", toolchain::scope(toolchain, async move {
+            let (_, build_dir) = match new_build_dir() {
+                Ok(build) => build,
+                Err(e) => {
+                    let error_response = error_response("generate-lockfile", vec![], &e.to_string());
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let _slot = ticket.ready(|_| {}).await;
+            let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+            let command = ArduinoCommand {
+                command: "compile".to_string(),
+                args: vec![
+                    "--fqbn".to_string(),
+                    fqbn.clone(),
+                    "--output-dir".to_string(),
+                    build_dir.to_string_lossy().to_string(),
+                    sketch.clone(),
+                ],
+            };
+            let mut response = run_arduino_command(&command).await;
+            // The versions are what's wanted, not the build
+            let _ = std::fs::remove_dir_all(&build_dir);
+            if response.success {
+                response = json_response("generate-lockfile", &sketch, Ok(Lockfile::from_output(Some(fqbn), &response.output)));
+            } else {
+                response.command = "generate-lockfile".to_string();
+            }
+            send_response(&socket, ack, &response);
+        })));
+    });
+
+    // The sketch as the builder hands it to gcc: concatenated, with prototypes and includes
+    on(socket, "preprocess-sketch", |socket: Connection, mut data: Value, ack: Ack| {
+        let request = match validation::parse::<SketchRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("preprocess-sketch", errors));
+                return;
+            }
+        };
+        let resolved = resolve_sketch(&socket, &mut data).and_then(|_| {
+            let sketch = data.get("sketch_path").and_then(|v| v.as_str()).ok_or("Missing sketch path")?;
+            let fqbn = data.get("fqbn").and_then(|v| v.as_str()).ok_or("Missing FQBN")?;
+            Ok((sketch.to_string(), fqbn.to_string(), toolchain::requested(&data)?))
+        });
+        let (sketch, fqbn, toolchain) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let error_response = error_response("preprocess-sketch", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let ticket = match queue::join(queue::priority_for(metered_subject(&socket).as_deref(), request.priority)) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("preprocess-sketch", vec![]);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "preprocess-sketch", toolchain::scope(toolchain, async move {
+            let _slot = ticket.ready(|_| {}).await;
+            let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+            let command = ArduinoCommand {
+                command: "compile".to_string(),
+                args: vec!["--fqbn".to_string(), fqbn, "--preprocess".to_string(), sketch],
+            };
+            let mut response = run_arduino_command(&command).await;
+            response.command = "preprocess-sketch".to_string();
+            if !response.success {
+                response.diagnostics = compiler_diagnostics(response.error.as_deref().unwrap_or_default());
+            }
+            send_response(&socket, ack, &response);
+        })));
+    });
+
+    // Diagnostics of a sketch in a fraction of a compile, for checks on every save
+    on(socket, "check-sketch", |socket: Connection, mut data: Value, ack: Ack| {
+        let request = match validation::parse::<SketchRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("check-sketch", errors));
+                return;
+            }
+        };
+        let resolved = resolve_sketch(&socket, &mut data).and_then(|_| {
+            let sketch = data.get("sketch_path").and_then(|v| v.as_str()).ok_or("Missing sketch path")?;
+            let fqbn = data.get("fqbn").and_then(|v| v.as_str()).ok_or("Missing FQBN")?;
+            Ok((sketch.to_string(), fqbn.to_string(), toolchain::requested(&data)?))
+        });
+        let (sketch, fqbn, toolchain) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let error_response = error_response("check-sketch", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        // Someone is waiting at the editor
+        let priority = request.priority.or(Some(Priority::Interactive));
+        let ticket = match queue::join(queue::priority_for(metered_subject(&socket).as_deref(), priority)) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("check-sketch", vec![]);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "check-sketch", toolchain::scope(toolchain, async move {
+            let _slot = ticket.ready(|_| {}).await;
+            let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+            let response = check::check_sketch(&fqbn, &sketch).await;
+            send_response(&socket, ack, &response);
+        })));
+    });
+
+    // compile_commands.json of a sketch for clangd and other IDE tooling on the client
+    on(socket, "compilation-database", |socket: Connection, mut data: Value, ack: Ack| {
+        let request = match validation::parse::<IdeRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("compilation-database", errors));
+                return;
+            }
+        };
+        let resolved = resolve_sketch(&socket, &mut data).and_then(|_| {
+            let sketch = data.get("sketch_path").and_then(|v| v.as_str()).ok_or("Missing sketch path")?;
+            let fqbn = data.get("fqbn").and_then(|v| v.as_str()).ok_or("Missing FQBN")?;
+            Ok((sketch.to_string(), fqbn.to_string(), toolchain::requested(&data)?))
+        });
+        let (sketch, fqbn, toolchain) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let error_response = error_response("compilation-database", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let client = compiledb::ClientPaths {
+            workspace_root: request.workspace_root,
+            arduino_data_dir: request.arduino_data_dir,
+            arduino_user_dir: request.arduino_user_dir,
+        };
+        let workspace = workspace(&socket);
+        let ticket = match queue::join(queue::priority_for(metered_subject(&socket).as_deref(), request.priority)) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("compilation-database", vec![]);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "compilation-database", toolchain::scope(toolchain, async move {
+            let (_, build_dir) = match new_build_dir() {
+                Ok(build) => build,
+                Err(e) => {
+                    let error_response = error_response("compilation-database", vec![], &e.to_string());
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let _slot = ticket.ready(|_| {}).await;
+            let result = {
+                let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+                compiledb::generate(&fqbn, &sketch, &workspace, &build_dir, &client).await
+            };
+            // Only the database is wanted, not the build
+            let _ = std::fs::remove_dir_all(&build_dir);
+            send_response(&socket, ack, &json_response("compilation-database", &sketch, result));
+        })));
+    });
+
+    // Compile one sketch for several boards or option sets
+    on(socket, "compile-matrix", |socket: Connection, data: Value, ack: Ack| {
+        compile_matrix(socket, data, ack);
+    });
+
+    // Upload a sketch
+    on(socket, "upload-sketch", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<UploadRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("upload", errors));
+                return;
+            }
+        };
+        let sketch_path = match client_path(&socket, &request.sketch_path) {
+            Ok(path) => path,
+            Err(e) => {
+                let error_response = error_response("upload", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        let upload = match prepare_upload(request, sketch_path) {
+            Ok(upload) => upload,
+            Err(e) => {
+                let error_response = error_response("upload", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let toolchain = match toolchain::requested(&data) {
+            Ok(toolchain) => toolchain,
+            Err(e) => {
+                let error_response = error_response("upload", redact_args(&upload.command.args), &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        let metered = metered_subject(&socket);
+        if let Some(subject) = &metered && let Err(e) = usage::check_upload(subject) {
+            let error_response = error_response("upload", vec![], &e);
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+
+        tokio::spawn(job(socket.id().to_string(), "upload-sketch", toolchain::scope(toolchain, async move {
+            let response = run_upload(upload, metered.as_deref()).await;
+            send_response(&socket, ack, &response);
+        })));
+    });
+
+    // Find ArduinoOTA devices on the server's network, for `upload-sketch` with an address
+    on(socket, "discover-ota-devices", |socket: Connection, data: Value, ack: Ack| {
+        let browse_time = data
+            .get("timeout_ms")
+            .and_then(|v| v.as_u64())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_BROWSE_TIME)
+            .min(MAX_BROWSE_TIME);
+
+        tokio::spawn(job(socket.id().to_string(), "discover-ota-devices", async move {
+            let result = discover_ota_devices(browse_time).await;
+            send_response(&socket, ack, &json_response("discover-ota-devices", "", result));
+        }));
+    });
+}
+
+// Register esptool maintenance operations, each maps to an esptool subcommand
+fn register_esptool_handlers(socket: &Connection) {
+    for (event, operation) in [
+        ("chip-info", "flash_id"),
+        ("read-mac", "read_mac"),
+        ("erase-flash", "erase_flash"),
+    ] {
+        on(socket, event, move |socket: Connection, data: Value, ack: Ack| {
+            let request = match validation::parse::<EsptoolRequest>(&data) {
+                Ok(request) => request,
+                Err(errors) => {
+                    send_response(&socket, ack, &invalid_response("esptool", errors));
+                    return;
+                }
+            };
+
+            tokio::spawn(job(socket.id().to_string(), event, async move {
+                let response = run_esptool(&request, operation).await;
+                send_response(&socket, ack, &response);
+            }));
+        });
+    }
+
+    // Recover a wedged board: reset it through DTR/RTS, or into the download mode
+    on(socket, "reset-board", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<ResetRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("reset-board", errors));
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "reset-board", async move {
+            let response = reset_board(&request).await;
+            send_response(&socket, ack, &response);
+        }));
+    });
+
+    // Cut and restore power, on agents with relay or switchable USB hub control
+    on(socket, "power-cycle", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<PowerCycleRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("power-cycle", errors));
+                return;
+            }
+        };
+        let port = request.port;
+        if !power_control_available() {
+            let error_response = error_response(
+                "power-cycle",
+                vec![port],
+                "Power control is not configured on this server"
+            );
+            send_response(&socket, ack, &error_response);
+            return;
+        }
+
+        tokio::spawn(job(socket.id().to_string(), "power-cycle", async move {
+            let response = power_cycle(&port).await;
+            send_response(&socket, ack, &response);
+        }));
+    });
+}
+
+// Wrap the outcome of a key management operation in a response
+fn key_response(command: &str, project: &str, result: Result<String, String>) -> CommandResponse {
+    match result {
+        Ok(output) =>
+            CommandResponse {
+                success: true,
+                output,
+                command: command.to_string(),
+                args: vec![project.to_string()],
+                ..Default::default()
+            },
+        Err(e) => error_response(command, vec![project.to_string()], &e),
+    }
+}
+
+// Register key management for pre-encrypted OTA images, keys are kept per identity
+fn register_encryption_handlers(socket: &Connection) {
+    // Generate a key pair, the private key is only returned here
+    on(socket, "encryption-key-create", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<EncryptionKeyRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("encryption-key-create", errors));
+                return;
+            }
+        };
+        let project = request.project;
+        let owner = metered_subject(&socket);
+        tokio::spawn(job(socket.id().to_string(), "encryption-key-create", async move {
+            let result = {
+                let project = project.clone();
+                tokio::task
+                    ::spawn_blocking(move || encryption::create_key(owner.as_deref(), &project)).await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+            };
+            let response = key_response("encryption-key-create", &project, result);
+            send_response(&socket, ack, &response);
+        }));
+    });
+
+    // Register an existing public key
+    on(socket, "encryption-key-import", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<EncryptionKeyImportRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("encryption-key-import", errors));
+                return;
+            }
+        };
+        let result = encryption
+            ::import_key(metered_subject(&socket).as_deref(), &request.project, &request.public_key)
+            .map(|_| String::new());
+        send_response(&socket, ack, &key_response("encryption-key-import", &request.project, result));
+    });
+
+    // Fetch the public key of a project
+    on(socket, "encryption-key-get", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<EncryptionKeyRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("encryption-key-get", errors));
+                return;
+            }
+        };
+        let result = encryption::get_key(metered_subject(&socket).as_deref(), &request.project);
+        send_response(&socket, ack, &key_response("encryption-key-get", &request.project, result));
+    });
+
+    on(socket, "encryption-key-delete", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<EncryptionKeyRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("encryption-key-delete", errors));
+                return;
+            }
+        };
+        let result = encryption::delete_key(metered_subject(&socket).as_deref(), &request.project).map(|_| String::new());
+        send_response(&socket, ack, &key_response("encryption-key-delete", &request.project, result));
+    });
+}
+
+// Register Secure Boot V2 signing key management, keys stay on the server and each identity
+// only sees its own
+fn register_signing_handlers(socket: &Connection) {
+    // Generate a key, returns the public key to burn into the device eFuse digest
+    on(socket, "signing-key-generate", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<SigningKeyRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("signing-key-generate", errors));
+                return;
+            }
+        };
+        let name = request.name;
+        let owner = metered_subject(&socket);
+        tokio::spawn(job(socket.id().to_string(), "signing-key-generate", async move {
+            let result = {
+                let name = name.clone();
+                tokio::task
+                    ::spawn_blocking(move || signing::generate_key(owner.as_deref(), &name)).await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+            };
+            let response = key_response("signing-key-generate", &name, result);
+            send_response(&socket, ack, &response);
+        }));
+    });
+
+    on(socket, "signing-key-import", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<SigningKeyImportRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("signing-key-import", errors));
+                return;
+            }
+        };
+        let result = signing::import_key(metered_subject(&socket).as_deref(), &request.name, &request.private_key);
+        send_response(&socket, ack, &key_response("signing-key-import", &request.name, result));
+    });
+
+    on(socket, "signing-key-list", |socket: Connection, _: Value, ack: Ack| {
+        let names = signing::list_keys(metered_subject(&socket).as_deref()).join("\n");
+        send_response(&socket, ack, &key_response("signing-key-list", "", Ok(names)));
+    });
+
+    on(socket, "signing-key-delete", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<SigningKeyRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("signing-key-delete", errors));
+                return;
+            }
+        };
+        let result = signing::delete_key(metered_subject(&socket).as_deref(), &request.name).map(|_| String::new());
+        send_response(&socket, ack, &key_response("signing-key-delete", &request.name, result));
+    });
+}
+
+// Register generators for data partition images
+fn register_image_handlers(socket: &Connection) {
+    // Build (and optionally flash) a LittleFS/SPIFFS image of the sketch data/ folder
+    on(socket, "build-filesystem", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<FilesystemRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("build-filesystem", errors));
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "build-filesystem", async move {
+            let response = build_filesystem(&request).await;
+            send_response(&socket, ack, &response);
+        }));
+    });
+    // Generate (and optionally flash) an NVS partition from key/value definitions
+    on(socket, "generate-nvs", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<NvsRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("generate-nvs", errors));
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "generate-nvs", async move {
+            let response = generate_nvs(&request).await;
+            send_response(&socket, ack, &response);
+        }));
+    });
+}
+
+// Serialize the result of an operation as JSON into the response output
+fn json_response<T: serde::Serialize>(command: &str, name: &str, result: Result<T, String>) -> CommandResponse {
+    let result = result.and_then(|value| serde_json::to_string(&value).map_err(|e| e.to_string()));
+    key_response(command, name, result)
+}
+
+// Register the stored project catalog: save, tag and search sketches kept on the server
+fn register_project_handlers(socket: &Connection) {
+    // Create or update a project (sketch, tags, board, metadata)
+    on(socket, "project-save", |socket: Connection, data: Value, ack: Ack| {
+        let mut request = match validation::parse::<ProjectRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("project-save", errors));
+                return;
+            }
+        };
+        if let Some(path) = &request.sketch_path {
+            match client_path(&socket, path) {
+                Ok(resolved) => {
+                    request.sketch_path = Some(resolved);
+                }
+                Err(e) => {
+                    let error_response = error_response("project-save", vec![], &e);
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            }
+        }
+        let result = projects::save_project(metered_subject(&socket).as_deref(), &request);
+        send_response(&socket, ack, &json_response("project-save", &request.name, result));
+    });
+
+    on(socket, "project-get", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<ProjectNameRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("project-get", errors));
+                return;
+            }
+        };
+        let result = projects::get_project(metered_subject(&socket).as_deref(), &request.name);
+        send_response(&socket, ack, &json_response("project-get", &request.name, result));
+    });
+
+    on(socket, "project-delete", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<ProjectNameRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("project-delete", errors));
+                return;
+            }
+        };
+        let result = projects::delete_project(metered_subject(&socket).as_deref(), &request.name).map(|_| String::new());
+        send_response(&socket, ack, &key_response("project-delete", &request.name, result));
+    });
+
+    // Find projects by name, tag, board and last build status
+    on(socket, "project-search", |socket: Connection, data: Value, ack: Ack| {
+        let query = match validation::parse::<ProjectQuery>(&data) {
+            Ok(query) => query,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("project-search", errors));
+                return;
+            }
+        };
+        let owner = metered_subject(&socket);
+        tokio::spawn(job(socket.id().to_string(), "project-search", async move {
+            let found = tokio::task
+                ::spawn_blocking(move || projects::search_projects(owner.as_deref(), &query)).await
+                .map_err(|e| e.to_string());
+            send_response(&socket, ack, &json_response("project-search", "", found));
+        }));
+    });
+}
+
+// Register the recorded serial monitor
+// A chunk read from a monitored port: text, or raw bytes with MessagePack framing
+#[derive(serde::Serialize)]
+struct MonitorData<'a, T> {
+    recording_id: &'a str,
+    data: T,
+}
+
+fn register_monitor_handlers(socket: &Connection) {
+    // Open the monitor, acks with the recording and streams `monitor-data` until closed
+    on(socket, "monitor-start", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<MonitorRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("monitor", errors));
+                return;
+            }
+        };
+        let recording = match monitor::start_recording(&request) {
+            Ok(recording) => recording,
+            Err(e) => {
+                let error_response = error_response("monitor", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        send_response(&socket, ack, &json_response("monitor", &recording.port, Ok(&recording)));
+
+        tokio::spawn(job(socket.id().to_string(), "monitor", async move {
+            let id = recording.id.clone();
+            let owner = socket.id().to_string();
+            let binary = socket.extensions().get::<MessagePack>().is_some();
+            let result = monitor::run_monitor(recording, &owner, |data| {
+                match binary {
+                    true => socket.emit("monitor-data", &MonitorData { recording_id: &id, data: Binary(data) }),
+                    false => socket.emit("monitor-data", &MonitorData { recording_id: &id, data: String::from_utf8_lossy(data) }),
+                }.ok();
+            }).await;
+            let closed = match result {
+                Ok(recording) => serde_json::json!({ "recording_id": id, "recording": recording }),
+                Err(e) => serde_json::json!({ "recording_id": id, "error": e }),
+            };
+            socket.emit("monitor-closed", &closed).ok();
+        }));
+    });
+
+    on(socket, "monitor-stop", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<MonitorStopRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("monitor-stop", errors));
+                return;
+            }
+        };
+        let result = monitor::stop_monitor(&request.recording_id, &socket.id().to_string()).map(|_| String::new());
+        send_response(&socket, ack, &key_response("monitor-stop", &request.recording_id, result));
+    });
+
+    // Recordings of a build (the runtime logs after flashing it) or of a port
+    on(socket, "list-recordings", |socket: Connection, data: Value, ack: Ack| {
+        let query = match validation::parse::<RecordingsQuery>(&data) {
+            Ok(query) => query,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("list-recordings", errors));
+                return;
+            }
+        };
+        let recordings = monitor::list_recordings(query.build_id.as_deref(), query.port.as_deref());
+        send_response(&socket, ack, &json_response("list-recordings", "", Ok(recordings)));
+    });
+}
+
+// Register firmware hosting: publish builds to channels polled by devices over HTTP
+fn register_firmware_handlers(socket: &Connection) {
+    on(socket, "firmware-publish", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<FirmwarePublishRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("firmware-publish", errors));
+                return;
+            }
+        };
+        let FirmwarePublishRequest { build_id, version, channel } = request;
+        let channel = channel.filter(|channel| !channel.is_empty()).unwrap_or_else(|| firmware::DEFAULT_CHANNEL.to_string());
+
+        tokio::spawn(job(socket.id().to_string(), "firmware-publish", async move {
+            let result = {
+                let channel = channel.clone();
+                tokio::task
+                    ::spawn_blocking(move || firmware::publish(&channel, &version, &build_id)).await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+            };
+            if let Ok(release) = &result {
+                notifications::release_published(release);
+            }
+            send_response(&socket, ack, &json_response("firmware-publish", &channel, result));
+        }));
+    });
+
+    on(socket, "firmware-list", |socket: Connection, data: Value, ack: Ack| {
+        let query = match validation::parse::<ReleasesQuery>(&data) {
+            Ok(query) => query,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("firmware-list", errors));
+                return;
+            }
+        };
+        let channel = query.channel.as_deref().unwrap_or(firmware::DEFAULT_CHANNEL);
+        let result = firmware::list_releases(channel);
+        send_response(&socket, ack, &json_response("firmware-list", channel, result));
+    });
+
+    // Point a device at a channel, it is then served from `GET /firmware`
+    on(socket, "firmware-assign", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<FirmwareAssignRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("firmware-assign", errors));
+                return;
+            }
+        };
+        let result = firmware::assign_channel(&request.mac, &request.channel).map(|_| String::new());
+        send_response(&socket, ack, &key_response("firmware-assign", &request.mac, result));
+    });
+}
+
+// Register the device registry and staged rollouts of firmware releases to device groups
+fn register_device_handlers(socket: &Connection) {
+    on(socket, "device-register", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<DeviceRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("device-register", errors));
+                return;
+            }
+        };
+        let DeviceRequest { mac, name, group } = request;
+        let result = devices::register_device(&mac, name, group);
+        send_response(&socket, ack, &json_response("device-register", &mac, result));
+    });
+
+    on(socket, "device-list", |socket: Connection, data: Value, ack: Ack| {
+        let query = match validation::parse::<DeviceQuery>(&data) {
+            Ok(query) => query,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("device-list", errors));
+                return;
+            }
+        };
+        let group = query.group.as_deref();
+        let found = devices::list_devices(group);
+        send_response(&socket, ack, &json_response("device-list", group.unwrap_or_default(), Ok(found)));
+    });
+
+    on(socket, "device-delete", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<DeviceDeleteRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("device-delete", errors));
+                return;
+            }
+        };
+        let result = devices::delete_device(&request.mac).map(|_| String::new());
+        send_response(&socket, ack, &key_response("device-delete", &request.mac, result));
+    });
+
+    // Start a rollout or change its percentage
+    on(socket, "rollout-set", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<RolloutRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("rollout-set", errors));
+                return;
+            }
+        };
+        let group = request.group.as_str();
+        let channel = request.channel.as_deref().filter(|channel| !channel.is_empty()).unwrap_or(firmware::DEFAULT_CHANNEL);
+        // At most 100, as checked with the request
+        let percentage = request.percentage.unwrap_or(100) as u8;
+        let result = devices::set_rollout(group, channel, &request.version, percentage);
+        if let Ok(rollout) = &result && let Some(release) = firmware::find_release(channel, &rollout.version) {
+            notifications::rollout_targets(rollout, &release, &devices::targeted_devices(rollout));
+        }
+        send_response(&socket, ack, &json_response("rollout-set", group, result));
+    });
+
+    on(socket, "rollout-status", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<RolloutGroupRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("rollout-status", errors));
+                return;
+            }
+        };
+        let result = devices::rollout_status(&request.group);
+        send_response(&socket, ack, &json_response("rollout-status", &request.group, result));
+    });
+
+    on(socket, "rollout-delete", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<RolloutGroupRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("rollout-delete", errors));
+                return;
+            }
+        };
+        let result = devices::delete_rollout(&request.group).map(|_| String::new());
+        send_response(&socket, ack, &key_response("rollout-delete", &request.group, result));
+    });
+}
+
+// Register source tooling for editors: formatting, project checks and static analysis
+fn register_tooling_handlers(socket: &Connection) {
+    // Format sources with clang-format, as the formatted text or diffs
+    on(socket, "format-sketch", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<FormatRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("format-sketch", errors));
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "format-sketch", async move {
+            let result = format_files(&request).await;
+            send_response(&socket, ack, &json_response("format-sketch", "", result));
+        }));
+    });
+
+    // Check a sketch or library with arduino-lint, answered with its JSON report
+    on(socket, "lint-project", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<LintRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("lint-project", errors));
+                return;
+            }
+        };
+        let request = match request.sketch_path.as_deref().map(|path| client_path(&socket, path)).transpose() {
+            Ok(sketch_path) => LintRequest { sketch_path, ..request },
+            Err(e) => {
+                let error_response = error_response("lint-project", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "lint-project", async move {
+            let result = lint_project(&request).await;
+            let name = request.name.as_deref().unwrap_or_default();
+            send_response(&socket, ack, &json_response("lint-project", name, result));
+        }));
+    });
+
+    // Run cppcheck over a sketch with the include paths and defines of its board
+    on(socket, "analyze-sketch", |socket: Connection, mut data: Value, ack: Ack| {
+        let request = match validation::parse::<AnalyzeRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("analyze-sketch", errors));
+                return;
+            }
+        };
+        let resolved = resolve_sketch(&socket, &mut data).and_then(|_| {
+            let sketch = data.get("sketch_path").and_then(|v| v.as_str()).ok_or("Missing sketch path")?;
+            let fqbn = data.get("fqbn").and_then(|v| v.as_str()).ok_or("Missing FQBN")?;
+            let checks = analysis::checks(request.checks.as_deref())?;
+            Ok((sketch.to_string(), fqbn.to_string(), checks, toolchain::requested(&data)?))
+        });
+        let (sketch, fqbn, checks, toolchain) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let error_response = error_response("analyze-sketch", vec![], &e);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+        let ticket = match queue::join(queue::priority_for(metered_subject(&socket).as_deref(), request.priority)) {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                let error_response = CompilerError::QueueFull(e).response("analyze-sketch", vec![]);
+                send_response(&socket, ack, &error_response);
+                return;
+            }
+        };
+
+        tokio::spawn(job(socket.id().to_string(), "analyze-sketch", toolchain::scope(toolchain, async move {
+            let (_, build_dir) = match new_build_dir() {
+                Ok(build) => build,
+                Err(e) => {
+                    let error_response = error_response("analyze-sketch", vec![], &e.to_string());
+                    send_response(&socket, ack, &error_response);
+                    return;
+                }
+            };
+            let _slot = ticket.ready(|_| {}).await;
+            let result = {
+                let _workspace = acquire(ResourceKind::Workspace, sketch_dir(std::path::Path::new(&sketch)).to_string_lossy());
+                analysis::analyze(&fqbn, &sketch, &build_dir, &checks).await
+            };
+            // The findings are the result, not the build
+            let _ = std::fs::remove_dir_all(&build_dir);
+            send_response(&socket, ack, &json_response("analyze-sketch", &sketch, result));
+        })));
+    });
+}
+
+// Register control of the client's own jobs
+fn register_job_handlers(socket: &Connection) {
+    // Stop a job of this socket or its session, killing its processes. Served while intake is
+    // paused or the server drains, so clients can still give up on a job.
+    on(socket, "cancel-job", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<JobRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("cancel-job", errors));
+                return;
+            }
+        };
+        let session = socket.extensions().get::<Session>().map(|session| session.0);
+        let id = socket.id().to_string();
+        match resources::cancel_job(&request.job_id, &id, session.as_deref()) {
+            Ok((event, request_id)) => {
+                info!(job_id = request.job_id, "Job cancelled by its client");
+                socket.emit("job-cancelled", &serde_json::json!({
+                    "job_id": request.job_id,
+                    "event": event,
+                    "request_id": request_id,
+                })).ok();
+                send_response(&socket, ack, &key_response("cancel-job", &request.job_id, Ok(String::new())));
+            }
+            Err(e) => send_response(&socket, ack, &error_response("cancel-job", vec![request.job_id], &e)),
+        }
+    });
+
+    // Queued, running and recently finished jobs of this socket or its session, for clients
+    // that reconnect to find what they had running. Served while intake is paused too.
+    on(socket, "my-jobs", |socket: Connection, _: Value, ack: Ack| {
+        let session = socket.extensions().get::<Session>().map(|session| session.0);
+        let jobs = resources::jobs_of(&socket.id().to_string(), session.as_deref());
+        send_response(&socket, ack, &json_response("my-jobs", "", Ok(jobs)));
+    });
+}
+
+// A chunk of a download, raw bytes on MessagePack sockets and base64 otherwise
+#[derive(serde::Serialize)]
+struct DownloadChunk<'a, T> {
+    success: bool,
+    transfer_id: &'a str,
+    seq: u64,
+    sha256: String,
+    data: T,
+}
+
+// Register chunked transfers: project archives up, build artifacts down (see `transfers`)
+fn register_transfer_handlers(socket: &Connection) {
+    // Announce an archive, answered with the transfer id its chunks go to
+    on(socket, "archive-begin", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<TransferBeginRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("archive-begin", errors));
+                return;
+            }
+        };
+        let result = transfers::begin_upload(&socket.id().to_string(), request.size, &request.sha256);
+        send_response(&socket, ack, &json_response("archive-begin", "", result));
+    });
+
+    on(socket, "archive-chunk", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<TransferChunkRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("archive-chunk", errors));
+                return;
+            }
+        };
+        let result = BASE64
+            .decode(&request.data)
+            .map_err(|e| format!("Invalid base64 chunk: {}", e))
+            .and_then(|bytes| {
+                transfers::upload_chunk(&socket.id().to_string(), &request.transfer_id, request.seq, &bytes, &request.sha256)
+            });
+        send_response(&socket, ack, &json_response("archive-chunk", &request.transfer_id, result));
+    });
+
+    // Check the archive and extract it into the workspace like `POST /sketches`
+    on(socket, "archive-end", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<ArchiveEndRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("archive-end", errors));
+                return;
+            }
+        };
+        let archive = match transfers::end_upload(&socket.id().to_string(), &request.transfer_id) {
+            Ok(archive) => archive,
+            Err(e) => {
+                send_response(&socket, ack, &error_response("archive-end", vec![request.transfer_id], &e));
+                return;
+            }
+        };
+        let workspace = workspace(&socket);
+        tokio::spawn(async move {
+            let imported = tokio::task
+                ::spawn_blocking(move || uploads::import(&workspace, &archive, request.subdir.as_deref())).await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            send_response(&socket, ack, &json_response("archive-end", &request.transfer_id, imported));
+        });
+    });
+
+    // Open an artifact of a build for download, answered with its size, checksum and chunk count
+    on(socket, "download-begin", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<DownloadRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("download-begin", errors));
+                return;
+            }
+        };
+        let Some(path) = artifact_path(&request.build_id, &request.name) else {
+            send_response(&socket, ack, &error_response("download-begin", vec![request.name], "Unknown artifact"));
+            return;
+        };
+        tokio::spawn(async move {
+            let result = transfers::begin_download(&socket.id().to_string(), &path).await;
+            send_response(&socket, ack, &json_response("download-begin", &request.name, result));
+        });
+    });
+
+    on(socket, "download-chunk", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<DownloadChunkRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("download-chunk", errors));
+                return;
+            }
+        };
+        let (bytes, sha256) = match transfers::download_chunk(&socket.id().to_string(), &request.transfer_id, request.seq) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                send_response(&socket, ack, &error_response("download-chunk", vec![request.transfer_id], &e));
+                return;
+            }
+        };
+        let (transfer_id, seq) = (request.transfer_id.as_str(), request.seq);
+        match socket.extensions().get::<MessagePack>().is_some() {
+            true => ack.send(&DownloadChunk { success: true, transfer_id, seq, sha256, data: Binary(&bytes) }),
+            false => ack.send(&DownloadChunk { success: true, transfer_id, seq, sha256, data: BASE64.encode(&bytes) }),
+        }.ok();
+    });
+
+    on(socket, "download-end", |socket: Connection, data: Value, ack: Ack| {
+        let request = match validation::parse::<TransferRequest>(&data) {
+            Ok(request) => request,
+            Err(errors) => {
+                send_response(&socket, ack, &invalid_response("download-end", errors));
+                return;
+            }
+        };
+        let result = match transfers::close(&socket.id().to_string(), &request.transfer_id) {
+            true => Ok(String::new()),
+            false => Err(format!("Unknown transfer {}", request.transfer_id)),
+        };
+        send_response(&socket, ack, &key_response("download-end", &request.transfer_id, result));
     });
 }
@@ -0,0 +1,76 @@
+// Server availability for client frontends. Sockets that join the `status` room get a
+// `server-status` event every few seconds: the queue depth, busy workers, whether intake is paused
+// for maintenance or the server drains, and whether a package index update runs. Each instance
+// reports its own state to its own sockets, also when rooms are shared through Redis.
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::Duration;
+use serde::Serialize;
+use socketioxide::SocketIo;
+use socketioxide::adapter::Adapter;
+use crate::admin;
+use crate::queue;
+use crate::shutdown;
+use crate::ws;
+
+pub const ROOM: &str = "status";
+const INTERVAL: Duration = Duration::from_secs(5);
+
+// arduino-cli index updates running
+static INDEX_UPDATES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Clone, Copy)]
+pub struct ServerStatus {
+    // Jobs waiting for a worker
+    pub queue_depth: usize,
+    pub workers: usize,
+    pub workers_busy: usize,
+    // Intake paused by an operator
+    pub maintenance: bool,
+    pub draining: bool,
+    pub index_update: bool,
+}
+
+pub fn status() -> ServerStatus {
+    let queue = queue::stats();
+    ServerStatus {
+        queue_depth: queue.waiting,
+        workers: queue.workers,
+        workers_busy: queue.running,
+        maintenance: admin::intake_paused(),
+        draining: shutdown::draining(),
+        index_update: INDEX_UPDATES.load(Ordering::Relaxed) > 0,
+    }
+}
+
+// An index update in progress, over once dropped
+pub struct IndexUpdate(());
+
+impl Drop for IndexUpdate {
+    fn drop(&mut self) {
+        INDEX_UPDATES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn index_update() -> IndexUpdate {
+    INDEX_UPDATES.fetch_add(1, Ordering::Relaxed);
+    IndexUpdate(())
+}
+
+// Push the status to the `status` room of the client namespaces, and of the WebSocket protocol
+pub fn spawn_broadcaster<A: Adapter>(ios: Vec<SocketIo<A>>) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(INTERVAL);
+        loop {
+            ticks.tick().await;
+            let status = status();
+            for io in &ios {
+                for namespace in ["/", "/custom"] {
+                    if let Some(sockets) = io.of(namespace) {
+                        sockets.local().to(ROOM).emit("server-status", &status).await.ok();
+                    }
+                }
+            }
+            ws::broadcast_to(ROOM, "server-status", &status);
+        }
+    });
+}
@@ -0,0 +1,139 @@
+use crate::models::*;
+
+// Beginner-friendly explanations of common compile errors, for educational frontends.
+// Each rule matches a needle in a compiler diagnostic (or the raw arduino-cli error) and
+// `{}` in its explanation is replaced with the quoted name from the message, if any.
+
+struct Rule {
+    code: &'static str,
+    needles: &'static [&'static str],
+    title: &'static str,
+    explanation: &'static str,
+    link: &'static str,
+}
+
+const RULES: &[Rule] = &[
+    Rule {
+        code: "MISSING_SEMICOLON",
+        needles: &["expected ';'", "expected ',' or ';'"],
+        title: "Missing semicolon",
+        explanation: "Every statement in C++ ends with a semicolon. Look at the end of the line above the one reported, it is probably missing a `;`.",
+        link: "https://docs.arduino.cc/language-reference/en/structure/further-syntax/semicolon/",
+    },
+    Rule {
+        code: "UNDECLARED_IDENTIFIER",
+        needles: &["was not declared in this scope"],
+        title: "Unknown name",
+        explanation: "`{}` is used before it was declared. Check the spelling and upper/lower case, declare the variable before using it, or include the library that provides it.",
+        link: "https://docs.arduino.cc/language-reference/en/variables/variable-scope-qualifiers/scope/",
+    },
+    Rule {
+        code: "UNKNOWN_TYPE",
+        needles: &["does not name a type"],
+        title: "Unknown type",
+        explanation: "`{}` is not a type the compiler knows. It usually comes from a library: add its `#include` at the top of the sketch and check that the library is installed.",
+        link: "https://docs.arduino.cc/software/ide-v1/tutorials/installing-libraries/",
+    },
+    Rule {
+        code: "MISSING_LIBRARY",
+        needles: &["No such file or directory"],
+        title: "Library not found",
+        explanation: "The sketch includes a file that is not installed. Install the library that provides it, or check the name in the `#include` line.",
+        link: "https://docs.arduino.cc/software/ide-v1/tutorials/installing-libraries/",
+    },
+    Rule {
+        code: "UNBALANCED_BRACES",
+        needles: &["expected '}' at end of input", "expected declaration before '}'", "expected unqualified-id before '}'"],
+        title: "Braces do not match",
+        explanation: "Every `{` needs a matching `}`. A brace is missing or there is one too many; indenting the code makes the mismatch easy to spot.",
+        link: "https://docs.arduino.cc/language-reference/en/structure/further-syntax/curlyBraces/",
+    },
+    Rule {
+        code: "DUPLICATE_DEFINITION",
+        needles: &["redefinition of", "multiple definition of"],
+        title: "Defined twice",
+        explanation: "`{}` is defined more than once. A sketch can only have one `setup()` and one `loop()`, and each variable name can only be declared once in the same place.",
+        link: "https://docs.arduino.cc/learn/programming/sketches/",
+    },
+    Rule {
+        code: "MISSING_SETUP_LOOP",
+        needles: &["undefined reference to `setup", "undefined reference to `loop"],
+        title: "setup() or loop() is missing",
+        explanation: "Every sketch needs both a `void setup()` and a `void loop()` function, even if one of them is empty.",
+        link: "https://docs.arduino.cc/learn/programming/sketches/",
+    },
+    Rule {
+        code: "TYPE_MISMATCH",
+        needles: &["invalid conversion from", "cannot convert"],
+        title: "Wrong kind of value",
+        explanation: "A value of one type is used where another is expected, for example text where a number is needed. Check what the function or variable expects.",
+        link: "https://docs.arduino.cc/language-reference/#variables",
+    },
+    Rule {
+        code: "SKETCH_TOO_BIG",
+        needles: &["Sketch too big", "will not fit in region", "overflowed by"],
+        title: "Sketch does not fit",
+        explanation: "The compiled sketch is larger than the space available on the board. Remove unused libraries or large data, or pick a partition scheme with a bigger app partition.",
+        link: "https://docs.espressif.com/projects/arduino-esp32/en/latest/tutorials/partition_table.html",
+    },
+    Rule {
+        code: "WRONG_BOARD",
+        needles: &["Invalid FQBN", "Error resolving FQBN", "Platform '", "not supported on this", "only supported on"],
+        title: "Board not available or not matching",
+        explanation: "The selected board is unknown, not installed, or does not support this code. Check the board selection and that its core is installed.",
+        link: "https://docs.espressif.com/projects/arduino-esp32/en/latest/installing.html",
+    },
+];
+
+// First single-quoted name in a gcc message: "'foo' was not declared" -> "foo"
+fn quoted_name(message: &str) -> Option<&str> {
+    let start = message.find(['\'', '‘'])?;
+    let rest = &message[start..];
+    let rest = &rest[rest.chars().next()?.len_utf8()..];
+    let end = rest.find(['\'', '’'])?;
+    Some(&rest[..end])
+}
+
+fn explanation(rule: &Rule, message: &str, file: Option<String>, line: Option<u32>) -> Explanation {
+    let name = quoted_name(message).unwrap_or("This name");
+    Explanation {
+        code: rule.code.to_string(),
+        title: rule.title.to_string(),
+        explanation: rule.explanation.replace("{}", name),
+        link: rule.link.to_string(),
+        file,
+        line,
+    }
+}
+
+// Explain the errors of a failed compile, one explanation per error location
+pub fn explain(diagnostics: &[Diagnostic], error: Option<&str>) -> Vec<Explanation> {
+    let mut explanations: Vec<Explanation> = Vec::new();
+    let mut push = |explained: Explanation| {
+        let duplicate = explanations
+            .iter()
+            .any(|e| e.code == explained.code && e.file == explained.file && e.line == explained.line);
+        if !duplicate {
+            explanations.push(explained);
+        }
+    };
+
+    for diagnostic in diagnostics.iter().filter(|d| d.severity == Severity::Error) {
+        if let Some(rule) = RULES.iter().find(|r| r.needles.iter().any(|n| diagnostic.message.contains(n))) {
+            push(explanation(rule, &diagnostic.message, diagnostic.file.clone(), diagnostic.line));
+        }
+    }
+
+    // Errors reported by arduino-cli itself (board resolution, size checks) have no location
+    for line in error.unwrap_or_default().lines() {
+        let from_compiler = diagnostics.iter().any(|d| line.contains(&d.message));
+        if from_compiler {
+            continue;
+        }
+        if let Some(rule) = RULES.iter().find(|r| r.needles.iter().any(|n| line.contains(n))) {
+            push(explanation(rule, line, None, None));
+        }
+    }
+
+    explanations
+}
@@ -0,0 +1,76 @@
+// Logging and trace export. Logs go to stdout, as text or, with CLOUD_COMPILER_LOG_FORMAT=json,
+// one JSON object per line carrying the fields of the enclosing spans (the job's `job_id`, ...)
+// so the lines of concurrent compiles can be told apart. With CLOUD_COMPILER_OTLP_ENDPOINT set
+// (`http://localhost:4317`), spans are also exported over OTLP/gRPC to a collector, Jaeger or
+// Tempo, so a compile can be followed from its socket event through the job to every process
+// it ran. CLOUD_COMPILER_OTLP_SERVICE_NAME names the service (default
+// `arduino-esp32-cloud-compiler`).
+use std::sync::OnceLock;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{ SpanExporter, WithExportConfig };
+use opentelemetry_sdk::{ runtime, trace::TracerProvider, Resource };
+use tracing::info;
+use tracing_subscriber::{ filter::LevelFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt };
+
+const DEFAULT_SERVICE_NAME: &str = "arduino-esp32-cloud-compiler";
+
+// Kept for flushing the exporter on shutdown
+static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+fn setting(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn tracer_provider(endpoint: &str) -> Result<TracerProvider, String> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| format!("Invalid OTLP endpoint {}: {}", endpoint, e))?;
+    let service_name = setting("CLOUD_COMPILER_OTLP_SERVICE_NAME").unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+    Ok(
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+            .build()
+    )
+}
+
+// Install the global subscriber. Must run inside the runtime, which drives the span exporter.
+pub fn init() -> Result<(), String> {
+    let json = match setting("CLOUD_COMPILER_LOG_FORMAT").as_deref() {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(format) => return Err(format!("Unknown log format {}, expected text or json", format)),
+    };
+    let endpoint = setting("CLOUD_COMPILER_OTLP_ENDPOINT");
+    let otel = match &endpoint {
+        Some(endpoint) => {
+            let provider = tracer_provider(endpoint)?;
+            let tracer = provider.tracer(DEFAULT_SERVICE_NAME);
+            PROVIDER.set(provider).ok();
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+    tracing_subscriber
+        ::registry()
+        .with(LevelFilter::INFO)
+        .with(json.then(|| fmt::layer().json()))
+        .with((!json).then(fmt::layer))
+        .with(otel)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    if let Some(endpoint) = endpoint {
+        info!("Exporting traces to {}", endpoint);
+    }
+    Ok(())
+}
+
+// Export the spans still buffered, before the process exits
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        provider.shutdown().ok();
+    }
+}
@@ -0,0 +1,126 @@
+// Template variables. A compile request's `variables` map fills `{{name}}` placeholders in the
+// sketch's sources for the duration of the build, how provisioning services make per-device
+// firmware from one sketch:
+//
+//   const char *DEVICE_ID = "{{device_id}}";
+//
+// A placeholder is `{{`, a name (letters, digits, `_`, `.`, `-`) with optional spaces around it
+// and `}}` on one line; anything else, like `{{1, 2}, {3, 4}}` in an initializer, is left alone.
+// `\{{` stands for a literal `{{`. Values are inserted as they are, in one pass, so a value
+// holding a placeholder is not expanded again. Placeholders without a variable fail the build.
+// Without `variables` the sources are compiled untouched.
+use std::collections::BTreeMap;
+use std::path::{ Path, PathBuf };
+use serde_json::Value;
+
+pub type Variables = BTreeMap<String, String>;
+
+const MAX_VARIABLES: usize = 256;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_VALUE_LENGTH: usize = 4096;
+// Sources larger than this are not templates
+const MAX_SOURCE_BYTES: u64 = 1024 * 1024;
+const SOURCE_EXTENSIONS: &[&str] = &["ino", "pde", "h", "hpp", "c", "cpp", "S"];
+// `src/` subdirectories are compiled recursively, up to this deep
+const SOURCE_DEPTH: usize = 4;
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() &&
+        name.len() <= MAX_NAME_LENGTH &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+// The `variables` of a request, strings, numbers or booleans; empty without
+pub fn from_request(data: &Value) -> Result<Variables, String> {
+    let fields = match data.get("variables") {
+        None | Some(Value::Null) => return Ok(Variables::new()),
+        Some(Value::Object(fields)) => fields,
+        Some(_) => return Err("variables must be an object of names to values".to_string()),
+    };
+    if fields.len() > MAX_VARIABLES {
+        return Err(format!("At most {} variables per build", MAX_VARIABLES));
+    }
+    let mut variables = Variables::new();
+    for (name, value) in fields {
+        if !valid_name(name) {
+            return Err(format!("Invalid variable name: {}", name));
+        }
+        let value = match value {
+            Value::String(text) => text.clone(),
+            Value::Number(number) => number.to_string(),
+            Value::Bool(flag) => flag.to_string(),
+            _ => return Err(format!("Variable {} must be a string, number or boolean", name)),
+        };
+        if value.len() > MAX_VALUE_LENGTH {
+            return Err(format!("Variable {} is longer than {} bytes", name, MAX_VALUE_LENGTH));
+        }
+        variables.insert(name.clone(), value);
+    }
+    Ok(variables)
+}
+
+// Fill the placeholders of `text`. Returns the result and the names without a variable, with
+// the line they are on.
+pub fn substitute(text: &str, variables: &Variables) -> (String, Vec<(String, usize)>) {
+    let mut result = String::with_capacity(text.len());
+    let mut unresolved = Vec::new();
+    let mut rest = text;
+    let mut line = 1;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("\\{{") {
+            result.push_str("{{");
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("{{") &&
+            let Some(end) = after.find("}}") &&
+            !after[..end].contains('\n') &&
+            valid_name(after[..end].trim())
+        {
+            let name = after[..end].trim();
+            match variables.get(name) {
+                Some(value) => result.push_str(value),
+                None => {
+                    unresolved.push((name.to_string(), line));
+                    result.push_str(&rest[..end + 4]);
+                }
+            }
+            rest = &after[end + 2..];
+            continue;
+        }
+        let c = rest.chars().next().unwrap_or_default();
+        if c == '\n' {
+            line += 1;
+        }
+        result.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    (result, unresolved)
+}
+
+fn collect_sources(dir: &Path, depth: usize, sources: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    entries.sort();
+    for path in entries {
+        let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        let extension = path.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_default();
+        if metadata.is_file() && metadata.len() <= MAX_SOURCE_BYTES && SOURCE_EXTENSIONS.contains(&extension.as_str()) {
+            sources.push(path);
+        } else if metadata.is_dir() && depth > 0 {
+            collect_sources(&path, depth - 1, sources);
+        }
+    }
+}
+
+// Source files arduino-cli compiles from a sketch folder: the top level and `src/`
+pub fn sources(sketch_dir: &Path) -> Vec<PathBuf> {
+    let mut sources = Vec::new();
+    collect_sources(sketch_dir, 0, &mut sources);
+    collect_sources(&sketch_dir.join("src"), SOURCE_DEPTH, &mut sources);
+    sources
+}
@@ -0,0 +1,140 @@
+// Serving HTTPS and secure WebSockets directly, for deployments without a TLS-terminating proxy
+// in front (browsers only open wss:// sockets from https:// pages).
+//
+// CLOUD_COMPILER_TLS_CERT and CLOUD_COMPILER_TLS_KEY (PEM files) serve that certificate, reloaded
+// from disk every CLOUD_COMPILER_TLS_RELOAD_SECS (default 3600) so renewals need no restart.
+// CLOUD_COMPILER_ACME_DOMAINS (comma separated) gets certificates from Let's Encrypt instead,
+// answering the TLS-ALPN-01 challenge on the server port itself, which must therefore be
+// reachable as port 443. CLOUD_COMPILER_ACME_EMAIL is the account contact and
+// CLOUD_COMPILER_ACME_CACHE_DIR (default `<data dir>/acme`) keeps account and certificates;
+// the staging directory is used unless CLOUD_COMPILER_ACME_PRODUCTION=1.
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use axum::Router;
+use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
+use futures::StreamExt;
+use rustls_acme::caches::DirCache;
+use rustls_acme::AcmeConfig;
+use tracing::{ info, warn };
+use crate::compiler::server_data_dir;
+
+const DEFAULT_RELOAD: Duration = Duration::from_secs(3600);
+// How long open connections get to close once the server shuts down
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Tls {
+    Plain,
+    Files {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    Acme {
+        domains: Vec<String>,
+        email: Option<String>,
+        cache_dir: PathBuf,
+        production: bool,
+    },
+}
+
+fn setting(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+// TLS mode from the environment
+pub fn configured() -> Result<Tls, String> {
+    if let Some(domains) = setting("CLOUD_COMPILER_ACME_DOMAINS") {
+        return Ok(Tls::Acme {
+            domains: domains.split(',').map(|domain| domain.trim().to_string()).filter(|d| !d.is_empty()).collect(),
+            email: setting("CLOUD_COMPILER_ACME_EMAIL"),
+            cache_dir: setting("CLOUD_COMPILER_ACME_CACHE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| server_data_dir().join("acme")),
+            production: matches!(setting("CLOUD_COMPILER_ACME_PRODUCTION").as_deref(), Some("1" | "true" | "yes")),
+        });
+    }
+    match (setting("CLOUD_COMPILER_TLS_CERT"), setting("CLOUD_COMPILER_TLS_KEY")) {
+        (Some(cert), Some(key)) => Ok(Tls::Files { cert: cert.into(), key: key.into() }),
+        (None, None) => Ok(Tls::Plain),
+        _ => Err("CLOUD_COMPILER_TLS_CERT and CLOUD_COMPILER_TLS_KEY must be set together".to_string()),
+    }
+}
+
+fn reload_interval() -> Duration {
+    setting("CLOUD_COMPILER_TLS_RELOAD_SECS")
+        .and_then(|secs| secs.trim().parse().ok())
+        .filter(|secs| *secs > 0)
+        .map_or(DEFAULT_RELOAD, Duration::from_secs)
+}
+
+// Serve `app` on `bind`:`port`, over HTTPS unless `tls` is plain, until `shutdown` resolves
+pub async fn serve(
+    tls: Tls,
+    bind: &str,
+    port: u16,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static
+) -> std::io::Result<()> {
+    // Peer addresses feed the per-IP rate limits
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let listener = std::net::TcpListener::bind((bind, port))?;
+    listener.set_nonblocking(true)?;
+    let handle = Handle::new();
+    if !matches!(tls, Tls::Plain) {
+        let trigger = handle.clone();
+        tokio::spawn(async move {
+            shutdown.await;
+            trigger.graceful_shutdown(Some(CLOSE_TIMEOUT));
+        });
+        return serve_tls(tls, listener, service, handle).await;
+    }
+    axum::serve(tokio::net::TcpListener::from_std(listener)?, service).with_graceful_shutdown(shutdown).await
+}
+
+async fn serve_tls(
+    tls: Tls,
+    listener: std::net::TcpListener,
+    service: IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+    handle: Handle
+) -> std::io::Result<()> {
+    match tls {
+        Tls::Plain => unreachable!("plain HTTP is served by axum::serve"),
+        Tls::Files { cert, key } => {
+            let config = RustlsConfig::from_pem_file(&cert, &key).await?;
+            let reloaded = config.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(reload_interval());
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = reloaded.reload_from_pem_file(&cert, &key).await {
+                        warn!("Failed to reload TLS certificate: {}", e);
+                    }
+                }
+            });
+            info!("Serving HTTPS");
+            axum_server::from_tcp_rustls(listener, config).handle(handle).serve(service).await
+        }
+        Tls::Acme { domains, email, cache_dir, production } => {
+            let mut state = AcmeConfig::new(&domains)
+                .contact(email.iter().map(|email| format!("mailto:{}", email)))
+                .cache(DirCache::new(cache_dir))
+                .directory_lets_encrypt(production)
+                .state();
+            let acceptor = state.axum_acceptor(state.default_rustls_config());
+            tokio::spawn(async move {
+                while let Some(event) = state.next().await {
+                    match event {
+                        Ok(event) => info!("ACME: {:?}", event),
+                        Err(e) => warn!("ACME: {:?}", e),
+                    }
+                }
+            });
+            info!("Serving HTTPS for {} with ACME certificates", domains.join(", "));
+            axum_server::from_tcp(listener).handle(handle).acceptor(acceptor).serve(service).await
+        }
+    }
+}
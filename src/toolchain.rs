@@ -0,0 +1,91 @@
+// Selectable toolchains. Users maintaining old firmware need to build against the exact core
+// version they shipped with, so besides the default Arduino data directory the server keeps one
+// data directory per toolchain under CLOUD_COMPILER_TOOLCHAINS_DIR (default
+// `<data dir>/toolchains`), named after what it holds (`esp32@3.0.7`). A directory may also carry
+// its own `arduino-cli` binary to use instead of the embedded one.
+//
+// Requests pick one with `toolchain`; the job then runs inside `scope`, which everything reading
+// `arduino_data_dir` (arguments, sandbox mounts, cache keys, tool lookups) picks up.
+use std::future::Future;
+use std::path::PathBuf;
+use serde_json::Value;
+use crate::compiler::{ arduino_data_dir, is_safe_name, server_data_dir };
+
+tokio::task_local! {
+    static SELECTED: PathBuf;
+}
+
+fn toolchains_dir() -> PathBuf {
+    std::env
+        ::var_os("CLOUD_COMPILER_TOOLCHAINS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| server_data_dir().join("toolchains"))
+}
+
+// Names of the installed toolchains, sorted
+pub fn toolchains() -> Vec<String> {
+    let mut names: Vec<String> = std::fs
+        ::read_dir(toolchains_dir())
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| !name.starts_with('.'))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+// Data directory of the toolchain a request asks for, None for the default one
+pub fn requested(data: &Value) -> Result<Option<PathBuf>, String> {
+    let Some(name) = data.get("toolchain").and_then(|v| v.as_str()) else {
+        return Ok(None);
+    };
+    // Only listed directories, so the name can't point anywhere else
+    match toolchains().iter().any(|installed| installed == name) {
+        true => Ok(Some(toolchains_dir().join(name))),
+        false => Err(format!("Unknown toolchain {}", name)),
+    }
+}
+
+// Create toolchain `name` unless it exists, seeded with the package indexes of the default data
+// directory so its platforms install from the same board manager URLs
+pub fn create(name: &str) -> Result<PathBuf, String> {
+    if !name.split('@').all(is_safe_name) {
+        return Err(format!("Invalid toolchain name: {}", name));
+    }
+    let dir = toolchains_dir().join(name);
+    if dir.is_dir() {
+        return Ok(dir);
+    }
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create toolchain {}: {}", name, e))?;
+    for entry in std::fs::read_dir(arduino_data_dir()).into_iter().flatten().flatten() {
+        let file = entry.file_name().to_string_lossy().to_string();
+        if file.starts_with("package_") && entry.path().is_file() {
+            std::fs::copy(entry.path(), dir.join(&file)).ok();
+        }
+    }
+    Ok(dir)
+}
+
+// Run `future` with `dir` as the Arduino data directory, or the default one for None
+pub async fn scope<F: Future>(dir: Option<PathBuf>, future: F) -> F::Output {
+    match dir {
+        Some(dir) => SELECTED.scope(dir, future).await,
+        None => future.await,
+    }
+}
+
+// Data directory selected for the current job
+pub fn selected() -> Option<PathBuf> {
+    SELECTED.try_with(|dir| dir.clone()).ok()
+}
+
+// arduino-cli shipped with the selected toolchain, if it has one
+pub fn arduino_cli() -> Option<PathBuf> {
+    let name = if cfg!(windows) { "arduino-cli.exe" } else { "arduino-cli" };
+    Some(selected()?.join(name)).filter(|path| path.is_file())
+}
@@ -0,0 +1,262 @@
+// Chunked transfers for payloads bigger than an engine.io frame may be (100 kB by default):
+// project archives the client uploads and build artifacts, like merged firmware images, it
+// downloads. Both go through begin, chunk and end events. Chunks are numbered from 0, at most
+// `CHUNK_SIZE` bytes each, and carry the SHA-256 of their bytes; the end checks the SHA-256 of
+// the whole. A chunk whose answer was lost can be sent or asked for again. Transfers belong to
+// the socket that began them and are dropped with it, or once idle for `IDLE_TIMEOUT`.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{ Arc, LazyLock, Mutex };
+use std::time::{ Duration, Instant };
+use serde::Serialize;
+use sha2::{ Digest, Sha256 };
+use crate::files::MAX_TOTAL_BYTES;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+// Transfers one socket may have open at once
+const MAX_PER_SOCKET: usize = 4;
+// Bytes all open transfers may hold in memory together, uploads counted at their announced size
+const MAX_BUFFERED_BYTES: u64 = 512 * 1024 * 1024;
+
+enum Direction {
+    Upload {
+        size: u64,
+        received: Vec<u8>,
+        next_seq: u64,
+    },
+    Download {
+        bytes: Arc<Vec<u8>>,
+    },
+}
+
+struct Transfer {
+    owner: String,
+    sha256: String,
+    direction: Direction,
+    used: Instant,
+}
+
+impl Transfer {
+    fn buffered(&self) -> u64 {
+        match &self.direction {
+            Direction::Upload { size, .. } => *size,
+            Direction::Download { bytes } => bytes.len() as u64,
+        }
+    }
+}
+
+static TRANSFERS: LazyLock<Mutex<HashMap<String, Transfer>>> = LazyLock::new(Default::default);
+
+#[derive(Serialize)]
+pub struct UploadStarted {
+    pub transfer_id: String,
+    pub chunk_size: usize,
+}
+
+#[derive(Serialize)]
+pub struct ChunkReceived {
+    pub transfer_id: String,
+    pub seq: u64,
+    // Bytes received so far
+    pub received: u64,
+}
+
+#[derive(Serialize)]
+pub struct DownloadStarted {
+    pub transfer_id: String,
+    pub size: u64,
+    pub sha256: String,
+    pub chunk_size: usize,
+    pub chunks: u64,
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// Whether `size` more bytes fit the buffers of all transfers, after dropping the idle ones
+fn has_room(transfers: &mut HashMap<String, Transfer>, size: u64) -> bool {
+    transfers.retain(|_, transfer| transfer.used.elapsed() < IDLE_TIMEOUT);
+    transfers.values().map(Transfer::buffered).sum::<u64>() + size <= MAX_BUFFERED_BYTES
+}
+
+fn busy() -> String {
+    "Too many transfers in progress, try again later".to_string()
+}
+
+// Register a transfer of `owner`
+fn open(owner: &str, sha256: String, direction: Direction) -> Result<String, String> {
+    let transfer = Transfer { owner: owner.to_string(), sha256, direction, used: Instant::now() };
+    let mut transfers = TRANSFERS.lock().unwrap();
+    if !has_room(&mut transfers, transfer.buffered()) {
+        return Err(busy());
+    }
+    if transfers.values().filter(|transfer| transfer.owner == owner).count() >= MAX_PER_SOCKET {
+        return Err(format!("Too many open transfers (max {})", MAX_PER_SOCKET));
+    }
+    let transfer_id = uuid::Uuid::new_v4().to_string();
+    transfers.insert(transfer_id.clone(), transfer);
+    Ok(transfer_id)
+}
+
+// Another socket's transfer looks like no transfer at all
+fn unknown(transfer_id: &str) -> String {
+    format!("Unknown transfer {}", transfer_id)
+}
+
+// Remove the transfer `transfer_id` if `owner` began it
+fn take(transfers: &mut HashMap<String, Transfer>, owner: &str, transfer_id: &str) -> Option<Transfer> {
+    match transfers.get(transfer_id) {
+        Some(transfer) if transfer.owner == owner => transfers.remove(transfer_id),
+        _ => None,
+    }
+}
+
+// Start receiving `size` bytes whose SHA-256 is `sha256`
+pub fn begin_upload(owner: &str, size: u64, sha256: &str) -> Result<UploadStarted, String> {
+    if size > MAX_TOTAL_BYTES {
+        return Err(format!("Transfer too large (max {} MiB)", MAX_TOTAL_BYTES / (1024 * 1024)));
+    }
+    let direction = Direction::Upload { size, received: Vec::new(), next_seq: 0 };
+    let transfer_id = open(owner, sha256.to_lowercase(), direction)?;
+    Ok(UploadStarted { transfer_id, chunk_size: CHUNK_SIZE })
+}
+
+// Take chunk `seq` of an upload. Chunks come in order, one already taken is acknowledged again.
+pub fn upload_chunk(owner: &str, transfer_id: &str, seq: u64, data: &[u8], checksum: &str) -> Result<ChunkReceived, String> {
+    let mut transfers = TRANSFERS.lock().unwrap();
+    let transfer = transfers
+        .get_mut(transfer_id)
+        .filter(|transfer| transfer.owner == owner)
+        .ok_or_else(|| unknown(transfer_id))?;
+    transfer.used = Instant::now();
+    let Direction::Upload { size, received, next_seq } = &mut transfer.direction else {
+        return Err(format!("Transfer {} is a download", transfer_id));
+    };
+    if seq < *next_seq {
+        return Ok(ChunkReceived { transfer_id: transfer_id.to_string(), seq, received: received.len() as u64 });
+    }
+    if seq > *next_seq {
+        return Err(format!("Expected chunk {}, got {}", next_seq, seq));
+    }
+    if data.len() > CHUNK_SIZE {
+        return Err(format!("Chunk too large (max {} bytes)", CHUNK_SIZE));
+    }
+    if !checksum.eq_ignore_ascii_case(&sha256(data)) {
+        return Err(format!("Checksum mismatch in chunk {}", seq));
+    }
+    if (received.len() + data.len()) as u64 > *size {
+        return Err(format!("Chunk {} goes past the announced size of {} bytes", seq, size));
+    }
+    received.extend_from_slice(data);
+    *next_seq += 1;
+    Ok(ChunkReceived { transfer_id: transfer_id.to_string(), seq, received: received.len() as u64 })
+}
+
+// Finish an upload, returning its bytes once they are complete and match the checksum
+pub fn end_upload(owner: &str, transfer_id: &str) -> Result<Vec<u8>, String> {
+    let transfer = take(&mut TRANSFERS.lock().unwrap(), owner, transfer_id).ok_or_else(|| unknown(transfer_id))?;
+    let Direction::Upload { size, received, .. } = transfer.direction else {
+        return Err(format!("Transfer {} is a download", transfer_id));
+    };
+    if received.len() as u64 != size {
+        return Err(format!("Incomplete transfer: {} of {} bytes received", received.len(), size));
+    }
+    if sha256(&received) != transfer.sha256 {
+        return Err("Checksum mismatch in the transfer".to_string());
+    }
+    Ok(received)
+}
+
+// Start sending the file at `path`
+pub async fn begin_download(owner: &str, path: &Path) -> Result<DownloadStarted, String> {
+    // Refused before reading a file that couldn't be held anyway
+    let size = tokio::fs::metadata(path).await.map_err(|e| e.to_string())?.len();
+    if !has_room(&mut TRANSFERS.lock().unwrap(), size) {
+        return Err(busy());
+    }
+    let bytes = tokio::fs::read(path).await.map_err(|e| e.to_string())?;
+    let size = bytes.len() as u64;
+    let checksum = sha256(&bytes);
+    let transfer_id = open(owner, checksum.clone(), Direction::Download { bytes: Arc::new(bytes) })?;
+    Ok(DownloadStarted { transfer_id, size, sha256: checksum, chunk_size: CHUNK_SIZE, chunks: size.div_ceil(CHUNK_SIZE as u64) })
+}
+
+// Chunk `seq` of a download and its SHA-256, in any order and as often as asked
+pub fn download_chunk(owner: &str, transfer_id: &str, seq: u64) -> Result<(Vec<u8>, String), String> {
+    let bytes = {
+        let mut transfers = TRANSFERS.lock().unwrap();
+        let transfer = transfers
+            .get_mut(transfer_id)
+            .filter(|transfer| transfer.owner == owner)
+            .ok_or_else(|| unknown(transfer_id))?;
+        transfer.used = Instant::now();
+        match &transfer.direction {
+            Direction::Download { bytes } => bytes.clone(),
+            Direction::Upload { .. } => {
+                return Err(format!("Transfer {} is an upload", transfer_id));
+            }
+        }
+    };
+    let start = usize::try_from(seq).ok().and_then(|seq| seq.checked_mul(CHUNK_SIZE)).filter(|start| *start < bytes.len());
+    let Some(start) = start else {
+        return Err(format!("No chunk {}", seq));
+    };
+    let chunk = bytes[start..(start + CHUNK_SIZE).min(bytes.len())].to_vec();
+    let checksum = sha256(&chunk);
+    Ok((chunk, checksum))
+}
+
+// Close a transfer of `owner`, false if there was none
+pub fn close(owner: &str, transfer_id: &str) -> bool {
+    take(&mut TRANSFERS.lock().unwrap(), owner, transfer_id).is_some()
+}
+
+// Drop the transfers of a socket that went away
+pub fn close_all_of(owner: &str) {
+    TRANSFERS.lock().unwrap().retain(|_, transfer| transfer.owner != owner);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One test, the transfers being shared by the whole process
+    #[test]
+    fn uploads_are_checked_and_bounded() {
+        let owner = format!("socket-{}", uuid::Uuid::new_v4().simple());
+        let data = vec![7u8; CHUNK_SIZE + 10];
+        let started = begin_upload(&owner, data.len() as u64, &sha256(&data)).unwrap();
+        let id = started.transfer_id;
+        let (first, second) = data.split_at(CHUNK_SIZE);
+
+        assert!(upload_chunk(&owner, &id, 1, second, &sha256(second)).is_err());
+        assert!(upload_chunk(&owner, &id, 0, first, &sha256(second)).is_err());
+        assert!(upload_chunk("another-socket", &id, 0, first, &sha256(first)).is_err());
+        assert_eq!(upload_chunk(&owner, &id, 0, first, &sha256(first)).unwrap().received, CHUNK_SIZE as u64);
+        // A resent chunk is acknowledged again
+        assert_eq!(upload_chunk(&owner, &id, 0, first, &sha256(first)).unwrap().received, CHUNK_SIZE as u64);
+        upload_chunk(&owner, &id, 1, second, &sha256(second)).unwrap();
+        assert_eq!(end_upload(&owner, &id).unwrap(), data);
+
+        assert!(begin_upload(&owner, MAX_TOTAL_BYTES + 1, "").is_err());
+        for _ in 0..MAX_PER_SOCKET {
+            begin_upload(&owner, 1, "").unwrap();
+        }
+        assert!(begin_upload(&owner, 1, "").is_err());
+        close_all_of(&owner);
+
+        // Past the global budget even new sockets are refused
+        let owners: Vec<String> = (0..MAX_BUFFERED_BYTES / MAX_TOTAL_BYTES).map(|i| format!("{}-{}", owner, i)).collect();
+        for owner in &owners {
+            begin_upload(owner, MAX_TOTAL_BYTES, "").unwrap();
+        }
+        assert_eq!(begin_upload(&owner, 1, "").err(), Some(busy()));
+        for owner in &owners {
+            close_all_of(owner);
+        }
+        assert!(begin_upload(&owner, 1, "").is_ok());
+        close_all_of(&owner);
+    }
+}
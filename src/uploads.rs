@@ -0,0 +1,45 @@
+// Sketches uploaded as ZIP archives. `POST /sketches` takes a multipart form with the archive,
+// extracts it into the client's workspace under `uploads/<id>/` (zip-slip protected, bounded
+// like other client file trees) and answers with the path of the sketch found in it, which
+// `compile-sketch` then builds. Projects with assets and libraries travel as one binary upload
+// instead of base64 per file.
+use std::path::{ Path, PathBuf };
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::files::{ extract_zip, locate_sketch, safe_relative_path };
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadedSketch {
+    pub upload_id: String,
+    // Relative to the client's workspace, as `compile-sketch` takes it
+    pub sketch_path: String,
+}
+
+pub fn uploads_dir(workspace: &Path) -> PathBuf {
+    workspace.join("uploads")
+}
+
+// Extract `archive` into a new upload of `workspace` and find its sketch, optionally in `subdir`
+pub fn import(workspace: &Path, archive: &[u8], subdir: Option<&str>) -> Result<UploadedSketch, String> {
+    let subdir = match subdir.filter(|subdir| !subdir.is_empty()) {
+        Some(subdir) => Some(safe_relative_path(subdir).ok_or_else(|| format!("Invalid subdirectory: {}", subdir))?),
+        None => None,
+    };
+    let upload_id = uuid::Uuid::new_v4().to_string();
+    let dir = uploads_dir(workspace).join(&upload_id);
+    let mut tree = dir.join("sketch");
+    let imported = std::fs
+        ::create_dir_all(&tree)
+        .map_err(|e| e.to_string())
+        .and_then(|_| extract_zip(&tree, archive))
+        .and_then(|_| locate_sketch(&dir, &mut tree, subdir.as_deref()));
+    let sketch = match imported {
+        Ok(sketch) => sketch,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&dir);
+            return Err(e);
+        }
+    };
+    let sketch_path = sketch.strip_prefix(workspace).unwrap_or(&sketch).to_string_lossy().to_string();
+    Ok(UploadedSketch { upload_id, sketch_path })
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use serde::{ Deserialize, Serialize };
+use utoipa::ToSchema;
+use tracing::warn;
+use crate::artifacts::builds_root;
+use crate::compiler::server_data_dir;
+
+// Usage metering per authenticated identity: compile time and uploads per period, and the
+// storage taken by their builds. Limits are configured with
+//   CLOUD_COMPILER_QUOTA_COMPILE_MINUTES, CLOUD_COMPILER_QUOTA_UPLOADS,
+//   CLOUD_COMPILER_QUOTA_STORAGE_MB, CLOUD_COMPILER_QUOTA_PERIOD_SECS (default 30 days)
+// and apply to every identity; requests over a limit are rejected. Guests have their own
+// session limits and are not metered here. Storage is counted from disk once and then kept up
+// to date as builds finish and are removed, the usage file is written in the background.
+
+const DEFAULT_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Marker written into build directories, holds the identity that owns the build
+const OWNER_MARKER: &str = ".owner";
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct Usage {
+    period_start: u64,
+    compile_seconds: u64,
+    uploads: u64,
+}
+
+#[derive(Serialize, Clone, Copy, Default, ToSchema)]
+pub struct Limits {
+    pub compile_seconds: Option<u64>,
+    pub uploads: Option<u64>,
+    pub storage_bytes: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageReport {
+    pub subject: String,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub compile_seconds: u64,
+    pub uploads: u64,
+    pub storage_bytes: u64,
+    pub limits: Limits,
+}
+
+static USAGE: LazyLock<Mutex<HashMap<String, Usage>>> = LazyLock::new(|| {
+    let usage = std::fs
+        ::read(usage_path())
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default();
+    Mutex::new(usage)
+});
+// Held while the usage file is written, so a write never replaces newer usage with older
+static WRITES: Mutex<()> = Mutex::new(());
+static WRITE_PENDING: AtomicBool = AtomicBool::new(false);
+
+// Owner and bytes of each owned build directory
+static STORAGE: LazyLock<Mutex<HashMap<PathBuf, (String, u64)>>> = LazyLock::new(|| {
+    let builds = std::fs
+        ::read_dir(builds_root())
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| build_owner(&e.path()).map(|owner| (owner, dir_size(&e.path()), e.path())))
+        .map(|(owner, size, path)| (path, (owner, size)))
+        .collect();
+    Mutex::new(builds)
+});
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn usage_path() -> PathBuf {
+    server_data_dir().join("usage.json")
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.trim().parse().ok())
+}
+
+pub fn limits() -> Limits {
+    Limits {
+        compile_seconds: env_u64("CLOUD_COMPILER_QUOTA_COMPILE_MINUTES").map(|m| m * 60),
+        uploads: env_u64("CLOUD_COMPILER_QUOTA_UPLOADS"),
+        storage_bytes: env_u64("CLOUD_COMPILER_QUOTA_STORAGE_MB").map(|mb| mb * 1024 * 1024),
+    }
+}
+
+fn period() -> u64 {
+    env_u64("CLOUD_COMPILER_QUOTA_PERIOD_SECS")
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_PERIOD.as_secs())
+}
+
+// Usage of `subject` in the current period, starting a new period when the last one ended
+fn current<'a>(usage: &'a mut HashMap<String, Usage>, subject: &str) -> &'a mut Usage {
+    let now = now();
+    let entry = usage
+        .entry(subject.to_string())
+        .or_insert_with(|| Usage { period_start: now, ..Default::default() });
+    if now >= entry.period_start + period() {
+        *entry = Usage { period_start: now, ..Default::default() };
+    }
+    entry
+}
+
+// Write the usage as it is now
+fn persist() {
+    let _writes = WRITES.lock().unwrap();
+    WRITE_PENDING.store(false, Ordering::SeqCst);
+    let json = serde_json::to_vec(&*USAGE.lock().unwrap());
+    if let Ok(json) = json {
+        std::fs::create_dir_all(server_data_dir()).ok();
+        if let Err(e) = std::fs::write(usage_path(), json) {
+            warn!("Failed to save usage: {}", e);
+        }
+    }
+}
+
+// Write the usage in the background, changes made before that write starts share it
+fn persist_later() {
+    if !WRITE_PENDING.swap(true, Ordering::SeqCst) {
+        tokio::task::spawn_blocking(persist);
+    }
+}
+
+pub fn dir_size(dir: &Path) -> u64 {
+    std::fs
+        ::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| {
+                    match e.file_type() {
+                        Ok(t) if t.is_dir() => dir_size(&e.path()),
+                        Ok(_) => e.metadata().map(|m| m.len()).unwrap_or_default(),
+                        Err(_) => 0,
+                    }
+                })
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+// Bytes taken by the builds of `subject`
+pub fn storage_bytes(subject: &str) -> u64 {
+    STORAGE.lock()
+        .unwrap()
+        .values()
+        .filter(|(owner, _)| owner == subject)
+        .map(|(_, size)| size)
+        .sum()
+}
+
+// Count the storage of the build in `dir` once it is written
+pub async fn record_build(subject: &str, dir: &Path) {
+    let measured = {
+        let dir = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || dir_size(&dir)).await
+    };
+    if let Ok(size) = measured {
+        STORAGE.lock().unwrap().insert(dir.to_path_buf(), (subject.to_string(), size));
+    }
+}
+
+// Stop counting a removed build
+pub fn forget_build(dir: &Path) {
+    STORAGE.lock().unwrap().remove(dir);
+}
+
+// Count the storage of the builds on disk now, ahead of the first check
+pub fn load_storage() {
+    LazyLock::force(&STORAGE);
+}
+
+// Identity a build directory counts against, None for anonymous and guest builds
+pub fn build_owner(dir: &Path) -> Option<String> {
+    std::fs::read_to_string(dir.join(OWNER_MARKER)).ok()
+}
+
+// Tag a build directory with the identity it counts against
+pub fn mark_build_owner(dir: &Path, subject: &str) -> std::io::Result<()> {
+    std::fs::write(dir.join(OWNER_MARKER), subject)
+}
+
+// Reject a compile once compile time or storage is used up
+pub fn check_compile(subject: &str) -> Result<(), String> {
+    let limits = limits();
+    if let Some(limit) = limits.compile_seconds {
+        let used = current(&mut USAGE.lock().unwrap(), subject).compile_seconds;
+        if used >= limit {
+            return Err(format!("Compile time quota of {} minutes exceeded", limit / 60));
+        }
+    }
+    if let Some(limit) = limits.storage_bytes && storage_bytes(subject) >= limit {
+        return Err(format!("Storage quota of {} MB exceeded", limit / 1024 / 1024));
+    }
+    Ok(())
+}
+
+pub fn check_upload(subject: &str) -> Result<(), String> {
+    if let Some(limit) = limits().uploads {
+        let used = current(&mut USAGE.lock().unwrap(), subject).uploads;
+        if used >= limit {
+            return Err(format!("Upload quota of {} uploads exceeded", limit));
+        }
+    }
+    Ok(())
+}
+
+pub fn record_compile(subject: &str, elapsed: Duration) {
+    let mut usage = USAGE.lock().unwrap();
+    // Every started compile counts at least a second
+    current(&mut usage, subject).compile_seconds += elapsed.as_secs().max(1);
+    drop(usage);
+    persist_later();
+}
+
+pub fn record_upload(subject: &str) {
+    let mut usage = USAGE.lock().unwrap();
+    current(&mut usage, subject).uploads += 1;
+    drop(usage);
+    persist_later();
+}
+
+pub fn report(subject: &str) -> UsageReport {
+    let usage = current(&mut USAGE.lock().unwrap(), subject).clone();
+    UsageReport {
+        subject: subject.to_string(),
+        period_start: usage.period_start,
+        period_end: usage.period_start + period(),
+        compile_seconds: usage.compile_seconds,
+        uploads: usage.uploads,
+        storage_bytes: storage_bytes(subject),
+        limits: limits(),
+    }
+}
+
+// Usage of every metered identity
+pub fn all_reports() -> Vec<UsageReport> {
+    let mut subjects: Vec<String> = USAGE.lock().unwrap().keys().cloned().collect();
+    subjects.sort();
+    subjects.iter().map(|subject| report(subject)).collect()
+}
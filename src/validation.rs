@@ -0,0 +1,158 @@
+// Request validation. Each typed request lists its fields with the JSON type they take, and an
+// event's data is checked against the whole list before it is deserialized: a client hears about
+// every missing or mistyped field at once, as `error_code: "invalid_request"` with the fields in
+// `invalid_fields`, instead of the first one serde trips over or a silently ignored value.
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use crate::models::{ CommandResponse, FieldError, FieldProblem };
+use crate::errors::CompilerError;
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    String,
+    Bool,
+    // A non-negative integer
+    Unsigned,
+    Number,
+    Object,
+    // An object of strings
+    StringMap,
+    Array,
+    Strings,
+    // Files as sent by clients: `[{path, content, encoding?}]`
+    Files,
+    OneOf(&'static [&'static str]),
+    // A string or an object, like a lockfile sent as text or parsed
+    StringOrObject,
+}
+
+impl Kind {
+    fn expected(&self) -> String {
+        match self {
+            Kind::String => "a string".to_string(),
+            Kind::Bool => "true or false".to_string(),
+            Kind::Unsigned => "a non-negative integer".to_string(),
+            Kind::Number => "a number".to_string(),
+            Kind::Object => "an object".to_string(),
+            Kind::StringMap => "an object of strings".to_string(),
+            Kind::Array => "an array".to_string(),
+            Kind::Strings => "an array of strings".to_string(),
+            Kind::Files => "an array of {path, content, encoding?}".to_string(),
+            Kind::OneOf(choices) => format!("one of {}", choices.join(", ")),
+            Kind::StringOrObject => "a string or an object".to_string(),
+        }
+    }
+
+    fn accepts(&self, value: &Value) -> bool {
+        match self {
+            Kind::String => value.is_string(),
+            Kind::Bool => value.is_boolean(),
+            Kind::Unsigned => value.is_u64(),
+            Kind::Number => value.is_number(),
+            Kind::Object => value.is_object(),
+            Kind::StringMap => value.as_object().is_some_and(|fields| fields.values().all(Value::is_string)),
+            Kind::Array => value.is_array(),
+            Kind::Strings => value.as_array().is_some_and(|items| items.iter().all(Value::is_string)),
+            Kind::Files => value.as_array().is_some_and(|files| files.iter().all(is_file)),
+            Kind::OneOf(choices) => value.as_str().is_some_and(|value| choices.contains(&value)),
+            Kind::StringOrObject => value.is_string() || value.is_object(),
+        }
+    }
+}
+
+fn is_file(file: &Value) -> bool {
+    file["path"].is_string() &&
+        file["content"].is_string() &&
+        (file["encoding"].is_null() || Kind::OneOf(&["utf8", "base64"]).accepts(&file["encoding"]))
+}
+
+pub struct Field {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+}
+
+pub const fn required(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: true }
+}
+
+pub const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: false }
+}
+
+// Fields most queued events take
+pub const TOOLCHAIN: Field = optional("toolchain", Kind::String);
+pub const PRIORITY: Field = optional("priority", Kind::OneOf(&["interactive", "normal", "bulk"]));
+
+// "Missing sketch path" for `sketch_path`, as the handlers said before the requests were typed
+pub fn missing(field: &str) -> FieldError {
+    let message = format!("Missing {}", field.replace('_', " "));
+    FieldError { field: field.to_string(), problem: FieldProblem::Missing, message }
+}
+
+pub fn invalid(field: &str, message: impl Into<String>) -> FieldError {
+    FieldError { field: field.to_string(), problem: FieldProblem::Invalid, message: message.into() }
+}
+
+// A request type and the fields of its JSON form. Unlisted fields are ignored, as by serde.
+pub trait Schema: DeserializeOwned {
+    const FIELDS: &'static [Field];
+
+    // Rules across fields, checked once every field has the right type
+    fn check(&self) -> Vec<FieldError> {
+        Vec::new()
+    }
+}
+
+// Problems of each field of `data` on its own
+pub fn check_fields(data: &Value, fields: &[Field]) -> Vec<FieldError> {
+    let empty = serde_json::Map::new();
+    let values = match data {
+        Value::Object(values) => values,
+        Value::Null => &empty,
+        _ => {
+            return vec![invalid("", "Expected an object")];
+        }
+    };
+    fields
+        .iter()
+        .filter_map(|field| match values.get(field.name).filter(|value| !value.is_null()) {
+            None if field.required => Some(missing(field.name)),
+            Some(value) if !field.kind.accepts(value) => {
+                Some(invalid(field.name, format!("Invalid {}: expected {}", field.name, field.kind.expected())))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// `data` as a `T`, or everything wrong with it
+pub fn parse<T: Schema>(data: &Value) -> Result<T, Vec<FieldError>> {
+    let errors = check_fields(data, T::FIELDS);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let data = match data {
+        Value::Null => Value::Object(Default::default()),
+        data => data.clone(),
+    };
+    // Nested values the field list doesn't describe can still be off
+    let request: T = serde_json::from_value(data).map_err(|e| vec![invalid("", format!("Invalid request: {}", e))])?;
+    let errors = request.check();
+    match errors.is_empty() {
+        true => Ok(request),
+        false => Err(errors),
+    }
+}
+
+// The response rejecting a request with `errors`, its `error` the messages in one line
+pub fn invalid_response(command: &str, errors: Vec<FieldError>) -> CommandResponse {
+    let message = errors
+        .iter()
+        .map(|error| error.message.as_str())
+        .collect::<Vec<_>>()
+        .join("; ");
+    let mut response = CompilerError::InvalidRequest(message).response(command, vec![]);
+    response.invalid_fields = errors;
+    response
+}
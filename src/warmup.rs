@@ -0,0 +1,112 @@
+// Warm-up compiles. The first compile for a board unpacks the core's archives and primes the
+// toolchain, which takes minutes; compiling a trivial sketch for every board listed in
+// CLOUD_COMPILER_WARM_FQBNS (comma separated) at startup, or on demand through the admin
+// routes, keeps that off the first real user request.
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+use serde::Serialize;
+use utoipa::ToSchema;
+use tracing::info;
+use crate::models::ArduinoCommand;
+use crate::compiler::{ run_arduino_command, server_data_dir };
+use crate::queue::{ self, Priority };
+use crate::resources::job;
+
+const WARMUP_SKETCH: &str = "void setup() {}\n\nvoid loop() {}\n";
+
+// Outcome of the latest warm-up of a board
+#[derive(Serialize, Clone, ToSchema)]
+pub struct WarmResult {
+    pub fqbn: String,
+    pub success: bool,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub finished_at: u64,
+}
+
+static RESULTS: LazyLock<Mutex<Vec<WarmResult>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+fn warmup_dir() -> PathBuf {
+    server_data_dir().join("warmup")
+}
+
+// Boards to warm at startup
+pub fn configured_fqbns() -> Vec<String> {
+    std::env
+        ::var("CLOUD_COMPILER_WARM_FQBNS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|fqbn| fqbn.trim().to_string())
+        .filter(|fqbn| !fqbn.is_empty())
+        .collect()
+}
+
+// Latest warm-up result per board
+pub fn results() -> Vec<WarmResult> {
+    RESULTS.lock().unwrap().clone()
+}
+
+async fn warm(fqbn: &str) -> WarmResult {
+    let started = Instant::now();
+    let sketch = warmup_dir().join("warmup");
+    let output = warmup_dir().join(format!("output-{}", uuid::Uuid::new_v4()));
+    let prepared = std::fs
+        ::create_dir_all(&sketch)
+        .and_then(|_| std::fs::write(sketch.join("warmup.ino"), WARMUP_SKETCH))
+        .and_then(|_| std::fs::create_dir_all(&output));
+
+    let result = match prepared {
+        Ok(()) => {
+            let command = ArduinoCommand {
+                command: "compile".to_string(),
+                args: vec![
+                    "--fqbn".to_string(),
+                    fqbn.to_string(),
+                    "--output-dir".to_string(),
+                    output.to_string_lossy().to_string(),
+                    sketch.to_string_lossy().to_string()
+                ],
+            };
+            let response = run_arduino_command(&command).await;
+            match response.success {
+                true => Ok(()),
+                false => Err(response.error.unwrap_or_default()),
+            }
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    let _ = std::fs::remove_dir_all(&output);
+
+    WarmResult {
+        fqbn: fqbn.to_string(),
+        success: result.is_ok(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        error: result.err(),
+        finished_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    }
+}
+
+// Warm `fqbns` one after another in the background, as bulk jobs so users go first
+pub fn spawn_warmup(fqbns: Vec<String>) {
+    if fqbns.is_empty() {
+        return;
+    }
+    tokio::spawn(job("server".to_string(), "warmup", async move {
+        for fqbn in fqbns {
+            let Ok(ticket) = queue::join(Priority::Bulk) else {
+                info!("Skipping warm-up of {}, the queue is full", fqbn);
+                continue;
+            };
+            let _slot = ticket.ready(|_| {}).await;
+            info!("Warming up {}", fqbn);
+            let result = warm(&fqbn).await;
+            info!("Warm-up of {} finished in {} ms (success: {})", fqbn, result.duration_ms, result.success);
+
+            let mut results = RESULTS.lock().unwrap();
+            results.retain(|r| r.fqbn != fqbn);
+            results.push(result);
+        }
+    }));
+}
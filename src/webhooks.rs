@@ -0,0 +1,321 @@
+// Outbound webhooks. When a job answers its client, every matching webhook receives a signed
+// POST with the job id, outcome, diagnostics and artifact URLs, so CI pipelines and chat bots
+// get pushed the result instead of holding a socket open.
+//
+// Server-wide hooks, CLOUD_COMPILER_WEBHOOK_URLS (comma separated), get every job and are signed
+// with CLOUD_COMPILER_WEBHOOK_SECRET. Authenticated clients register their own through
+// `/webhooks`; those only get the identity's jobs and are signed with the secret returned at
+// registration. The signature is `X-Webhook-Signature: sha256=<hex HMAC-SHA256 of the body>`.
+//
+// A client's hook must resolve to public addresses only, checked again on every delivery and
+// pinned for it, and redirects are never followed: the server can't be aimed at itself, a cloud
+// metadata endpoint or the internal network.
+use std::collections::BTreeMap;
+use std::net::{ IpAddr, SocketAddr };
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use hmac::{ Hmac, Mac };
+use serde::{ Deserialize, Serialize };
+use utoipa::ToSchema;
+use sha2::Sha256;
+use tracing::{ info, warn };
+use crate::compiler::server_data_dir;
+use crate::models::{ CommandResponse, Diagnostic, ErrorCode };
+use crate::notifications::public_url;
+use crate::resources::CurrentJob;
+
+const WEBHOOKS_FILE: &str = "webhooks.json";
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+// Waits before the retries of a failed delivery
+const RETRY_DELAYS: &[Duration] = &[Duration::from_secs(1), Duration::from_secs(10), Duration::from_secs(60)];
+const MAX_WEBHOOKS_PER_SUBJECT: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct Webhook {
+    pub id: String,
+    pub subject: String,
+    pub url: String,
+    // Events the hook wants (`compile-sketch`, ...), all when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    pub created_at: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct WebhookRequest {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+    // Signing secret, generated when missing
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ArtifactLink {
+    name: String,
+    size: u64,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct JobFinished<'a> {
+    job_id: &'a str,
+    event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<&'a str>,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_id: Option<&'a str>,
+    diagnostics: &'a [Diagnostic],
+    artifacts: Vec<ArtifactLink>,
+    duration_ms: u64,
+    finished_at: u64,
+}
+
+// Serializes changes to the registrations file
+static STORE: Mutex<()> = Mutex::new(());
+
+static HTTP: LazyLock<reqwest::Client> = LazyLock::new(|| client(None));
+
+fn client(pinned: Option<(&str, SocketAddr)>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).redirect(reqwest::redirect::Policy::none());
+    if let Some((host, address)) = pinned {
+        builder = builder.resolve(host, address);
+    }
+    builder.build().unwrap_or_default()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn registry_path() -> PathBuf {
+    server_data_dir().join(WEBHOOKS_FILE)
+}
+
+fn read() -> BTreeMap<String, Webhook> {
+    std::fs::read(registry_path())
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default()
+}
+
+fn write(webhooks: &BTreeMap<String, Webhook>) -> Result<(), String> {
+    let path = registry_path();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_vec_pretty(webhooks).map_err(|e| e.to_string())?;
+    std::fs::write(path, json).map_err(|e| e.to_string())
+}
+
+fn valid_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some())
+}
+
+// Addresses of the public internet: not loopback, private, link-local, shared (CGNAT),
+// unique local, multicast or unspecified
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback() ||
+                ip.is_private() ||
+                ip.is_link_local() ||
+                ip.is_unspecified() ||
+                ip.is_broadcast() ||
+                ip.is_multicast() ||
+                ip.is_documentation() ||
+                a == 0 ||
+                (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback() ||
+                ip.is_unspecified() ||
+                ip.is_multicast() ||
+                (first & 0xfe00) == 0xfc00 ||
+                (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+// The address to deliver to, refused unless everything the URL's host resolves to is public
+async fn public_address(url: &str) -> Result<(String, SocketAddr), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let host = parsed.host_str().ok_or("The URL has no host")?.trim_matches(['[', ']']).to_string();
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<SocketAddr> = tokio::net
+        ::lookup_host((host.as_str(), port)).await
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        return Err(format!("{} resolves to the non-public address {}", host, address.ip()));
+    }
+    let address = addresses.first().ok_or_else(|| format!("{} has no addresses", host))?;
+    Ok((host, *address))
+}
+
+// Register a webhook for `subject`'s jobs, the response carries its secret
+pub async fn register(subject: &str, request: WebhookRequest) -> Result<Webhook, String> {
+    if !valid_url(&request.url) {
+        return Err(format!("Invalid webhook URL: {}", request.url));
+    }
+    public_address(&request.url).await.map_err(|e| format!("Invalid webhook URL: {}", e))?;
+    let _store = STORE.lock().unwrap();
+    let mut webhooks = read();
+    if webhooks.values().filter(|webhook| webhook.subject == subject).count() >= MAX_WEBHOOKS_PER_SUBJECT {
+        return Err(format!("At most {} webhooks per identity", MAX_WEBHOOKS_PER_SUBJECT));
+    }
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().to_string(),
+        subject: subject.to_string(),
+        url: request.url,
+        events: request.events,
+        secret: request.secret.filter(|secret| !secret.is_empty()).unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()),
+        created_at: now(),
+    };
+    webhooks.insert(webhook.id.clone(), webhook.clone());
+    write(&webhooks)?;
+    info!("Registered webhook {} for {}", webhook.id, subject);
+    Ok(webhook)
+}
+
+// Webhooks of `subject`, without their secrets
+pub fn list(subject: &str) -> Vec<Webhook> {
+    read()
+        .into_values()
+        .filter(|webhook| webhook.subject == subject)
+        .map(|webhook| Webhook { secret: String::new(), ..webhook })
+        .collect()
+}
+
+// Remove one of `subject`'s webhooks, false if it has none with that id
+pub fn delete(subject: &str, id: &str) -> Result<bool, String> {
+    let _store = STORE.lock().unwrap();
+    let mut webhooks = read();
+    if webhooks.get(id).is_none_or(|webhook| webhook.subject != subject) {
+        return Ok(false);
+    }
+    webhooks.remove(id);
+    write(&webhooks)?;
+    Ok(true)
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+// POST the body to `url`, retried on failure. A client's hook is resolved before each attempt
+// and the connection pinned to the checked address, a name can't be re-pointed in between.
+async fn deliver(url: String, secret: String, delivery: String, body: Vec<u8>, checked: bool) {
+    for (attempt, delay) in std::iter::once(&Duration::ZERO).chain(RETRY_DELAYS).enumerate() {
+        tokio::time::sleep(*delay).await;
+        let pinned = match checked {
+            true =>
+                match public_address(&url).await {
+                    Ok((host, address)) => Some(client(Some((&host, address)))),
+                    Err(e) => {
+                        warn!("Webhook {} refused: {}", url, e);
+                        return;
+                    }
+                }
+            false => None,
+        };
+        let mut request = pinned
+            .as_ref()
+            .unwrap_or(&HTTP)
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Event", "job.finished")
+            .header("X-Webhook-Delivery", &delivery)
+            .body(body.clone());
+        if !secret.is_empty() {
+            request = request.header("X-Webhook-Signature", signature(&secret, &body));
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                return;
+            }
+            Ok(response) => warn!("Webhook {} answered {} (attempt {})", url, response.status(), attempt + 1),
+            Err(e) => warn!("Webhook {} failed: {} (attempt {})", url, e, attempt + 1),
+        }
+    }
+}
+
+// Notify the webhooks interested in how `job` answered, in the background
+pub fn job_finished(job: &CurrentJob, subject: Option<&str>, response: &CommandResponse) {
+    let server_hooks: Vec<(String, String)> = std::env
+        ::var("CLOUD_COMPILER_WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .map(|url| (url, std::env::var("CLOUD_COMPILER_WEBHOOK_SECRET").unwrap_or_default()))
+        .collect();
+    let client_hooks: Vec<(String, String)> = match subject {
+        Some(subject) => read()
+            .into_values()
+            .filter(|webhook| webhook.subject == subject)
+            .filter(|webhook| webhook.events.is_empty() || webhook.events.contains(&job.name))
+            .map(|webhook| (webhook.url, webhook.secret))
+            .collect(),
+        None => Vec::new(),
+    };
+    if server_hooks.is_empty() && client_hooks.is_empty() {
+        return;
+    }
+
+    let base = public_url();
+    let artifacts = match &response.build_id {
+        Some(build_id) => response.artifacts
+            .iter()
+            .map(|artifact| ArtifactLink {
+                name: artifact.name.clone(),
+                size: artifact.size,
+                url: artifact.url
+                    .clone()
+                    .unwrap_or_else(|| format!("{}/builds/{}/artifacts/{}", base, build_id, artifact.name)),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let payload = JobFinished {
+        job_id: &job.id,
+        event: &job.name,
+        subject,
+        status: if response.success { "success" } else { "failure" },
+        error_code: response.error_code.map(ErrorCode::as_str),
+        error: response.error.as_deref().filter(|_| !response.success),
+        build_id: response.build_id.as_deref(),
+        diagnostics: &response.diagnostics,
+        artifacts,
+        duration_ms: job.started.elapsed().as_millis() as u64,
+        finished_at: now(),
+    };
+    let Ok(body) = serde_json::to_vec(&payload) else {
+        return;
+    };
+    // The operator's own hooks may point at internal hosts
+    for (url, secret) in server_hooks {
+        tokio::spawn(deliver(url, secret, uuid::Uuid::new_v4().to_string(), body.clone(), false));
+    }
+    for (url, secret) in client_hooks {
+        tokio::spawn(deliver(url, secret, uuid::Uuid::new_v4().to_string(), body.clone(), true));
+    }
+}
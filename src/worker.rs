@@ -0,0 +1,297 @@
+// The worker side of `dispatch`. With CLOUD_COMPILER_ROLE=worker the server serves no clients:
+// it registers with the dispatcher at CLOUD_COMPILER_DISPATCHER_URL as CLOUD_COMPILER_WORKER_NAME
+// (default the host name) and runs the compiles it pulls, CLOUD_COMPILER_WORKER_SLOTS at once
+// (default the worker pool's size). Output goes back as it is printed, the build directory once
+// the compile is done. On SIGTERM it stops pulling, finishes what it runs and deregisters.
+use std::path::Path;
+use std::sync::{ Arc, LazyLock };
+use std::time::Duration;
+use tokio::sync::{ mpsc, OwnedSemaphorePermit, Semaphore };
+use tokio_util::sync::CancellationToken;
+use tracing::{ info, warn };
+use crate::artifacts::zip_dir;
+use crate::compiler::{ error_response, is_safe_name, run_arduino_command, server_data_dir };
+use crate::dispatch::{ self, Assignment, Registered, Registration, WorkerHealth, BUILD, SKETCH };
+use crate::files::extract_zip_base64;
+use crate::health;
+use crate::models::{ ArduinoCommand, CommandResponse, LogLine };
+use crate::output::{ self, Sink };
+use crate::queue;
+use crate::resources::{ acquire, ResourceKind };
+use crate::shutdown;
+
+// Pause after a request to the dispatcher failed
+const RETRY: Duration = Duration::from_secs(5);
+// How often output goes back, which also tells the dispatcher the worker is alive
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// Longer than the dispatcher holds a poll
+const POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+static HTTP: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+struct Dispatcher {
+    url: String,
+    token: String,
+}
+
+impl Dispatcher {
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        HTTP.post(format!("{}{}", self.url, path)).bearer_auth(&self.token)
+    }
+}
+
+enum Polled {
+    Job(Assignment),
+    Idle,
+    // The dispatcher doesn't know this worker
+    Unknown,
+}
+
+fn slots() -> usize {
+    std::env
+        ::var("CLOUD_COMPILER_WORKER_SLOTS")
+        .ok()
+        .and_then(|slots| slots.trim().parse().ok())
+        .filter(|slots| *slots > 0)
+        .unwrap_or_else(|| queue::stats().workers)
+}
+
+async fn registration(slots: usize) -> Registration {
+    let name = ["CLOUD_COMPILER_WORKER_NAME", "HOSTNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+        .unwrap_or_else(|| "worker".to_string());
+    let (version, cores) = tokio::join!(health::arduino_cli_version(), health::installed_cores());
+    Registration {
+        name,
+        slots,
+        platforms: cores
+            .unwrap_or_default()
+            .into_iter()
+            .map(|core| core.id)
+            .collect(),
+        arduino_cli: version.ok(),
+    }
+}
+
+fn health(running: usize) -> WorkerHealth {
+    let free = health
+        ::disk_space()
+        .into_iter()
+        .filter_map(|disk| disk.available_bytes)
+        .min();
+    WorkerHealth { running, free_disk_mb: free.map(|bytes| bytes / 1024 / 1024) }
+}
+
+// Register, retrying until the dispatcher takes the worker
+async fn register(dispatcher: &Dispatcher, registration: &Registration) -> String {
+    loop {
+        let registered = async {
+            let response = dispatcher.post("/workers").json(registration).send().await.map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("Dispatcher answered {}", response.status()));
+            }
+            response.json::<Registered>().await.map_err(|e| e.to_string())
+        };
+        match registered.await {
+            Ok(Registered { id }) => {
+                info!("Registered with the dispatcher as {}", id);
+                return id;
+            }
+            Err(e) => {
+                warn!("Failed to register with the dispatcher: {}", e);
+                tokio::time::sleep(RETRY).await;
+            }
+        }
+    }
+}
+
+async fn poll(dispatcher: &Dispatcher, id: &str, health: &WorkerHealth) -> Result<Polled, String> {
+    let response = dispatcher
+        .post(&format!("/workers/{}/poll", id))
+        .json(health)
+        .timeout(POLL_TIMEOUT)
+        .send().await
+        .map_err(|e| e.to_string())?;
+    match response.status() {
+        reqwest::StatusCode::OK => Ok(Polled::Job(response.json().await.map_err(|e| e.to_string())?)),
+        reqwest::StatusCode::NO_CONTENT => Ok(Polled::Idle),
+        reqwest::StatusCode::NOT_FOUND => Ok(Polled::Unknown),
+        status => Err(format!("Dispatcher answered {}", status)),
+    }
+}
+
+// Pull compiles while a slot is free, `id` the worker's registration
+async fn pull(dispatcher: &Arc<Dispatcher>, registration: &Registration, slots: &Arc<Semaphore>, id: &mut Option<String>) {
+    loop {
+        let permit = slots.clone().acquire_owned().await.expect("slots are never closed");
+        let worker = match id {
+            Some(worker) => worker.clone(),
+            None => id.insert(register(dispatcher, registration).await).clone(),
+        };
+        let running = registration.slots - slots.available_permits() - 1;
+        match poll(dispatcher, &worker, &health(running)).await {
+            Ok(Polled::Job(assignment)) => {
+                tokio::spawn(run_job(dispatcher.clone(), worker, assignment, permit));
+            }
+            Ok(Polled::Idle) => {}
+            Ok(Polled::Unknown) => {
+                warn!("The dispatcher dropped this worker, registering again");
+                *id = None;
+            }
+            Err(e) => {
+                warn!("Failed to poll the dispatcher: {}", e);
+                tokio::time::sleep(RETRY).await;
+            }
+        }
+    }
+}
+
+// Compile for the dispatcher until a shutdown signal
+pub async fn run() -> Result<(), String> {
+    let url = std::env
+        ::var("CLOUD_COMPILER_DISPATCHER_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .ok_or("CLOUD_COMPILER_DISPATCHER_URL is not set")?;
+    let token = dispatch::token().ok_or("CLOUD_COMPILER_WORKER_TOKEN is not set")?;
+    let dispatcher = Arc::new(Dispatcher { url, token });
+    let registration = registration(slots()).await;
+    info!("Worker {} with {} slots, dispatcher {}", registration.name, registration.slots, dispatcher.url);
+
+    let slots = Arc::new(Semaphore::new(registration.slots));
+    let mut id = None;
+    tokio::select! {
+        _ = pull(&dispatcher, &registration, &slots, &mut id) => {}
+        _ = shutdown::signalled() => {}
+    }
+    // Compiles pulled so far still run to the end
+    shutdown::finish_jobs().await;
+    if let Some(id) = id {
+        let deregistered = HTTP.delete(format!("{}/workers/{}", dispatcher.url, id)).bearer_auth(&dispatcher.token).send().await;
+        if let Err(e) = deregistered {
+            warn!("Failed to deregister from the dispatcher: {}", e);
+        }
+    }
+    info!("Drained, stopping the worker");
+    Ok(())
+}
+
+// Real paths in `text` replaced by the stand-ins the dispatcher knows
+fn conceal(text: &str, sketch: &Path, build: &Path) -> String {
+    text.replace(&*sketch.to_string_lossy(), SKETCH).replace(&*build.to_string_lossy(), BUILD)
+}
+
+// Run `assignment` in a slot, then send the result and build directory back
+async fn run_job(dispatcher: Arc<Dispatcher>, worker: String, assignment: Assignment, _slot: OwnedSemaphorePermit) {
+    let guard = acquire(ResourceKind::Job, "compile");
+    let base = format!("/workers/{}/jobs/{}", worker, assignment.job_id);
+    if !is_safe_name(&assignment.job_id) || !is_safe_name(&assignment.sketch) {
+        let response = error_response(&assignment.command, vec![], "Invalid job or sketch name");
+        send_result(&dispatcher, &base, &response, None).await.ok();
+        return;
+    }
+    info!(job_id = %assignment.job_id, "Compiling for the dispatcher");
+    let dir = server_data_dir().join("worker").join(&assignment.job_id);
+    let sketch = dir.join("sketch").join(&assignment.sketch);
+    let build = dir.join("build");
+
+    let mut response = guard.scope(compile(&dispatcher, &base, &assignment, &sketch, &build, guard.token())).await;
+    response.output = conceal(&response.output, &sketch, &build);
+    response.error = response.error.map(|error| conceal(&error, &sketch, &build));
+    if let Err(e) = send_result(&dispatcher, &base, &response, Some(&build)).await {
+        warn!(job_id = %assignment.job_id, "Failed to send the result: {}", e);
+    }
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        warn!("Failed to remove {}: {}", dir.display(), e);
+    }
+}
+
+async fn compile(
+    dispatcher: &Dispatcher,
+    base: &str,
+    assignment: &Assignment,
+    sketch: &Path,
+    build: &Path,
+    cancel: &CancellationToken
+) -> CommandResponse {
+    let args = assignment.args
+        .iter()
+        .map(|arg| arg.replace(SKETCH, &sketch.to_string_lossy()).replace(BUILD, &build.to_string_lossy()))
+        .collect();
+    let command = ArduinoCommand { command: assignment.command.clone(), args };
+    let unpacked = std::fs
+        ::create_dir_all(build)
+        .map_err(|e| e.to_string())
+        .and_then(|_| extract_zip_base64(sketch, &assignment.archive));
+    if let Err(e) = unpacked {
+        return error_response(&command.command, command.args, &format!("Failed to unpack the sketch: {}", e));
+    }
+
+    let (sender, mut lines) = mpsc::unbounded_channel();
+    let sink: Sink = Arc::new(move |stream, text| {
+        sender.send(LogLine { stream, text: text.to_string() }).ok();
+    });
+    let run = output::scope(Some(sink), output::live(run_arduino_command(&command)));
+    tokio::pin!(run);
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    let response = loop {
+        tokio::select! {
+            response = &mut run => break response,
+            _ = flush.tick() => {
+                if !send_output(dispatcher, base, &mut lines, sketch, build).await {
+                    // Nobody waits for the compile anymore
+                    cancel.cancel();
+                }
+            }
+        }
+    };
+    send_output(dispatcher, base, &mut lines, sketch, build).await;
+    response
+}
+
+// Send the output printed since the last call, false once the dispatcher gave up on the compile
+async fn send_output(
+    dispatcher: &Dispatcher,
+    base: &str,
+    lines: &mut mpsc::UnboundedReceiver<LogLine>,
+    sketch: &Path,
+    build: &Path
+) -> bool {
+    let mut batch = vec![];
+    while let Ok(mut line) = lines.try_recv() {
+        line.text = conceal(&line.text, sketch, build);
+        batch.push(line);
+    }
+    match dispatcher.post(&format!("{}/output", base)).json(&batch).send().await {
+        Ok(response) => response.status() != reqwest::StatusCode::GONE,
+        // The compile carries on, the dispatcher may be back by its end
+        Err(e) => {
+            warn!("Failed to send output to the dispatcher: {}", e);
+            true
+        }
+    }
+}
+
+async fn send_result(dispatcher: &Dispatcher, base: &str, response: &CommandResponse, build: Option<&Path>) -> Result<(), String> {
+    let mut form = reqwest::multipart::Form::new().text("response", serde_json::to_string(response).map_err(|e| e.to_string())?);
+    if let Some(build) = build {
+        let build = build.to_path_buf();
+        let archive = build.with_extension("zip");
+        let bytes = tokio::task
+            ::spawn_blocking(move || zip_dir(&build, "", &archive).and_then(|_| std::fs::read(&archive).map_err(|e| e.to_string()))).await
+            .map_err(|e| e.to_string())??;
+        form = form.part("build", reqwest::multipart::Part::bytes(bytes).file_name("build.zip"));
+    }
+    let answer = dispatcher
+        .post(&format!("{}/result", base))
+        .multipart(form)
+        .send().await
+        .map_err(|e| e.to_string())?;
+    match answer.status() {
+        // Cancelled in the meantime
+        status if status.is_success() || status == reqwest::StatusCode::GONE => Ok(()),
+        status => Err(format!("Dispatcher answered {}", status)),
+    }
+}
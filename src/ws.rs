@@ -0,0 +1,224 @@
+// Plain WebSocket protocol on `/ws`, for clients on platforms where Socket.IO libraries are poor
+// (embedded devices, some mobile stacks). It serves the events of the `/` namespace through the
+// same handlers. Every message is an object, as a JSON text frame or a MessagePack binary frame:
+//
+//     {"event": "compile-sketch", "data": {...}, "id": 1}    an event, `id` asks for an answer
+//     {"id": 1, "data": {...}}                               the answer to event 1
+//     {"event": "queue-update", "data": {...}}               an event the server sends
+//
+// The first message is `{"event": "connect", "data": <auth>}`, the auth data of a Socket.IO
+// handshake. Its framing is the connection's: binary frames mean raw binaries, like the
+// MessagePack Socket.IO path.
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, LazyLock, Mutex };
+use std::time::Duration;
+use axum::extract::{ FromRequestParts, Request };
+use axum::extract::ws::{ Message, WebSocket, WebSocketUpgrade };
+use axum::http::request::Parts;
+use axum::response::{ IntoResponse, Response };
+use futures::{ SinkExt, StreamExt };
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use socketioxide::extensions::Extensions;
+use socketioxide::socket::Sid;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+use crate::errors::CompilerError;
+use crate::connection::{ Ack, Connection, Disconnected, Handlers };
+use crate::msgpack::{ self, MessagePack };
+use crate::socketio;
+
+pub const PATH: &str = "/ws";
+
+// How long a client may take to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct WsClient {
+    pub id: Sid,
+    pub extensions: Extensions,
+    pub parts: Parts,
+    pub handlers: Handlers,
+    pub disconnected: Disconnected,
+    // Rooms joined, local to this instance
+    pub rooms: Mutex<HashSet<String>>,
+    // MessagePack framing
+    binary: bool,
+    outgoing: mpsc::UnboundedSender<Message>,
+    closed: CancellationToken,
+}
+
+#[derive(Deserialize)]
+struct Incoming {
+    event: String,
+    #[serde(default)]
+    data: Value,
+    id: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Outgoing<'a, T: ?Sized> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    data: &'a T,
+}
+
+impl WsClient {
+    // Send an event, or with `id` an answer
+    pub fn send<T: ?Sized + Serialize>(&self, event: Option<&str>, id: Option<u64>, data: &T) -> Result<(), String> {
+        let message = Outgoing { event, id, data };
+        let frame = match self.binary {
+            true => Message::Binary(msgpack::encode(&message)?.into()),
+            false => Message::Text(serde_json::to_string(&message).map_err(|e| e.to_string())?.into()),
+        };
+        self.outgoing.send(frame).map_err(|_| "Connection closed".to_string())
+    }
+
+    // Hang up once what was sent so far went out
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+}
+
+static CLIENTS: LazyLock<Mutex<HashMap<Sid, Arc<WsClient>>>> = LazyLock::new(Default::default);
+
+// Send `event` to every connected client
+pub fn broadcast<T: ?Sized + Serialize>(event: &str, data: &T) {
+    for client in CLIENTS.lock().unwrap().values() {
+        client.send(Some(event), None, data).ok();
+    }
+}
+
+// Send `event` to the clients in `room`
+pub fn broadcast_to<T: ?Sized + Serialize>(room: &str, event: &str, data: &T) {
+    for client in CLIENTS.lock().unwrap().values().filter(|client| client.rooms.lock().unwrap().contains(room)) {
+        client.send(Some(event), None, data).ok();
+    }
+}
+
+pub fn close_all() {
+    for client in CLIENTS.lock().unwrap().values() {
+        client.close();
+    }
+}
+
+pub async fn upgrade(request: Request) -> Response {
+    let (mut parts, _) = request.into_parts();
+    match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(upgrade) => upgrade.on_upgrade(move |socket| serve(socket, parts)),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+// The message of a frame, and whether it was MessagePack. Other frames are `None`.
+fn decode(message: Message) -> Option<(Result<Incoming, String>, bool)> {
+    match message {
+        Message::Text(text) => Some((serde_json::from_str(&text).map_err(|e| e.to_string()), false)),
+        Message::Binary(bytes) => Some((msgpack::decode(&bytes), true)),
+        _ => None,
+    }
+}
+
+async fn serve(socket: WebSocket, parts: Parts) {
+    let (mut sink, mut stream) = socket.split();
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        while let Some(Ok(message)) = stream.next().await {
+            if let Some(decoded) = decode(message) {
+                return Some(decoded);
+            }
+        }
+        None
+    }).await;
+    let (auth, binary) = match handshake {
+        Ok(Some((Ok(handshake), binary))) if handshake.event == "connect" => (handshake.data, binary),
+        _ => {
+            sink.close().await.ok();
+            return;
+        }
+    };
+
+    let (outgoing, mut queued) = mpsc::unbounded_channel();
+    let client = Arc::new(WsClient {
+        id: Sid::new(),
+        extensions: Extensions::new(),
+        parts,
+        handlers: Default::default(),
+        disconnected: Default::default(),
+        rooms: Default::default(),
+        binary,
+        outgoing,
+        closed: CancellationToken::new(),
+    });
+    let closed = client.closed.clone();
+    let writer = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                frame = queued.recv() => {
+                    let Some(frame) = frame else {
+                        break;
+                    };
+                    if sink.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+                _ = closed.cancelled() => {
+                    // What was queued before the hang-up still goes out
+                    while let Ok(frame) = queued.try_recv() {
+                        sink.send(frame).await.ok();
+                    }
+                    break;
+                }
+            }
+        }
+        sink.close().await.ok();
+    });
+
+    let connection = Connection::WebSocket(client.clone());
+    if binary {
+        client.extensions.insert(MessagePack);
+    }
+    CLIENTS.lock().unwrap().insert(client.id, client.clone());
+    socketio::on_connection(connection.clone(), auth);
+
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = client.closed.cancelled() => None,
+        };
+        let Some(Ok(message)) = message else {
+            break;
+        };
+        match decode(message) {
+            Some((Ok(incoming), _)) => dispatch(&client, incoming),
+            Some((Err(e), _)) => {
+                client.send(Some("malformed-message"), None, &serde_json::json!({ "error": e })).ok();
+            }
+            None => {}
+        }
+    }
+
+    info!(ns = PATH, socket.id = %client.id, "WebSocket disconnected");
+    CLIENTS.lock().unwrap().remove(&client.id);
+    client.close();
+    let callbacks = std::mem::take(&mut *client.disconnected.lock().unwrap());
+    for callback in callbacks {
+        callback(connection.clone());
+    }
+    // Handlers capture nothing of the client, but running jobs may still hold it to answer
+    client.handlers.lock().unwrap().clear();
+    writer.await.ok();
+}
+
+fn dispatch(client: &Arc<WsClient>, incoming: Incoming) {
+    let handler = client.handlers.lock().unwrap().get(incoming.event.as_str()).cloned();
+    let ack = Ack::websocket(client, incoming.id, &incoming.data);
+    match handler {
+        Some(handler) => handler(Connection::WebSocket(client.clone()), incoming.data, ack),
+        None => {
+            let response = CompilerError::UnknownEvent(incoming.event.clone()).response(&incoming.event, vec![]);
+            ack.send(&response).ok();
+        }
+    }
+}
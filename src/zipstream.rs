@@ -0,0 +1,331 @@
+use std::io::{ self, Read, Write };
+use flate2::Crc;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+// ZIP writer for outputs that can't seek, like a response body. Entries are deflated straight
+// into the output with their CRC and sizes in a data descriptor after the data, so the local
+// headers never need patching. ZIP64 records are only written where a size, offset or entry
+// count doesn't fit the classic fields.
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+
+// General purpose flags: sizes in a data descriptor, UTF-8 names
+const FLAG_DESCRIPTOR: u16 = 0x0008;
+const FLAG_UTF8: u16 = 0x0800;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+// Unix host, so the external attributes carry permissions
+const MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+
+// Entries are dated 1980-01-01 00:00, as the zip crate does without its time feature
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+const FILE_ATTRIBUTES: u32 = 0o100644 << 16;
+const DIRECTORY_ATTRIBUTES: u32 = (0o040755 << 16) | 0x10;
+
+const U32_LIMIT: u64 = 0xffff_ffff;
+const U16_LIMIT: usize = 0xffff;
+// Files from this size on get ZIP64 sizes up front, leaving room for deflate's worst case growth
+const ZIP64_FILE_SIZE: u64 = 0xf000_0000;
+
+struct CentralEntry {
+    name: String,
+    method: u16,
+    flags: u16,
+    crc: u32,
+    compressed: u64,
+    uncompressed: u64,
+    offset: u64,
+    attributes: u32,
+    zip64: bool,
+}
+
+// Counts what has been written, for the offsets of the central directory
+struct Counted<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct ZipStream<W: Write> {
+    out: Counted<W>,
+    entries: Vec<CentralEntry>,
+}
+
+impl<W: Write> ZipStream<W> {
+    pub fn new(out: W) -> Self {
+        ZipStream { out: Counted { inner: out, written: 0 }, entries: Vec::new() }
+    }
+
+    // Add a directory, `name` gets its trailing slash when missing
+    pub fn add_directory(&mut self, name: &str) -> io::Result<()> {
+        let name = if name.ends_with('/') { name.to_string() } else { format!("{}/", name) };
+        let offset = self.out.written;
+        self.write_local_header(&name, STORED, FLAG_UTF8, false)?;
+        self.entries.push(CentralEntry {
+            name,
+            method: STORED,
+            flags: FLAG_UTF8,
+            crc: 0,
+            compressed: 0,
+            uncompressed: 0,
+            offset,
+            attributes: DIRECTORY_ATTRIBUTES,
+            zip64: false,
+        });
+        Ok(())
+    }
+
+    // Add a file with the contents of `source`. `size` is its expected length, it picks whether
+    // the entry gets ZIP64 sizes; a source that ends up past 4 GiB without them is an error.
+    pub fn add_file(&mut self, name: &str, source: &mut impl Read, size: u64) -> io::Result<()> {
+        let zip64 = size >= ZIP64_FILE_SIZE;
+        let flags = FLAG_DESCRIPTOR | FLAG_UTF8;
+        let offset = self.out.written;
+        self.write_local_header(name, DEFLATED, flags, zip64)?;
+
+        let start = self.out.written;
+        let mut crc = Crc::new();
+        let mut uncompressed = 0u64;
+        let mut encoder = DeflateEncoder::new(&mut self.out, Compression::default());
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = match source.read(&mut buffer) {
+                Ok(0) => {
+                    break;
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+            crc.update(&buffer[..n]);
+            uncompressed += n as u64;
+            encoder.write_all(&buffer[..n])?;
+        }
+        encoder.finish()?;
+        let compressed = self.out.written - start;
+        if !zip64 && (compressed >= U32_LIMIT || uncompressed >= U32_LIMIT) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} grew past 4 GiB while zipping", name)));
+        }
+
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR);
+        put_u32(&mut descriptor, crc.sum());
+        if zip64 {
+            put_u64(&mut descriptor, compressed);
+            put_u64(&mut descriptor, uncompressed);
+        } else {
+            put_u32(&mut descriptor, compressed as u32);
+            put_u32(&mut descriptor, uncompressed as u32);
+        }
+        self.out.write_all(&descriptor)?;
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            method: DEFLATED,
+            flags,
+            crc: crc.sum(),
+            compressed,
+            uncompressed,
+            offset,
+            attributes: FILE_ATTRIBUTES,
+            zip64,
+        });
+        Ok(())
+    }
+
+    fn write_local_header(&mut self, name: &str, method: u16, flags: u16, zip64: bool) -> io::Result<()> {
+        let mut extra = Vec::new();
+        if zip64 {
+            // Sizes follow in the descriptor, the fields only mark the entry as ZIP64
+            put_u16(&mut extra, ZIP64_EXTRA);
+            put_u16(&mut extra, 16);
+            put_u64(&mut extra, 0);
+            put_u64(&mut extra, 0);
+        }
+        let sizes = if zip64 { U32_LIMIT as u32 } else { 0 };
+
+        let mut header = Vec::with_capacity(30 + name.len() + extra.len());
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(&mut header, if zip64 { VERSION_ZIP64 } else { VERSION });
+        put_u16(&mut header, flags);
+        put_u16(&mut header, method);
+        put_u16(&mut header, DOS_TIME);
+        put_u16(&mut header, DOS_DATE);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, sizes);
+        put_u32(&mut header, sizes);
+        put_u16(&mut header, name_len(name)?);
+        put_u16(&mut header, extra.len() as u16);
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&extra);
+        self.out.write_all(&header)
+    }
+
+    // Write the central directory and hand back the output, flushed
+    pub fn finish(mut self) -> io::Result<W> {
+        let start = self.out.written;
+        for entry in &self.entries {
+            let compressed_zip64 = entry.zip64 || entry.compressed >= U32_LIMIT;
+            let uncompressed_zip64 = entry.zip64 || entry.uncompressed >= U32_LIMIT;
+            let offset_zip64 = entry.offset >= U32_LIMIT;
+
+            let mut fields = Vec::new();
+            if uncompressed_zip64 {
+                put_u64(&mut fields, entry.uncompressed);
+            }
+            if compressed_zip64 {
+                put_u64(&mut fields, entry.compressed);
+            }
+            if offset_zip64 {
+                put_u64(&mut fields, entry.offset);
+            }
+            let mut extra = Vec::new();
+            if !fields.is_empty() {
+                put_u16(&mut extra, ZIP64_EXTRA);
+                put_u16(&mut extra, fields.len() as u16);
+                extra.extend_from_slice(&fields);
+            }
+
+            let mut header = Vec::with_capacity(46 + entry.name.len() + extra.len());
+            put_u32(&mut header, CENTRAL_HEADER);
+            put_u16(&mut header, MADE_BY);
+            put_u16(&mut header, if extra.is_empty() { VERSION } else { VERSION_ZIP64 });
+            put_u16(&mut header, entry.flags);
+            put_u16(&mut header, entry.method);
+            put_u16(&mut header, DOS_TIME);
+            put_u16(&mut header, DOS_DATE);
+            put_u32(&mut header, entry.crc);
+            put_u32(&mut header, (if compressed_zip64 { U32_LIMIT } else { entry.compressed }) as u32);
+            put_u32(&mut header, (if uncompressed_zip64 { U32_LIMIT } else { entry.uncompressed }) as u32);
+            put_u16(&mut header, name_len(&entry.name)?);
+            put_u16(&mut header, extra.len() as u16);
+            put_u16(&mut header, 0);
+            put_u16(&mut header, 0);
+            put_u16(&mut header, 0);
+            put_u32(&mut header, entry.attributes);
+            put_u32(&mut header, entry.offset.min(U32_LIMIT) as u32);
+            header.extend_from_slice(entry.name.as_bytes());
+            header.extend_from_slice(&extra);
+            self.out.write_all(&header)?;
+        }
+        let end = self.out.written;
+        let size = end - start;
+        let count = self.entries.len();
+
+        let mut trailer = Vec::new();
+        if count >= U16_LIMIT || start >= U32_LIMIT || size >= U32_LIMIT {
+            put_u32(&mut trailer, ZIP64_END_OF_CENTRAL);
+            put_u64(&mut trailer, 44);
+            put_u16(&mut trailer, MADE_BY);
+            put_u16(&mut trailer, VERSION_ZIP64);
+            put_u32(&mut trailer, 0);
+            put_u32(&mut trailer, 0);
+            put_u64(&mut trailer, count as u64);
+            put_u64(&mut trailer, count as u64);
+            put_u64(&mut trailer, size);
+            put_u64(&mut trailer, start);
+
+            put_u32(&mut trailer, ZIP64_LOCATOR);
+            put_u32(&mut trailer, 0);
+            put_u64(&mut trailer, end);
+            put_u32(&mut trailer, 1);
+        }
+        put_u32(&mut trailer, END_OF_CENTRAL);
+        put_u16(&mut trailer, 0);
+        put_u16(&mut trailer, 0);
+        put_u16(&mut trailer, count.min(U16_LIMIT) as u16);
+        put_u16(&mut trailer, count.min(U16_LIMIT) as u16);
+        put_u32(&mut trailer, size.min(U32_LIMIT) as u32);
+        put_u32(&mut trailer, start.min(U32_LIMIT) as u32);
+        put_u16(&mut trailer, 0);
+        self.out.write_all(&trailer)?;
+        self.out.flush()?;
+        Ok(self.out.inner)
+    }
+}
+
+fn name_len(name: &str) -> io::Result<u16> {
+    u16::try_from(name.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Entry name too long: {}", name)))
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn archives_read_back_with_the_zip_crate() {
+        let mut zip = ZipStream::new(Vec::new());
+        zip.add_directory("build").unwrap();
+        let firmware: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        zip.add_file("build/firmware.bin", &mut firmware.as_slice(), firmware.len() as u64).unwrap();
+        zip.add_file("build/empty.txt", &mut io::empty(), 0).unwrap();
+        // Sizes announced as ZIP64 read back the same
+        zip.add_file("sketch.ino.map", &mut &b"map contents"[..], ZIP64_FILE_SIZE).unwrap();
+        let bytes = zip.finish().unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 4);
+        assert!(archive.by_name("build/").unwrap().is_dir());
+        let mut read = |name: &str| {
+            let mut contents = Vec::new();
+            archive.by_name(name).unwrap().read_to_end(&mut contents).unwrap();
+            contents
+        };
+        assert_eq!(read("build/firmware.bin"), firmware);
+        assert!(read("build/empty.txt").is_empty());
+        assert_eq!(read("sketch.ino.map"), b"map contents");
+    }
+
+    #[test]
+    fn entry_counts_past_the_classic_limit_get_zip64_records() {
+        let mut zip = ZipStream::new(Vec::new());
+        for i in 0..U16_LIMIT + 1 {
+            zip.add_directory(&format!("d{}", i)).unwrap();
+        }
+        let bytes = zip.finish().unwrap();
+
+        let archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), U16_LIMIT + 1);
+    }
+}
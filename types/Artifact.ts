@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type Artifact = { name: string, size: number, sha256?: string, url?: string, expires_at?: number, };
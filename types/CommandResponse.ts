@@ -0,0 +1,11 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Artifact } from "./Artifact";
+import type { Diagnostic } from "./Diagnostic";
+import type { ErrorCode } from "./ErrorCode";
+import type { Explanation } from "./Explanation";
+import type { FieldError } from "./FieldError";
+import type { MemoryUsage } from "./MemoryUsage";
+import type { SizeDelta } from "./SizeDelta";
+import type { Timings } from "./Timings";
+
+export type CommandResponse = { success: boolean, output: string, error: string | null, command: string, args: Array<string>, build_id?: string, artifacts?: Array<Artifact>, diagnostics?: Array<Diagnostic>, explanations?: Array<Explanation>, error_code?: ErrorCode, retry_after?: number, cached?: boolean, job_id?: string, stderr?: string, exit_code?: number, started_at?: number, finished_at?: number, duration_ms?: number, arduino_cli_version?: string, size?: MemoryUsage, size_delta?: SizeDelta, invalid_fields?: Array<FieldError>, timings?: Timings, };
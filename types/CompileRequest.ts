@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { JsonValue } from "./serde_json/JsonValue";
+import type { Priority } from "./Priority";
+
+export type CompileRequest = { sketch_path?: string, project?: string, fqbn?: string, profile?: string, lockfile?: JsonValue, merge?: boolean, encrypt?: string, sign?: JsonValue, partitions_csv?: string, keep_build_dir?: boolean, teaching?: boolean, timings?: boolean, stream_output?: boolean, secrets?: { [key in string]?: string }, variables?: JsonValue, patches?: JsonValue, size_threshold_percent?: number, toolchain?: string, priority?: Priority, };
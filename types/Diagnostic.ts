@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Severity } from "./Severity";
+
+export type Diagnostic = { severity: Severity, code: string, message: string, file?: string, line?: number, };
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type ErrorCode = "invalid_request" | "unauthorized" | "rate_limited" | "queue_full" | "shutting_down" | "intake_paused" | "unknown_event" | "policy_violation" | "timeout" | "cancelled" | "worker_failed" | "core_not_installed" | "port_busy" | "port_not_found" | "sketch_too_large" | "compile_failed";
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type Explanation = { code: string, title: string, explanation: string, link: string, file?: string, line?: number, };
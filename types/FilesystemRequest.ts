@@ -0,0 +1,5 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FilePayload } from "./FilePayload";
+import type { FilesystemKind } from "./FilesystemKind";
+
+export type FilesystemRequest = { build_id: string, filesystem?: FilesystemKind, files?: Array<FilePayload>, zip?: string, port?: string, };
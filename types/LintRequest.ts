@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FilePayload } from "./FilePayload";
+
+export type LintRequest = { sketch_path?: string, files?: Array<FilePayload>, zip?: string, name?: string, project_type?: string, compliance?: string, library_manager?: string, recursive?: boolean, };
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type MemoryUsage = { flash_bytes: number, flash_max?: number, ram_bytes?: number, ram_max?: number, };
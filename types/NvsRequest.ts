@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { NvsEntry } from "./NvsEntry";
+
+export type NvsRequest = { build_id?: string, csv?: string, entries?: Array<NvsEntry>, size?: number, offset?: number, port?: string, chip?: string, };
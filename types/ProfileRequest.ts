@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Priority } from "./Priority";
+
+export type ProfileRequest = { sketch_path: string, fqbn: string, profile: string, default?: boolean, priority?: Priority, };
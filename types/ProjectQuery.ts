@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { BuildStatus } from "./BuildStatus";
+
+export type ProjectQuery = { text?: string, tags?: Array<string>, board?: string, status?: BuildStatus, limit?: number, };
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FilePayload } from "./FilePayload";
+
+export type ProjectRequest = { name: string, sketch_path?: string, files?: Array<FilePayload>, tags?: Array<string>, board?: string, metadata?: { [key in string]?: string }, };
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type SizeDelta = { previous_build_id: string, flash_bytes: number, flash_percent: number, ram_bytes?: number, };
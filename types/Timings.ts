@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type Timings = { queue_ms: number, setup_ms: number, compile_ms: number, post_process_ms: number, packaging_ms: number, total_ms: number, };
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type TransferChunkRequest = { transfer_id: string, seq: number, data: string | Uint8Array, sha256: string, };
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type UploadRequest = { sketch_path: string, fqbn: string, port?: string, address?: string, build_id?: string, ota_password?: string, toolchain?: string, };
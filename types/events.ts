@@ -0,0 +1,113 @@
+// Generated by `cargo run --bin export-types`, do not edit.
+import type { AnalyzeRequest } from "./AnalyzeRequest";
+import type { ArchiveEndRequest } from "./ArchiveEndRequest";
+import type { CompileExampleRequest } from "./CompileExampleRequest";
+import type { CompileRequest } from "./CompileRequest";
+import type { DeviceDeleteRequest } from "./DeviceDeleteRequest";
+import type { DeviceQuery } from "./DeviceQuery";
+import type { DeviceRequest } from "./DeviceRequest";
+import type { DownloadChunkRequest } from "./DownloadChunkRequest";
+import type { DownloadRequest } from "./DownloadRequest";
+import type { EncryptionKeyImportRequest } from "./EncryptionKeyImportRequest";
+import type { EncryptionKeyRequest } from "./EncryptionKeyRequest";
+import type { EsptoolRequest } from "./EsptoolRequest";
+import type { ExamplesQuery } from "./ExamplesQuery";
+import type { FilesystemRequest } from "./FilesystemRequest";
+import type { FirmwareAssignRequest } from "./FirmwareAssignRequest";
+import type { FirmwarePublishRequest } from "./FirmwarePublishRequest";
+import type { FormatRequest } from "./FormatRequest";
+import type { IdeRequest } from "./IdeRequest";
+import type { InstallCoreRequest } from "./InstallCoreRequest";
+import type { JobRequest } from "./JobRequest";
+import type { LintRequest } from "./LintRequest";
+import type { LocaleRequest } from "./LocaleRequest";
+import type { LspFileRequest } from "./LspFileRequest";
+import type { MonitorRequest } from "./MonitorRequest";
+import type { MonitorStopRequest } from "./MonitorStopRequest";
+import type { NvsRequest } from "./NvsRequest";
+import type { PowerCycleRequest } from "./PowerCycleRequest";
+import type { ProfileRequest } from "./ProfileRequest";
+import type { ProjectNameRequest } from "./ProjectNameRequest";
+import type { ProjectQuery } from "./ProjectQuery";
+import type { ProjectRequest } from "./ProjectRequest";
+import type { RecordingsQuery } from "./RecordingsQuery";
+import type { ReleasesQuery } from "./ReleasesQuery";
+import type { ReplayRequest } from "./ReplayRequest";
+import type { ResetRequest } from "./ResetRequest";
+import type { RolloutGroupRequest } from "./RolloutGroupRequest";
+import type { RolloutRequest } from "./RolloutRequest";
+import type { SigningKeyImportRequest } from "./SigningKeyImportRequest";
+import type { SigningKeyRequest } from "./SigningKeyRequest";
+import type { SketchArchiveRequest } from "./SketchArchiveRequest";
+import type { SketchNewRequest } from "./SketchNewRequest";
+import type { SketchRequest } from "./SketchRequest";
+import type { TransferBeginRequest } from "./TransferBeginRequest";
+import type { TransferChunkRequest } from "./TransferChunkRequest";
+import type { TransferRequest } from "./TransferRequest";
+import type { UploadRequest } from "./UploadRequest";
+import type { CommandResponse } from "./CommandResponse";
+
+// Payload of each client event
+export interface ClientEvents {
+  "install-core": InstallCoreRequest;
+  "compile-sketch": CompileRequest;
+  "upload-sketch": UploadRequest;
+  "chip-info": EsptoolRequest;
+  "read-mac": EsptoolRequest;
+  "erase-flash": EsptoolRequest;
+  "reset-board": ResetRequest;
+  "build-filesystem": FilesystemRequest;
+  "generate-nvs": NvsRequest;
+  "format-sketch": FormatRequest;
+  "lint-project": LintRequest;
+  "project-save": ProjectRequest;
+  "project-search": ProjectQuery;
+  "monitor-start": MonitorRequest;
+  "cancel-job": JobRequest;
+  "archive-begin": TransferBeginRequest;
+  "archive-chunk": TransferChunkRequest;
+  "archive-end": ArchiveEndRequest;
+  "download-begin": DownloadRequest;
+  "download-chunk": DownloadChunkRequest;
+  "download-end": TransferRequest;
+  "set-locale": LocaleRequest;
+  "lsp-start": IdeRequest;
+  "lsp-read-file": LspFileRequest;
+  "list-examples": ExamplesQuery;
+  "compile-example": CompileExampleRequest;
+  "replay-build": ReplayRequest;
+  "sketch-new": SketchNewRequest;
+  "sketch-archive": SketchArchiveRequest;
+  "profile-save": ProfileRequest;
+  "generate-lockfile": SketchRequest;
+  "preprocess-sketch": SketchRequest;
+  "check-sketch": SketchRequest;
+  "compilation-database": IdeRequest;
+  "analyze-sketch": AnalyzeRequest;
+  "power-cycle": PowerCycleRequest;
+  "encryption-key-create": EncryptionKeyRequest;
+  "encryption-key-import": EncryptionKeyImportRequest;
+  "encryption-key-get": EncryptionKeyRequest;
+  "encryption-key-delete": EncryptionKeyRequest;
+  "signing-key-generate": SigningKeyRequest;
+  "signing-key-import": SigningKeyImportRequest;
+  "signing-key-delete": SigningKeyRequest;
+  "project-get": ProjectNameRequest;
+  "project-delete": ProjectNameRequest;
+  "monitor-stop": MonitorStopRequest;
+  "list-recordings": RecordingsQuery;
+  "firmware-publish": FirmwarePublishRequest;
+  "firmware-list": ReleasesQuery;
+  "firmware-assign": FirmwareAssignRequest;
+  "device-register": DeviceRequest;
+  "device-list": DeviceQuery;
+  "device-delete": DeviceDeleteRequest;
+  "rollout-set": RolloutRequest;
+  "rollout-status": RolloutGroupRequest;
+  "rollout-delete": RolloutGroupRequest;
+}
+
+export type ClientEvent = keyof ClientEvents;
+
+// Every client event is acknowledged with a response of this shape
+export type ClientEventResponse = CommandResponse;
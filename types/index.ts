@@ -0,0 +1,69 @@
+// Generated by `cargo run --bin export-types`, do not edit.
+export * from "./AnalyzeRequest";
+export * from "./ArchiveEndRequest";
+export * from "./Artifact";
+export * from "./BuildStatus";
+export * from "./CommandResponse";
+export * from "./CommandResponseV2";
+export * from "./CompileExampleRequest";
+export * from "./CompileRequest";
+export * from "./DeviceDeleteRequest";
+export * from "./DeviceQuery";
+export * from "./DeviceRequest";
+export * from "./Diagnostic";
+export * from "./DownloadChunkRequest";
+export * from "./DownloadRequest";
+export * from "./EncryptionKeyImportRequest";
+export * from "./EncryptionKeyRequest";
+export * from "./ErrorCode";
+export * from "./EsptoolRequest";
+export * from "./ExamplesQuery";
+export * from "./Explanation";
+export * from "./FieldError";
+export * from "./FieldProblem";
+export * from "./FilePayload";
+export * from "./FilesystemKind";
+export * from "./FilesystemRequest";
+export * from "./FirmwareAssignRequest";
+export * from "./FirmwarePublishRequest";
+export * from "./FormatRequest";
+export * from "./IdeRequest";
+export * from "./InstallCoreRequest";
+export * from "./JobRequest";
+export * from "./LintRequest";
+export * from "./LocaleRequest";
+export * from "./LogLine";
+export * from "./LogStream";
+export * from "./LspFileRequest";
+export * from "./MemoryUsage";
+export * from "./MonitorRequest";
+export * from "./MonitorStopRequest";
+export * from "./NvsEntry";
+export * from "./NvsRequest";
+export * from "./PowerCycleRequest";
+export * from "./Priority";
+export * from "./ProfileRequest";
+export * from "./ProjectNameRequest";
+export * from "./ProjectQuery";
+export * from "./ProjectRequest";
+export * from "./RecordingsQuery";
+export * from "./ReleasesQuery";
+export * from "./ReplayRequest";
+export * from "./ResetMode";
+export * from "./ResetRequest";
+export * from "./RolloutGroupRequest";
+export * from "./RolloutRequest";
+export * from "./Severity";
+export * from "./SigningKeyImportRequest";
+export * from "./SigningKeyRequest";
+export * from "./SizeDelta";
+export * from "./SketchArchiveRequest";
+export * from "./SketchNewRequest";
+export * from "./SketchRequest";
+export * from "./Timings";
+export * from "./TransferBeginRequest";
+export * from "./TransferChunkRequest";
+export * from "./TransferRequest";
+export * from "./UploadRequest";
+export * from "./events";
+export * from "./serde_json/JsonValue";